@@ -0,0 +1,102 @@
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::time::timeout;
+
+/// Number of requests sent to the reference clock, the one with the shortest round trip
+/// gives the offset
+const SYNC_SAMPLES: usize = 16;
+const SYNC_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Source of the timestamps carried by the publishes, in nanoseconds
+#[derive(Clone, Copy)]
+pub enum Clock {
+    /// Time since the benchmark started, only comparable within a single process
+    Process(Instant),
+    /// Wall-clock time of the reference clock, this host being `offset` nanoseconds behind
+    /// it, comparable across hosts synced with the same reference
+    Synced { offset: i128 },
+}
+
+impl Clock {
+    pub fn now(&self) -> u128 {
+        match self {
+            Clock::Process(reference) => reference.elapsed().as_nanos(),
+            Clock::Synced { offset } => (wall_clock() + offset).max(0) as u128,
+        }
+    }
+    /// Time elapsed since `timestamp`, a timestamp ahead of this clock counts as none
+    pub fn since(&self, timestamp: u128) -> Duration {
+        let nanos = self.now().saturating_sub(timestamp);
+        Duration::new(
+            (nanos / 1_000_000_000) as u64,
+            (nanos % 1_000_000_000) as u32,
+        )
+    }
+}
+
+fn wall_clock() -> i128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock before 1970")
+        .as_nanos() as i128
+}
+
+/// Answers the clock requests of the hosts taking part in a benchmark, never returns
+/// unless the socket fails
+pub async fn serve<A: ToSocketAddrs>(addr: A) -> Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    let mut request = [0; 16];
+    loop {
+        let (len, peer) = socket.recv_from(&mut request).await?;
+        let received = wall_clock();
+        if len != request.len() {
+            continue;
+        }
+        // the request time is echoed so the answer can be matched with its request
+        let mut answer = [0; 48];
+        answer[..16].copy_from_slice(&request);
+        answer[16..32].copy_from_slice(&received.to_be_bytes());
+        answer[32..].copy_from_slice(&wall_clock().to_be_bytes());
+        socket.send_to(&answer, peer).await?;
+    }
+}
+
+/// Estimates how far this host is behind the reference clock served at `addr`, the same
+/// way NTP does: for a request sent at t0, received at t1, answered at t2 and whose answer
+/// is received at t3 the offset is ((t1 - t0) + (t2 - t3)) / 2. Returns the offset of the
+/// request with the shortest round trip along with that round trip.
+pub async fn estimate_offset<A: ToSocketAddrs>(addr: A) -> Result<(i128, Duration)> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(addr).await?;
+    let mut best: Option<(i128, i128)> = None;
+    for _ in 0..SYNC_SAMPLES {
+        let t0 = wall_clock();
+        socket.send(&t0.to_be_bytes()).await?;
+        let mut answer = [0; 48];
+        let len = match timeout(SYNC_TIMEOUT, socket.recv(&mut answer)).await {
+            Ok(len) => len?,
+            Err(_) => continue,
+        };
+        let t3 = wall_clock();
+        let field =
+            |i: usize| i128::from_be_bytes(answer[i * 16..(i + 1) * 16].try_into().unwrap());
+        // a late answer to an earlier request
+        if len != answer.len() || field(0) != t0 {
+            continue;
+        }
+        let (t1, t2) = (field(1), field(2));
+        let delay = (t3 - t0) - (t2 - t1);
+        let offset = ((t1 - t0) + (t2 - t3)) / 2;
+        if best.is_none_or(|(_, best_delay)| delay < best_delay) {
+            best = Some((offset, delay));
+        }
+    }
+    match best {
+        Some((offset, delay)) => Ok((offset, Duration::from_nanos(delay.max(0) as u64))),
+        None => Err(Error::new(
+            ErrorKind::TimedOut,
+            "the reference clock did not answer",
+        )),
+    }
+}
@@ -1,6 +1,43 @@
 use crate::Arc;
 use std::time::Duration;
 
+/// Client identifier used by the `i`th benchmarking client of the given `role`. Apiformes
+/// assigns identifiers to clients connecting with an empty one, other brokers may not.
+pub fn clientid(cfg: &Config, role: &str, i: usize) -> Arc<str> {
+    if cfg.external {
+        format!("apiformes-bm-{}-{}", role, i).into()
+    } else {
+        "".into()
+    }
+}
+
+/// Topic the `i`th publisher publishes on, the publishers are spread over `cfg.topics`
+/// sub-topics of `cfg.topic` when there are several
+pub fn publish_topic(cfg: &Config, i: usize) -> Arc<str> {
+    if cfg.topics > 1 {
+        format!("{}/{}", cfg.topic, i % cfg.topics).into()
+    } else {
+        cfg.topic.clone()
+    }
+}
+
+/// Filter matching the topics of every publisher
+pub fn subscribe_filter(cfg: &Config) -> Arc<str> {
+    if cfg.topics > 1 {
+        format!("{}/+", cfg.topic).into()
+    } else {
+        cfg.topic.clone()
+    }
+}
+
+/// Clients run by this process, the others may be run on other hosts
+#[derive(Clone, Copy, PartialEq)]
+pub enum Role {
+    Both,
+    Publishers,
+    Subscribers,
+}
+
 #[derive(Clone, Copy)]
 pub enum Sleep {
     NoDelay,
@@ -11,10 +48,26 @@ pub enum Sleep {
 pub struct Config {
     pub endpoint: String,
     pub topic: Arc<str>,
+    /// Number of sub-topics of `topic` the publishers are spread over
+    pub topics: usize,
     pub n_pubs: usize,
     pub n_subs: usize,
     pub sleep: Sleep,
     pub iterations: usize,
+    /// The endpoint is not necessarily apiformes, so do not rely on any of its behaviour
+    pub external: bool,
+    /// Use QoS 1 with up to this many publishes awaiting a PUBACK per client
+    pub in_flight: Option<u16>,
+    /// Read buffer sizes of the brokers started for the benchmark, each one run with and
+    /// without frame coalescing
+    pub sweep_reads: Option<Vec<usize>>,
+    /// Dispatcher workers of the brokers started for the benchmark, one per dispatcher
+    /// sharding
+    pub sweep_sharding: Option<usize>,
+    pub role: Role,
+    /// Reference clock served by another instance the timestamps are synced with, so
+    /// publishers and subscribers on different hosts can measure trip times
+    pub clock_sync: Option<String>,
 }
 
 impl Default for Config {
@@ -22,10 +75,17 @@ impl Default for Config {
         Config {
             endpoint: "0.0.0.0:1883".to_owned(),
             topic: "/benchmark/stress_test".into(),
+            topics: 1,
             n_pubs: 10,
             n_subs: 10,
             iterations: 1000,
             sleep: Sleep::ConstantTime(Duration::from_millis(1)),
+            external: false,
+            in_flight: None,
+            sweep_reads: None,
+            sweep_sharding: None,
+            role: Role::Both,
+            clock_sync: None,
         }
     }
 }
@@ -1,12 +1,15 @@
 #[macro_use]
 extern crate clap;
 
-mod client;
+mod clock;
 mod config;
 mod publisher;
 mod subscriber;
 
+use apiformes_client_lib::prelude::ClientError;
+use apiformes_server_lib::prelude::{DispatcherSharding, MqttServer, MqttServerConfig, ReadTuning};
 use clap::App;
+use clock::Clock;
 use config::*;
 use futures::future::{join_all, JoinAll};
 use publisher::*;
@@ -18,15 +21,30 @@ use tokio::sync::Notify;
 use tokio::time::sleep;
 use tokio::{select, task::JoinHandle};
 
-async fn starts_subs(cfg: &Config, time_ref: Instant) -> JoinAll<JoinHandle<SubscriberStats>> {
+/// How long publishers stay connected once done when the subscribers run on another host,
+/// so the broker delivers their last publishes
+const PUBLISHERS_LINGER: Duration = Duration::from_secs(1);
+
+fn client_error(e: ClientError) -> std::io::Error {
+    std::io::Error::other(format!("{:?}", e))
+}
+
+async fn starts_subs(cfg: &Config, clock: Clock) -> JoinAll<JoinHandle<SubscriberStats>> {
     let mut subs_handles = Vec::with_capacity(cfg.n_subs);
-    for _ in 0..cfg.n_subs {
+    // the subscribers of a publishers only run are on another host
+    let n_subs = match cfg.role {
+        Role::Publishers => 0,
+        _ => cfg.n_subs,
+    };
+    for i in 0..n_subs {
         // each subscriber would receive iteration * n_pubs messages
         let mut sub = Subscriber::new(
             &cfg.endpoint,
-            cfg.topic.clone(),
+            clientid(cfg, "sub", i),
+            subscribe_filter(cfg),
             cfg.iterations * cfg.n_pubs,
-            time_ref,
+            clock,
+            cfg.in_flight,
         )
         .await
         .unwrap();
@@ -38,18 +56,29 @@ async fn starts_subs(cfg: &Config, time_ref: Instant) -> JoinAll<JoinHandle<Subs
 
 async fn start_pubs(
     cfg: &Config,
-    time_ref: Instant,
+    clock: Clock,
 ) -> (JoinAll<JoinHandle<PublisherStats>>, Arc<Notify>) {
     let release_signal = Arc::new(Notify::new());
     let mut pubs_handles = Vec::with_capacity(cfg.n_pubs);
-    for _ in 0..cfg.n_pubs {
+    let n_pubs = match cfg.role {
+        Role::Subscribers => 0,
+        _ => cfg.n_pubs,
+    };
+    let linger = match cfg.role {
+        Role::Publishers => PUBLISHERS_LINGER,
+        _ => Duration::ZERO,
+    };
+    for i in 0..n_pubs {
         let _pub = Publisher::new(
             &cfg.endpoint,
-            &cfg.topic,
+            clientid(cfg, "pub", i),
+            &publish_topic(cfg, i),
             cfg.iterations,
-            time_ref,
+            clock,
             cfg.sleep,
             release_signal.clone(),
+            linger,
+            cfg.in_flight,
         )
         .await
         .unwrap();
@@ -124,6 +153,32 @@ async fn main() {
     if let Some(topic) = matches.value_of("Topic") {
         cfg.topic = topic.into();
     }
+    if let Some(topics) = matches.value_of("Topics") {
+        cfg.topics = topics.parse().unwrap();
+    }
+    if let Some(reference) = matches.value_of("ServeClock") {
+        println!("Serving the reference clock on {}", reference);
+        clock::serve(reference).await.unwrap();
+        return;
+    }
+    cfg.external = matches.is_present("External");
+    if let Some(reference) = matches.value_of("ClockSync") {
+        cfg.clock_sync = Some(reference.to_owned());
+    }
+    match matches.value_of("Role") {
+        Some("publishers") => cfg.role = Role::Publishers,
+        Some("subscribers") => cfg.role = Role::Subscribers,
+        _ => (),
+    }
+    if let Some(sizes) = matches.value_of("SweepReads") {
+        cfg.sweep_reads = Some(sizes.split(',').map(|size| size.parse().unwrap()).collect());
+    }
+    if let Some(workers) = matches.value_of("SweepSharding") {
+        cfg.sweep_sharding = Some(workers.parse().unwrap());
+    }
+    if let Some(in_flight) = matches.value_of("InFlight") {
+        cfg.in_flight = Some(in_flight.parse().unwrap());
+    }
     if matches.is_present("NoDelay") {
         cfg.sleep = Sleep::NoDelay;
     } else if let Some(delay) = matches.value_of("ConstDelay") {
@@ -137,19 +192,102 @@ async fn main() {
     }
 
     println!("Benchmarking configuration:");
+    if cfg.external {
+        println!("Broker under test: external broker at {}", cfg.endpoint);
+    } else if cfg.sweep_reads.is_some() || cfg.sweep_sharding.is_some() {
+        println!("Broker under test: apiformes started at {}", cfg.endpoint);
+    } else {
+        println!("Broker under test: apiformes at {}", cfg.endpoint);
+    }
     println!("Number of concurrent Publishers: {}", cfg.n_pubs);
     println!("Number of concurrent Subscribers: {}", cfg.n_subs);
-    println!("Benchmarking topic: {}", cfg.topic);
+    println!("Benchmarking topic: {}", subscribe_filter(&cfg));
     println!("Number of publish messages: {}", cfg.iterations);
+    match cfg.role {
+        Role::Both => (),
+        Role::Publishers => println!("Only the publishers run on this host"),
+        Role::Subscribers => println!("Only the subscribers run on this host"),
+    }
+    if let Some(reference) = &cfg.clock_sync {
+        println!(
+            "Timestamps synced with the reference clock at {}",
+            reference
+        );
+    }
+    if let Some(in_flight) = cfg.in_flight {
+        println!("QoS 1 publishes in flight per client: {}", in_flight);
+    }
+
+    match (&cfg.sweep_reads, cfg.sweep_sharding) {
+        (Some(sizes), _) => {
+            let saddr = cfg.endpoint.parse().expect("invalid endpoint");
+            for &buffer_size in sizes {
+                for coalesce_frames in [false, true] {
+                    println!();
+                    println!(
+                        "Read buffer: {} bytes, frame coalescing: {}",
+                        buffer_size, coalesce_frames
+                    );
+                    let server = MqttServer::new(MqttServerConfig {
+                        mqtt_socketaddr: Some(saddr),
+                        mqtt_read: ReadTuning {
+                            buffer_size,
+                            coalesce_frames,
+                        },
+                        ..Default::default()
+                    })
+                    .await
+                    .unwrap();
+                    bench(&cfg).await;
+                    server.shutdown().await;
+                }
+            }
+        }
+        (None, Some(workers)) => {
+            let saddr = cfg.endpoint.parse().expect("invalid endpoint");
+            for (name, sharding) in [
+                ("client id", DispatcherSharding::ClientId),
+                ("topic", DispatcherSharding::Topic),
+            ] {
+                println!();
+                println!("Dispatcher workers: {}, sharding by {}", workers, name);
+                let server = MqttServer::new(MqttServerConfig {
+                    mqtt_socketaddr: Some(saddr),
+                    dispatcher_workers: workers,
+                    dispatcher_sharding: sharding,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+                bench(&cfg).await;
+                server.shutdown().await;
+            }
+        }
+        (None, None) => bench(&cfg).await,
+    }
+}
 
-    let time_ref = Instant::now();
+/// Runs the benchmark once and prints its results
+async fn bench(cfg: &Config) {
+    let clock = match &cfg.clock_sync {
+        Some(reference) => {
+            let (offset, round_trip) = clock::estimate_offset(reference).await.unwrap();
+            println!(
+                "Clock offset to the reference: {}µs, measured over a {:?} round trip",
+                offset / 1000,
+                round_trip
+            );
+            Clock::Synced { offset }
+        }
+        None => Clock::Process(Instant::now()),
+    };
 
     // first start the subscribers because if we start publishers first some messages may not be
     // delivered at all
-    let subs_handles = starts_subs(&cfg, time_ref).await;
+    let subs_handles = starts_subs(cfg, clock).await;
 
     // next we start the publishers this way we are sure that all published messages will be captured
-    let (mut pubs_handles, release_signal) = start_pubs(&cfg, time_ref).await;
+    let (mut pubs_handles, release_signal) = start_pubs(cfg, clock).await;
 
     // we then wait for all subscribers to finish
     let subs = subs_handles.await;
@@ -197,20 +335,44 @@ async fn main() {
         / cfg.n_pubs as f64
         * 1000000.0; //because we are doing measurements in micrseconds
 
+    let publishing_time = pubs
+        .iter()
+        .map(|s| s.as_ref().unwrap().total_time)
+        .max()
+        .unwrap_or_default();
+    let receiving_time = subs
+        .iter()
+        .map(|s| s.as_ref().unwrap().total_time)
+        .max()
+        .unwrap_or_default();
+
+    let mut acks: Vec<_> = pubs
+        .iter()
+        .flat_map(|s| s.as_ref().unwrap().acks_time.iter().copied())
+        .collect();
+    acks.sort();
+
     let mut aggregate: Vec<_> = subs
         .into_iter()
-        .map(|s| s.unwrap().trips_time)
-        .flatten()
+        .flat_map(|s| s.unwrap().trips_time)
         .collect();
     aggregate.sort();
-    println!(
-        "Mean departure rate = {:.2} packets/second/publisher",
-        publishing_rate
-    );
+    if !pubs.is_empty() {
+        println!(
+            "Mean departure rate = {:.2} packets/second/publisher",
+            publishing_rate
+        );
+        println!("Slowest publisher total time: {:?}", publishing_time);
+    }
+    if aggregate.is_empty() {
+        print_acks(&acks);
+        return;
+    }
     println!(
         "Mean arrival rate = {:.2} packets/second/subscriber",
         arrival_rate
     );
+    println!("Slowest subscriber total time: {:?}", receiving_time);
     println!("Minimum trip time: {:?}", aggregate[0]);
     println!("Maximum trip time: {:?}", aggregate[aggregate.len() - 1]);
     println!(
@@ -225,4 +387,16 @@ async fn main() {
         "99th percentile trip time: {:?}",
         aggregate[aggregate.len() * 99 / 100]
     );
+    print_acks(&acks);
+}
+
+fn print_acks(acks: &[Duration]) {
+    if !acks.is_empty() {
+        println!("Maximum PUBACK time: {:?}", acks[acks.len() - 1]);
+        println!("50th percentile PUBACK time: {:?}", acks[acks.len() / 2]);
+        println!(
+            "99th percentile PUBACK time: {:?}",
+            acks[acks.len() * 99 / 100]
+        );
+    }
 }
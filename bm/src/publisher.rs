@@ -1,21 +1,24 @@
-use crate::{client::Client, config::Sleep};
-use apiformes_packet::prelude::*;
+use crate::{client_error, clock::Clock, config::Sleep};
+use apiformes_client_lib::prelude::*;
 use bytes::Bytes;
 use rand::{distributions::Uniform, rngs::SmallRng, Rng, SeedableRng};
+use std::collections::VecDeque;
 use std::io::Result;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::net::ToSocketAddrs;
 use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
 
 pub struct PublisherStats {
     pub total_time: Duration,
     pub deltas: Vec<Duration>,
+    /// Time between sending each QoS 1 publish and receiving its PUBACK
+    pub acks_time: Vec<Duration>,
 }
 
 pub struct Publisher {
-    time_reference: Instant,
+    clock: Clock,
     client: Client,
     topic: Arc<str>,
     iterations: usize,
@@ -29,34 +32,72 @@ pub struct Publisher {
     // sd we must drop the connection only after all the Subscribers
     // receive the message.
     release_signal: Arc<Notify>,
+    // kept connected for this long once done, when the subscribers are not in this
+    // process and cannot tell when they received everything
+    linger: Duration,
+    in_flight: Option<u16>,
+    // QoS 1 publishes awaiting their PUBACK, oldest first, each resolving to the time it
+    // took to be acknowledged
+    pending: VecDeque<JoinHandle<std::result::Result<Duration, ClientError>>>,
+    acks_time: Vec<Duration>,
 }
 
 impl Publisher {
-    pub async fn new<A: ToSocketAddrs>(
-        addr: A,
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        addr: &str,
+        clientid: Arc<str>,
         topic: &str,
         iterations: usize,
-        time_reference: Instant,
+        clock: Clock,
         sleep: Sleep,
         release_signal: Arc<Notify>,
+        linger: Duration,
+        in_flight: Option<u16>,
     ) -> Result<Publisher> {
+        let cfg = ClientConfig::new(addr, clientid).reconnect_delay(None);
         Ok(Publisher {
-            client: Client::new(addr).await?,
+            client: Client::connect(cfg).await.map_err(client_error)?,
             topic: topic.into(),
             deltas: Vec::with_capacity(iterations),
             iterations,
-            time_reference,
+            clock,
             sleep,
             release_signal,
+            linger,
+            in_flight,
+            pending: VecDeque::new(),
+            acks_time: Vec::new(),
         })
     }
+    async fn wait_ack(&mut self) -> Result<()> {
+        // the broker acknowledges the publishes of a client in order
+        if let Some(ack) = self.pending.pop_front() {
+            let time = ack.await.unwrap().map_err(client_error)?;
+            self.acks_time.push(time);
+        }
+        Ok(())
+    }
     async fn run_once(&mut self) -> Result<()> {
         let start = Instant::now();
-        let timestamp = start.duration_since(self.time_reference).as_nanos();
+        let timestamp = self.clock.now();
         let payload = timestamp.to_be_bytes();
         let bytes = Bytes::copy_from_slice(&payload[..]);
-        let pub_packet = Publish::new(self.topic.clone(), bytes).unwrap().build();
-        self.client.send(&pub_packet).await?;
+        let mut publish = Publish::new(self.topic.clone(), bytes).unwrap();
+        if let Some(window) = self.in_flight {
+            while self.pending.len() >= window as usize {
+                self.wait_ack().await?;
+            }
+            publish.set_qos(QoS::QoS1);
+            let sent = Instant::now();
+            let ack = self.client.send(publish);
+            // timed apart so acknowledgements are not measured late while waiting for others
+            self.pending.push_back(tokio::spawn(
+                async move { ack.await.map(|_| sent.elapsed()) },
+            ));
+        } else {
+            self.client.send(publish).await.map_err(client_error)?;
+        }
         self.deltas.push(Instant::now().duration_since(start));
         Ok(())
     }
@@ -90,21 +131,22 @@ impl Publisher {
 
     pub async fn run(mut self) -> Result<PublisherStats> {
         let start = Instant::now();
-        let mut conn = Connect::new("".into()).unwrap();
-        conn.set_clean_start();
-        let conn = conn.build();
-        self.client.send(&conn).await?;
-        drop(self.client.recv().await?); //connack
         match self.sleep {
             Sleep::NoDelay => self.run_nodelay().await?,
             Sleep::ConstantTime(d) => self.run_constant_sleep(d).await?,
             Sleep::MinMax(min, max) => self.run_min_max_sleep(min, max).await?,
         }
+        while !self.pending.is_empty() {
+            self.wait_ack().await?;
+        }
         let total_time = Instant::now().duration_since(start);
+        sleep(self.linger).await;
         self.release_signal.notified().await;
+        self.client.disconnect().await.map_err(client_error)?;
         Ok(PublisherStats {
             total_time,
             deltas: self.deltas,
+            acks_time: self.acks_time,
         })
     }
 }
@@ -1,9 +1,8 @@
-use super::client::Client;
-use apiformes_packet::prelude::*;
+use super::{client_error, clock::Clock};
+use apiformes_client_lib::prelude::*;
 use std::io::Result;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::net::ToSocketAddrs;
 
 pub struct SubscriberStats {
     pub total_time: Duration,
@@ -13,75 +12,82 @@ pub struct SubscriberStats {
 
 pub struct Subscriber {
     client: Client,
-    time_reference: Instant,
+    subscription: Option<Subscription>,
+    clock: Clock,
     topic: Arc<str>,
     iterations: usize,
     deltas: Vec<Duration>,
     trips_time: Vec<Duration>,
+    in_flight: Option<u16>,
 }
 
 impl Subscriber {
-    pub async fn new<A: ToSocketAddrs>(
-        addr: A,
+    pub async fn new(
+        addr: &str,
+        clientid: Arc<str>,
         topic: Arc<str>,
         iterations: usize,
-        time_reference: Instant,
+        clock: Clock,
+        in_flight: Option<u16>,
     ) -> Result<Subscriber> {
+        let mut cfg = ClientConfig::new(addr, clientid).reconnect_delay(None);
+        if let Some(in_flight) = in_flight {
+            cfg = cfg.receive_maximum(in_flight);
+        }
         Ok(Subscriber {
-            time_reference,
-            client: Client::new(addr).await?,
+            clock,
+            client: Client::connect(cfg).await.map_err(client_error)?,
+            subscription: None,
             topic,
             deltas: Vec::with_capacity(iterations),
             iterations,
             trips_time: Vec::with_capacity(iterations),
+            in_flight,
         })
     }
 
     async fn listen(&mut self) -> Result<()> {
-        for _ in 0..self.iterations {
+        // kept until disconnected, older apiformes brokers do not support UNSUBSCRIBE
+        let subscription = self.subscription.as_mut().expect("subscribed first");
+        let mut received = 0;
+        while received < self.iterations {
             let start = Instant::now();
-            let packet = self.client.recv().await?;
-            let p = match packet {
-                Packet::Publish(p) => p,
-                _ => continue,
+            let message = match subscription.recv().await {
+                Some(message) => message,
+                None => return Err(client_error(ClientError::Disconnected)),
+            };
+            // ignore messages published on the same topic by anything other than the benchmark
+            let t = match message.payload[..].try_into() {
+                Ok(timestamp) => u128::from_be_bytes(timestamp),
+                Err(_) => continue,
             };
-            let payload = p.payload();
-            let t = u128::from_be_bytes(payload[..].try_into().unwrap());
-            // Both sender and receiver have time reference t
-            //  Reference                                              Now
-            //  |---------------------------|---------------------------|
-            //  |    t(stored in payload)   |      we need this part    |
-            let now = Instant::now();
-            let t_secs = (t / 1000000000) as u64;
-            let t_nanos = (t % 1000000000) as u32;
-            let t = Duration::new(t_secs, t_nanos);
-            let trip_time = now.duration_since(self.time_reference).saturating_sub(t);
-            self.trips_time.push(trip_time);
-            self.deltas.push(now.duration_since(start));
+            received += 1;
+            // the publisher stamped the payload with the same clock, in this process or
+            // synced with the same reference
+            self.trips_time.push(self.clock.since(t));
+            self.deltas.push(start.elapsed());
         }
         Ok(())
     }
 
     pub async fn connect(&mut self) -> Result<()> {
-        let mut conn = Connect::new("".into()).unwrap();
-        conn.set_clean_start();
-        let conn = conn.build();
-        self.client.send(&conn).await?;
-
-        drop(self.client.recv().await?); // the ack message
-
-        let mut packet = Subscribe::new(1);
-        packet
-            .add_topic(self.topic.clone(), RetainHandling::DoNotSend.into())
-            .unwrap();
-        let packet = packet.build();
-        self.client.send(&packet).await?;
-        drop(self.client.recv().await?); // the ack message
+        let qos = match self.in_flight {
+            Some(_) => QoS::QoS1,
+            None => QoS::QoS0,
+        };
+        let options = SubscriptionOptions::from(qos) | RetainHandling::DoNotSend.into();
+        let subscription = self
+            .client
+            .subscribe(self.topic.clone(), options)
+            .await
+            .map_err(client_error)?;
+        self.subscription = Some(subscription);
         Ok(())
     }
     pub async fn run(mut self) -> Result<SubscriberStats> {
         let start = Instant::now();
         self.listen().await?;
+        self.client.disconnect().await.map_err(client_error)?;
         Ok(SubscriberStats {
             total_time: Instant::now().duration_since(start),
             trips_time: self.trips_time,
@@ -0,0 +1,87 @@
+#[cfg(feature = "noise")]
+use crate::noise::NoiseConnector;
+use bytes::Bytes;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How a `Client` connects to a broker, see `ClientConfig::new` for the defaults
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Address of the broker, anything `TcpStream::connect` accepts
+    pub addr: String,
+    pub clientid: Arc<str>,
+    /// Seconds without sending anything after which a PINGREQ is sent, 0 disables it.
+    /// A keep alive imposed by the broker in its CONNACK takes precedence.
+    pub keep_alive: u16,
+    /// Starts a new session instead of resuming the one the broker kept for the clientid
+    pub clean_start: bool,
+    /// Seconds the broker keeps the session once disconnected
+    pub session_expiry: u32,
+    /// QoS 1 and 2 publishes the broker may send before they are acknowledged
+    pub receive_maximum: Option<u16>,
+    pub username: Option<Arc<str>>,
+    pub password: Option<Bytes>,
+    /// Delay between attempts to connect again once the connection is lost, the client
+    /// gives up on the first loss when unset. Subscriptions are made again and the
+    /// publishes not acknowledged yet are sent again once reconnected.
+    pub reconnect_delay: Option<Duration>,
+    /// Messages buffered for each subscription, the client stops reading from the broker
+    /// while a subscription is full
+    pub queue: usize,
+    /// Connects to the Noise listener of an apiformes broker instead of plain MQTT
+    #[cfg(feature = "noise")]
+    pub noise: Option<NoiseConnector>,
+}
+
+impl ClientConfig {
+    pub fn new(addr: impl Into<String>, clientid: impl Into<Arc<str>>) -> Self {
+        ClientConfig {
+            addr: addr.into(),
+            clientid: clientid.into(),
+            keep_alive: 60,
+            clean_start: true,
+            session_expiry: 0,
+            receive_maximum: None,
+            username: None,
+            password: None,
+            reconnect_delay: Some(Duration::from_secs(1)),
+            queue: 256,
+            #[cfg(feature = "noise")]
+            noise: None,
+        }
+    }
+    pub fn keep_alive(mut self, keep_alive: u16) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+    pub fn clean_start(mut self, clean_start: bool) -> Self {
+        self.clean_start = clean_start;
+        self
+    }
+    pub fn session_expiry(mut self, session_expiry: u32) -> Self {
+        self.session_expiry = session_expiry;
+        self
+    }
+    pub fn receive_maximum(mut self, receive_maximum: u16) -> Self {
+        self.receive_maximum = Some(receive_maximum);
+        self
+    }
+    pub fn credentials(mut self, username: impl Into<Arc<str>>, password: Bytes) -> Self {
+        self.username = Some(username.into());
+        self.password = Some(password);
+        self
+    }
+    pub fn reconnect_delay(mut self, reconnect_delay: Option<Duration>) -> Self {
+        self.reconnect_delay = reconnect_delay;
+        self
+    }
+    pub fn queue(mut self, queue: usize) -> Self {
+        self.queue = queue;
+        self
+    }
+    #[cfg(feature = "noise")]
+    pub fn noise(mut self, connector: NoiseConnector) -> Self {
+        self.noise = Some(connector);
+        self
+    }
+}
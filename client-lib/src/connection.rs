@@ -0,0 +1,133 @@
+#[cfg(feature = "noise")]
+use crate::noise::NoiseStream;
+use crate::{config::ClientConfig, error::ClientError};
+use apiformes_packet::prelude::*;
+use bytes::{Buf, BytesMut};
+use std::io::Cursor;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+enum Transport {
+    Tcp(TcpStream),
+    #[cfg(feature = "noise")]
+    Noise(Box<NoiseStream>),
+}
+
+/// A connection to the broker framing the packets sent and received
+pub(crate) struct Connection {
+    transport: Transport,
+    recv_bytes: BytesMut,
+    send_bytes: BytesMut,
+}
+
+/// What the broker granted in its CONNACK
+pub(crate) struct Session {
+    pub(crate) present: bool,
+    /// Keep alive to use, the one of the configuration unless the broker imposed another
+    pub(crate) keep_alive: u16,
+    /// Highest QoS of the publishes the broker accepts
+    pub(crate) maximum_qos: QoS,
+}
+
+impl Connection {
+    /// Connects to the broker and waits for a successful CONNACK
+    pub(crate) async fn open(cfg: &ClientConfig) -> Result<(Connection, Session), ClientError> {
+        let stream = TcpStream::connect(&*cfg.addr).await?;
+        #[cfg(feature = "noise")]
+        let transport = match &cfg.noise {
+            Some(connector) => Transport::Noise(Box::new(connector.handshake(stream).await?)),
+            None => Transport::Tcp(stream),
+        };
+        #[cfg(not(feature = "noise"))]
+        let transport = Transport::Tcp(stream);
+        let mut conn = Connection {
+            transport,
+            recv_bytes: BytesMut::with_capacity(128),
+            send_bytes: BytesMut::with_capacity(128),
+        };
+        let session = conn.handshake(cfg).await?;
+        Ok((conn, session))
+    }
+    async fn handshake(&mut self, cfg: &ClientConfig) -> Result<Session, ClientError> {
+        let mut connect = Connect::new(cfg.clientid.clone())?;
+        if cfg.clean_start {
+            connect.set_clean_start();
+        }
+        connect.set_keep_alive(cfg.keep_alive);
+        if cfg.session_expiry != 0 {
+            connect.add_prop(
+                Property::SessionExpiryInterval,
+                MqttPropValue::new_u32(cfg.session_expiry),
+            )?;
+        }
+        if let Some(receive_maximum) = cfg.receive_maximum {
+            connect.add_prop(
+                Property::ReceiveMaximum,
+                MqttPropValue::new_u16(receive_maximum),
+            )?;
+        }
+        if let Some(username) = &cfg.username {
+            connect.set_username(username.clone())?;
+        }
+        if let Some(password) = &cfg.password {
+            connect.set_password(password.clone())?;
+        }
+        self.send(connect.build()).await?;
+        let connack = match self.recv().await? {
+            Packet::ConnAck(connack) => connack,
+            _ => return Err(ClientError::UnexpectedPacket),
+        };
+        if !matches!(connack.reason_code(), ConnAckReasonCode::Success) {
+            return Err(ClientError::ConnectionRefused(connack.reason_code() as u8));
+        }
+        let keep_alive = connack
+            .get_prop(Property::ServerKeepAlive)
+            .and_then(|v| v[0].into_u16())
+            .unwrap_or(cfg.keep_alive);
+        // 3.2.2.3.4: an absent MaximumQoS means QoS 2 is supported
+        let maximum_qos = match connack.get_prop(Property::MaximumQoS) {
+            Some(v) => QoS::from_u8(v[0].into_u8().unwrap_or(0))?,
+            None => QoS::QoS2,
+        };
+        Ok(Session {
+            present: connack.flags().contains(ConnAckFlags::SESSION_PRESENT),
+            keep_alive,
+            maximum_qos,
+        })
+    }
+    /// Waits for the next packet, cancelling it loses no data
+    pub(crate) async fn recv(&mut self) -> Result<Packet, ClientError> {
+        match &mut self.transport {
+            Transport::Tcp(stream) => recv_tcp(stream, &mut self.recv_bytes).await,
+            #[cfg(feature = "noise")]
+            Transport::Noise(noise) => noise.recv().await,
+        }
+    }
+    pub(crate) async fn send(&mut self, packet: Packet) -> Result<(), ClientError> {
+        match &mut self.transport {
+            Transport::Tcp(stream) => {
+                packet.to_bytes(&mut self.send_bytes);
+                stream.write_all_buf(&mut self.send_bytes).await?;
+                Ok(())
+            }
+            #[cfg(feature = "noise")]
+            Transport::Noise(noise) => noise.send(packet).await,
+        }
+    }
+}
+
+/// Reads from `stream` into `buf` until it holds a whole packet
+async fn recv_tcp(stream: &mut TcpStream, buf: &mut BytesMut) -> Result<Packet, ClientError> {
+    loop {
+        if let Some(len) = Packet::peek_frame_len(buf)? {
+            if len <= buf.len() {
+                let packet = Packet::from_bytes(&mut Cursor::new(&buf[..len]))?;
+                buf.advance(len);
+                return Ok(packet);
+            }
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(ClientError::Disconnected);
+        }
+    }
+}
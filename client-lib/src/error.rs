@@ -0,0 +1,49 @@
+use apiformes_packet::prelude::DataParseError;
+use std::io;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    Packet(DataParseError),
+    /// The broker answered the CONNECT with the given reason code
+    ConnectionRefused(u8),
+    /// The broker refused the subscription with the given reason code
+    SubscriptionRefused(u8),
+    /// The broker does not support publishes of the given QoS
+    QoSNotSupported(u8),
+    /// The broker refused the publish with the given reason code
+    PublishRefused(u8),
+    /// The broker sent a DISCONNECT with the given reason code
+    DisconnectedByServer(u8),
+    /// The broker did not answer a PINGREQ within the keep alive
+    KeepAliveTimeout,
+    #[cfg(feature = "noise")]
+    Noise(snow::Error),
+    /// The Noise handshake failed, the broker does not hold the private key matching the
+    /// pinned public key
+    #[cfg(feature = "noise")]
+    HandshakeRejected,
+    /// The broker sent a packet it should not have at this point
+    UnexpectedPacket,
+    /// The connection is closed and is not reconnecting anymore
+    Disconnected,
+}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> ClientError {
+        ClientError::Io(err)
+    }
+}
+
+#[cfg(feature = "noise")]
+impl From<snow::Error> for ClientError {
+    fn from(err: snow::Error) -> ClientError {
+        ClientError::Noise(err)
+    }
+}
+
+impl From<DataParseError> for ClientError {
+    fn from(err: DataParseError) -> ClientError {
+        ClientError::Packet(err)
+    }
+}
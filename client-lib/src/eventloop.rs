@@ -0,0 +1,455 @@
+use crate::{
+    config::ClientConfig,
+    connection::{Connection, Session},
+    error::ClientError,
+    subscription::Message,
+};
+use apiformes_packet::prelude::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::{
+    sync::{
+        mpsc::{Sender, UnboundedReceiver},
+        oneshot,
+    },
+    time::{self, Duration, Instant},
+};
+use tracing::{info, warn};
+
+type Done = oneshot::Sender<Result<(), ClientError>>;
+
+/// Requests of the `Client` handles and subscriptions to the event loop
+pub(crate) enum Command {
+    Publish(Publish, Done),
+    Subscribe {
+        id: u64,
+        filter: TopicFilter,
+        options: SubscriptionOptions,
+        messages: Sender<Message>,
+        done: Done,
+    },
+    Unsubscribe(u64),
+    Disconnect(Done),
+}
+
+struct Route {
+    id: u64,
+    filter: TopicFilter,
+    options: SubscriptionOptions,
+    messages: Sender<Message>,
+}
+
+/// Packets waiting for the broker to acknowledge them, by packet identifier
+enum Pending {
+    /// A QoS 1 publish waiting for its PUBACK or a QoS 2 one waiting for its PUBREC
+    Publish(Publish, Option<Done>),
+    /// A QoS 2 publish waiting for its PUBCOMP
+    Release(Option<Done>),
+    /// The routes of a SUBSCRIBE waiting for its SUBACK
+    Subscribe(Vec<u64>, Vec<Done>),
+    Unsubscribe,
+}
+
+enum Event {
+    Command(Option<Command>),
+    Packet(Result<Packet, ClientError>),
+    KeepAlive,
+}
+
+/// Owns the connection to the broker, serving the commands of the client and delivering
+/// the messages of the broker to the subscriptions
+pub(crate) struct EventLoop {
+    cfg: ClientConfig,
+    commands: UnboundedReceiver<Command>,
+    // commands received while reconnecting
+    backlog: VecDeque<Command>,
+    routes: Vec<Route>,
+    pending: HashMap<u16, Pending>,
+    // QoS 2 publishes received whose PUBREL is awaited, they are not delivered again when
+    // the broker sends them again
+    received: HashSet<u16>,
+    last_id: u16,
+    maximum_qos: QoS,
+}
+
+fn refused(code: u8) -> bool {
+    code >= 0x80
+}
+
+impl EventLoop {
+    pub(crate) fn new(cfg: ClientConfig, commands: UnboundedReceiver<Command>) -> Self {
+        EventLoop {
+            cfg,
+            commands,
+            backlog: VecDeque::new(),
+            routes: Vec::new(),
+            pending: HashMap::new(),
+            received: HashSet::new(),
+            last_id: 0,
+            maximum_qos: QoS::QoS2,
+        }
+    }
+    /// Serves the commands until the client disconnects, or until the connection is lost
+    /// when not reconnecting
+    pub(crate) async fn run(mut self, mut conn: Connection, session: Session) {
+        let mut keep_alive = session.keep_alive;
+        self.maximum_qos = session.maximum_qos;
+        loop {
+            let lost = match self.serve(&mut conn, keep_alive).await {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+            let delay = match self.cfg.reconnect_delay {
+                Some(delay) => delay,
+                None => {
+                    warn!(broker = &*self.cfg.addr, "Connection lost, {:?}", lost);
+                    return;
+                }
+            };
+            warn!(
+                broker = &*self.cfg.addr,
+                "Connection lost, reconnecting, {:?}", lost
+            );
+            match self.reconnect(delay).await {
+                Some((reconnected, session)) => {
+                    conn = reconnected;
+                    keep_alive = session.keep_alive;
+                    self.maximum_qos = session.maximum_qos;
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// Connects again every `delay` until it succeeds, `None` when the client disconnected
+    /// in the meantime
+    async fn reconnect(&mut self, delay: Duration) -> Option<(Connection, Session)> {
+        loop {
+            let sleep = time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    command = self.commands.recv() => match command {
+                        Some(Command::Disconnect(done)) => {
+                            let _ = done.send(Ok(()));
+                            return None;
+                        }
+                        Some(command) => self.backlog.push_back(command),
+                        None => return None,
+                    },
+                }
+            }
+            match Connection::open(&self.cfg).await {
+                Ok((mut conn, session)) => match self.resume(&mut conn, &session).await {
+                    Ok(()) => {
+                        info!(broker = &*self.cfg.addr, "Reconnected");
+                        return Some((conn, session));
+                    }
+                    Err(e) => warn!(broker = &*self.cfg.addr, "Failed resuming, {:?}", e),
+                },
+                Err(e) => warn!(broker = &*self.cfg.addr, "Failed reconnecting, {:?}", e),
+            }
+        }
+    }
+
+    /// Subscribes again when the broker did not keep the session and sends again what it
+    /// did not acknowledge
+    async fn resume(
+        &mut self,
+        conn: &mut Connection,
+        session: &Session,
+    ) -> Result<(), ClientError> {
+        if !session.present {
+            self.received.clear();
+        }
+        let mut resent = Vec::new();
+        let mut waiting = Vec::new();
+        let mut ids: Vec<_> = self.pending.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            match self.pending.remove(&id).unwrap() {
+                Pending::Publish(mut publish, done) => {
+                    publish.set_dup();
+                    resent.push(publish.clone().build());
+                    self.pending.insert(id, Pending::Publish(publish, done));
+                }
+                Pending::Release(done) => {
+                    resent.push(PubRel::new(id).build());
+                    self.pending.insert(id, Pending::Release(done));
+                }
+                // the subscriptions made again below cover them
+                Pending::Subscribe(_, done) => waiting.extend(done),
+                Pending::Unsubscribe => (),
+            }
+        }
+        if !session.present || !waiting.is_empty() {
+            // a filter subscribed to several times is subscribed to again with the options
+            // granting the highest QoS
+            let qos = |options: SubscriptionOptions| {
+                (options & (SubscriptionOptions::QOS1 | SubscriptionOptions::QOS2)).bits()
+            };
+            let mut filters: Vec<(Arc<str>, SubscriptionOptions)> = Vec::new();
+            for route in &self.routes {
+                match filters
+                    .iter_mut()
+                    .find(|(filter, _)| filter == route.filter.inner())
+                {
+                    Some((_, options)) if qos(*options) < qos(route.options) => {
+                        *options = route.options
+                    }
+                    Some(_) => (),
+                    None => filters.push((route.filter.inner().clone(), route.options)),
+                }
+            }
+            if !filters.is_empty() {
+                let id = self.next_id();
+                let mut subscribe = Subscribe::new(id);
+                for (filter, options) in filters {
+                    subscribe.add_topic(filter, options)?;
+                }
+                let routes = self.routes.iter().map(|route| route.id).collect();
+                self.pending.insert(id, Pending::Subscribe(routes, waiting));
+                conn.send(subscribe.build()).await?;
+            } else {
+                for done in waiting {
+                    let _ = done.send(Ok(()));
+                }
+            }
+        }
+        for packet in resent {
+            conn.send(packet).await?;
+        }
+        Ok(())
+    }
+
+    /// Serves the connection until the client disconnects or the connection is lost
+    async fn serve(&mut self, conn: &mut Connection, keep_alive: u16) -> Result<(), ClientError> {
+        while let Some(command) = self.backlog.pop_front() {
+            if !self.command(conn, command).await? {
+                return Ok(());
+            }
+        }
+        let period = Duration::from_secs(keep_alive as u64);
+        let mut deadline = Instant::now() + period;
+        let mut pinged = false;
+        loop {
+            let event = tokio::select! {
+                command = self.commands.recv() => Event::Command(command),
+                packet = conn.recv() => Event::Packet(packet),
+                _ = time::sleep_until(deadline), if keep_alive != 0 => Event::KeepAlive,
+            };
+            match event {
+                Event::Command(Some(command)) => {
+                    if !self.command(conn, command).await? {
+                        return Ok(());
+                    }
+                    if !pinged {
+                        deadline = Instant::now() + period;
+                    }
+                }
+                Event::Command(None) => {
+                    // every handle and subscription is gone, nobody can use the connection
+                    let disconnect = Disconnect::new(DisconnectReasonCode::NormalDisconnection);
+                    return conn.send(disconnect.build()).await;
+                }
+                Event::Packet(packet) => {
+                    self.incoming(conn, packet?).await?;
+                    pinged = false;
+                }
+                Event::KeepAlive if pinged => return Err(ClientError::KeepAliveTimeout),
+                Event::KeepAlive => {
+                    conn.send(Ping::new().build_req()).await?;
+                    pinged = true;
+                    deadline = Instant::now() + period;
+                }
+            }
+        }
+    }
+
+    fn next_id(&mut self) -> u16 {
+        loop {
+            self.last_id = self.last_id.wrapping_add(1).max(1);
+            if !self.pending.contains_key(&self.last_id) {
+                return self.last_id;
+            }
+        }
+    }
+
+    /// Serves a command, returns whether the connection is still in use
+    async fn command(
+        &mut self,
+        conn: &mut Connection,
+        command: Command,
+    ) -> Result<bool, ClientError> {
+        match command {
+            Command::Publish(mut publish, done) => {
+                if publish.qos() > self.maximum_qos {
+                    let qos = publish.qos().as_u8();
+                    let _ = done.send(Err(ClientError::QoSNotSupported(qos)));
+                    return Ok(true);
+                }
+                if publish.qos() == QoS::QoS0 {
+                    conn.send(publish.build()).await?;
+                    let _ = done.send(Ok(()));
+                    return Ok(true);
+                }
+                let id = self.next_id();
+                publish.set_packet_identifier(id)?;
+                // kept until acknowledged so it is sent again if the connection is lost
+                self.pending
+                    .insert(id, Pending::Publish(publish.clone(), Some(done)));
+                conn.send(publish.build()).await?;
+            }
+            Command::Subscribe {
+                id,
+                filter,
+                options,
+                messages,
+                done,
+            } => {
+                let packet_id = self.next_id();
+                let mut subscribe = Subscribe::new(packet_id);
+                if let Err(e) = subscribe.add_topic(filter.inner().clone(), options) {
+                    let _ = done.send(Err(e.into()));
+                    return Ok(true);
+                }
+                self.routes.push(Route {
+                    id,
+                    filter,
+                    options,
+                    messages,
+                });
+                self.pending
+                    .insert(packet_id, Pending::Subscribe(vec![id], vec![done]));
+                conn.send(subscribe.build()).await?;
+            }
+            Command::Unsubscribe(id) => {
+                let route = match self.routes.iter().position(|route| route.id == id) {
+                    Some(position) => self.routes.remove(position),
+                    None => return Ok(true),
+                };
+                if self
+                    .routes
+                    .iter()
+                    .any(|r| r.filter.inner() == route.filter.inner())
+                {
+                    return Ok(true);
+                }
+                let packet_id = self.next_id();
+                let mut unsubscribe = Unsubscribe::new(packet_id);
+                unsubscribe.add_topic(route.filter.unwrap())?;
+                self.pending.insert(packet_id, Pending::Unsubscribe);
+                conn.send(unsubscribe.build()).await?;
+            }
+            Command::Disconnect(done) => {
+                let disconnect = Disconnect::new(DisconnectReasonCode::NormalDisconnection);
+                let _ = done.send(conn.send(disconnect.build()).await);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Delivers the message to every subscription matching its topic, waiting for room in
+    /// the full ones
+    async fn deliver(&self, publish: &Publish) {
+        let message = Message::from(publish);
+        for route in &self.routes {
+            if route.filter.matches(&message.topic) {
+                // a subscription dropped meanwhile is removed by its Unsubscribe command
+                let _ = route.messages.send(message.clone()).await;
+            }
+        }
+    }
+
+    fn acknowledged(&mut self, id: u16, result: Result<(), ClientError>) {
+        match self.pending.remove(&id) {
+            Some(Pending::Publish(_, Some(done))) | Some(Pending::Release(Some(done))) => {
+                let _ = done.send(result);
+            }
+            Some(Pending::Subscribe(routes, waiting)) => {
+                if result.is_err() {
+                    self.routes.retain(|route| !routes.contains(&route.id));
+                }
+                let mut waiting = waiting.into_iter();
+                if let Some(done) = waiting.next() {
+                    let _ = done.send(result);
+                }
+                // subscriptions made again after a reconnection
+                for done in waiting {
+                    let _ = done.send(Ok(()));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    async fn incoming(&mut self, conn: &mut Connection, packet: Packet) -> Result<(), ClientError> {
+        match packet {
+            Packet::Publish(publish) => match (publish.qos(), publish.packet_identifier()) {
+                (QoS::QoS1, Some(id)) => {
+                    self.deliver(&publish).await;
+                    conn.send(PubAck::new(id).build()).await?;
+                }
+                (QoS::QoS2, Some(id)) => {
+                    if self.received.insert(id) {
+                        self.deliver(&publish).await;
+                    }
+                    conn.send(PubRec::new(id).build()).await?;
+                }
+                _ => self.deliver(&publish).await,
+            },
+            Packet::PubRel(pubrel) => {
+                self.received.remove(&pubrel.identifier());
+                conn.send(PubComp::new(pubrel.identifier()).build()).await?;
+            }
+            Packet::PubAck(puback) => {
+                let code = puback.reason_code() as u8;
+                let result = match refused(code) {
+                    true => Err(ClientError::PublishRefused(code)),
+                    false => Ok(()),
+                };
+                self.acknowledged(puback.identifier(), result);
+            }
+            Packet::PubRec(pubrec) => {
+                let id = pubrec.identifier();
+                let code = pubrec.reason_code() as u8;
+                if refused(code) {
+                    self.acknowledged(id, Err(ClientError::PublishRefused(code)));
+                    return Ok(());
+                }
+                if let Some(Pending::Publish(_, done)) = self.pending.remove(&id) {
+                    self.pending.insert(id, Pending::Release(done));
+                }
+                conn.send(PubRel::new(id).build()).await?;
+            }
+            // the broker received the publish with its PUBREC, a PUBCOMP not finding it
+            // only means the session was lost meanwhile
+            Packet::PubComp(pubcomp) => self.acknowledged(pubcomp.identifier(), Ok(())),
+            Packet::SubAck(suback) => {
+                let result = match suback
+                    .reason_codes()
+                    .iter()
+                    .map(|code| *code as u8)
+                    .find(|code| refused(*code))
+                {
+                    Some(code) => Err(ClientError::SubscriptionRefused(code)),
+                    None => Ok(()),
+                };
+                self.acknowledged(suback.identifier(), result);
+            }
+            Packet::UnsubAck(unsuback) => {
+                self.pending.remove(&unsuback.identifier());
+            }
+            Packet::Disconnect(disconnect) => {
+                return Err(ClientError::DisconnectedByServer(
+                    disconnect.reason_code() as u8
+                ))
+            }
+            Packet::PingRes(_) => (),
+            _ => return Err(ClientError::UnexpectedPacket),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+//! Asynchronous MQTT v5 client built on `apiformes-packet`. A `Client` keeps a connection to
+//! a broker alive in a background task: it sends PINGREQ when idle, connects again when the
+//! connection is lost and acknowledges the QoS 1 and 2 messages it receives.
+mod config;
+mod connection;
+pub mod error;
+mod eventloop;
+#[cfg(feature = "noise")]
+mod noise;
+pub mod prelude;
+mod subscription;
+
+pub use config::ClientConfig;
+use connection::Connection;
+use error::ClientError;
+use eventloop::{Command, EventLoop};
+#[cfg(feature = "noise")]
+pub use noise::NoiseConnector;
+pub use subscription::{Message, Subscription};
+
+use apiformes_packet::prelude::*;
+use bytes::Bytes;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::{
+    mpsc::{self, UnboundedSender},
+    oneshot,
+};
+use tokio::task::JoinHandle;
+
+/// Resolves once the broker acknowledged a publish, once it was written for QoS 0
+pub struct Ack(oneshot::Receiver<Result<(), ClientError>>);
+
+impl Future for Ack {
+    type Output = Result<(), ClientError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|done| done.unwrap_or(Err(ClientError::Disconnected)))
+    }
+}
+
+/// Handle on a connection to a broker, clones share the same connection
+#[derive(Clone)]
+pub struct Client {
+    commands: UnboundedSender<Command>,
+    subscriptions: Arc<AtomicU64>,
+    queue: usize,
+}
+
+impl Client {
+    /// Connects to the broker, fails when the first connection does not succeed whatever
+    /// `reconnect_delay` is
+    pub async fn connect(cfg: ClientConfig) -> Result<Client, ClientError> {
+        let (conn, session) = Connection::open(&cfg).await?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let queue = cfg.queue;
+        tokio::spawn(EventLoop::new(cfg, rx).run(conn, session));
+        Ok(Client {
+            commands: tx,
+            subscriptions: Arc::new(AtomicU64::new(0)),
+            queue,
+        })
+    }
+    fn request(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<(), ClientError>>) -> Command,
+    ) -> Ack {
+        let (tx, rx) = oneshot::channel();
+        // the receiver sees the sender dropped along with the command when the client stopped
+        let _ = self.commands.send(command(tx));
+        Ack(rx)
+    }
+    /// Publishes `payload` on `topic`, waits for the broker to acknowledge it unless `qos`
+    /// is 0
+    pub async fn publish(
+        &self,
+        topic: impl Into<Arc<str>>,
+        payload: impl Into<Bytes>,
+        qos: QoS,
+    ) -> Result<(), ClientError> {
+        let mut publish = Publish::new(topic.into(), payload.into())?;
+        publish.set_qos(qos);
+        self.send(publish).await
+    }
+    /// Queues a publish built by the caller, e.g. retained or with properties, without
+    /// waiting for it to be acknowledged. Its packet identifier is set by the client.
+    pub fn send(&self, publish: Publish) -> Ack {
+        self.request(|done| Command::Publish(publish, done))
+    }
+    /// Subscribes to `filter` with the given QoS or options, the messages matching it are
+    /// received from the returned subscription once the broker acknowledged it
+    pub async fn subscribe(
+        &self,
+        filter: impl Into<Arc<str>>,
+        options: impl Into<SubscriptionOptions>,
+    ) -> Result<Subscription, ClientError> {
+        let id = self.subscriptions.fetch_add(1, Ordering::Relaxed);
+        let filter = TopicFilter::new(filter.into())?;
+        let (tx, rx) = mpsc::channel(self.queue);
+        // dropping it once refused finds no route left to unsubscribe
+        let subscription = Subscription::new(id, filter.inner().clone(), rx, self.commands.clone());
+        self.request(|done| Command::Subscribe {
+            id,
+            filter,
+            options: options.into(),
+            messages: tx,
+            done,
+        })
+        .await?;
+        Ok(subscription)
+    }
+    /// Subscribes to `filter` and calls `callback` with every message matching it, aborting
+    /// the returned task unsubscribes
+    pub async fn subscribe_with<F>(
+        &self,
+        filter: impl Into<Arc<str>>,
+        options: impl Into<SubscriptionOptions>,
+        mut callback: F,
+    ) -> Result<JoinHandle<()>, ClientError>
+    where
+        F: FnMut(Message) + Send + 'static,
+    {
+        let mut subscription = self.subscribe(filter, options).await?;
+        Ok(tokio::spawn(async move {
+            while let Some(message) = subscription.recv().await {
+                callback(message);
+            }
+        }))
+    }
+    /// Sends a DISCONNECT and closes the connection, the subscriptions end
+    pub async fn disconnect(&self) -> Result<(), ClientError> {
+        self.request(Command::Disconnect).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use apiformes_server_lib::prelude::{MqttServer, MqttServerConfig};
+    use futures::StreamExt;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    async fn broker() -> (MqttServer, SocketAddr) {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = MqttServer::new(MqttServerConfig {
+            mqtt_socketaddr: Some(addr),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        (server, addr)
+    }
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        let (_server, addr) = broker().await;
+        let cfg = ClientConfig::new(addr.to_string(), "subscriber");
+        let subscriber = Client::connect(cfg).await.unwrap();
+        let mut sub = subscriber.subscribe("sensors/+", QoS::QoS2).await.unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let _callback = subscriber
+            .subscribe_with("sensors/#", QoS::QoS0, move |m| tx.send(m).unwrap())
+            .await
+            .unwrap();
+        let publisher = Client::connect(ClientConfig::new(addr.to_string(), "publisher"))
+            .await
+            .unwrap();
+        for (i, qos) in [QoS::QoS0, QoS::QoS1].into_iter().enumerate() {
+            publisher
+                .publish("sensors/a", vec![i as u8], qos)
+                .await
+                .unwrap();
+        }
+        // the broker announces a MaximumQoS of 1
+        assert!(matches!(
+            publisher.publish("sensors/a", vec![], QoS::QoS2).await,
+            Err(ClientError::QoSNotSupported(2))
+        ));
+        for i in 0..2u8 {
+            let message = timeout(Duration::from_secs(5), sub.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&*message.topic, "sensors/a");
+            assert_eq!(&message.payload[..], &[i]);
+            let message = timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&message.payload[..], &[i]);
+        }
+        publisher.disconnect().await.unwrap();
+        assert!(matches!(
+            publisher.publish("sensors/a", vec![], QoS::QoS0).await,
+            Err(ClientError::Disconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let (server, addr) = broker().await;
+        let cfg = ClientConfig::new(addr.to_string(), "reconnecting")
+            .reconnect_delay(Some(Duration::from_millis(20)));
+        let client = Client::connect(cfg).await.unwrap();
+        let mut sub = client.subscribe("alerts", QoS::QoS1).await.unwrap();
+        assert_eq!(server.redirect_clients("elsewhere").await.unwrap(), 1);
+        // resubscribed once connected again
+        let publisher = Client::connect(ClientConfig::new(addr.to_string(), "publisher"))
+            .await
+            .unwrap();
+        let message = timeout(Duration::from_secs(5), async {
+            loop {
+                publisher
+                    .publish("alerts", "fire", QoS::QoS1)
+                    .await
+                    .unwrap();
+                if let Ok(Some(message)) = timeout(Duration::from_millis(50), sub.recv()).await {
+                    return message;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(&message.payload[..], b"fire");
+    }
+}
@@ -0,0 +1,231 @@
+use crate::error::ClientError;
+use apiformes_packet::prelude::*;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use snow::TransportState;
+use std::fmt;
+use std::io::Cursor;
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// Default pattern of the broker's NoiseListener, the client takes the initiator role
+const NOISE_PATTERN: &str = "Noise_XK_25519_ChaChaPoly_BLAKE2s";
+
+/// Size of the authentication tag a Noise message adds to its payload
+const TAG_LEN: usize = 16;
+
+/// Connects to the Noise listener of an apiformes broker. The broker's static public key is
+/// pinned: the handshake only completes with a broker holding the matching private key.
+/// The broker learns the static public key of the client, which `PublicKeyClientIds` turns
+/// into its clientid.
+#[derive(Clone)]
+pub struct NoiseConnector {
+    private_key: [u8; 32],
+    broker_key: [u8; 32],
+    pattern: String,
+}
+
+impl fmt::Debug for NoiseConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NoiseConnector({:x?})", self.broker_key)
+    }
+}
+
+impl NoiseConnector {
+    /// `private_key` is the static key of the client, `broker_key` the static public key of
+    /// the broker
+    pub fn new(private_key: [u8; 32], broker_key: [u8; 32]) -> Self {
+        NoiseConnector {
+            private_key,
+            broker_key,
+            pattern: NOISE_PATTERN.to_owned(),
+        }
+    }
+    /// Pattern matching `MqttServerConfig::noise_pattern` of the broker. With the patterns
+    /// where the broker sends its static key during the handshake, e.g. XX, the key is
+    /// checked against the pinned one once received.
+    pub fn pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.pattern = pattern.into();
+        self
+    }
+    /// Generates a static key pair for the pattern, returned as (private, public)
+    pub fn generate_keypair() -> Result<([u8; 32], [u8; 32]), ClientError> {
+        let keypair = snow::Builder::new(NOISE_PATTERN.parse()?).generate_keypair()?;
+        let mut private = [0; 32];
+        let mut public = [0; 32];
+        private.copy_from_slice(&keypair.private);
+        public.copy_from_slice(&keypair.public);
+        Ok((private, public))
+    }
+    /// Runs the handshake over `stream` as the initiator
+    pub(crate) async fn handshake(&self, stream: TcpStream) -> Result<NoiseStream, ClientError> {
+        let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
+        let params: snow::params::NoiseParams = self.pattern.parse()?;
+        let known = params.handshake.pattern.need_known_remote_pubkey(true);
+        let mut builder = snow::Builder::new(params).local_private_key(&self.private_key[..]);
+        if known {
+            builder = builder.remote_public_key(&self.broker_key[..]);
+        }
+        let mut initiator = builder.build_initiator()?;
+        let mut buf = [0; 200];
+
+        while !initiator.is_handshake_finished() {
+            if initiator.is_my_turn() {
+                let size = initiator.write_message(&[], &mut buf)?;
+                stream.send(Bytes::copy_from_slice(&buf[..size])).await?;
+            } else {
+                // the broker drops the connection when it does not hold the pinned key or
+                // does not accept ours
+                let frame = match stream.next().await {
+                    Some(Ok(frame)) => frame,
+                    _ => return Err(ClientError::HandshakeRejected),
+                };
+                initiator.read_message(&frame[..], &mut [])?;
+                // checked before our static key is sent
+                if matches!(initiator.get_remote_static(), Some(key) if key != self.broker_key) {
+                    return Err(ClientError::HandshakeRejected);
+                }
+            }
+        }
+        if initiator.get_remote_static() != Some(&self.broker_key[..]) {
+            return Err(ClientError::HandshakeRejected);
+        }
+
+        Ok(NoiseStream {
+            stream,
+            crypto: initiator.into_transport_mode()?,
+        })
+    }
+}
+
+/// An established Noise channel, each frame carries a single packet
+pub(crate) struct NoiseStream {
+    stream: Framed<TcpStream, LengthDelimitedCodec>,
+    crypto: TransportState,
+}
+
+impl NoiseStream {
+    /// Waits for the next packet, cancelling it loses no data
+    pub(crate) async fn recv(&mut self) -> Result<Packet, ClientError> {
+        let frame = self
+            .stream
+            .next()
+            .await
+            .ok_or(ClientError::Disconnected)??;
+        let mut message = vec![0; frame.len()];
+        let size = self.crypto.read_message(&frame[..], &mut message)?;
+        Ok(Packet::from_bytes(&mut Cursor::new(&message[..size]))?)
+    }
+    pub(crate) async fn send(&mut self, packet: Packet) -> Result<(), ClientError> {
+        let mut bytes = Vec::with_capacity(packet.frame_len());
+        packet.to_bytes(&mut bytes);
+        let mut frame = vec![0; bytes.len() + TAG_LEN];
+        let size = self.crypto.write_message(&bytes, &mut frame)?;
+        self.stream
+            .send(Bytes::copy_from_slice(&frame[..size]))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use apiformes_server_lib::prelude::{MqttServer, MqttServerConfig, Permeability};
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tokio::time::{timeout, Duration};
+
+    async fn noise_broker(cfg: MqttServerConfig) -> (MqttServer, SocketAddr) {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = MqttServer::new(MqttServerConfig {
+            mqtt_socketaddr: None,
+            noise_socketaddr: Some(addr),
+            channel_permeability: Permeability::Permissive,
+            ..cfg
+        })
+        .await
+        .unwrap();
+        (server, addr)
+    }
+
+    #[tokio::test]
+    async fn test_noise_connector() {
+        let (broker_private, broker_public) = NoiseConnector::generate_keypair().unwrap();
+        let (client_private, _) = NoiseConnector::generate_keypair().unwrap();
+        let (_server, addr) = noise_broker(MqttServerConfig {
+            private_key: broker_private,
+            ..Default::default()
+        })
+        .await;
+        let cfg = ClientConfig::new(addr.to_string(), "encrypted")
+            .noise(NoiseConnector::new(client_private, broker_public));
+        let client = Client::connect(cfg).await.unwrap();
+        let mut sub = client.subscribe("secrets", QoS::QoS1).await.unwrap();
+        client
+            .publish("secrets", "hidden", QoS::QoS1)
+            .await
+            .unwrap();
+        let message = timeout(Duration::from_secs(5), sub.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&message.payload[..], b"hidden");
+
+        // a broker holding another key is refused
+        let (_, other_public) = NoiseConnector::generate_keypair().unwrap();
+        let cfg = ClientConfig::new(addr.to_string(), "pinned")
+            .noise(NoiseConnector::new(client_private, other_public));
+        assert!(matches!(
+            Client::connect(cfg).await,
+            Err(ClientError::HandshakeRejected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_noise_rotation() {
+        const XX: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+        let (old_private, old_public) = NoiseConnector::generate_keypair().unwrap();
+        let (new_private, new_public) = NoiseConnector::generate_keypair().unwrap();
+        let (client_private, client_public) = NoiseConnector::generate_keypair().unwrap();
+        let (stranger_private, _) = NoiseConnector::generate_keypair().unwrap();
+        let (server, addr) = noise_broker(MqttServerConfig {
+            private_key: old_private,
+            noise_pattern: XX.to_owned(),
+            noise_accepted_keys: Some(vec![client_public]),
+            ..Default::default()
+        })
+        .await;
+        let connect = |clientid: &str, private_key, broker_key| {
+            let connector = NoiseConnector::new(private_key, broker_key).pattern(XX);
+            let cfg = ClientConfig::new(addr.to_string(), clientid)
+                .reconnect_delay(None)
+                .noise(connector);
+            Client::connect(cfg)
+        };
+        let before = connect("before", client_private, old_public).await.unwrap();
+        let mut sub = before.subscribe("keys", QoS::QoS1).await.unwrap();
+        // the broker drops clients whose key it does not accept
+        assert!(connect("stranger", stranger_private, old_public)
+            .await
+            .is_err());
+
+        server.rotate_noise_key(new_private).unwrap();
+        assert!(matches!(
+            connect("stale", client_private, old_public).await,
+            Err(ClientError::HandshakeRejected)
+        ));
+        let after = connect("after", client_private, new_public).await.unwrap();
+        after.publish("keys", "rotated", QoS::QoS1).await.unwrap();
+        // the session opened with the old key survived the rotation
+        let message = timeout(Duration::from_secs(5), sub.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&message.payload[..], b"rotated");
+    }
+}
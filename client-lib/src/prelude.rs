@@ -0,0 +1,7 @@
+//! The public API of the client, `use apiformes_client_lib::prelude::*` brings the packet
+//! types along
+pub use crate::error::ClientError;
+#[cfg(feature = "noise")]
+pub use crate::NoiseConnector;
+pub use crate::{Client, ClientConfig, Message, Subscription};
+pub use apiformes_packet::prelude::*;
@@ -0,0 +1,76 @@
+use crate::eventloop::Command;
+use apiformes_packet::prelude::*;
+use bytes::Bytes;
+use futures::Stream;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc::{Receiver, UnboundedSender};
+
+/// An application message delivered to a `Subscription`
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub topic: Arc<str>,
+    pub payload: Bytes,
+    pub qos: QoS,
+    pub retain: bool,
+    pub properties: Vec<(Property, MqttPropValue)>,
+}
+
+impl From<&Publish> for Message {
+    fn from(publish: &Publish) -> Self {
+        Message {
+            topic: publish.topic_name().clone(),
+            payload: publish.payload(),
+            qos: publish.qos(),
+            retain: publish.flags().contains(PublishFlags::RETAIN),
+            properties: publish.props_iter().map(|(k, v)| (*k, v.clone())).collect(),
+        }
+    }
+}
+
+/// Stream of the messages matching a topic filter, returned by `Client::subscribe`.
+/// Dropping it unsubscribes from the broker once no other subscription uses the filter.
+pub struct Subscription {
+    id: u64,
+    filter: Arc<str>,
+    messages: Receiver<Message>,
+    commands: UnboundedSender<Command>,
+}
+
+impl Subscription {
+    pub(crate) fn new(
+        id: u64,
+        filter: Arc<str>,
+        messages: Receiver<Message>,
+        commands: UnboundedSender<Command>,
+    ) -> Self {
+        Subscription {
+            id,
+            filter,
+            messages,
+            commands,
+        }
+    }
+    pub fn filter(&self) -> &Arc<str> {
+        &self.filter
+    }
+    /// Waits for the next message, `None` once the client stopped
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.messages.recv().await
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Message;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.messages.poll_recv(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // the client may be gone already, there is nothing left to unsubscribe from then
+        let _ = self.commands.send(Command::Unsubscribe(self.id));
+    }
+}
@@ -0,0 +1,100 @@
+/// How the captured bytes are written down
+#[derive(Clone, Copy)]
+pub enum Format {
+    /// Hex digits, e.g. a "Copy as Hex Stream" from Wireshark or the `tcp.payload` field
+    /// printed by tshark. Whitespace, `:` separators and `0x` prefixes are ignored.
+    Hex,
+    Base64,
+    /// The bytes themselves
+    Raw,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "hex" => Some(Format::Hex),
+            "base64" => Some(Format::Base64),
+            "raw" => Some(Format::Raw),
+            _ => None,
+        }
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn decode_hex(line: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = line
+        .split(|c: char| c.is_whitespace() || c == ':')
+        .map(|word| word.strip_prefix("0x").unwrap_or(word))
+        .flat_map(|word| word.bytes())
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".to_owned());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| match (hex_digit(pair[0]), hex_digit(pair[1])) {
+            (Some(high), Some(low)) => Ok(high << 4 | low),
+            _ => Err(format!(
+                "invalid hex digits `{}`",
+                String::from_utf8_lossy(pair)
+            )),
+        })
+        .collect()
+}
+
+/// Turns the captured input into the byte stream it describes. Text formats hold one chunk
+/// per line, e.g. one TCP segment, chunks are concatenated since packets may span several
+/// of them. Empty lines and lines starting with `#` are skipped.
+pub fn decode(input: &[u8], format: Format) -> Result<Vec<u8>, String> {
+    let text = match format {
+        Format::Raw => return Ok(input.to_vec()),
+        Format::Hex | Format::Base64 => {
+            std::str::from_utf8(input).map_err(|e| format!("input is not text, {}", e))?
+        }
+    };
+    let mut bytes = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let chunk = match format {
+            Format::Hex => decode_hex(line),
+            Format::Base64 => base64::decode(line).map_err(|e| e.to_string()),
+            Format::Raw => unreachable!(),
+        };
+        bytes.extend(chunk.map_err(|e| format!("line {}: {}", n + 1, e))?);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_decode() {
+        let hex = b"# pingreq then pingresp\nc0 00\n\nd0:00\n0xe000\n";
+        assert_eq!(
+            decode(hex, Format::Hex).unwrap(),
+            [0xc0, 0x00, 0xd0, 0x00, 0xe0, 0x00]
+        );
+        assert_eq!(
+            decode(b"wAA=\n0AA=\n", Format::Base64).unwrap(),
+            [0xc0, 0x00, 0xd0, 0x00]
+        );
+        assert_eq!(decode(&[0xc0, 0x00], Format::Raw).unwrap(), [0xc0, 0x00]);
+        assert_eq!(
+            decode(b"c0 00\nc0 0\n", Format::Hex).unwrap_err(),
+            "line 2: odd number of hex digits"
+        );
+        assert!(decode(b"zz", Format::Hex).is_err());
+    }
+}
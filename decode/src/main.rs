@@ -0,0 +1,87 @@
+#[macro_use]
+extern crate clap;
+
+mod input;
+
+use apiformes_packet::prelude::*;
+use clap::App;
+use input::Format;
+use std::fs;
+use std::io::{self, Read};
+use std::process::exit;
+
+fn read_input(path: Option<&str>) -> io::Result<Vec<u8>> {
+    match path {
+        Some(path) => fs::read(path),
+        None => {
+            let mut input = Vec::new();
+            io::stdin().read_to_end(&mut input)?;
+            Ok(input)
+        }
+    }
+}
+
+/// Prints every packet of `bytes`, returns false if the stream could not be parsed to its end
+fn print_packets(bytes: &[u8], strict: bool) -> bool {
+    let mut offset = 0;
+    let mut index = 0;
+    while offset < bytes.len() {
+        let mut buf = &bytes[offset..];
+        let parsed = if strict {
+            Packet::from_bytes_strict(&mut buf)
+        } else {
+            Packet::from_bytes(&mut buf)
+        };
+        match parsed {
+            Ok(packet) => {
+                let len = bytes.len() - offset - buf.len();
+                println!("#{} at offset {}, {} bytes", index, offset, len);
+                println!("{:#?}", packet);
+                offset += len;
+                index += 1;
+            }
+            Err(DataParseError::InsufficientBuffer { .. }) => {
+                eprintln!(
+                    "offset {}: truncated packet, only {} bytes left",
+                    offset,
+                    bytes.len() - offset
+                );
+                return false;
+            }
+            Err(e) => {
+                // without a valid fixed header there is no way to find the next packet
+                eprintln!("offset {}: {:?}", offset, e);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn main() {
+    let yaml = load_yaml!("cli.yaml");
+    let matches = App::from_yaml(yaml)
+        .version(crate_version!())
+        .version_short("v")
+        .get_matches();
+
+    // clap already restricted the value to the known formats
+    let format = Format::from_name(matches.value_of("Format").unwrap()).unwrap();
+    let input = match read_input(matches.value_of("Input")) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Failed reading the input, {}", e);
+            exit(1);
+        }
+    };
+    let bytes = match input::decode(&input, format) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Failed decoding the input, {}", e);
+            exit(1);
+        }
+    };
+    if !print_packets(&bytes, matches.is_present("Strict")) {
+        exit(1);
+    }
+}
@@ -5,5 +5,7 @@ use libfuzzer_sys::fuzz_target;
 
 fuzz_target!(|data: &[u8]| {
     let mut bytes = Bytes::copy_from_slice(data);
-    let _ = Packet::from_bytes(&mut bytes);
+    if let Ok(packet) = Packet::from_bytes(&mut bytes) {
+        let _ = packet.validate();
+    }
 });
@@ -8,12 +8,13 @@ use super::{
 };
 use bytes::{Buf, BufMut};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct Auth {
     // 3.14.2.1 Auth Reason Code
     reason_code: AuthReasonCode,
     // 3.14.2.2 AUTH Properties
-    props: Properties,
+    pub(crate) props: Properties,
 }
 
 impl Auth {
@@ -32,6 +33,9 @@ impl Auth {
     pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
         self.props.get(key)
     }
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        self.props.remove(key)
+    }
     pub fn props_iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props.iter()
     }
@@ -26,18 +26,20 @@ impl MqttUncheckedDeserialize for ConnAckFlags {
     }
     fn unchecked_deserialize<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
         let raw_flags = MqttOneBytesInt::unchecked_deserialize(buf)?;
-        ConnAckFlags::from_bits(raw_flags.inner()).ok_or(DataParseError::BadConnectMessage)
+        // 3.2.2.1: bits 7-1 are reserved and must be 0
+        ConnAckFlags::from_bits(raw_flags.inner()).ok_or(DataParseError::BadConnAckMessage)
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct ConnAck {
     // 3.2.2.1 Connect Acknowledge Flags
     flags: ConnAckFlags,
     // 3.2.2.2 Connect Reason Code
     reason_code: ConnAckReasonCode,
     // 3.2.2.3 CONNACK Properties
-    props: Properties,
+    pub(crate) props: Properties,
 }
 
 impl Default for ConnAck {
@@ -72,6 +74,9 @@ impl ConnAck {
     pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
         self.props.get(key)
     }
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        self.props.remove(key)
+    }
     pub fn props_iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props.iter()
     }
@@ -96,8 +101,9 @@ impl MqttSerialize for ConnAck {
 impl MqttDeserialize for ConnAck {
     fn deserialize<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
         let length = MqttVariableBytesInt::deserialize(buf)?.inner() as usize;
-        if length < ConnAck::min_size() - MqttVariableBytesInt::min_size() {
-            // one for flags, one for reason_code and one for empty props
+        // one for flags and one for reason_code, the property length may be omitted when
+        // there are no properties
+        if length < ConnAck::min_size() - MqttVariableBytesInt::min_size() - 1 {
             return Err(DataParseError::BadConnAckMessage);
         }
         if buf.remaining() < length {
@@ -109,20 +115,22 @@ impl MqttDeserialize for ConnAck {
         let mut buf = buf.take(length);
         let flags = ConnAckFlags::unchecked_deserialize(&mut buf)?;
         let reason_code = ConnAckReasonCode::unchecked_deserialize(&mut buf)?;
-        let props = Properties::deserialize(&mut buf)?;
+        let props = if buf.has_remaining() {
+            Properties::deserialize(&mut buf)?
+        } else {
+            Properties::new()
+        };
         if !props.is_valid_for(PropOwner::CONNACK) {
             return Err(DataParseError::BadProperty);
         }
-        let packet = ConnAck {
+        if buf.has_remaining() {
+            return Err(DataParseError::BadConnAckMessage);
+        }
+        Ok(ConnAck {
             flags,
             reason_code,
             props,
-        };
-        if packet.partial_size() == length {
-            Ok(packet)
-        } else {
-            Err(DataParseError::BadConnectMessage)
-        }
+        })
     }
 }
 impl MqttSize for ConnAck {
@@ -173,4 +181,28 @@ mod test {
         connack2.serialize(&mut b2);
         assert_eq!(b, b2);
     }
+    #[test]
+    fn test_connack_short_form() {
+        let connack = ConnAck::deserialize(&mut &[0x02, 0x01, 0x00][..]).unwrap();
+        assert!(connack.flags().contains(ConnAckFlags::SESSION_PRESENT));
+        assert!(matches!(connack.reason_code(), ConnAckReasonCode::Success));
+        assert_eq!(connack.props_iter().count(), 0);
+        let mut b = BytesMut::new();
+        connack.serialize(&mut b);
+        assert_eq!(b, &[0x03, 0x01, 0x00, 0x00][..]);
+    }
+    #[test]
+    fn test_connack_malformed() {
+        for bad in [
+            &[0x03, 0x02, 0x00, 0x00][..],       // reserved flag
+            &[0x03, 0x80, 0x00, 0x00][..],       // reserved flag
+            &[0x01, 0x00][..],                   // no reason code
+            &[0x04, 0x00, 0x00, 0x00, 0x00][..], // trailing byte
+        ] {
+            assert!(matches!(
+                ConnAck::deserialize(&mut &bad[..]),
+                Err(DataParseError::BadConnAckMessage)
+            ));
+        }
+    }
 }
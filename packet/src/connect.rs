@@ -82,10 +82,11 @@ impl TryInto<QoS> for ConnectFlags {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct Will {
     // 3.1.3.2 Will Properties
-    props: Properties,
+    pub(crate) props: Properties,
     // 3.1.3.3 Will Topic
     topic: MqttUtf8String,
     // 3.1.3.4 Will Payload
@@ -106,6 +107,9 @@ impl Will {
     pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
         self.props.get(key)
     }
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        self.props.remove(key)
+    }
     pub fn props_iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props.iter()
     }
@@ -116,7 +120,7 @@ impl Will {
         self.topic = MqttUtf8String::new(topic)?;
         Ok(())
     }
-    pub fn payload(&mut self) -> &Bytes {
+    pub fn payload(&self) -> &Bytes {
         self.payload.inner()
     }
     pub fn set_payload<T: Buf>(&mut self, buf: T) -> Result<(), DataParseError> {
@@ -155,14 +159,15 @@ impl MqttSize for Will {
 }
 
 // 3.1 CONNECT – Connection Request
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct Connect {
     // 3.1.2.3 Connect Flags
     flags: ConnectFlags,
     // 3.1.2.10 Keep Alive
     keep_alive: MqttTwoBytesInt,
     // 3.1.2.11 CONNECT Properties
-    props: Properties,
+    pub(crate) props: Properties,
 
     // 3.1.3 CONNECT Payload
 
@@ -252,6 +257,9 @@ impl Connect {
     pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
         self.props.get(key)
     }
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        self.props.remove(key)
+    }
     pub fn props_iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props.iter()
     }
@@ -272,6 +280,54 @@ impl Connect {
     }
 }
 
+/// Builds a `Connect`, the QoS and retain flag of the will are given along with it
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub struct ConnectBuilder {
+    connect: Connect,
+}
+
+impl Connect {
+    pub fn builder(clientid: Arc<str>) -> Result<ConnectBuilder, DataParseError> {
+        Ok(ConnectBuilder {
+            connect: Connect::new(clientid)?,
+        })
+    }
+}
+
+impl ConnectBuilder {
+    pub fn clean_start(mut self) -> Self {
+        self.connect.set_clean_start();
+        self
+    }
+    pub fn keep_alive(mut self, keep_alive: u16) -> Self {
+        self.connect.set_keep_alive(keep_alive);
+        self
+    }
+    pub fn username(mut self, username: Arc<str>) -> Result<Self, DataParseError> {
+        self.connect.set_username(username)?;
+        Ok(self)
+    }
+    pub fn password<T: Buf>(mut self, password: T) -> Result<Self, DataParseError> {
+        self.connect.set_password(password)?;
+        Ok(self)
+    }
+    pub fn will(mut self, will: Will, qos: QoS, retain: bool) -> Self {
+        let flags = &mut self.connect.flags;
+        *flags -= ConnectFlags::WILL_QOS1 | ConnectFlags::WILL_QOS2 | ConnectFlags::WILL_RETAIN;
+        *flags |= ConnectFlags::WILL | qos.into();
+        flags.set(ConnectFlags::WILL_RETAIN, retain);
+        self.connect.will_info = Some(will);
+        self
+    }
+    pub fn prop(mut self, key: Property, value: MqttPropValue) -> Result<Self, DataParseError> {
+        self.connect.add_prop(key, value)?;
+        Ok(self)
+    }
+    pub fn build(self) -> Connect {
+        self.connect
+    }
+}
+
 impl MqttSerialize for Connect {
     fn serialize<T: BufMut>(&self, buf: &mut T) {
         let length = MqttVariableBytesInt::new(self.partial_size() as u32)
@@ -430,4 +486,25 @@ mod test {
             DataParseError::BadQoS
         );
     }
+    #[test]
+    fn test_connect_builder() {
+        let will = || Will::new(Arc::from("Hello"), Bytes::from(&b"World"[..])).unwrap();
+        let mut connect = Connect::new(Arc::from("Client1")).unwrap();
+        connect.set_clean_start();
+        connect.set_will(will());
+        connect.set_will_qos(QoS::QoS1).unwrap();
+        connect.set_will_retain().unwrap();
+        connect.set_username(Arc::from("apiformes")).unwrap();
+        connect.set_keep_alive(5);
+        let built = Connect::builder(Arc::from("Client1"))
+            .unwrap()
+            .clean_start()
+            .will(will(), QoS::QoS2, false)
+            .will(will(), QoS::QoS1, true)
+            .username(Arc::from("apiformes"))
+            .unwrap()
+            .keep_alive(5)
+            .build();
+        assert!(built == connect);
+    }
 }
@@ -6,7 +6,7 @@ use bytes::{Buf, BufMut, Bytes};
 use std::fmt;
 use std::sync::Arc;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub(super) struct MqttOneBytesInt(u8);
 impl MqttOneBytesInt {
     pub(super) fn new(i: u8) -> Self {
@@ -32,8 +32,15 @@ impl MqttSerialize for MqttOneBytesInt {
     }
 }
 
+#[cfg(feature = "debug")]
+impl fmt::Debug for MqttOneBytesInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "0x{:02x}", self.0)
+    }
+}
+
 /// 1.5.2 Two Byte Integer
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub(super) struct MqttTwoBytesInt(u16);
 
 impl MqttTwoBytesInt {
@@ -68,7 +75,7 @@ impl fmt::Debug for MqttTwoBytesInt {
 }
 
 /// 1.5.3 Four Byte Integer
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub(super) struct MqttFourBytesInt(u32);
 
 impl MqttFourBytesInt {
@@ -103,7 +110,7 @@ impl fmt::Debug for MqttFourBytesInt {
 }
 
 /// 1.5.4 UTF-8 Encoded String
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub(super) struct MqttUtf8String {
     s: Arc<str>,
 }
@@ -135,6 +142,7 @@ impl MqttUtf8String {
     /// - U+007F..U+009F control characters
     /// - Code points defined in the Unicode specification [Unicode] to be
     ///   non-characters (for example U+0FFFF)
+    ///
     /// A UTF-8 encoded sequence 0xEF 0xBB 0xBF is always interpreted as U+FEFF ("ZERO
     /// WIDTH NO-BREAK SPACE") wherever it appears in a string and MUST NOT be skipped
     /// over or stripped off by a packet receiver.
@@ -191,7 +199,7 @@ impl fmt::Debug for MqttUtf8String {
 }
 
 /// 1.5.5 Variable Byte Integer
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub(super) struct MqttVariableBytesInt {
     i: u32,
 }
@@ -212,6 +220,17 @@ impl MqttVariableBytesInt {
         }
         Ok(())
     }
+
+    /// Same as `deserialize` but rejects values that are not encoded using the
+    /// minimum number of bytes as required by section 1.5.5 (e.g. `0x80 0x00`)
+    pub(super) fn deserialize_minimal<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
+        let before = buf.remaining();
+        let i = MqttVariableBytesInt::deserialize(buf)?;
+        if before - buf.remaining() != i.size() {
+            return Err(DataParseError::MalformedPacket);
+        }
+        Ok(i)
+    }
 }
 impl MqttSerialize for MqttVariableBytesInt {
     fn serialize<T: BufMut>(&self, buf: &mut T) {
@@ -282,7 +301,7 @@ impl fmt::Debug for MqttVariableBytesInt {
 }
 
 /// 1.5.6 Binary Data
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub(super) struct MqttBinaryData {
     d: Bytes,
 }
@@ -350,7 +369,7 @@ impl fmt::Debug for MqttBinaryData {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub(super) struct MqttUtf8StringPair {
     pub(super) name: MqttUtf8String,
     pub(super) value: MqttUtf8String,
@@ -630,6 +649,30 @@ mod test {
         };
     }
 
+    #[test]
+    fn test_serde_data_variable_byte_int_non_minimal() {
+        for encoded in [
+            &[0x80, 0x00][..],
+            &[0xff, 0x00][..],
+            &[0x80, 0x80, 0x00][..],
+            &[0x80, 0x80, 0x80, 0x00][..],
+            &[0xff, 0xff, 0x80, 0x00][..],
+        ] {
+            let i = MqttVariableBytesInt::deserialize(&mut Bytes::from(encoded)).unwrap();
+            assert!(i.size() < encoded.len());
+            assert_eq!(
+                MqttVariableBytesInt::deserialize_minimal(&mut Bytes::from(encoded)).err(),
+                Some(DataParseError::MalformedPacket)
+            );
+        }
+        for i in [0, 0x7f, 0x80, 0x3fff, 0x4000, 0x1fffff, 0x200000, 0xfffffff] {
+            let mut buf = BytesMut::new();
+            MqttVariableBytesInt::new(i).unwrap().serialize(&mut buf);
+            let i2 = MqttVariableBytesInt::deserialize_minimal(&mut buf).unwrap();
+            assert_eq!(i2.inner(), i);
+        }
+    }
+
     #[test]
     #[cfg(feature = "debug")]
     fn test_format_data_binary_data_string() {
@@ -0,0 +1,262 @@
+// Incremental decoding of packets read in arbitrary chunks, e.g. from partial TCP reads.
+// `Packet::from_bytes` needs the whole frame at hand, the decoder buffers the chunks until
+// a frame is complete and only then parses it.
+
+use super::{error::DataParseError, packet::Packet, version::ProtocolVersion};
+use bytes::{Buf, BytesMut};
+
+/// Outcome of `PacketDecoder::decode`
+#[derive(PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub enum Decoded {
+    Packet(Packet),
+    /// At least that many more bytes are needed before the next packet can be decoded
+    NeedMoreData(usize),
+}
+
+/// Decodes packets from the byte chunks it is fed, in any size
+#[derive(Default)]
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub struct PacketDecoder {
+    buf: BytesMut,
+    // length of the frame being received once its fixed header is complete
+    frame_len: Option<usize>,
+    version: ProtocolVersion,
+    max_packet_size: Option<usize>,
+}
+
+impl PacketDecoder {
+    pub fn new() -> Self {
+        PacketDecoder::default()
+    }
+    /// Decodes packets encoded for `version`, MQTT 5 by default
+    pub fn version(mut self, version: ProtocolVersion) -> Self {
+        self.version = version;
+        self
+    }
+    /// Frames over `max` bytes are rejected with `DataParseError::MalformedPacket` as soon
+    /// as their fixed header is received, instead of being buffered
+    pub fn max_packet_size(mut self, max: usize) -> Self {
+        self.max_packet_size = Some(max);
+        self
+    }
+    /// Switches versions once the CONNECT packet told which one the connection uses
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+    /// Appends `chunk` to the bytes received so far
+    pub fn feed(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+    /// Number of bytes fed and not decoded yet
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+    /// The next packet out of the bytes fed so far, call it again until it needs more
+    /// data as one chunk may hold several packets
+    pub fn decode(&mut self) -> Result<Decoded, DataParseError> {
+        let mut buf = std::mem::take(&mut self.buf);
+        let decoded = self.decode_from(&mut buf);
+        self.buf = buf;
+        decoded
+    }
+    /// Same as `decode` reading from `buf` instead of the bytes fed, the frame of the
+    /// decoded packet is removed from `buf`
+    pub fn decode_from(&mut self, buf: &mut BytesMut) -> Result<Decoded, DataParseError> {
+        let frame_len = match self.frame_len {
+            Some(frame_len) => frame_len,
+            None => match Packet::peek_frame_len(buf)? {
+                Some(frame_len) => {
+                    if self.max_packet_size.is_some_and(|max| frame_len > max) {
+                        return Err(DataParseError::MalformedPacket);
+                    }
+                    self.frame_len = Some(frame_len);
+                    frame_len
+                }
+                // the fixed header is at least 2 bytes, the remaining length goes on while
+                // its last byte has the continuation bit
+                None => {
+                    return Ok(Decoded::NeedMoreData(
+                        2usize.saturating_sub(buf.len()).max(1),
+                    ))
+                }
+            },
+        };
+        if buf.len() < frame_len {
+            return Ok(Decoded::NeedMoreData(frame_len - buf.len()));
+        }
+        self.frame_len = None;
+        let mut frame = buf.split_to(frame_len).freeze();
+        let packet = Packet::from_bytes_versioned(&mut frame, self.version)?;
+        if frame.has_remaining() {
+            return Err(DataParseError::MalformedPacket);
+        }
+        Ok(Decoded::Packet(packet))
+    }
+}
+
+#[cfg(feature = "codec")]
+mod codec {
+    use super::{Decoded, PacketDecoder};
+    use crate::{error::DataParseError, packet::Packet, version::ProtocolVersion};
+    use bytes::BytesMut;
+    use std::io;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    #[derive(Debug)]
+    pub enum CodecError {
+        Io(io::Error),
+        Parse(DataParseError),
+    }
+
+    impl From<io::Error> for CodecError {
+        fn from(e: io::Error) -> Self {
+            CodecError::Io(e)
+        }
+    }
+
+    impl From<DataParseError> for CodecError {
+        fn from(e: DataParseError) -> Self {
+            CodecError::Parse(e)
+        }
+    }
+
+    /// Frames a byte stream into packets, for `tokio_util::codec::Framed`
+    #[derive(Default)]
+    #[cfg_attr(feature = "debug", derive(Debug))]
+    pub struct PacketCodec {
+        decoder: PacketDecoder,
+    }
+
+    impl PacketCodec {
+        pub fn new(decoder: PacketDecoder) -> Self {
+            PacketCodec { decoder }
+        }
+        /// Packets are decoded and encoded for `version` from now on
+        pub fn set_version(&mut self, version: ProtocolVersion) {
+            self.decoder.set_version(version);
+        }
+    }
+
+    impl Decoder for PacketCodec {
+        type Item = Packet;
+        type Error = CodecError;
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Packet>, CodecError> {
+            match self.decoder.decode_from(src)? {
+                Decoded::Packet(packet) => Ok(Some(packet)),
+                Decoded::NeedMoreData(n) => {
+                    src.reserve(n);
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    impl Encoder<Packet> for PacketCodec {
+        type Error = CodecError;
+        fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<(), CodecError> {
+            let version = self.decoder.version;
+            dst.reserve(packet.frame_len_versioned(version));
+            packet.to_bytes_versioned(dst, version);
+            Ok(())
+        }
+    }
+}
+#[cfg(feature = "codec")]
+pub use codec::{CodecError, PacketCodec};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prelude::*;
+    use bytes::{BufMut, Bytes};
+    use std::sync::Arc;
+
+    fn frames() -> BytesMut {
+        let mut buf = BytesMut::new();
+        let publish = Publish::new(Arc::from("a/b"), Bytes::from(vec![7u8; 300])).unwrap();
+        Packet::Publish(publish).to_bytes(&mut buf);
+        Packet::ping_req().to_bytes(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_decode_chunks() {
+        let frames = frames();
+        for chunk_len in [1, 2, 3, 7, 64, frames.len()] {
+            let mut decoder = PacketDecoder::new();
+            let mut packets = Vec::new();
+            for chunk in frames.chunks(chunk_len) {
+                decoder.feed(chunk);
+                while let Decoded::Packet(packet) = decoder.decode().unwrap() {
+                    packets.push(packet);
+                }
+            }
+            assert_eq!(packets.len(), 2);
+            assert!(matches!(&packets[0], Packet::Publish(p) if p.payload().len() == 300));
+            assert!(matches!(packets[1], Packet::PingReq(_)));
+            assert_eq!(decoder.buffered(), 0);
+        }
+    }
+
+    #[test]
+    fn test_need_more_data() {
+        let frames = frames();
+        let mut decoder = PacketDecoder::new();
+        assert!(matches!(decoder.decode(), Ok(Decoded::NeedMoreData(2))));
+        decoder.feed(&frames[..1]);
+        assert!(matches!(decoder.decode(), Ok(Decoded::NeedMoreData(1))));
+        // 2 bytes of remaining length, the first one has the continuation bit
+        decoder.feed(&frames[1..2]);
+        assert!(matches!(decoder.decode(), Ok(Decoded::NeedMoreData(1))));
+        decoder.feed(&frames[2..10]);
+        let frame_len = frames.len() - 2;
+        assert!(matches!(decoder.decode(), Ok(Decoded::NeedMoreData(n)) if n == frame_len - 10));
+
+        let mut limited = PacketDecoder::new().max_packet_size(64);
+        limited.feed(&frames[..3]);
+        assert_eq!(
+            limited.decode().err(),
+            Some(DataParseError::MalformedPacket)
+        );
+
+        let mut bad_length = PacketDecoder::new();
+        bad_length.feed(&[0x30, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(
+            bad_length.decode().err(),
+            Some(DataParseError::BadMqttVariableBytesInt)
+        );
+
+        // a PUBACK declaring a byte it does not use
+        let mut trailing = PacketDecoder::new();
+        let mut buf = BytesMut::new();
+        buf.put_slice(&[0x40, 0x05, 0x00, 0x01, 0x00, 0x00, 0xff]);
+        assert_eq!(
+            trailing.decode_from(&mut buf).err(),
+            Some(DataParseError::MalformedPacket)
+        );
+    }
+
+    #[cfg(feature = "codec")]
+    #[test]
+    fn test_codec() {
+        use tokio_util::codec::{Decoder, Encoder};
+        let mut codec = PacketCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode(Packet::ping_req(), &mut buf).unwrap();
+        codec.set_version(ProtocolVersion::V311);
+        codec.encode(Packet::ping_res(), &mut buf).unwrap();
+        let mut partial = buf.split_to(1);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        assert!(matches!(
+            codec.decode(&mut partial),
+            Ok(Some(Packet::PingReq(_)))
+        ));
+        assert!(matches!(
+            codec.decode(&mut partial),
+            Ok(Some(Packet::PingRes(_)))
+        ));
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+    }
+}
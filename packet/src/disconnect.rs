@@ -8,12 +8,13 @@ use super::{
 };
 use bytes::{Buf, BufMut};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct Disconnect {
     // 3.14.2.1 Disconnect Reason Code
     reason_code: DisconnectReasonCode,
     // 3.14.2.2 DISCONNECT Properties
-    props: Properties,
+    pub(crate) props: Properties,
 }
 
 impl Disconnect {
@@ -32,6 +33,9 @@ impl Disconnect {
     pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
         self.props.get(key)
     }
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        self.props.remove(key)
+    }
     pub fn props_iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props.iter()
     }
@@ -55,9 +59,6 @@ impl MqttSerialize for Disconnect {
 impl MqttDeserialize for Disconnect {
     fn deserialize<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
         let length = MqttVariableBytesInt::deserialize(buf)?.inner() as usize;
-        if length < Disconnect::min_size() - MqttVariableBytesInt::min_size() {
-            return Err(DataParseError::BadDisconnectMessage);
-        }
         if buf.remaining() < length {
             return Err(DataParseError::InsufficientBuffer {
                 needed: length,
@@ -65,11 +66,24 @@ impl MqttDeserialize for Disconnect {
             });
         }
         let mut buf = buf.take(length);
-        let reason_code = DisconnectReasonCode::unchecked_deserialize(&mut buf)?;
-        let props = Properties::deserialize(&mut buf)?;
+        // 3.14.2.1: a remaining length of 0 means a normal disconnection
+        let reason_code = if length == 0 {
+            DisconnectReasonCode::NormalDisconnection
+        } else {
+            DisconnectReasonCode::unchecked_deserialize(&mut buf)?
+        };
+        // 3.14.2.2.1: the property length is omitted below a remaining length of 2
+        let props = if length < 2 {
+            Properties::new()
+        } else {
+            Properties::deserialize(&mut buf)?
+        };
         if !props.is_valid_for(PropOwner::DISCONNECT) {
             return Err(DataParseError::BadProperty);
         }
+        if buf.has_remaining() {
+            return Err(DataParseError::BadDisconnectMessage);
+        }
         Ok(Disconnect { reason_code, props })
     }
 }
@@ -106,4 +120,26 @@ mod test {
         disconnect2.serialize(&mut b2);
         assert_eq!(b, b2);
     }
+    #[test]
+    fn test_disconnect_short_forms() {
+        let normal = Disconnect::deserialize(&mut &[0x00][..]).unwrap();
+        assert!(matches!(
+            normal.reason_code(),
+            DisconnectReasonCode::NormalDisconnection
+        ));
+        assert_eq!(normal.props_iter().count(), 0);
+        let reason_only = Disconnect::deserialize(&mut &[0x01, 0x8b][..]).unwrap();
+        assert!(matches!(
+            reason_only.reason_code(),
+            DisconnectReasonCode::ServerShuttingDown
+        ));
+        assert_eq!(reason_only.props_iter().count(), 0);
+        let mut b = BytesMut::new();
+        reason_only.serialize(&mut b);
+        assert_eq!(b, &[0x02, 0x8b, 0x00][..]);
+        assert!(matches!(
+            Disconnect::deserialize(&mut &[0x03, 0x8b, 0x00, 0x00][..]),
+            Err(DataParseError::BadDisconnectMessage)
+        ));
+    }
 }
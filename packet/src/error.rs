@@ -25,4 +25,5 @@ pub enum DataParseError {
     BadUnsubAckMessage,
     BadPing,
     BadConnAckMessage,
+    MalformedPacket,
 }
@@ -2,6 +2,7 @@ pub mod auth;
 pub mod connack;
 pub mod connect;
 mod data;
+pub mod decoder;
 pub mod disconnect;
 pub mod error;
 mod helpers;
@@ -23,3 +24,5 @@ pub mod subscribe;
 pub mod topic;
 pub mod unsuback;
 pub mod unsubscribe;
+pub mod validate;
+pub mod version;
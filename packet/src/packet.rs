@@ -1,12 +1,29 @@
 use super::{
-    auth::Auth, connack::ConnAck, connect::Connect, data::MqttOneBytesInt, disconnect::Disconnect,
-    error::DataParseError, helpers::bits_u8, packet_type::PacketType, parsable::*, ping::Ping,
-    puback::PubAck, pubcomp::PubComp, publish::Publish, pubrec::PubRec, pubrel::PubRel,
-    suback::SubAck, subscribe::Subscribe, unsuback::UnsubAck, unsubscribe::Unsubscribe,
+    auth::Auth,
+    connack::ConnAck,
+    connect::Connect,
+    data::{MqttOneBytesInt, MqttVariableBytesInt},
+    disconnect::Disconnect,
+    error::DataParseError,
+    helpers::bits_u8,
+    packet_type::PacketType,
+    parsable::*,
+    ping::Ping,
+    props::{MqttPropValue, Property},
+    puback::PubAck,
+    pubcomp::PubComp,
+    publish::Publish,
+    pubrec::PubRec,
+    pubrel::PubRel,
+    suback::SubAck,
+    subscribe::Subscribe,
+    unsuback::UnsubAck,
+    unsubscribe::Unsubscribe,
 };
 use bytes::{Buf, BufMut};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub enum Packet {
     Connect(Connect),
     ConnAck(ConnAck),
@@ -31,6 +48,106 @@ impl Packet {
     pub fn from_bytes<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
         Packet::deserialize(buf)
     }
+    pub fn ping_req() -> Self {
+        Ping::new().build_req()
+    }
+    pub fn ping_res() -> Self {
+        Ping::new().build_res()
+    }
+    /// Same as `from_bytes` but additionally rejects packets whose remaining length or
+    /// properties hold variable byte integers that are not minimally encoded, or whose
+    /// content is not entirely consumed by the parser, with `DataParseError::MalformedPacket`.
+    pub fn from_bytes_strict<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
+        Packet::parse_strict(buf, |mut buf| Packet::from_bytes(&mut buf))
+    }
+    /// The checks of `from_bytes_strict` around the lenient `parse`
+    pub(crate) fn parse_strict<T: Buf>(
+        buf: &mut T,
+        parse: impl FnOnce(&mut dyn Buf) -> Result<Self, DataParseError>,
+    ) -> Result<Self, DataParseError> {
+        if buf.remaining() < 2 {
+            return Err(DataParseError::InsufficientBuffer {
+                needed: 2,
+                available: buf.remaining(),
+            });
+        }
+        let byte1 = buf.get_u8();
+        let length = MqttVariableBytesInt::deserialize_minimal(buf)?;
+        let content_len = length.inner() as usize;
+        if buf.remaining() < content_len {
+            return Err(DataParseError::InsufficientBuffer {
+                needed: content_len,
+                available: buf.remaining(),
+            });
+        }
+        let mut header = Vec::with_capacity(1 + length.size());
+        header.put_u8(byte1);
+        length.serialize(&mut header);
+        let mut content = buf.take(content_len);
+        let packet = parse(&mut (&header[..]).chain(&mut content))?;
+        if content.has_remaining() || packet.padded_props() {
+            return Err(DataParseError::MalformedPacket);
+        }
+        Ok(packet)
+    }
+    fn padded_props(&self) -> bool {
+        match self {
+            Packet::Connect(p) => {
+                p.props.padded() || p.will().is_some_and(|will| will.props.padded())
+            }
+            Packet::ConnAck(p) => p.props.padded(),
+            Packet::Publish(p) => p.props.padded(),
+            Packet::PubAck(p) => p.props.padded(),
+            Packet::PubRec(p) => p.props.padded(),
+            Packet::PubRel(p) => p.props.padded(),
+            Packet::PubComp(p) => p.props.padded(),
+            Packet::Subscribe(p) => p.props.padded(),
+            Packet::SubAck(p) => p.props.padded(),
+            Packet::Unsubscribe(p) => p.props.padded(),
+            Packet::UnsubAck(p) => p.props.padded(),
+            Packet::PingReq(_) | Packet::PingRes(_) => false,
+            Packet::Disconnect(p) => p.props.padded(),
+            Packet::Auth(p) => p.props.padded(),
+        }
+    }
+    pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
+        match self {
+            Packet::Connect(p) => p.get_prop(key),
+            Packet::ConnAck(p) => p.get_prop(key),
+            Packet::Publish(p) => p.get_prop(key),
+            Packet::PubAck(p) => p.get_prop(key),
+            Packet::PubRec(p) => p.get_prop(key),
+            Packet::PubRel(p) => p.get_prop(key),
+            Packet::PubComp(p) => p.get_prop(key),
+            Packet::Subscribe(p) => p.get_prop(key),
+            Packet::SubAck(p) => p.get_prop(key),
+            Packet::Unsubscribe(p) => p.get_prop(key),
+            Packet::UnsubAck(p) => p.get_prop(key),
+            Packet::PingReq(_) | Packet::PingRes(_) => None,
+            Packet::Disconnect(p) => p.get_prop(key),
+            Packet::Auth(p) => p.get_prop(key),
+        }
+    }
+    /// Removes every value of `key` from the properties of the packet, packets without
+    /// properties are left untouched
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        match self {
+            Packet::Connect(p) => p.remove_prop(key),
+            Packet::ConnAck(p) => p.remove_prop(key),
+            Packet::Publish(p) => p.remove_prop(key),
+            Packet::PubAck(p) => p.remove_prop(key),
+            Packet::PubRec(p) => p.remove_prop(key),
+            Packet::PubRel(p) => p.remove_prop(key),
+            Packet::PubComp(p) => p.remove_prop(key),
+            Packet::Subscribe(p) => p.remove_prop(key),
+            Packet::SubAck(p) => p.remove_prop(key),
+            Packet::Unsubscribe(p) => p.remove_prop(key),
+            Packet::UnsubAck(p) => p.remove_prop(key),
+            Packet::PingReq(_) | Packet::PingRes(_) => None,
+            Packet::Disconnect(p) => p.remove_prop(key),
+            Packet::Auth(p) => p.remove_prop(key),
+        }
+    }
     pub fn frame_len(&self) -> usize {
         1 + match self {
             Packet::Connect(p) => p.size(),
@@ -50,6 +167,21 @@ impl Packet {
             Packet::Auth(p) => p.size(),
         }
     }
+    /// Length of the packet starting `buf`, read from its fixed header alone. `None` when
+    /// `buf` does not hold the whole fixed header yet.
+    pub fn peek_frame_len(buf: &[u8]) -> Result<Option<usize>, DataParseError> {
+        let mut header = buf.get(1..).unwrap_or_default();
+        let before = header.len();
+        match MqttVariableBytesInt::deserialize(&mut header) {
+            Ok(length) => Ok(Some(1 + before - header.len() + length.inner() as usize)),
+            // the remaining length is at most 4 bytes long
+            Err(DataParseError::InsufficientBuffer { .. }) if before >= 4 => {
+                Err(DataParseError::BadMqttVariableBytesInt)
+            }
+            Err(DataParseError::InsufficientBuffer { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
 }
 impl MqttSerialize for Packet {
     fn serialize<T: BufMut>(&self, buf: &mut T) {
@@ -195,6 +327,36 @@ impl MqttDeserialize for Packet {
         }
     }
 }
+
+// same as the `build` method of each packet type
+macro_rules! into_packet {
+    ($($variant:ident),*) => {
+        $(
+            impl From<$variant> for Packet {
+                fn from(p: $variant) -> Packet {
+                    Packet::$variant(p)
+                }
+            }
+        )*
+    };
+}
+
+// PINGREQ and PINGRESP share `Ping`, see `Packet::ping_req` and `Packet::ping_res`
+into_packet!(
+    Connect,
+    ConnAck,
+    Publish,
+    PubAck,
+    PubRec,
+    PubRel,
+    PubComp,
+    Subscribe,
+    SubAck,
+    Unsubscribe,
+    UnsubAck,
+    Disconnect,
+    Auth
+);
 /*
     fn size(&self) -> usize {
         }
@@ -206,6 +368,74 @@ mod test {
     use super::*;
     use bytes::{Buf, Bytes, BytesMut};
     use std::sync::Arc;
+    #[cfg(feature = "debug")]
+    #[test]
+    fn test_packet_debug() {
+        let mut publish = Publish::new(Arc::from("a/b"), Bytes::from_static(b"hi")).unwrap();
+        publish
+            .add_prop(Property::MessageExpiryInterval, MqttPropValue::new_u32(10))
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", publish.build()),
+            "Publish(Publish { flags: NO_FLAGS, topic_name: MqttTopic(\"a/b\"), \
+             packet_identifier: None, props: {MessageExpiryInterval: [FourBytesInt(0x0000000a)]}, \
+             payload: b\"hi\" })"
+        );
+        assert_eq!(format!("{:?}", Packet::ping_req()), "PingReq(Ping)");
+    }
+    #[test]
+    fn test_into_packet() {
+        let packets: [Packet; 15] = [
+            Connect::new(Arc::from("c")).unwrap().into(),
+            ConnAck::new().into(),
+            Publish::new(Arc::from("a"), Bytes::new()).unwrap().into(),
+            PubAck::new(1).into(),
+            PubRec::new(1).into(),
+            PubRel::new(1).into(),
+            PubComp::new(1).into(),
+            Subscribe::new(1).into(),
+            SubAck::new(1).into(),
+            Unsubscribe::new(1).into(),
+            UnsubAck::new(1).into(),
+            Packet::ping_req(),
+            Packet::ping_res(),
+            Disconnect::new(DisconnectReasonCode::NormalDisconnection).into(),
+            Auth::new(AuthReasonCode::ReAuthenticate).into(),
+        ];
+        let types: Vec<_> = packets
+            .iter()
+            .map(|p| {
+                let mut b = BytesMut::new();
+                p.to_bytes(&mut b);
+                b[0] >> 4
+            })
+            .collect();
+        assert_eq!(types, (1..=15).collect::<Vec<u8>>());
+    }
+    #[test]
+    fn test_packet_eq() {
+        let mut publish = Publish::new(Arc::from("a/b"), Bytes::from_static(b"hi")).unwrap();
+        publish
+            .add_prop(Property::MessageExpiryInterval, MqttPropValue::new_u32(10))
+            .unwrap();
+        let packets: [Packet; 8] = [
+            Connect::new(Arc::from("c")).unwrap().into(),
+            ConnAck::new().into(),
+            publish.build(),
+            PubAck::new(1).into(),
+            PubComp::new(1).into(),
+            Packet::ping_req(),
+            Disconnect::new(DisconnectReasonCode::NormalDisconnection).into(),
+            Auth::new(AuthReasonCode::ReAuthenticate).into(),
+        ];
+        for packet in &packets {
+            let mut b = BytesMut::new();
+            packet.to_bytes(&mut b);
+            assert!(Packet::from_bytes(&mut b).unwrap() == *packet);
+        }
+        assert!(packets[0] != packets[1]);
+        assert!(Packet::from(PubAck::new(1)) != Packet::from(PubAck::new(2)));
+    }
     #[test]
     fn test_auth_packet() {
         let auth = Auth::new(AuthReasonCode::ReAuthenticate).build();
@@ -315,6 +545,118 @@ mod test {
         disconnect2.serialize(&mut b2);
         assert_eq!(b, b2);
     }
+    #[test]
+    fn test_strict_remaining_length() {
+        // AUTH with a remaining length of 2 encoded as 0x82 0x00
+        let non_minimal = &[0xf0, 0x82, 0x00, 0x19, 0x00][..];
+        assert!(Packet::from_bytes(&mut Bytes::from(non_minimal)).is_ok());
+        assert_eq!(
+            Packet::from_bytes_strict(&mut Bytes::from(non_minimal)).err(),
+            Some(DataParseError::MalformedPacket)
+        );
+
+        let mut publish = Publish::new(Arc::from("a/b"), Bytes::from("hello")).unwrap();
+        publish
+            .add_prop(
+                Property::ContentType,
+                MqttPropValue::new_string(Arc::from("text/plain")).unwrap(),
+            )
+            .unwrap();
+        let packets = [
+            Ping::new().build_req(),
+            Ping::new().build_res(),
+            Auth::new(AuthReasonCode::ReAuthenticate).build(),
+            Disconnect::new(DisconnectReasonCode::NormalDisconnection).build(),
+            PubAck::new(10).build(),
+            ConnAck::new().build(),
+            publish.build(),
+        ];
+        for packet in packets.iter() {
+            let mut b = BytesMut::new();
+            packet.to_bytes(&mut b);
+            let len = b.remaining();
+            let mut cursor = b.clone().chain(&[0x42][..]);
+            let packet2 = Packet::from_bytes_strict(&mut cursor).unwrap();
+            assert_eq!(packet2.frame_len(), len);
+            // only the packet itself is consumed
+            assert_eq!(cursor.remaining(), 1);
+
+            // the same packet with a redundant continuation byte in the remaining length
+            let mut padded = BytesMut::new();
+            padded.put_u8(b[0]);
+            padded.put_u8(b[1] | 0x80);
+            padded.put_u8(0);
+            padded.put_slice(&b[2..]);
+            if b[1] & 0x80 == 0 {
+                assert_eq!(
+                    Packet::from_bytes_strict(&mut padded).err(),
+                    Some(DataParseError::MalformedPacket)
+                );
+            }
+        }
+
+        // the short forms leaving out a success reason code or empty properties (3.2.2.3,
+        // 3.14.2.1) are minimal
+        let short_forms = [
+            &[0xe0, 0x00][..],
+            &[0xe0, 0x01, 0x8b][..],
+            &[0x20, 0x02, 0x00, 0x00][..],
+        ];
+        for short in short_forms {
+            assert!(
+                Packet::from_bytes_strict(&mut Bytes::from(short)).unwrap()
+                    == Packet::from_bytes(&mut Bytes::from(short)).unwrap()
+            );
+        }
+        // a byte left once the PUBACK and its empty properties are read
+        let trailing = &[0x40, 0x05, 0x00, 0x0a, 0x00, 0x00, 0xff][..];
+        assert!(Packet::from_bytes(&mut Bytes::from(trailing)).is_ok());
+        assert_eq!(
+            Packet::from_bytes_strict(&mut Bytes::from(trailing)).err(),
+            Some(DataParseError::MalformedPacket)
+        );
+
+        // PUBLISH on `a` with SubscriptionIdentifier 5
+        let minimal = &[0x30, 0x06, 0x00, 0x01, b'a', 0x02, 0x0b, 0x05][..];
+        assert!(Packet::from_bytes_strict(&mut Bytes::from(minimal)).is_ok());
+        let padded_props_len = &[0x30, 0x07, 0x00, 0x01, b'a', 0x82, 0x00, 0x0b, 0x05][..];
+        let padded_identifier = &[0x30, 0x07, 0x00, 0x01, b'a', 0x03, 0x0b, 0x85, 0x00][..];
+        for padded in [padded_props_len, padded_identifier] {
+            assert_eq!(
+                Packet::from_bytes_strict(&mut Bytes::from(padded)).err(),
+                Some(DataParseError::MalformedPacket)
+            );
+            let lenient = Packet::from_bytes(&mut Bytes::from(padded)).unwrap();
+            assert!(lenient == Packet::from_bytes(&mut Bytes::from(minimal)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_peek_frame_len() {
+        assert_eq!(Packet::peek_frame_len(&[]), Ok(None));
+        assert_eq!(Packet::peek_frame_len(&[0x30]), Ok(None));
+        assert_eq!(Packet::peek_frame_len(&[0x30, 0xff, 0xff]), Ok(None));
+        assert_eq!(Packet::peek_frame_len(&[0xc0, 0x00]), Ok(Some(2)));
+        assert_eq!(
+            Packet::peek_frame_len(&[0x30, 0xff, 0xff, 0xff, 0x7f]),
+            Ok(Some(5 + 0xfffffff))
+        );
+        assert!(Packet::peek_frame_len(&[0x30, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(Packet::peek_frame_len(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01]).is_err());
+
+        let publish = Publish::new(Arc::from("a/b"), Bytes::from(vec![0; 300]))
+            .unwrap()
+            .build();
+        let mut b = BytesMut::new();
+        publish.to_bytes(&mut b);
+        assert_eq!(Packet::peek_frame_len(&b[..2]), Ok(None));
+        assert_eq!(
+            Packet::peek_frame_len(&b[..3]),
+            Ok(Some(publish.frame_len()))
+        );
+        assert_eq!(Packet::peek_frame_len(&b), Ok(Some(b.len())));
+    }
+
     #[test]
     fn test_ping_req_packet() {
         let ping_req = Ping::new().build_req();
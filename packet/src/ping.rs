@@ -1,7 +1,8 @@
 use super::{data::MqttOneBytesInt, error::DataParseError, packet::Packet, parsable::*};
 use bytes::{Buf, BufMut};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct Ping {}
 impl Default for Ping {
     fn default() -> Self {
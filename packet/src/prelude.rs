@@ -1,5 +1,5 @@
 pub use crate::{
-    auth::*, connack::*, connect::*, disconnect::*, error::*, packet::*, ping::*, props::*,
-    puback::*, pubcomp::*, publish::*, pubrec::*, pubrel::*, qos::*, reason::*, suback::*,
-    subscribe::*, topic::*, unsuback::*, unsubscribe::*,
+    auth::*, connack::*, connect::*, decoder::*, disconnect::*, error::*, packet::*, ping::*,
+    props::*, puback::*, pubcomp::*, publish::*, pubrec::*, pubrel::*, qos::*, reason::*,
+    suback::*, subscribe::*, topic::*, unsuback::*, unsubscribe::*, validate::*, version::*,
 };
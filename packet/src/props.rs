@@ -8,7 +8,8 @@ use super::{
 };
 use bitflags::bitflags;
 use bytes::{Buf, BufMut, Bytes};
-use std::collections::HashMap;
+#[cfg(feature = "debug")]
+use std::fmt;
 use std::sync::Arc;
 
 bitflags! {
@@ -36,8 +37,33 @@ bitflags! {
 pub struct Properties {
     size: usize,
     valid: PropOwner,
-    props: HashMap<Property, Vec<MqttPropValue>>,
+    // kept in insertion order so the same properties always serialize to the same bytes,
+    // the values of a key repeated several times are stored together in their own order
+    props: Vec<(Property, Vec<MqttPropValue>)>,
+    // one of the variable byte integers read was not minimally encoded (1.5.5)
+    padded: bool,
 }
+#[cfg(feature = "debug")]
+impl fmt::Debug for Properties {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // size and owners are bookkeeping, only the properties themselves are of interest
+        f.debug_map()
+            .entries(self.props.iter().map(|(key, values)| (key, values)))
+            .finish()
+    }
+}
+/// Two sets of properties are equal when they hold the same values, whichever order the
+/// keys were added in. The values of a repeated key are compared in order (3.3.2.3.7).
+impl PartialEq for Properties {
+    fn eq(&self, other: &Self) -> bool {
+        self.props.len() == other.props.len()
+            && self
+                .props
+                .iter()
+                .all(|(key, values)| other.props.iter().any(|(k, v)| k == key && v == values))
+    }
+}
+impl Eq for Properties {}
 impl Default for Properties {
     fn default() -> Self {
         Self::new()
@@ -48,9 +74,14 @@ impl Properties {
         Properties {
             size: 0,
             valid: PropOwner::ALL_MESSAGES,
-            props: HashMap::new(),
+            props: Vec::new(),
+            padded: false,
         }
     }
+    /// A variable byte integer read for these properties was not minimally encoded
+    pub(crate) fn padded(&self) -> bool {
+        self.padded
+    }
     pub fn insert(&mut self, key: Property, value: MqttPropValue) -> Result<(), DataParseError> {
         let (filter, prop_type, multiple) = key.auxiliary_data();
         if value.prop_type() != prop_type {
@@ -68,24 +99,47 @@ impl Properties {
         value: MqttPropValue,
         multiple: bool,
     ) -> Option<MqttPropValue> {
-        if multiple {
-            self.size += key.size() + value.size();
-            let old = self.props.get_mut(&key);
-            match old {
-                Some(old) => old.push(value),
-                None => drop(self.props.insert(key, vec![value])),
+        self.size += key.size() + value.size();
+        let ret = match self.values_mut(key) {
+            Some(old) if multiple => {
+                old.push(value);
+                None
             }
-            None
-        } else {
-            self.size += value.size();
-            let old = self.props.insert(key, vec![value]);
-            if let Some(v) = old.as_ref() {
-                self.size -= v[0].size();
-            } else {
-                self.size += key.size();
+            Some(old) => {
+                let old = std::mem::replace(old, vec![value]);
+                // every value stored under a key is prefixed by its own copy of the key
+                self.size -= old.iter().map(|v| key.size() + v.size()).sum::<usize>();
+                old.into_iter().next()
             }
-            old.map(|mut v| v.remove(0))
-        }
+            None => {
+                self.props.push((key, vec![value]));
+                None
+            }
+        };
+        debug_assert_eq!(
+            self.size,
+            Self::compute_size(&self.props),
+            "Properties size accounting is out of sync"
+        );
+        ret
+    }
+    fn values_mut(&mut self, key: Property) -> Option<&mut Vec<MqttPropValue>> {
+        self.props
+            .iter_mut()
+            .find(|(k, _)| *k == key)
+            .map(|(_, values)| values)
+    }
+    fn compute_size(props: &[(Property, Vec<MqttPropValue>)]) -> usize {
+        props
+            .iter()
+            .map(|(key, values)| values.iter().map(|v| key.size() + v.size()).sum::<usize>())
+            .sum()
+    }
+    /// Recomputes the length of the serialized properties (without the length prefix)
+    /// from the stored values and returns it.
+    pub fn recompute_size(&mut self) -> usize {
+        self.size = Self::compute_size(&self.props);
+        self.size
     }
     pub fn checked_insert(
         &mut self,
@@ -102,16 +156,26 @@ impl Properties {
         Ok(())
     }
     pub fn get(&self, key: Property) -> Option<&[MqttPropValue]> {
-        self.props.get(&key).map(|v| v.as_ref())
+        self.props
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, values)| values.as_ref())
+    }
+    /// Removes every value of `key`
+    pub fn remove(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        let index = self.props.iter().position(|(k, _)| *k == key)?;
+        let (_, values) = self.props.remove(index);
+        self.size -= values.iter().map(|v| key.size() + v.size()).sum::<usize>();
+        Some(values)
     }
     pub fn is_valid_for(&self, message: PropOwner) -> bool {
         self.valid & message == message
     }
+    /// Iterates in insertion order, the values of a repeated key in the order they were added
     pub fn iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props
             .iter()
-            .map(|(key, value_vec)| value_vec.iter().map(move |value| (key, value)))
-            .flatten()
+            .flat_map(|(key, value_vec)| value_vec.iter().map(move |value| (key, value)))
     }
 }
 
@@ -128,14 +192,26 @@ impl MqttSerialize for Properties {
 }
 impl MqttDeserialize for Properties {
     fn deserialize<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
-        let mut size = MqttVariableBytesInt::deserialize(buf)?.inner() as usize;
+        // the bytes read are counted rather than the sizes of what they decode to, so
+        // padded variable byte integers are accepted here and left to `from_bytes_strict`
+        let available = buf.remaining();
+        let length = MqttVariableBytesInt::deserialize(buf)?;
         let mut table = Properties::new();
+        table.padded = available - buf.remaining() != length.size();
+        let size = length.inner() as usize;
+        if buf.remaining() < size {
+            return Err(DataParseError::InsufficientBuffer {
+                needed: size,
+                available: buf.remaining(),
+            });
+        }
         let mut buf = buf.take(size);
-        while size != 0 {
+        while buf.has_remaining() {
+            let available = buf.remaining();
             let key = Property::deserialize(&mut buf)?;
             let (_, ty, _) = key.auxiliary_data();
             let value = MqttPropValue::deserialize(&mut buf, ty)?;
-            size -= key.size() + value.size();
+            table.padded |= available - buf.remaining() != key.size() + value.size();
             table.insert(key, value)?;
         }
         Ok(table)
@@ -151,6 +227,7 @@ impl MqttSize for Properties {
 }
 
 #[derive(PartialEq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub(crate) enum MqttPropValueType {
     Bool,
     Byte,
@@ -309,7 +386,7 @@ impl Property {
                     | PropOwner::UNSUBACK
                     | PropOwner::DISCONNECT
                     | PropOwner::AUTH,
-                MqttPropValueType::String,
+                MqttPropValueType::StringPair,
                 true,
             ),
             Property::MaximumPacketSize => (
@@ -384,7 +461,8 @@ impl MqttSize for Property {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 enum MqttPropValueInner {
     Bool(MqttOneBytesInt),
     Byte(MqttOneBytesInt),
@@ -396,14 +474,20 @@ enum MqttPropValueInner {
     TwoBytesInt(MqttTwoBytesInt),
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct MqttPropValue(MqttPropValueInner);
+
+#[cfg(feature = "debug")]
+impl fmt::Debug for MqttPropValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
 impl MqttPropValue {
     pub fn into_bool(&self) -> Option<bool> {
-        if let MqttPropValueInner::Byte(i) = &self.0 {
-            Some(i.inner() == 1)
-        } else {
-            None
+        match &self.0 {
+            MqttPropValueInner::Bool(i) | MqttPropValueInner::Byte(i) => Some(i.inner() == 1),
+            _ => None,
         }
     }
     pub fn into_u8(&self) -> Option<u8> {
@@ -673,20 +757,190 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_props_insertion_order() {
+        let build = || {
+            let mut props = Properties::new();
+            let pair = |k: &str, v: &str| {
+                MqttPropValue::new_string_pair(Arc::from(k), Arc::from(v)).unwrap()
+            };
+            props
+                .insert(Property::UserProperty, pair("b", "1"))
+                .unwrap();
+            props
+                .insert(Property::MessageExpiryInterval, MqttPropValue::new_u32(7))
+                .unwrap();
+            props
+                .insert(Property::UserProperty, pair("a", "2"))
+                .unwrap();
+            props
+                .insert(
+                    Property::ContentType,
+                    MqttPropValue::new_string(Arc::from("t")).unwrap(),
+                )
+                .unwrap();
+            props
+        };
+        let mut b = BytesMut::new();
+        build().serialize(&mut b);
+        for _ in 0..16 {
+            let mut again = BytesMut::new();
+            build().serialize(&mut again);
+            assert_eq!(b, again);
+        }
+        let keys: Vec<_> = build().iter().map(|(k, _)| *k).collect();
+        assert!(
+            keys == [
+                Property::UserProperty,
+                Property::UserProperty,
+                Property::MessageExpiryInterval,
+                Property::ContentType
+            ]
+        );
+        // parsing preserves the order as well
+        let mut reserialized = BytesMut::new();
+        Properties::deserialize(&mut b.clone())
+            .unwrap()
+            .serialize(&mut reserialized);
+        assert_eq!(b, reserialized);
+    }
+
+    #[test]
+    fn test_props_eq() {
+        let pair =
+            |k: &str, v: &str| MqttPropValue::new_string_pair(Arc::from(k), Arc::from(v)).unwrap();
+        let build = |keys: &[Property], users: &[(&str, &str)]| {
+            let mut props = Properties::new();
+            for key in keys {
+                let value = match key {
+                    Property::UserProperty => continue,
+                    Property::MessageExpiryInterval => MqttPropValue::new_u32(7),
+                    _ => MqttPropValue::new_string(Arc::from("t")).unwrap(),
+                };
+                props.insert(*key, value).unwrap();
+            }
+            for (k, v) in users {
+                props.insert(Property::UserProperty, pair(k, v)).unwrap();
+            }
+            props
+        };
+        let keys = [Property::MessageExpiryInterval, Property::ContentType];
+        let reversed = [Property::ContentType, Property::MessageExpiryInterval];
+        let users = [("a", "1"), ("b", "2")];
+        assert!(build(&keys, &users) == build(&reversed, &users));
+        assert!(build(&keys, &users) != build(&keys[..1], &users));
+        // the order of user properties matters
+        assert!(build(&keys, &users) != build(&keys, &[("b", "2"), ("a", "1")]));
+    }
+
+    #[test]
+    fn test_into_bool() {
+        assert_eq!(MqttPropValue::new_bool(false).into_bool(), Some(false));
+        assert_eq!(MqttPropValue::new_bool(true).into_bool(), Some(true));
+        assert_eq!(MqttPropValue::new_u8(1).into_bool(), Some(true));
+        assert_eq!(MqttPropValue::new_u32(1).into_bool(), None);
+    }
+
+    fn assert_size_consistent(props: &Properties) {
+        let mut b = BytesMut::new();
+        props.serialize(&mut b);
+        assert_eq!(b.remaining(), props.size());
+        assert_eq!(props.clone().recompute_size(), props.size);
+        let props2 = Properties::deserialize(&mut b.clone()).unwrap();
+        assert_eq!(props2.size(), props.size());
+    }
+
+    #[test]
+    fn test_props_size_replace() {
+        let mut props = Properties::new();
+        props
+            .insert(
+                Property::ContentType,
+                MqttPropValue::new_string(Arc::from("text/plain")).unwrap(),
+            )
+            .unwrap();
+        assert_size_consistent(&props);
+        props
+            .checked_insert(
+                Property::ContentType,
+                MqttPropValue::new_string(Arc::from("json")).unwrap(),
+                PropOwner::PUBLISH,
+            )
+            .unwrap();
+        assert_size_consistent(&props);
+        assert_eq!(props.get(Property::ContentType).unwrap().len(), 1);
+        assert!(props
+            .insert(
+                Property::ContentType,
+                MqttPropValue::new_string(Arc::from("application/octet-stream")).unwrap(),
+            )
+            .is_err());
+        assert_size_consistent(&props);
+    }
+
+    #[test]
+    fn test_props_remove() {
+        let mut props = Properties::new();
+        let pair = MqttPropValue::new_string_pair(Arc::from("k"), Arc::from("v")).unwrap();
+        props.insert(Property::UserProperty, pair.clone()).unwrap();
+        props
+            .insert(
+                Property::ContentType,
+                MqttPropValue::new_string(Arc::from("json")).unwrap(),
+            )
+            .unwrap();
+        props.insert(Property::UserProperty, pair).unwrap();
+        assert_eq!(props.remove(Property::UserProperty).unwrap().len(), 2);
+        assert!(props.remove(Property::UserProperty).is_none());
+        assert!(props.get(Property::UserProperty).is_none());
+        assert_size_consistent(&props);
+        props.remove(Property::ContentType).unwrap();
+        assert_eq!(props.size(), Properties::new().size());
+    }
+
+    #[test]
+    fn test_props_size_user_property_heavy() {
+        let mut props = Properties::new();
+        for i in 0..200 {
+            props
+                .checked_insert(
+                    Property::UserProperty,
+                    MqttPropValue::new_string_pair(
+                        Arc::from(format!("key{}", i)),
+                        Arc::from("x".repeat(i)),
+                    )
+                    .unwrap(),
+                    PropOwner::PUBLISH,
+                )
+                .unwrap();
+            props
+                .checked_insert(
+                    Property::MessageExpiryInterval,
+                    MqttPropValue::new_u32(i as u32),
+                    PropOwner::PUBLISH,
+                )
+                .unwrap();
+        }
+        assert_eq!(props.get(Property::UserProperty).unwrap().len(), 200);
+        assert_size_consistent(&props);
+        // the size now needs a multi byte length prefix
+        assert!(props.size > 0x4000);
+    }
+
     #[test]
     fn test_props_duplicate() {
         let mut props = Properties::new();
         props
             .checked_insert(
                 Property::UserProperty,
-                MqttPropValue::new_string(Arc::from("Hello")).unwrap(),
+                MqttPropValue::new_string_pair(Arc::from("k"), Arc::from("Hello")).unwrap(),
                 PropOwner::CONNACK,
             )
             .unwrap();
         props
             .checked_insert(
                 Property::UserProperty,
-                MqttPropValue::new_string(Arc::from("World")).unwrap(),
+                MqttPropValue::new_string_pair(Arc::from("k"), Arc::from("World")).unwrap(),
                 PropOwner::CONNACK,
             )
             .unwrap();
@@ -695,8 +949,8 @@ mod test {
         assert_eq!(
             b,
             &[
-                0x10, 0x26, 0x00, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x26, 0x00, 0x05, 0x57, 0x6f,
-                0x72, 0x6c, 0x64
+                0x16, 0x26, 0x00, 0x01, 0x6b, 0x00, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x26, 0x00,
+                0x01, 0x6b, 0x00, 0x05, 0x57, 0x6f, 0x72, 0x6c, 0x64
             ][..]
         );
         assert_eq!(b.remaining(), props.size());
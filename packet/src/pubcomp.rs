@@ -9,14 +9,15 @@ use super::{
 
 use bytes::{Buf, BufMut};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct PubComp {
     // 2.2.1 Packet Identifier
     packet_identifier: MqttTwoBytesInt,
     // 3.7.2.1 PUBCOMP Reason Code
     reason_code: PubCompReasonCode,
     // 3.7.2.2 PUBCOMP Properties
-    props: Properties,
+    pub(crate) props: Properties,
 }
 
 impl PubComp {
@@ -42,6 +43,9 @@ impl PubComp {
     pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
         self.props.get(key)
     }
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        self.props.remove(key)
+    }
     pub fn props_iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props.iter()
     }
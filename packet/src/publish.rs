@@ -10,6 +10,7 @@ use super::{
 use bitflags::bitflags;
 use bytes::{Buf, BufMut, Bytes};
 use std::convert::TryInto;
+use std::marker::PhantomData;
 use std::sync::Arc;
 bitflags! {
     pub struct PublishFlags: u8 {
@@ -61,7 +62,8 @@ impl TryInto<QoS> for PublishFlags {
 }
 
 //TODO check flag is equivalent to good QoS
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct Publish {
     // 2.1.3 Flags & 3.3.1 PUBLISH Fixed Header.
     // Note this is part of the fixed header and not serializable
@@ -75,7 +77,7 @@ pub struct Publish {
     packet_identifier: Option<MqttTwoBytesInt>,
 
     // 3.3.2.3 PUBLISH Properties
-    props: Properties,
+    pub(crate) props: Properties,
 
     //3.3.3 PUBLISH Payload
     // Why did they not use MqttBinaryData :(
@@ -107,6 +109,9 @@ impl Publish {
     pub fn set_retain(&mut self) {
         self.flags |= PublishFlags::RETAIN;
     }
+    pub fn clear_retain(&mut self) {
+        self.flags -= PublishFlags::RETAIN;
+    }
     pub fn set_dup(&mut self) {
         self.flags |= PublishFlags::DUP;
     }
@@ -146,6 +151,9 @@ impl Publish {
     pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
         self.props.get(key)
     }
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        self.props.remove(key)
+    }
     pub fn props_iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props.iter()
     }
@@ -164,6 +172,68 @@ impl Publish {
     }
 }
 
+/// State of a `PublishBuilder` before a QoS above 0 is chosen
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub struct AtMostOnce;
+/// State of a `PublishBuilder` once it has a QoS above 0 and a packet identifier
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub struct Acknowledged;
+
+/// Builds a `Publish`, the packet identifier is given along with a QoS above 0 and DUP
+/// can only be set then (3.3.1.1)
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub struct PublishBuilder<S = AtMostOnce> {
+    publish: Publish,
+    state: PhantomData<S>,
+}
+
+impl Publish {
+    pub fn builder(topic_name: Arc<str>, payload: Bytes) -> Result<PublishBuilder, DataParseError> {
+        Ok(PublishBuilder {
+            publish: Publish::new(topic_name, payload)?,
+            state: PhantomData,
+        })
+    }
+}
+
+impl<S> PublishBuilder<S> {
+    pub fn retain(mut self) -> Self {
+        self.publish.set_retain();
+        self
+    }
+    pub fn prop(mut self, key: Property, value: MqttPropValue) -> Result<Self, DataParseError> {
+        self.publish.add_prop(key, value)?;
+        Ok(self)
+    }
+    pub fn build(self) -> Publish {
+        self.publish
+    }
+}
+
+impl PublishBuilder<AtMostOnce> {
+    pub fn qos1(self, packet_identifier: u16) -> PublishBuilder<Acknowledged> {
+        self.acknowledged(QoS::QoS1, packet_identifier)
+    }
+    pub fn qos2(self, packet_identifier: u16) -> PublishBuilder<Acknowledged> {
+        self.acknowledged(QoS::QoS2, packet_identifier)
+    }
+    fn acknowledged(mut self, qos: QoS, packet_identifier: u16) -> PublishBuilder<Acknowledged> {
+        self.publish.set_qos(qos);
+        self.publish.packet_identifier = Some(MqttTwoBytesInt::new(packet_identifier));
+        PublishBuilder {
+            publish: self.publish,
+            state: PhantomData,
+        }
+    }
+}
+
+impl PublishBuilder<Acknowledged> {
+    pub fn dup(mut self) -> Self {
+        self.publish.set_dup();
+        self
+    }
+}
+
 impl MqttSerialize for Publish {
     fn serialize<T: BufMut>(&self, buf: &mut T) {
         let length = MqttVariableBytesInt::new(self.partial_size() as u32)
@@ -261,4 +331,33 @@ mod tests {
         publish2.serialize(&mut b2);
         assert_eq!(b, b2);
     }
+    #[test]
+    fn test_publish_builder() {
+        let mut publish = Publish::new(Arc::from("a/b"), Bytes::from_static(b"hi")).unwrap();
+        publish.set_qos(QoS::QoS2);
+        publish.set_packet_identifier(7).unwrap();
+        publish.set_dup();
+        publish.set_retain();
+        publish
+            .add_prop(Property::MessageExpiryInterval, MqttPropValue::new_u32(10))
+            .unwrap();
+        let built = Publish::builder(Arc::from("a/b"), Bytes::from_static(b"hi"))
+            .unwrap()
+            .retain()
+            .qos2(7)
+            .dup()
+            .prop(Property::MessageExpiryInterval, MqttPropValue::new_u32(10))
+            .unwrap()
+            .build();
+        assert!(built == publish);
+        let qos0 = Publish::builder(Arc::from("a"), Bytes::new())
+            .unwrap()
+            .build();
+        assert!(qos0.qos() == QoS::QoS0 && qos0.packet_identifier().is_none());
+        assert!(Publish::builder(Arc::from("a/+"), Bytes::new()).is_err());
+        let bad_prop = Publish::builder(Arc::from("a"), Bytes::new())
+            .unwrap()
+            .prop(Property::SessionExpiryInterval, MqttPropValue::new_u32(1));
+        assert!(bad_prop.is_err());
+    }
 }
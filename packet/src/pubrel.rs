@@ -8,14 +8,15 @@ use super::{
 };
 use bytes::{Buf, BufMut};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct PubRel {
     // 2.2.1 Packet Identifier
     packet_identifier: MqttTwoBytesInt,
     // 3.6.2.1 PUBREL Reason Code
     reason_code: PubRelReasonCode,
     // 3.6.2.2 PUBREL Properties
-    props: Properties,
+    pub(crate) props: Properties,
 }
 
 impl PubRel {
@@ -41,6 +42,9 @@ impl PubRel {
     pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
         self.props.get(key)
     }
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        self.props.remove(key)
+    }
     pub fn props_iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props.iter()
     }
@@ -1,7 +1,47 @@
-#[derive(Clone, Copy, PartialOrd, PartialEq)]
+use super::error::DataParseError;
+
+/// Quality of service of an application message. Levels are ordered by their delivery
+/// guarantee, `QoS0 < QoS1 < QoS2`, so a message delivered over a subscription is
+/// downgraded with `QoS::min` (3.8.4).
+#[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 #[repr(u8)]
 pub enum QoS {
     QoS0 = 0,
     QoS1 = 1,
     QoS2 = 2,
 }
+
+impl QoS {
+    pub fn from_u8(value: u8) -> Result<QoS, DataParseError> {
+        match value {
+            0 => Ok(QoS::QoS0),
+            1 => Ok(QoS::QoS1),
+            2 => Ok(QoS::QoS2),
+            _ => Err(DataParseError::BadQoS),
+        }
+    }
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+    /// The lowest of the two levels, e.g. the QoS a message published with `self` is
+    /// delivered with to a subscription granted `other`
+    pub fn min(self, other: QoS) -> QoS {
+        Ord::min(self, other)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_qos_conversions() {
+        for qos in [QoS::QoS0, QoS::QoS1, QoS::QoS2] {
+            assert!(QoS::from_u8(qos.as_u8()).unwrap() == qos);
+        }
+        assert!(matches!(QoS::from_u8(3), Err(DataParseError::BadQoS)));
+        assert!(QoS::QoS0 < QoS::QoS1 && QoS::QoS1 < QoS::QoS2);
+        assert!(QoS::min(QoS::QoS2, QoS::QoS1) == QoS::QoS1);
+        assert!(QoS::QoS0.min(QoS::QoS2) == QoS::QoS0);
+    }
+}
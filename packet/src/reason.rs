@@ -1,9 +1,61 @@
 use super::{data::MqttOneBytesInt, error::DataParseError, parsable::*};
 use bytes::{Buf, BufMut};
+use std::fmt;
+
+/// Who a reason code blames, see `ReasonCode::category`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReasonCodeCategory {
+    /// Below 0x80, the operation went through
+    Success,
+    /// The request or the behaviour of the client was refused
+    ClientError,
+    /// The server could not serve an otherwise valid request
+    ServerError,
+}
+
+impl ReasonCodeCategory {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReasonCodeCategory::Success => "success",
+            ReasonCodeCategory::ClientError => "client_error",
+            ReasonCodeCategory::ServerError => "server_error",
+        }
+    }
+}
+
+/// Common interface of the reason code enums, `as_str` gives the snake case name of the
+/// code (e.g. `keep_alive_timeout`) which is also what `Display` writes
+pub trait ReasonCode: Copy {
+    fn value(self) -> u8;
+    fn as_str(self) -> &'static str;
+    fn category(self) -> ReasonCodeCategory {
+        match self.value() {
+            0x00..=0x7f => ReasonCodeCategory::Success,
+            // unspecified, implementation specific, unavailable, busy, shutting down and
+            // the redirections
+            0x80 | 0x83 | 0x88 | 0x89 | 0x8b | 0x9c | 0x9d => ReasonCodeCategory::ServerError,
+            _ => ReasonCodeCategory::ClientError,
+        }
+    }
+    fn is_error(self) -> bool {
+        self.value() >= 0x80
+    }
+}
+
+macro_rules! reason_code_display {
+    ($t:ty) => {
+        impl fmt::Display for $t {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+    };
+}
 
 //2.4 Reason Code
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub enum ConnAckReasonCode {
     Success = 0x0,
     UnspecifiedError = 0x80,
@@ -42,7 +94,14 @@ impl MqttUncheckedDeserialize for ConnAckReasonCode {
     }
     fn unchecked_deserialize<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
         let b = MqttOneBytesInt::unchecked_deserialize(buf)?;
-        match b.inner() {
+        ConnAckReasonCode::try_from(b.inner())
+    }
+}
+
+impl TryFrom<u8> for ConnAckReasonCode {
+    type Error = DataParseError;
+    fn try_from(v: u8) -> Result<Self, DataParseError> {
+        match v {
             0x0 => Ok(ConnAckReasonCode::Success),
             0x80 => Ok(ConnAckReasonCode::UnspecifiedError),
             0x81 => Ok(ConnAckReasonCode::MalformedPacket),
@@ -70,9 +129,43 @@ impl MqttUncheckedDeserialize for ConnAckReasonCode {
     }
 }
 
+impl ReasonCode for ConnAckReasonCode {
+    fn value(self) -> u8 {
+        self as u8
+    }
+    fn as_str(self) -> &'static str {
+        match self {
+            ConnAckReasonCode::Success => "success",
+            ConnAckReasonCode::UnspecifiedError => "unspecified_error",
+            ConnAckReasonCode::MalformedPacket => "malformed_packet",
+            ConnAckReasonCode::ProtocolError => "protocol_error",
+            ConnAckReasonCode::ImplementationSpecificError => "implementation_specific_error",
+            ConnAckReasonCode::UnsupportedProtocolVersion => "unsupported_protocol_version",
+            ConnAckReasonCode::ClientIdentifierNotValid => "client_identifier_not_valid",
+            ConnAckReasonCode::BadUserNameOrPassword => "bad_user_name_or_password",
+            ConnAckReasonCode::NotAuthorized => "not_authorized",
+            ConnAckReasonCode::ServerUnavailable => "server_unavailable",
+            ConnAckReasonCode::ServerBusy => "server_busy",
+            ConnAckReasonCode::Banned => "banned",
+            ConnAckReasonCode::BadAuthenicationMethod => "bad_authentication_method",
+            ConnAckReasonCode::TopicNameInvalid => "topic_name_invalid",
+            ConnAckReasonCode::PacketTooLarge => "packet_too_large",
+            ConnAckReasonCode::QuotaExceeded => "quota_exceeded",
+            ConnAckReasonCode::PayloadFormatInvalid => "payload_format_invalid",
+            ConnAckReasonCode::RetainNotSupported => "retain_not_supported",
+            ConnAckReasonCode::QoSNotSupported => "qos_not_supported",
+            ConnAckReasonCode::UseAnotherServer => "use_another_server",
+            ConnAckReasonCode::ServerMoved => "server_moved",
+            ConnAckReasonCode::ConnectionRateExceeded => "connection_rate_exceeded",
+        }
+    }
+}
+reason_code_display!(ConnAckReasonCode);
+
 //2.4 Reason Code
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub enum PubAckReasonCode {
     Success = 0x0,
     NoMatchingSubscribers = 0x10,
@@ -96,7 +189,14 @@ impl MqttUncheckedDeserialize for PubAckReasonCode {
     }
     fn unchecked_deserialize<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
         let b = MqttOneBytesInt::unchecked_deserialize(buf)?;
-        match b.inner() {
+        PubAckReasonCode::try_from(b.inner())
+    }
+}
+
+impl TryFrom<u8> for PubAckReasonCode {
+    type Error = DataParseError;
+    fn try_from(v: u8) -> Result<Self, DataParseError> {
+        match v {
             0x0 => Ok(PubAckReasonCode::Success),
             0x10 => Ok(PubAckReasonCode::NoMatchingSubscribers),
             0x80 => Ok(PubAckReasonCode::UnspecifiedError),
@@ -111,12 +211,33 @@ impl MqttUncheckedDeserialize for PubAckReasonCode {
     }
 }
 
+impl ReasonCode for PubAckReasonCode {
+    fn value(self) -> u8 {
+        self as u8
+    }
+    fn as_str(self) -> &'static str {
+        match self {
+            PubAckReasonCode::Success => "success",
+            PubAckReasonCode::NoMatchingSubscribers => "no_matching_subscribers",
+            PubAckReasonCode::UnspecifiedError => "unspecified_error",
+            PubAckReasonCode::ImplementationSpecificError => "implementation_specific_error",
+            PubAckReasonCode::NotAuthorized => "not_authorized",
+            PubAckReasonCode::TopicNameInvalid => "topic_name_invalid",
+            PubAckReasonCode::PacketIdentifierInUse => "packet_identifier_in_use",
+            PubAckReasonCode::QuotaExceeded => "quota_exceeded",
+            PubAckReasonCode::PayloadFormatInvalid => "payload_format_invalid",
+        }
+    }
+}
+reason_code_display!(PubAckReasonCode);
+
 //2.4 Reason Code
 pub type PubRecReasonCode = PubAckReasonCode;
 
 //2.4 Reason Code
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub enum PubRelReasonCode {
     Success = 0x0,
     PacketIdentifierNotFound = 0x92,
@@ -135,7 +256,14 @@ impl MqttUncheckedDeserialize for PubRelReasonCode {
     }
     fn unchecked_deserialize<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
         let b = MqttOneBytesInt::unchecked_deserialize(buf)?;
-        match b.inner() {
+        PubRelReasonCode::try_from(b.inner())
+    }
+}
+
+impl TryFrom<u8> for PubRelReasonCode {
+    type Error = DataParseError;
+    fn try_from(v: u8) -> Result<Self, DataParseError> {
+        match v {
             0x0 => Ok(PubRelReasonCode::Success),
             0x92 => Ok(PubRelReasonCode::PacketIdentifierNotFound),
             _ => Err(DataParseError::BadReasonCode),
@@ -143,12 +271,26 @@ impl MqttUncheckedDeserialize for PubRelReasonCode {
     }
 }
 
+impl ReasonCode for PubRelReasonCode {
+    fn value(self) -> u8 {
+        self as u8
+    }
+    fn as_str(self) -> &'static str {
+        match self {
+            PubRelReasonCode::Success => "success",
+            PubRelReasonCode::PacketIdentifierNotFound => "packet_identifier_not_found",
+        }
+    }
+}
+reason_code_display!(PubRelReasonCode);
+
 //2.4 Reason Code
 pub type PubCompReasonCode = PubRelReasonCode;
 
 //2.4 Reason Code
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub enum UnsubAckReasonCode {
     Success = 0x0,
     NoSubscriptionExisted = 0x11,
@@ -172,7 +314,14 @@ impl MqttUncheckedDeserialize for UnsubAckReasonCode {
     }
     fn unchecked_deserialize<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
         let b = MqttOneBytesInt::unchecked_deserialize(buf)?;
-        match b.inner() {
+        UnsubAckReasonCode::try_from(b.inner())
+    }
+}
+
+impl TryFrom<u8> for UnsubAckReasonCode {
+    type Error = DataParseError;
+    fn try_from(v: u8) -> Result<Self, DataParseError> {
+        match v {
             0x0 => Ok(UnsubAckReasonCode::Success),
             0x11 => Ok(UnsubAckReasonCode::NoSubscriptionExisted),
             0x80 => Ok(UnsubAckReasonCode::UnspecifiedError),
@@ -185,9 +334,28 @@ impl MqttUncheckedDeserialize for UnsubAckReasonCode {
     }
 }
 
+impl ReasonCode for UnsubAckReasonCode {
+    fn value(self) -> u8 {
+        self as u8
+    }
+    fn as_str(self) -> &'static str {
+        match self {
+            UnsubAckReasonCode::Success => "success",
+            UnsubAckReasonCode::NoSubscriptionExisted => "no_subscription_existed",
+            UnsubAckReasonCode::UnspecifiedError => "unspecified_error",
+            UnsubAckReasonCode::ImplementationSpecificError => "implementation_specific_error",
+            UnsubAckReasonCode::NotAuthorized => "not_authorized",
+            UnsubAckReasonCode::TopicFilterInvalid => "topic_filter_invalid",
+            UnsubAckReasonCode::PacketIdentifierInUse => "packet_identifier_in_use",
+        }
+    }
+}
+reason_code_display!(UnsubAckReasonCode);
+
 //2.4 Reason Code
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub enum AuthReasonCode {
     Success = 0x0,
     ContinueAuthentication = 0x18,
@@ -206,7 +374,14 @@ impl MqttUncheckedDeserialize for AuthReasonCode {
     }
     fn unchecked_deserialize<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
         let b = MqttOneBytesInt::unchecked_deserialize(buf)?;
-        match b.inner() {
+        AuthReasonCode::try_from(b.inner())
+    }
+}
+
+impl TryFrom<u8> for AuthReasonCode {
+    type Error = DataParseError;
+    fn try_from(v: u8) -> Result<Self, DataParseError> {
+        match v {
             0x0 => Ok(AuthReasonCode::Success),
             0x18 => Ok(AuthReasonCode::ContinueAuthentication),
             0x19 => Ok(AuthReasonCode::ReAuthenticate),
@@ -215,9 +390,24 @@ impl MqttUncheckedDeserialize for AuthReasonCode {
     }
 }
 
+impl ReasonCode for AuthReasonCode {
+    fn value(self) -> u8 {
+        self as u8
+    }
+    fn as_str(self) -> &'static str {
+        match self {
+            AuthReasonCode::Success => "success",
+            AuthReasonCode::ContinueAuthentication => "continue_authentication",
+            AuthReasonCode::ReAuthenticate => "re_authenticate",
+        }
+    }
+}
+reason_code_display!(AuthReasonCode);
+
 //2.4 Reason Code
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub enum DisconnectReasonCode {
     NormalDisconnection = 0x0,
     DisconnectWithWillMessage = 0x04,
@@ -263,7 +453,14 @@ impl MqttUncheckedDeserialize for DisconnectReasonCode {
     }
     fn unchecked_deserialize<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
         let b = MqttOneBytesInt::unchecked_deserialize(buf)?;
-        match b.inner() {
+        DisconnectReasonCode::try_from(b.inner())
+    }
+}
+
+impl TryFrom<u8> for DisconnectReasonCode {
+    type Error = DataParseError;
+    fn try_from(v: u8) -> Result<Self, DataParseError> {
+        match v {
             0x0 => Ok(DisconnectReasonCode::NormalDisconnection),
             0x04 => Ok(DisconnectReasonCode::DisconnectWithWillMessage),
             0x80 => Ok(DisconnectReasonCode::UnspecifiedError),
@@ -299,9 +496,57 @@ impl MqttUncheckedDeserialize for DisconnectReasonCode {
     }
 }
 
+impl ReasonCode for DisconnectReasonCode {
+    fn value(self) -> u8 {
+        self as u8
+    }
+    fn as_str(self) -> &'static str {
+        match self {
+            DisconnectReasonCode::NormalDisconnection => "normal_disconnection",
+            DisconnectReasonCode::DisconnectWithWillMessage => "disconnect_with_will_message",
+            DisconnectReasonCode::UnspecifiedError => "unspecified_error",
+            DisconnectReasonCode::MalformedPacket => "malformed_packet",
+            DisconnectReasonCode::ProtocolError => "protocol_error",
+            DisconnectReasonCode::ImplementationSpecificError => "implementation_specific_error",
+            DisconnectReasonCode::NotAuthorized => "not_authorized",
+            DisconnectReasonCode::ServerBusy => "server_busy",
+            DisconnectReasonCode::ServerShuttingDown => "server_shutting_down",
+            DisconnectReasonCode::BadAuthenicationMethod => "bad_authentication_method",
+            DisconnectReasonCode::KeepAliveTimeout => "keep_alive_timeout",
+            DisconnectReasonCode::SessionTakenOver => "session_taken_over",
+            DisconnectReasonCode::TopicFilterInvalid => "topic_filter_invalid",
+            DisconnectReasonCode::TopicNameInvalid => "topic_name_invalid",
+            DisconnectReasonCode::ReceiveMaximumExceeded => "receive_maximum_exceeded",
+            DisconnectReasonCode::TopicAliasInvalid => "topic_alias_invalid",
+            DisconnectReasonCode::PacketTooLarge => "packet_too_large",
+            DisconnectReasonCode::MessageRateTooHigh => "message_rate_too_high",
+            DisconnectReasonCode::QuotaExceeded => "quota_exceeded",
+            DisconnectReasonCode::AdministrativeAction => "administrative_action",
+            DisconnectReasonCode::PayloadFormatInvalid => "payload_format_invalid",
+            DisconnectReasonCode::RetainNotSupported => "retain_not_supported",
+            DisconnectReasonCode::QoSNotSupported => "qos_not_supported",
+            DisconnectReasonCode::UseAnotherServer => "use_another_server",
+            DisconnectReasonCode::ServerMoved => "server_moved",
+            DisconnectReasonCode::SharedSubscriptionsNotSupported => {
+                "shared_subscriptions_not_supported"
+            }
+            DisconnectReasonCode::ConnectionRateExceeded => "connection_rate_exceeded",
+            DisconnectReasonCode::MaximumConnectTime => "maximum_connect_time",
+            DisconnectReasonCode::SubscriptionIdentifiersNotSupported => {
+                "subscription_identifiers_not_supported"
+            }
+            DisconnectReasonCode::WildcardSubscriptionsNotSupported => {
+                "wildcard_subscriptions_not_supported"
+            }
+        }
+    }
+}
+reason_code_display!(DisconnectReasonCode);
+
 //2.4 Reason Code
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub enum SubAckReasonCode {
     GrantedQoS0 = 0x0,
     GrantedQoS1 = 0x1,
@@ -329,7 +574,14 @@ impl MqttUncheckedDeserialize for SubAckReasonCode {
     }
     fn unchecked_deserialize<T: Buf>(buf: &mut T) -> Result<Self, DataParseError> {
         let b = MqttOneBytesInt::unchecked_deserialize(buf)?;
-        match b.inner() {
+        SubAckReasonCode::try_from(b.inner())
+    }
+}
+
+impl TryFrom<u8> for SubAckReasonCode {
+    type Error = DataParseError;
+    fn try_from(v: u8) -> Result<Self, DataParseError> {
+        match v {
             0x0 => Ok(SubAckReasonCode::GrantedQoS0),
             0x1 => Ok(SubAckReasonCode::GrantedQoS1),
             0x2 => Ok(SubAckReasonCode::GrantedQoS2),
@@ -346,3 +598,69 @@ impl MqttUncheckedDeserialize for SubAckReasonCode {
         }
     }
 }
+
+impl ReasonCode for SubAckReasonCode {
+    fn value(self) -> u8 {
+        self as u8
+    }
+    fn as_str(self) -> &'static str {
+        match self {
+            SubAckReasonCode::GrantedQoS0 => "granted_qos_0",
+            SubAckReasonCode::GrantedQoS1 => "granted_qos_1",
+            SubAckReasonCode::GrantedQoS2 => "granted_qos_2",
+            SubAckReasonCode::UnspecifiedError => "unspecified_error",
+            SubAckReasonCode::ImplementationSpecificError => "implementation_specific_error",
+            SubAckReasonCode::NotAuthorized => "not_authorized",
+            SubAckReasonCode::TopicFilterInvalid => "topic_filter_invalid",
+            SubAckReasonCode::PacketIdentifierInUse => "packet_identifier_in_use",
+            SubAckReasonCode::QuotaExceeded => "quota_exceeded",
+            SubAckReasonCode::SharedSubscriptionsNotSupported => {
+                "shared_subscriptions_not_supported"
+            }
+            SubAckReasonCode::SubscriptionIdentifiersNotSupported => {
+                "subscription_identifiers_not_supported"
+            }
+            SubAckReasonCode::WildcardSubscriptionsNotSupported => {
+                "wildcard_subscriptions_not_supported"
+            }
+        }
+    }
+}
+reason_code_display!(SubAckReasonCode);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_reason_code_names() {
+        let code = DisconnectReasonCode::KeepAliveTimeout;
+        assert_eq!(code.value(), 0x8d);
+        assert_eq!(code.to_string(), "keep_alive_timeout");
+        assert_eq!(code.category(), ReasonCodeCategory::ClientError);
+        assert!(code.is_error());
+        let code = DisconnectReasonCode::try_from(0x8b).unwrap();
+        assert_eq!(code.as_str(), "server_shutting_down");
+        assert_eq!(code.category(), ReasonCodeCategory::ServerError);
+        assert_eq!(
+            DisconnectReasonCode::DisconnectWithWillMessage.category(),
+            ReasonCodeCategory::Success
+        );
+        assert_eq!(
+            ConnAckReasonCode::BadAuthenicationMethod.as_str(),
+            "bad_authentication_method"
+        );
+        assert_eq!(SubAckReasonCode::GrantedQoS1.to_string(), "granted_qos_1");
+        assert!(!SubAckReasonCode::GrantedQoS1.is_error());
+        assert_eq!(
+            DisconnectReasonCode::try_from(0x01).err(),
+            Some(DataParseError::BadReasonCode)
+        );
+        // every code the decoder accepts has a name
+        for v in 0..=u8::MAX {
+            if let Ok(code) = DisconnectReasonCode::try_from(v) {
+                assert_eq!(code.value(), v);
+                assert!(!code.as_str().is_empty());
+            }
+        }
+    }
+}
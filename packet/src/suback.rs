@@ -8,13 +8,14 @@ use super::{
 };
 use bytes::{Buf, BufMut};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct SubAck {
     // 2.2.1 Packet Identifier
     packet_identifier: MqttTwoBytesInt,
 
     // 3.9.2.1 SUBACK Properties
-    props: Properties,
+    pub(crate) props: Properties,
 
     // 3.9.3 SUBACK Payload
     reason_codes: Vec<SubAckReasonCode>,
@@ -43,6 +44,9 @@ impl SubAck {
     pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
         self.props.get(key)
     }
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        self.props.remove(key)
+    }
     pub fn props_iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props.iter()
     }
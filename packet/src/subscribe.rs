@@ -9,6 +9,7 @@ use super::{
 };
 use bitflags::bitflags;
 use bytes::{Buf, BufMut};
+use std::marker::PhantomData;
 use std::sync::Arc;
 
 #[cfg_attr(feature = "debug", derive(Debug))]
@@ -107,12 +108,13 @@ impl TryInto<QoS> for SubscriptionOptions {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct Subscribe {
     // 2.2.1 Packet Identifier
     packet_identifier: MqttTwoBytesInt,
     // 3.8.2.1 SUBSCRIBE Properties
-    props: Properties,
+    pub(crate) props: Properties,
     /// 3.8.3 SUBSCRIBE Payload
     topics: Vec<(MqttTopic, SubscriptionOptions)>,
 }
@@ -149,6 +151,9 @@ impl Subscribe {
     pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
         self.props.get(key)
     }
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        self.props.remove(key)
+    }
     pub fn props_iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props.iter()
     }
@@ -166,6 +171,53 @@ impl Subscribe {
     }
 }
 
+/// State of a `SubscribeBuilder` before its first topic filter
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub struct NoTopics;
+/// State of a `SubscribeBuilder` holding at least one topic filter
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub struct WithTopics;
+
+/// Builds a `Subscribe`, it cannot be built without a topic filter (3.8.3)
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub struct SubscribeBuilder<S = NoTopics> {
+    subscribe: Subscribe,
+    state: PhantomData<S>,
+}
+
+impl Subscribe {
+    pub fn builder(packet_identifier: u16) -> SubscribeBuilder {
+        SubscribeBuilder {
+            subscribe: Subscribe::new(packet_identifier),
+            state: PhantomData,
+        }
+    }
+}
+
+impl<S> SubscribeBuilder<S> {
+    pub fn topic(
+        mut self,
+        topic: Arc<str>,
+        options: SubscriptionOptions,
+    ) -> Result<SubscribeBuilder<WithTopics>, DataParseError> {
+        self.subscribe.add_topic(topic, options)?;
+        Ok(SubscribeBuilder {
+            subscribe: self.subscribe,
+            state: PhantomData,
+        })
+    }
+    pub fn prop(mut self, key: Property, value: MqttPropValue) -> Result<Self, DataParseError> {
+        self.subscribe.add_prop(key, value)?;
+        Ok(self)
+    }
+}
+
+impl SubscribeBuilder<WithTopics> {
+    pub fn build(self) -> Subscribe {
+        self.subscribe
+    }
+}
+
 impl MqttSerialize for Subscribe {
     fn serialize<T: BufMut>(&self, buf: &mut T) {
         let length = MqttVariableBytesInt::new(self.partial_size() as u32)
@@ -280,4 +332,25 @@ mod test {
         subscribe2.serialize(&mut b2);
         assert_eq!(b, b2);
     }
+    #[test]
+    fn test_subscribe_builder() {
+        let mut subscribe = Subscribe::new(123);
+        subscribe
+            .add_topic(Arc::from("foo"), SubscriptionOptions::NO_LOCAL)
+            .unwrap();
+        subscribe
+            .add_topic(Arc::from("bar"), SubscriptionOptions::QOS1)
+            .unwrap();
+        let built = Subscribe::builder(123)
+            .topic(Arc::from("foo"), SubscriptionOptions::NO_LOCAL)
+            .unwrap()
+            .topic(Arc::from("bar"), SubscriptionOptions::QOS1)
+            .unwrap()
+            .build();
+        assert!(built == subscribe);
+        let both_qos = SubscriptionOptions::QOS1 | SubscriptionOptions::QOS2;
+        assert!(Subscribe::builder(1)
+            .topic(Arc::from("foo"), both_qos)
+            .is_err());
+    }
 }
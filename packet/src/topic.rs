@@ -2,7 +2,8 @@ use super::{data::MqttUtf8String, error::DataParseError, parsable::*};
 use bytes::{Buf, BufMut};
 use std::sync::Arc;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct MqttTopic(MqttUtf8String);
 
 fn is_valid_topic(topic: &str) -> bool {
@@ -10,18 +11,16 @@ fn is_valid_topic(topic: &str) -> bool {
     let mut prev = '/';
     while let Some(c) = iter.next() {
         match c {
-            '#' => {
+            '#'
                 // must be last character
                 // previous character must be `/` or non existant
-                if prev != '/' || iter.peek().is_some() {
+                if (prev != '/' || iter.peek().is_some()) => {
                     return false;
                 }
-            }
-            '+' => {
-                if prev != '/' || *iter.peek().unwrap_or(&'/') != '/' {
+            '+'
+                if (prev != '/' || *iter.peek().unwrap_or(&'/') != '/') => {
                     return false;
                 }
-            }
             _ => (),
         }
         prev = c;
@@ -56,6 +55,102 @@ impl MqttTopic {
     }
 }
 
+/// Prefix of the topic filters of shared subscriptions (4.8.2)
+pub const SHARE_PREFIX: &str = "$share/";
+
+/// Rules a `TopicFilter` is checked against on top of the ones of the specification
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub struct FilterRules {
+    /// Accept empty levels, e.g. `a//b` or `a/`, which 4.7.1 allows
+    pub empty_levels: bool,
+}
+
+impl Default for FilterRules {
+    fn default() -> Self {
+        FilterRules { empty_levels: true }
+    }
+}
+
+/// A topic filter of a SUBSCRIBE or UNSUBSCRIBE. Its wildcards fill whole levels and `#`
+/// is the last one (4.7.1), the share name of a shared subscription holds no wildcard and
+/// is followed by a filter (4.8.2).
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
+pub struct TopicFilter {
+    filter: Arc<str>,
+    // length of the `$share/{name}/` prefix, 0 unless shared
+    prefix: usize,
+}
+
+impl TopicFilter {
+    pub fn new(filter: Arc<str>) -> Result<TopicFilter, DataParseError> {
+        TopicFilter::with_rules(filter, FilterRules::default())
+    }
+    pub fn with_rules(filter: Arc<str>, rules: FilterRules) -> Result<TopicFilter, DataParseError> {
+        let prefix = match filter.strip_prefix(SHARE_PREFIX) {
+            Some(shared) => match shared.split_once('/') {
+                Some((name, _)) if !name.is_empty() && !name.contains(['+', '#']) => {
+                    SHARE_PREFIX.len() + name.len() + 1
+                }
+                _ => return Err(DataParseError::BadTopic),
+            },
+            None => 0,
+        };
+        let inner = &filter[prefix..];
+        // 4.7.3: a filter is at least one character long
+        if inner.is_empty()
+            || !is_valid_topic(inner)
+            || (!rules.empty_levels && inner.split('/').any(str::is_empty))
+        {
+            return Err(DataParseError::BadTopic);
+        }
+        Ok(TopicFilter { filter, prefix })
+    }
+    /// The share name of a shared subscription
+    pub fn share_name(&self) -> Option<&str> {
+        (self.prefix > 0).then(|| &self.filter[SHARE_PREFIX.len()..self.prefix - 1])
+    }
+    /// The filter without the `$share/{name}/` prefix of a shared subscription
+    pub fn filter(&self) -> &str {
+        &self.filter[self.prefix..]
+    }
+    pub fn is_wildcard(&self) -> bool {
+        self.filter().contains(['+', '#'])
+    }
+    /// Whether `topic` matches the filter, a shared subscription matches the topics of its
+    /// filter
+    pub fn matches(&self, topic: &str) -> bool {
+        filter_matches(self.filter(), topic)
+    }
+    pub fn inner(&self) -> &Arc<str> {
+        &self.filter
+    }
+    pub fn unwrap(self) -> Arc<str> {
+        self.filter
+    }
+}
+
+/// Whether `topic` matches `filter` as described in 4.7, `filter` being a valid filter
+/// which is not shared. See `TopicFilter::matches` for filters yet to be checked.
+pub fn filter_matches(filter: &str, topic: &str) -> bool {
+    // 4.7.2: filters starting with a wildcard do not match topics starting with `$`
+    if topic.starts_with('$') && (filter.starts_with('#') || filter.starts_with('+')) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => (),
+            (Some(f), Some(t)) if f == t => (),
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
 impl MqttSerialize for MqttTopic {
     fn serialize<T: BufMut>(&self, buf: &mut T) {
         self.0.serialize(buf);
@@ -79,3 +174,66 @@ impl MqttSize for MqttTopic {
         self.0.size()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn filter(filter: &str) -> Result<TopicFilter, DataParseError> {
+        TopicFilter::new(Arc::from(filter))
+    }
+    #[test]
+    fn test_topic_filter_validation() {
+        for valid in [
+            "a/b",
+            "a/+/c",
+            "+",
+            "#",
+            "a/#",
+            "+/+",
+            "a//b",
+            "$share/g/a/#",
+        ] {
+            assert!(filter(valid).is_ok(), "{}", valid);
+        }
+        for invalid in [
+            "",
+            "a/#/b",
+            "a#",
+            "a/b#",
+            "a+",
+            "a/+b",
+            "$share/g",
+            "$share//a",
+            "$share/g+/a",
+            "$share/g/",
+        ] {
+            assert!(filter(invalid).is_err(), "{}", invalid);
+        }
+        let strict = FilterRules {
+            empty_levels: false,
+        };
+        for empty in ["a//b", "a/", "/a"] {
+            assert!(TopicFilter::with_rules(Arc::from(empty), strict).is_err());
+        }
+        let shared = filter("$share/group/a/+").unwrap();
+        assert_eq!(shared.share_name(), Some("group"));
+        assert_eq!(shared.filter(), "a/+");
+        assert!(filter("a/+").unwrap().share_name().is_none());
+    }
+    #[test]
+    fn test_topic_filter_matches() {
+        let matches = |f: &str, topic: &str| filter(f).unwrap().matches(topic);
+        assert!(matches("a/b", "a/b"));
+        assert!(!matches("a/b", "a/b/c"));
+        assert!(matches("a/+", "a/b"));
+        assert!(!matches("a/+", "a/b/c"));
+        assert!(matches("a/#", "a"));
+        assert!(matches("a/#", "a/b/c"));
+        assert!(matches("+/b", "a/b"));
+        assert!(!matches("#", "$SYS/uptime"));
+        assert!(!matches("+/uptime", "$SYS/uptime"));
+        assert!(matches("$SYS/#", "$SYS/uptime"));
+        assert!(matches("$share/group/a/+", "a/b"));
+        assert!(!matches("$share/group/a/+", "$share/group/a/b"));
+    }
+}
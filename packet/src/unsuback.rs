@@ -8,13 +8,14 @@ use super::{
 };
 use bytes::{Buf, BufMut};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct UnsubAck {
     // 2.2.1 Packet Identifier
     packet_identifier: MqttTwoBytesInt,
 
     // 3.11.2.1 UNSUBACK Properties
-    props: Properties,
+    pub(crate) props: Properties,
 
     // 3.11.3 UNSUBACK Payload
     reason_codes: Vec<UnsubAckReasonCode>,
@@ -43,6 +44,9 @@ impl UnsubAck {
     pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
         self.props.get(key)
     }
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        self.props.remove(key)
+    }
     pub fn props_iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props.iter()
     }
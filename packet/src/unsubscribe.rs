@@ -9,12 +9,13 @@ use super::{
 use bytes::{Buf, BufMut};
 use std::sync::Arc;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(Debug))]
 pub struct Unsubscribe {
     // 2.2.1 Packet Identifier
     packet_identifier: MqttTwoBytesInt,
     // 3.10.2.1 UNSUBSCRIBE Properties
-    props: Properties,
+    pub(crate) props: Properties,
     /// 3.8.3 UNSUBSCRIBE Payload
     topics: Vec<MqttTopic>,
 }
@@ -27,6 +28,9 @@ impl Unsubscribe {
             topics: Vec::new(),
         }
     }
+    pub fn packet_identifier(&self) -> u16 {
+        self.packet_identifier.inner()
+    }
     pub fn topics_iter(&self) -> impl Iterator<Item = &Arc<str>> {
         self.topics.iter().map(|t| t.inner())
     }
@@ -43,6 +47,9 @@ impl Unsubscribe {
     pub fn get_prop(&self, key: Property) -> Option<&[MqttPropValue]> {
         self.props.get(key)
     }
+    pub fn remove_prop(&mut self, key: Property) -> Option<Vec<MqttPropValue>> {
+        self.props.remove(key)
+    }
     pub fn props_iter(&self) -> impl Iterator<Item = (&Property, &MqttPropValue)> {
         self.props.iter()
     }
@@ -0,0 +1,265 @@
+// Invariants spanning several fields of a packet. Parsing checks each field on its own,
+// packets built through the API or decoded leniently may still break these.
+
+use super::{
+    connect::{Connect, ConnectFlags},
+    packet::Packet,
+    props::Property,
+    publish::{Publish, PublishFlags},
+    qos::QoS,
+    subscribe::{Subscribe, SubscriptionOptions},
+    topic::SHARE_PREFIX,
+    unsubscribe::Unsubscribe,
+};
+use std::fmt;
+
+/// An invariant of the MQTT 5 specification broken by a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// A QoS 0 PUBLISH carries a packet identifier
+    PacketIdentifierOnQoS0,
+    /// A QoS 1 or 2 PUBLISH has no packet identifier
+    MissingPacketIdentifier,
+    ZeroPacketIdentifier,
+    /// DUP is set on a QoS 0 PUBLISH
+    DupOnQoS0,
+    /// A PUBLISH with an empty topic name and no topic alias
+    EmptyTopicWithoutAlias,
+    ZeroTopicAlias,
+    /// The Will Flag does not match the presence of a will
+    WillFlagMismatch,
+    /// Will QoS or Will Retain set without a will
+    WillOptionsWithoutWill,
+    /// The User Name Flag does not match the presence of a user name
+    UsernameFlagMismatch,
+    /// The Password Flag does not match the presence of a password
+    PasswordFlagMismatch,
+    ZeroReceiveMaximum,
+    ZeroMaximumPacketSize,
+    /// A SUBSCRIBE without any topic filter
+    EmptySubscribe,
+    /// No Local set on a shared subscription
+    NoLocalOnSharedSubscription,
+    /// An UNSUBSCRIBE without any topic filter
+    EmptyUnsubscribe,
+}
+
+impl Violation {
+    /// The section of the MQTT 5 specification stating the invariant
+    pub fn section(self) -> &'static str {
+        match self {
+            Violation::PacketIdentifierOnQoS0
+            | Violation::MissingPacketIdentifier
+            | Violation::ZeroPacketIdentifier => "2.2.1",
+            Violation::DupOnQoS0 => "3.3.1.1",
+            Violation::EmptyTopicWithoutAlias | Violation::ZeroTopicAlias => "3.3.2.3.4",
+            Violation::WillFlagMismatch => "3.1.2.5",
+            Violation::WillOptionsWithoutWill => "3.1.2.6",
+            Violation::UsernameFlagMismatch => "3.1.2.8",
+            Violation::PasswordFlagMismatch => "3.1.2.9",
+            Violation::ZeroReceiveMaximum => "3.1.2.11.3",
+            Violation::ZeroMaximumPacketSize => "3.1.2.11.4",
+            Violation::EmptySubscribe => "3.8.3",
+            Violation::NoLocalOnSharedSubscription => "3.8.3.1",
+            Violation::EmptyUnsubscribe => "3.10.3",
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} (MQTT 5 section {})", self, self.section())
+    }
+}
+
+impl std::error::Error for Violation {}
+
+impl Packet {
+    /// Checks the invariants spanning several fields of the packet, the first one broken
+    /// is returned
+    pub fn validate(&self) -> Result<(), Violation> {
+        match self {
+            Packet::Publish(p) => p.validate(),
+            Packet::Connect(c) => c.validate(),
+            Packet::Subscribe(s) => s.validate(),
+            Packet::Unsubscribe(u) => u.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Publish {
+    pub fn validate(&self) -> Result<(), Violation> {
+        match (self.qos(), self.packet_identifier()) {
+            (QoS::QoS0, Some(_)) => return Err(Violation::PacketIdentifierOnQoS0),
+            (QoS::QoS0, None) if self.flags().contains(PublishFlags::DUP) => {
+                return Err(Violation::DupOnQoS0)
+            }
+            (QoS::QoS1 | QoS::QoS2, None) => return Err(Violation::MissingPacketIdentifier),
+            (_, Some(0)) => return Err(Violation::ZeroPacketIdentifier),
+            _ => (),
+        }
+        match self.get_prop(Property::TopicAlias) {
+            Some([alias, ..]) if alias.into_u16() == Some(0) => Err(Violation::ZeroTopicAlias),
+            None if self.topic_name().is_empty() => Err(Violation::EmptyTopicWithoutAlias),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Connect {
+    pub fn validate(&self) -> Result<(), Violation> {
+        let flags = self.flags();
+        if flags.contains(ConnectFlags::WILL) != self.will().is_some() {
+            return Err(Violation::WillFlagMismatch);
+        }
+        let will_options =
+            ConnectFlags::WILL_QOS1 | ConnectFlags::WILL_QOS2 | ConnectFlags::WILL_RETAIN;
+        if flags.intersects(will_options) && !flags.contains(ConnectFlags::WILL) {
+            return Err(Violation::WillOptionsWithoutWill);
+        }
+        if flags.contains(ConnectFlags::USERNAME) != self.username().is_some() {
+            return Err(Violation::UsernameFlagMismatch);
+        }
+        if flags.contains(ConnectFlags::PASSWORD) != self.password().is_some() {
+            return Err(Violation::PasswordFlagMismatch);
+        }
+        let is_zero = |key| match self.get_prop(key) {
+            Some([value, ..]) => {
+                value.into_u32().or_else(|| value.into_u16().map(u32::from)) == Some(0)
+            }
+            _ => false,
+        };
+        if is_zero(Property::ReceiveMaximum) {
+            return Err(Violation::ZeroReceiveMaximum);
+        }
+        if is_zero(Property::MaximumPacketSize) {
+            return Err(Violation::ZeroMaximumPacketSize);
+        }
+        Ok(())
+    }
+}
+
+impl Subscribe {
+    pub fn validate(&self) -> Result<(), Violation> {
+        if self.packet_identifier() == 0 {
+            return Err(Violation::ZeroPacketIdentifier);
+        }
+        let mut topics = self.topics_iter().peekable();
+        if topics.peek().is_none() {
+            return Err(Violation::EmptySubscribe);
+        }
+        for (filter, options) in topics {
+            if filter.starts_with(SHARE_PREFIX) && options.contains(SubscriptionOptions::NO_LOCAL) {
+                return Err(Violation::NoLocalOnSharedSubscription);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Unsubscribe {
+    pub fn validate(&self) -> Result<(), Violation> {
+        if self.packet_identifier() == 0 {
+            return Err(Violation::ZeroPacketIdentifier);
+        }
+        if self.topics_iter().next().is_none() {
+            return Err(Violation::EmptyUnsubscribe);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    fn publish(topic: &str) -> Publish {
+        Publish::new(Arc::from(topic), Bytes::new()).unwrap()
+    }
+
+    #[test]
+    fn test_validate_publish() {
+        assert_eq!(publish("a").build().validate(), Ok(()));
+        let mut p = publish("a");
+        p.set_qos(QoS::QoS1);
+        assert_eq!(
+            p.clone().build().validate(),
+            Err(Violation::MissingPacketIdentifier)
+        );
+        p.set_packet_identifier(0).unwrap();
+        assert_eq!(
+            p.clone().build().validate(),
+            Err(Violation::ZeroPacketIdentifier)
+        );
+        p.set_packet_identifier(1).unwrap();
+        p.set_dup();
+        assert_eq!(p.clone().build().validate(), Ok(()));
+        p.set_qos(QoS::QoS0);
+        assert_eq!(p.build().validate(), Err(Violation::PacketIdentifierOnQoS0));
+        let mut p = publish("a");
+        p.set_dup();
+        assert_eq!(p.build().validate(), Err(Violation::DupOnQoS0));
+
+        let empty = publish("");
+        assert_eq!(
+            empty.clone().build().validate(),
+            Err(Violation::EmptyTopicWithoutAlias)
+        );
+        let mut aliased = empty;
+        aliased
+            .add_prop(Property::TopicAlias, MqttPropValue::new_u16(3))
+            .unwrap();
+        assert_eq!(aliased.build().validate(), Ok(()));
+        let mut zero = publish("a");
+        zero.add_prop(Property::TopicAlias, MqttPropValue::new_u16(0))
+            .unwrap();
+        let violation = zero.build().validate().unwrap_err();
+        assert_eq!(violation, Violation::ZeroTopicAlias);
+        assert_eq!(violation.section(), "3.3.2.3.4");
+    }
+
+    #[test]
+    fn test_validate_connect() {
+        let mut connect = Connect::new(Arc::from("c")).unwrap();
+        connect.set_username(Arc::from("u")).unwrap();
+        connect.set_will(Will::new(Arc::from("w"), Bytes::new()).unwrap());
+        connect.set_will_qos(QoS::QoS1).unwrap();
+        assert_eq!(connect.clone().build().validate(), Ok(()));
+        connect
+            .add_prop(Property::ReceiveMaximum, MqttPropValue::new_u16(0))
+            .unwrap();
+        assert_eq!(
+            connect.build().validate(),
+            Err(Violation::ZeroReceiveMaximum)
+        );
+    }
+
+    #[test]
+    fn test_validate_subscriptions() {
+        assert_eq!(
+            Subscribe::new(1).build().validate(),
+            Err(Violation::EmptySubscribe)
+        );
+        let shared = Subscribe::builder(1)
+            .topic(Arc::from("$share/g/a"), SubscriptionOptions::NO_LOCAL)
+            .unwrap()
+            .build();
+        assert_eq!(
+            shared.build().validate(),
+            Err(Violation::NoLocalOnSharedSubscription)
+        );
+        let mut unsubscribe = Unsubscribe::new(0);
+        unsubscribe.add_topic(Arc::from("a")).unwrap();
+        assert_eq!(
+            unsubscribe.build().validate(),
+            Err(Violation::ZeroPacketIdentifier)
+        );
+        assert_eq!(
+            Unsubscribe::new(1).build().validate(),
+            Err(Violation::EmptyUnsubscribe)
+        );
+    }
+}
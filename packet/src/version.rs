@@ -0,0 +1,616 @@
+// MQTT 3.1.1 encodings of the packets. The packets themselves are MQTT 5 packets, a 3.1.1
+// encoding leaves out whatever 3.1.1 cannot carry (properties, most reason codes) and the
+// decoded packets have no properties.
+
+use super::{
+    connack::{ConnAck, ConnAckFlags},
+    connect::{Connect, ConnectFlags, Will},
+    data::{
+        MqttBinaryData, MqttOneBytesInt, MqttTwoBytesInt, MqttUtf8String, MqttVariableBytesInt,
+    },
+    disconnect::Disconnect,
+    error::DataParseError,
+    packet::Packet,
+    packet_type::PacketType,
+    parsable::*,
+    ping::Ping,
+    puback::PubAck,
+    pubcomp::PubComp,
+    publish::{Publish, PublishFlags},
+    pubrec::PubRec,
+    pubrel::PubRel,
+    qos::QoS,
+    reason::{ConnAckReasonCode, DisconnectReasonCode, SubAckReasonCode},
+    suback::SubAck,
+    subscribe::{Subscribe, SubscriptionOptions},
+    unsuback::UnsubAck,
+    unsubscribe::Unsubscribe,
+};
+use bytes::{Buf, BufMut};
+
+/// Protocol Level of a CONNECT packet (3.1.2.2), the other packets of the connection are
+/// encoded for the version it announced
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[repr(u8)]
+pub enum ProtocolVersion {
+    /// MQTT 3.1.1, packets without properties
+    V311 = 4,
+    #[default]
+    V5 = 5,
+}
+
+impl ProtocolVersion {
+    pub fn from_level(level: u8) -> Result<Self, DataParseError> {
+        match level {
+            4 => Ok(ProtocolVersion::V311),
+            5 => Ok(ProtocolVersion::V5),
+            _ => Err(DataParseError::UnsupportedMqttVersion),
+        }
+    }
+    pub fn level(self) -> u8 {
+        self as u8
+    }
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ProtocolVersion::V311 => "3.1.1",
+            ProtocolVersion::V5 => "5.0",
+        }
+    }
+    /// Version announced by the CONNECT packet starting `buf`, `None` if `buf` starts with
+    /// another packet
+    pub fn of_connect(buf: &[u8]) -> Result<Option<Self>, DataParseError> {
+        match buf.first() {
+            Some(byte1) if byte1 >> 4 == PacketType::Connect as u8 => (),
+            _ => return Ok(None),
+        }
+        let mut buf = &buf[1..];
+        MqttVariableBytesInt::deserialize(&mut buf)?;
+        let protocol_name = MqttUtf8String::deserialize(&mut buf)?;
+        if protocol_name.inner().as_ref() != "MQTT" {
+            return Err(DataParseError::BadConnectMessage);
+        }
+        let level = MqttOneBytesInt::deserialize(&mut buf)?;
+        ProtocolVersion::from_level(level.inner()).map(Some)
+    }
+}
+
+impl Packet {
+    pub fn to_bytes_versioned<T: BufMut>(&self, buf: &mut T, version: ProtocolVersion) {
+        match version {
+            ProtocolVersion::V5 => self.to_bytes(buf),
+            ProtocolVersion::V311 => serialize_v311(self, buf),
+        }
+    }
+    pub fn from_bytes_versioned<T: Buf>(
+        buf: &mut T,
+        version: ProtocolVersion,
+    ) -> Result<Self, DataParseError> {
+        match version {
+            ProtocolVersion::V5 => Packet::from_bytes(buf),
+            ProtocolVersion::V311 => deserialize_v311(buf),
+        }
+    }
+    /// `from_bytes_strict` for packets encoded for `version`
+    pub fn from_bytes_strict_versioned<T: Buf>(
+        buf: &mut T,
+        version: ProtocolVersion,
+    ) -> Result<Self, DataParseError> {
+        match version {
+            ProtocolVersion::V5 => Packet::from_bytes_strict(buf),
+            ProtocolVersion::V311 => {
+                Packet::parse_strict(buf, |mut buf| deserialize_v311(&mut buf))
+            }
+        }
+    }
+    /// Length of the packet encoded for `version`, 0 for packets `version` does not have
+    pub fn frame_len_versioned(&self, version: ProtocolVersion) -> usize {
+        match (version, self) {
+            (ProtocolVersion::V5, _) => self.frame_len(),
+            (ProtocolVersion::V311, Packet::Auth(_)) => 0,
+            (ProtocolVersion::V311, _) => {
+                let len = body_len_v311(self);
+                1 + MqttVariableBytesInt::new(len as u32).unwrap().size() + len
+            }
+        }
+    }
+}
+
+fn first_byte(packet: &Packet) -> u8 {
+    let packet_type = match packet {
+        Packet::Publish(p) => return ((PacketType::Publish as u8) << 4) | p.flags().bits(),
+        Packet::Connect(_) => PacketType::Connect,
+        Packet::ConnAck(_) => PacketType::ConnAck,
+        Packet::PubAck(_) => PacketType::PubAck,
+        Packet::PubRec(_) => PacketType::PubRec,
+        Packet::PubRel(_) => PacketType::PubRel,
+        Packet::PubComp(_) => PacketType::PubComp,
+        Packet::Subscribe(_) => PacketType::Subscribe,
+        Packet::SubAck(_) => PacketType::SubAck,
+        Packet::Unsubscribe(_) => PacketType::Unsubscribe,
+        Packet::UnsubAck(_) => PacketType::UnsubAck,
+        Packet::PingReq(_) => PacketType::PingReq,
+        Packet::PingRes(_) => PacketType::PingRes,
+        Packet::Disconnect(_) => PacketType::Disconnect,
+        Packet::Auth(_) => PacketType::Auth,
+    };
+    let flags = packet_type.fixed_flags();
+    ((packet_type as u8) << 4) | flags
+}
+
+fn str_len(s: &str) -> usize {
+    2 + s.len()
+}
+
+fn put_str<T: BufMut>(buf: &mut T, s: &str) {
+    buf.put_u16(s.len() as u16);
+    buf.put_slice(s.as_bytes());
+}
+
+fn put_data<T: BufMut>(buf: &mut T, d: &[u8]) {
+    buf.put_u16(d.len() as u16);
+    buf.put_slice(d);
+}
+
+// the remaining length of the 3.1.1 encoding
+fn body_len_v311(packet: &Packet) -> usize {
+    match packet {
+        // 10 = 6 for "MQTT" + 1 for the level + 1 for the flags + 2 for the keep alive
+        Packet::Connect(c) => {
+            10 + str_len(c.clientid())
+                + c.will()
+                    .map(|w| str_len(w.topic()) + 2 + w.payload().len())
+                    .unwrap_or(0)
+                + c.username().map(|u| str_len(u)).unwrap_or(0)
+                + c.password().map(|p| 2 + p.len()).unwrap_or(0)
+        }
+        Packet::ConnAck(_) => 2,
+        Packet::Publish(p) => {
+            str_len(p.topic_name())
+                + p.packet_identifier().map(|_| 2).unwrap_or(0)
+                + p.payload().len()
+        }
+        Packet::PubAck(_)
+        | Packet::PubRec(_)
+        | Packet::PubRel(_)
+        | Packet::PubComp(_)
+        | Packet::UnsubAck(_) => 2,
+        Packet::Subscribe(s) => 2 + s.topics_iter().map(|(t, _)| str_len(t) + 1).sum::<usize>(),
+        Packet::SubAck(s) => 2 + s.reason_codes().len(),
+        Packet::Unsubscribe(u) => 2 + u.topics_iter().map(|t| str_len(t)).sum::<usize>(),
+        Packet::PingReq(_) | Packet::PingRes(_) | Packet::Disconnect(_) | Packet::Auth(_) => 0,
+    }
+}
+
+// 3.2.2.3: the Connect Return Codes, the reasons 3.1.1 has no code for become "Server
+// unavailable"
+fn connack_return_code(reason_code: ConnAckReasonCode) -> u8 {
+    match reason_code {
+        ConnAckReasonCode::Success => 0,
+        ConnAckReasonCode::UnsupportedProtocolVersion => 1,
+        ConnAckReasonCode::ClientIdentifierNotValid => 2,
+        ConnAckReasonCode::BadUserNameOrPassword => 4,
+        ConnAckReasonCode::NotAuthorized
+        | ConnAckReasonCode::Banned
+        | ConnAckReasonCode::BadAuthenicationMethod => 5,
+        _ => 3,
+    }
+}
+
+// 3.9.3: granted QoS or failure
+fn suback_return_code(reason_code: SubAckReasonCode) -> u8 {
+    match reason_code {
+        SubAckReasonCode::GrantedQoS0 => 0,
+        SubAckReasonCode::GrantedQoS1 => 1,
+        SubAckReasonCode::GrantedQoS2 => 2,
+        _ => 0x80,
+    }
+}
+
+// AUTH does not exist in 3.1.1, nothing is written
+fn serialize_v311<T: BufMut>(packet: &Packet, buf: &mut T) {
+    if let Packet::Auth(_) = packet {
+        return;
+    }
+    buf.put_u8(first_byte(packet));
+    MqttVariableBytesInt::new(body_len_v311(packet) as u32)
+        .expect("Somehow you allocated a packet that is larger than the allowed size")
+        .serialize(buf);
+    match packet {
+        Packet::Connect(c) => {
+            put_str(buf, "MQTT");
+            buf.put_u8(ProtocolVersion::V311.level());
+            buf.put_u8(c.flags().bits());
+            buf.put_u16(c.keep_alive());
+            put_str(buf, c.clientid());
+            if let Some(will) = c.will() {
+                put_str(buf, will.topic());
+                put_data(buf, will.payload());
+            }
+            if let Some(username) = c.username() {
+                put_str(buf, username);
+            }
+            if let Some(password) = c.password() {
+                put_data(buf, password);
+            }
+        }
+        Packet::ConnAck(c) => {
+            let code = connack_return_code(c.reason_code());
+            // 3.2.2-4: no session is present when the connection is refused
+            let flags = if code == 0 { c.flags().bits() } else { 0 };
+            buf.put_u8(flags);
+            buf.put_u8(code);
+        }
+        Packet::Publish(p) => {
+            put_str(buf, p.topic_name());
+            if let Some(id) = p.packet_identifier() {
+                buf.put_u16(id);
+            }
+            buf.put(p.payload());
+        }
+        Packet::PubAck(p) => buf.put_u16(p.identifier()),
+        Packet::PubRec(p) => buf.put_u16(p.identifier()),
+        Packet::PubRel(p) => buf.put_u16(p.identifier()),
+        Packet::PubComp(p) => buf.put_u16(p.identifier()),
+        Packet::Subscribe(s) => {
+            buf.put_u16(s.packet_identifier());
+            for (topic, options) in s.topics_iter() {
+                put_str(buf, topic);
+                // 3.8.3.1: only the requested QoS, the other bits are reserved
+                buf.put_u8(
+                    options.bits() & (SubscriptionOptions::QOS1 | SubscriptionOptions::QOS2).bits(),
+                );
+            }
+        }
+        Packet::SubAck(s) => {
+            buf.put_u16(s.identifier());
+            for reason_code in s.reason_codes() {
+                buf.put_u8(suback_return_code(*reason_code));
+            }
+        }
+        Packet::Unsubscribe(u) => {
+            buf.put_u16(u.packet_identifier());
+            for topic in u.topics_iter() {
+                put_str(buf, topic);
+            }
+        }
+        Packet::UnsubAck(u) => buf.put_u16(u.identifier()),
+        Packet::PingReq(_) | Packet::PingRes(_) | Packet::Disconnect(_) | Packet::Auth(_) => (),
+    }
+}
+
+fn deserialize_v311<T: Buf>(buf: &mut T) -> Result<Packet, DataParseError> {
+    if buf.remaining() < 2 {
+        return Err(DataParseError::InsufficientBuffer {
+            needed: 2,
+            available: buf.remaining(),
+        });
+    }
+    let byte1 = buf.get_u8();
+    let packet_type = PacketType::parse(byte1)?;
+    let length = MqttVariableBytesInt::deserialize(buf)?.inner() as usize;
+    if buf.remaining() < length {
+        return Err(DataParseError::InsufficientBuffer {
+            needed: length,
+            available: buf.remaining(),
+        });
+    }
+    let mut body = buf.take(length);
+    let packet = deserialize_body_v311(packet_type, byte1, &mut body).map_err(|e| match e {
+        // the remaining length announced less than the packet needs
+        DataParseError::InsufficientBuffer { .. } => DataParseError::MalformedPacket,
+        e => e,
+    })?;
+    if body.has_remaining() {
+        return Err(DataParseError::MalformedPacket);
+    }
+    Ok(packet)
+}
+
+fn deserialize_body_v311<T: Buf>(
+    packet_type: PacketType,
+    byte1: u8,
+    buf: &mut T,
+) -> Result<Packet, DataParseError> {
+    let packet = match packet_type {
+        PacketType::Reserved | PacketType::Auth => return Err(DataParseError::BadPacketType),
+        PacketType::Connect => Packet::Connect(deserialize_connect_v311(buf)?),
+        PacketType::ConnAck => {
+            let flags = ConnAckFlags::deserialize(buf)?;
+            let reason_code = match MqttOneBytesInt::deserialize(buf)?.inner() {
+                0 => ConnAckReasonCode::Success,
+                1 => ConnAckReasonCode::UnsupportedProtocolVersion,
+                2 => ConnAckReasonCode::ClientIdentifierNotValid,
+                3 => ConnAckReasonCode::ServerUnavailable,
+                4 => ConnAckReasonCode::BadUserNameOrPassword,
+                5 => ConnAckReasonCode::NotAuthorized,
+                _ => return Err(DataParseError::BadReasonCode),
+            };
+            let mut connack = ConnAck::new();
+            if flags.contains(ConnAckFlags::SESSION_PRESENT) {
+                connack.set_session_present();
+            }
+            connack.set_reason_code(reason_code);
+            connack.build()
+        }
+        PacketType::Publish => {
+            let flags = PublishFlags::deserialize(&mut &[byte1 & 0x0f][..])?;
+            let topic = MqttUtf8String::deserialize(buf)?.unwrap();
+            // 4.7.3: there are no topic aliases to stand for an empty topic name
+            if topic.is_empty() {
+                return Err(DataParseError::BadTopic);
+            }
+            let mut publish = Publish::new(topic, Default::default())?;
+            publish.set_qos(flags.try_into()?);
+            if flags.contains(PublishFlags::RETAIN) {
+                publish.set_retain();
+            }
+            if flags.contains(PublishFlags::DUP) {
+                publish.set_dup();
+            }
+            if publish.qos() != QoS::QoS0 {
+                publish.set_packet_identifier(MqttTwoBytesInt::deserialize(buf)?.inner())?;
+            }
+            publish.set_payload_bytes(buf.copy_to_bytes(buf.remaining()));
+            publish.build()
+        }
+        PacketType::PubAck => PubAck::new(MqttTwoBytesInt::deserialize(buf)?.inner()).build(),
+        PacketType::PubRec => PubRec::new(MqttTwoBytesInt::deserialize(buf)?.inner()).build(),
+        PacketType::PubRel => PubRel::new(MqttTwoBytesInt::deserialize(buf)?.inner()).build(),
+        PacketType::PubComp => PubComp::new(MqttTwoBytesInt::deserialize(buf)?.inner()).build(),
+        PacketType::Subscribe => {
+            let mut subscribe = Subscribe::new(MqttTwoBytesInt::deserialize(buf)?.inner());
+            while buf.has_remaining() {
+                let topic = MqttUtf8String::deserialize(buf)?.unwrap();
+                let options = MqttOneBytesInt::deserialize(buf)?.inner();
+                // 3.8.3-4: the bits above the requested QoS are reserved
+                let options = SubscriptionOptions::from_bits(options)
+                    .filter(|o| {
+                        (*o - (SubscriptionOptions::QOS1 | SubscriptionOptions::QOS2)).is_empty()
+                    })
+                    .ok_or(DataParseError::BadSubscribeMessage)?;
+                let _: QoS = options.try_into()?;
+                subscribe.add_topic(topic, options)?;
+            }
+            // 3.8.3-3: at least one topic filter
+            if subscribe.topics_iter().next().is_none() {
+                return Err(DataParseError::BadSubscribeMessage);
+            }
+            subscribe.build()
+        }
+        PacketType::SubAck => {
+            let mut suback = SubAck::new(MqttTwoBytesInt::deserialize(buf)?.inner());
+            while buf.has_remaining() {
+                suback.add_reason_code(match buf.get_u8() {
+                    0 => SubAckReasonCode::GrantedQoS0,
+                    1 => SubAckReasonCode::GrantedQoS1,
+                    2 => SubAckReasonCode::GrantedQoS2,
+                    0x80 => SubAckReasonCode::UnspecifiedError,
+                    _ => return Err(DataParseError::BadReasonCode),
+                });
+            }
+            suback.build()
+        }
+        PacketType::Unsubscribe => {
+            let mut unsubscribe = Unsubscribe::new(MqttTwoBytesInt::deserialize(buf)?.inner());
+            while buf.has_remaining() {
+                unsubscribe.add_topic(MqttUtf8String::deserialize(buf)?.unwrap())?;
+            }
+            // 3.10.3-2: at least one topic filter
+            if unsubscribe.topics_iter().next().is_none() {
+                return Err(DataParseError::BadUnsubscribeMessage);
+            }
+            unsubscribe.build()
+        }
+        PacketType::UnsubAck => UnsubAck::new(MqttTwoBytesInt::deserialize(buf)?.inner()).build(),
+        PacketType::PingReq => Ping::new().build_req(),
+        PacketType::PingRes => Ping::new().build_res(),
+        PacketType::Disconnect => {
+            Disconnect::new(DisconnectReasonCode::NormalDisconnection).build()
+        }
+    };
+    Ok(packet)
+}
+
+fn deserialize_connect_v311<T: Buf>(buf: &mut T) -> Result<Connect, DataParseError> {
+    let protocol_name = MqttUtf8String::deserialize(buf)?;
+    if protocol_name.inner().as_ref() != "MQTT" {
+        return Err(DataParseError::BadConnectMessage);
+    }
+    let level = MqttOneBytesInt::deserialize(buf)?.inner();
+    if ProtocolVersion::from_level(level)? != ProtocolVersion::V311 {
+        return Err(DataParseError::UnsupportedMqttVersion);
+    }
+    let flags = ConnectFlags::deserialize(buf)?;
+    // 3.1.2-22: no password without a user name
+    if flags.contains(ConnectFlags::PASSWORD) && !flags.contains(ConnectFlags::USERNAME) {
+        return Err(DataParseError::BadConnectMessage);
+    }
+    let keep_alive = MqttTwoBytesInt::deserialize(buf)?.inner();
+    let mut connect = Connect::new(MqttUtf8String::deserialize(buf)?.unwrap())?;
+    connect.set_keep_alive(keep_alive);
+    if flags.contains(ConnectFlags::CLEAN_START) {
+        connect.set_clean_start();
+    }
+    if flags.contains(ConnectFlags::WILL) {
+        let topic = MqttUtf8String::deserialize(buf)?.unwrap();
+        let payload = MqttBinaryData::deserialize(buf)?;
+        connect.set_will(Will::new(topic, payload.inner().clone())?);
+        connect.set_will_qos(flags.try_into()?)?;
+        if flags.contains(ConnectFlags::WILL_RETAIN) {
+            connect.set_will_retain()?;
+        }
+    }
+    if flags.contains(ConnectFlags::USERNAME) {
+        connect.set_username(MqttUtf8String::deserialize(buf)?.unwrap())?;
+    }
+    if flags.contains(ConnectFlags::PASSWORD) {
+        connect.set_password(MqttBinaryData::deserialize(buf)?.inner().clone())?;
+    }
+    Ok(connect)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::{Bytes, BytesMut};
+    use std::sync::Arc;
+
+    fn roundtrip(packet: &Packet) -> (BytesMut, Packet) {
+        let mut b = BytesMut::new();
+        packet.to_bytes_versioned(&mut b, ProtocolVersion::V311);
+        assert_eq!(b.len(), packet.frame_len_versioned(ProtocolVersion::V311));
+        let decoded = Packet::from_bytes_versioned(&mut &b[..], ProtocolVersion::V311).unwrap();
+        (b, decoded)
+    }
+
+    #[test]
+    fn test_connect_v311() {
+        let raw = [
+            0x10, 0x17, // fixed header
+            0x00, 0x04, 0x4d, 0x51, 0x54, 0x54, // Protocol Name
+            0x04, // Protocol Level
+            0x2e, // flags: retained QoS 1 will, clean session
+            0x00, 0x3c, // Keep Alive
+            0x00, 0x01, 0x63, // Client Identifier
+            0x00, 0x01, 0x77, // Will Topic
+            0x00, 0x03, 0x62, 0x79, 0x65, // Will Message
+            0x00, 0x00, // nothing else
+        ];
+        // two bytes left once the packet is read
+        assert_eq!(
+            Packet::from_bytes_versioned(&mut &raw[..], ProtocolVersion::V311).err(),
+            Some(DataParseError::MalformedPacket)
+        );
+        let mut raw = raw[..raw.len() - 2].to_vec();
+        raw[1] = 0x15;
+        assert_eq!(
+            ProtocolVersion::of_connect(&raw),
+            Ok(Some(ProtocolVersion::V311))
+        );
+        // MQTT 5 expects properties
+        assert!(Packet::from_bytes(&mut &raw[..]).is_err());
+        let connect = match Packet::from_bytes_versioned(&mut &raw[..], ProtocolVersion::V311) {
+            Ok(Packet::Connect(c)) => c,
+            _ => panic!("expected a CONNECT"),
+        };
+        assert_eq!(&**connect.clientid(), "c");
+        assert_eq!(connect.keep_alive(), 60);
+        assert_eq!(connect.flags().bits(), 0x2e);
+        assert_eq!(&connect.will().unwrap().payload()[..], b"bye");
+        let (b, _) = roundtrip(&connect.build());
+        assert_eq!(&b[..], &raw[..]);
+
+        let mut v5 = BytesMut::new();
+        Connect::new(Arc::from("c"))
+            .unwrap()
+            .build()
+            .to_bytes(&mut v5);
+        assert_eq!(
+            ProtocolVersion::of_connect(&v5),
+            Ok(Some(ProtocolVersion::V5))
+        );
+        raw[8] = 3;
+        assert_eq!(
+            ProtocolVersion::of_connect(&raw),
+            Err(DataParseError::UnsupportedMqttVersion)
+        );
+        assert_eq!(ProtocolVersion::of_connect(&[0xc0, 0x00]), Ok(None));
+    }
+
+    #[test]
+    fn test_acks_v311() {
+        let mut connack = ConnAck::new();
+        connack.set_session_present();
+        connack.set_reason_code(ConnAckReasonCode::ServerBusy);
+        let (b, decoded) = roundtrip(&connack.build());
+        assert_eq!(&b[..], &[0x20, 0x02, 0x00, 0x03]);
+        assert!(matches!(
+            decoded,
+            Packet::ConnAck(c) if matches!(c.reason_code(), ConnAckReasonCode::ServerUnavailable)
+        ));
+
+        let mut suback = SubAck::new(7);
+        suback.add_reason_code(SubAckReasonCode::GrantedQoS1);
+        suback.add_reason_code(SubAckReasonCode::NotAuthorized);
+        let (b, _) = roundtrip(&suback.build());
+        assert_eq!(&b[..], &[0x90, 0x04, 0x00, 0x07, 0x01, 0x80]);
+
+        let mut puback = PubAck::new(9);
+        puback.set_reason_code(crate::reason::PubAckReasonCode::NoMatchingSubscribers);
+        let (b, _) = roundtrip(&puback.build());
+        assert_eq!(&b[..], &[0x40, 0x02, 0x00, 0x09]);
+
+        let (b, _) = roundtrip(&UnsubAck::new(3).build());
+        assert_eq!(&b[..], &[0xb0, 0x02, 0x00, 0x03]);
+        let (b, _) = roundtrip(&Disconnect::new(DisconnectReasonCode::ServerBusy).build());
+        assert_eq!(&b[..], &[0xe0, 0x00]);
+        // 3.1.1 has no AUTH
+        assert_eq!(
+            Packet::from_bytes_versioned(&mut &[0xf0, 0x00][..], ProtocolVersion::V311).err(),
+            Some(DataParseError::BadPacketType)
+        );
+        // a remaining length of 2 encoded as 0x82 0x00
+        let padded = &[0xb0, 0x82, 0x00, 0x00, 0x03][..];
+        assert!(Packet::from_bytes_versioned(&mut &padded[..], ProtocolVersion::V311).is_ok());
+        assert_eq!(
+            Packet::from_bytes_strict_versioned(&mut &padded[..], ProtocolVersion::V311).err(),
+            Some(DataParseError::MalformedPacket)
+        );
+    }
+
+    #[test]
+    fn test_publish_subscribe_v311() {
+        let mut publish = Publish::new(Arc::from("a/b"), Bytes::from_static(b"hi")).unwrap();
+        publish.set_qos(QoS::QoS1);
+        publish.set_retain();
+        publish.set_packet_identifier(5).unwrap();
+        publish
+            .add_prop(
+                crate::props::Property::ContentType,
+                crate::props::MqttPropValue::new_string(Arc::from("text")).unwrap(),
+            )
+            .unwrap();
+        let (b, decoded) = roundtrip(&publish.build());
+        assert_eq!(
+            &b[..],
+            &[0x33, 0x09, 0x00, 0x03, 0x61, 0x2f, 0x62, 0x00, 0x05, 0x68, 0x69]
+        );
+        match decoded {
+            Packet::Publish(p) => {
+                assert!(p.qos() == QoS::QoS1);
+                assert_eq!(p.packet_identifier(), Some(5));
+                assert_eq!(&p.payload()[..], b"hi");
+                assert_eq!(p.props_iter().count(), 0);
+            }
+            _ => panic!("expected a PUBLISH"),
+        }
+
+        let mut subscribe = Subscribe::new(1);
+        subscribe
+            .add_topic(
+                Arc::from("a/#"),
+                SubscriptionOptions::QOS1 | SubscriptionOptions::NO_LOCAL,
+            )
+            .unwrap();
+        let (b, _) = roundtrip(&subscribe.build());
+        assert_eq!(
+            &b[..],
+            &[0x82, 0x08, 0x00, 0x01, 0x00, 0x03, 0x61, 0x2f, 0x23, 0x01]
+        );
+        // reserved subscription option bits
+        let mut raw = b.to_vec();
+        raw[9] = 0x05;
+        assert_eq!(
+            Packet::from_bytes_versioned(&mut &raw[..], ProtocolVersion::V311).err(),
+            Some(DataParseError::BadSubscribeMessage)
+        );
+
+        let mut unsubscribe = Unsubscribe::new(2);
+        unsubscribe.add_topic(Arc::from("a/#")).unwrap();
+        let (b, _) = roundtrip(&unsubscribe.build());
+        assert_eq!(
+            &b[..],
+            &[0xa2, 0x07, 0x00, 0x02, 0x00, 0x03, 0x61, 0x2f, 0x23]
+        );
+    }
+}
@@ -0,0 +1,33 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    // Embed the commit the broker was built from so it can be reported under $SYS/broker/version,
+    // builds outside of a git checkout (e.g. from a packaged tarball) report "unknown" instead.
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|s| s.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=APIFORMES_COMMIT={}", commit);
+    // HEAD only changes when switching branches, a new commit moves the branch it refers to
+    let git = Path::new("../.git");
+    println!("cargo:rerun-if-changed={}", git.join("HEAD").display());
+    if let Some(branch) = fs::read_to_string(git.join("HEAD"))
+        .ok()
+        .and_then(|head| Some(head.strip_prefix("ref:")?.trim().to_owned()))
+    {
+        let loose = git.join(&branch);
+        // refs are moved to packed-refs by `git gc`
+        let packed = if loose.exists() {
+            loose
+        } else {
+            git.join("packed-refs")
+        };
+        println!("cargo:rerun-if-changed={}", packed.display());
+    }
+}
@@ -0,0 +1,60 @@
+//! Embeds the broker in an application and lets a few clients chat with each other through
+//! it: the application itself through `MqttServer::local_client`, the others over the
+//! loopback interface.
+//!
+//! `cargo run --features client --example embedded_chat`
+use apiformes_server_lib::prelude::*;
+use client::{Client, ClientConfig, ClientError};
+use futures::StreamExt;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), ClientError> {
+    // port 0 lets the system pick a free port, `local_addr` tells which one
+    let server = MqttServer::builder()
+        .mqtt(([127, 0, 0, 1], 0).into())
+        .build()
+        .await
+        .unwrap();
+    let addr = server.local_addr("mqtt").unwrap();
+
+    let mut host = server.local_client().await.unwrap();
+    host.subscribe("chat/room/#", QoS::QoS0).await.unwrap();
+    let names = ["alice", "bob", "carol"];
+    let mut members = Vec::new();
+    for name in names {
+        let client = Client::connect(ClientConfig::new(addr.to_string(), name)).await?;
+        let messages = client.subscribe("chat/room/#", QoS::QoS1).await?;
+        members.push((name, client, messages));
+    }
+    for (name, client, _) in &members {
+        let topic = format!("chat/room/{}", name);
+        client
+            .publish(topic, format!("hi, I am {}", name), QoS::QoS1)
+            .await?;
+    }
+    // every member receives every message, including its own
+    for (name, _, messages) in &mut members {
+        for _ in 0..names.len() {
+            let message = messages.recv().await.unwrap();
+            println!(
+                "[{}] {} says {:?}",
+                name,
+                message.topic,
+                String::from_utf8_lossy(&message.payload)
+            );
+        }
+    }
+    for _ in 0..names.len() {
+        let message = host.next().await.unwrap();
+        println!(
+            "[host] {} says {:?}",
+            message.topic,
+            String::from_utf8_lossy(&message.payload)
+        );
+    }
+    for (_, client, _) in members {
+        client.disconnect().await?;
+    }
+    server.shutdown().await;
+    Ok(())
+}
@@ -0,0 +1,22 @@
+//! Publishes a single message to a running broker.
+//!
+//! `cargo run --features client --example publisher -- [addr] [topic] [message]`
+use apiformes_server_lib::prelude::*;
+use client::{Client, ClientConfig, ClientError};
+use std::env;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), ClientError> {
+    let mut args = env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:1883".to_owned());
+    let topic = args
+        .next()
+        .unwrap_or_else(|| "sensors/kitchen/temperature".to_owned());
+    let message = args.next().unwrap_or_else(|| "21.5".to_owned());
+
+    let client = Client::connect(ClientConfig::new(addr, "example-publisher")).await?;
+    // waits for the PUBACK, the message reached the broker once it returns
+    client.publish(&*topic, message.clone(), QoS::QoS1).await?;
+    println!("published {:?} on {}", message, topic);
+    client.disconnect().await
+}
@@ -0,0 +1,87 @@
+//! Request/response on top of MQTT v5 using the ResponseTopic and CorrelationData
+//! properties, against a broker embedded in the same process. The service answering the
+//! requests is a client of the application, see `MqttServer::local_client`.
+//!
+//! `cargo run --features client --example request_response`
+use apiformes_server_lib::prelude::*;
+use bytes::Bytes;
+use client::{Client, ClientConfig, ClientError};
+use futures::StreamExt;
+use std::sync::Arc;
+
+/// Answers the requests received by `service` with their payload in upper case
+async fn responder(mut service: LocalClient) {
+    while let Some(request) = service.next().await {
+        let property = |wanted| {
+            request
+                .properties
+                .iter()
+                .find(|(property, _)| *property == wanted)
+                .map(|(_, value)| value.clone())
+        };
+        let response_topic = match property(Property::ResponseTopic) {
+            Some(topic) => topic.into_str().unwrap().to_owned(),
+            None => continue,
+        };
+        let body = String::from_utf8_lossy(&request.payload).to_uppercase();
+        let correlation = property(Property::CorrelationData)
+            .map(|correlation| (Property::CorrelationData, correlation));
+        service
+            .publish(
+                &response_topic,
+                Bytes::from(body),
+                QoS::QoS0,
+                false,
+                correlation,
+            )
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), ClientError> {
+    let server = MqttServer::builder()
+        .mqtt(([127, 0, 0, 1], 0).into())
+        .build()
+        .await
+        .unwrap();
+    let addr = server.local_addr("mqtt").unwrap();
+
+    let service = server.local_client().await.unwrap();
+    service
+        .subscribe("services/uppercase", QoS::QoS0)
+        .await
+        .unwrap();
+    tokio::spawn(responder(service));
+
+    // by convention each requester receives its responses below a topic of its own
+    let requester = Client::connect(ClientConfig::new(addr.to_string(), "requester")).await?;
+    let mut responses = requester
+        .subscribe("replies/requester/#", QoS::QoS0)
+        .await?;
+    let response_topic: Arc<str> = Arc::from("replies/requester/uppercase");
+    for (id, text) in ["hello", "world"].iter().enumerate() {
+        let mut request = Publish::new(Arc::from("services/uppercase"), Bytes::from(*text))?;
+        request.add_prop(
+            Property::ResponseTopic,
+            MqttPropValue::new_string(response_topic.clone())?,
+        )?;
+        request.add_prop(
+            Property::CorrelationData,
+            MqttPropValue::new_data(Bytes::from(vec![id as u8]))?,
+        )?;
+        requester.send(request).await?;
+
+        let response = responses.recv().await.unwrap();
+        println!(
+            "request #{} {:?} -> {:?}",
+            id,
+            text,
+            String::from_utf8_lossy(&response.payload)
+        );
+    }
+    requester.disconnect().await?;
+    server.shutdown().await;
+    Ok(())
+}
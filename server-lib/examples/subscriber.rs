@@ -0,0 +1,28 @@
+//! Subscribes to a wildcard filter on a running broker and prints every message.
+//!
+//! `cargo run --features client --example subscriber -- [addr] [filter]`, then run the
+//! `publisher` example from another terminal.
+use apiformes_server_lib::prelude::*;
+use client::{Client, ClientConfig, ClientError};
+use std::env;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), ClientError> {
+    let mut args = env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:1883".to_owned());
+    let filter = args
+        .next()
+        .unwrap_or_else(|| "sensors/+/temperature".to_owned());
+
+    let client = Client::connect(ClientConfig::new(addr, "example-subscriber")).await?;
+    let mut messages = client.subscribe(&*filter, QoS::QoS1).await?;
+    println!("subscribed to {}", filter);
+    while let Some(message) = messages.recv().await {
+        println!(
+            "{}: {}",
+            message.topic,
+            String::from_utf8_lossy(&message.payload)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,252 @@
+use crate::ServerError;
+use std::fs;
+use std::path::Path;
+
+/// What a client asks to do with a topic
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Publishing on a topic name, wills included
+    Publish,
+    /// Subscribing to a topic filter
+    Subscribe,
+}
+
+/// The client an authorization decision is made for
+pub struct Identity<'a> {
+    pub clientid: &'a str,
+    /// Only set when an `AuthProvider` vouched for the username of the CONNECT
+    pub username: Option<&'a str>,
+}
+
+/// Decides which topics clients may publish on and subscribe to. The dispatcher asks it
+/// before accepting every PUBLISH and every topic filter of a SUBSCRIBE, internal clients
+/// are never checked.
+pub trait Authorizer: Send + Sync {
+    fn authorize(&self, identity: &Identity, action: Action, topic: &str) -> bool;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Subject {
+    Client(String),
+    User(String),
+    AnyUser,
+    Anyone,
+}
+
+#[derive(Debug)]
+struct Rule {
+    allow: bool,
+    subject: Subject,
+    // None applies to both actions
+    action: Option<Action>,
+    pattern: String,
+}
+
+/// Access control list loaded from a file, one rule per line:
+///
+/// ```text
+/// # allow|deny  client|user  <id>|*  publish|subscribe|all  <topic filter>
+/// allow user    alice  all       sensors/#
+/// allow client  *      publish   devices/%c/#
+/// deny  client  *      all       #
+/// ```
+///
+/// `user *` applies to every client with a username, `client *` to every client. `%c`
+/// and `%u` in a filter stand for the client id and the username of the client, a
+/// rule using `%u` does not apply to clients without a username. The first rule applying
+/// to a request decides, requests no rule applies to are denied. An allow rule applies to
+/// a subscription only if its filter covers every topic the subscription could match, a
+/// deny rule as soon as the two filters share a topic.
+#[derive(Debug)]
+pub struct AclFile {
+    rules: Vec<Rule>,
+}
+
+impl AclFile {
+    pub fn load(path: &Path) -> Result<Self, ServerError> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            ServerError::InvalidConfig(format!("cannot read acl {}, {}", path.display(), e))
+        })?;
+        content.parse()
+    }
+    fn parse_rule(line: &str) -> Result<Rule, String> {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let (allow, kind, id, action, pattern) = match fields[..] {
+            [allow, kind, id, action, pattern] => (allow, kind, id, action, pattern),
+            _ => return Err("expected 5 fields".to_owned()),
+        };
+        let allow = match allow {
+            "allow" => true,
+            "deny" => false,
+            _ => return Err(format!("unknown permission {}", allow)),
+        };
+        let subject = match (kind, id) {
+            ("client", "*") => Subject::Anyone,
+            ("user", "*") => Subject::AnyUser,
+            ("client", id) => Subject::Client(id.to_owned()),
+            ("user", id) => Subject::User(id.to_owned()),
+            _ => return Err(format!("unknown subject {}", kind)),
+        };
+        let action = match action {
+            "publish" => Some(Action::Publish),
+            "subscribe" => Some(Action::Subscribe),
+            "all" => None,
+            _ => return Err(format!("unknown action {}", action)),
+        };
+        let levels: Vec<_> = pattern.split('/').collect();
+        let misplaced = levels.iter().enumerate().any(|(i, level)| {
+            (level.contains('#') && (*level != "#" || i != levels.len() - 1))
+                || (level.contains('+') && *level != "+")
+        });
+        if misplaced {
+            return Err(format!("invalid topic filter {}", pattern));
+        }
+        Ok(Rule {
+            allow,
+            subject,
+            action,
+            pattern: pattern.to_owned(),
+        })
+    }
+}
+
+impl std::str::FromStr for AclFile {
+    type Err = ServerError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = Self::parse_rule(line)
+                .map_err(|e| ServerError::InvalidConfig(format!("acl line {}: {}", n + 1, e)))?;
+            rules.push(rule);
+        }
+        Ok(AclFile { rules })
+    }
+}
+
+impl Rule {
+    fn applies_to(&self, identity: &Identity) -> bool {
+        match &self.subject {
+            Subject::Anyone => true,
+            Subject::Client(id) => id == identity.clientid,
+            Subject::User(name) => Some(&**name) == identity.username,
+            Subject::AnyUser => identity.username.is_some(),
+        }
+    }
+    /// The pattern with the placeholders replaced, None if the client lacks a username
+    fn expand(&self, identity: &Identity) -> Option<String> {
+        let pattern = self.pattern.replace("%c", identity.clientid);
+        if !pattern.contains("%u") {
+            return Some(pattern);
+        }
+        identity.username.map(|u| pattern.replace("%u", u))
+    }
+}
+
+/// Every topic `filter` matches is also matched by `pattern`
+fn covers(pattern: &[&str], filter: &[&str]) -> bool {
+    match (pattern.split_first(), filter.split_first()) {
+        (Some((&"#", _)), _) => true,
+        (Some((p, pattern)), Some((f, filter))) => {
+            ((*p == "+" && *f != "#") || p == f) && covers(pattern, filter)
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Some topic is matched by both filters
+fn overlaps(a: &[&str], b: &[&str]) -> bool {
+    match (a.split_first(), b.split_first()) {
+        (Some((&"#", _)), _) | (_, Some((&"#", _))) => true,
+        (Some((x, a)), Some((y, b))) => (*x == "+" || *y == "+" || x == y) && overlaps(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+impl Authorizer for AclFile {
+    fn authorize(&self, identity: &Identity, action: Action, topic: &str) -> bool {
+        let topic: Vec<_> = topic.split('/').collect();
+        for rule in &self.rules {
+            if !rule.applies_to(identity) || rule.action.is_some_and(|a| a != action) {
+                continue;
+            }
+            let pattern = match rule.expand(identity) {
+                Some(pattern) => pattern,
+                None => continue,
+            };
+            let pattern: Vec<_> = pattern.split('/').collect();
+            // topic names have no wildcards, both tests agree on them
+            let applies = if rule.allow {
+                covers(&pattern, &topic)
+            } else {
+                overlaps(&pattern, &topic)
+            };
+            if applies {
+                return rule.allow;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    const ACL: &str = "
+        # operators see everything
+        allow user   ops    all       #
+        deny  client *      subscribe sensors/private/#
+        allow client *      subscribe sensors/#
+        allow client *      publish   devices/%c/#
+        allow user   *      all       users/%u/+
+    ";
+    fn id<'a>(clientid: &'a str, username: Option<&'a str>) -> Identity<'a> {
+        Identity { clientid, username }
+    }
+    #[test]
+    fn test_acl_rules() {
+        let acl: AclFile = ACL.parse().unwrap();
+        let anon = id("dev1", None);
+        assert!(acl.authorize(&anon, Action::Publish, "devices/dev1/temp"));
+        assert!(!acl.authorize(&anon, Action::Publish, "devices/dev2/temp"));
+        assert!(!acl.authorize(&anon, Action::Subscribe, "devices/dev1/temp"));
+        assert!(acl.authorize(&anon, Action::Subscribe, "sensors/kitchen/+"));
+        // overlaps the private sensors
+        assert!(!acl.authorize(&anon, Action::Subscribe, "sensors/#"));
+        assert!(!acl.authorize(&anon, Action::Subscribe, "sensors/+/x"));
+        assert!(!acl.authorize(&anon, Action::Subscribe, "#"));
+        // %u never applies without a username
+        assert!(!acl.authorize(&anon, Action::Publish, "users//x"));
+
+        let alice = id("dev2", Some("alice"));
+        assert!(acl.authorize(&alice, Action::Subscribe, "users/alice/+"));
+        assert!(!acl.authorize(&alice, Action::Subscribe, "users/alice/#"));
+        assert!(!acl.authorize(&alice, Action::Publish, "users/bob/x"));
+
+        let ops = id("console", Some("ops"));
+        assert!(acl.authorize(&ops, Action::Subscribe, "sensors/#"));
+        assert!(acl.authorize(&ops, Action::Publish, "anything"));
+    }
+    #[test]
+    fn test_acl_syntax() {
+        assert!("allow client * all a/#".parse::<AclFile>().is_ok());
+        for bad in [
+            "allow client *",
+            "permit client * all #",
+            "allow group * all #",
+            "allow client * read #",
+            "allow client * all a/#/b",
+            "allow client * all a/b+",
+        ] {
+            assert!(matches!(
+                bad.parse::<AclFile>(),
+                Err(ServerError::InvalidConfig(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use crate::ServerError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Category of an `ApiError`, automation should branch on it rather than on the message
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiErrorCode {
+    /// The configuration is refused, `ApiError::field` names the setting when known
+    InvalidConfig,
+    /// An argument of the request is refused
+    InvalidArgument,
+    /// Something the broker relies on, such as a listener address or a file, is not usable
+    Unavailable,
+    /// What the request names does not exist, such as a client that is not connected
+    NotFound,
+    /// Anything else, the message is meant for humans only
+    Internal,
+}
+
+impl ApiErrorCode {
+    /// Exit status of a command line tool failing with this error, as defined by sysexits.h
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ApiErrorCode::InvalidArgument => 64,
+            ApiErrorCode::NotFound => 67,
+            ApiErrorCode::Unavailable => 69,
+            ApiErrorCode::Internal => 70,
+            ApiErrorCode::InvalidConfig => 78,
+        }
+    }
+}
+
+/// Error returned by the management operations of the broker, serialized as
+/// `{"code":"invalid_config","message":"...","field":"max_qos"}`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiError {
+    pub code: ApiErrorCode,
+    pub message: String,
+    /// The setting or argument the error is about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(code: ApiErrorCode, message: String) -> Self {
+        ApiError {
+            code,
+            message,
+            field: None,
+        }
+    }
+    pub fn with_field(mut self, field: &str) -> Self {
+        self.field = Some(field.to_owned());
+        self
+    }
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "{}: {}", field, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl From<ServerError> for ApiError {
+    fn from(err: ServerError) -> ApiError {
+        match err {
+            ServerError::InvalidSetting { field, reason } => {
+                ApiError::new(ApiErrorCode::InvalidConfig, reason).with_field(field)
+            }
+            ServerError::InvalidConfig(reason) => {
+                ApiError::new(ApiErrorCode::InvalidConfig, reason)
+            }
+            ServerError::Io(e) => ApiError::new(ApiErrorCode::Unavailable, e.to_string()),
+            e => ApiError::new(ApiErrorCode::Internal, format!("{:?}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+    #[test]
+    fn test_api_error() {
+        let err = ApiError::from(ServerError::InvalidSetting {
+            field: "max_qos",
+            reason: "above the highest supported QoS".to_owned(),
+        });
+        assert_eq!(err.code, ApiErrorCode::InvalidConfig);
+        assert_eq!(err.code.exit_code(), 78);
+        assert_eq!(
+            err.to_json(),
+            r#"{"code":"invalid_config","message":"above the highest supported QoS","field":"max_qos"}"#
+        );
+        let err = ApiError::from(ServerError::Io(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "in use",
+        )));
+        assert_eq!(err.code, ApiErrorCode::Unavailable);
+        assert_eq!(
+            err.to_json(),
+            r#"{"code":"unavailable","message":"in use"}"#
+        );
+        let parsed: ApiError = serde_json::from_str(&err.to_json()).unwrap();
+        assert_eq!(parsed.code, ApiErrorCode::Unavailable);
+        assert_eq!(parsed.field, None);
+        let err = ApiError::from(ServerError::ServerBusy);
+        assert_eq!(err.code.exit_code(), 70);
+    }
+}
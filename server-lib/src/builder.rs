@@ -0,0 +1,129 @@
+use crate::{
+    acl::Authorizer,
+    clients::{AuthProvider, ClientIdGenerator},
+    config::{ListenerConfig, MqttServerConfig},
+    error::ServerError,
+    intercept::Interceptor,
+    storage::Storage,
+    validate::{PayloadValidator, TopicValidator},
+    MqttServer,
+};
+use std::{net::SocketAddr, sync::Arc};
+
+/// Assembles the configuration of a `MqttServer` along with the components plugged into
+/// it, see `MqttServer::builder`. Settings depending on each other are checked by `build`.
+#[derive(Default)]
+pub struct MqttServerBuilder {
+    cfg: MqttServerConfig,
+}
+
+impl From<MqttServerConfig> for MqttServerBuilder {
+    /// Starts from `cfg`, e.g. one loaded with `MqttServerConfig::load`
+    fn from(cfg: MqttServerConfig) -> Self {
+        MqttServerBuilder { cfg }
+    }
+}
+
+impl MqttServerBuilder {
+    /// Listens for MQTT without encryption on `saddr`
+    pub fn mqtt(mut self, saddr: SocketAddr) -> Self {
+        self.cfg.mqtt_socketaddr = Some(saddr);
+        self
+    }
+    /// Listens for encrypted MQTT on `saddr`, with `private_key` as the static key
+    #[cfg(feature = "noise")]
+    pub fn noise(mut self, saddr: SocketAddr, private_key: [u8; 32]) -> Self {
+        self.cfg.noise_socketaddr = Some(saddr);
+        self.cfg.private_key = private_key;
+        self
+    }
+    /// Serves MQTT and MQTT over WebSocket on `saddr`
+    #[cfg(feature = "websocket")]
+    pub fn multiplex(mut self, saddr: SocketAddr) -> Self {
+        self.cfg.multiplex_socketaddr = Some(saddr);
+        self
+    }
+    /// Serves the HTTP management API on `saddr`
+    #[cfg(feature = "admin")]
+    pub fn admin(mut self, saddr: SocketAddr) -> Self {
+        self.cfg.admin_socketaddr = Some(saddr);
+        self
+    }
+    /// Adds a listener with its own settings
+    pub fn listener(mut self, listener: ListenerConfig) -> Self {
+        self.cfg.listeners.push(listener);
+        self
+    }
+    /// Offers the enhanced authentication method of `provider`
+    pub fn auth_provider(mut self, provider: Arc<dyn AuthProvider>) -> Self {
+        self.cfg.auth_providers.push(provider);
+        self
+    }
+    pub fn authorizer(mut self, authorizer: Arc<dyn Authorizer>) -> Self {
+        self.cfg.authorizer = Some(authorizer);
+        self
+    }
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.cfg.storage = Some(storage);
+        self
+    }
+    /// Checks the payloads published on the topics starting with `prefix`
+    pub fn payload_validator(mut self, prefix: &str, validator: Arc<dyn PayloadValidator>) -> Self {
+        self.cfg.payload_validators.push(TopicValidator {
+            prefix: prefix.to_owned(),
+            validator,
+        });
+        self
+    }
+    pub fn clientid_generator(mut self, generator: Arc<dyn ClientIdGenerator>) -> Self {
+        self.cfg.clientid_generator = generator;
+        self
+    }
+    /// Adds `interceptor` after the ones added so far
+    pub fn interceptor(mut self, interceptor: Arc<dyn Interceptor>) -> Self {
+        self.cfg.interceptors.push(interceptor);
+        self
+    }
+    /// Changes the settings without a method of their own
+    pub fn configure(mut self, configure: impl FnOnce(&mut MqttServerConfig)) -> Self {
+        configure(&mut self.cfg);
+        self
+    }
+    /// Checks that the server could start, see `MqttServerConfig::check`
+    pub async fn check(&self) -> Result<(), ServerError> {
+        self.cfg.check().await
+    }
+    /// Validates the configuration and starts the server
+    pub async fn build(self) -> Result<MqttServer, ServerError> {
+        MqttServer::new(self.cfg).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acl::AclFile;
+
+    #[tokio::test]
+    async fn test_build() {
+        let acl: AclFile = "allow client * all #".parse().unwrap();
+        let conflicting = MqttServer::builder()
+            .mqtt("127.0.0.1:0".parse().unwrap())
+            .authorizer(Arc::new(acl))
+            .configure(|cfg| cfg.acl_path = Some("acl.txt".into()));
+        assert!(matches!(
+            conflicting.build().await,
+            Err(ServerError::InvalidSetting {
+                field: "acl_path",
+                ..
+            })
+        ));
+        let server = MqttServer::builder()
+            .mqtt("127.0.0.1:0".parse().unwrap())
+            .configure(|cfg| cfg.max_qos = 0)
+            .build()
+            .await
+            .unwrap();
+        server.shutdown().await;
+    }
+}
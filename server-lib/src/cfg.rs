@@ -1,7 +1,7 @@
-pub const MAX_QOS: u8 = 0;
+pub const MAX_QOS: u8 = 1;
 pub const TOPIC_ALIAS_MAX: u16 = 0;
 pub const WILDCARD_SUB: bool = false;
 pub const SUB_ID: bool = false;
-pub const SHARED_SUB: bool = false;
+pub const SHARED_SUB: bool = true;
 #[cfg(feature = "noise")]
 pub const NOISE_PATTERN: &str = "Noise_XK_25519_ChaChaPoly_BLAKE2s";
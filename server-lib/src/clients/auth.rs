@@ -0,0 +1,39 @@
+use bytes::Bytes;
+use std::sync::Arc;
+
+/// Outcome of one step of an enhanced authentication exchange
+pub enum AuthStep {
+    /// The client is authenticated, the data, if any, is sent along with the CONNACK
+    Success(Option<Bytes>),
+    /// The challenge to send to the client in an AUTH packet, its response is handed to the
+    /// next step
+    Continue(Bytes),
+    /// The connection is refused with the NotAuthorized reason code
+    Failure,
+}
+
+/// Implements an enhanced authentication method such as SCRAM or Kerberos (4.12). Clients
+/// pick it by naming `method` in the AuthenticationMethod property of their CONNECT.
+pub trait AuthProvider: Send + Sync {
+    /// The AuthenticationMethod this provider implements
+    fn method(&self) -> &str;
+    /// Starts the exchange authenticating one connection, `clientid` is the one of the
+    /// CONNECT and may be empty when the server is to assign it. The exchange vouches for
+    /// `username` too when it succeeds, authorization rules may rely on it.
+    fn start(&self, clientid: &str, username: Option<&str>) -> Box<dyn AuthExchange>;
+}
+
+/// State of the exchange authenticating one connection
+pub trait AuthExchange: Send {
+    /// Handles the AuthenticationData of the CONNECT, then the one of every AUTH packet
+    /// the client answers a challenge with
+    fn step(&mut self, data: Option<&[u8]>) -> AuthStep;
+}
+
+/// The provider registered for `method`, if any
+pub(super) fn find_provider(
+    providers: &[Arc<dyn AuthProvider>],
+    method: &str,
+) -> Option<Arc<dyn AuthProvider>> {
+    providers.iter().find(|p| p.method() == method).cloned()
+}
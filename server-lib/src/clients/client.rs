@@ -1,28 +1,84 @@
-use crate::ServerError;
-use apiformes_packet::prelude::Packet;
-use std::sync::Arc;
+use super::{
+    inflight::InFlight,
+    outbound::SharedFrame,
+    queue::{Outgoing, OutgoingSender, Pushed},
+};
+#[cfg(feature = "noise")]
+use crate::config::Permeability;
+use crate::{
+    cfg::MAX_QOS, config::PublishQuota, quota::QuotaTracker, shutdown::Shutdown, ServerError,
+};
+use apiformes_packet::prelude::{
+    Disconnect, DisconnectReasonCode, MqttPropValue, Packet, Property, Publish, QoS,
+};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
 use tokio::sync::{mpsc::UnboundedSender, Notify};
+use tokio::time::Instant;
+
+/// Client identifiers starting with this prefix are reserved for components living inside
+/// the broker (bridges, cluster links, control handlers, ...), external clients are not
+/// allowed to use them.
+pub const INTERNAL_CLIENTID_PREFIX: &str = "$internal/";
+
+pub fn is_internal_clientid(clientid: &str) -> bool {
+    clientid.starts_with(INTERNAL_CLIENTID_PREFIX)
+}
+
 #[derive(Clone)]
 pub struct Client {
     pub(super) session_expirary: u32,
     pub(super) recv_max: u16,
     pub(super) max_packet_size: u32,
     pub(super) topic_alias_max: u16,
+    /// the MaximumQoS advertised to the client in the CONNACK
+    pub(super) max_qos: QoS,
+    /// negotiated keep alive in seconds, 0 means the client is never timed out
+    pub(super) keep_alive: u16,
     pub(super) response_info: bool,
     pub(super) problem_info: bool,
     pub(super) encrypted: bool,
+    /// tag of the listener the client connected to
+    pub(super) listener: Arc<str>,
+    /// whether the client receives the publishes of encrypted clients while unencrypted
+    #[cfg(feature = "noise")]
+    pub(super) permeability: Permeability,
+    /// the CONNACK told the client its previous session was resumed
+    pub(super) session_present: bool,
     pub(super) clientid: Arc<str>,
+    /// username of the CONNECT, only kept once an `AuthProvider` accepted it
+    pub(super) username: Option<Arc<str>>,
     //global server shutdown
-    pub(super) shutdown: Arc<Notify>,
+    pub(super) shutdown: Shutdown,
     // local shutdown signal
     pub(super) killme: Arc<Notify>,
-    outgoing: UnboundedSender<Packet>,
+    // set before `killme` is notified when another connection takes over the client id
+    taken_over: Arc<AtomicBool>,
+    // set before `killme` is notified when the outgoing queue overflowed
+    overflowed: Arc<AtomicBool>,
+    // set by the first send finding the outgoing queue closed
+    closed: Arc<AtomicBool>,
+    // where the connection is reported once its queue is found closed
+    purge: Option<UnboundedSender<Client>>,
+    /// QoS 1 deliveries waiting for a PUBACK, shared with the connection resuming the session
+    pub(super) inflight: Arc<InFlight>,
+    /// published in place of the client when the connection ends abnormally (3.1.2.5)
+    pub(super) will: Option<Publish>,
+    /// WillDelayInterval in seconds
+    pub(super) will_delay: u32,
+    outgoing: OutgoingSender,
+    // only set when the listener of the connection has a publish quota
+    quota: Option<Arc<Mutex<QuotaTracker>>>,
+    // what the client publishes or subscribes to is not submitted to the authorizer
+    acl_exempt: bool,
 }
 
 impl Client {
     pub(super) fn new(
-        shutdown: Arc<Notify>,
-        outgoing: UnboundedSender<Packet>,
+        shutdown: Shutdown,
+        outgoing: OutgoingSender,
         encrypted: bool,
         max_packet_size: u32,
     ) -> Self {
@@ -31,30 +87,216 @@ impl Client {
             recv_max: u16::MAX,
             max_packet_size,
             topic_alias_max: 0,
+            max_qos: QoS::QoS0,
+            keep_alive: 0,
             response_info: false,
             problem_info: true,
             clientid: Arc::from(""), //TODO lazy static would be useful here as well
+            username: None,
             shutdown,
             killme: Arc::new(Notify::new()),
+            taken_over: Arc::new(AtomicBool::new(false)),
+            overflowed: Arc::new(AtomicBool::new(false)),
+            closed: Arc::new(AtomicBool::new(false)),
+            purge: None,
+            inflight: Arc::new(InFlight::new()),
+            will: None,
+            will_delay: 0,
             outgoing,
             encrypted,
+            listener: Arc::from(""),
+            #[cfg(feature = "noise")]
+            permeability: Permeability::Strict,
+            session_present: false,
+            quota: None,
+            acl_exempt: false,
+        }
+    }
+    /// Holds the publishes of the client to `quota`
+    pub(super) fn limit_publishes(&mut self, quota: Option<PublishQuota>) {
+        self.quota = quota.map(|q| Arc::new(Mutex::new(QuotaTracker::new(q, Instant::now()))));
+    }
+    /// Charges a publish of `payload_len` bytes to the quota of the client, the reason
+    /// code to disconnect it with once over it
+    pub(crate) fn charge_publish(&self, payload_len: usize) -> Result<(), DisconnectReasonCode> {
+        match &self.quota {
+            Some(quota) => quota.lock().unwrap().charge(payload_len, Instant::now()),
+            None => Ok(()),
         }
     }
 
+    pub(crate) fn new_internal(
+        clientid: Arc<str>,
+        shutdown: Shutdown,
+        outgoing: OutgoingSender,
+        acl_exempt: bool,
+    ) -> Self {
+        let mut client = Client::new(shutdown, outgoing, false, u32::MAX);
+        client.clientid = clientid;
+        client.max_qos = QoS::from_u8(MAX_QOS).unwrap();
+        client.acl_exempt = acl_exempt;
+        client
+    }
+    /// Internal clients registered with `InternalClient::register` are trusted with any
+    /// topic, the authorizer decides for the others
+    pub fn acl_exempt(&self) -> bool {
+        self.acl_exempt
+    }
+
     pub fn encrypted(&self) -> bool {
         self.encrypted
     }
+    #[cfg(all(test, feature = "noise"))]
+    pub(crate) fn set_encrypted(&mut self) {
+        self.encrypted = true;
+    }
+    pub fn internal(&self) -> bool {
+        is_internal_clientid(&self.clientid)
+    }
+    /// Tag of the listener the client connected to, e.g. `mqtt` or one of
+    /// `MqttServerConfig::listeners`. Empty for internal clients.
+    pub fn listener(&self) -> &Arc<str> {
+        &self.listener
+    }
+    #[cfg(feature = "noise")]
+    pub fn permeability(&self) -> Permeability {
+        self.permeability
+    }
+    /// Highest QoS this client may publish with and be granted on subscriptions
+    pub fn max_qos(&self) -> QoS {
+        self.max_qos
+    }
+    /// The client wants to be told why its requests failed (3.1.2.11.7)
+    pub fn problem_info(&self) -> bool {
+        self.problem_info
+    }
+    /// `reason` as the value of a ReasonString, only when the client asked for it
+    pub(crate) fn explain(&self, reason: Option<String>) -> Option<MqttPropValue> {
+        reason
+            .filter(|_| self.problem_info)
+            .and_then(|reason| MqttPropValue::new_string(Arc::from(reason)).ok())
+    }
+    /// A DISCONNECT with `reason_code`, explained by `err` when the client asked for it
+    pub(crate) fn disconnect(
+        &self,
+        reason_code: DisconnectReasonCode,
+        err: &ServerError,
+    ) -> Packet {
+        let mut disconnect = Disconnect::new(reason_code);
+        if let Some(reason) = self.explain(err.reason_string()) {
+            disconnect.add_prop(Property::ReasonString, reason).unwrap();
+        }
+        disconnect.build()
+    }
+    pub fn clientid(&self) -> &Arc<str> {
+        &self.clientid
+    }
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+    /// Number of packets queued for this client its worker did not take yet
+    pub fn backlog(&self) -> usize {
+        self.outgoing.len()
+    }
+    /// Number of packets discarded because the queue of this client was full, see
+    /// `MqttServerConfig::max_outgoing_packets`
+    pub fn dropped(&self) -> u64 {
+        self.outgoing.dropped()
+    }
+    /// Number of QoS 1 publishes this client has not acknowledged yet
+    pub fn unacknowledged(&self) -> usize {
+        self.inflight.len()
+    }
+    /// Checks if both handles refer to the same connection, a client id may be reused by a
+    /// new connection while the worker of the old one is still retiring
+    pub fn same_connection(&self, other: &Client) -> bool {
+        Arc::ptr_eq(&self.killme, &other.killme)
+    }
     pub fn shutdown(self) {
-        self.shutdown.notify_one();
+        self.shutdown.trigger();
     }
 
     pub fn killme(self) {
         self.killme.notify_one();
     }
 
-    pub fn send(&self, packet: Packet) -> Result<(), ServerError> {
-        self.outgoing
-            .send(packet)
-            .map_err(|_| ServerError::Misc("outgoing channel is closed".to_owned()))
+    /// Stops the worker of this client because a new connection uses the same client id
+    pub(super) fn take_over(&self) {
+        self.taken_over.store(true, Ordering::Release);
+        self.killme.notify_one();
+    }
+    pub(super) fn taken_over(&self) -> bool {
+        self.taken_over.load(Ordering::Acquire)
+    }
+    pub(super) fn overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::Acquire)
+    }
+    /// Reports this connection to `purge` the first time a packet cannot be queued because
+    /// its worker is gone, so it is removed without waiting for the worker to retire
+    pub(super) fn purge_on_close(&mut self, purge: UnboundedSender<Client>) {
+        self.purge = Some(purge);
+    }
+
+    /// Queues `packet` for delivery to this client. Outbound packets are immutable, so
+    /// fanning out a shared `Arc<Packet>` avoids copying it once per recipient. A full
+    /// queue discards packets or disconnects the client, see `OverflowPolicy`.
+    pub fn send(&self, packet: impl Into<Arc<Packet>>) -> Result<(), ServerError> {
+        self.queue(Outgoing::from(packet.into()))
+    }
+    /// Same as `send` for a publish queued unchanged for several clients, their workers
+    /// write the encoding held by `frame` instead of serializing the publish again
+    pub(crate) fn send_shared(
+        &self,
+        packet: Arc<Packet>,
+        frame: Arc<SharedFrame>,
+    ) -> Result<(), ServerError> {
+        self.queue(Outgoing {
+            packet,
+            frame: Some(frame),
+        })
+    }
+    fn queue(&self, outgoing: Outgoing) -> Result<(), ServerError> {
+        let pushed = match self.outgoing.push(outgoing) {
+            Ok(pushed) => pushed,
+            Err(_) => {
+                if !self.closed.swap(true, Ordering::AcqRel) {
+                    if let Some(purge) = &self.purge {
+                        // the manager is only gone during the shutdown
+                        let _ = purge.send(self.clone());
+                    }
+                }
+                return Err(ServerError::Misc("outgoing channel is closed".to_owned()));
+            }
+        };
+        if pushed == Pushed::Overflowed && !self.overflowed.swap(true, Ordering::AcqRel) {
+            self.killme.notify_one();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clients::outgoing_queue;
+    use crate::config::OverflowPolicy;
+    use apiformes_packet::prelude::PubAck;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn test_purge_on_close() {
+        let (tx, rx) = outgoing_queue(None, OverflowPolicy::Disconnect);
+        let (purge_tx, mut purge_rx) = unbounded_channel();
+        let mut client = Client::new(Shutdown::new(), tx, false, u32::MAX);
+        client.purge_on_close(purge_tx);
+        let copy = client.clone();
+        assert!(client.send(PubAck::new(1).build()).is_ok());
+        assert!(purge_rx.try_recv().is_err());
+        // the worker is gone, only the first failure is reported
+        drop(rx);
+        assert!(client.send(PubAck::new(2).build()).is_err());
+        assert!(copy.send(PubAck::new(3).build()).is_err());
+        assert!(purge_rx.try_recv().unwrap().same_connection(&client));
+        assert!(purge_rx.try_recv().is_err());
     }
 }
@@ -0,0 +1,118 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// What is known about a connection when the server has to assign it a client id
+pub struct ConnectionInfo<'a> {
+    pub peer_addr: SocketAddr,
+    /// Static public key of the peer, only available for Noise connections
+    pub public_key: Option<&'a [u8]>,
+}
+
+/// Produces the ids assigned to clients connecting with an empty client id (3.1.3.1).
+///
+/// The server takes care of collisions: an id which is already in use, by a connection or
+/// a retained session, is discarded and `generate` is called again a few times before
+/// falling back to `UuidClientIds`. Stable ids are the exception, see `is_stable`.
+pub trait ClientIdGenerator: Send + Sync {
+    fn generate(&self, info: &ConnectionInfo) -> Arc<str>;
+    /// Whether `generate` always returns the same id for this connection, e.g. derived from
+    /// its key. The connection then takes over the connection and session holding the id,
+    /// as a client sending its own client id does.
+    fn is_stable(&self, _info: &ConnectionInfo) -> bool {
+        false
+    }
+}
+
+/// Assigns random UUIDs, this is the default
+pub struct UuidClientIds;
+
+impl ClientIdGenerator for UuidClientIds {
+    fn generate(&self, _: &ConnectionInfo) -> Arc<str> {
+        Uuid::new_v4().to_hyphenated().to_string().into()
+    }
+}
+
+/// Assigns `<prefix><n>` where `n` is a counter starting at 1
+pub struct SequentialClientIds {
+    prefix: String,
+    next: AtomicU64,
+}
+
+impl SequentialClientIds {
+    pub fn new(prefix: &str) -> Self {
+        SequentialClientIds {
+            prefix: prefix.to_owned(),
+            next: AtomicU64::new(1),
+        }
+    }
+}
+
+impl ClientIdGenerator for SequentialClientIds {
+    fn generate(&self, _: &ConnectionInfo) -> Arc<str> {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        format!("{}{}", self.prefix, n).into()
+    }
+}
+
+/// Derives `<prefix><hex encoded public key>` from the peer's Noise static key so a device
+/// gets the same id every time it connects. Connections without a public key fall back
+/// to random UUIDs.
+pub struct PublicKeyClientIds {
+    prefix: String,
+}
+
+impl PublicKeyClientIds {
+    pub fn new(prefix: &str) -> Self {
+        PublicKeyClientIds {
+            prefix: prefix.to_owned(),
+        }
+    }
+    fn id_for_key(&self, key: &[u8]) -> String {
+        let mut id = String::with_capacity(self.prefix.len() + key.len() * 2);
+        id.push_str(&self.prefix);
+        for b in key {
+            write!(id, "{:02x}", b).unwrap();
+        }
+        id
+    }
+}
+
+impl ClientIdGenerator for PublicKeyClientIds {
+    fn generate(&self, info: &ConnectionInfo) -> Arc<str> {
+        match info.public_key {
+            Some(key) => self.id_for_key(key).into(),
+            None => UuidClientIds.generate(info),
+        }
+    }
+    fn is_stable(&self, info: &ConnectionInfo) -> bool {
+        info.public_key.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_clientid_generators() {
+        let info = ConnectionInfo {
+            peer_addr: SocketAddr::from(([127, 0, 0, 1], 1883)),
+            public_key: Some(&[0xde, 0xad, 0x01]),
+        };
+        let seq = SequentialClientIds::new("dev-");
+        assert_eq!(&*seq.generate(&info), "dev-1");
+        assert_eq!(&*seq.generate(&info), "dev-2");
+        let pk = PublicKeyClientIds::new("key-");
+        assert_eq!(&*pk.generate(&info), "key-dead01");
+        assert!(pk.is_stable(&info));
+        assert!(!seq.is_stable(&info));
+        let info = ConnectionInfo {
+            public_key: None,
+            ..info
+        };
+        assert_ne!(pk.generate(&info), pk.generate(&info));
+        assert!(!pk.is_stable(&info));
+    }
+}
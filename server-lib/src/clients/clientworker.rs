@@ -1,20 +1,61 @@
 #[cfg(feature = "noise")]
 use super::noiseclient::NoiseClient;
-use super::{mqttclient::MqttClient, Client};
-use crate::{cfg::*, config::MqttServerConfig, error::ServerError, packetinfo::PacketInfo};
+#[cfg(feature = "websocket")]
+use super::wsclient::WsClient;
+use super::{
+    auth::{find_provider, AuthStep},
+    client::is_internal_clientid,
+    clientid::{ClientIdGenerator, ConnectionInfo, UuidClientIds},
+    history::DisconnectReason,
+    inflight::InFlight,
+    listener::Listener,
+    mqttclient::MqttClient,
+    outbound::{encode, fit_packet, SharedFrame},
+    pacing::{Admission, ConnectPacer, ConnectionSlot, Limit},
+    packetid::ReceivedIds,
+    queue::{outgoing_queue, Outgoing, OutgoingReceiver},
+    session::{ReservedId, SessionStore, NEVER_EXPIRES},
+    Client,
+};
+use crate::{
+    acl::{Action, Identity},
+    cfg::*,
+    config::{MqttServerConfig, SessionPolicy, ZeroKeepAlive},
+    error::ServerError,
+    packetinfo::PacketInfo,
+    shutdown::Shutdown,
+    throttle::LogThrottle,
+    trace::{PublishTracer, TraceStage},
+};
 use apiformes_packet::prelude::*;
-use std::sync::Arc;
-use tokio::sync::{
-    mpsc::{unbounded_channel, Sender, UnboundedReceiver},
-    Notify,
+use bytes::Bytes;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
 };
-use tracing::{error, info, instrument, warn};
-use uuid::Uuid;
+use tokio::sync::{mpsc::Sender, RwLock};
+use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
+use tracing::{error, field, info, info_span, instrument, trace, warn, Span};
+
+/// Packets queued for a client from which a stalled write disconnects it, see
+/// `MqttServerConfig::backlog_write_timeout`
+const PROBED_BACKLOG: usize = 64;
+
+/// Time given to the DISCONNECT ending a connection, the connection may be the reason it
+/// ends and not take anything anymore
+const DISCONNECT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Packets buffered while the CONNACK is delayed, once reached the connection is left
+/// unread until the CONNACK is sent
+const MAX_PIPELINED: usize = 64;
 
 pub(super) enum Connection {
     Mqtt(MqttClient),
     #[cfg(feature = "noise")]
     Noise(Box<NoiseClient>),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<WsClient>),
 }
 
 impl Connection {
@@ -23,13 +64,68 @@ impl Connection {
             Connection::Mqtt(c) => c.recv().await,
             #[cfg(feature = "noise")]
             Connection::Noise(n) => n.recv().await,
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(w) => w.recv().await,
+        }
+    }
+    /// A packet already read along with the previous one, when the transport coalesces
+    /// frames
+    pub fn recv_buffered(&mut self) -> Result<Option<Packet>, ServerError> {
+        match self {
+            Connection::Mqtt(c) => c.recv_buffered(),
+            #[cfg(feature = "noise")]
+            Connection::Noise(n) => n.recv_buffered(),
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(_) => Ok(None),
+        }
+    }
+    /// Writes an encoded packet, see `encode`
+    pub async fn write(&mut self, frame: Bytes) -> Result<(), ServerError> {
+        match self {
+            Connection::Mqtt(c) => c.write(frame).await,
+            #[cfg(feature = "noise")]
+            Connection::Noise(n) => n.write(frame).await,
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(w) => w.write(frame).await,
+        }
+    }
+    pub fn peer_addr(&self) -> SocketAddr {
+        match self {
+            Connection::Mqtt(c) => c.peer_addr(),
+            #[cfg(feature = "noise")]
+            Connection::Noise(n) => n.peer_addr(),
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(w) => w.peer_addr(),
         }
     }
-    pub async fn send(&mut self, p: &Packet) -> Result<(), ServerError> {
+    pub fn remote_public_key(&self) -> Option<&[u8]> {
         match self {
-            Connection::Mqtt(c) => c.send(p).await,
+            Connection::Mqtt(_) => None,
             #[cfg(feature = "noise")]
-            Connection::Noise(n) => n.send(p).await,
+            Connection::Noise(n) => n.remote_public_key(),
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(_) => None,
+        }
+    }
+    /// Protocol version of the connection, announced by the CONNECT
+    pub fn version(&self) -> ProtocolVersion {
+        match self {
+            Connection::Mqtt(c) => c.version(),
+            #[cfg(feature = "noise")]
+            Connection::Noise(n) => n.version(),
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(w) => w.version(),
+        }
+    }
+    /// When the last packet was completely read, if the transport tells it apart from
+    /// when it was decoded
+    pub fn received_at(&self) -> Option<Instant> {
+        match self {
+            Connection::Mqtt(c) => Some(c.received_at()),
+            #[cfg(feature = "noise")]
+            Connection::Noise(_) => None,
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(_) => None,
         }
     }
     pub fn is_encrypted(&self) -> bool {
@@ -37,106 +133,969 @@ impl Connection {
             Connection::Mqtt(_) => false,
             #[cfg(feature = "noise")]
             Connection::Noise(_) => true,
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(_) => false,
         }
     }
 }
 
+/// The span of a connection, from the moment it is accepted by `listener` until it is
+/// closed. Traffic and the disconnect reason are recorded once it is closed.
+pub(super) fn connection_span(listener: &str, saddr: SocketAddr) -> Span {
+    info_span!(
+        "connection",
+        listener,
+        SocketAddr = %saddr,
+        clientid = field::Empty,
+        protocol_version = field::Empty,
+        bytes_in = field::Empty,
+        bytes_out = field::Empty,
+        packets_in = field::Empty,
+        packets_out = field::Empty,
+        disconnect_reason = field::Empty,
+    )
+}
+
+pub(super) fn record_disconnect(span: &Span, reason: &DisconnectReason) {
+    span.record("disconnect_reason", &field::display(reason));
+}
+
+/// MQTT packets and bytes exchanged over a connection, transport framing excluded
+#[derive(Default)]
+struct Traffic {
+    bytes_in: u64,
+    bytes_out: u64,
+    packets_in: u64,
+    packets_out: u64,
+}
+
+impl Traffic {
+    fn received(&mut self, packet: &Packet, version: ProtocolVersion) {
+        self.bytes_in += packet.frame_len_versioned(version) as u64;
+        self.packets_in += 1;
+    }
+    fn sent(&mut self, packet: &Packet, version: ProtocolVersion) {
+        self.bytes_out += packet.frame_len_versioned(version) as u64;
+        self.packets_out += 1;
+    }
+}
+
 pub(super) struct ClientWorker {
     incoming: Sender<PacketInfo>,
-    outgoing: UnboundedReceiver<Packet>,
+    outgoing: OutgoingReceiver,
     conn: Connection,
     cfg: Arc<MqttServerConfig>,
+    clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+    pacer: Arc<ConnectPacer>,
+    listener: Arc<Listener>,
+    // released once the worker is dropped
+    _slot: ConnectionSlot,
+    _listener_slot: ConnectionSlot,
+    sessions: Arc<SessionStore>,
+    throttle: Arc<LogThrottle>,
+    tracer: Arc<PublishTracer>,
     internals: Client,
+    // the client is disconnected if nothing is received before this deadline
+    keep_alive_deadline: Instant,
+    span: Span,
+    traffic: Traffic,
+    // 3.1.4: packets the client sent after its CONNECT without waiting for the CONNACK
+    pending: VecDeque<Packet>,
+    // identifiers of the client's publishes and subscriptions not acknowledged yet
+    received: ReceivedIds,
+    // the client id assigned to the client, released once the worker is dropped
+    _assigned: Option<ReservedId>,
+}
+
+/// 3.3.2.3.4: a topic alias must be within the TopicAliasMaximum of the CONNACK, and a
+/// PUBLISH without topic name must use an alias defined earlier. The broker advertises
+/// `TOPIC_ALIAS_MAX` and keeps no alias, so no alias is ever defined.
+fn topic_alias_valid(publish: &Publish) -> bool {
+    match publish.get_prop(Property::TopicAlias) {
+        Some([alias, ..]) => {
+            let alias = alias.into_u16().unwrap_or_default();
+            // always false while TOPIC_ALIAS_MAX is 0
+            #[allow(clippy::absurd_extreme_comparisons)]
+            let within = alias != 0 && alias <= TOPIC_ALIAS_MAX;
+            within && !publish.topic_name().is_empty()
+        }
+        _ => true,
+    }
 }
 
 impl ClientWorker {
+    async fn send(&mut self, packet: &Packet) -> Result<(), ServerError> {
+        self.send_shared(packet, None).await
+    }
+    /// Same as `send`, the packet is written from `frame` when other clients share it
+    async fn send_shared(
+        &mut self,
+        packet: &Packet,
+        frame: Option<&SharedFrame>,
+    ) -> Result<(), ServerError> {
+        let version = self.conn.version();
+        // 3.1.1 servers close the connection without a DISCONNECT
+        if version == ProtocolVersion::V311 && matches!(packet, Packet::Disconnect(_)) {
+            return Ok(());
+        }
+        let bytes = match frame {
+            Some(frame) => frame.encoded(packet, version),
+            None => encode(packet, version),
+        };
+        match self.cfg.backlog_write_timeout {
+            Some(secs) => {
+                self.bounded_write(bytes, Duration::from_secs(secs as u64))
+                    .await?
+            }
+            None => self.conn.write(bytes).await?,
+        }
+        self.traffic.sent(packet, version);
+        Ok(())
+    }
+    /// Writes `packet`, gives up if the write is still pending after `bound` while a backlog
+    /// is queued for the client. The peer is then considered dead instead of queuing
+    /// everything published to it until its keep alive expires.
+    async fn bounded_write(&mut self, frame: Bytes, bound: Duration) -> Result<(), ServerError> {
+        let internals = &self.internals;
+        let write = self.conn.write(frame);
+        tokio::pin!(write);
+        loop {
+            tokio::select! {
+                r = &mut write => return r,
+                _ = sleep(bound) => {
+                    if internals.backlog() >= PROBED_BACKLOG {
+                        return Err(ServerError::Unwritable);
+                    }
+                }
+            }
+        }
+    }
+    fn reset_keep_alive(&mut self) {
+        // 3.1.2.10: the server disconnects if nothing is received within one and a half
+        // times the keep alive period
+        let keep_alive = self.internals.keep_alive as u64;
+        self.keep_alive_deadline = Instant::now() + Duration::from_millis(keep_alive * 1500);
+    }
+    /// Sends `packets` in order. Packets that cannot fit the client maximum packet size are
+    /// discarded, discarded QoS 1 publishes count as delivered (3.1.2.25).
+    async fn deliver(&mut self, packets: Vec<Outgoing>) -> Result<(), ServerError> {
+        let mut packets = VecDeque::from(packets);
+        while let Some(Outgoing { packet, frame }) = packets.pop_front() {
+            let id = match &*packet {
+                Packet::Publish(p) => p.packet_identifier(),
+                _ => None,
+            };
+            let packet = match fit_packet(packet, self.internals.max_packet_size, &self.cfg) {
+                Some(packet) => packet,
+                None => {
+                    if let Some(suppressed) = self.throttle.admit("oversized", Instant::now()) {
+                        warn!(
+                            clientid = &*self.internals.clientid,
+                            suppressed,
+                            "Discarding a packet larger than the client maximum packet size"
+                        );
+                    }
+                    if let Some(id) = id {
+                        packets.extend(self.acknowledge(id).into_iter().map(Outgoing::from));
+                    }
+                    continue;
+                }
+            };
+            // publishes are never altered to fit, the shared frame still encodes them
+            self.send_shared(&packet, frame.as_deref()).await?;
+            // 4.13.2: the network connection is closed after sending DISCONNECT
+            if let Packet::Disconnect(d) = &*packet {
+                return Err(ServerError::DisconnectedByServer(d.reason_code() as u8));
+            }
+        }
+        Ok(())
+    }
+    /// Completes the QoS 1 delivery `id`, returns the queued publishes which can be sent now
+    fn acknowledge(&mut self, id: u16) -> Vec<Arc<Packet>> {
+        let inflight = &self.internals.inflight;
+        if !inflight.ack(id) {
+            if let Some(suppressed) = self.throttle.admit("unknown puback", Instant::now()) {
+                warn!(
+                    clientid = &*self.internals.clientid,
+                    suppressed, "Received PUBACK for unknown packet identifier {}", id
+                );
+            }
+        }
+        inflight.release(self.internals.recv_max, Instant::now())
+    }
+    async fn process_outgoing(&mut self, outgoing: Outgoing) -> Result<(), ServerError> {
+        let Outgoing { packet, frame } = outgoing;
+        let traced = self.tracer.is_active().then(|| packet.clone());
+        let packet = match &*packet {
+            Packet::Publish(publish) if publish.qos() == QoS::QoS1 => {
+                let inflight = &self.internals.inflight;
+                match inflight.submit(publish, self.internals.recv_max, Instant::now()) {
+                    // sent with a packet identifier of this client, the shared frame
+                    // does not encode it
+                    Some(packet) => Outgoing::from(packet),
+                    // waiting for the client to acknowledge earlier publishes
+                    None => {
+                        let max = self.cfg.max_queued_publishes.unwrap_or(usize::MAX);
+                        let dropped = inflight.trim_queue(max);
+                        if dropped > 0 {
+                            if let Some(suppressed) =
+                                self.throttle.admit("queue full", Instant::now())
+                            {
+                                warn!(
+                                    clientid = &*self.internals.clientid,
+                                    suppressed,
+                                    "Dropping the oldest publish waiting for the receive maximum"
+                                );
+                            }
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+            Packet::PubAck(ack) => {
+                self.received.remove(ack.identifier());
+                Outgoing { packet, frame }
+            }
+            Packet::SubAck(ack) => {
+                self.received.remove(ack.identifier());
+                Outgoing { packet, frame }
+            }
+            Packet::UnsubAck(ack) => {
+                self.received.remove(ack.identifier());
+                Outgoing { packet, frame }
+            }
+            _ => Outgoing { packet, frame },
+        };
+        self.deliver(vec![packet]).await?;
+        if let Some(packet) = traced {
+            self.tracer.flushed(&self.internals.clientid, &packet);
+        }
+        Ok(())
+    }
+    fn next_retransmit(&self) -> Option<Instant> {
+        let timeout = Duration::from_secs(self.cfg.retransmit_interval? as u64);
+        self.internals.inflight.next_retransmit(timeout)
+    }
+    async fn retransmit(&mut self) -> Result<(), ServerError> {
+        let packets = self.internals.inflight.retransmit(Instant::now());
+        self.deliver(packets.into_iter().map(Outgoing::from).collect())
+            .await
+    }
+    /// `timing` holds when a packet read from the connection was received and decoded
+    async fn process_incoming(
+        &mut self,
+        packet: Packet,
+        timing: Option<(Instant, Instant)>,
+    ) -> Result<(), ServerError> {
+        self.reset_keep_alive();
+        // 3.14.4: the client closes the connection, the manager decides about its will from
+        // the reason code
+        if let Packet::Disconnect(d) = &packet {
+            self.update_session_expiry(d).await?;
+            return Err(ServerError::DisconnectedByClient(d.reason_code() as u8));
+        }
+        // 3.12.4: answered right away, the keep alive was reset above
+        if let Packet::PingReq(_) = &packet {
+            return self.send(&Packet::ping_res()).await;
+        }
+        if let Packet::Publish(publish) = &packet {
+            if !topic_alias_valid(publish) {
+                let reason = format!("Topic alias above the maximum of {}", TOPIC_ALIAS_MAX);
+                self.send_disconnect(DisconnectReasonCode::TopicAliasInvalid, Some(reason))
+                    .await;
+                return Err(ServerError::DisconnectedByServer(
+                    DisconnectReasonCode::TopicAliasInvalid as u8,
+                ));
+            }
+        }
+        if let Err(violation) = packet.validate() {
+            let err = ServerError::ProtocolViolation(violation);
+            self.send_disconnect(DisconnectReasonCode::ProtocolError, err.reason_string())
+                .await;
+            return Err(err);
+        }
+        // 2.2.1: an identifier is not reused before its packet is acknowledged
+        let free = match &packet {
+            Packet::Publish(p) => match p.packet_identifier() {
+                Some(id) if p.qos() == QoS::QoS1 => self.received.insert_publish(id),
+                _ => true,
+            },
+            Packet::Subscribe(s) => self.received.insert(s.packet_identifier()),
+            Packet::Unsubscribe(u) => self.received.insert(u.packet_identifier()),
+            _ => true,
+        };
+        if !free {
+            return self.identifier_in_use(&packet).await;
+        }
+        // QoS 1 deliveries are tracked by the worker, acknowledgements stop here
+        if let Packet::PubAck(ack) = &packet {
+            let released = self.acknowledge(ack.identifier());
+            return self
+                .deliver(released.into_iter().map(Outgoing::from).collect())
+                .await;
+        }
+        let traced = match (&packet, timing) {
+            (Packet::Publish(p), Some((received, decoded))) => {
+                self.tracer
+                    .start(&self.internals.clientid, p.topic_name(), received, decoded)
+            }
+            _ => false,
+        };
+        let p = PacketInfo {
+            senderid: self.internals.clientid.clone(),
+            packet,
+            traced,
+        };
+        self.incoming.send(p).await.map_err(|_| {
+            ServerError::Misc("Error sending incoming packet to processing queue".to_owned())
+        })?;
+        if traced {
+            self.tracer.record(TraceStage::Enqueued);
+        }
+        Ok(())
+    }
+    /// Refuses a packet reusing the identifier of one not acknowledged yet
+    async fn identifier_in_use(&mut self, packet: &Packet) -> Result<(), ServerError> {
+        let response = match packet {
+            // 4.3.2: a retransmission of a publish still being processed, the acknowledgement
+            // of the original covers it. Once acknowledged, it is a new delivery.
+            Packet::Publish(p)
+                if p.flags().contains(PublishFlags::DUP)
+                    && self.received.is_publish(p.packet_identifier().unwrap()) =>
+            {
+                trace!(
+                    clientid = &*self.internals.clientid,
+                    "Discarding the retransmission of a publish being processed"
+                );
+                return Ok(());
+            }
+            Packet::Publish(p) => {
+                let mut puback = PubAck::new(p.packet_identifier().unwrap());
+                puback.set_reason_code(PubAckReasonCode::PacketIdentifierInUse);
+                puback.build()
+            }
+            Packet::Subscribe(s) => {
+                let mut suback = SubAck::new(s.packet_identifier());
+                for _ in s.topics_iter() {
+                    suback.add_reason_code(SubAckReasonCode::PacketIdentifierInUse);
+                }
+                suback.build()
+            }
+            Packet::Unsubscribe(u) => {
+                let mut unsuback = UnsubAck::new(u.packet_identifier());
+                for _ in u.topics_iter() {
+                    unsuback.add_reason_code(UnsubAckReasonCode::PacketIdentifierInUse);
+                }
+                unsuback.build()
+            }
+            _ => return Ok(()),
+        };
+        if let Some(suppressed) = self.throttle.admit("identifier in use", Instant::now()) {
+            warn!(
+                clientid = &*self.internals.clientid,
+                suppressed, "Client reused a packet identifier not acknowledged yet"
+            );
+        }
+        self.send(&response).await
+    }
+    /// 3.14.2.2.2: the client may change its session expiry interval when disconnecting,
+    /// unless its session was meant to end with the connection
+    async fn update_session_expiry(&mut self, disconnect: &Disconnect) -> Result<(), ServerError> {
+        let expiry = match disconnect.get_prop(Property::SessionExpiryInterval) {
+            Some([v, ..]) => v.into_u32().unwrap_or_default(),
+            _ => return Ok(()),
+        };
+        if self.internals.session_expirary == 0 && expiry != 0 {
+            let reason = "Session expiry interval set on a session ending with the connection";
+            self.send_disconnect(DisconnectReasonCode::ProtocolError, Some(reason.to_owned()))
+                .await;
+            return Err(ServerError::DisconnectedByServer(
+                DisconnectReasonCode::ProtocolError as u8,
+            ));
+        }
+        self.internals.session_expirary = expiry;
+        Ok(())
+    }
     async fn listen(&mut self) -> Result<(), ServerError> {
+        // packets pipelined before the CONNACK go first, in the order they were received
+        if let Some(packet) = self.pending.pop_front() {
+            return self.process_incoming(packet, None).await;
+        }
+        let keep_alive = self.internals.keep_alive;
+        let next_retransmit = self.next_retransmit();
         tokio::select! {
             p = self.conn.recv() => {
-                let packet = p?;
-                let p = PacketInfo {
-                    senderid: self.internals.clientid.clone(),
-                    packet,
-                };
-                self.incoming.send(p).await.
-                    map_err(|_| ServerError::Misc("Error sending incoming packet to processing queue".to_owned()))?;
+                let mut packet = p?;
+                loop {
+                    let decoded = Instant::now();
+                    let received = self.conn.received_at().unwrap_or(decoded);
+                    self.traffic.received(&packet, self.conn.version());
+                    self.process_incoming(packet, Some((received, decoded))).await?;
+                    match self.conn.recv_buffered()? {
+                        Some(next) => packet = next,
+                        None => break,
+                    }
+                }
             }
             p = self.outgoing.recv() => {
                 let packet = p.map(Ok).unwrap_or_else(|| Err(ServerError::Misc("outgoing queue lost all its senders".to_owned())))?;
-                self.conn.send(&packet).await?;
+                self.process_outgoing(packet).await?;
+            }
+            _ = sleep_until(next_retransmit.unwrap_or_else(Instant::now)), if next_retransmit.is_some() => {
+                info!(clientid = &*self.internals.clientid, "Retransmitting unacknowledged publishes");
+                self.retransmit().await?;
+            }
+            _ = sleep_until(self.keep_alive_deadline), if keep_alive != 0 => {
+                let disconnect = Disconnect::new(DisconnectReasonCode::KeepAliveTimeout).build();
+                self.send(&disconnect).await?;
+                return Err(ServerError::KeepAliveTimeout);
             }
         }
         Ok(())
     }
-    async fn listen_forever(&mut self) {
+    async fn listen_forever(&mut self) -> ServerError {
         loop {
             if let Err(e) = self.listen().await {
                 info!(
                     clientid = &*self.internals.clientid,
                     "Disconnecting, received error while listening, {:?}", e
                 );
-                break;
+                return e;
             }
         }
     }
+    /// Tells the client the connection ends, and why when it asked for problem information
+    async fn send_disconnect(&mut self, reason_code: DisconnectReasonCode, reason: Option<String>) {
+        let mut disconnect = Disconnect::new(reason_code);
+        if let Some(reason) = self.internals.explain(reason) {
+            disconnect.add_prop(Property::ReasonString, reason).unwrap();
+        }
+        let disconnect = disconnect.build();
+        match timeout(DISCONNECT_WRITE_TIMEOUT, self.send(&disconnect)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!(clientid = &*self.internals.clientid, "{:?}", e),
+            Err(_) => warn!(
+                clientid = &*self.internals.clientid,
+                "Gave up sending DISCONNECT"
+            ),
+        }
+    }
+    /// Delivers what is left for the client and waits for its acknowledgements until
+    /// `deadline` before telling it the server is shutting down
+    async fn drain(&mut self, deadline: Instant) {
+        let shutdown = self.internals.shutdown.clone();
+        let drained = tokio::select! {
+            r = self.drain_forever() => r,
+            _ = sleep_until(deadline) => Ok(()),
+            _ = shutdown.wait() => Ok(()),
+        };
+        match drained {
+            Ok(()) => {
+                self.send_disconnect(DisconnectReasonCode::ServerShuttingDown, None)
+                    .await
+            }
+            Err(e) => info!(
+                clientid = &*self.internals.clientid,
+                "Connection lost while draining, {:?}", e
+            ),
+        }
+    }
+    async fn drain_forever(&mut self) -> Result<(), ServerError> {
+        loop {
+            while let Some(packet) = self.outgoing.try_recv() {
+                self.process_outgoing(packet).await?;
+            }
+            if self.internals.inflight.len() == 0 {
+                return Ok(());
+            }
+            tokio::select! {
+                p = self.conn.recv() => match p? {
+                    // left unacknowledged, the client sends it again wherever it reconnects
+                    Packet::Publish(_) => (),
+                    packet => self.process_incoming(packet, None).await?,
+                },
+                p = self.outgoing.recv() => match p {
+                    Some(packet) => self.process_outgoing(packet).await?,
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+    /// Serves the client until the connection ends, returns the client as it was last
+    /// updated by the connection and why the connection ended
     #[instrument(name = "ClientWorker::run", skip_all)]
-    pub(super) async fn run(mut self) -> Arc<str> {
+    pub(super) async fn run(mut self) -> (Client, DisconnectReason) {
         let shutdown = self.internals.shutdown.clone();
         let killme = self.internals.killme.clone();
-        tokio::select! {
-            _ = killme.notified() => (),
-            _ = shutdown.notified() => (),
-            _ = self.listen_forever() => (),
+        let max_connect_time = self.cfg.max_connect_time;
+        let mut drain_until = None;
+        let reason = tokio::select! {
+            _ = killme.notified() => None,
+            deadline = shutdown.draining() => {
+                drain_until = deadline;
+                Some(DisconnectReason::ServerShutdown)
+            }
+            e = self.listen_forever() => Some(DisconnectReason::from(&e)),
+            _ = sleep(Duration::from_secs(max_connect_time.unwrap_or_default() as u64)),
+                if max_connect_time.is_some() => Some(DisconnectReason::MaximumConnectTime),
+        };
+        if let Some(deadline) = drain_until {
+            self.drain(deadline).await;
         }
-        self.internals.clientid
+        let reason = match reason {
+            Some(DisconnectReason::MaximumConnectTime) => {
+                info!(
+                    clientid = &*self.internals.clientid,
+                    "Disconnecting, maximum connect time reached"
+                );
+                let reason = ServerError::MaximumConnectTime.reason_string();
+                self.send_disconnect(DisconnectReasonCode::MaximumConnectTime, reason)
+                    .await;
+                DisconnectReason::MaximumConnectTime
+            }
+            // 4.13.1: the rest of the packet is not read, the connection cannot go on
+            Some(DisconnectReason::PacketTooLarge) => {
+                let reason = ServerError::MaxPacketSizeExceeded.reason_string();
+                self.send_disconnect(DisconnectReasonCode::PacketTooLarge, reason)
+                    .await;
+                DisconnectReason::PacketTooLarge
+            }
+            Some(reason) => reason,
+            None if self.internals.overflowed() => {
+                info!(
+                    clientid = &*self.internals.clientid,
+                    "Disconnecting, outgoing queue overflowed"
+                );
+                let reason = "Too many packets waiting to be sent".to_owned();
+                self.send_disconnect(DisconnectReasonCode::QuotaExceeded, Some(reason))
+                    .await;
+                DisconnectReason::QueueOverflow
+            }
+            None if self.internals.taken_over() => {
+                info!(
+                    clientid = &*self.internals.clientid,
+                    "Disconnecting, session taken over by a new connection"
+                );
+                self.send_disconnect(DisconnectReasonCode::SessionTakenOver, None)
+                    .await;
+                DisconnectReason::SessionTakenOver
+            }
+            None => DisconnectReason::Error("Killed by the server".to_owned()),
+        };
+        self.close_span(&reason);
+        (self.internals, reason)
+    }
+    /// Records the traffic of the connection and why it ended on its span
+    pub(super) fn close_span(&self, reason: &DisconnectReason) {
+        self.span.record("bytes_in", &self.traffic.bytes_in);
+        self.span.record("bytes_out", &self.traffic.bytes_out);
+        self.span.record("packets_in", &self.traffic.packets_in);
+        self.span.record("packets_out", &self.traffic.packets_out);
+        record_disconnect(&self.span, reason);
+    }
+    pub(super) fn span(&self) -> &Span {
+        &self.span
     }
 
     pub(super) fn internals(&self) -> &Client {
         &self.internals
     }
+    pub(super) fn internals_mut(&mut self) -> &mut Client {
+        &mut self.internals
+    }
     pub(super) fn cfg(&self) -> Arc<MqttServerConfig> {
         self.cfg.clone()
     }
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         c: Connection,
+        span: Span,
         cfg: Arc<MqttServerConfig>,
-        shutdown: Arc<Notify>,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+        shutdown: Shutdown,
         incoming: Sender<PacketInfo>,
+        pacer: Arc<ConnectPacer>,
+        sessions: Arc<SessionStore>,
+        throttle: Arc<LogThrottle>,
+        tracer: Arc<PublishTracer>,
+        listener: Arc<Listener>,
     ) -> Self {
-        let (outgoing_tx, outgoing_rx) = unbounded_channel();
+        let (outgoing_tx, outgoing_rx) =
+            outgoing_queue(cfg.max_outgoing_packets, cfg.outgoing_overflow);
+        let mut internals = Client::new(
+            shutdown,
+            outgoing_tx,
+            c.is_encrypted(),
+            listener.max_packet_size,
+        );
+        internals.limit_publishes(listener.quota.clone());
+        internals.listener = listener.tag.clone();
+        #[cfg(feature = "noise")]
+        {
+            internals.permeability = listener.permeability;
+        }
         ClientWorker {
-            internals: Client::new(shutdown, outgoing_tx, c.is_encrypted(), cfg.max_packet_size),
+            internals,
             incoming,
             outgoing: outgoing_rx,
             conn: c,
             cfg,
+            clients,
+            _slot: pacer.open(),
+            pacer,
+            _listener_slot: listener.open(),
+            listener,
+            sessions,
+            throttle,
+            tracer,
+            keep_alive_deadline: Instant::now(),
+            span,
+            traffic: Traffic::default(),
+            pending: VecDeque::new(),
+            received: ReceivedIds::default(),
+            _assigned: None,
         }
     }
 
-    async fn unimplemented(&mut self) -> Result<(), ServerError> {
+    async fn reject<T>(
+        &mut self,
+        reason_code: ConnAckReasonCode,
+        err: ServerError,
+    ) -> Result<T, ServerError> {
         let mut connack = ConnAck::new();
-        connack.set_reason_code(ConnAckReasonCode::ImplementationSpecificError);
-        self.conn.send(&connack.build()).await?;
-        Err(ServerError::Misc("Unimplemented".to_owned()))
+        connack.set_reason_code(reason_code);
+        if let Some(reason) = self.internals.explain(err.reason_string()) {
+            connack.add_prop(Property::ReasonString, reason).unwrap();
+        }
+        self.send(&connack.build()).await?;
+        // 3.1.4: nothing the client pipelined is processed, the worker is dropped with it
+        Err(err)
+    }
+    /// Asks the configured generator for a client id that is neither in use nor reserved,
+    /// it stays reserved until the worker is dropped. A stable id is used as is, it takes
+    /// over the connection and session holding it.
+    async fn assign_clientid(&mut self) -> Arc<str> {
+        const ATTEMPTS: usize = 8;
+        let info = ConnectionInfo {
+            peer_addr: self.conn.peer_addr(),
+            public_key: self.conn.remote_public_key(),
+        };
+        let generator = &self.cfg.clientid_generator;
+        let usable = |id: &str| !id.is_empty() && !is_internal_clientid(id);
+        // the write lock keeps other connections from assigning the same id meanwhile
+        let clients = self.clients.write().await;
+        let mut id = generator.generate(&info);
+        if usable(&id) && generator.is_stable(&info) {
+            return id;
+        }
+        let mut attempts = 1;
+        loop {
+            if usable(&id) && !clients.contains_key(&id) {
+                if let Some(reserved) = self.sessions.reserve(&id) {
+                    self._assigned = Some(reserved);
+                    return id;
+                }
+            }
+            id = if attempts < ATTEMPTS {
+                warn!(
+                    clientid = &*id,
+                    "Generated client id is not usable, retrying"
+                );
+                attempts += 1;
+                generator.generate(&info)
+            } else {
+                UuidClientIds.generate(&info)
+            };
+        }
+    }
+    /// The QoS 1 deliveries of the session the server holds for the client id, either
+    /// because the client is still connected or because its session was retained after it
+    /// left. `None` if there is no session to resume.
+    async fn resumable_session(&self) -> Option<Arc<InFlight>> {
+        if self.cfg.session_policy == SessionPolicy::CleanAll {
+            return None;
+        }
+        let clientid = &self.internals.clientid;
+        if let Some(c) = self.clients.read().await.get(clientid) {
+            return Some(c.inflight.clone());
+        }
+        self.sessions.inflight(clientid, Instant::now())
+    }
+    /// Waits until `deadline` while buffering what the client pipelines after its CONNECT,
+    /// the buffered packets are only processed once the CONNACK is sent (3.1.4)
+    async fn buffer_until(&mut self, deadline: Instant) -> Result<(), ServerError> {
+        while self.pending.len() < MAX_PIPELINED {
+            tokio::select! {
+                _ = sleep_until(deadline) => return Ok(()),
+                p = self.conn.recv() => {
+                    let packet = p?;
+                    self.traffic.received(&packet, self.conn.version());
+                    self.pending.push_back(packet);
+                }
+            }
+        }
+        sleep_until(deadline).await;
+        Ok(())
+    }
+    /// Keeps the Will Message of the CONNECT as the publish sent in place of the client
+    /// once its connection ends abnormally, refuses the connection if it cannot be honoured
+    async fn accept_will(&mut self, flags: ConnectFlags, will: &Will) -> Result<(), ServerError> {
+        let qos: QoS = flags.try_into()?;
+        // 3.2.2-12: the Will QoS cannot exceed the MaximumQoS of the server
+        if qos > QoS::from_u8(self.listener.max_qos)? {
+            error!(
+                "Client attempted having a will with unsupported QoS {}",
+                qos.as_u8()
+            );
+            return self
+                .reject(
+                    ConnAckReasonCode::QoSNotSupported,
+                    ServerError::QoSNotSupported(qos.as_u8()),
+                )
+                .await;
+        }
+        // 3.1.3.3: the Will Topic is a topic name, not a filter
+        if will.topic().contains(['+', '#']) {
+            error!(
+                topic = &**will.topic(),
+                "Client attempted having a will on a topic filter"
+            );
+            return self
+                .reject(
+                    ConnAckReasonCode::TopicNameInvalid,
+                    ServerError::Packet(DataParseError::BadTopic),
+                )
+                .await;
+        }
+        let mut publish = Publish::new(will.topic().clone(), will.payload().clone())?;
+        publish.set_qos(qos);
+        if flags.contains(ConnectFlags::WILL_RETAIN) {
+            publish.set_retain();
+        }
+        for (k, v) in will.props_iter() {
+            match k {
+                Property::WillDelayInterval => self.internals.will_delay = v.into_u32().unwrap(),
+                Property::MessageExpiryInterval => {
+                    error!("Client attempted a will message expiry which is not supported");
+                    return self.unimplemented().await;
+                }
+                // the remaining will properties are publish properties
+                _ => publish.add_prop(*k, v.clone())?,
+            }
+        }
+        self.internals.will = Some(publish);
+        Ok(())
+    }
+    /// Runs the enhanced authentication exchange of `method` (4.12), returns the
+    /// AuthenticationData completing it in the CONNACK
+    async fn authenticate(
+        &mut self,
+        clientid: &str,
+        username: Option<&str>,
+        method: Arc<str>,
+        mut data: Option<Bytes>,
+    ) -> Result<Option<Bytes>, ServerError> {
+        let provider = match find_provider(&self.cfg.auth_providers, &method) {
+            Some(provider) => provider,
+            None => {
+                if let Some(suppressed) = self.throttle.admit("auth method", Instant::now()) {
+                    error!(
+                        method = &*method,
+                        suppressed, "Client attempted an unknown authentication method"
+                    );
+                }
+                return self
+                    .reject(
+                        ConnAckReasonCode::BadAuthenicationMethod,
+                        ServerError::BadAuthenticationMethod(method),
+                    )
+                    .await;
+            }
+        };
+        let mut exchange = provider.start(clientid, username);
+        loop {
+            let challenge = match exchange.step(data.as_deref()) {
+                AuthStep::Success(data) => return Ok(data),
+                AuthStep::Continue(challenge) => challenge,
+                AuthStep::Failure => {
+                    if let Some(suppressed) = self.throttle.admit("auth failure", Instant::now()) {
+                        warn!(
+                            method = &*method,
+                            suppressed, "Client failed to authenticate"
+                        );
+                    }
+                    return self
+                        .reject(
+                            ConnAckReasonCode::NotAuthorized,
+                            ServerError::AuthenticationFailed,
+                        )
+                        .await;
+                }
+            };
+            let mut auth = Auth::new(AuthReasonCode::ContinueAuthentication);
+            auth.add_prop(
+                Property::AuthenticationMethod,
+                MqttPropValue::new_string(method.clone())?,
+            )?;
+            auth.add_prop(
+                Property::AuthenticationData,
+                MqttPropValue::new_data(challenge)?,
+            )?;
+            self.send(&auth.build()).await?;
+            // 3.1.2-30: nothing but AUTH and DISCONNECT until the CONNACK
+            let packet = self.conn.recv().await?;
+            self.traffic.received(&packet, self.conn.version());
+            let response = match packet {
+                Packet::Auth(auth) => auth,
+                Packet::Disconnect(d) => {
+                    return Err(ServerError::DisconnectedByClient(d.reason_code() as u8))
+                }
+                _ => {
+                    return self
+                        .reject(
+                            ConnAckReasonCode::ProtocolError,
+                            ServerError::Misc("Expected an AUTH packet".to_owned()),
+                        )
+                        .await
+                }
+            };
+            let same_method = matches!(
+                response.get_prop(Property::AuthenticationMethod),
+                Some([m]) if m.into_str() == Some(&*method)
+            );
+            if !same_method
+                || !matches!(
+                    response.reason_code(),
+                    AuthReasonCode::ContinueAuthentication
+                )
+            {
+                return self
+                    .reject(
+                        ConnAckReasonCode::ProtocolError,
+                        ServerError::Misc("Unexpected AUTH packet".to_owned()),
+                    )
+                    .await;
+            }
+            data = response
+                .get_prop(Property::AuthenticationData)
+                .and_then(|d| d.first())
+                .and_then(|d| d.into_data())
+                .cloned();
+        }
+    }
+    /// The will is published in place of the client, it needs the same permission as its
+    /// other publishes
+    fn will_authorized(&self) -> bool {
+        match (&self.cfg.authorizer, &self.internals.will) {
+            (Some(authorizer), Some(will)) => {
+                let identity = Identity {
+                    clientid: &self.internals.clientid,
+                    username: self.internals.username(),
+                };
+                authorizer.authorize(&identity, Action::Publish, will.topic_name())
+            }
+            _ => true,
+        }
+    }
+    async fn unimplemented(&mut self) -> Result<(), ServerError> {
+        self.reject(
+            ConnAckReasonCode::ImplementationSpecificError,
+            ServerError::Misc("Unimplemented".to_owned()),
+        )
+        .await
     }
     #[instrument(name = "ClientWorker::process_connect", skip_all)]
     async fn process_connect(&mut self, connect: Connect) -> Result<(), ServerError> {
-        if connect.username().is_some() {
-            error!("Client attempted using username for authentication which is not supported");
-            return self.unimplemented().await;
+        match self.pacer.limit(self.conn.peer_addr().ip()) {
+            Limit::Full => {
+                if let Some(suppressed) = self.throttle.admit("full", Instant::now()) {
+                    warn!(suppressed, "Too many connections open, refusing the client");
+                }
+                return self
+                    .reject(ConnAckReasonCode::ServerBusy, ServerError::ServerBusy)
+                    .await;
+            }
+            Limit::RateExceeded => {
+                if let Some(suppressed) = self.throttle.admit("rate", Instant::now()) {
+                    warn!(suppressed, "Client connects too often from its address");
+                }
+                return self
+                    .reject(
+                        ConnAckReasonCode::ConnectionRateExceeded,
+                        ServerError::ConnectionRateExceeded,
+                    )
+                    .await;
+            }
+            Limit::Within if self.listener.full() => {
+                if let Some(suppressed) = self.throttle.admit("full", Instant::now()) {
+                    warn!(
+                        suppressed,
+                        listener = &*self.listener.tag,
+                        "Too many connections open on the listener, refusing the client"
+                    );
+                }
+                return self
+                    .reject(ConnAckReasonCode::ServerBusy, ServerError::ServerBusy)
+                    .await;
+            }
+            Limit::Within => (),
+        }
+        // read first for the rejections below to be explained as requested
+        if let Some([v, ..]) = connect.get_prop(Property::RequestProblemInformation) {
+            self.internals.problem_info = v.into_bool().unwrap_or(true);
+        }
+        if let Err(violation) = connect.validate() {
+            return self
+                .reject(
+                    ConnAckReasonCode::ProtocolError,
+                    ServerError::ProtocolViolation(violation),
+                )
+                .await;
+        }
+        if is_internal_clientid(connect.clientid()) {
+            if let Some(suppressed) = self.throttle.admit("reserved clientid", Instant::now()) {
+                error!(
+                    clientid = &**connect.clientid(),
+                    suppressed, "Client attempted using a clientid from the reserved namespace"
+                );
+            }
+            return self
+                .reject(
+                    ConnAckReasonCode::ClientIdentifierNotValid,
+                    ServerError::ReservedClientId(connect.clientid().clone()),
+                )
+                .await;
+        }
+        let clean_start = connect.flags().contains(ConnectFlags::CLEAN_START);
+        let v311 = self.conn.version() == ProtocolVersion::V311;
+        // 3.1.3-8: a client without a client id has no session to resume, the one it
+        // would be assigned is always new
+        if connect.clientid().is_empty() && !clean_start {
+            return self
+                .reject(
+                    ConnAckReasonCode::ClientIdentifierNotValid,
+                    ServerError::Misc("Empty client id without clean session".to_owned()),
+                )
+                .await;
         }
         if connect.password().is_some() {
             error!("Client attempted using password for authentication which is not supported");
             return self.unimplemented().await;
         }
-        if connect.will().is_some() {
-            error!("Client attempted having a will which is not supported");
-            return self.unimplemented().await;
+        if let Some(will) = connect.will() {
+            self.accept_will(connect.flags(), will).await?;
         }
-        if !connect.flags().contains(ConnectFlags::CLEAN_START) {
-            error!("Client attempted reusing session which is not supported");
-            return self.unimplemented().await;
+        if connect.keep_alive() == 0 && self.cfg.zero_keep_alive == ZeroKeepAlive::Reject {
+            error!("Client attempted disabling keep alive which is not allowed");
+            return self
+                .reject(
+                    ConnAckReasonCode::ImplementationSpecificError,
+                    ServerError::ZeroKeepAliveRejected,
+                )
+                .await;
         }
+        let mut auth_method: Option<Arc<str>> = None;
+        let mut auth_data = None;
         for (k, v) in connect.props_iter() {
             match k {
                 Property::SessionExpiryInterval => {
@@ -153,36 +1112,88 @@ impl ClientWorker {
                 Property::RequestResponseInformation => {
                     self.internals.response_info = v.into_bool().unwrap()
                 }
-                Property::RequestProblemInformation => {
-                    self.internals.problem_info = v.into_bool().unwrap()
-                }
+                Property::RequestProblemInformation => (),
                 Property::UserProperty => warn!(
                     "Client is using strange property in connect packet {:?}",
                     v.into_str_pair().unwrap()
                 ),
-                Property::AuthenticationMethod => {
-                    error!("Client attempted Authentication which is not supported");
-                    return self.unimplemented().await;
-                }
-                Property::AuthenticationData => {
-                    error!("Client attempted Authentication which is not supported");
-                    return self.unimplemented().await;
-                }
+                Property::AuthenticationMethod => auth_method = v.into_str().map(Arc::from),
+                Property::AuthenticationData => auth_data = v.into_data().cloned(),
                 _ => error!(
                     "Internal Error: {:?} should not be part of Connect packet",
                     k
                 ),
             }
         }
+        // 3.1.1 has no session expiry interval, the session of a client not asking for a
+        // clean session lasts until the next clean one
+        if v311 && !clean_start {
+            self.internals.session_expirary = NEVER_EXPIRES;
+        }
+        if self.cfg.session_policy == SessionPolicy::CleanAll {
+            // the server may override the interval requested by the client (3.2.2.3.2)
+            self.internals.session_expirary = 0;
+        }
+        let username = connect.username().map(|u| &**u);
+        let auth = match auth_method {
+            Some(method) => {
+                let data = self
+                    .authenticate(connect.clientid(), username, method.clone(), auth_data)
+                    .await?;
+                self.internals.username = username.map(Arc::from);
+                Some((method, data))
+            }
+            // usernames are only trusted once an authentication method checked them
+            None if username.is_some() => {
+                error!("Client attempted using username for authentication which is not supported");
+                return self.unimplemented().await;
+            }
+            // 3.1.2-29: data without a method is a protocol error
+            None if auth_data.is_some() => {
+                return self
+                    .reject(
+                        ConnAckReasonCode::ProtocolError,
+                        ServerError::Misc("AuthenticationData without a method".to_owned()),
+                    )
+                    .await;
+            }
+            None if self.listener.require_auth => {
+                warn!(
+                    listener = &*self.listener.tag,
+                    "Client did not authenticate on a listener requiring it"
+                );
+                return self
+                    .reject(
+                        ConnAckReasonCode::NotAuthorized,
+                        ServerError::AuthenticationRequired,
+                    )
+                    .await;
+            }
+            None => None,
+        };
+        // only handshakes about to succeed are paced
+        match self.pacer.admit() {
+            Admission::Now => (),
+            Admission::After(delay) => self.buffer_until(Instant::now() + delay).await?,
+            Admission::Busy => {
+                if let Some(suppressed) = self.throttle.admit("busy", Instant::now()) {
+                    warn!(
+                        suppressed,
+                        "Too many handshakes in progress, asking the client to retry later"
+                    );
+                }
+                return self
+                    .reject(ConnAckReasonCode::ServerBusy, ServerError::ServerBusy)
+                    .await;
+            }
+            Admission::Standby => {
+                return self
+                    .reject(ConnAckReasonCode::ServerUnavailable, ServerError::Standby)
+                    .await;
+            }
+        }
         let mut connack = ConnAck::new();
         connack.set_reason_code(ConnAckReasonCode::Success);
-        // TODO once we support sessions
-        // If the Server accepts a connection with Clean Start set to 1, the Server MUST set Session Present to 0 in
-        // the CONNACK packet in addition to setting a 0x00 (Success) Reason Code in the CONNACK packet
-        // If the Server accepts a connection with Clean Start set to 0 and the Server has Session State for the
-        // ClientID, it MUST set Session Present to 1 in the CONNACK packet, otherwise it MUST set Session
-        // Present to 0 in the CONNACK packet. In both cases it MUST set a 0x00 (Success) Reason Code in the
-        // CONNACK packet
         connack
             .add_prop(
                 Property::SessionExpiryInterval,
@@ -195,12 +1206,21 @@ impl ClientWorker {
                 MqttPropValue::new_u16(self.internals.recv_max),
             )
             .unwrap();
-        connack
-            .add_prop(Property::MaximumQoS, MqttPropValue::new_u8(MAX_QOS))
-            .unwrap();
+        // validated against MAX_QOS by MqttServerConfig::validate
+        self.internals.max_qos = QoS::from_u8(self.listener.max_qos)?;
+        // 3.2.2.3.4: an absent MaximumQoS means QoS 2 is supported, which the property
+        // cannot carry anyway
+        if self.internals.max_qos < QoS::QoS2 {
+            connack
+                .add_prop(
+                    Property::MaximumQoS,
+                    MqttPropValue::new_u8(self.internals.max_qos.as_u8()),
+                )
+                .unwrap();
+        }
         let clientid = connect.clientid();
         if clientid.is_empty() {
-            self.internals.clientid = Uuid::new_v4().to_hyphenated().to_string().into();
+            self.internals.clientid = self.assign_clientid().await;
             info!("Assigning {} to client", self.internals.clientid);
             connack
                 .add_prop(
@@ -211,6 +1231,35 @@ impl ClientWorker {
         } else {
             self.internals.clientid = clientid.clone();
         }
+        if !self.will_authorized() {
+            warn!("Client is not allowed to publish its will");
+            return self
+                .reject(
+                    ConnAckReasonCode::NotAuthorized,
+                    ServerError::NotAuthorized(Action::Publish),
+                )
+                .await;
+        }
+        // If the Server accepts a connection with Clean Start set to 1, the Server MUST set Session Present to 0 in
+        // the CONNACK packet in addition to setting a 0x00 (Success) Reason Code in the CONNACK packet
+        // If the Server accepts a connection with Clean Start set to 0 and the Server has Session State for the
+        // ClientID, it MUST set Session Present to 1 in the CONNACK packet, otherwise it MUST set Session
+        // Present to 0 in the CONNACK packet. In both cases it MUST set a 0x00 (Success) Reason Code in the
+        // CONNACK packet
+        if !clean_start {
+            if let Some(inflight) = self.resumable_session().await {
+                self.internals.session_present = true;
+                self.internals.inflight = inflight;
+                connack.set_session_present();
+            }
+        }
+        // 3.2.2.3.6: clients must not send packets above it
+        connack
+            .add_prop(
+                Property::MaximumPacketSize,
+                MqttPropValue::new_u32(self.listener.max_packet_size),
+            )
+            .unwrap();
         connack
             .add_prop(
                 Property::TopicAliasMaximum,
@@ -235,16 +1284,79 @@ impl ClientWorker {
                 MqttPropValue::new_bool(SHARED_SUB),
             )
             .unwrap();
-        connack
-            .add_prop(
-                Property::ServerKeepAlive,
-                MqttPropValue::new_u16(self.cfg.keep_alive),
-            )
-            .unwrap();
-        self.conn.send(&connack.build()).await
+        if connect.keep_alive() == 0 && self.cfg.zero_keep_alive == ZeroKeepAlive::Allow {
+            self.internals.keep_alive = 0;
+        } else {
+            self.internals.keep_alive = self.cfg.keep_alive;
+            connack
+                .add_prop(
+                    Property::ServerKeepAlive,
+                    MqttPropValue::new_u16(self.cfg.keep_alive),
+                )
+                .unwrap();
+        }
+        self.reset_keep_alive();
+        if let Some(node_id) = &self.cfg.node_id {
+            connack
+                .add_prop(
+                    Property::UserProperty,
+                    MqttPropValue::new_string_pair(Arc::from("node_id"), Arc::from(&**node_id))?,
+                )
+                .unwrap();
+        }
+        // 3.1.2.11.6: only sent to the clients asking for it
+        if self.internals.response_info {
+            if let Some(info) = self.cfg.response_information(&self.internals.clientid) {
+                connack
+                    .add_prop(
+                        Property::ResponseInformation,
+                        MqttPropValue::new_string(info)?,
+                    )
+                    .unwrap();
+            }
+        }
+        if let Some((method, data)) = auth {
+            connack
+                .add_prop(
+                    Property::AuthenticationMethod,
+                    MqttPropValue::new_string(method)?,
+                )
+                .unwrap();
+            if let Some(data) = data {
+                connack
+                    .add_prop(Property::AuthenticationData, MqttPropValue::new_data(data)?)
+                    .unwrap();
+            }
+        }
+        let user_properties = self.cfg.connack_user_properties()?;
+        let mut connack = connack.build();
+        if !user_properties.is_empty() {
+            let mut with_banner = connack.clone();
+            if let Packet::ConnAck(c) = &mut with_banner {
+                for prop in user_properties {
+                    c.add_prop(Property::UserProperty, prop)?;
+                }
+            }
+            if with_banner.frame_len() <= self.internals.max_packet_size as usize {
+                connack = with_banner;
+            } else {
+                warn!("Configured CONNACK user properties exceed the client maximum packet size");
+            }
+        }
+        self.send(&connack).await?;
+        self.span.record("clientid", &&*self.internals.clientid);
+        self.span
+            .record("protocol_version", &self.conn.version().as_str());
+        // 4.4: a resumed session resends its unacknowledged publishes first
+        if self.internals.session_present {
+            self.retransmit().await?;
+        }
+        Ok(())
     }
     pub async fn connect(&mut self) -> Result<(), ServerError> {
-        match self.conn.recv().await? {
+        let packet = self.conn.recv().await?;
+        self.traffic.received(&packet, self.conn.version());
+        match packet {
             Packet::Connect(c) => self.process_connect(c).await,
             _ => Err(ServerError::FirstPacketNotConnect),
         }
@@ -0,0 +1,197 @@
+use crate::error::ServerError;
+use apiformes_packet::prelude::DisconnectReasonCode;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+const SERVER_MOVED: u8 = DisconnectReasonCode::ServerMoved as u8;
+
+/// Why the broker stopped serving a client
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// The network connection was closed by the client
+    ConnectionClosed,
+    KeepAliveTimeout,
+    /// The connection stopped taking the packets queued for the client
+    Unwritable,
+    /// More packets were queued for the client than `MqttServerConfig::max_outgoing_packets`
+    QueueOverflow,
+    MaximumConnectTime,
+    /// Another connection using the same client id replaced this one
+    SessionTakenOver,
+    ServerShutdown,
+    MalformedPacket(String),
+    PacketTooLarge,
+    /// The server sent a DISCONNECT with this reason code
+    DisconnectedByServer(u8),
+    /// The client sent a DISCONNECT with this reason code
+    DisconnectedByClient(u8),
+    /// The worker serving the client panicked
+    Panic(String),
+    Error(String),
+}
+
+impl From<&ServerError> for DisconnectReason {
+    fn from(err: &ServerError) -> Self {
+        match err {
+            ServerError::ConnectionClosed => DisconnectReason::ConnectionClosed,
+            ServerError::KeepAliveTimeout => DisconnectReason::KeepAliveTimeout,
+            ServerError::Unwritable => DisconnectReason::Unwritable,
+            ServerError::MaximumConnectTime => DisconnectReason::MaximumConnectTime,
+            ServerError::MaxPacketSizeExceeded => DisconnectReason::PacketTooLarge,
+            ServerError::DisconnectedByServer(code) => {
+                DisconnectReason::DisconnectedByServer(*code)
+            }
+            ServerError::DisconnectedByClient(code) => {
+                DisconnectReason::DisconnectedByClient(*code)
+            }
+            ServerError::Packet(e) => DisconnectReason::MalformedPacket(format!("{:?}", e)),
+            e => DisconnectReason::Error(format!("{:?}", e)),
+        }
+    }
+}
+
+impl DisconnectReason {
+    /// 3.1.2.5: the Will Message is published unless the client disconnected normally,
+    /// a server shutting down has no one left to publish it to and a client redirected
+    /// by `MqttServer::redirect_clients` carries on with another server
+    pub fn publishes_will(&self) -> bool {
+        !matches!(
+            self,
+            DisconnectReason::DisconnectedByClient(0)
+                | DisconnectReason::ServerShutdown
+                | DisconnectReason::DisconnectedByServer(SERVER_MOVED)
+        )
+    }
+}
+
+/// `keep_alive_timeout (0x8d)`, or the bare value for a code unknown to MQTT 5
+fn reason_code_name(code: u8) -> String {
+    match DisconnectReasonCode::try_from(code) {
+        Ok(reason) => format!("{} (0x{:02x})", reason, code),
+        Err(_) => format!("0x{:02x}", code),
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::ConnectionClosed => write!(f, "connection closed by client"),
+            DisconnectReason::KeepAliveTimeout => write!(f, "keep alive timeout"),
+            DisconnectReason::Unwritable => write!(f, "connection not writable"),
+            DisconnectReason::QueueOverflow => write!(f, "outgoing queue overflowed"),
+            DisconnectReason::MaximumConnectTime => write!(f, "maximum connect time reached"),
+            DisconnectReason::SessionTakenOver => write!(f, "session taken over"),
+            DisconnectReason::ServerShutdown => write!(f, "server shutting down"),
+            DisconnectReason::MalformedPacket(e) => write!(f, "malformed packet, {}", e),
+            DisconnectReason::PacketTooLarge => write!(f, "packet too large"),
+            DisconnectReason::DisconnectedByServer(code) => {
+                write!(f, "disconnected by server, {}", reason_code_name(*code))
+            }
+            DisconnectReason::DisconnectedByClient(code) => {
+                write!(f, "disconnected by client, {}", reason_code_name(*code))
+            }
+            DisconnectReason::Panic(e) => write!(f, "worker panicked, {}", e),
+            DisconnectReason::Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DisconnectRecord {
+    pub reason: DisconnectReason,
+    pub at: SystemTime,
+}
+
+/// The last disconnect reason of the most recently disconnected client ids. Once
+/// `capacity` client ids are tracked, recording a new one evicts the least recently
+/// disconnected.
+pub struct DisconnectHistory {
+    capacity: usize,
+    seq: u64,
+    records: HashMap<Arc<str>, (u64, DisconnectRecord)>,
+    // seq -> clientid, the first entry is the least recently disconnected client
+    order: BTreeMap<u64, Arc<str>>,
+}
+
+impl DisconnectHistory {
+    pub fn new(capacity: usize) -> Self {
+        DisconnectHistory {
+            capacity,
+            seq: 0,
+            records: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+    pub fn record(&mut self, clientid: Arc<str>, reason: DisconnectReason) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.seq += 1;
+        let record = DisconnectRecord {
+            reason,
+            at: SystemTime::now(),
+        };
+        if let Some((seq, _)) = self.records.insert(clientid.clone(), (self.seq, record)) {
+            self.order.remove(&seq);
+        }
+        self.order.insert(self.seq, clientid);
+        while self.records.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            self.records.remove(&oldest);
+        }
+    }
+    pub fn get(&self, clientid: &str) -> Option<&DisconnectRecord> {
+        self.records.get(clientid).map(|(_, record)| record)
+    }
+    /// Iterates over the recorded disconnects, most recent first
+    pub fn iter(&self) -> impl Iterator<Item = (&Arc<str>, &DisconnectRecord)> {
+        self.order
+            .values()
+            .rev()
+            .map(move |id| (id, &self.records[id].1))
+    }
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_disconnect_history_lru() {
+        let mut history = DisconnectHistory::new(2);
+        history.record("a".into(), DisconnectReason::KeepAliveTimeout);
+        history.record("b".into(), DisconnectReason::ConnectionClosed);
+        history.record("a".into(), DisconnectReason::SessionTakenOver);
+        assert_eq!(history.len(), 2);
+        assert_eq!(
+            history.get("a").unwrap().reason,
+            DisconnectReason::SessionTakenOver
+        );
+        history.record("c".into(), DisconnectReason::PacketTooLarge);
+        assert!(history.get("b").is_none());
+        let ids: Vec<_> = history.iter().map(|(id, _)| &**id).collect();
+        assert_eq!(ids, ["c", "a"]);
+
+        assert_eq!(
+            DisconnectReason::DisconnectedByClient(0x8d).to_string(),
+            "disconnected by client, keep_alive_timeout (0x8d)"
+        );
+        assert_eq!(
+            DisconnectReason::DisconnectedByServer(0x42).to_string(),
+            "disconnected by server, 0x42"
+        );
+
+        let mut disabled = DisconnectHistory::new(0);
+        disabled.record("a".into(), DisconnectReason::ServerShutdown);
+        assert!(disabled.is_empty());
+    }
+}
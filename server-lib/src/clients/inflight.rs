@@ -0,0 +1,482 @@
+use super::packetid::PacketIds;
+use apiformes_packet::prelude::*;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, OnceLock};
+use tokio::time::{Duration, Instant};
+
+/// Slots of `AckSlots` allocated at once
+const CHUNK: usize = 1024;
+
+struct Unacked {
+    publish: Publish,
+    sent_at: Instant,
+    // tells a slot apart from earlier publishes sent with the same identifier
+    seq: u64,
+}
+
+/// The sequence number of the publish in flight with each packet identifier, 0 when there
+/// is none. Slots are allocated by chunks of `CHUNK` as identifiers get used and never
+/// move, so they are read and cleared without any lock.
+struct AckSlots {
+    chunks: [OnceLock<Box<[AtomicU64]>>; u16::MAX as usize / CHUNK + 1],
+}
+
+impl Default for AckSlots {
+    fn default() -> Self {
+        AckSlots {
+            chunks: std::array::from_fn(|_| OnceLock::new()),
+        }
+    }
+}
+
+impl AckSlots {
+    fn slot(&self, id: u16) -> Option<&AtomicU64> {
+        let i = (id as usize).checked_sub(1)?;
+        Some(&self.chunks[i / CHUNK].get()?[i % CHUNK])
+    }
+    fn set(&self, id: u16, seq: u64) {
+        let i = id as usize - 1;
+        let chunk =
+            self.chunks[i / CHUNK].get_or_init(|| (0..CHUNK).map(|_| AtomicU64::new(0)).collect());
+        chunk[i % CHUNK].store(seq, Ordering::Release);
+    }
+    /// Clears the slot of `id`, false if no publish was in flight with it
+    fn take(&self, id: u16) -> bool {
+        match self.slot(id) {
+            Some(slot) => slot.swap(0, Ordering::AcqRel) != 0,
+            None => false,
+        }
+    }
+}
+
+/// The bookkeeping behind the slots, caught up with the acknowledgements whenever it is
+/// locked
+#[derive(Default)]
+struct Deliveries {
+    // slot `id - 1` holds the publish sent with packet identifier `id`
+    slots: Vec<Option<Unacked>>,
+    // allocates the identifier of each slot, every slot up to the highest one exists
+    ids: PacketIds,
+    // identifiers in the order the publishes were first sent, which is the order they are
+    // retransmitted in. Entries of acknowledged publishes are skipped and pruned lazily,
+    // the first entry is always a publish in flight.
+    order: VecDeque<(u16, u64)>,
+    unacked: usize,
+    // publishes waiting for the client to acknowledge others, see ReceiveMaximum (3.3.4)
+    queued: VecDeque<Publish>,
+    seq: u64,
+}
+
+/// QoS 1 publishes sent to a client and not acknowledged yet. It is part of the session
+/// state, a resumed session retransmits them before anything else (4.4).
+///
+/// A PUBACK only clears the slot indexed by its packet identifier and queues the
+/// identifier, without taking any lock, so acknowledgements scale with the number of
+/// publishes in flight. The publishes themselves are kept behind a lock which is only
+/// taken to send, retransmit or release them.
+pub(super) struct InFlight {
+    acks: AckSlots,
+    // identifiers acknowledged through `acks` the deliveries did not catch up with yet
+    acked: mpsc::Sender<u16>,
+    // mirrors the length of the queue, an acknowledgement releases nothing while it is 0
+    queued: AtomicUsize,
+    deliveries: Mutex<(Deliveries, mpsc::Receiver<u16>)>,
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        let (acked, acks_rx) = mpsc::channel();
+        InFlight {
+            acks: AckSlots::default(),
+            acked,
+            queued: AtomicUsize::new(0),
+            deliveries: Mutex::new((Deliveries::default(), acks_rx)),
+        }
+    }
+}
+
+impl Deliveries {
+    fn is_live(&self, id: u16, seq: u64) -> bool {
+        matches!(&self.slots[id as usize - 1], Some(u) if u.seq == seq)
+    }
+    fn next_id(&mut self) -> u16 {
+        let id = self.ids.allocate();
+        self.slots.resize_with(self.ids.highest() as usize, || None);
+        id
+    }
+    fn place(&mut self, acks: &AckSlots, id: u16, mut publish: Publish, now: Instant) {
+        publish.set_packet_identifier(id).unwrap();
+        self.seq += 1;
+        self.slots[id as usize - 1] = Some(Unacked {
+            publish,
+            sent_at: now,
+            seq: self.seq,
+        });
+        acks.set(id, self.seq);
+        self.order.push_back((id, self.seq));
+        self.unacked += 1;
+    }
+    fn send(&mut self, acks: &AckSlots, publish: Publish, now: Instant) -> Arc<Packet> {
+        // only QoS 1 publishes are tracked
+        let id = self.next_id();
+        self.place(acks, id, publish, now);
+        let unacked = self.slots[id as usize - 1].as_ref().unwrap();
+        Arc::new(unacked.publish.clone().build())
+    }
+    /// Forgets the publish acknowledged with `id`, its slot is already cleared
+    fn forget(&mut self, id: u16) {
+        self.slots[id as usize - 1] = None;
+        self.unacked -= 1;
+        self.ids.release(id);
+        // 4.6: acknowledgements come in order, the publish is usually the first one
+        while let Some(&(id, seq)) = self.order.front() {
+            if self.is_live(id, seq) {
+                break;
+            }
+            self.order.pop_front();
+        }
+        // acknowledgements out of order leave entries behind the first one
+        if self.order.len() > 2 * self.unacked + 64 {
+            let order = std::mem::take(&mut self.order);
+            self.order = order
+                .into_iter()
+                .filter(|&(id, seq)| self.is_live(id, seq))
+                .collect();
+        }
+    }
+    fn unacked_iter(&self) -> impl Iterator<Item = &Unacked> {
+        self.order
+            .iter()
+            .filter_map(|&(id, seq)| match &self.slots[id as usize - 1] {
+                Some(u) if u.seq == seq => Some(u),
+                _ => None,
+            })
+    }
+}
+
+impl InFlight {
+    pub(super) fn new() -> Self {
+        InFlight::default()
+    }
+    /// The deliveries caught up with the acknowledgements received so far
+    fn lock(&self) -> MutexGuard<'_, (Deliveries, mpsc::Receiver<u16>)> {
+        let mut guard = self.deliveries.lock().unwrap();
+        let (deliveries, acked) = &mut *guard;
+        for id in acked.try_iter() {
+            deliveries.forget(id);
+        }
+        guard
+    }
+    /// Assigns a packet identifier to `publish`, returns the packet to send unless the
+    /// client already has `recv_max` publishes to acknowledge, it is queued then
+    pub(super) fn submit(
+        &self,
+        publish: &Publish,
+        recv_max: u16,
+        now: Instant,
+    ) -> Option<Arc<Packet>> {
+        let mut guard = self.lock();
+        let deliveries = &mut guard.0;
+        if deliveries.unacked >= recv_max as usize || !deliveries.queued.is_empty() {
+            deliveries.queued.push_back(publish.clone());
+            self.queued
+                .store(deliveries.queued.len(), Ordering::Release);
+            return None;
+        }
+        Some(deliveries.send(&self.acks, publish.clone(), now))
+    }
+    /// Completes the delivery of the publish identified by `id`, returns false if no
+    /// publish was waiting for it
+    pub(super) fn ack(&self, id: u16) -> bool {
+        if !self.acks.take(id) {
+            return false;
+        }
+        // the receiver lives as long as the sender, both are owned by `self`
+        self.acked.send(id).unwrap();
+        true
+    }
+    /// Drops the oldest queued publishes beyond `max`, returns how many
+    pub(super) fn trim_queue(&self, max: usize) -> usize {
+        let mut guard = self.lock();
+        let queued = &mut guard.0.queued;
+        let excess = queued.len().saturating_sub(max);
+        queued.drain(..excess);
+        self.queued.store(queued.len(), Ordering::Release);
+        excess
+    }
+    /// The queued publishes the client can now receive
+    pub(super) fn release(&self, recv_max: u16, now: Instant) -> Vec<Arc<Packet>> {
+        if self.queued.load(Ordering::Acquire) == 0 {
+            return Vec::new();
+        }
+        let mut guard = self.lock();
+        let deliveries = &mut guard.0;
+        let mut packets = Vec::new();
+        while deliveries.unacked < recv_max as usize {
+            match deliveries.queued.pop_front() {
+                Some(publish) => packets.push(deliveries.send(&self.acks, publish, now)),
+                None => break,
+            }
+        }
+        self.queued
+            .store(deliveries.queued.len(), Ordering::Release);
+        packets
+    }
+    /// Every unacknowledged publish flagged as a duplicate, in the order they were first sent
+    pub(super) fn retransmit(&self, now: Instant) -> Vec<Arc<Packet>> {
+        let mut guard = self.lock();
+        let deliveries = &mut guard.0;
+        let mut packets = Vec::with_capacity(deliveries.unacked);
+        for &(id, seq) in &deliveries.order {
+            if let Some(u) = &mut deliveries.slots[id as usize - 1] {
+                if u.seq == seq {
+                    u.publish.set_dup();
+                    u.sent_at = now;
+                    packets.push(Arc::new(u.publish.clone().build()));
+                }
+            }
+        }
+        packets
+    }
+    /// The unacknowledged publishes with their packet identifier in the order they were
+    /// sent, and the publishes waiting to be sent
+    pub(super) fn export(&self) -> (Vec<Publish>, Vec<Publish>) {
+        let guard = self.lock();
+        let deliveries = &guard.0;
+        let unacked = deliveries
+            .unacked_iter()
+            .map(|u| u.publish.clone())
+            .collect();
+        (unacked, deliveries.queued.iter().cloned().collect())
+    }
+    /// Rebuilds the deliveries returned by `export`, possibly on another broker. The
+    /// unacknowledged publishes keep their identifier unless it is missing or repeated.
+    pub(super) fn restore(unacked: Vec<Publish>, queued: Vec<Publish>, now: Instant) -> Self {
+        let mut kept = vec![false; u16::MAX as usize + 1];
+        let ids: Vec<_> = unacked
+            .iter()
+            .map(|publish| match publish.packet_identifier() {
+                Some(id) if id != 0 && !kept[id as usize] => {
+                    kept[id as usize] = true;
+                    Some(id)
+                }
+                _ => None,
+            })
+            .collect();
+        let inflight = InFlight::new();
+        {
+            let mut guard = inflight.lock();
+            let deliveries = &mut guard.0;
+            let highest = ids.iter().flatten().max().copied().unwrap_or(0);
+            deliveries.slots.resize_with(highest as usize, || None);
+            deliveries.ids = PacketIds::with_used(highest, &kept);
+            for (mut publish, id) in unacked.into_iter().zip(ids) {
+                let id = id.unwrap_or_else(|| deliveries.next_id());
+                publish.set_qos(QoS::QoS1);
+                deliveries.place(&inflight.acks, id, publish, now);
+            }
+            inflight.queued.store(queued.len(), Ordering::Release);
+            deliveries.queued = queued.into();
+        }
+        inflight
+    }
+    /// Number of publishes sent and not acknowledged yet or waiting to be sent
+    pub(super) fn len(&self) -> usize {
+        let guard = self.lock();
+        guard.0.unacked + guard.0.queued.len()
+    }
+    /// When the oldest unacknowledged publish will have waited `timeout`
+    pub(super) fn next_retransmit(&self, timeout: Duration) -> Option<Instant> {
+        let guard = self.lock();
+        let deliveries = &guard.0;
+        let &(id, _) = deliveries.order.front()?;
+        deliveries.slots[id as usize - 1]
+            .as_ref()
+            .map(|u| u.sent_at + timeout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn publish(topic: &str) -> Publish {
+        let mut publish = Publish::new(Arc::from(topic), Default::default()).unwrap();
+        publish.set_qos(QoS::QoS1);
+        publish
+    }
+    fn id(packet: &Packet) -> u16 {
+        match packet {
+            Packet::Publish(p) => p.packet_identifier().unwrap(),
+            _ => panic!("not a publish"),
+        }
+    }
+    #[test]
+    fn test_inflight_window() {
+        let inflight = InFlight::new();
+        let now = Instant::now();
+        let first = inflight.submit(&publish("a"), 2, now).unwrap();
+        let second = inflight.submit(&publish("b"), 2, now).unwrap();
+        assert_ne!(id(&first), id(&second));
+        // the window is full
+        assert!(inflight.submit(&publish("c"), 2, now).is_none());
+        assert!(inflight.release(2, now).is_empty());
+
+        assert!(inflight.ack(id(&first)));
+        assert!(!inflight.ack(id(&first)));
+        let released = inflight.release(2, now);
+        assert_eq!(released.len(), 1);
+        match &*released[0] {
+            Packet::Publish(p) => assert_eq!(&**p.topic_name(), "c"),
+            _ => panic!("not a publish"),
+        }
+
+        let later = now + Duration::from_secs(30);
+        assert_eq!(
+            inflight.next_retransmit(Duration::from_secs(10)),
+            Some(now + Duration::from_secs(10))
+        );
+        let resent = inflight.retransmit(later);
+        assert_eq!(resent.len(), 2);
+        assert_eq!(id(&resent[0]), id(&second));
+        assert_eq!(id(&resent[1]), id(&released[0]));
+        for packet in resent {
+            match &*packet {
+                Packet::Publish(p) => assert!(p.flags().contains(PublishFlags::DUP)),
+                _ => panic!("not a publish"),
+            }
+        }
+        assert_eq!(
+            inflight.next_retransmit(Duration::from_secs(10)),
+            Some(later + Duration::from_secs(10))
+        );
+    }
+    #[test]
+    fn test_inflight_queue_trimmed() {
+        let inflight = InFlight::new();
+        let now = Instant::now();
+        let first = inflight.submit(&publish("a"), 1, now).unwrap();
+        for topic in ["b", "c", "d"] {
+            assert!(inflight.submit(&publish(topic), 1, now).is_none());
+        }
+        assert_eq!(inflight.trim_queue(2), 1);
+        assert_eq!(inflight.trim_queue(2), 0);
+        inflight.ack(id(&first));
+        // b was the oldest
+        match &*inflight.release(1, now)[0] {
+            Packet::Publish(p) => assert_eq!(&**p.topic_name(), "c"),
+            _ => panic!("not a publish"),
+        }
+    }
+    #[test]
+    fn test_inflight_ids_reuse_acked() {
+        let inflight = InFlight::new();
+        let now = Instant::now();
+        let first = inflight.submit(&publish("a"), u16::MAX, now).unwrap();
+        let second = inflight.submit(&publish("b"), u16::MAX, now).unwrap();
+        let third = inflight.submit(&publish("c"), u16::MAX, now).unwrap();
+        assert_eq!((id(&first), id(&second), id(&third)), (1, 2, 3));
+        // out of order, the least recently freed identifier is reused first
+        assert!(inflight.ack(2));
+        assert!(inflight.ack(1));
+        assert!(!inflight.ack(0));
+        assert!(!inflight.ack(4));
+        assert_eq!(
+            id(&inflight.submit(&publish("d"), u16::MAX, now).unwrap()),
+            2
+        );
+        assert_eq!(
+            id(&inflight.submit(&publish("e"), u16::MAX, now).unwrap()),
+            1
+        );
+        assert_eq!(
+            id(&inflight.submit(&publish("f"), u16::MAX, now).unwrap()),
+            4
+        );
+        let resent: Vec<_> = inflight.retransmit(now).iter().map(|p| id(p)).collect();
+        assert_eq!(resent, [3, 2, 1, 4]);
+    }
+    #[test]
+    fn test_inflight_many_out_of_order() {
+        let inflight = InFlight::new();
+        let now = Instant::now();
+        let ids: Vec<_> = (0..10_000)
+            .map(|_| id(&inflight.submit(&publish("a"), u16::MAX, now).unwrap()))
+            .collect();
+        let later = now + Duration::from_secs(1);
+        // the first publish stays unacknowledged while the others cycle through slots
+        for round in 0..20 {
+            for &i in ids.iter().skip(1).rev() {
+                assert!(inflight.ack(i));
+                inflight.submit(&publish("b"), u16::MAX, later).unwrap();
+            }
+            assert_eq!(inflight.len(), 10_000, "round {}", round);
+        }
+        let guard = inflight.lock();
+        assert!(guard.0.order.len() <= 2 * guard.0.unacked + 64);
+        assert!(guard.0.slots.len() <= 10_000);
+        drop(guard);
+        assert_eq!(
+            inflight.next_retransmit(Duration::from_secs(10)),
+            Some(now + Duration::from_secs(10))
+        );
+        assert!(inflight.ack(ids[0]));
+        assert_eq!(
+            inflight.next_retransmit(Duration::from_secs(10)),
+            Some(later + Duration::from_secs(10))
+        );
+    }
+    #[test]
+    fn test_inflight_acks_from_threads() {
+        let inflight = Arc::new(InFlight::new());
+        let now = Instant::now();
+        let ids: Vec<u16> = (0..4000)
+            .map(|i| {
+                id(&inflight
+                    .submit(&publish(&i.to_string()), u16::MAX, now)
+                    .unwrap())
+            })
+            .collect();
+        let threads: Vec<_> = ids
+            .chunks(1000)
+            .map(|ids| {
+                let (inflight, ids) = (inflight.clone(), ids.to_vec());
+                std::thread::spawn(move || ids.into_iter().all(|id| inflight.ack(id)))
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap());
+        }
+        assert!(!inflight.ack(ids[0]));
+        assert_eq!(inflight.len(), 0);
+        assert!(inflight.retransmit(now).is_empty());
+        // the acknowledged identifiers are allocated again
+        let again = inflight.submit(&publish("a"), u16::MAX, now).unwrap();
+        assert!(ids.contains(&id(&again)));
+    }
+    #[test]
+    fn test_inflight_restore() {
+        let inflight = InFlight::new();
+        let now = Instant::now();
+        let first = inflight.submit(&publish("a"), 1, now).unwrap();
+        assert!(inflight.submit(&publish("b"), 1, now).is_none());
+        let (unacked, queued) = inflight.export();
+        assert_eq!(unacked.len(), 1);
+        assert_eq!(queued.len(), 1);
+
+        let restored = InFlight::restore(unacked, queued, now);
+        assert_eq!(restored.len(), 2);
+        // the client acknowledges with the identifier it was sent
+        assert!(restored.ack(id(&first)));
+        let released = restored.release(1, now);
+        assert_eq!(released.len(), 1);
+        // the acknowledged identifier is free again
+        assert_eq!(id(&released[0]), id(&first));
+
+        // publishes lacking an identifier get a free one
+        let restored = InFlight::restore(vec![publish("c"), publish("d")], Vec::new(), now);
+        let (unacked, _) = restored.export();
+        assert_eq!(unacked[0].packet_identifier(), Some(1));
+        assert_eq!(unacked[1].packet_identifier(), Some(2));
+    }
+}
@@ -0,0 +1,88 @@
+use super::{migration::subscriptions_of, Client, ExportedSubscription};
+use crate::{
+    admin::{ApiError, ApiErrorCode},
+    topics::TopicsTable,
+};
+use apiformes_packet::prelude::{Disconnect, DisconnectReasonCode};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// A connected client along with its session, see `MqttServer::client_sessions`
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ClientSession {
+    pub clientid: String,
+    pub username: Option<String>,
+    /// Tag of the listener the client connected to
+    pub listener: String,
+    pub encrypted: bool,
+    /// Negotiated keep alive in seconds, 0 when the client is never timed out
+    pub keep_alive: u16,
+    /// Seconds the session outlives the connection, `u32::MAX` never expires
+    pub session_expiry: u32,
+    /// The CONNACK told the client its previous session was resumed
+    pub session_present: bool,
+    /// Packets queued for the client its connection did not take yet
+    pub backlog: usize,
+    /// Packets discarded because the queue of the client was full
+    pub dropped: u64,
+    /// QoS 1 publishes the client has not acknowledged yet
+    pub unacknowledged: usize,
+    pub subscriptions: Vec<ExportedSubscription>,
+}
+
+/// The sessions of the connected clients, internal ones excluded, ordered by client id
+pub(crate) async fn client_sessions(
+    clients: &RwLock<HashMap<Arc<str>, Client>>,
+    topics: &TopicsTable,
+) -> Vec<ClientSession> {
+    let mut connected: Vec<_> = clients
+        .read()
+        .await
+        .values()
+        .filter(|c| !c.internal())
+        .cloned()
+        .collect();
+    connected.sort_by(|a, b| a.clientid.cmp(&b.clientid));
+    let mut listed = Vec::with_capacity(connected.len());
+    for c in connected {
+        listed.push(ClientSession {
+            clientid: c.clientid.to_string(),
+            username: c.username().map(str::to_owned),
+            listener: c.listener.to_string(),
+            encrypted: c.encrypted,
+            keep_alive: c.keep_alive,
+            session_expiry: c.session_expirary,
+            session_present: c.session_present,
+            backlog: c.backlog(),
+            dropped: c.dropped(),
+            unacknowledged: c.unacknowledged(),
+            subscriptions: subscriptions_of(topics, &c.clientid).await,
+        });
+    }
+    listed
+}
+
+/// Disconnects `clientid` with the AdministrativeAction reason code, its will is published
+/// and its session kept as for any other disconnect initiated by the broker
+pub(crate) async fn disconnect_client(
+    clients: &RwLock<HashMap<Arc<str>, Client>>,
+    clientid: &str,
+) -> Result<(), ApiError> {
+    let not_found = || {
+        ApiError::new(
+            ApiErrorCode::NotFound,
+            format!("{} is not connected", clientid),
+        )
+        .with_field("clientid")
+    };
+    let clients = clients.read().await;
+    let client = clients
+        .get(clientid)
+        .filter(|c| !c.internal())
+        .ok_or_else(not_found)?;
+    let disconnect = Disconnect::new(DisconnectReasonCode::AdministrativeAction);
+    // the connection is gone when its queue is closed
+    client.send(disconnect.build()).map_err(|_| not_found())
+}
@@ -0,0 +1,82 @@
+use super::{
+    client::INTERNAL_CLIENTID_PREFIX,
+    queue::{outgoing_queue, OutgoingReceiver},
+    Client,
+};
+use crate::{config::OverflowPolicy, error::ServerError, shutdown::Shutdown};
+use apiformes_packet::prelude::Packet;
+use std::collections::{hash_map::Entry, HashMap};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::RwLock;
+use tracing::info;
+
+/// A pseudo-client living inside the broker. It occupies an entry in the clients map
+/// under the reserved `$internal/` namespace, so the dispatcher can deliver packets to it
+/// exactly as it does for clients connected over the network.
+pub struct InternalClient {
+    clientid: Arc<str>,
+    incoming: OutgoingReceiver,
+    clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+}
+
+impl InternalClient {
+    pub(crate) async fn register(
+        name: &str,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+        shutdown: Shutdown,
+    ) -> Result<Self, ServerError> {
+        InternalClient::register_with(name, clients, shutdown, true).await
+    }
+    /// Same as `register` for a client acting on behalf of parties outside of the broker,
+    /// the authorizer decides what it may publish or subscribe to
+    pub(crate) async fn register_untrusted(
+        name: &str,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+        shutdown: Shutdown,
+    ) -> Result<Self, ServerError> {
+        InternalClient::register_with(name, clients, shutdown, false).await
+    }
+    async fn register_with(
+        name: &str,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+        shutdown: Shutdown,
+        acl_exempt: bool,
+    ) -> Result<Self, ServerError> {
+        let clientid: Arc<str> = format!("{}{}", INTERNAL_CLIENTID_PREFIX, name).into();
+        // internal clients keep up with the broker, their queue is not bounded
+        let (tx, rx) = outgoing_queue(None, OverflowPolicy::Disconnect);
+        match clients.write().await.entry(clientid.clone()) {
+            Entry::Occupied(_) => return Err(ServerError::ClientIdInUse(clientid)),
+            Entry::Vacant(e) => {
+                e.insert(Client::new_internal(
+                    clientid.clone(),
+                    shutdown,
+                    tx,
+                    acl_exempt,
+                ));
+            }
+        }
+        info!(clientid = &*clientid, "Registered internal client");
+        Ok(InternalClient {
+            clientid,
+            incoming: rx,
+            clients,
+        })
+    }
+    pub fn clientid(&self) -> &Arc<str> {
+        &self.clientid
+    }
+    /// Receives the next packet the broker delivered to this client, returns `None`
+    /// once the client has been removed from the clients map.
+    pub async fn recv(&mut self) -> Option<Arc<Packet>> {
+        self.incoming.recv().await.map(|o| o.packet)
+    }
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Arc<Packet>>> {
+        self.incoming.poll_recv(cx).map(|o| o.map(|o| o.packet))
+    }
+    pub async fn unregister(self) {
+        self.clients.write().await.remove(&self.clientid);
+        info!(clientid = &*self.clientid, "Unregistered internal client");
+    }
+}
@@ -0,0 +1,100 @@
+use super::pacing::ConnectionSlot;
+#[cfg(feature = "noise")]
+use crate::config::Permeability;
+use crate::config::{ListenerConfig, MqttServerConfig, PublishQuota, ReadTuning};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Settings of the connections accepted by a listener, the global ones unless its
+/// `ListenerConfig` overrides them
+pub(super) struct Listener {
+    pub(super) tag: Arc<str>,
+    pub(super) max_connections: Option<usize>,
+    pub(super) require_auth: bool,
+    pub(super) max_qos: u8,
+    pub(super) max_packet_size: u32,
+    pub(super) strict_parsing: bool,
+    #[cfg(feature = "noise")]
+    pub(super) permeability: Permeability,
+    pub(super) read: ReadTuning,
+    pub(super) quota: Option<PublishQuota>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Listener {
+    /// One of the listeners of the global settings, e.g. `mqtt_socketaddr`
+    pub(super) fn builtin(
+        cfg: &MqttServerConfig,
+        tag: &str,
+        read: ReadTuning,
+        quota: Option<PublishQuota>,
+    ) -> Self {
+        Listener {
+            tag: Arc::from(tag),
+            max_connections: None,
+            require_auth: false,
+            max_qos: cfg.max_qos,
+            max_packet_size: cfg.max_packet_size,
+            strict_parsing: cfg.strict_parsing,
+            #[cfg(feature = "noise")]
+            permeability: cfg.channel_permeability,
+            read,
+            quota,
+            connections: Arc::default(),
+        }
+    }
+    /// One of `MqttServerConfig::listeners`
+    pub(super) fn configured(cfg: &MqttServerConfig, listener: &ListenerConfig) -> Self {
+        Listener {
+            tag: Arc::from(&*listener.tag),
+            max_connections: listener.max_connections,
+            require_auth: listener.require_auth,
+            max_qos: listener.max_qos.unwrap_or(cfg.max_qos),
+            max_packet_size: listener.max_packet_size.unwrap_or(cfg.max_packet_size),
+            strict_parsing: cfg.strict_parsing,
+            #[cfg(feature = "noise")]
+            permeability: listener
+                .channel_permeability
+                .unwrap_or(cfg.channel_permeability),
+            read: listener.read.unwrap_or(cfg.mqtt_read),
+            quota: listener.publish_quota.clone(),
+            connections: Arc::default(),
+        }
+    }
+    /// Counts a connection to this listener as open until the returned slot is dropped
+    pub(super) fn open(&self) -> ConnectionSlot {
+        ConnectionSlot::open(&self.connections)
+    }
+    /// More connections are open than the listener accepts, the one checking included
+    pub(super) fn full(&self) -> bool {
+        matches!(self.max_connections, Some(max) if self.connections.load(Ordering::Acquire) > max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_listener_settings() {
+        let cfg = MqttServerConfig::default();
+        let listener: ListenerConfig = toml::from_str(
+            "tag = \"sensors\"\nsocketaddr = \"127.0.0.1:1885\"\nmax_connections = 1\nmax_qos = 0",
+        )
+        .unwrap();
+        let listener = Listener::configured(&cfg, &listener);
+        assert_eq!(&*listener.tag, "sensors");
+        assert_eq!(listener.max_qos, 0);
+        assert_eq!(listener.max_packet_size, cfg.max_packet_size);
+        let first = listener.open();
+        assert!(!listener.full());
+        let second = listener.open();
+        assert!(listener.full());
+        drop(first);
+        assert!(!listener.full());
+        drop(second);
+        assert!(!Listener::builtin(&cfg, "mqtt", cfg.mqtt_read, None).full());
+    }
+}
@@ -0,0 +1,263 @@
+use super::{inflight::InFlight, is_internal_clientid, session::SessionStore, Client};
+use crate::{
+    admin::{ApiError, ApiErrorCode},
+    topics::{SubscriptionFlags, TopicsTable},
+};
+use apiformes_packet::prelude::*;
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+
+/// A subscription of an exported session
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExportedSubscription {
+    pub filter: String,
+    pub qos: u8,
+    pub no_local: bool,
+    pub retain_as_published: bool,
+}
+
+/// The state of one client session, publishes are kept as encoded PUBLISH packets so their
+/// properties survive the migration
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExportedSession {
+    pub clientid: String,
+    /// Seconds left before the session expires, `u32::MAX` never expires
+    pub expiry: u32,
+    pub subscriptions: Vec<ExportedSubscription>,
+    /// QoS 1 publishes not acknowledged by the client yet, in the order they were sent
+    pub unacked: Vec<Vec<u8>>,
+    /// QoS 1 publishes waiting for the client to acknowledge the unacked ones
+    pub queued: Vec<Vec<u8>>,
+    pub will: Option<Vec<u8>>,
+    /// Seconds left before the will is published
+    pub will_delay: u32,
+}
+
+/// The sessions of a broker in a portable format, see `MqttServer::export_sessions`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SessionExport {
+    pub node_id: String,
+    /// The id the next forwarded message of the exporting broker would have been stamped with
+    pub message_id: u64,
+    pub sessions: Vec<ExportedSession>,
+}
+
+impl SessionExport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    pub fn from_json(json: &str) -> Result<Self, ApiError> {
+        serde_json::from_str(json)
+            .map_err(|e| ApiError::new(ApiErrorCode::InvalidArgument, e.to_string()))
+    }
+}
+
+pub(crate) fn encode(publish: &Publish) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    publish.clone().build().to_bytes(&mut buf);
+    buf.to_vec()
+}
+
+fn decode(clientid: &str, bytes: &[u8]) -> Result<Publish, ApiError> {
+    match Packet::from_bytes(&mut Cursor::new(bytes)) {
+        Ok(Packet::Publish(publish)) => Ok(publish),
+        _ => Err(ApiError::new(
+            ApiErrorCode::InvalidArgument,
+            format!("session of {} holds an invalid publish", clientid),
+        )
+        .with_field("sessions")),
+    }
+}
+
+/// The subscriptions `clientid` holds in `topics`
+pub(crate) async fn subscriptions_of(
+    topics: &TopicsTable,
+    clientid: &Arc<str>,
+) -> Vec<ExportedSubscription> {
+    topics
+        .subscriptions_of(clientid)
+        .await
+        .into_iter()
+        .map(|(filter, info)| ExportedSubscription {
+            filter: filter.to_string(),
+            qos: info.qos.as_u8(),
+            no_local: info.flags.contains(SubscriptionFlags::NO_LOCAL),
+            retain_as_published: info.flags.contains(SubscriptionFlags::RETAIN_AS_PUBLISHED),
+        })
+        .collect()
+}
+
+async fn export_session(
+    topics: &TopicsTable,
+    clientid: &Arc<str>,
+    expiry: u32,
+    inflight: &InFlight,
+    will: Option<&Publish>,
+    will_delay: u32,
+) -> ExportedSession {
+    let (unacked, queued) = inflight.export();
+    let subscriptions = subscriptions_of(topics, clientid).await;
+    ExportedSession {
+        clientid: clientid.to_string(),
+        expiry,
+        subscriptions,
+        unacked: unacked.iter().map(encode).collect(),
+        queued: queued.iter().map(encode).collect(),
+        will: will.map(encode),
+        will_delay,
+    }
+}
+
+/// The sessions of the connected clients which outlive their connection and the parked
+/// sessions, ordered by client id
+pub(crate) async fn export(
+    clients: &RwLock<HashMap<Arc<str>, Client>>,
+    sessions: &SessionStore,
+    topics: &TopicsTable,
+) -> Vec<ExportedSession> {
+    let connected: Vec<_> = clients
+        .read()
+        .await
+        .values()
+        .filter(|c| !c.internal() && c.session_expirary > 0)
+        .cloned()
+        .collect();
+    let mut exported = Vec::new();
+    for c in &connected {
+        let session = export_session(
+            topics,
+            &c.clientid,
+            c.session_expirary,
+            &c.inflight,
+            c.will.as_ref(),
+            c.will_delay,
+        )
+        .await;
+        exported.push(session);
+    }
+    for parked in sessions.parked(Instant::now()) {
+        let session = export_session(
+            topics,
+            &parked.clientid,
+            parked.expiry,
+            &parked.inflight,
+            parked.will.as_ref(),
+            parked.will_delay,
+        )
+        .await;
+        exported.push(session);
+    }
+    exported.sort_by(|a, b| a.clientid.cmp(&b.clientid));
+    exported
+}
+
+struct Decoded {
+    clientid: Arc<str>,
+    subscriptions: Vec<(Arc<str>, QoS, SubscriptionFlags)>,
+    unacked: Vec<Publish>,
+    queued: Vec<Publish>,
+    will: Option<Publish>,
+}
+
+fn decode_session(session: &ExportedSession) -> Result<Decoded, ApiError> {
+    let clientid = &*session.clientid;
+    let mut subscriptions = Vec::new();
+    for sub in &session.subscriptions {
+        let qos = QoS::from_u8(sub.qos).map_err(|_| {
+            ApiError::new(
+                ApiErrorCode::InvalidArgument,
+                format!(
+                    "subscription {} of {} has an invalid QoS",
+                    sub.filter, clientid
+                ),
+            )
+            .with_field("sessions")
+        })?;
+        let mut flags = SubscriptionFlags::empty();
+        flags.set(SubscriptionFlags::NO_LOCAL, sub.no_local);
+        flags.set(
+            SubscriptionFlags::RETAIN_AS_PUBLISHED,
+            sub.retain_as_published,
+        );
+        subscriptions.push((Arc::from(&*sub.filter), qos, flags));
+    }
+    let decode_all = |packets: &[Vec<u8>]| -> Result<Vec<Publish>, ApiError> {
+        packets.iter().map(|p| decode(clientid, p)).collect()
+    };
+    Ok(Decoded {
+        clientid: Arc::from(clientid),
+        subscriptions,
+        unacked: decode_all(&session.unacked)?,
+        queued: decode_all(&session.queued)?,
+        will: session
+            .will
+            .as_deref()
+            .map(|will| decode(clientid, will))
+            .transpose()?,
+    })
+}
+
+/// Parks the exported sessions until their clients connect, sessions of the clients
+/// already connected or holding a session are left alone. Nothing is imported if any
+/// session is invalid. Returns the number of sessions imported.
+pub(crate) async fn import(
+    exported: &[ExportedSession],
+    clients: &RwLock<HashMap<Arc<str>, Client>>,
+    sessions: &SessionStore,
+    topics: &TopicsTable,
+) -> Result<usize, ApiError> {
+    let decoded = exported
+        .iter()
+        .map(decode_session)
+        .collect::<Result<Vec<_>, _>>()?;
+    let now = Instant::now();
+    let mut imported = 0;
+    for (session, decoded) in exported.iter().zip(decoded) {
+        let clientid = decoded.clientid;
+        if session.expiry == 0
+            || is_internal_clientid(&clientid)
+            || clients.read().await.contains_key(&clientid)
+            || sessions.inflight(&clientid, now).is_some()
+        {
+            continue;
+        }
+        for (filter, qos, flags) in decoded.subscriptions {
+            topics.subscribe(clientid.clone(), filter, qos, flags).await;
+        }
+        let inflight = InFlight::restore(decoded.unacked, decoded.queued, now);
+        sessions.park(
+            clientid,
+            session.expiry,
+            Arc::new(inflight),
+            decoded.will,
+            session.will_delay,
+            now,
+        );
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+/// Makes the exported sessions the only parked ones, the sessions missing from `exported`
+/// are dropped without publishing their wills. Meant for a standby following its primary,
+/// nothing is changed if any session is invalid. Returns the number of sessions parked.
+pub(crate) async fn replace(
+    exported: &[ExportedSession],
+    clients: &RwLock<HashMap<Arc<str>, Client>>,
+    sessions: &SessionStore,
+    topics: &TopicsTable,
+) -> Result<usize, ApiError> {
+    for session in exported {
+        decode_session(session)?;
+    }
+    for parked in sessions.parked(Instant::now()) {
+        topics.unsubscribe_all(parked.clientid.clone()).await;
+        sessions.remove(&parked.clientid);
+    }
+    import(exported, clients, sessions, topics).await
+}
@@ -1,193 +1,681 @@
+mod auth;
 mod client;
+mod clientid;
 mod clientworker;
+mod history;
+mod inflight;
+mod inspect;
+mod internal;
+mod listener;
+mod migration;
 mod mqttclient;
 #[cfg(feature = "noise")]
 mod noiseclient;
+mod outbound;
+mod pacing;
+mod packetid;
+mod queue;
+mod session;
+#[cfg(feature = "websocket")]
+mod wsclient;
 
-use crate::{config::MqttServerConfig, error::ServerError, packetinfo::PacketInfo};
-pub use client::Client;
-use clientworker::ClientWorker;
-use futures::{stream::FuturesUnordered, StreamExt};
+use crate::{
+    config::{MqttServerConfig, SessionPolicy},
+    error::ServerError,
+    metrics::Metrics,
+    packetinfo::PacketInfo,
+    shutdown::Shutdown,
+    throttle::LogThrottle,
+    topics::TopicsTable,
+    trace::PublishTracer,
+};
+use apiformes_packet::prelude::{Packet, Publish, ReasonCode};
+pub use auth::{AuthExchange, AuthProvider, AuthStep};
+pub use client::{is_internal_clientid, Client, INTERNAL_CLIENTID_PREFIX};
+pub use clientid::{
+    ClientIdGenerator, ConnectionInfo, PublicKeyClientIds, SequentialClientIds, UuidClientIds,
+};
+use clientworker::{record_disconnect, ClientWorker};
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
+pub use history::{DisconnectHistory, DisconnectReason, DisconnectRecord};
+pub use inspect::ClientSession;
+pub(crate) use inspect::{client_sessions, disconnect_client};
+pub use internal::InternalClient;
+use listener::Listener;
+pub(crate) use migration::{
+    encode as encode_publish, export as export_sessions, import as import_sessions,
+    replace as replace_sessions,
+};
+pub use migration::{ExportedSession, ExportedSubscription, SessionExport};
 pub use mqttclient::MqttListener;
 #[cfg(feature = "noise")]
+pub(crate) use noiseclient::NoiseKey;
+#[cfg(feature = "noise")]
 pub use noiseclient::NoiseListener;
+pub(crate) use outbound::SharedFrame;
+use pacing::ConnectPacer;
+#[cfg(test)]
+pub(crate) use queue::{outgoing_queue, Outgoing, OutgoingReceiver};
+pub(crate) use session::SessionStore;
 use std::collections::HashMap;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    any::Any,
+    future::Future,
+    net::SocketAddr,
+    panic::AssertUnwindSafe,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
 use tokio::{
     net::TcpListener,
     sync::{
         mpsc::{unbounded_channel, Sender, UnboundedReceiver, UnboundedSender},
-        Notify, RwLock,
+        RwLock,
     },
     task::{JoinError, JoinHandle},
+    time::{sleep_until, Duration, Instant},
 };
-use tracing::{error, info, instrument, warn};
+use tracing::{error, info, instrument, warn, Instrument, Span};
+#[cfg(feature = "websocket")]
+pub use wsclient::MultiplexListener;
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(|s| s.as_str()))
+        .unwrap_or("unknown cause")
+}
+
+/// How a client worker task ended
+enum WorkerExit {
+    Retired(Client, DisconnectReason),
+    Panicked(Client, Box<dyn Any + Send>),
+}
+
+/// Runs the worker of `client` in its own task, panics are caught inside the task so we
+/// still know which client to clean up
+fn spawn_worker(
+    client: Client,
+    run: impl Future<Output = (Client, DisconnectReason)> + Send + 'static,
+    span: Span,
+) -> JoinHandle<WorkerExit> {
+    tokio::spawn(
+        async move {
+            match AssertUnwindSafe(run).catch_unwind().await {
+                Ok((client, reason)) => WorkerExit::Retired(client, reason),
+                Err(panic) => {
+                    let reason = DisconnectReason::Panic(panic_message(&*panic).to_owned());
+                    record_disconnect(&Span::current(), &reason);
+                    WorkerExit::Panicked(client, panic)
+                }
+            }
+        }
+        .instrument(span),
+    )
+}
 
 pub struct ClientManager {
     rx: UnboundedReceiver<ClientWorker>,
     clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+    topics: Arc<TopicsTable>,
+    metrics: Arc<Metrics>,
+    history: Arc<Mutex<DisconnectHistory>>,
     cfg: Arc<MqttServerConfig>,
-    shutdown: Arc<Notify>,
-    workers: FuturesUnordered<JoinHandle<Arc<str>>>,
+    shutdown: Shutdown,
+    workers: FuturesUnordered<JoinHandle<WorkerExit>>,
+    sessions: Arc<SessionStore>,
+    // wills are published by this client in place of the clients that are gone
+    wills: InternalClient,
+    incoming: Sender<PacketInfo>,
+    // connections whose outgoing queue was found closed before their worker retired
+    purge_tx: UnboundedSender<Client>,
+    purge_rx: UnboundedReceiver<Client>,
 }
 
 impl ClientManager {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         cfg: Arc<MqttServerConfig>,
         clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
-        shutdown: Arc<Notify>,
+        topics: Arc<TopicsTable>,
+        metrics: Arc<Metrics>,
+        history: Arc<Mutex<DisconnectHistory>>,
+        shutdown: Shutdown,
         rx: UnboundedReceiver<ClientWorker>,
+        sessions: Arc<SessionStore>,
+        wills: InternalClient,
+        incoming: Sender<PacketInfo>,
     ) -> Self {
+        let (purge_tx, purge_rx) = unbounded_channel();
         ClientManager {
             rx,
             clients,
+            topics,
+            metrics,
+            history,
             cfg,
             shutdown,
             workers: FuturesUnordered::new(),
+            sessions,
+            wills,
+            incoming,
+            purge_tx,
+            purge_rx,
         }
     }
+    #[allow(clippy::too_many_arguments)]
     #[instrument(name = "ClientManager::start", skip_all)]
     pub async fn start(
         cfg: Arc<MqttServerConfig>,
         clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
-        shutdown: Arc<Notify>,
+        topics: Arc<TopicsTable>,
+        metrics: Arc<Metrics>,
+        history: Arc<Mutex<DisconnectHistory>>,
+        shutdown: Shutdown,
         incoming: Sender<PacketInfo>,
-    ) -> Result<Vec<JoinHandle<()>>, ServerError> {
+        sessions: Arc<SessionStore>,
+        tracer: Arc<PublishTracer>,
+        standby: Arc<AtomicBool>,
+        #[cfg(feature = "noise")] noise_key: NoiseKey,
+    ) -> Result<(Vec<JoinHandle<()>>, Vec<(Arc<str>, SocketAddr)>), ServerError> {
         let (tx, rx) = unbounded_channel();
+        let pacer = Arc::new(ConnectPacer::new(&cfg, metrics.clone(), standby));
+        let throttle = Arc::new(LogThrottle::new(Duration::from_secs(
+            cfg.log_throttle as u64,
+        )));
 
         let mut workers = Vec::new();
-        if let Some(saddr) = cfg.mqtt_socketaddr {
-            let handle = ClientManager::incomming_mqtt_listener(
+        // the address each listener is bound to, by tag
+        let mut bound = Vec::new();
+        let builtin =
+            Listener::builtin(&cfg, "mqtt", cfg.mqtt_read, cfg.publish_quotas.mqtt.clone());
+        let listeners = cfg
+            .mqtt_socketaddr
+            .map(|saddr| (saddr, builtin))
+            .into_iter()
+            .chain(
+                cfg.listeners
+                    .iter()
+                    .map(|l| (l.socketaddr, Listener::configured(&cfg, l))),
+            );
+        for (saddr, settings) in listeners {
+            let tag = settings.tag.clone();
+            let (handle, local) = ClientManager::incomming_mqtt_listener(
                 &saddr,
                 tx.clone(),
                 shutdown.clone(),
                 cfg.clone(),
+                clients.clone(),
                 incoming.clone(),
+                pacer.clone(),
+                sessions.clone(),
+                throttle.clone(),
+                tracer.clone(),
+                Arc::new(settings),
             )
             .await?;
-            workers.push(handle)
+            workers.push(handle);
+            bound.push((tag, local));
         }
 
         #[cfg(feature = "noise")]
         if let Some(saddr) = cfg.noise_socketaddr {
-            let handle = ClientManager::incomming_noise_listener(
+            let settings = Listener::builtin(
+                &cfg,
+                "noise",
+                cfg.noise_read,
+                cfg.publish_quotas.noise.clone(),
+            );
+            let (handle, local) = ClientManager::incomming_noise_listener(
                 &saddr,
+                noise_key,
                 tx.clone(),
                 shutdown.clone(),
                 cfg.clone(),
-                incoming,
+                clients.clone(),
+                incoming.clone(),
+                pacer.clone(),
+                sessions.clone(),
+                throttle.clone(),
+                tracer.clone(),
+                Arc::new(settings),
             )
             .await?;
-            workers.push(handle)
+            workers.push(handle);
+            bound.push((Arc::from("noise"), local));
         }
 
-        let man = ClientManager::new(cfg, clients, shutdown, rx);
+        #[cfg(feature = "websocket")]
+        if let Some(saddr) = cfg.multiplex_socketaddr {
+            let settings = Listener::builtin(
+                &cfg,
+                "multiplex",
+                cfg.multiplex_read,
+                cfg.publish_quotas.multiplex.clone(),
+            );
+            let (handle, local) = ClientManager::incomming_multiplex_listener(
+                &saddr,
+                tx.clone(),
+                shutdown.clone(),
+                cfg.clone(),
+                clients.clone(),
+                incoming.clone(),
+                pacer,
+                sessions.clone(),
+                throttle.clone(),
+                tracer.clone(),
+                Arc::new(settings),
+            )
+            .await?;
+            workers.push(handle);
+            bound.push((Arc::from("multiplex"), local));
+        }
+
+        let wills = InternalClient::register("wills", clients.clone(), shutdown.clone()).await?;
+        let man = ClientManager::new(
+            cfg, clients, topics, metrics, history, shutdown, rx, sessions, wills, incoming,
+        );
         workers.push(man.start_processing().await);
-        Ok(workers)
+        Ok((workers, bound))
     }
 
     async fn process_new_worker(&mut self, maybe_worker: Option<ClientWorker>) -> bool {
-        let worker = match maybe_worker {
+        let mut worker = match maybe_worker {
             Some(worker) => worker,
             None => {
-                warn!("All ClientWorker Tx halves has been dropped which is means server is shutting down or this is an internal bug,");
+                info!("Every listener is gone, no new connection will be served");
                 return false;
             }
         };
-        let client = worker.internals();
-        self.clients
+        worker.internals_mut().purge_on_close(self.purge_tx.clone());
+        let client = worker.internals().clone();
+        let previous = self
+            .clients
             .write()
             .await
             .insert(client.clientid.clone(), client.clone());
-        self.workers
-            .push(tokio::spawn(async move { worker.run().await }));
+        if let Some(previous) = previous {
+            previous.take_over();
+        }
+        self.sessions.remove(&client.clientid);
+        self.enforce_max_sessions().await;
+        if !client.session_present {
+            // whatever is left of an older session is dropped, clean start or not
+            self.topics.unsubscribe_all(client.clientid.clone()).await;
+        }
+        for interceptor in &self.cfg.interceptors {
+            interceptor.on_connect(&client);
+        }
+        let span = worker.span().clone();
+        self.workers.push(spawn_worker(client, worker.run(), span));
         true
     }
-    async fn process_retiring_worker(&mut self, maybe_id: Option<Result<Arc<str>, JoinError>>) {
-        //TODO we don't remove the worker information from the topic file system yet
-        match maybe_id {
+    /// Removes every trace of a client whose worker is gone
+    async fn cleanup_client(&self, client: &Client, reason: DisconnectReason) {
+        info!(clientid = &*client.clientid, "Client retired, {}", reason);
+        for interceptor in &self.cfg.interceptors {
+            interceptor.on_disconnect(client, &reason);
+        }
+        let will = client.will.clone().filter(|_| reason.publishes_will());
+        self.history
+            .lock()
+            .unwrap()
+            .record(client.clientid.clone(), reason);
+        let mut clients = self.clients.write().await;
+        match clients.get(&client.clientid) {
+            // the client id has been taken over by a newer connection which we must keep, it
+            // also cancels a will that is held back (3.1.3.2.2)
+            Some(current) if !current.same_connection(client) => {
+                drop(clients);
+                if let Some(will) = will.filter(|_| client.will_delay == 0) {
+                    self.publish_will(will).await;
+                }
+                return;
+            }
+            Some(_) => drop(clients.remove(&client.clientid)),
+            None => (),
+        }
+        drop(clients);
+        if self.retains_session(client) {
+            info!(
+                clientid = &*client.clientid,
+                "Retaining session for {}s", client.session_expirary
+            );
+            // the will is published by `expire_sessions` once its delay passes
+            self.sessions.park(
+                client.clientid.clone(),
+                client.session_expirary,
+                client.inflight.clone(),
+                will,
+                client.will_delay,
+                Instant::now(),
+            );
+            self.enforce_max_sessions().await;
+        } else {
+            // the session ends with the connection, so does the will delay
+            if let Some(will) = will {
+                self.publish_will(will).await;
+            }
+            self.topics.unsubscribe_all(client.clientid.clone()).await;
+        }
+    }
+    /// Stops the fan-out to a connection whose worker is gone as soon as a packet for it
+    /// could not be queued, `cleanup_client` completes the cleanup once the worker retires
+    async fn purge_client(&self, client: Client) {
+        let mut clients = self.clients.write().await;
+        match clients.get(&client.clientid) {
+            Some(current) if current.same_connection(&client) => {
+                clients.remove(&client.clientid);
+            }
+            _ => return,
+        }
+        drop(clients);
+        info!(
+            clientid = &*client.clientid,
+            "Purged client with a closed queue"
+        );
+        self.metrics.inc_clients_purged();
+        if !self.retains_session(&client) {
+            self.topics.unsubscribe_all(client.clientid.clone()).await;
+        }
+    }
+    /// Publishes a will in place of the client it belongs to
+    async fn publish_will(&self, will: Publish) {
+        info!(topic = &**will.topic_name(), "Publishing will message");
+        let packet = PacketInfo {
+            senderid: self.wills.clientid().clone(),
+            packet: will.build(),
+            traced: false,
+        };
+        if self.incoming.send(packet).await.is_err() {
+            warn!("Dispatcher queue is closed, dropping will message");
+        }
+    }
+    /// Transient sessions end with the network connection (3.1.2.11.2)
+    fn retains_session(&self, client: &Client) -> bool {
+        self.cfg.session_policy == SessionPolicy::RetainUntilExpiry && client.session_expirary > 0
+    }
+    async fn expire_sessions(&self) {
+        let now = Instant::now();
+        for will in self.sessions.due_wills(now) {
+            self.publish_will(will).await;
+        }
+        for clientid in self.sessions.expire(now) {
+            info!(clientid = &*clientid, "Session expired");
+            self.topics.unsubscribe_all(clientid).await;
+        }
+    }
+    /// Ends the retained sessions idle for the longest until the connected clients and the
+    /// retained sessions fit within `max_sessions`
+    async fn enforce_max_sessions(&self) {
+        let max_sessions = match self.cfg.max_sessions {
+            Some(max_sessions) => max_sessions,
+            None => return,
+        };
+        let connected = self
+            .clients
+            .read()
+            .await
+            .keys()
+            .filter(|id| !is_internal_clientid(id))
+            .count();
+        let excess = (connected + self.sessions.len()).saturating_sub(max_sessions);
+        if excess == 0 {
+            return;
+        }
+        for (clientid, will) in self.sessions.evict(excess) {
+            info!(clientid = &*clientid, "Session evicted, too many sessions");
+            self.metrics.inc_sessions_evicted();
+            if let Some(will) = will {
+                self.publish_will(will).await;
+            }
+            self.topics.unsubscribe_all(clientid).await;
+        }
+    }
+    async fn process_retiring_worker(&mut self, maybe_exit: Option<Result<WorkerExit, JoinError>>) {
+        match maybe_exit {
             Some(Err(e)) => error!(
                 "Failed joining one of the threads, possible orphan threads running, {:?}",
                 e
             ),
-            Some(Ok(id)) => {
-                self.clients.write().await.remove(&id);
+            Some(Ok(WorkerExit::Retired(client, reason))) => {
+                self.cleanup_client(&client, reason).await
+            }
+            Some(Ok(WorkerExit::Panicked(client, panic))) => {
+                let msg = panic_message(&*panic);
+                error!(
+                    clientid = &*client.clientid,
+                    "Client worker panicked, {}", msg
+                );
+                self.metrics.inc_worker_panics();
+                let reason = DisconnectReason::Panic(msg.to_owned());
+                self.cleanup_client(&client, reason).await;
             }
             None => (),
         };
     }
     #[instrument(name = "ClientManager::process_forever", skip_all)]
     async fn process_forever(&mut self) {
+        // the listeners stop first when the connections are drained, the connected clients
+        // are still cleaned up as they go
+        let mut accepting = true;
         loop {
-            // the reason we have this before the tokio select is because when workers set is empty
-            // we will be burning through CPU cycles because we have nothing to await for,
-            // so it is better to at least make sure that there is 1 element in the queue before we do anything
-            if self.workers.is_empty() {
-                let w = self.rx.recv().await;
-                if !self.process_new_worker(w).await {
-                    break;
-                }
-            }
+            // an empty workers set resolves immediately, polling it would be burning through
+            // CPU cycles because we have nothing to await for
+            let has_workers = !self.workers.is_empty();
+            let next_expiry = self.sessions.next_expiry();
             tokio::select! {
-                w = self.rx.recv() => if !self.process_new_worker(w).await {
-                    break;
+                w = self.rx.recv(), if accepting => if !self.process_new_worker(w).await {
+                    accepting = false;
+                },
+                clientid = self.workers.next(), if has_workers => self.process_retiring_worker(clientid).await,
+                Some(client) = self.purge_rx.recv() => self.purge_client(client).await,
+                _ = sleep_until(next_expiry.unwrap_or_else(Instant::now)), if next_expiry.is_some() => {
+                    self.expire_sessions().await
+                }
+                // acknowledgements of QoS 1 wills, or why one was refused
+                Some(packet) = self.wills.recv() => if let Packet::Disconnect(d) = &*packet {
+                    warn!(
+                        reason = d.reason_code().as_str(),
+                        "Will message rejected by the dispatcher"
+                    );
                 },
-                clientid = self.workers.next() => self.process_retiring_worker(clientid).await,
             };
         }
     }
     async fn run(mut self) {
         let shutdown = self.shutdown.clone();
         tokio::select! {
-            _ = shutdown.notified() => (),
+            _ = shutdown.wait() => (),
             _ = self.process_forever() => ()
         };
         while self.workers.next().await.is_some() {
             // wait for all workers to yeield
         }
+        self.wills.unregister().await;
     }
     async fn start_processing(self) -> JoinHandle<()> {
-        info!("Starting clients manager");
+        info!(
+            node_id = self.cfg.node_id.as_deref().unwrap_or_default(),
+            "Starting clients manager"
+        );
         tokio::spawn(async move { self.run().await })
     }
+    #[allow(clippy::too_many_arguments)]
     async fn incomming_mqtt_listener(
         saddr: &SocketAddr,
         tx: UnboundedSender<ClientWorker>,
-        shutdown: Arc<Notify>,
+        shutdown: Shutdown,
         cfg: Arc<MqttServerConfig>,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
         incoming: Sender<PacketInfo>,
-    ) -> Result<JoinHandle<()>, ServerError> {
+        pacer: Arc<ConnectPacer>,
+        sessions: Arc<SessionStore>,
+        throttle: Arc<LogThrottle>,
+        tracer: Arc<PublishTracer>,
+        settings: Arc<Listener>,
+    ) -> Result<(JoinHandle<()>, SocketAddr), ServerError> {
         let listener = TcpListener::bind(saddr).await?;
+        let local = listener.local_addr()?;
         info!(
-            SocketAddr = &*format!("{}", saddr),
+            SocketAddr = &*format!("{}", local),
+            listener = &*settings.tag,
             "Starting listener for incoming unencrypted connections"
         );
 
-        Ok(tokio::spawn(async move {
-            MqttListener::new(listener, tx, shutdown, cfg, incoming)
-                .run()
-                .await
-        }))
+        let handle = tokio::spawn(async move {
+            MqttListener::new(
+                listener, tx, shutdown, cfg, clients, incoming, pacer, sessions, throttle, tracer,
+                settings,
+            )
+            .run()
+            .await
+        });
+        Ok((handle, local))
     }
 
     #[cfg(feature = "noise")]
+    #[allow(clippy::too_many_arguments)]
     async fn incomming_noise_listener(
         saddr: &SocketAddr,
+        key: NoiseKey,
         tx: UnboundedSender<ClientWorker>,
-        shutdown: Arc<Notify>,
+        shutdown: Shutdown,
         cfg: Arc<MqttServerConfig>,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
         incoming: Sender<PacketInfo>,
-    ) -> Result<JoinHandle<()>, ServerError> {
+        pacer: Arc<ConnectPacer>,
+        sessions: Arc<SessionStore>,
+        throttle: Arc<LogThrottle>,
+        tracer: Arc<PublishTracer>,
+        settings: Arc<Listener>,
+    ) -> Result<(JoinHandle<()>, SocketAddr), ServerError> {
         let listener = TcpListener::bind(saddr).await?;
+        let local = listener.local_addr()?;
         info!(
-            SocketAddr = &*format!("{}", saddr),
+            SocketAddr = &*format!("{}", local),
             "Starting listener for incoming encrypted connections"
         );
 
-        Ok(tokio::spawn(async move {
-            NoiseListener::new(listener, tx, shutdown, cfg, incoming)
-                .run()
+        let handle = tokio::spawn(async move {
+            NoiseListener::new(
+                listener, key, tx, shutdown, cfg, clients, incoming, pacer, sessions, throttle,
+                tracer, settings,
+            )
+            .run()
+            .await
+        });
+        Ok((handle, local))
+    }
+
+    #[cfg(feature = "websocket")]
+    #[allow(clippy::too_many_arguments)]
+    async fn incomming_multiplex_listener(
+        saddr: &SocketAddr,
+        tx: UnboundedSender<ClientWorker>,
+        shutdown: Shutdown,
+        cfg: Arc<MqttServerConfig>,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+        incoming: Sender<PacketInfo>,
+        pacer: Arc<ConnectPacer>,
+        sessions: Arc<SessionStore>,
+        throttle: Arc<LogThrottle>,
+        tracer: Arc<PublishTracer>,
+        settings: Arc<Listener>,
+    ) -> Result<(JoinHandle<()>, SocketAddr), ServerError> {
+        let listener = TcpListener::bind(saddr).await?;
+        let local = listener.local_addr()?;
+        info!(
+            SocketAddr = &*format!("{}", local),
+            "Starting listener for incoming MQTT and WebSocket connections"
+        );
+
+        let handle = tokio::spawn(async move {
+            MultiplexListener::new(
+                listener, tx, shutdown, cfg, clients, incoming, pacer, sessions, throttle, tracer,
+                settings,
+            )
+            .run()
+            .await
+        });
+        Ok((handle, local))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::OverflowPolicy;
+    use crate::sys::SysTopics;
+    use crate::topics::SubscriptionFlags;
+    use apiformes_packet::prelude::QoS;
+    use tokio::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn test_worker_panic_cleanup() {
+        let metrics = Arc::new(Metrics::new());
+        let sys = Arc::new(SysTopics::new("node"));
+        let topics = Arc::new(TopicsTable::new(metrics.clone(), sys, None));
+        let clients = Arc::new(RwLock::new(HashMap::new()));
+        let history = Arc::new(Mutex::new(DisconnectHistory::new(16)));
+        let shutdown = Shutdown::new();
+        let wills = InternalClient::register("wills", clients.clone(), shutdown.clone())
+            .await
+            .unwrap();
+        let (_, rx) = unbounded_channel();
+        let (incoming, _) = channel(16);
+        let mut manager = ClientManager::new(
+            Arc::new(MqttServerConfig::default()),
+            clients.clone(),
+            topics.clone(),
+            metrics.clone(),
+            history.clone(),
+            shutdown.clone(),
+            rx,
+            Arc::new(SessionStore::new()),
+            wills,
+            incoming,
+        );
+        for clientid in ["doomed", "bystander"] {
+            let (outgoing, _) = outgoing_queue(None, OverflowPolicy::Disconnect);
+            let mut client = Client::new(shutdown.clone(), outgoing, false, u32::MAX);
+            client.clientid = Arc::from(clientid);
+            let flags = SubscriptionFlags::empty();
+            topics
+                .subscribe(
+                    client.clientid.clone(),
+                    Arc::from("news/#"),
+                    QoS::QoS0,
+                    flags,
+                )
+                .await;
+            clients
+                .write()
                 .await
-        }))
+                .insert(client.clientid.clone(), client);
+        }
+
+        let doomed = clients.read().await["doomed"].clone();
+        let run = async { panic!("worker bug") };
+        manager
+            .workers
+            .push(spawn_worker(doomed, run, Span::none()));
+        let exit = manager.workers.next().await;
+        manager.process_retiring_worker(exit).await;
+        assert_eq!(metrics.worker_panics(), 1);
+        assert!(matches!(
+            &history.lock().unwrap().get("doomed").unwrap().reason,
+            DisconnectReason::Panic(msg) if msg == "worker bug"
+        ));
+        let mut connected: Vec<_> = clients.read().await.keys().cloned().collect();
+        connected.retain(|clientid| !is_internal_clientid(clientid));
+        assert_eq!(connected, [Arc::from("bystander")]);
+        let subscribers: Vec<_> = topics
+            .matching_subscriptions("news/a")
+            .await
+            .into_iter()
+            .map(|(clientid, _)| clientid)
+            .collect();
+        assert_eq!(subscribers, [Arc::from("bystander")]);
     }
 }
@@ -1,10 +1,25 @@
-use super::clientworker::{ClientWorker, Connection};
-use crate::{config::MqttServerConfig, error::ServerError, packetinfo::PacketInfo};
+use super::{
+    clientworker::{connection_span, ClientWorker, Connection},
+    history::DisconnectReason,
+    listener::Listener,
+    pacing::ConnectPacer,
+    session::SessionStore,
+    Client,
+};
+use crate::{
+    config::{MqttServerConfig, ReadTuning},
+    error::ServerError,
+    packetinfo::PacketInfo,
+    shutdown::Shutdown,
+    throttle::LogThrottle,
+    trace::PublishTracer,
+};
 use apiformes_packet::prelude::*;
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::HashMap;
 use std::io::Cursor;
 use std::{fmt, net::SocketAddr, sync::Arc};
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, Take},
     net::{
@@ -13,10 +28,13 @@ use tokio::{
     },
     sync::{
         mpsc::{Sender, UnboundedSender},
-        Notify,
+        RwLock,
     },
 };
-use tracing::{error, info, instrument, warn};
+use tracing::{error, info, instrument, warn, Instrument};
+
+/// Longest fixed header, the packet type and a 4 bytes remaining length (2.1.1)
+const MAX_HEADER_SIZE: usize = 5;
 
 pub struct MqttClient {
     tcp_reader: Take<OwnedReadHalf>,
@@ -24,6 +42,11 @@ pub struct MqttClient {
     bytes: BytesMut,
     saddr: SocketAddr,
     max_packet_size: u32,
+    strict_parsing: bool,
+    read: ReadTuning,
+    // when the last packet returned by `recv` was completely read
+    received_at: Instant,
+    version: ProtocolVersion,
 }
 
 impl fmt::Debug for MqttClient {
@@ -33,80 +56,168 @@ impl fmt::Debug for MqttClient {
 }
 
 impl MqttClient {
-    pub fn new(stream: TcpStream, saddr: SocketAddr, max_packet_size: u32) -> Self {
+    pub fn new(
+        stream: TcpStream,
+        saddr: SocketAddr,
+        max_packet_size: u32,
+        strict_parsing: bool,
+        read: ReadTuning,
+    ) -> Self {
         let (tcp_reader, tcp_writer) = stream.into_split();
 
         MqttClient {
             tcp_reader: tcp_reader.take(max_packet_size as u64),
             tcp_writer,
             saddr,
-            bytes: BytesMut::with_capacity(max_packet_size as usize),
+            bytes: BytesMut::new(),
             max_packet_size,
+            strict_parsing,
+            read,
+            received_at: Instant::now(),
+            version: ProtocolVersion::default(),
+        }
+    }
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.saddr
+    }
+    /// Decodes the next packet if it was completely read already
+    fn decode_buffered(&mut self) -> Result<Result<Packet, usize>, ServerError> {
+        // the body is only buffered once the fixed header announced an acceptable length
+        match Packet::peek_frame_len(&self.bytes)? {
+            Some(len) if len > self.max_packet_size as usize => {
+                Err(ServerError::MaxPacketSizeExceeded)
+            }
+            Some(len) if len <= self.bytes.len() => {
+                let packet =
+                    decode_frame(&self.bytes[..len], &mut self.version, self.strict_parsing)?;
+                self.bytes.advance(len);
+                Ok(Ok(packet))
+            }
+            Some(len) => Ok(Err(len - self.bytes.len())),
+            None => Ok(Err(MAX_HEADER_SIZE - self.bytes.len())),
         }
     }
     pub async fn recv(&mut self) -> Result<Packet, ServerError> {
         loop {
-            let mut cursor = Cursor::new(&self.bytes[..]);
-            match Packet::from_bytes(&mut cursor) {
+            let missing = match self.decode_buffered()? {
                 Ok(packet) => {
-                    self.bytes.advance(packet.frame_len());
+                    self.received_at = Instant::now();
                     return Ok(packet);
                 }
-                Err(DataParseError::InsufficientBuffer {
-                    needed: _,
-                    available: _,
-                }) => {
-                    if self.bytes.remaining() == self.max_packet_size as usize {
-                        return Err(ServerError::MaxPacketSizeExceeded);
-                    }
-                    self.tcp_reader
-                        .set_limit(self.max_packet_size as u64 - self.bytes.remaining() as u64);
-                    self.tcp_reader.read_buf(&mut self.bytes).await?;
-                }
-                Err(e) => return Err(e.into()),
+                Err(missing) => missing,
+            };
+            // reading ahead is bounded by the buffer size, a packet longer than the buffer
+            // is still read whole
+            let limit = missing.max(self.read.buffer_size.saturating_sub(self.bytes.len()));
+            self.bytes.reserve(limit);
+            self.tcp_reader.set_limit(limit as u64);
+            if self.tcp_reader.read_buf(&mut self.bytes).await? == 0 {
+                return Err(ServerError::ConnectionClosed);
             }
         }
     }
-    pub async fn send(&mut self, p: &Packet) -> Result<(), ServerError> {
-        let mut bytes = BytesMut::with_capacity(p.frame_len());
-        p.to_bytes(&mut bytes);
-        self.tcp_writer.write_all_buf(&mut bytes).await?;
+    /// The next packet when frames are coalesced and it was read along with the previous
+    /// ones, never waits for the socket
+    pub fn recv_buffered(&mut self) -> Result<Option<Packet>, ServerError> {
+        if !self.read.coalesce_frames {
+            return Ok(None);
+        }
+        Ok(self.decode_buffered()?.ok())
+    }
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+    pub async fn write(&mut self, mut frame: Bytes) -> Result<(), ServerError> {
+        self.tcp_writer.write_all_buf(&mut frame).await?;
         Ok(())
     }
 }
 
+/// Decodes the packet filling `frame` for a connection speaking `version`, the version is
+/// the one announced by the last CONNECT. `strict` is `MqttServerConfig::strict_parsing`.
+pub(super) fn decode_frame(
+    frame: &[u8],
+    version: &mut ProtocolVersion,
+    strict: bool,
+) -> Result<Packet, DataParseError> {
+    if let Some(announced) = ProtocolVersion::of_connect(frame)? {
+        *version = announced;
+    }
+    let mut frame = Cursor::new(frame);
+    if strict {
+        Packet::from_bytes_strict_versioned(&mut frame, *version)
+    } else {
+        Packet::from_bytes_versioned(&mut frame, *version)
+    }
+}
+
 pub struct MqttListener {
     mqtt_listener: TcpListener,
     queue: UnboundedSender<ClientWorker>,
-    shutdown: Arc<Notify>,
+    shutdown: Shutdown,
     cfg: Arc<MqttServerConfig>,
+    clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
     incoming: Sender<PacketInfo>,
+    pacer: Arc<ConnectPacer>,
+    sessions: Arc<SessionStore>,
+    throttle: Arc<LogThrottle>,
+    tracer: Arc<PublishTracer>,
+    listener: Arc<Listener>,
 }
 
 impl MqttListener {
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         listener: TcpListener,
         queue: UnboundedSender<ClientWorker>,
-        shutdown: Arc<Notify>,
+        shutdown: Shutdown,
         cfg: Arc<MqttServerConfig>,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
         incoming: Sender<PacketInfo>,
+        pacer: Arc<ConnectPacer>,
+        sessions: Arc<SessionStore>,
+        throttle: Arc<LogThrottle>,
+        tracer: Arc<PublishTracer>,
+        settings: Arc<Listener>,
     ) -> MqttListener {
         MqttListener {
             mqtt_listener: listener,
             queue,
             shutdown,
             cfg,
+            clients,
             incoming,
+            pacer,
+            sessions,
+            throttle,
+            tracer,
+            listener: settings,
         }
     }
     async fn listen(&mut self) -> Result<(), ServerError> {
         let (stream, saddr) = self.mqtt_listener.accept().await?;
-        let connection = Connection::Mqtt(MqttClient::new(stream, saddr, self.cfg.max_packet_size));
+        let connection = Connection::Mqtt(MqttClient::new(
+            stream,
+            saddr,
+            self.listener.max_packet_size,
+            self.listener.strict_parsing,
+            self.listener.read,
+        ));
         let client = ClientWorker::new(
             connection,
+            connection_span(&self.listener.tag, saddr),
             self.cfg.clone(),
+            self.clients.clone(),
             self.shutdown.clone(),
             self.incoming.clone(),
+            self.pacer.clone(),
+            self.sessions.clone(),
+            self.throttle.clone(),
+            self.tracer.clone(),
+            self.listener.clone(),
         );
         connect_client(client, saddr, self.queue.clone(), self.shutdown.clone());
         Ok(())
@@ -122,21 +233,23 @@ impl MqttListener {
     #[instrument(name = "MqttListener::run", skip_all)]
     pub async fn run(mut self) {
         let shutdown = self.shutdown.clone();
+        // no new connection once the connected clients are being drained
         tokio::select! {
-            _ = shutdown.notified() => (),
+            _ = shutdown.draining() => (),
             _ = self.listen_forever() => ()
         };
         info!("shutting down");
     }
 }
 
-fn connect_client(
+pub(super) fn connect_client(
     client: ClientWorker,
     saddr: SocketAddr,
     queue: UnboundedSender<ClientWorker>,
-    shutdown: Arc<Notify>,
+    shutdown: Shutdown,
 ) {
-    tokio::spawn(async move { _connect_client(client, saddr, queue, shutdown).await });
+    let span = client.span().clone();
+    tokio::spawn(_connect_client(client, saddr, queue, shutdown).instrument(span));
 }
 
 enum ConnectState {
@@ -158,24 +271,30 @@ async fn _connect_client(
     mut client: ClientWorker,
     saddr: SocketAddr,
     queue: UnboundedSender<ClientWorker>,
-    shutdown: Arc<Notify>,
+    shutdown: Shutdown,
 ) {
-    let keep_alive = client.cfg().keep_alive as u64;
+    let handshake_timeout = Duration::from_secs(client.cfg().handshake_timeout as u64);
 
     let state = tokio::select! {
-        _ = shutdown.notified() => ConnectState::ShuttingDown,
+        _ = shutdown.wait() => ConnectState::ShuttingDown,
         v = client.connect() => v.into(),
-        _ = sleep(Duration::new(keep_alive, 0)) => ConnectState::Err(ServerError::Misc("TimeOut".to_string())),
+        _ = sleep(handshake_timeout) => ConnectState::Err(ServerError::Misc("TimeOut".to_string())),
     };
     let saddr = format!("{}", saddr);
     match state {
         ConnectState::Success => info!(SocketAddr = &*saddr, "MQTT Connection established"),
-        ConnectState::ShuttingDown => info!(SocketAddr = &*saddr, "Shutting down"),
+        ConnectState::ShuttingDown => {
+            // the manager is going away, the worker would never be started
+            info!(SocketAddr = &*saddr, "Shutting down");
+            client.close_span(&DisconnectReason::ServerShutdown);
+            return;
+        }
         ConnectState::Err(e) => {
             warn!(
                 SocketAddr = &*saddr,
                 " Failed to establish MQTT connection, {:?}", e
             );
+            client.close_span(&DisconnectReason::from(&e));
             return;
         }
     }
@@ -1,28 +1,45 @@
-use super::clientworker::{ClientWorker, Connection};
+use super::{
+    clientworker::{connection_span, record_disconnect, ClientWorker, Connection},
+    history::DisconnectReason,
+    listener::Listener,
+    mqttclient::decode_frame,
+    pacing::ConnectPacer,
+    session::SessionStore,
+    Client,
+};
 use crate::{
-    cfg::NOISE_PATTERN, config::MqttServerConfig, error::ServerError, packetinfo::PacketInfo,
+    config::{MqttServerConfig, ReadTuning},
+    error::ServerError,
+    packetinfo::PacketInfo,
+    shutdown::Shutdown,
+    throttle::LogThrottle,
+    trace::PublishTracer,
 };
 use apiformes_packet::prelude::*;
+use arc_swap::ArcSwap;
 use bytes::{Buf, Bytes, BytesMut};
 use snow::{HandshakeState, TransportState};
-use std::{fmt, net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, fmt, net::SocketAddr, sync::Arc};
 use tokio::time::{sleep, Duration};
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::{
         mpsc::{Sender, UnboundedSender},
-        Notify,
+        RwLock,
     },
 };
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
-use tracing::{error, info, instrument, warn};
+use tracing::{error, info, instrument, warn, Instrument, Span};
 
-use futures::{SinkExt, StreamExt};
+use futures::{FutureExt, SinkExt, StreamExt};
 use tracing::trace;
 pub struct NoiseClient {
     stream: Framed<TcpStream, LengthDelimitedCodec>,
     saddr: SocketAddr,
     crypto: TransportState,
+    strict_parsing: bool,
+    read: ReadTuning,
+    version: ProtocolVersion,
 }
 
 impl fmt::Debug for NoiseClient {
@@ -36,26 +53,56 @@ impl NoiseClient {
         stream: Framed<TcpStream, LengthDelimitedCodec>,
         saddr: SocketAddr,
         crypto: TransportState,
+        strict_parsing: bool,
+        read: ReadTuning,
     ) -> Self {
         NoiseClient {
             stream,
             saddr,
             crypto,
+            strict_parsing,
+            read,
+            version: ProtocolVersion::default(),
         }
     }
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.saddr
+    }
+    pub fn remote_public_key(&self) -> Option<&[u8]> {
+        self.crypto.get_remote_static()
+    }
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
     pub async fn recv(&mut self) -> Result<Packet, ServerError> {
         //let frame = self.stream.send();
         let frame = self
             .stream
             .next()
             .await
-            .ok_or_else(|| ServerError::Misc("Client disconnected".to_owned()))??;
+            .ok_or(ServerError::ConnectionClosed)??;
+        self.decode(frame)
+    }
+    /// The next packet when frames are coalesced and its frame was read along with the
+    /// previous ones, never waits for the socket
+    pub fn recv_buffered(&mut self) -> Result<Option<Packet>, ServerError> {
+        if !self.read.coalesce_frames {
+            return Ok(None);
+        }
+        // polled once, the socket is only read if it is ready and no frame is buffered
+        match self.stream.next().now_or_never() {
+            Some(frame) => self
+                .decode(frame.ok_or(ServerError::ConnectionClosed)??)
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+    fn decode(&mut self, frame: BytesMut) -> Result<Packet, ServerError> {
         //TODO when you implement noise protocol by hand... make sure to make this happen in place
         let mut message = vec![0; frame.remaining()];
         self.crypto.read_message(&frame[..], &mut message)?;
 
-        let mut buf: &[u8] = message.as_ref();
-        match Packet::from_bytes(&mut buf) {
+        match decode_frame(&message, &mut self.version, self.strict_parsing) {
             Ok(packet) => Ok(packet),
             Err(DataParseError::InsufficientBuffer {
                 needed: _,
@@ -64,40 +111,64 @@ impl NoiseClient {
             Err(e) => Err(e.into()),
         }
     }
-    pub async fn send(&mut self, p: &Packet) -> Result<(), ServerError> {
-        let mut bytes = BytesMut::with_capacity(p.frame_len());
-        p.to_bytes(&mut bytes);
-        let mut frame = vec![0; bytes.remaining() + 100];
-        let size = self.crypto.write_message(&bytes[..], &mut frame)?;
+    pub async fn write(&mut self, frame: Bytes) -> Result<(), ServerError> {
+        let mut message = vec![0; frame.len() + 100];
+        let size = self.crypto.write_message(&frame[..], &mut message)?;
         self.stream
-            .send(Bytes::copy_from_slice(&frame[..size]))
+            .send(Bytes::copy_from_slice(&message[..size]))
             .await?;
         Ok(())
     }
 }
 
+/// Static private key of the broker, the handshakes started after a rotation use the new
+/// key while the established sessions keep theirs
+pub(crate) type NoiseKey = Arc<ArcSwap<[u8; 32]>>;
+
 pub struct NoiseListener {
     listener: TcpListener,
+    key: NoiseKey,
     queue: UnboundedSender<ClientWorker>,
-    shutdown: Arc<Notify>,
+    shutdown: Shutdown,
     cfg: Arc<MqttServerConfig>,
+    clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
     incoming: Sender<PacketInfo>,
+    pacer: Arc<ConnectPacer>,
+    sessions: Arc<SessionStore>,
+    throttle: Arc<LogThrottle>,
+    tracer: Arc<PublishTracer>,
+    settings: Arc<Listener>,
 }
 
 impl NoiseListener {
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         listener: TcpListener,
+        key: NoiseKey,
         queue: UnboundedSender<ClientWorker>,
-        shutdown: Arc<Notify>,
+        shutdown: Shutdown,
         cfg: Arc<MqttServerConfig>,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
         incoming: Sender<PacketInfo>,
+        pacer: Arc<ConnectPacer>,
+        sessions: Arc<SessionStore>,
+        throttle: Arc<LogThrottle>,
+        tracer: Arc<PublishTracer>,
+        settings: Arc<Listener>,
     ) -> NoiseListener {
         NoiseListener {
             listener,
+            key,
             queue,
             shutdown,
             cfg,
+            clients,
             incoming,
+            pacer,
+            sessions,
+            throttle,
+            tracer,
+            settings,
         }
     }
     async fn listen(&mut self) -> Result<(), ServerError> {
@@ -105,10 +176,17 @@ impl NoiseListener {
         connect_client(
             stream,
             saddr,
+            self.key.load_full(),
             self.queue.clone(),
             self.shutdown.clone(),
             self.cfg.clone(),
+            self.clients.clone(),
             self.incoming.clone(),
+            self.pacer.clone(),
+            self.sessions.clone(),
+            self.throttle.clone(),
+            self.tracer.clone(),
+            self.settings.clone(),
         );
         Ok(())
     }
@@ -123,24 +201,38 @@ impl NoiseListener {
     #[instrument(name = "NoiseListener::run", skip_all)]
     pub async fn run(mut self) {
         let shutdown = self.shutdown.clone();
+        // no new connection once the connected clients are being drained
         tokio::select! {
-            _ = shutdown.notified() => (),
+            _ = shutdown.draining() => (),
             _ = self.listen_forever() => ()
         };
         info!("shutting down");
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn connect_client(
     stream: TcpStream,
     saddr: SocketAddr,
+    key: Arc<[u8; 32]>,
     queue: UnboundedSender<ClientWorker>,
-    shutdown: Arc<Notify>,
+    shutdown: Shutdown,
     cfg: Arc<MqttServerConfig>,
+    clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
     incoming: Sender<PacketInfo>,
+    pacer: Arc<ConnectPacer>,
+    sessions: Arc<SessionStore>,
+    throttle: Arc<LogThrottle>,
+    tracer: Arc<PublishTracer>,
+    settings: Arc<Listener>,
 ) {
+    let span = connection_span(&settings.tag, saddr);
     tokio::spawn(
-        async move { _connect_client(stream, saddr, queue, shutdown, cfg, incoming).await },
+        _connect_client(
+            stream, saddr, key, queue, shutdown, cfg, clients, incoming, pacer, sessions, throttle,
+            tracer, settings,
+        )
+        .instrument(span),
     );
 }
 
@@ -159,61 +251,100 @@ impl From<Result<(), ServerError>> for ConnectState {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn _connect_client(
     stream: TcpStream,
     saddr: SocketAddr,
+    key: Arc<[u8; 32]>,
     queue: UnboundedSender<ClientWorker>,
-    shutdown: Arc<Notify>,
+    shutdown: Shutdown,
     cfg: Arc<MqttServerConfig>,
+    clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
     incoming: Sender<PacketInfo>,
+    pacer: Arc<ConnectPacer>,
+    sessions: Arc<SessionStore>,
+    throttle: Arc<LogThrottle>,
+    tracer: Arc<PublishTracer>,
+    settings: Arc<Listener>,
 ) {
-    let keep_alive = cfg.keep_alive as u64;
-    let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
+    let handshake_timeout = Duration::from_secs(cfg.handshake_timeout as u64);
+    // frames are read whole whatever the buffer size, it bounds what is read ahead
+    let mut stream = match settings.read.buffer_size {
+        0 => Framed::new(stream, LengthDelimitedCodec::new()),
+        size => Framed::with_capacity(stream, LengthDelimitedCodec::new(), size),
+    };
 
-    let mut responder = snow::Builder::new(NOISE_PATTERN.parse().unwrap())
-        .local_private_key(&cfg.private_key[..])
+    // the pattern and the key were checked by MqttServerConfig::validate and rotate_noise_key
+    let mut responder = snow::Builder::new(cfg.noise_pattern.parse().unwrap())
+        .local_private_key(&key[..])
         .build_responder()
         .unwrap();
 
     let state = tokio::select! {
-        _ = shutdown.notified() => ConnectState::ShuttingDown,
-        v = handshake(&mut stream, &mut responder) => v.into(),
-        _ = sleep(Duration::new(keep_alive * 3, 0)) => ConnectState::Err(ServerError::Misc("TimeOut".to_string())),
+        _ = shutdown.wait() => ConnectState::ShuttingDown,
+        v = handshake(&mut stream, &mut responder, cfg.noise_accepted_keys.as_deref()) => v.into(),
+        _ = sleep(handshake_timeout) => ConnectState::Err(ServerError::Misc("TimeOut".to_string())),
     };
     let saddr_str = format!("{}", saddr);
     match state {
         ConnectState::Success => info!(SocketAddr = &*saddr_str, "MQTT Connection established"),
-        ConnectState::ShuttingDown => info!(SocketAddr = &*saddr_str, "Shutting down"),
+        ConnectState::ShuttingDown => {
+            // the manager is going away, the worker would never be started
+            info!(SocketAddr = &*saddr_str, "Shutting down");
+            record_disconnect(&Span::current(), &DisconnectReason::ServerShutdown);
+            return;
+        }
         ConnectState::Err(e) => {
             warn!(
                 SocketAddr = &*saddr_str,
                 " Failed to establish Noise handshake, {:?}", e
             );
+            record_disconnect(&Span::current(), &DisconnectReason::from(&e));
             return;
         }
     }
     let transport = responder.into_transport_mode().unwrap();
 
-    let nc = NoiseClient::new(stream, saddr, transport);
+    let nc = NoiseClient::new(
+        stream,
+        saddr,
+        transport,
+        settings.strict_parsing,
+        settings.read,
+    );
     let mut client = ClientWorker::new(
         Connection::Noise(Box::new(nc)),
+        // this task runs in the span of the connection
+        Span::current(),
         cfg,
+        clients,
         shutdown.clone(),
         incoming,
+        pacer,
+        sessions,
+        throttle,
+        tracer,
+        settings,
     );
     let state = tokio::select! {
-        _ = shutdown.notified() => ConnectState::ShuttingDown,
+        _ = shutdown.wait() => ConnectState::ShuttingDown,
         v = client.connect() => v.into(),
-        _ = sleep(Duration::new(keep_alive, 0)) => ConnectState::Err(ServerError::Misc("TimeOut".to_string())),
+        _ = sleep(handshake_timeout) => ConnectState::Err(ServerError::Misc("TimeOut".to_string())),
     };
     match state {
         ConnectState::Success => info!(SocketAddr = &*saddr_str, "MQTT Connection established"),
-        ConnectState::ShuttingDown => info!(SocketAddr = &*saddr_str, "Shutting down"),
+        ConnectState::ShuttingDown => {
+            // the manager is going away, the worker would never be started
+            info!(SocketAddr = &*saddr_str, "Shutting down");
+            client.close_span(&DisconnectReason::ServerShutdown);
+            return;
+        }
         ConnectState::Err(e) => {
             warn!(
                 SocketAddr = &*saddr_str,
                 " Failed to establish MQTT connection, {:?}", e
             );
+            client.close_span(&DisconnectReason::from(&e));
             return;
         }
     }
@@ -222,36 +353,30 @@ async fn _connect_client(
     }
 }
 
+/// Runs the messages of the handshake pattern, then refuses clients whose static key is not
+/// one of `accepted` when set
 async fn handshake(
     stream: &mut Framed<TcpStream, LengthDelimitedCodec>,
     handshake: &mut HandshakeState,
+    accepted: Option<&[[u8; 32]]>,
 ) -> Result<(), ServerError> {
-    //  -> e, es
-    let frame = stream
-        .next()
-        .await
-        .ok_or_else(|| ServerError::Misc("Client disconnected".to_owned()))??;
-    trace!("-> e, es");
-    trace!("{:x?}", &frame[..]);
     let mut out_buf = [0; 200];
-    handshake.read_message(&frame[..], &mut [])?;
-
-    // <- e, ee
-    let size = handshake.write_message(&[], &mut out_buf)?;
-    trace!("<- e, ee");
-    trace!("{:x?}", &out_buf[..size]);
-    stream
-        .send(Bytes::copy_from_slice(&out_buf[..size]))
-        .await?;
-
-    // -> s, se
-    let frame = stream
-        .next()
-        .await
-        .ok_or_else(|| ServerError::Misc("Client disconnected".to_owned()))??;
-    trace!("-> s, se");
-    trace!("{:x?}", &frame[..]);
-    handshake.read_message(&frame[..], &mut [])?;
-
-    Ok(())
+    while !handshake.is_handshake_finished() {
+        if handshake.is_my_turn() {
+            let size = handshake.write_message(&[], &mut out_buf)?;
+            trace!("<- {:x?}", &out_buf[..size]);
+            stream
+                .send(Bytes::copy_from_slice(&out_buf[..size]))
+                .await?;
+        } else {
+            let frame = stream.next().await.ok_or(ServerError::ConnectionClosed)??;
+            trace!("-> {:x?}", &frame[..]);
+            handshake.read_message(&frame[..], &mut [])?;
+        }
+    }
+    match (accepted, handshake.get_remote_static()) {
+        (None, _) => Ok(()),
+        (Some(keys), Some(key)) if keys.iter().any(|k| k[..] == *key) => Ok(()),
+        _ => Err(ServerError::AuthenticationFailed),
+    }
 }
@@ -0,0 +1,145 @@
+use crate::config::MqttServerConfig;
+use apiformes_packet::prelude::*;
+use bytes::{Bytes, BytesMut};
+use std::sync::{Arc, OnceLock};
+
+/// Properties which can be left out without changing the meaning of a packet, in the
+/// order they are dropped to fit the client MaximumPacketSize
+const OPTIONAL_PROPERTIES: [Property; 2] = [Property::ReasonString, Property::UserProperty];
+
+fn props_len(packet: &Packet, key: Property) -> usize {
+    let values = packet.get_prop(key).unwrap_or_default();
+    values
+        .iter()
+        .map(|v| match (v.into_str(), v.into_str_pair()) {
+            (Some(s), _) => s.len(),
+            (_, Some((name, value))) => name.len() + value.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// `packet` as written to a connection speaking `version`
+pub(super) fn encode(packet: &Packet, version: ProtocolVersion) -> Bytes {
+    let mut bytes = BytesMut::with_capacity(packet.frame_len_versioned(version));
+    packet.to_bytes_versioned(&mut bytes, version);
+    bytes.freeze()
+}
+
+/// The encodings of a publish queued unchanged for several clients. The first worker
+/// writing it for a protocol version encodes it, the others write the same bytes instead
+/// of serializing the publish once per subscriber.
+#[derive(Default, Debug)]
+pub(crate) struct SharedFrame {
+    v311: OnceLock<Bytes>,
+    v5: OnceLock<Bytes>,
+}
+
+impl SharedFrame {
+    /// `packet` as written to a connection speaking `version`, `packet` must be the one
+    /// this frame was queued along with
+    pub(crate) fn encoded(&self, packet: &Packet, version: ProtocolVersion) -> Bytes {
+        let frame = match version {
+            ProtocolVersion::V311 => &self.v311,
+            ProtocolVersion::V5 => &self.v5,
+        };
+        frame.get_or_init(|| encode(packet, version)).clone()
+    }
+}
+
+/// Makes `packet` fit the limits of a client before it is sent: ReasonStrings and
+/// UserProperties longer than configured are dropped, then optional properties are
+/// removed until the packet is within `max_packet_size` (3.1.2.24). Returns `None` for
+/// packets which are still too large, those must be discarded without being sent.
+pub(super) fn fit_packet(
+    packet: Arc<Packet>,
+    max_packet_size: u32,
+    cfg: &MqttServerConfig,
+) -> Option<Arc<Packet>> {
+    let max_packet_size = max_packet_size as usize;
+    if let Packet::Publish(_) = &*packet {
+        // 3.3.2.3.7: the user properties of an application message are forwarded unaltered
+        return (packet.frame_len() <= max_packet_size).then_some(packet);
+    }
+    let reason_string_too_long =
+        props_len(&packet, Property::ReasonString) > cfg.max_reason_string_len;
+    let user_properties_too_long =
+        props_len(&packet, Property::UserProperty) > cfg.max_user_properties_len;
+    if !reason_string_too_long && !user_properties_too_long && packet.frame_len() <= max_packet_size
+    {
+        return Some(packet);
+    }
+    let mut packet = Arc::try_unwrap(packet).unwrap_or_else(|p| (*p).clone());
+    if reason_string_too_long {
+        packet.remove_prop(Property::ReasonString);
+    }
+    if user_properties_too_long {
+        packet.remove_prop(Property::UserProperty);
+    }
+    for key in OPTIONAL_PROPERTIES {
+        if packet.frame_len() <= max_packet_size {
+            break;
+        }
+        packet.remove_prop(key);
+    }
+    (packet.frame_len() <= max_packet_size).then(|| Arc::new(packet))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+    fn puback(reason: &str, user_properties: usize) -> Arc<Packet> {
+        let mut puback = PubAck::new(1);
+        puback.set_reason_code(PubAckReasonCode::UnspecifiedError);
+        puback
+            .add_prop(
+                Property::ReasonString,
+                MqttPropValue::new_string(Arc::from(reason)).unwrap(),
+            )
+            .unwrap();
+        for i in 0..user_properties {
+            let pair = MqttPropValue::new_string_pair(Arc::from("k"), Arc::from(i.to_string()));
+            puback
+                .add_prop(Property::UserProperty, pair.unwrap())
+                .unwrap();
+        }
+        Arc::new(puback.build())
+    }
+    #[test]
+    fn test_fit_packet() {
+        let cfg = MqttServerConfig {
+            max_reason_string_len: 16,
+            max_user_properties_len: 64,
+            ..Default::default()
+        };
+        // within every limit, the packet is sent as is
+        let packet = puback("failed", 2);
+        let fitted = fit_packet(packet.clone(), 1024, &cfg).unwrap();
+        assert!(Arc::ptr_eq(&packet, &fitted));
+
+        // over the configured limits
+        let fitted = fit_packet(puback(&"x".repeat(17), 64), 1024, &cfg).unwrap();
+        assert!(fitted.get_prop(Property::ReasonString).is_none());
+        assert!(fitted.get_prop(Property::UserProperty).is_none());
+
+        // the reason string goes first to fit the maximum packet size
+        let packet = puback("failed", 2);
+        let without_reason = packet.frame_len() - 1 - 2 - "failed".len();
+        let fitted = fit_packet(packet.clone(), without_reason as u32, &cfg).unwrap();
+        assert!(fitted.get_prop(Property::ReasonString).is_none());
+        assert_eq!(fitted.get_prop(Property::UserProperty).unwrap().len(), 2);
+        let fitted = fit_packet(packet, without_reason as u32 - 1, &cfg).unwrap();
+        assert!(fitted.get_prop(Property::UserProperty).is_none());
+        assert!(fit_packet(puback("failed", 2), 4, &cfg).is_none());
+
+        // application messages are never altered
+        let mut publish = Publish::new(Arc::from("a"), Bytes::from_static(b"payload")).unwrap();
+        let pair = MqttPropValue::new_string_pair(Arc::from("k"), Arc::from("v")).unwrap();
+        publish.add_prop(Property::UserProperty, pair).unwrap();
+        let publish = Arc::new(publish.build());
+        let len = publish.frame_len() as u32;
+        assert!(fit_packet(publish.clone(), len, &cfg).is_some());
+        assert!(fit_packet(publish, len - 1, &cfg).is_none());
+    }
+}
@@ -0,0 +1,258 @@
+use crate::{
+    config::{ConnectRate, IpConnectRate, MqttServerConfig},
+    metrics::Metrics,
+};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use tokio::time::{Duration, Instant};
+
+/// What to do with a connection waiting for its CONNACK
+pub(super) enum Admission {
+    /// Answer right away
+    Now,
+    /// Answer after the given delay, the token is already reserved
+    After(Duration),
+    /// Too many handshakes are already waiting, refuse with ServerBusy
+    Busy,
+    /// The broker is a standby, refuse with ServerUnavailable
+    Standby,
+}
+
+/// Whether a new CONNECT is within the connection limits
+pub(super) enum Limit {
+    Within,
+    /// Too many connections are open, refuse with ServerBusy
+    Full,
+    /// The IP address of the client connects too often, refuse with ConnectionRateExceeded
+    RateExceeded,
+}
+
+/// Number of IP addresses tracked before the ones whose bucket refilled are forgotten
+const IP_BUCKETS_PRUNE: usize = 4096;
+
+struct Bucket {
+    // may go negative, every missing token is a handshake waiting for its turn
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn full(burst: u32, now: Instant) -> Self {
+        Bucket {
+            tokens: burst as f64,
+            last: now,
+        }
+    }
+    fn refill(&mut self, per_second: f64, burst: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(burst as f64);
+        self.last = now;
+    }
+}
+
+/// Counts the open connections, held by a connection from its accept until it closes
+pub(super) struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Counts a connection in `connections` until the slot is dropped
+    pub(super) fn open(connections: &Arc<AtomicUsize>) -> Self {
+        connections.fetch_add(1, Ordering::AcqRel);
+        ConnectionSlot(connections.clone())
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Token bucket limiting how many handshakes complete per second, so clients reconnecting
+/// all at once after a restart are spread over time instead of overwhelming the broker
+pub(super) struct ConnectPacer {
+    rate: Option<ConnectRate>,
+    bucket: Mutex<Bucket>,
+    ip_rate: Option<IpConnectRate>,
+    ip_buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    max_connections: Option<usize>,
+    connections: Arc<AtomicUsize>,
+    metrics: Arc<Metrics>,
+    // cleared once the standby is promoted
+    standby: Arc<AtomicBool>,
+}
+
+/// Random delay of up to a quarter of `delay`, so paced clients do not all get their
+/// CONNACK at the same instant
+fn jitter(delay: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    delay / 4 * (random % 1000) as u32 / 1000
+}
+
+impl ConnectPacer {
+    pub(super) fn new(
+        cfg: &MqttServerConfig,
+        metrics: Arc<Metrics>,
+        standby: Arc<AtomicBool>,
+    ) -> Self {
+        let rate = cfg.connect_rate.clone();
+        let burst = rate.as_ref().map(|r| r.burst).unwrap_or_default();
+        ConnectPacer {
+            rate,
+            bucket: Mutex::new(Bucket::full(burst, Instant::now())),
+            ip_rate: cfg.connect_rate_per_ip.clone(),
+            ip_buckets: Mutex::new(HashMap::new()),
+            max_connections: cfg.max_connections,
+            connections: Arc::default(),
+            metrics,
+            standby,
+        }
+    }
+    /// Counts a connection as open until the returned slot is dropped
+    pub(super) fn open(&self) -> ConnectionSlot {
+        ConnectionSlot::open(&self.connections)
+    }
+    /// Takes a token from the bucket of `ip`, false when it is empty
+    fn take_ip_token(&self, rate: &IpConnectRate, ip: IpAddr, now: Instant) -> bool {
+        let per_second = rate.per_second as f64;
+        let mut buckets = self.ip_buckets.lock().unwrap();
+        if buckets.len() >= IP_BUCKETS_PRUNE && !buckets.contains_key(&ip) {
+            // a refilled bucket is the same as none
+            buckets.retain(|_, b| {
+                b.refill(per_second, rate.burst, now);
+                b.tokens < rate.burst as f64
+            });
+        }
+        let bucket = buckets
+            .entry(ip)
+            .or_insert_with(|| Bucket::full(rate.burst, now));
+        bucket.refill(per_second, rate.burst, now);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+    /// Checks the limits applying to a new CONNECT from `ip`, before any work is done for it
+    pub(super) fn limit(&self, ip: IpAddr) -> Limit {
+        if matches!(self.max_connections, Some(max) if self.connections.load(Ordering::Acquire) > max)
+        {
+            self.metrics.inc_connects_refused_busy();
+            return Limit::Full;
+        }
+        match &self.ip_rate {
+            Some(rate) if !self.take_ip_token(rate, ip, Instant::now()) => {
+                self.metrics.inc_connects_refused_rate();
+                Limit::RateExceeded
+            }
+            _ => Limit::Within,
+        }
+    }
+    fn reserve(&self, rate: &ConnectRate, now: Instant) -> Admission {
+        let per_second = rate.per_second.max(1) as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(per_second, rate.burst, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Admission::Now;
+        }
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / per_second);
+        if wait > Duration::from_millis(rate.max_wait_ms as u64) {
+            return Admission::Busy;
+        }
+        bucket.tokens -= 1.0;
+        Admission::After(wait)
+    }
+    /// Reserves a handshake slot for a new connection
+    pub(super) fn admit(&self) -> Admission {
+        if self.standby.load(Ordering::Acquire) {
+            return Admission::Standby;
+        }
+        let rate = match &self.rate {
+            Some(rate) => rate,
+            None => return Admission::Now,
+        };
+        match self.reserve(rate, Instant::now()) {
+            Admission::After(wait) => {
+                self.metrics.inc_connects_paced();
+                Admission::After(wait + jitter(wait))
+            }
+            Admission::Busy => {
+                self.metrics.inc_connects_refused_busy();
+                Admission::Busy
+            }
+            admission => admission,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_connect_pacer() {
+        let rate = ConnectRate {
+            per_second: 10,
+            burst: 2,
+            max_wait_ms: 250,
+        };
+        let cfg = MqttServerConfig {
+            connect_rate: Some(rate.clone()),
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::new());
+        let pacer = ConnectPacer::new(&cfg, metrics, Arc::default());
+        let now = Instant::now();
+        assert!(matches!(pacer.reserve(&rate, now), Admission::Now));
+        assert!(matches!(pacer.reserve(&rate, now), Admission::Now));
+        // the burst is spent, the next handshakes wait 100ms more each
+        assert!(
+            matches!(pacer.reserve(&rate, now), Admission::After(d) if d <= Duration::from_millis(101))
+        );
+        assert!(
+            matches!(pacer.reserve(&rate, now), Admission::After(d) if d <= Duration::from_millis(201))
+        );
+        assert!(matches!(pacer.reserve(&rate, now), Admission::Busy));
+        // a second later the bucket is full again
+        let later = now + Duration::from_secs(1);
+        assert!(matches!(pacer.reserve(&rate, later), Admission::Now));
+    }
+    #[test]
+    fn test_connection_limits() {
+        let cfg = MqttServerConfig {
+            connect_rate_per_ip: Some(IpConnectRate {
+                per_second: 1,
+                burst: 2,
+            }),
+            max_connections: Some(2),
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::new());
+        let pacer = ConnectPacer::new(&cfg, metrics.clone(), Arc::default());
+        let rate = cfg.connect_rate_per_ip.as_ref().unwrap();
+        let (flooder, other) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let now = Instant::now();
+        assert!(pacer.take_ip_token(rate, flooder, now));
+        assert!(pacer.take_ip_token(rate, flooder, now));
+        assert!(!pacer.take_ip_token(rate, flooder, now));
+        // other addresses have their own bucket
+        assert!(pacer.take_ip_token(rate, other, now));
+        assert!(pacer.take_ip_token(rate, flooder, now + Duration::from_secs(1)));
+
+        let first = pacer.open();
+        let _second = pacer.open();
+        assert!(matches!(pacer.limit(other), Limit::Within));
+        let third = pacer.open();
+        assert!(matches!(pacer.limit(other), Limit::Full));
+        drop(third);
+        drop(first);
+        assert!(matches!(pacer.limit(flooder), Limit::RateExceeded));
+        assert_eq!(metrics.connects_refused_rate(), 1);
+        assert_eq!(metrics.connects_refused_busy(), 1);
+    }
+}
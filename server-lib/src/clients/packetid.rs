@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+
+/// Packet identifiers of the QoS 1 and 2 publishes the broker sends to a client. 2.2.1: an
+/// identifier is not reused until the publish it was sent with is acknowledged.
+#[derive(Default)]
+pub(super) struct PacketIds {
+    // every identifier up to it was allocated at least once
+    highest: u16,
+    // identifiers released, the least recently released is reused first
+    free: VecDeque<u16>,
+}
+
+impl PacketIds {
+    /// Identifiers below `highest` are free unless `used` says otherwise, `used` is
+    /// indexed by identifier
+    pub(super) fn with_used(highest: u16, used: &[bool]) -> Self {
+        PacketIds {
+            highest,
+            free: (1..=highest).filter(|id| !used[*id as usize]).collect(),
+        }
+    }
+    /// The highest identifier allocated so far
+    pub(super) fn highest(&self) -> u16 {
+        self.highest
+    }
+    /// An identifier not in use, there is always one as ReceiveMaximum cannot exceed 65535
+    pub(super) fn allocate(&mut self) -> u16 {
+        if let Some(id) = self.free.pop_front() {
+            return id;
+        }
+        self.highest += 1;
+        self.highest
+    }
+    pub(super) fn release(&mut self, id: u16) {
+        self.free.push_back(id);
+    }
+}
+
+/// Packet identifiers of the QoS 1 and 2 publishes and the subscriptions received from a
+/// client and not acknowledged yet. 2.2.1: the client cannot use them again before then.
+pub(super) struct ReceivedIds {
+    // bit `id` is set while `id` is in use
+    bits: Box<[u64; 1024]>,
+    // bit `id` is set while `id` is in use by a publish
+    publishes: Box<[u64; 1024]>,
+}
+
+impl Default for ReceivedIds {
+    fn default() -> Self {
+        ReceivedIds {
+            bits: Box::new([0; 1024]),
+            publishes: Box::new([0; 1024]),
+        }
+    }
+}
+
+impl ReceivedIds {
+    /// Marks `id` as in use, false if it already was
+    pub(super) fn insert(&mut self, id: u16) -> bool {
+        let (word, bit) = (id as usize / 64, 1 << (id % 64));
+        let free = self.bits[word] & bit == 0;
+        self.bits[word] |= bit;
+        free
+    }
+    /// Same as `insert` for the identifier of a publish
+    pub(super) fn insert_publish(&mut self, id: u16) -> bool {
+        let free = self.insert(id);
+        if free {
+            self.publishes[id as usize / 64] |= 1 << (id % 64);
+        }
+        free
+    }
+    /// `id` is in use by a publish, a publish flagged DUP with it is a retransmission
+    pub(super) fn is_publish(&self, id: u16) -> bool {
+        self.publishes[id as usize / 64] & (1 << (id % 64)) != 0
+    }
+    /// The acknowledgement for `id` was sent, the client may reuse it
+    pub(super) fn remove(&mut self, id: u16) {
+        let (word, bit) = (id as usize / 64, 1 << (id % 64));
+        self.bits[word] &= !bit;
+        self.publishes[word] &= !bit;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_packet_ids() {
+        let mut used = vec![false; 4];
+        used[2] = true;
+        let mut ids = PacketIds::with_used(3, &used);
+        assert_eq!(ids.allocate(), 1);
+        assert_eq!(ids.allocate(), 3);
+        assert_eq!(ids.allocate(), 4);
+        ids.release(1);
+        assert_eq!(ids.allocate(), 1);
+        assert_eq!(ids.highest(), 4);
+
+        let mut received = ReceivedIds::default();
+        assert!(received.insert(7));
+        assert!(!received.insert(7));
+        assert!(received.insert(u16::MAX));
+        received.remove(7);
+        assert!(received.insert(7));
+        assert!(!received.is_publish(7));
+        // a publish cannot take an identifier held by a subscription
+        assert!(!received.insert_publish(7));
+        assert!(!received.is_publish(7));
+        received.remove(7);
+        assert!(received.insert_publish(7));
+        assert!(received.is_publish(7));
+        received.remove(7);
+        assert!(!received.is_publish(7));
+    }
+}
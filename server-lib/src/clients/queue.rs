@@ -0,0 +1,218 @@
+use super::outbound::SharedFrame;
+use crate::config::OverflowPolicy;
+use apiformes_packet::prelude::Packet;
+use futures::{future::poll_fn, task::AtomicWaker};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// Where a packet handed to `OutgoingSender::push` ended up
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Pushed {
+    Queued,
+    /// The queue was full, the policy discarded either this packet or the oldest one
+    Dropped,
+    /// The queue was full under `OverflowPolicy::Disconnect`, the packet was discarded
+    Overflowed,
+}
+
+/// A packet waiting for the worker of a client
+#[derive(Debug)]
+pub(crate) struct Outgoing {
+    pub(crate) packet: Arc<Packet>,
+    /// set when the packet is queued unchanged for other clients as well, see `SharedFrame`
+    pub(crate) frame: Option<Arc<SharedFrame>>,
+}
+
+impl From<Arc<Packet>> for Outgoing {
+    fn from(packet: Arc<Packet>) -> Self {
+        Outgoing {
+            packet,
+            frame: None,
+        }
+    }
+}
+
+struct Shared {
+    packets: Mutex<VecDeque<Outgoing>>,
+    // the receiver waiting for a packet
+    waker: AtomicWaker,
+    senders: AtomicUsize,
+    // the receiver is gone
+    closed: AtomicBool,
+    dropped: AtomicU64,
+}
+
+/// The packets waiting for the worker of a client, at most `capacity` of them. Unlike a
+/// channel the oldest packet can be discarded to make room for a new one.
+pub(crate) fn outgoing_queue(
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+) -> (OutgoingSender, OutgoingReceiver) {
+    let shared = Arc::new(Shared {
+        packets: Mutex::new(VecDeque::new()),
+        waker: AtomicWaker::new(),
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+        dropped: AtomicU64::new(0),
+    });
+    let sender = OutgoingSender {
+        shared: shared.clone(),
+        capacity,
+        policy,
+    };
+    (sender, OutgoingReceiver { shared })
+}
+
+pub(crate) struct OutgoingSender {
+    shared: Arc<Shared>,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+}
+
+impl Clone for OutgoingSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::AcqRel);
+        OutgoingSender {
+            shared: self.shared.clone(),
+            capacity: self.capacity,
+            policy: self.policy,
+        }
+    }
+}
+
+impl Drop for OutgoingSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.waker.wake();
+        }
+    }
+}
+
+impl OutgoingSender {
+    /// Queues `packet`, gives it back if the receiver is gone
+    pub(crate) fn push(&self, packet: impl Into<Outgoing>) -> Result<Pushed, Outgoing> {
+        let packet = packet.into();
+        if self.shared.closed.load(Ordering::Acquire) {
+            return Err(packet);
+        }
+        let mut packets = self.shared.packets.lock().unwrap();
+        let pushed = match self.capacity {
+            Some(capacity) if packets.len() >= capacity => match self.policy {
+                OverflowPolicy::DropOldest => {
+                    packets.pop_front();
+                    packets.push_back(packet);
+                    Pushed::Dropped
+                }
+                OverflowPolicy::DropNewest => Pushed::Dropped,
+                OverflowPolicy::Disconnect => Pushed::Overflowed,
+            },
+            _ => {
+                packets.push_back(packet);
+                Pushed::Queued
+            }
+        };
+        drop(packets);
+        match pushed {
+            Pushed::Queued => self.shared.waker.wake(),
+            _ => {
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(pushed)
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.shared.packets.lock().unwrap().len()
+    }
+    pub(crate) fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct OutgoingReceiver {
+    shared: Arc<Shared>,
+}
+
+impl OutgoingReceiver {
+    pub(crate) fn try_recv(&mut self) -> Option<Outgoing> {
+        self.shared.packets.lock().unwrap().pop_front()
+    }
+    /// Returns `None` once the queue is empty and every sender is gone
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Outgoing>> {
+        if let Some(packet) = self.try_recv() {
+            return Poll::Ready(Some(packet));
+        }
+        self.shared.waker.register(cx.waker());
+        // pushed or dropped before the waker was registered
+        if let Some(packet) = self.try_recv() {
+            return Poll::Ready(Some(packet));
+        }
+        if self.shared.senders.load(Ordering::Acquire) == 0 {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+    pub(crate) async fn recv(&mut self) -> Option<Outgoing> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+}
+
+impl Drop for OutgoingReceiver {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+        self.shared.packets.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use apiformes_packet::prelude::*;
+
+    fn ack(id: u16) -> Arc<Packet> {
+        Arc::new(PubAck::new(id).build())
+    }
+    fn drain(rx: &mut OutgoingReceiver) -> Vec<u16> {
+        std::iter::from_fn(|| rx.try_recv())
+            .map(|p| match &*p.packet {
+                Packet::PubAck(p) => p.identifier(),
+                _ => panic!("not a PUBACK"),
+            })
+            .collect()
+    }
+    #[tokio::test]
+    async fn test_outgoing_queue_policies() {
+        for (policy, pushed, kept) in [
+            (OverflowPolicy::DropOldest, Pushed::Dropped, [2, 3]),
+            (OverflowPolicy::DropNewest, Pushed::Dropped, [1, 2]),
+            (OverflowPolicy::Disconnect, Pushed::Overflowed, [1, 2]),
+        ] {
+            let (tx, mut rx) = outgoing_queue(Some(2), policy);
+            assert_eq!(tx.push(ack(1)).ok(), Some(Pushed::Queued));
+            assert_eq!(tx.push(ack(2)).ok(), Some(Pushed::Queued));
+            assert_eq!(tx.push(ack(3)).ok(), Some(pushed));
+            assert_eq!(tx.len(), 2);
+            assert_eq!(tx.dropped(), 1);
+            assert_eq!(drain(&mut rx), kept);
+        }
+
+        let (tx, mut rx) = outgoing_queue(None, OverflowPolicy::Disconnect);
+        let waiting = tokio::spawn(async move { rx.recv().await.is_some() });
+        tokio::task::yield_now().await;
+        tx.push(ack(1)).unwrap();
+        assert!(waiting.await.unwrap());
+
+        let (tx, mut rx) = outgoing_queue(None, OverflowPolicy::Disconnect);
+        let other = tx.clone();
+        drop(tx);
+        other.push(ack(1)).unwrap();
+        drop(other);
+        // the packets queued before the last sender went away are still received
+        assert!(rx.recv().await.is_some());
+        assert!(rx.recv().await.is_none());
+        let (tx, rx) = outgoing_queue(None, OverflowPolicy::Disconnect);
+        drop(rx);
+        assert!(tx.push(ack(1)).is_err());
+    }
+}
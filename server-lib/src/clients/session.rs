@@ -0,0 +1,348 @@
+use super::inflight::InFlight;
+use apiformes_packet::prelude::Publish;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// 3.1.2.11.2: a session expiry interval of 0xFFFFFFFF means the session does not expire
+pub(super) const NEVER_EXPIRES: u32 = u32::MAX;
+
+struct ParkedSession {
+    parked_at: Instant,
+    // `None` never expires
+    expires_at: Option<Instant>,
+    inflight: Arc<InFlight>,
+    // the Will Message not published yet, it is due at `will_at` or when the session
+    // expires, whichever comes first
+    will: Option<Publish>,
+    will_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Sessions {
+    parked: HashMap<Arc<str>, ParkedSession>,
+    // the first entry is the next session to expire
+    expiries: BTreeSet<(Instant, Arc<str>)>,
+    // the first entry is the next Will Message whose delay passes
+    wills: BTreeSet<(Instant, Arc<str>)>,
+    // the first entry is the session idle for the longest
+    idle: BTreeSet<(Instant, Arc<str>)>,
+    // client ids assigned by the broker, see `SessionStore::reserve`
+    reserved: HashSet<Arc<str>>,
+}
+
+impl Sessions {
+    fn remove(&mut self, clientid: &str) -> Option<ParkedSession> {
+        let (clientid, parked) = self.parked.remove_entry(clientid)?;
+        self.idle.remove(&(parked.parked_at, clientid.clone()));
+        if let Some(expires_at) = parked.expires_at {
+            self.expiries.remove(&(expires_at, clientid.clone()));
+        }
+        if let Some(will_at) = parked.will_at {
+            self.wills.remove(&(will_at, clientid));
+        }
+        Some(parked)
+    }
+}
+
+/// A client id assigned to a connection, released once dropped
+pub(super) struct ReservedId {
+    store: Arc<SessionStore>,
+    clientid: Arc<str>,
+}
+
+impl Drop for ReservedId {
+    fn drop(&mut self) {
+        let mut sessions = self.store.sessions.lock().unwrap();
+        sessions.reserved.remove(&self.clientid);
+    }
+}
+
+/// What is left of a parked session, see `SessionStore::parked`
+pub(super) struct ParkedState {
+    pub(super) clientid: Arc<str>,
+    // seconds, `NEVER_EXPIRES` included
+    pub(super) expiry: u32,
+    pub(super) inflight: Arc<InFlight>,
+    pub(super) will: Option<Publish>,
+    // seconds
+    pub(super) will_delay: u32,
+}
+
+/// Seconds from `now` until `at`, rounded up so a session is never cut short
+fn secs_until(at: Instant, now: Instant) -> u32 {
+    let left = at.saturating_duration_since(now);
+    let secs = left.as_secs() + (left.subsec_nanos() > 0) as u64;
+    secs.min(NEVER_EXPIRES as u64 - 1) as u32
+}
+
+/// Sessions of disconnected clients, their subscriptions stay in the topics table until
+/// the session is resumed or expires, see `SessionPolicy::RetainUntilExpiry`
+#[derive(Default)]
+pub(crate) struct SessionStore {
+    sessions: Mutex<Sessions>,
+}
+
+impl SessionStore {
+    pub(crate) fn new() -> Self {
+        SessionStore::default()
+    }
+    /// Keeps the session of a client which just disconnected for `expiry` seconds, its
+    /// will is held back for `will_delay` seconds unless the client reconnects (3.1.3.2.2)
+    pub(super) fn park(
+        &self,
+        clientid: Arc<str>,
+        expiry: u32,
+        inflight: Arc<InFlight>,
+        will: Option<Publish>,
+        will_delay: u32,
+        now: Instant,
+    ) {
+        let expires_at = match expiry {
+            NEVER_EXPIRES => None,
+            secs => Some(now + Duration::from_secs(secs as u64)),
+        };
+        // a will outliving the session is published when the session expires
+        let will_at = Some(now + Duration::from_secs(will_delay as u64))
+            .filter(|at| will.is_some() && expires_at.is_none_or(|expires_at| *at < expires_at));
+        let parked = ParkedSession {
+            parked_at: now,
+            expires_at,
+            inflight,
+            will,
+            will_at,
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.remove(&clientid);
+        if let Some(expires_at) = expires_at {
+            sessions.expiries.insert((expires_at, clientid.clone()));
+        }
+        if let Some(will_at) = will_at {
+            sessions.wills.insert((will_at, clientid.clone()));
+        }
+        sessions.idle.insert((now, clientid.clone()));
+        sessions.parked.insert(clientid, parked);
+    }
+    /// The QoS 1 deliveries of the session of `clientid`, `None` if it has no session or
+    /// the session expired
+    pub(super) fn inflight(&self, clientid: &str, now: Instant) -> Option<Arc<InFlight>> {
+        match self.sessions.lock().unwrap().parked.get(clientid) {
+            Some(parked) if parked.expires_at.is_none_or(|at| at > now) => {
+                Some(parked.inflight.clone())
+            }
+            _ => None,
+        }
+    }
+    /// Every session which has not expired by `now`, with its expiry and will delay counted
+    /// from `now`
+    pub(super) fn parked(&self, now: Instant) -> Vec<ParkedState> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .parked
+            .iter()
+            .filter(|(_, parked)| parked.expires_at.is_none_or(|at| at > now))
+            .map(|(clientid, parked)| {
+                let expiry = parked
+                    .expires_at
+                    .map_or(NEVER_EXPIRES, |at| secs_until(at, now));
+                ParkedState {
+                    clientid: clientid.clone(),
+                    expiry,
+                    inflight: parked.inflight.clone(),
+                    will: parked.will.clone(),
+                    // a will without its own time is due when the session expires
+                    will_delay: parked.will_at.map_or(expiry, |at| secs_until(at, now)),
+                }
+            })
+            .collect()
+    }
+    /// Keeps `clientid` from being assigned to another connection until the returned
+    /// reservation is dropped, `None` if it is already reserved or has a parked session
+    pub(super) fn reserve(self: &Arc<Self>, clientid: &Arc<str>) -> Option<ReservedId> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.parked.contains_key(clientid) || !sessions.reserved.insert(clientid.clone()) {
+            return None;
+        }
+        Some(ReservedId {
+            store: self.clone(),
+            clientid: clientid.clone(),
+        })
+    }
+    /// Forgets the session of `clientid` and cancels its will, the client reconnected
+    pub(super) fn remove(&self, clientid: &str) {
+        self.sessions.lock().unwrap().remove(clientid);
+    }
+    /// When the next session expires or the next held back will is due
+    pub(super) fn next_expiry(&self) -> Option<Instant> {
+        let sessions = self.sessions.lock().unwrap();
+        let expiry = sessions.expiries.iter().next().map(|(at, _)| *at);
+        let will = sessions.wills.iter().next().map(|(at, _)| *at);
+        expiry.into_iter().chain(will).min()
+    }
+    /// Takes the wills due by `now`, including those of the sessions expiring by `now`
+    pub(super) fn due_wills(&self, now: Instant) -> Vec<Publish> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let sessions = &mut *sessions;
+        let mut due = Vec::new();
+        while let Some((at, clientid)) = sessions.wills.iter().next().cloned() {
+            if at > now {
+                break;
+            }
+            sessions.wills.remove(&(at, clientid.clone()));
+            due.push(clientid);
+        }
+        due.extend(
+            sessions
+                .expiries
+                .iter()
+                .take_while(|(at, _)| *at <= now)
+                .map(|(_, clientid)| clientid.clone()),
+        );
+        due.iter()
+            .filter_map(|clientid| {
+                let parked = sessions.parked.get_mut(clientid)?;
+                parked.will_at = None;
+                parked.will.take()
+            })
+            .collect()
+    }
+    pub(super) fn len(&self) -> usize {
+        self.sessions.lock().unwrap().parked.len()
+    }
+    /// Removes up to `count` sessions, those idle for the longest first, returns them
+    /// along with the will they still held back
+    pub(super) fn evict(&self, count: usize) -> Vec<(Arc<str>, Option<Publish>)> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let oldest: Vec<_> = sessions
+            .idle
+            .iter()
+            .take(count)
+            .map(|(_, clientid)| clientid.clone())
+            .collect();
+        oldest
+            .into_iter()
+            .filter_map(|clientid| {
+                let parked = sessions.remove(&clientid)?;
+                Some((clientid, parked.will))
+            })
+            .collect()
+    }
+    /// Removes and returns the sessions which expired by `now`
+    pub(super) fn expire(&self, now: Instant) -> Vec<Arc<str>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut expired = Vec::new();
+        while let Some((at, clientid)) = sessions.expiries.iter().next().cloned() {
+            if at > now {
+                break;
+            }
+            sessions.remove(&clientid);
+            expired.push(clientid);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_session_store() {
+        let store = SessionStore::new();
+        let now = Instant::now();
+        store.park(Arc::from("a"), 10, Arc::default(), None, 0, now);
+        store.park(Arc::from("b"), 5, Arc::default(), None, 0, now);
+        store.park(Arc::from("c"), NEVER_EXPIRES, Arc::default(), None, 0, now);
+        assert!(store.inflight("a", now).is_some());
+        assert!(store.inflight("d", now).is_none());
+        assert_eq!(store.next_expiry(), Some(now + Duration::from_secs(5)));
+
+        // parking again replaces the previous expiry
+        store.park(Arc::from("b"), 20, Arc::default(), None, 0, now);
+        assert_eq!(store.next_expiry(), Some(now + Duration::from_secs(10)));
+
+        let later = now + Duration::from_secs(15);
+        assert!(store.inflight("a", later).is_none());
+        assert_eq!(store.expire(later), vec![Arc::<str>::from("a")]);
+        assert!(store.inflight("b", later).is_some());
+
+        store.remove("b");
+        assert!(store.inflight("b", later).is_none());
+        assert_eq!(store.next_expiry(), None);
+        assert!(store.expire(later + Duration::from_secs(3600)).is_empty());
+        assert!(store
+            .inflight("c", later + Duration::from_secs(3600))
+            .is_some());
+    }
+    fn will(topic: &str) -> Option<Publish> {
+        Some(Publish::new(Arc::from(topic), Default::default()).unwrap())
+    }
+    fn topics(wills: Vec<Publish>) -> Vec<String> {
+        wills.iter().map(|w| w.topic_name().to_string()).collect()
+    }
+    #[test]
+    fn test_session_wills() {
+        let store = SessionStore::new();
+        let now = Instant::now();
+        let secs = |s| now + Duration::from_secs(s);
+        store.park(Arc::from("a"), 60, Arc::default(), will("a"), 5, now);
+        // the session ends before the delay passes
+        store.park(Arc::from("b"), 10, Arc::default(), will("b"), 30, now);
+        store.park(Arc::from("c"), 60, Arc::default(), will("c"), 5, now);
+        assert_eq!(store.next_expiry(), Some(secs(5)));
+
+        // reconnecting cancels the will
+        store.remove("c");
+        assert!(store.due_wills(secs(4)).is_empty());
+        assert_eq!(topics(store.due_wills(secs(5))), vec!["a"]);
+        assert!(store.due_wills(secs(5)).is_empty());
+        assert_eq!(store.next_expiry(), Some(secs(10)));
+
+        assert_eq!(topics(store.due_wills(secs(10))), vec!["b"]);
+        assert_eq!(store.expire(secs(10)), vec![Arc::<str>::from("b")]);
+        assert!(store.due_wills(secs(3600)).is_empty());
+    }
+    #[test]
+    fn test_session_eviction() {
+        let store = SessionStore::new();
+        let now = Instant::now();
+        let secs = |s| now + Duration::from_secs(s);
+        store.park(Arc::from("a"), 60, Arc::default(), will("a"), 30, secs(0));
+        store.park(
+            Arc::from("b"),
+            NEVER_EXPIRES,
+            Arc::default(),
+            None,
+            0,
+            secs(1),
+        );
+        store.park(Arc::from("c"), 60, Arc::default(), None, 0, secs(2));
+        // parking again makes the session the most recent one
+        store.park(Arc::from("a"), 60, Arc::default(), will("a"), 30, secs(3));
+        assert_eq!(store.len(), 3);
+        let evicted = store.evict(2);
+        let clientids: Vec<_> = evicted.iter().map(|(c, _)| &**c).collect();
+        assert_eq!(clientids, vec!["b", "c"]);
+        assert!(evicted.iter().all(|(_, will)| will.is_none()));
+        assert_eq!(store.len(), 1);
+        // the will is handed over with the session and no longer due
+        let evicted = store.evict(2);
+        assert_eq!(
+            topics(evicted.into_iter().filter_map(|(_, w)| w).collect()),
+            vec!["a"]
+        );
+        assert!(store.due_wills(secs(3600)).is_empty());
+        assert_eq!(store.next_expiry(), None);
+    }
+    #[test]
+    fn test_reserve() {
+        let store = Arc::new(SessionStore::new());
+        let (a, b): (Arc<str>, Arc<str>) = (Arc::from("a"), Arc::from("b"));
+        let reserved = store.reserve(&a).unwrap();
+        assert!(store.reserve(&a).is_none());
+        drop(reserved);
+        assert!(store.reserve(&a).is_some());
+        // a parked session keeps its id
+        store.park(b.clone(), 10, Arc::default(), None, 0, Instant::now());
+        assert!(store.reserve(&b).is_none());
+    }
+}
@@ -0,0 +1,367 @@
+use super::{
+    clientworker::{connection_span, record_disconnect, ClientWorker, Connection},
+    history::DisconnectReason,
+    listener::Listener,
+    mqttclient::{connect_client, decode_frame, MqttClient},
+    pacing::ConnectPacer,
+    session::SessionStore,
+    Client,
+};
+use crate::{
+    config::MqttServerConfig, error::ServerError, packetinfo::PacketInfo, shutdown::Shutdown,
+    throttle::LogThrottle, trace::PublishTracer,
+};
+use apiformes_packet::prelude::*;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use std::{collections::HashMap, fmt, net::SocketAddr, sync::Arc};
+use tokio::time::{sleep, Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{Sender, UnboundedSender},
+        RwLock,
+    },
+};
+use tokio_tungstenite::{
+    accept_hdr_async_with_config,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::HeaderValue,
+        protocol::WebSocketConfig,
+        Message,
+    },
+    WebSocketStream,
+};
+use tracing::{error, info, instrument, trace, warn, Instrument, Span};
+
+/// WebSocket subprotocol MQTT clients must ask for (6.0.0-3)
+const MQTT_SUBPROTOCOL: &str = "mqtt";
+/// First byte of a CONNECT packet, the only packet a raw MQTT client may start with
+const MQTT_CONNECT: u8 = 0x10;
+
+pub struct WsClient {
+    stream: WebSocketStream<TcpStream>,
+    bytes: BytesMut,
+    saddr: SocketAddr,
+    max_packet_size: u32,
+    strict_parsing: bool,
+    version: ProtocolVersion,
+}
+
+impl fmt::Debug for WsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WsWorkerClient({:?})", self.saddr)
+    }
+}
+
+impl WsClient {
+    pub fn new(
+        stream: WebSocketStream<TcpStream>,
+        saddr: SocketAddr,
+        max_packet_size: u32,
+        strict_parsing: bool,
+    ) -> Self {
+        WsClient {
+            stream,
+            saddr,
+            bytes: BytesMut::new(),
+            max_packet_size,
+            strict_parsing,
+            version: ProtocolVersion::default(),
+        }
+    }
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.saddr
+    }
+    pub async fn recv(&mut self) -> Result<Packet, ServerError> {
+        loop {
+            if let Some(len) =
+                Packet::peek_frame_len(&self.bytes)?.filter(|len| *len <= self.bytes.len())
+            {
+                let packet =
+                    decode_frame(&self.bytes[..len], &mut self.version, self.strict_parsing)?;
+                self.bytes.advance(len);
+                return Ok(packet);
+            }
+            // MQTT packets may span several WebSocket messages (6.0.0-2)
+            match self.stream.next().await {
+                Some(Ok(Message::Binary(data))) => {
+                    if self.bytes.remaining() + data.len() > self.max_packet_size as usize {
+                        return Err(ServerError::MaxPacketSizeExceeded);
+                    }
+                    self.bytes.extend_from_slice(&data);
+                    // no need to wait for the rest of a packet that will be refused
+                    if Packet::peek_frame_len(&self.bytes)?
+                        .is_some_and(|len| len > self.max_packet_size as usize)
+                    {
+                        return Err(ServerError::MaxPacketSizeExceeded);
+                    }
+                }
+                // 6.0.0-1: anything but binary data closes the connection
+                Some(Ok(Message::Text(_))) => {
+                    return Err(ServerError::Misc(
+                        "Received a text WebSocket message".to_owned(),
+                    ))
+                }
+                Some(Ok(Message::Close(_))) | None => return Err(ServerError::ConnectionClosed),
+                // pings are answered by tungstenite itself
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e.into()),
+            }
+        }
+    }
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+    pub async fn write(&mut self, frame: Bytes) -> Result<(), ServerError> {
+        self.stream.send(Message::Binary(Vec::from(frame))).await?;
+        Ok(())
+    }
+}
+
+/// Serves raw MQTT and MQTT over WebSocket on the same port, telling them apart by the
+/// first byte the client sends
+pub struct MultiplexListener {
+    listener: TcpListener,
+    queue: UnboundedSender<ClientWorker>,
+    shutdown: Shutdown,
+    cfg: Arc<MqttServerConfig>,
+    clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+    incoming: Sender<PacketInfo>,
+    pacer: Arc<ConnectPacer>,
+    sessions: Arc<SessionStore>,
+    throttle: Arc<LogThrottle>,
+    tracer: Arc<PublishTracer>,
+    settings: Arc<Listener>,
+}
+
+impl MultiplexListener {
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn new(
+        listener: TcpListener,
+        queue: UnboundedSender<ClientWorker>,
+        shutdown: Shutdown,
+        cfg: Arc<MqttServerConfig>,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+        incoming: Sender<PacketInfo>,
+        pacer: Arc<ConnectPacer>,
+        sessions: Arc<SessionStore>,
+        throttle: Arc<LogThrottle>,
+        tracer: Arc<PublishTracer>,
+        settings: Arc<Listener>,
+    ) -> MultiplexListener {
+        MultiplexListener {
+            listener,
+            queue,
+            shutdown,
+            cfg,
+            clients,
+            incoming,
+            pacer,
+            sessions,
+            throttle,
+            tracer,
+            settings,
+        }
+    }
+    async fn listen(&mut self) -> Result<(), ServerError> {
+        let (stream, saddr) = self.listener.accept().await?;
+        // sniffing happens in its own task so a silent client cannot stall the listener
+        let queue = self.queue.clone();
+        let shutdown = self.shutdown.clone();
+        let cfg = self.cfg.clone();
+        let clients = self.clients.clone();
+        let incoming = self.incoming.clone();
+        let pacer = self.pacer.clone();
+        let sessions = self.sessions.clone();
+        let throttle = self.throttle.clone();
+        let tracer = self.tracer.clone();
+        let settings = self.settings.clone();
+        let span = connection_span(&settings.tag, saddr);
+        tokio::spawn(
+            route_client(
+                stream, saddr, queue, shutdown, cfg, clients, incoming, pacer, sessions, throttle,
+                tracer, settings,
+            )
+            .instrument(span),
+        );
+        Ok(())
+    }
+    #[instrument(name = "MultiplexListener::listen_forever", skip_all)]
+    async fn listen_forever(&mut self) -> ! {
+        loop {
+            if let Err(e) = self.listen().await {
+                error!("Error listening to new connections, {:?}", e);
+            }
+        }
+    }
+    #[instrument(name = "MultiplexListener::run", skip_all)]
+    pub async fn run(mut self) {
+        let shutdown = self.shutdown.clone();
+        // no new connection once the connected clients are being drained
+        tokio::select! {
+            _ = shutdown.draining() => (),
+            _ = self.listen_forever() => ()
+        };
+        info!("shutting down");
+    }
+}
+
+enum Protocol {
+    Mqtt,
+    WebSocket,
+}
+
+/// Peeks at the first byte of the connection without consuming it
+async fn sniff(stream: &TcpStream) -> Result<Protocol, ServerError> {
+    let mut first = [0; 1];
+    if stream.peek(&mut first).await? == 0 {
+        return Err(ServerError::ConnectionClosed);
+    }
+    match first[0] {
+        MQTT_CONNECT => Ok(Protocol::Mqtt),
+        // the opening handshake is an HTTP GET request
+        b'G' => Ok(Protocol::WebSocket),
+        b => Err(ServerError::Misc(format!(
+            "Unknown protocol, first byte 0x{:02x}",
+            b
+        ))),
+    }
+}
+
+/// Echoes the `mqtt` subprotocol back when the client offers it
+// the signature is imposed by tungstenite's handshake callback
+#[allow(clippy::result_large_err)]
+fn negotiate_subprotocol(
+    request: &Request,
+    mut response: Response,
+) -> Result<Response, ErrorResponse> {
+    let offers_mqtt = request
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|p| p.trim() == MQTT_SUBPROTOCOL);
+    if offers_mqtt {
+        response.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(MQTT_SUBPROTOCOL),
+        );
+    } else {
+        trace!("WebSocket client did not ask for the mqtt subprotocol");
+    }
+    Ok(response)
+}
+
+async fn open_connection(
+    stream: TcpStream,
+    saddr: SocketAddr,
+    settings: &Listener,
+) -> Result<Connection, ServerError> {
+    match sniff(&stream).await? {
+        Protocol::Mqtt => Ok(Connection::Mqtt(MqttClient::new(
+            stream,
+            saddr,
+            settings.max_packet_size,
+            settings.strict_parsing,
+            settings.read,
+        ))),
+        Protocol::WebSocket => {
+            let config = WebSocketConfig {
+                max_message_size: Some(settings.max_packet_size as usize),
+                max_frame_size: Some(settings.max_packet_size as usize),
+                ..Default::default()
+            };
+            let ws =
+                accept_hdr_async_with_config(stream, negotiate_subprotocol, Some(config)).await?;
+            Ok(Connection::WebSocket(Box::new(WsClient::new(
+                ws,
+                saddr,
+                settings.max_packet_size,
+                settings.strict_parsing,
+            ))))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn route_client(
+    stream: TcpStream,
+    saddr: SocketAddr,
+    queue: UnboundedSender<ClientWorker>,
+    shutdown: Shutdown,
+    cfg: Arc<MqttServerConfig>,
+    clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+    incoming: Sender<PacketInfo>,
+    pacer: Arc<ConnectPacer>,
+    sessions: Arc<SessionStore>,
+    throttle: Arc<LogThrottle>,
+    tracer: Arc<PublishTracer>,
+    settings: Arc<Listener>,
+) {
+    let handshake_timeout = Duration::from_secs(cfg.handshake_timeout as u64);
+    let connection = tokio::select! {
+        _ = shutdown.wait() => {
+            record_disconnect(&Span::current(), &DisconnectReason::ServerShutdown);
+            return;
+        }
+        c = open_connection(stream, saddr, &settings) => c,
+        _ = sleep(handshake_timeout) => Err(ServerError::Misc("TimeOut".to_string())),
+    };
+    let connection = match connection {
+        Ok(c) => c,
+        Err(e) => {
+            warn!(
+                SocketAddr = &*format!("{}", saddr),
+                " Failed to open multiplexed connection, {:?}", e
+            );
+            record_disconnect(&Span::current(), &DisconnectReason::from(&e));
+            return;
+        }
+    };
+    let client = ClientWorker::new(
+        connection,
+        // this task runs in the span of the connection
+        Span::current(),
+        cfg,
+        clients,
+        shutdown.clone(),
+        incoming,
+        pacer,
+        sessions,
+        throttle,
+        tracer,
+        settings,
+    );
+    connect_client(client, saddr, queue, shutdown);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    async fn sniff_first_bytes(bytes: &[u8]) -> Result<Protocol, ServerError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.write_all(bytes).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        sniff(&stream).await
+    }
+    #[tokio::test]
+    async fn test_sniff() {
+        let connect = [MQTT_CONNECT, 0x00];
+        assert!(matches!(
+            sniff_first_bytes(&connect).await,
+            Ok(Protocol::Mqtt)
+        ));
+        assert!(matches!(
+            sniff_first_bytes(b"GET /mqtt HTTP/1.1\r\n").await,
+            Ok(Protocol::WebSocket)
+        ));
+        assert!(sniff_first_bytes(&[0x30]).await.is_err());
+    }
+}
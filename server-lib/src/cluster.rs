@@ -0,0 +1,332 @@
+use crate::{
+    clients::InternalClient,
+    config::Cluster,
+    error::ServerError,
+    link::{authenticate, send, LinkReader},
+    metrics::Metrics,
+    packetinfo::PacketInfo,
+    shutdown::Shutdown,
+    topics::TopicsTable,
+};
+use apiformes_packet::prelude::*;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{channel, Receiver, Sender},
+    task::JoinHandle,
+    time::{self, Duration},
+};
+use tracing::{info, warn};
+
+/// Topic of the PUBLISH packets announcing the topic filters subscribed to on a node, as a
+/// JSON list. Every other PUBLISH sent over a cluster link is a forwarded publish.
+const FILTERS_TOPIC: &str = "$cluster/filters";
+
+/// Delay before connecting again to a peer which could not be reached
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Number of publishes waiting to be sent to a peer, new ones are dropped beyond it
+const PEER_QUEUE: usize = 4096;
+
+/// What a node knows of one of its peers, see `MqttServer::cluster_peers`
+#[derive(Clone, Debug)]
+pub struct ClusterPeer {
+    pub addr: String,
+    /// Whether the link publishes are forwarded over is up
+    pub connected: bool,
+    /// Number of topic filters subscribed to on the peer, as last announced
+    pub filters: usize,
+}
+
+#[derive(Default)]
+struct Peer {
+    filters: Vec<Arc<str>>,
+    // only set while the link to the peer is up
+    link: Option<Sender<Packet>>,
+}
+
+/// Forwards the publishes of this node to the peers hosting matching subscriptions, and
+/// dispatches the publishes forwarded by the peers. Nodes are fully meshed: every node
+/// connects to every peer to send it publishes, and announces its own topic filters over
+/// the connections it accepts.
+pub(crate) struct Router {
+    cfg: Cluster,
+    topics: Arc<TopicsTable>,
+    metrics: Arc<Metrics>,
+    // publishes forwarded by peers are dispatched as sent by this internal client, which
+    // is not exempt from the authorizer
+    clientid: Arc<str>,
+    incoming: Sender<PacketInfo>,
+    peers: Mutex<HashMap<String, Peer>>,
+}
+
+impl Router {
+    pub(crate) fn new(
+        cfg: Cluster,
+        topics: Arc<TopicsTable>,
+        metrics: Arc<Metrics>,
+        clientid: Arc<str>,
+        incoming: Sender<PacketInfo>,
+    ) -> Self {
+        let peers = cfg
+            .peers
+            .iter()
+            .map(|addr| (addr.clone(), Peer::default()))
+            .collect();
+        Router {
+            cfg,
+            topics,
+            metrics,
+            clientid,
+            incoming,
+            peers: Mutex::new(peers),
+        }
+    }
+    pub(crate) fn peers(&self) -> Vec<ClusterPeer> {
+        let mut peers: Vec<_> = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, peer)| ClusterPeer {
+                addr: addr.clone(),
+                connected: peer.link.is_some(),
+                filters: peer.filters.len(),
+            })
+            .collect();
+        peers.sort_by(|a, b| a.addr.cmp(&b.addr));
+        peers
+    }
+    /// Forwards `publish` to every peer with a matching subscription. Publishes forwarded
+    /// by a peer are not forwarded again, the peer already sent them to every node.
+    pub(crate) fn forward(&self, senderid: &str, publish: &Publish) {
+        if senderid == &*self.clientid {
+            return;
+        }
+        let topic = publish.topic_name();
+        let peers = self.peers.lock().unwrap();
+        for peer in peers.values() {
+            if !peer
+                .filters
+                .iter()
+                .any(|filter| filter_matches(filter, topic))
+            {
+                continue;
+            }
+            let mut forwarded = publish.clone();
+            // the link is never acknowledged, the id only lets the publish keep its QoS
+            if forwarded.qos() != QoS::QoS0 {
+                forwarded.set_packet_identifier(1).unwrap();
+            }
+            let sent = match &peer.link {
+                Some(link) => link.try_send(forwarded.build()).is_ok(),
+                None => false,
+            };
+            match sent {
+                true => self.metrics.inc_cluster_forwarded(),
+                false => self.metrics.inc_cluster_dropped(),
+            }
+        }
+    }
+    fn connected(&self, addr: &str, link: Option<Sender<Packet>>) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(addr) {
+            // the filters are announced again once connected
+            peer.filters.clear();
+            peer.link = link;
+        }
+    }
+    fn announced(&self, addr: &str, filters: Vec<Arc<str>>) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(addr) {
+            peer.filters = filters;
+        }
+    }
+}
+
+/// Starts the links with the peers, `client` is the internal client the publishes of the
+/// peers are dispatched from. Also returns the address the peers are accepted on.
+pub(crate) async fn start(
+    router: Arc<Router>,
+    mut client: InternalClient,
+    shutdown: Shutdown,
+) -> Result<(Vec<JoinHandle<()>>, SocketAddr), ServerError> {
+    let listener = TcpListener::bind(router.cfg.listen).await?;
+    let local = listener.local_addr()?;
+    info!(
+        SocketAddr = &*local.to_string(),
+        "Starting listener for cluster peers"
+    );
+    let mut workers = vec![tokio::spawn(serve(
+        listener,
+        router.clone(),
+        shutdown.clone(),
+    ))];
+    for addr in &router.cfg.peers {
+        workers.push(tokio::spawn(dial(
+            router.clone(),
+            addr.clone(),
+            shutdown.clone(),
+        )));
+    }
+    // nothing is addressed to the client but the acknowledgements of its publishes
+    tokio::spawn(async move { while client.recv().await.is_some() {} });
+    Ok((workers, local))
+}
+
+/// Sends the publishes matching the filters of the peer at `addr` until the shutdown,
+/// connecting again whenever the link breaks
+async fn dial(router: Arc<Router>, addr: String, shutdown: Shutdown) {
+    loop {
+        let connected = tokio::select! {
+            _ = shutdown.wait() => return,
+            connected = connect(&addr, &router.cfg.key) => connected,
+        };
+        match connected {
+            Ok(stream) => {
+                info!(peer = &*addr, "Connected to cluster peer");
+                let (tx, rx) = channel(PEER_QUEUE);
+                router.connected(&addr, Some(tx));
+                let closed = tokio::select! {
+                    _ = shutdown.wait() => return,
+                    closed = forward_to(stream, &router, &addr, rx) => closed,
+                };
+                router.connected(&addr, None);
+                warn!(peer = &*addr, "Lost cluster peer, {:?}", closed);
+            }
+            Err(e) => warn!(peer = &*addr, "Failed connecting to cluster peer, {:?}", e),
+        }
+        tokio::select! {
+            _ = shutdown.wait() => return,
+            _ = time::sleep(RECONNECT_DELAY) => (),
+        }
+    }
+}
+
+async fn connect(addr: &str, key: &str) -> Result<TcpStream, ServerError> {
+    let mut stream = TcpStream::connect(addr).await?;
+    authenticate(&mut stream, key).await?;
+    Ok(stream)
+}
+
+/// Sends the publishes queued for the peer at `addr` and records the filters it announces,
+/// returns why the link broke
+async fn forward_to(
+    mut stream: TcpStream,
+    router: &Router,
+    addr: &str,
+    mut publishes: Receiver<Packet>,
+) -> ServerError {
+    let (reader, mut writer) = stream.split();
+    let mut reader = LinkReader::new(reader);
+    loop {
+        let sent = tokio::select! {
+            publish = publishes.recv() => match publish {
+                Some(publish) => send(&mut writer, publish).await,
+                None => return ServerError::ConnectionClosed,
+            },
+            packets = reader.recv() => packets.map(|packets| {
+                for packet in packets {
+                    match packet {
+                        Packet::Publish(p) if &**p.topic_name() == FILTERS_TOPIC => {
+                            match serde_json::from_slice::<Vec<String>>(&p.payload()) {
+                                Ok(filters) => {
+                                    router.announced(addr, filters.into_iter().map(Arc::from).collect())
+                                }
+                                Err(e) => warn!(peer = addr, "Invalid filters announced, {}", e),
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+            }),
+        };
+        if let Err(e) = sent {
+            return e;
+        }
+    }
+}
+
+/// Accepts the links of the peers until the shutdown, those not presenting the key of the
+/// cluster are closed
+async fn serve(listener: TcpListener, router: Arc<Router>, shutdown: Shutdown) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            _ = shutdown.wait() => return,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed accepting a cluster peer, {:?}", e);
+                    continue;
+                }
+            },
+        };
+        let router = router.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = shutdown.wait() => (),
+                closed = receive_from(stream, &router) => {
+                    info!(peer = &*addr.to_string(), "Cluster peer left, {:?}", closed);
+                }
+            }
+        });
+    }
+}
+
+/// Announces the filters of this node every gossip interval when they changed, a PINGREQ
+/// is sent when they did not, and dispatches the publishes forwarded by the peer once it
+/// presented the key. Returns why the link broke.
+async fn receive_from(mut stream: TcpStream, router: &Router) -> ServerError {
+    let (reader, mut writer) = stream.split();
+    let mut reader = LinkReader::new(reader);
+    if let Err(e) = reader.authenticated(&router.cfg.key).await {
+        return e;
+    }
+    let mut announced = None;
+    let mut tick = time::interval(Duration::from_millis(router.cfg.gossip_interval_ms as u64));
+    loop {
+        let done = tokio::select! {
+            _ = tick.tick() => {
+                let filters = router.topics.filters().await;
+                if announced.as_ref() == Some(&filters) {
+                    send(&mut writer, Ping::new().build_req()).await
+                } else {
+                    let json = serde_json::to_vec(&filters.iter().map(|f| &**f).collect::<Vec<_>>())
+                        .unwrap();
+                    let announcement = Publish::new(Arc::from(FILTERS_TOPIC), Bytes::from(json))
+                        .unwrap()
+                        .build();
+                    announced = Some(filters);
+                    send(&mut writer, announcement).await
+                }
+            }
+            packets = reader.recv() => match packets {
+                Ok(packets) => dispatch(router, packets).await,
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = done {
+            return e;
+        }
+    }
+}
+
+async fn dispatch(router: &Router, packets: Vec<Packet>) -> Result<(), ServerError> {
+    for packet in packets {
+        if let Packet::Publish(_) = packet {
+            let packet = PacketInfo {
+                senderid: router.clientid.clone(),
+                packet,
+                traced: false,
+            };
+            router
+                .incoming
+                .send(packet)
+                .await
+                .map_err(|_| ServerError::Misc("Dispatcher is not running".to_owned()))?;
+        }
+    }
+    Ok(())
+}
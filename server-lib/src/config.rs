@@ -1,37 +1,1279 @@
+use crate::acl::{AclFile, Authorizer};
+use crate::cfg::MAX_QOS;
+#[cfg(feature = "noise")]
+use crate::cfg::NOISE_PATTERN;
+use crate::clients::{AuthProvider, ClientIdGenerator, UuidClientIds};
+use crate::error::ServerError;
+use crate::intercept::Interceptor;
+use crate::msgid::MessageIds;
+use crate::storage::{FileStorage, Storage};
+use crate::validate::TopicValidator;
+use apiformes_packet::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::info;
 
 #[cfg(feature = "noise")]
-#[derive(Serialize, Deserialize, PartialEq)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
 pub enum Permeability {
     Permissive,
     Strict,
 }
 
+/// What to do with clients connecting with a keep alive of 0, i.e. asking the server to
+/// never time them out (3.1.2.10)
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum ZeroKeepAlive {
+    /// Accept the client and never time it out
+    Allow,
+    /// Accept the client but impose `keep_alive` through the ServerKeepAlive property
+    Override,
+    /// Refuse the connection
+    Reject,
+}
+
+/// What happens to the subscriptions of a client once its network connection is gone
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum SessionPolicy {
+    /// Every session is transient, subscriptions are removed as soon as the client
+    /// disconnects and a SessionExpiryInterval of 0 is imposed in the CONNACK
+    CleanAll,
+    /// Sessions with a non-zero SessionExpiryInterval keep their subscriptions until they
+    /// expire and are resumed by reconnecting with Clean Start set to 0 (3.1.2.11.2),
+    /// transient sessions are still cleaned immediately
+    RetainUntilExpiry,
+}
+
+/// Which member of a shared subscription receives a publish matching it (4.8.2)
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum SharedDelivery {
+    /// Connected members take turns
+    RoundRobin,
+    /// The connected member with the fewest unacknowledged QoS 1 publishes, members
+    /// equally loaded take turns
+    LeastLoaded,
+}
+
+/// What happens to a publish no connected subscriber received
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum UnmatchedPolicy {
+    Discard,
+    /// Kept as the retained message of its topic, replacing the previous one
+    Retain,
+    /// Delivered to the subscriptions made within `grace` seconds, giving subscribers in
+    /// the middle of a reconnection a chance to receive it
+    Queue {
+        grace: u32,
+    },
+}
+
+/// How the subscription tree is stored, see `MqttServerConfig::subscription_tree`
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum SubscriptionTree {
+    /// Every level has its own locks, matching a publish takes a read lock per level
+    Locked,
+    /// Immutable snapshots swapped on every change, matching a publish takes no lock but
+    /// every subscription change copies the levels of its topic filter
+    CopyOnWrite,
+}
+
+/// How the packets are spread over the dispatcher workers, see
+/// `MqttServerConfig::dispatcher_workers`
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum DispatcherSharding {
+    /// By sender, every packet of a client is processed in the order it was sent
+    ClientId,
+    /// QoS 0 publishes by topic and the other packets by sender, so the PUBACKs of a client
+    /// keep the order of its QoS 1 publishes (4.6). The QoS 0 publishes on one topic keep
+    /// their order, but they may be reordered with the other packets of their sender: one
+    /// sent right after a SUBSCRIBE may be matched before the subscription is added.
+    Topic,
+}
+
+/// How the connections of a listener read their socket, trading latency for throughput
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(default)]
+pub struct ReadTuning {
+    /// Bytes read from the socket at once, several packets may then be read with a single
+    /// read. 0 reads each packet on its own and never reads past it.
+    pub buffer_size: usize,
+    /// Handle every packet already read before going back to the outgoing packets and
+    /// the timers of the connection
+    pub coalesce_frames: bool,
+}
+
+/// What happens to a packet for a client whose outgoing queue is full, see
+/// `MqttServerConfig::max_outgoing_packets`
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum OverflowPolicy {
+    /// The oldest queued packet is discarded to make room
+    DropOldest,
+    /// The packet is discarded
+    DropNewest,
+    /// The packet is discarded and the client disconnected with QuotaExceeded
+    Disconnect,
+}
+
+/// Which retained message makes room once `RetainedLimits` are reached
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum RetainedEviction {
+    /// The one stored or sent to a subscription the longest ago
+    LeastRecentlyUsed,
+    /// The one stored the longest ago
+    OldestFirst,
+}
+
+/// Bounds of the retained messages, those published with RETAIN and those kept under
+/// `UnmatchedPolicy::Retain`, a bound of None is unlimited. Sizes count the topic name
+/// and the payload.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetainedLimits {
+    pub max_entries: Option<usize>,
+    /// A message larger than this is not kept, and drops the previous one of its topic
+    pub max_bytes: Option<usize>,
+    pub eviction: RetainedEviction,
+}
+
+impl Default for RetainedLimits {
+    fn default() -> Self {
+        RetainedLimits {
+            max_entries: Some(100_000),
+            max_bytes: Some(64 * 1024 * 1024),
+            eviction: RetainedEviction::LeastRecentlyUsed,
+        }
+    }
+}
+
+/// The policy applied to the unmatched publishes of the topics starting with `prefix`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UnmatchedTopics {
+    pub prefix: String,
+    pub policy: UnmatchedPolicy,
+}
+
+/// What to tell a publisher when some subscribers were skipped because of `Permeability::Strict`
+#[cfg(feature = "noise")]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum PermeabilityViolation {
+    /// Deliver to the allowed subscribers only and report nothing to the publisher
+    Ignore,
+    /// Answer with reason code ImplementationSpecificError, QoS 0 publishers have no
+    /// acknowledgement to carry it so they are disconnected
+    Reject,
+}
+
+/// Pacing of the handshakes, see `MqttServerConfig::connect_rate`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConnectRate {
+    /// Sustained number of CONNACKs sent per second
+    pub per_second: u32,
+    /// Number of CONNACKs that can be sent at once before pacing kicks in
+    pub burst: u32,
+    /// Longest a client is kept waiting for its CONNACK, clients that would wait longer
+    /// are refused with the ServerBusy reason code
+    pub max_wait_ms: u32,
+}
+
+/// Connections allowed from a single IP address, see `MqttServerConfig::connect_rate_per_ip`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IpConnectRate {
+    /// Sustained number of connections per second
+    pub per_second: u32,
+    /// Number of connections accepted at once before the rate applies
+    pub burst: u32,
+}
+
+/// Publish rates a single client may sustain, see `MqttServerConfig::publish_quotas`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PublishQuota {
+    /// Publishes per second, a client above it is disconnected with MessageRateTooHigh
+    pub messages_per_second: Option<u32>,
+    /// Payload bytes per second, a client above it is disconnected with QuotaExceeded
+    pub bytes_per_second: Option<u64>,
+    /// Seconds worth of the rates a client may publish at once before being held to them
+    pub burst_seconds: u32,
+}
+
+/// Publish quota of the clients of each listener, `None` puts no limit
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct PublishQuotas {
+    pub mqtt: Option<PublishQuota>,
+    #[cfg(feature = "noise")]
+    pub noise: Option<PublishQuota>,
+    #[cfg(feature = "websocket")]
+    pub multiplex: Option<PublishQuota>,
+}
+
+/// A listener for MQTT without encryption along with `mqtt_socketaddr`, its clients get the
+/// global settings it leaves unset
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ListenerConfig {
+    /// Name of the listener, told apart by `Client::listener`
+    pub tag: String,
+    pub socketaddr: SocketAddr,
+    /// Maximum number of connections open at once on this listener, on top of the global
+    /// `max_connections`
+    pub max_connections: Option<usize>,
+    /// Refuses the clients no `AuthProvider` authenticated with the NotAuthorized reason code
+    #[serde(default)]
+    pub require_auth: bool,
+    pub max_qos: Option<u8>,
+    pub max_packet_size: Option<u32>,
+    /// Whether its unencrypted clients receive what encrypted ones publish
+    #[cfg(feature = "noise")]
+    pub channel_permeability: Option<Permeability>,
+    pub read: Option<ReadTuning>,
+    pub publish_quota: Option<PublishQuota>,
+}
+
+/// Role of the broker in a warm standby pair, see `MqttServer::promote`
+///
+/// The primary streams its sessions and retained messages to whoever connects to `listen`
+/// with `key`. The key crosses the network in the clear, `listen` must only be reachable
+/// by the standbys.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Replication {
+    /// Streams the retained messages and the sessions to the standbys connecting to
+    /// `listen`. Retained messages are sent as they are stored, the sessions every
+    /// `max_lag_ms` milliseconds when they changed.
+    Primary {
+        listen: SocketAddr,
+        key: String,
+        max_lag_ms: u32,
+    },
+    /// Follows the primary at `primary`, presenting `key`, refusing clients with
+    /// ServerUnavailable until promoted
+    Standby { primary: String, key: String },
+}
+
+/// Membership of a cluster of brokers forwarding publishes to the nodes hosting matching
+/// subscriptions, see `MqttServerConfig::cluster`. Every node lists every other one.
+///
+/// A node accepts as a peer whoever connects to `listen` with `key`, and dispatches its
+/// publishes as the `$internal/cluster` client, which the authorizer is asked about like
+/// any other. The key crosses the network in the clear, `listen` must only be reachable
+/// by the other nodes.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Cluster {
+    /// Address the other nodes connect to
+    pub listen: SocketAddr,
+    /// The `listen` addresses of the other nodes
+    pub peers: Vec<String>,
+    /// Secret shared by every node, presented when connecting to a peer
+    pub key: String,
+    /// Milliseconds between two announcements of the topic filters subscribed to on this
+    /// node, publishes matching a newer subscription are not forwarded until then
+    pub gossip_interval_ms: u32,
+}
+
+/// Bounds of the subscription tree, going over any of them logs a warning as it usually
+/// means clients are leaking subscriptions
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TopicTreeAlarm {
+    /// Maximum number of levels of a subscribed topic filter
+    pub max_depth: u64,
+    /// Maximum number of nodes in the subscription tree
+    pub max_nodes: u64,
+    /// Maximum number of active subscriptions across all clients
+    pub max_subscriptions: u64,
+    /// Also describe the raised alarm under `$SYS/broker/alarms/topic_tree`
+    pub sys_event: bool,
+}
+
+/// Periodic report of the broker load published under `$SYS/broker/heartbeat` as a JSON
+/// object, e.g. `{"clients":12,"queues":{"dispatcher":0},"memory_estimate":53248,
+/// "msg_per_sec":4.5}`. Indicators can be left out to keep the heartbeat small.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Heartbeat {
+    /// Seconds between two heartbeats
+    pub interval: u32,
+    /// Number of connected clients, internal ones excluded
+    pub clients: bool,
+    /// Number of packets waiting in the dispatcher queue
+    pub queue_depths: bool,
+    /// Rough number of bytes used by subscriptions, clients and queued packets
+    pub memory_estimate: bool,
+    /// Publishes accepted per second since the previous heartbeat
+    pub message_rate: bool,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: 10,
+            clients: true,
+            queue_depths: true,
+            memory_estimate: true,
+            message_rate: true,
+        }
+    }
+}
+
+/// Bounds of the payload filters clients attach to their subscriptions, see
+/// `filter::FILTER_PROPERTY`. Subscriptions exceeding them are refused with QuotaExceeded.
+#[cfg(feature = "edge-filter")]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct FilterQuota {
+    /// Maximum number of filters of a single SUBSCRIBE, 0 disables payload filtering
+    pub max_conditions: usize,
+    /// Maximum number of reference tokens of a JSON pointer
+    pub max_pointer_depth: usize,
+    /// Maximum length in bytes of a single filter
+    pub max_condition_len: usize,
+}
+
+#[cfg(feature = "edge-filter")]
+impl Default for FilterQuota {
+    fn default() -> Self {
+        FilterQuota {
+            max_conditions: 4,
+            max_pointer_depth: 8,
+            max_condition_len: 256,
+        }
+    }
+}
+
+/// Settings left out of a configuration file keep their default value
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct MqttServerConfig {
+    /// Identifier of this broker instance, used to tell brokers apart in multi-node
+    /// deployments. A random one is generated at startup when left empty.
+    pub node_id: Option<String>,
     /// IP and port for MQTT without encryption
     pub mqtt_socketaddr: Option<SocketAddr>,
+    /// Reads of the connections to `mqtt_socketaddr`
+    pub mqtt_read: ReadTuning,
+    /// More listeners for MQTT without encryption, each with its own settings
+    pub listeners: Vec<ListenerConfig>,
     /// time in seconds
     pub keep_alive: u16,
+    /// Seconds a new connection has to complete its handshakes, the Noise or WebSocket
+    /// one if any then the CONNECT, before it is closed
+    pub handshake_timeout: u32,
+    /// Highest QoS advertised to clients through the MaximumQoS property, SUBSCRIBE
+    /// requests above it are granted this QoS instead and PUBLISH packets above it are
+    /// refused. Cannot exceed what the broker implements.
+    pub max_qos: u8,
+    /// Policy for clients connecting with a keep alive of 0
+    pub zero_keep_alive: ZeroKeepAlive,
+    /// What is kept of a session after its client disconnects
+    pub session_policy: SessionPolicy,
+    /// Maximum number of sessions, connected clients and retained sessions together. Once
+    /// reached the retained sessions idle for the longest are ended as if they expired,
+    /// connected clients are never refused.
+    pub max_sessions: Option<usize>,
+    /// How shared subscriptions spread publishes among their members
+    pub shared_delivery: SharedDelivery,
+    /// Limits the rate of successful handshakes so a reconnect storm, e.g. after a
+    /// restart, is spread over time. `None` accepts connections as fast as they come.
+    pub connect_rate: Option<ConnectRate>,
+    /// Limits how often a single IP address connects, its CONNECTs beyond the rate are
+    /// refused with the ConnectionRateExceeded reason code. `None` puts no limit.
+    pub connect_rate_per_ip: Option<IpConnectRate>,
+    /// Maximum number of connections open at once, including the ones still waiting for
+    /// their CONNACK. The CONNECTs beyond it are refused with the ServerBusy reason code.
+    pub max_connections: Option<usize>,
+    /// Limits how fast a client publishes, depending on the listener it connected to
+    pub publish_quotas: PublishQuotas,
+    /// Accepts the topic filters with empty levels, e.g. `a//b`, which the specification
+    /// allows. Subscriptions to other filters are refused with TopicFilterInvalid.
+    pub empty_filter_levels: bool,
+    /// Maximum lifetime of a connection in seconds, after which the client is disconnected
+    /// with the MaximumConnectTime reason code. `None` means connections never expire.
+    pub max_connect_time: Option<u32>,
+    /// User properties added to every successful CONNACK, e.g. operator contact or terms
+    /// of use. They are left out for clients whose maximum packet size is too small.
+    pub connack_user_properties: Vec<(String, String)>,
+    /// Topic prefix sent as ResponseInformation to the clients setting
+    /// RequestResponseInformation, `%c` is replaced by the client id, e.g. `replies/%c`.
+    /// By convention a client subscribes to `<prefix>/#` and sets its ResponseTopics below
+    /// the prefix. `None` never sends ResponseInformation.
+    pub response_information: Option<String>,
+    /// Longest ReasonString in bytes sent to clients, longer ones are left out of the packet.
+    /// Does not apply to forwarded PUBLISH packets.
+    pub max_reason_string_len: usize,
+    /// Maximum total length in bytes of the names and values of the UserProperties of a
+    /// packet sent to clients, all of them are left out above it. Does not apply to
+    /// forwarded PUBLISH packets, their user properties are part of the message.
+    pub max_user_properties_len: usize,
+    /// Seconds to wait for the PUBACK of a QoS 1 publish before sending every
+    /// unacknowledged publish of the client again. MQTT 5 only retransmits when a session
+    /// is resumed (4.4), `None` sticks to that.
+    pub retransmit_interval: Option<u32>,
+    /// Number of QoS 1 publishes kept for a client which has as many unacknowledged ones as
+    /// its ReceiveMaximum allows, the oldest are dropped beyond it. `None` keeps them all.
+    pub max_queued_publishes: Option<usize>,
+    /// Number of packets queued for a client its worker did not take yet, beyond which
+    /// `outgoing_overflow` applies. `None` lets a slow client exhaust the memory.
+    pub max_outgoing_packets: Option<usize>,
+    pub outgoing_overflow: OverflowPolicy,
+    /// Seconds a write to a client may take once packets pile up in its queue, the client
+    /// is disconnected when the write takes longer so a dead peer does not accumulate
+    /// packets until the keep alive expires. `None` waits for slow clients forever.
+    pub backlog_write_timeout: Option<u32>,
+    /// Nodes this broker shares its subscriptions with so clients can connect to any of
+    /// them, `None` runs a standalone broker
+    pub cluster: Option<Cluster>,
+    /// Replication of the retained messages and sessions to a warm standby, `None` keeps
+    /// them on this broker only
+    pub replication: Option<Replication>,
+    /// Seconds `MqttServer::shutdown_gracefully` gives the connected clients to receive
+    /// and acknowledge what is left for them before they are disconnected anyway
+    pub drain_timeout: u32,
+    /// Seconds during which the repetitions of a warning or error logged on a hot path, such
+    /// as a refused publish, are only counted. 0 logs every occurrence.
+    pub log_throttle: u32,
+    /// Number of client ids for which the reason of their last disconnect is remembered
+    pub disconnect_history_size: usize,
+    /// Bounds of the subscription tree to warn about, `None` disables the alarm
+    pub topic_tree_alarm: Option<TopicTreeAlarm>,
+    /// Seconds between two removals of the subscription tree nodes left without
+    /// subscriptions, `None` lets the tree grow with every topic ever subscribed to
+    pub topic_tree_sweep: Option<u32>,
+    /// `CopyOnWrite` suits brokers whose subscriptions change rarely compared to publishes
+    pub subscription_tree: SubscriptionTree,
+    /// Number of topics whose subscribers are cached between publishes, 0 disables the
+    /// cache. Any subscribe or unsubscribe invalidates it, so it pays off with a stable set
+    /// of subscriptions and many publishes.
+    pub subscriber_cache_size: usize,
+    /// Load report published periodically under `$SYS`, `None` disables it
+    pub heartbeat: Option<Heartbeat>,
+    /// File holding the high-water mark of the message ids, so they keep increasing across
+    /// restarts. Ids are only kept in memory when `None`.
+    pub message_id_path: Option<PathBuf>,
+    /// Add a `MESSAGE_ID_PROPERTY` user property carrying `<node_id>:<message id>` to every
+    /// forwarded publish, letting bridges and peers deduplicate replayed messages
+    pub stamp_message_ids: bool,
+    /// Keep the redacted `MqttServerConfig::snapshot` under `$SYS/broker/config`
+    pub publish_config: bool,
+    /// Produces the ids assigned to clients connecting without one
+    #[serde(skip, default = "default_clientid_generator")]
+    pub clientid_generator: Arc<dyn ClientIdGenerator>,
+    /// Enhanced authentication methods clients can ask for in their CONNECT, clients that
+    /// do not ask for one connect as before
+    #[serde(skip)]
+    pub auth_providers: Vec<Arc<dyn AuthProvider>>,
+    /// Checks run on the payloads published on the topics of their prefix, publishes
+    /// failing any of them are refused
+    #[serde(skip)]
+    pub payload_validators: Vec<TopicValidator>,
+    /// Hooks called in turn as clients connect, publish, subscribe, receive publishes and
+    /// disconnect
+    #[serde(skip)]
+    pub interceptors: Vec<Arc<dyn Interceptor>>,
+    /// Decides which topics clients may publish on and subscribe to, clients may use any
+    /// topic when `None`
+    #[serde(skip)]
+    pub authorizer: Option<Arc<dyn Authorizer>>,
+    /// File holding the rules of an `AclFile` used as the `authorizer`
+    pub acl_path: Option<PathBuf>,
+    /// Where the sessions and retained messages are saved to be restored on startup,
+    /// nothing survives a restart when `None`
+    #[serde(skip)]
+    pub storage: Option<Arc<dyn Storage>>,
+    /// Directory of a `FileStorage` used as the `storage`
+    pub storage_path: Option<PathBuf>,
+    /// Seconds between two saves to the `storage`, the state is also saved on shutdown.
+    /// What changed since the last save is lost if the broker crashes.
+    pub persist_interval: u32,
+    /// Topic prefixes whose publishes are fanned out by a dedicated task, isolating heavy
+    /// topics from latency sensitive ones. Topics matching several prefixes go to the
+    /// longest one, the others are fanned out by the dispatcher itself.
+    pub fanout_lanes: Vec<String>,
+    /// Policies for the publishes reaching no connected subscriber by topic prefix. Topics
+    /// matching several prefixes follow the longest one, the others are discarded. Kept
+    /// publishes are sent to new subscriptions along with the retained messages.
+    pub unmatched_publishes: Vec<UnmatchedTopics>,
+    /// Bounds of the retained messages, evictions are logged and counted in
+    /// `Metrics::retained_evictions`
+    pub retained_limits: RetainedLimits,
     /// The size of packets dispatcher queue in bytes. The size is rounded down to
     /// the nearest element size with 1 element being the minimum. The smaller the
     /// number the more back pressure applied to client threads which mean more contex
     /// switching between threads.
     pub dispatcher_queue_size: usize,
+    /// Number of tasks validating and fanning out the incoming packets, each one with its
+    /// own queue of `dispatcher_queue_size` bytes fed according to `dispatcher_sharding`.
+    /// 1 processes every packet in a single task.
+    pub dispatcher_workers: usize,
+    pub dispatcher_sharding: DispatcherSharding,
 
     /// Maximum packet that the server may send or receive
     /// If the server receives a packet bigger than this size, it will disconect
     pub max_packet_size: u32,
+    /// Disconnects the clients sending packets that are not minimally encoded, e.g. with a
+    /// padded remaining length, or with content their parser leaves unread. Off by default
+    /// as some clients pad their packets.
+    pub strict_parsing: bool,
 
     #[cfg(feature = "noise")]
     /// IP and port for encrypted MQTT
     pub noise_socketaddr: Option<SocketAddr>,
 
+    #[cfg(feature = "noise")]
+    /// Reads of the connections to `noise_socketaddr`. A Noise frame is always read whole,
+    /// with a buffer size of 0 the read buffer keeps the size of the codec.
+    pub noise_read: ReadTuning,
+
     #[cfg(feature = "noise")]
     /// Forward Packets sent over Noise to clients listening to TCP
     pub channel_permeability: Permeability,
 
+    #[cfg(feature = "noise")]
+    /// How publishers are told about deliveries suppressed by `channel_permeability`
+    pub permeability_violation: PermeabilityViolation,
+
     #[cfg(feature = "noise")]
     pub private_key: [u8; 32],
+
+    #[cfg(feature = "noise")]
+    /// Handshake pattern of `noise_socketaddr`, the broker takes the responder role. It
+    /// must be interactive, with 25519 keys and no pre-shared key.
+    pub noise_pattern: String,
+
+    #[cfg(feature = "noise")]
+    /// Static public keys of the clients allowed over Noise, any key is accepted when
+    /// unset. The pattern must then transmit the static key of the clients.
+    pub noise_accepted_keys: Option<Vec<[u8; 32]>>,
+
+    #[cfg(feature = "websocket")]
+    /// IP and port accepting both unencrypted MQTT and MQTT over WebSocket, the protocol
+    /// is detected from the first byte sent by the client
+    pub multiplex_socketaddr: Option<SocketAddr>,
+
+    #[cfg(feature = "websocket")]
+    /// Reads of the unencrypted MQTT connections to `multiplex_socketaddr`
+    pub multiplex_read: ReadTuning,
+
+    #[cfg(feature = "edge-filter")]
+    /// Bounds of the payload filters evaluated on behalf of subscribers
+    pub filter_quota: FilterQuota,
+
+    #[cfg(feature = "admin")]
+    /// IP and port of the HTTP management API. Requests are not authenticated, it must
+    /// only be reachable by operators.
+    pub admin_socketaddr: Option<SocketAddr>,
+}
+
+/// Settings whose value is replaced by `REDACTED` in `MqttServerConfig::snapshot`
+const SECRET_FIELDS: &[&str] = &["private_key"];
+pub const REDACTED: &str = "<redacted>";
+
+fn default_clientid_generator() -> Arc<dyn ClientIdGenerator> {
+    Arc::new(UuidClientIds)
+}
+
+impl Default for MqttServerConfig {
+    fn default() -> Self {
+        MqttServerConfig {
+            node_id: None,
+            mqtt_socketaddr: Some(SocketAddr::from(([0, 0, 0, 0], 1883))),
+            mqtt_read: ReadTuning::default(),
+            listeners: Vec::new(),
+            keep_alive: 50,
+            handshake_timeout: 10,
+            max_qos: MAX_QOS,
+            zero_keep_alive: ZeroKeepAlive::Override,
+            session_policy: SessionPolicy::CleanAll,
+            max_sessions: None,
+            shared_delivery: SharedDelivery::RoundRobin,
+            connect_rate: None,
+            connect_rate_per_ip: None,
+            max_connections: None,
+            publish_quotas: PublishQuotas::default(),
+            empty_filter_levels: true,
+            max_connect_time: None,
+            connack_user_properties: Vec::new(),
+            response_information: None,
+            max_reason_string_len: 1024,
+            max_user_properties_len: 8 * 1024,
+            retransmit_interval: None,
+            max_queued_publishes: Some(1000),
+            max_outgoing_packets: Some(10_000),
+            outgoing_overflow: OverflowPolicy::Disconnect,
+            backlog_write_timeout: Some(10),
+            cluster: None,
+            replication: None,
+            drain_timeout: 10,
+            log_throttle: 10,
+            disconnect_history_size: 1024,
+            topic_tree_alarm: None,
+            topic_tree_sweep: Some(60),
+            subscription_tree: SubscriptionTree::Locked,
+            subscriber_cache_size: 0,
+            heartbeat: None,
+            message_id_path: None,
+            stamp_message_ids: false,
+            publish_config: false,
+            clientid_generator: default_clientid_generator(),
+            auth_providers: Vec::new(),
+            payload_validators: Vec::new(),
+            interceptors: Vec::new(),
+            authorizer: None,
+            acl_path: None,
+            storage: None,
+            storage_path: None,
+            persist_interval: 10,
+            fanout_lanes: Vec::new(),
+            unmatched_publishes: Vec::new(),
+            retained_limits: RetainedLimits::default(),
+            dispatcher_queue_size: 1024 * 1024,
+            dispatcher_workers: 1,
+            dispatcher_sharding: DispatcherSharding::ClientId,
+            max_packet_size: 64 * 1024,
+            strict_parsing: false,
+            #[cfg(feature = "noise")]
+            noise_socketaddr: None,
+            #[cfg(feature = "noise")]
+            noise_read: ReadTuning::default(),
+            #[cfg(feature = "noise")]
+            channel_permeability: Permeability::Strict,
+            #[cfg(feature = "noise")]
+            permeability_violation: PermeabilityViolation::Ignore,
+            #[cfg(feature = "noise")]
+            private_key: [0; 32],
+            #[cfg(feature = "noise")]
+            noise_pattern: NOISE_PATTERN.to_owned(),
+            #[cfg(feature = "noise")]
+            noise_accepted_keys: None,
+            #[cfg(feature = "websocket")]
+            multiplex_socketaddr: None,
+            #[cfg(feature = "websocket")]
+            multiplex_read: ReadTuning::default(),
+            #[cfg(feature = "edge-filter")]
+            filter_quota: FilterQuota::default(),
+            #[cfg(feature = "admin")]
+            admin_socketaddr: None,
+        }
+    }
+}
+
+impl MqttServerConfig {
+    pub(crate) fn connack_user_properties(&self) -> Result<Vec<MqttPropValue>, DataParseError> {
+        self.connack_user_properties
+            .iter()
+            .map(|(k, v)| MqttPropValue::new_string_pair(Arc::from(&**k), Arc::from(&**v)))
+            .collect()
+    }
+    /// The ResponseInformation of `clientid`, left out when its client id makes it an
+    /// invalid topic name
+    pub(crate) fn response_information(&self, clientid: &str) -> Option<Arc<str>> {
+        let prefix = self.response_information.as_ref()?.replace("%c", clientid);
+        MqttTopic::new(Arc::from(prefix))
+            .ok()
+            .filter(|topic| !topic.is_wildcard())
+            .map(MqttTopic::unwrap)
+    }
+    /// Reads the configuration from a TOML or YAML file, told apart by its extension.
+    /// Settings that are not serializable, like the auth providers, keep their default.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, ServerError> {
+        let path = path.as_ref();
+        let invalid = |e: &dyn std::fmt::Display| {
+            ServerError::InvalidConfig(format!("cannot load {}, {}", path.display(), e))
+        };
+        let content = std::fs::read_to_string(path).map_err(|e| invalid(&e))?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| invalid(&e)),
+            Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(|e| invalid(&e)),
+            _ => Err(invalid(&"expected a .toml, .yaml or .yml file")),
+        }
+    }
+    /// The configuration as JSON with the secrets, such as the Noise private key, redacted.
+    /// Settings that are not serializable, like the auth providers, are left out.
+    pub fn snapshot(&self) -> Result<String, ServerError> {
+        let mut value = serde_json::to_value(self)
+            .map_err(|e| ServerError::Misc(format!("cannot serialize config, {}", e)))?;
+        if let Some(fields) = value.as_object_mut() {
+            for field in SECRET_FIELDS {
+                if let Some(secret) = fields.get_mut(*field) {
+                    *secret = REDACTED.into();
+                }
+            }
+        }
+        Ok(value.to_string())
+    }
+    /// Checks for values the broker cannot run with
+    pub fn validate(&self) -> Result<(), ServerError> {
+        if self.max_qos > MAX_QOS {
+            return Err(ServerError::InvalidSetting {
+                field: "max_qos",
+                reason: format!(
+                    "{} is above the highest supported QoS {}",
+                    self.max_qos, MAX_QOS
+                ),
+            });
+        }
+        if matches!(&self.heartbeat, Some(heartbeat) if heartbeat.interval == 0) {
+            return Err(ServerError::InvalidSetting {
+                field: "heartbeat",
+                reason: "interval must be at least one second".to_owned(),
+            });
+        }
+        if self.topic_tree_sweep == Some(0) {
+            return Err(ServerError::InvalidSetting {
+                field: "topic_tree_sweep",
+                reason: "must be at least one second".to_owned(),
+            });
+        }
+        if self.storage.is_some() && self.storage_path.is_some() {
+            return Err(ServerError::InvalidSetting {
+                field: "storage_path",
+                reason: "cannot be set along with a storage".to_owned(),
+            });
+        }
+        if self.handshake_timeout == 0 {
+            return Err(ServerError::InvalidSetting {
+                field: "handshake_timeout",
+                reason: "must be at least one second".to_owned(),
+            });
+        }
+        if self.persist_interval == 0 {
+            return Err(ServerError::InvalidSetting {
+                field: "persist_interval",
+                reason: "must be at least one second".to_owned(),
+            });
+        }
+        if matches!(&self.connect_rate_per_ip, Some(rate) if rate.per_second == 0 || rate.burst == 0)
+        {
+            return Err(ServerError::InvalidSetting {
+                field: "connect_rate_per_ip",
+                reason: "per_second and burst must be at least 1".to_owned(),
+            });
+        }
+        self.validate_publish_quotas()?;
+        self.validate_listeners()?;
+        if self.max_connections == Some(0) {
+            return Err(ServerError::InvalidSetting {
+                field: "max_connections",
+                reason: "must be at least 1".to_owned(),
+            });
+        }
+        if matches!(&self.cluster, Some(cluster) if cluster.gossip_interval_ms == 0) {
+            return Err(ServerError::InvalidSetting {
+                field: "cluster",
+                reason: "gossip_interval_ms must be at least 1".to_owned(),
+            });
+        }
+        if matches!(&self.cluster, Some(cluster) if cluster.key.is_empty()) {
+            return Err(ServerError::InvalidSetting {
+                field: "cluster",
+                reason: "key must not be empty".to_owned(),
+            });
+        }
+        match &self.replication {
+            Some(Replication::Primary { max_lag_ms: 0, .. }) => {
+                return Err(ServerError::InvalidSetting {
+                    field: "replication",
+                    reason: "max_lag_ms must be at least 1".to_owned(),
+                });
+            }
+            Some(Replication::Primary { key, .. } | Replication::Standby { key, .. })
+                if key.is_empty() =>
+            {
+                return Err(ServerError::InvalidSetting {
+                    field: "replication",
+                    reason: "key must not be empty".to_owned(),
+                });
+            }
+            Some(Replication::Standby { .. })
+                if self.session_policy != SessionPolicy::RetainUntilExpiry =>
+            {
+                return Err(ServerError::InvalidSetting {
+                    field: "replication",
+                    reason: "a standby needs the RetainUntilExpiry session policy".to_owned(),
+                });
+            }
+            _ => (),
+        }
+        #[cfg(feature = "noise")]
+        self.validate_noise()?;
+        if self.dispatcher_workers == 0 {
+            return Err(ServerError::InvalidSetting {
+                field: "dispatcher_workers",
+                reason: "must be at least 1".to_owned(),
+            });
+        }
+        if self.response_information.is_some() && self.response_information("c").is_none() {
+            return Err(ServerError::InvalidSetting {
+                field: "response_information",
+                reason: "must be a topic name without wildcards".to_owned(),
+            });
+        }
+        if self.authorizer.is_some() && self.acl_path.is_some() {
+            return Err(ServerError::InvalidSetting {
+                field: "acl_path",
+                reason: "cannot be set along with an authorizer".to_owned(),
+            });
+        }
+        let user_properties =
+            self.connack_user_properties()
+                .map_err(|e| ServerError::InvalidSetting {
+                    field: "connack_user_properties",
+                    reason: format!("{:?}", e),
+                })?;
+        let mut connack = ConnAck::new();
+        for prop in user_properties {
+            connack.add_prop(Property::UserProperty, prop)?;
+        }
+        if connack.build().frame_len() > self.max_packet_size as usize {
+            return Err(ServerError::InvalidSetting {
+                field: "connack_user_properties",
+                reason: "do not fit within max_packet_size".to_owned(),
+            });
+        }
+        Ok(())
+    }
+    /// How the topic filters of the clients are checked
+    pub(crate) fn filter_rules(&self) -> FilterRules {
+        FilterRules {
+            empty_levels: self.empty_filter_levels,
+        }
+    }
+    fn validate_publish_quotas(&self) -> Result<(), ServerError> {
+        let quotas = [
+            &self.publish_quotas.mqtt,
+            #[cfg(feature = "noise")]
+            &self.publish_quotas.noise,
+            #[cfg(feature = "websocket")]
+            &self.publish_quotas.multiplex,
+        ];
+        let listeners = self.listeners.iter().map(|l| &l.publish_quota);
+        for quota in quotas.into_iter().chain(listeners).flatten() {
+            let reason = if quota.burst_seconds == 0 {
+                "burst_seconds must be at least 1"
+            } else if quota.messages_per_second == Some(0) || quota.bytes_per_second == Some(0) {
+                "rates must be at least 1"
+            } else {
+                continue;
+            };
+            return Err(ServerError::InvalidSetting {
+                field: "publish_quotas",
+                reason: reason.to_owned(),
+            });
+        }
+        Ok(())
+    }
+    fn validate_listeners(&self) -> Result<(), ServerError> {
+        let builtin = ["mqtt", "noise", "multiplex"];
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let reason = if builtin.contains(&&*listener.tag)
+                || self.listeners[..i].iter().any(|l| l.tag == listener.tag)
+            {
+                format!("tag {} is already used", listener.tag)
+            } else if listener.max_connections == Some(0) {
+                "max_connections must be at least 1".to_owned()
+            } else if matches!(listener.max_qos, Some(qos) if qos > MAX_QOS) {
+                format!("max_qos is above the highest supported QoS {}", MAX_QOS)
+            } else if listener.max_packet_size == Some(0) {
+                "max_packet_size must be at least 1".to_owned()
+            } else {
+                continue;
+            };
+            return Err(ServerError::InvalidSetting {
+                field: "listeners",
+                reason,
+            });
+        }
+        Ok(())
+    }
+    #[cfg(feature = "noise")]
+    fn validate_noise(&self) -> Result<(), ServerError> {
+        // an all zero key is the default one, never meant to be used
+        if self.noise_socketaddr.is_some() && self.private_key == [0; 32] {
+            return Err(ServerError::InvalidSetting {
+                field: "private_key",
+                reason: "must be set along with noise_socketaddr".to_owned(),
+            });
+        }
+        let invalid = |reason: &str| ServerError::InvalidSetting {
+            field: "noise_pattern",
+            reason: reason.to_owned(),
+        };
+        let params: snow::params::NoiseParams = self
+            .noise_pattern
+            .parse()
+            .map_err(|e| invalid(&format!("{:?}", e)))?;
+        let pattern = params.handshake.pattern;
+        if pattern.is_oneway() {
+            return Err(invalid("one-way patterns cannot carry MQTT"));
+        }
+        if params.handshake.is_psk() {
+            return Err(invalid("pre-shared keys are not supported"));
+        }
+        if !matches!(params.dh, snow::params::DHChoice::Curve25519) {
+            return Err(invalid("only 25519 keys are supported"));
+        }
+        // a client key known beforehand cannot be one of several accepted keys
+        if pattern.need_known_remote_pubkey(false) {
+            return Err(invalid(
+                "the broker cannot know the key of a client in advance",
+            ));
+        }
+        match &self.noise_accepted_keys {
+            Some(keys) if keys.is_empty() => Err(ServerError::InvalidSetting {
+                field: "noise_accepted_keys",
+                reason: "must accept at least one key".to_owned(),
+            }),
+            Some(_) if !pattern.needs_local_static_key(true) => Err(ServerError::InvalidSetting {
+                field: "noise_accepted_keys",
+                reason: format!(
+                    "{} does not transmit the key of the clients",
+                    pattern.as_str()
+                ),
+            }),
+            _ => Ok(()),
+        }
+    }
+    fn listener_addrs(&self) -> Vec<SocketAddr> {
+        [
+            self.mqtt_socketaddr,
+            #[cfg(feature = "noise")]
+            self.noise_socketaddr,
+            #[cfg(feature = "websocket")]
+            self.multiplex_socketaddr,
+            #[cfg(feature = "admin")]
+            self.admin_socketaddr,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.listeners.iter().map(|l| l.socketaddr))
+        .collect()
+    }
+    /// Checks that a broker could start with this configuration by binding every listener,
+    /// opening the message id storage and loading the keys, then releases all of them
+    /// without serving any traffic
+    pub async fn check(&self) -> Result<(), ServerError> {
+        self.validate()?;
+        for saddr in self.listener_addrs() {
+            TcpListener::bind(saddr)
+                .await
+                .map_err(|e| ServerError::InvalidConfig(format!("cannot bind {}, {}", saddr, e)))?;
+            info!(SocketAddr = &*format!("{}", saddr), "Listener check passed");
+        }
+        MessageIds::open(self.message_id_path.clone()).map_err(|e| {
+            ServerError::InvalidSetting {
+                field: "message_id_path",
+                reason: format!("cannot open message id storage, {}", e),
+            }
+        })?;
+        if let Some(path) = &self.acl_path {
+            AclFile::load(path)?;
+        }
+        if let Some(path) = &self.storage_path {
+            FileStorage::open(path.clone()).map_err(|e| ServerError::InvalidSetting {
+                field: "storage_path",
+                reason: format!("cannot open storage, {}", e),
+            })?;
+        }
+        #[cfg(feature = "noise")]
+        snow::Builder::new(self.noise_pattern.parse()?)
+            .local_private_key(&self.private_key[..])
+            .build_responder()
+            .map_err(|e| ServerError::InvalidSetting {
+                field: "private_key",
+                reason: format!("{:?}", e),
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_validate_connack_user_properties() {
+        let mut cfg = MqttServerConfig::default();
+        assert!(cfg.validate().is_ok());
+        cfg.connack_user_properties = vec![("contact".to_owned(), "ops@example.com".to_owned())];
+        assert!(cfg.validate().is_ok());
+        cfg.connack_user_properties = vec![("terms".to_owned(), "x".repeat(70000))];
+        assert!(matches!(
+            cfg.validate(),
+            Err(ServerError::InvalidSetting {
+                field: "connack_user_properties",
+                ..
+            })
+        ));
+        cfg.connack_user_properties = vec![("terms".to_owned(), "x".repeat(200))];
+        cfg.max_packet_size = 128;
+        assert!(matches!(
+            cfg.validate(),
+            Err(ServerError::InvalidSetting {
+                field: "connack_user_properties",
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn test_validate_max_qos() {
+        let cfg = MqttServerConfig {
+            max_qos: MAX_QOS + 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            cfg.validate(),
+            Err(ServerError::InvalidSetting {
+                field: "max_qos",
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn test_validate_listeners() {
+        let listener: ListenerConfig =
+            toml::from_str("tag = \"sensors\"\nsocketaddr = \"127.0.0.1:1885\"\nmax_qos = 0")
+                .unwrap();
+        assert!(!listener.require_auth);
+        assert!(listener.max_packet_size.is_none());
+        let mut cfg = MqttServerConfig {
+            listeners: vec![listener.clone()],
+            ..Default::default()
+        };
+        assert!(cfg.validate().is_ok());
+        let invalid = |cfg: &MqttServerConfig| {
+            matches!(
+                cfg.validate(),
+                Err(ServerError::InvalidSetting {
+                    field: "listeners",
+                    ..
+                })
+            )
+        };
+        cfg.listeners.push(listener.clone());
+        assert!(invalid(&cfg));
+        cfg.listeners[1].tag = "mqtt".to_owned();
+        assert!(invalid(&cfg));
+        cfg.listeners[1].tag = "admins".to_owned();
+        cfg.listeners[1].max_qos = Some(MAX_QOS + 1);
+        assert!(invalid(&cfg));
+        cfg.listeners[1].max_qos = None;
+        cfg.listeners[1].max_connections = Some(0);
+        assert!(invalid(&cfg));
+    }
+    #[cfg(feature = "noise")]
+    #[test]
+    fn test_validate_noise() {
+        let mut cfg = MqttServerConfig {
+            noise_pattern: "Noise_XX_25519_ChaChaPoly_BLAKE2s".to_owned(),
+            noise_accepted_keys: Some(vec![[1; 32]]),
+            ..Default::default()
+        };
+        assert!(cfg.validate().is_ok());
+        for pattern in [
+            "Noise_X_25519_ChaChaPoly_BLAKE2s",
+            "Noise_KK_25519_ChaChaPoly_BLAKE2s",
+            "Noise_XKpsk3_25519_ChaChaPoly_BLAKE2s",
+            "Noise_XK_448_ChaChaPoly_BLAKE2s",
+            "XK",
+        ] {
+            cfg.noise_pattern = pattern.to_owned();
+            assert!(matches!(
+                cfg.validate(),
+                Err(ServerError::InvalidSetting {
+                    field: "noise_pattern",
+                    ..
+                })
+            ));
+        }
+        // the client stays anonymous
+        cfg.noise_pattern = "Noise_NK_25519_ChaChaPoly_BLAKE2s".to_owned();
+        assert!(matches!(
+            cfg.validate(),
+            Err(ServerError::InvalidSetting {
+                field: "noise_accepted_keys",
+                ..
+            })
+        ));
+        cfg.noise_accepted_keys = None;
+        assert!(cfg.validate().is_ok());
+        cfg.noise_socketaddr = "127.0.0.1:8883".parse().ok();
+        assert!(matches!(
+            cfg.validate(),
+            Err(ServerError::InvalidSetting {
+                field: "private_key",
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn test_validate_connection_limits() {
+        let mut cfg = MqttServerConfig {
+            connect_rate_per_ip: Some(IpConnectRate {
+                per_second: 0,
+                burst: 5,
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(
+            cfg.validate(),
+            Err(ServerError::InvalidSetting {
+                field: "connect_rate_per_ip",
+                ..
+            })
+        ));
+        cfg.connect_rate_per_ip = None;
+        cfg.publish_quotas.mqtt = Some(PublishQuota {
+            messages_per_second: Some(10),
+            bytes_per_second: None,
+            burst_seconds: 0,
+        });
+        assert!(matches!(
+            cfg.validate(),
+            Err(ServerError::InvalidSetting {
+                field: "publish_quotas",
+                ..
+            })
+        ));
+        cfg.publish_quotas.mqtt = None;
+        cfg.max_connections = Some(0);
+        assert!(matches!(
+            cfg.validate(),
+            Err(ServerError::InvalidSetting {
+                field: "max_connections",
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn test_validate_heartbeat() {
+        let mut cfg = MqttServerConfig {
+            heartbeat: Some(Heartbeat::default()),
+            ..Default::default()
+        };
+        assert!(cfg.validate().is_ok());
+        cfg.heartbeat = Some(Heartbeat {
+            interval: 0,
+            ..Default::default()
+        });
+        assert!(matches!(
+            cfg.validate(),
+            Err(ServerError::InvalidSetting {
+                field: "heartbeat",
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn test_response_information() {
+        let mut cfg = MqttServerConfig::default();
+        assert_eq!(cfg.response_information("a"), None);
+        cfg.response_information = Some("replies/%c".to_owned());
+        assert!(cfg.validate().is_ok());
+        assert_eq!(cfg.response_information("a").as_deref(), Some("replies/a"));
+        // the client id would make a wildcard out of it
+        assert_eq!(cfg.response_information("a/#"), None);
+        cfg.response_information = Some("replies/+/%c".to_owned());
+        assert!(matches!(
+            cfg.validate(),
+            Err(ServerError::InvalidSetting {
+                field: "response_information",
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn test_snapshot() {
+        let cfg = MqttServerConfig {
+            node_id: Some("node-1".to_owned()),
+            #[cfg(feature = "noise")]
+            private_key: [7; 32],
+            ..Default::default()
+        };
+        let snapshot = cfg.snapshot().unwrap();
+        assert!(snapshot.contains("\"node_id\":\"node-1\""));
+        assert!(snapshot.contains("\"keep_alive\":50"));
+        #[cfg(feature = "noise")]
+        assert!(snapshot.contains("\"private_key\":\"<redacted>\""));
+        assert!(!snapshot.contains("[7,7"));
+    }
+    fn config_file(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("apiformes-config-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+    #[test]
+    fn test_from_path() {
+        let toml = config_file(
+            "broker.toml",
+            r#"
+            mqtt_socketaddr = "127.0.0.1:1884"
+            max_packet_size = 4096
+            session_policy = "RetainUntilExpiry"
+            max_sessions = 100
+            channel_permeability = "Permissive"
+            private_key = [7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+                           7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7]
+
+            [retained_limits]
+            max_entries = 10
+            "#,
+        );
+        let cfg = MqttServerConfig::from_path(&toml).unwrap();
+        std::fs::remove_file(toml).unwrap();
+        assert_eq!(cfg.mqtt_socketaddr, "127.0.0.1:1884".parse().ok());
+        assert_eq!(cfg.max_packet_size, 4096);
+        assert!(cfg.session_policy == SessionPolicy::RetainUntilExpiry);
+        assert_eq!(cfg.max_sessions, Some(100));
+        assert_eq!(cfg.retained_limits.max_entries, Some(10));
+        // left out settings keep their default
+        assert!(cfg.retained_limits.eviction == RetainedEviction::LeastRecentlyUsed);
+        assert_eq!(cfg.keep_alive, 50);
+        #[cfg(feature = "noise")]
+        {
+            assert!(cfg.channel_permeability == Permeability::Permissive);
+            assert_eq!(cfg.private_key, [7; 32]);
+        }
+
+        let yaml = config_file(
+            "broker.yaml",
+            "mqtt_socketaddr: 127.0.0.1:1885\nmqtt_read:\n  buffer_size: 8192\n",
+        );
+        let cfg = MqttServerConfig::from_path(&yaml).unwrap();
+        std::fs::remove_file(yaml).unwrap();
+        assert_eq!(cfg.mqtt_socketaddr, "127.0.0.1:1885".parse().ok());
+        assert_eq!(cfg.mqtt_read.buffer_size, 8192);
+        assert!(!cfg.mqtt_read.coalesce_frames);
+        #[cfg(feature = "noise")]
+        {
+            let yaml = config_file("noise.yaml", "noise_read:\n  coalesce_frames: true\n");
+            let cfg = MqttServerConfig::from_path(&yaml).unwrap();
+            std::fs::remove_file(yaml).unwrap();
+            assert!(cfg.noise_read.coalesce_frames);
+            assert_eq!(cfg.noise_read.buffer_size, 0);
+        }
+
+        let unsupported = config_file("broker.json", "{}");
+        let invalid = config_file("invalid.toml", "max_qos = \"two\"");
+        let missing = std::env::temp_dir().join("apiformes-config-missing.yml");
+        for path in [unsupported, invalid, missing] {
+            assert!(matches!(
+                MqttServerConfig::from_path(&path),
+                Err(ServerError::InvalidConfig(_))
+            ));
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    #[tokio::test]
+    async fn test_check_binds_listeners() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = MqttServerConfig {
+            mqtt_socketaddr: Some(taken.local_addr().unwrap()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            cfg.check().await,
+            Err(ServerError::InvalidConfig(_))
+        ));
+        drop(taken);
+        assert!(cfg.check().await.is_ok());
+    }
 }
@@ -0,0 +1,225 @@
+use crate::topics::{push_matched, Matched, SubscriptionInfo};
+use arc_swap::ArcSwap;
+use std::collections::{hash_map::Entry, HashMap};
+use std::sync::{Arc, Mutex};
+type ClientId = Arc<str>;
+type SubTopic = Arc<str>;
+type Subscribers = HashMap<ClientId, SubscriptionInfo>;
+
+/// One level of a snapshot, never modified once published. The subscriber maps are behind
+/// their own `Arc` so copying a node does not copy its subscriptions.
+#[derive(Default, Clone)]
+struct Node {
+    subscribers: Arc<Subscribers>,
+    hash_wildcard: Arc<Subscribers>,
+    // the `+` wild card is stored in here
+    children: HashMap<SubTopic, Arc<Node>>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.subscribers.is_empty() && self.hash_wildcard.is_empty() && self.children.is_empty()
+    }
+    fn collect<'a>(
+        &self,
+        subs: &mut Subscribers,
+        mut sections: impl Iterator<Item = &'a str> + Clone,
+    ) {
+        merge(subs, &self.hash_wildcard);
+        match sections.next() {
+            Some(section) => {
+                if let Some(child) = self.children.get("+") {
+                    child.collect(subs, sections.clone());
+                }
+                if let Some(child) = self.children.get(section) {
+                    child.collect(subs, sections);
+                }
+            }
+            None => merge(subs, &self.subscribers),
+        }
+    }
+    fn collect_matched<'a>(
+        &self,
+        found: &mut Vec<Matched>,
+        levels: &mut Vec<&'a str>,
+        mut sections: impl Iterator<Item = &'a str> + Clone,
+    ) {
+        levels.push("#");
+        push_matched(found, levels, &self.hash_wildcard);
+        levels.pop();
+        match sections.next() {
+            Some(section) => {
+                for level in ["+", section] {
+                    if let Some(child) = self.children.get(level) {
+                        levels.push(level);
+                        child.collect_matched(found, levels, sections.clone());
+                        levels.pop();
+                    }
+                }
+            }
+            None => push_matched(found, levels, &self.subscribers),
+        }
+    }
+    /// A copy of this node with `change` applied to the subscribers at the end of
+    /// `sections`, returns the number of nodes created minus the number of nodes dropped
+    /// for being empty
+    fn with<'a>(
+        &self,
+        mut sections: impl Iterator<Item = &'a str>,
+        change: &impl Fn(&mut Subscribers),
+    ) -> (Node, i64) {
+        let mut node = self.clone();
+        let section = match sections.next() {
+            Some("#") => {
+                change(Arc::make_mut(&mut node.hash_wildcard));
+                return (node, 0);
+            }
+            None => {
+                change(Arc::make_mut(&mut node.subscribers));
+                return (node, 0);
+            }
+            Some(section) => section,
+        };
+        let (child, mut nodes) = match self.children.get(section) {
+            Some(child) => child.with(sections, change),
+            None => {
+                let (child, nodes) = Node::default().with(sections, change);
+                (child, nodes + 1)
+            }
+        };
+        if child.is_empty() {
+            // also undoes the creation of a node for a removal below a missing one
+            node.children.remove(section);
+            nodes -= 1;
+        } else {
+            let key = match self.children.get_key_value(section) {
+                Some((key, _)) => key.clone(),
+                None => Arc::from(section),
+            };
+            node.children.insert(key, Arc::new(child));
+        }
+        (node, nodes)
+    }
+    fn get<'a>(
+        &self,
+        mut sections: impl Iterator<Item = &'a str>,
+        clientid: &str,
+    ) -> Option<SubscriptionInfo> {
+        match sections.next() {
+            Some("#") => self.hash_wildcard.get(clientid).cloned(),
+            None => self.subscribers.get(clientid).cloned(),
+            Some(section) => self.children.get(section)?.get(sections, clientid),
+        }
+    }
+}
+
+/// Keeps the subscription with the highest QoS of each client
+fn merge(subs: &mut Subscribers, from: &Subscribers) {
+    for (clientid, info) in from {
+        match subs.entry(clientid.clone()) {
+            Entry::Vacant(e) => {
+                e.insert(info.clone());
+            }
+            Entry::Occupied(mut e) => {
+                if e.get().qos < info.qos {
+                    e.insert(info.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Subscription tree published as immutable snapshots: readers take the current snapshot
+/// without locking while a change copies the nodes on the path to its topic filter and
+/// swaps the root. Empty nodes are dropped as soon as they are left empty.
+pub(crate) struct CowTree {
+    root: ArcSwap<Node>,
+    // changes are applied one at a time, readers are never blocked
+    writer: Mutex<()>,
+}
+
+impl CowTree {
+    pub(crate) fn new() -> Self {
+        CowTree {
+            root: ArcSwap::from_pointee(Node::default()),
+            writer: Mutex::new(()),
+        }
+    }
+    /// Applies `change` to the subscribers of the filter split in `sections`, returns the
+    /// number of nodes created minus the number of nodes dropped
+    fn update<'a>(
+        &self,
+        sections: impl Iterator<Item = &'a str>,
+        change: impl Fn(&mut Subscribers),
+    ) -> i64 {
+        let _writer = self.writer.lock().unwrap();
+        let (root, nodes) = self.root.load().with(sections, &change);
+        self.root.store(Arc::new(root));
+        nodes
+    }
+    /// Returns the number of nodes created
+    pub(crate) fn insert<'a>(
+        &self,
+        sections: impl Iterator<Item = &'a str>,
+        clientid: ClientId,
+        info: SubscriptionInfo,
+    ) -> u64 {
+        let nodes = self.update(sections, |subs| {
+            subs.insert(clientid.clone(), info.clone());
+        });
+        nodes.max(0) as u64
+    }
+    /// Returns the number of nodes dropped
+    pub(crate) fn remove<'a>(
+        &self,
+        sections: impl Iterator<Item = &'a str>,
+        clientid: &str,
+    ) -> u64 {
+        let nodes = self.update(sections, |subs| {
+            subs.remove(clientid);
+        });
+        (-nodes).max(0) as u64
+    }
+    pub(crate) fn get<'a>(
+        &self,
+        sections: impl Iterator<Item = &'a str>,
+        clientid: &str,
+    ) -> Option<SubscriptionInfo> {
+        self.root.load().get(sections, clientid)
+    }
+    pub(crate) fn collect<'a>(
+        &self,
+        sections: impl Iterator<Item = &'a str> + Clone,
+    ) -> Subscribers {
+        let mut subs = HashMap::new();
+        let root = self.root.load();
+        match sections.clone().next() {
+            // 4.7.2: filters starting with a wildcard do not match topics starting with `$`
+            Some(first) if first.starts_with('$') => {
+                if let Some(child) = root.children.get(first) {
+                    child.collect(&mut subs, sections.skip(1));
+                }
+            }
+            _ => root.collect(&mut subs, sections),
+        }
+        subs
+    }
+    /// Adds every subscription matching the topic split in `sections` to `found` along with
+    /// its filter
+    pub(crate) fn collect_matched<'a>(
+        &self,
+        found: &mut Vec<Matched>,
+        sections: impl Iterator<Item = &'a str> + Clone,
+    ) {
+        let root = self.root.load();
+        match sections.clone().next() {
+            // 4.7.2, see `collect`
+            Some(first) if first.starts_with('$') => {
+                if let Some(child) = root.children.get(first) {
+                    child.collect_matched(found, &mut vec![first], sections.skip(1));
+                }
+            }
+            _ => root.collect_matched(found, &mut Vec::new(), sections),
+        }
+    }
+}
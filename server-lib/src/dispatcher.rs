@@ -1,90 +1,289 @@
-#[cfg(feature = "noise")]
-use super::Permeability;
+#[cfg(feature = "edge-filter")]
+use super::filter::{PayloadFilter, FILTER_PROPERTY};
 use super::{
-    topics::{SubscriptionFlags, TopicsTable},
-    Client, MqttServerConfig, ServerError,
+    acl::{Action, Identity},
+    cluster::Router,
+    intercept,
+    lanes::{Fanout, FanoutJob, Lanes},
+    metrics::Metrics,
+    msgid::{MessageIds, MESSAGE_ID_PROPERTY},
+    shutdown::Shutdown,
+    sys::SysTopics,
+    throttle::LogThrottle,
+    topics::{split_shared, SubscriptionFlags, TopicsTable},
+    trace::{PublishTracer, TraceStage},
+    unmatched::UnmatchedPublishes,
+    validate::validate,
+    Client, DispatcherSharding, MqttServerConfig, ServerError,
+};
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender},
+    RwLock,
 };
-use tokio::sync::{mpsc::Receiver, Notify, RwLock};
 use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
 
 use super::packetinfo::PacketInfo;
 use apiformes_packet::prelude::*;
-use std::collections::HashMap;
+use std::collections::{hash_map::DefaultHasher, HashMap};
 use std::convert::TryInto;
+use std::hash::{Hash, Hasher};
+use std::mem::{discriminant, size_of, Discriminant};
 use std::sync::Arc;
 use tracing::{error, instrument, trace, warn};
 
 pub struct Dispatcher {
     topics: Arc<TopicsTable>,
+    sys: Arc<SysTopics>,
+    fanout: Fanout,
+    metrics: Arc<Metrics>,
+    // empty until the dispatcher is spawned, shared by all the workers
+    lanes: Arc<Lanes>,
     cfg: Arc<MqttServerConfig>,
-    shutdown: Arc<Notify>,
+    shutdown: Shutdown,
     clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
     incoming: Receiver<PacketInfo>,
+    // only set when forwarded publishes are stamped with a message id
+    message_ids: Option<Arc<MessageIds>>,
+    // the retained messages and the kept unmatched publishes
+    unmatched: Arc<UnmatchedPublishes>,
+    // refusals logged while processing a packet
+    warnings: LogThrottle,
+    // errors ending the processing of a packet, by kind
+    errors: LogThrottle<Discriminant<ServerError>>,
+    tracer: Option<Arc<PublishTracer>>,
+    // only set when the broker is part of a cluster
+    cluster: Option<Arc<Router>>,
 }
 
 impl Dispatcher {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         topics: Arc<TopicsTable>,
+        sys: Arc<SysTopics>,
+        metrics: Arc<Metrics>,
         cfg: Arc<MqttServerConfig>,
-        shutdown: Arc<Notify>,
+        shutdown: Shutdown,
         clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
         incoming: Receiver<PacketInfo>,
+        unmatched: Arc<UnmatchedPublishes>,
     ) -> Self {
+        let mut fanout = Fanout::new(
+            topics.clone(),
+            clients.clone(),
+            metrics.clone(),
+            cfg.clone(),
+        );
+        if !cfg.unmatched_publishes.is_empty() {
+            fanout = fanout.keep_unmatched(unmatched.clone());
+        }
+        let log_window = Duration::from_secs(cfg.log_throttle as u64);
         Dispatcher {
+            fanout,
+            metrics,
+            lanes: Arc::default(),
             topics,
+            sys,
             cfg,
             shutdown,
             clients,
             incoming,
+            message_ids: None,
+            unmatched,
+            warnings: LogThrottle::new(log_window),
+            errors: LogThrottle::new(log_window),
+            tracer: None,
+            cluster: None,
+        }
+    }
+    /// Stamps every forwarded publish with an id taken from `message_ids`
+    pub fn stamp_message_ids(mut self, message_ids: Arc<MessageIds>) -> Self {
+        self.message_ids = Some(message_ids);
+        self
+    }
+    /// Records the stages of the publishes traced by `tracer`
+    pub fn trace_publishes(mut self, tracer: Arc<PublishTracer>) -> Self {
+        self.fanout = self.fanout.trace_publishes(tracer.clone());
+        self.tracer = Some(tracer);
+        self
+    }
+    /// Forwards the publishes to the peers of `cluster` hosting matching subscriptions
+    pub(crate) fn forward_to_peers(mut self, cluster: Arc<Router>) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+    /// A worker sharing everything with this dispatcher but its queue and log throttles
+    fn worker(&self, incoming: Receiver<PacketInfo>) -> Self {
+        let log_window = Duration::from_secs(self.cfg.log_throttle as u64);
+        Dispatcher {
+            topics: self.topics.clone(),
+            sys: self.sys.clone(),
+            fanout: self.fanout.clone(),
+            metrics: self.metrics.clone(),
+            lanes: self.lanes.clone(),
+            cfg: self.cfg.clone(),
+            shutdown: self.shutdown.clone(),
+            clients: self.clients.clone(),
+            incoming,
+            message_ids: self.message_ids.clone(),
+            unmatched: self.unmatched.clone(),
+            warnings: LogThrottle::new(log_window),
+            errors: LogThrottle::new(log_window),
+            tracer: self.tracer.clone(),
+            cluster: self.cluster.clone(),
         }
     }
+    fn node_id(&self) -> &str {
+        self.cfg.node_id.as_deref().unwrap_or_default()
+    }
     async fn unimplemented(&mut self, client: &str) -> Result<(), ServerError> {
-        let disconnect = Disconnect::new(DisconnectReasonCode::ImplementationSpecificError).build();
+        let err = ServerError::Misc("Unimplemented".to_owned());
         let clients = self.clients.read().await;
         let c = clients.get(client).unwrap();
+        let disconnect = c.disconnect(DisconnectReasonCode::ImplementationSpecificError, &err);
         if c.send(disconnect).is_err() {
             error!(clientid = client, "Internal Error: tx closed");
         }
-        Err(ServerError::Misc("Unimplemented".to_owned()))
+        Err(err)
+    }
+
+    /// Asks the configured authorizer, trusted internal clients and servers without one
+    /// allow everything
+    fn authorized(&self, client: &Client, action: Action, topic: &str) -> bool {
+        let authorizer = match &self.cfg.authorizer {
+            Some(authorizer) if !client.acl_exempt() => authorizer,
+            _ => return true,
+        };
+        let identity = Identity {
+            clientid: client.clientid(),
+            username: client.username(),
+        };
+        if authorizer.authorize(&identity, action, topic) {
+            return true;
+        }
+        if let Some(suppressed) = self.warnings.admit("not authorized", Instant::now()) {
+            warn!(
+                clientid = &**client.clientid(),
+                topic, suppressed, "Refusing {:?}, not authorized", action
+            );
+        }
+        self.metrics.inc_acl_denied();
+        false
+    }
+
+    /// Whether `sender` may publish `publish` and its payload is valid for its topic. The
+    /// refusal is answered to the sender on the PUBACK of `id`, or by disconnecting it at
+    /// QoS 0 for an invalid payload. `Ok(false)` for a publish silently dropped.
+    fn admit_publish(
+        &self,
+        sender: &Client,
+        publish: &Publish,
+        id: Option<u16>,
+    ) -> Result<bool, ServerError> {
+        let client = &**sender.clientid();
+        let topic = publish.topic_name();
+        if !self.authorized(sender, Action::Publish, topic) {
+            // QoS 0 publishes cannot be acknowledged, they are silently dropped
+            if let Some(id) = id {
+                let mut puback = PubAck::new(id);
+                puback.set_reason_code(PubAckReasonCode::NotAuthorized);
+                if sender.send(puback.build()).is_err() {
+                    trace!(clientid = client, "client shutdown: tx closed");
+                }
+            }
+            return Ok(false);
+        }
+        if let Err(reason) = validate(&self.cfg.payload_validators, topic, &publish.payload()) {
+            if let Some(suppressed) = self.warnings.admit("invalid payload", Instant::now()) {
+                warn!(
+                    clientid = client,
+                    topic = &**topic,
+                    suppressed,
+                    "Refusing invalid payload, {}",
+                    reason
+                );
+            }
+            self.metrics.inc_payloads_rejected();
+            let err = ServerError::PayloadFormatInvalid(reason);
+            // QoS 0 publishes cannot be acknowledged, their publisher is disconnected
+            let response = match id {
+                Some(id) => {
+                    let mut puback = PubAck::new(id);
+                    puback.set_reason_code(PubAckReasonCode::PayloadFormatInvalid);
+                    puback.build()
+                }
+                None => sender.disconnect(DisconnectReasonCode::PayloadFormatInvalid, &err),
+            };
+            if sender.send(response).is_err() {
+                trace!(clientid = client, "client shutdown: tx closed");
+            }
+            return Err(err);
+        }
+        Ok(true)
     }
 
     #[instrument(skip_all)]
-    async fn process_publish(&mut self, client: &str, publish: Publish) -> Result<(), ServerError> {
+    async fn process_publish(
+        &mut self,
+        client: &str,
+        publish: Publish,
+        traced: bool,
+    ) -> Result<(), ServerError> {
         trace!("Processing a publish packet");
-        #[cfg(feature = "noise")]
-        let strict_encryption = {
-            let clients = self.clients.read().await;
-            match clients.get(client) {
-                Some(c) => c.encrypted() && self.cfg.channel_permeability == Permeability::Strict,
-                None => {
-                    warn!(
-                        clientid = client,
-                        "Client Prematurely shutdown before its publish request could be processed"
-                    );
-                    return Ok(());
-                }
+        let sender = match self.clients.read().await.get(client) {
+            Some(c) => c.clone(),
+            None => {
+                warn!(
+                    clientid = client,
+                    "Client Prematurely shutdown before its publish request could be processed"
+                );
+                return Ok(());
             }
         };
+        // 3.2.2.3.4: publishing above the MaximumQoS advertised in the CONNACK
+        if publish.qos() > sender.max_qos() {
+            let err = ServerError::QoSNotSupported(publish.qos().as_u8());
+            let disconnect = sender.disconnect(DisconnectReasonCode::QoSNotSupported, &err);
+            if sender.send(disconnect).is_err() {
+                trace!(clientid = client, "client shutdown: tx closed");
+            }
+            return Err(err);
+        }
+        if let Err(code) = sender.charge_publish(publish.payload().len()) {
+            self.metrics.inc_publishes_over_quota();
+            let err = ServerError::PublishQuotaExceeded(code as u8);
+            if sender.send(sender.disconnect(code, &err)).is_err() {
+                trace!(clientid = client, "client shutdown: tx closed");
+            }
+            return Err(err);
+        }
         match publish.qos() {
             QoS::QoS0 => (),
-            QoS::QoS1 => return self.unimplemented(client).await,
+            QoS::QoS1 => (),
             QoS::QoS2 => return self.unimplemented(client).await,
         }
-        if publish
-            .flags()
-            .intersects(PublishFlags::DUP | PublishFlags::RETAIN)
-        {
-            return self.unimplemented(client).await;
+        // 3.3.1.1: the retransmissions still pending were discarded by the client worker,
+        // the others are forwarded again as QoS 1 promises at least once delivery. The
+        // DUP flag is not forwarded, it is set by each hop on its own retransmissions.
+        if !self.admit_publish(&sender, &publish, publish.packet_identifier())? {
+            return Ok(());
         }
         let topic = publish.topic_name();
+        self.metrics.inc_publishes_received();
         let mut response = Publish::new(topic.clone(), publish.payload()).unwrap();
+        response.set_qos(publish.qos());
+        if publish.flags().contains(PublishFlags::RETAIN) {
+            response.set_retain();
+        }
         for (k, v) in publish.props_iter() {
             match k {
                 Property::PayloadFormatIndicator => response
                     .add_prop(Property::PayloadFormatIndicator, v.clone())
                     .unwrap(),
                 Property::MessageExpiryInterval => return self.unimplemented(client).await,
-                Property::TopicAlias => return self.unimplemented(client).await,
+                // only names a topic on the connection it came from: the client workers
+                // refuse them (`topic_alias_valid`), those of internal publishes are dropped
+                Property::TopicAlias => (),
                 Property::ResponseTopic => response
                     .add_prop(Property::ResponseTopic, v.clone())
                     .unwrap(),
@@ -104,34 +303,70 @@ impl Dispatcher {
                 ),
             }
         }
-        let resp = response.build();
-        let clients = self.clients.read().await;
-
-        for (target, info) in self.topics.get_all_subscribed(topic).await {
-            if target.as_ref() == client && info.flags.contains(SubscriptionFlags::NO_LOCAL) {
-                continue;
-            }
-            if info.flags.contains(SubscriptionFlags::RETAIN_AS_PUBLISHED) {
-                unimplemented!();
-            }
-            if let Some(c) = clients.get(&target) {
-                #[cfg(feature = "noise")]
-                if strict_encryption && !c.encrypted() {
-                    continue;
-                }
-                match info.qos {
-                    QoS::QoS0 => {
-                        if c.send(resp.clone()).is_err() {
-                            trace!(clientid = target.as_ref(), "client shutdown: tx closed");
-                        };
+        if let Some(ids) = &self.message_ids {
+            let id = format!("{}:{}", self.node_id(), ids.next()?);
+            response.add_prop(
+                Property::UserProperty,
+                MqttPropValue::new_string_pair(Arc::from(MESSAGE_ID_PROPERTY), Arc::from(id))?,
+            )?;
+        }
+        let response = match intercept::publish(&self.cfg.interceptors, &sender, response) {
+            Some(response) => response,
+            None => {
+                trace!(clientid = client, "Publish dropped by an interceptor");
+                if let Some(id) = publish.packet_identifier() {
+                    if sender.send(PubAck::new(id).build()).is_err() {
+                        trace!(clientid = client, "client shutdown: tx closed");
                     }
-                    _ => unimplemented!(),
                 }
+                return Ok(());
             }
+        };
+        // the interceptors may have changed the topic or the payload of the publish
+        if !self.cfg.interceptors.is_empty()
+            && !self.admit_publish(&sender, &response, publish.packet_identifier())?
+        {
+            return Ok(());
+        }
+        if response.flags().contains(PublishFlags::RETAIN) {
+            self.unmatched.publish_retained(&response, Instant::now());
+        }
+        let topic = response.topic_name().clone();
+        if let Some(cluster) = &self.cluster {
+            cluster.forward(client, &response);
+        }
+        let job = FanoutJob {
+            senderid: Arc::from(client),
+            topic: topic.clone(),
+            packet: Arc::new(response.build()),
+            ack: publish.packet_identifier(),
+            traced,
+            #[cfg(feature = "noise")]
+            encrypted: sender.encrypted(),
+        };
+        match self.lanes.route(&topic) {
+            Some(lane) => lane
+                .send(job)
+                .await
+                .map_err(|_| ServerError::Misc("Fan-out lane is not running".to_owned())),
+            None => self.fanout.run(&job).await,
         }
-        Ok(())
     }
 
+    #[cfg(feature = "edge-filter")]
+    fn is_filter_property(v: &MqttPropValue) -> bool {
+        matches!(v.into_str_pair(), Some((name, _)) if &**name == FILTER_PROPERTY)
+    }
+    /// The payload filter requested through the user properties of `sub`
+    #[cfg(feature = "edge-filter")]
+    fn payload_filter(&self, sub: &Subscribe) -> Result<Option<Arc<PayloadFilter>>, ServerError> {
+        let specs = sub
+            .props_iter()
+            .filter(|(_, v)| Self::is_filter_property(v))
+            .filter_map(|(_, v)| v.into_str_pair())
+            .map(|(_, value)| &**value);
+        Ok(PayloadFilter::parse(specs, &self.cfg.filter_quota)?.map(Arc::new))
+    }
     #[instrument(skip_all)]
     async fn process_subscribe(
         &mut self,
@@ -140,56 +375,190 @@ impl Dispatcher {
     ) -> Result<(), ServerError> {
         trace!("Processing a subscribe packet");
         let ident = sub.packet_identifier();
-        for (k, _) in sub.props_iter() {
+        for (k, v) in sub.props_iter() {
             match k {
                 Property::SubscriptionIdentifier => return self.unimplemented(client).await,
-                Property::UserProperty => {
-                    warn!(clientid = client.as_ref(), "Received unknown user property")
-                }
+                #[cfg(feature = "edge-filter")]
+                Property::UserProperty if Self::is_filter_property(v) => (),
+                Property::UserProperty => warn!(
+                    clientid = client.as_ref(),
+                    "Received unknown user property {:?}",
+                    v.into_str_pair()
+                ),
                 _ => error!(
                     "Internal Error: {:?} should not be part of publish packet",
                     k
                 ),
             }
         }
+        let subscriber = match self.clients.read().await.get(client) {
+            Some(c) => c.clone(),
+            None => return Ok(()),
+        };
+        #[cfg(feature = "edge-filter")]
+        let filter = match self.payload_filter(&sub) {
+            Ok(filter) => filter,
+            Err(e) => {
+                warn!(
+                    clientid = client.as_ref(),
+                    "Refusing payload filter, {:?}", e
+                );
+                let code = match e {
+                    ServerError::PayloadFilterQuotaExceeded => SubAckReasonCode::QuotaExceeded,
+                    _ => SubAckReasonCode::ImplementationSpecificError,
+                };
+                let mut suback = SubAck::new(ident);
+                for _ in sub.topics_iter() {
+                    suback.add_reason_code(code);
+                }
+                if let Some(reason) = subscriber.explain(e.reason_string()) {
+                    suback.add_prop(Property::ReasonString, reason).unwrap();
+                }
+                if let Some(c) = self.clients.read().await.get(client) {
+                    if c.send(suback.build()).is_err() {
+                        error!(clientid = client.as_ref(), "Internal Error: tx closed");
+                    }
+                }
+                return Ok(());
+            }
+        };
         let mut suback = SubAck::new(ident);
+        let mut retained = Vec::new();
+        // filters refused and why, explained to clients asking for problem information
+        let mut refused = Vec::new();
         for (topic, options) in sub.topics_iter() {
-            let qos: QoS = (*options).try_into()?;
+            let requested: QoS = (*options).try_into()?;
+            // 3.9.3: the server may grant a lower QoS than requested
+            let qos = requested.min(subscriber.max_qos());
             let mut flags = SubscriptionFlags::empty();
             match qos {
-                QoS::QoS0 => (),
-                _ => {
+                QoS::QoS0 | QoS::QoS1 => (),
+                QoS::QoS2 => {
                     suback.add_reason_code(SubAckReasonCode::ImplementationSpecificError);
+                    refused.push((topic.clone(), ServerError::QoSNotSupported(2)));
                     continue;
                 }
             }
-            match (*options).try_into()? {
-                RetainHandling::DoNotSend => (),
-                _ => {
-                    suback.add_reason_code(SubAckReasonCode::ImplementationSpecificError);
-                    continue;
+            if let Err(e) = TopicFilter::with_rules(topic.clone(), self.cfg.filter_rules()) {
+                suback.add_reason_code(SubAckReasonCode::TopicFilterInvalid);
+                refused.push((topic.clone(), ServerError::Packet(e)));
+                continue;
+            }
+            let shared = split_shared(topic);
+            // 3.8.3-4: a shared subscription cannot be NoLocal
+            if shared.is_some() && options.contains(SubscriptionOptions::NO_LOCAL) {
+                let err = ServerError::ProtocolViolation(Violation::NoLocalOnSharedSubscription);
+                let disconnect = subscriber.disconnect(DisconnectReasonCode::ProtocolError, &err);
+                if subscriber.send(disconnect).is_err() {
+                    trace!(clientid = client.as_ref(), "client shutdown: tx closed");
                 }
+                return Err(err);
+            }
+            let topic_filter = shared.map_or(&**topic, |(_, filter)| filter);
+            if !self.authorized(&subscriber, Action::Subscribe, topic_filter) {
+                suback.add_reason_code(SubAckReasonCode::NotAuthorized);
+                refused.push((topic.clone(), ServerError::NotAuthorized(Action::Subscribe)));
+                continue;
             }
+            let retain_handling: RetainHandling = (*options).try_into()?;
             if options.contains(SubscriptionOptions::NO_LOCAL) {
                 flags |= SubscriptionFlags::NO_LOCAL;
             }
             if options.contains(SubscriptionOptions::RETAIN_AS_PUBLISHED) {
                 flags |= SubscriptionFlags::RETAIN_AS_PUBLISHED;
             }
-            self.topics
+            #[cfg(feature = "edge-filter")]
+            let is_new = self
+                .topics
+                .subscribe_filtered(client.clone(), topic.clone(), qos, flags, filter.clone())
+                .await;
+            #[cfg(not(feature = "edge-filter"))]
+            let is_new = self
+                .topics
                 .subscribe(client.clone(), topic.clone(), qos, flags)
                 .await;
+            // the retained messages are the `$SYS` ones, those published with RETAIN and the
+            // kept unmatched publishes, shared subscriptions never get retained messages (4.8.2)
+            let send_retained = match retain_handling {
+                _ if shared.is_some() => false,
+                RetainHandling::Send => true,
+                RetainHandling::SendIfNotExisting => is_new,
+                _ => false,
+            };
+            // retained messages are sent with RETAIN set whatever Retain As Published (3.8.3.1)
+            if send_retained {
+                retained.extend(self.sys.matching(topic).await);
+                let retain_as_published = flags.contains(SubscriptionFlags::RETAIN_AS_PUBLISHED);
+                retained.extend(self.unmatched.matching(
+                    topic,
+                    qos,
+                    retain_as_published,
+                    Instant::now(),
+                ));
+            }
+            if !subscriber.internal() {
+                for interceptor in &self.cfg.interceptors {
+                    interceptor.on_subscribe(&subscriber, topic, qos);
+                }
+            }
             match qos {
                 QoS::QoS0 => suback.add_reason_code(SubAckReasonCode::GrantedQoS0),
                 QoS::QoS1 => suback.add_reason_code(SubAckReasonCode::GrantedQoS1),
                 QoS::QoS2 => suback.add_reason_code(SubAckReasonCode::GrantedQoS2),
             }
         }
+        explain_refusals(&subscriber, &mut suback, refused);
 
         let clients = self.clients.read().await;
         if let Some(c) = clients.get(client) {
             if c.send(suback.build()).is_err() {
                 error!(clientid = client.as_ref(), "Internal Error: tx closed");
+                return Ok(());
+            }
+            for publish in retained {
+                if c.send(publish).is_err() {
+                    error!(clientid = client.as_ref(), "Internal Error: tx closed");
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+    #[instrument(skip_all)]
+    async fn process_unsubscribe(
+        &mut self,
+        client: &Arc<str>,
+        unsub: Unsubscribe,
+    ) -> Result<(), ServerError> {
+        trace!("Processing an unsubscribe packet");
+        for (k, v) in unsub.props_iter() {
+            match k {
+                Property::UserProperty => warn!(
+                    clientid = client.as_ref(),
+                    "Received unknown user property {:?}",
+                    v.into_str_pair()
+                ),
+                _ => error!(
+                    "Internal Error: {:?} should not be part of unsubscribe packet",
+                    k
+                ),
+            }
+        }
+        let mut unsuback = UnsubAck::new(unsub.packet_identifier());
+        for topic in unsub.topics_iter() {
+            let valid = TopicFilter::with_rules(topic.clone(), self.cfg.filter_rules()).is_ok();
+            let code = if !valid {
+                UnsubAckReasonCode::TopicFilterInvalid
+            } else if self.topics.unsubscribe(client.clone(), topic).await {
+                UnsubAckReasonCode::Success
+            } else {
+                UnsubAckReasonCode::NoSubscriptionExisted
+            };
+            unsuback.add_reason_code(code);
+        }
+        if let Some(c) = self.clients.read().await.get(client) {
+            if c.send(unsuback.build()).is_err() {
+                error!(clientid = client.as_ref(), "Internal Error: tx closed");
             }
         }
         Ok(())
@@ -198,10 +567,12 @@ impl Dispatcher {
         &mut self,
         client: Arc<str>,
         packet: Packet,
+        traced: bool,
     ) -> Result<(), ServerError> {
         match packet {
-            Packet::Publish(publish) => self.process_publish(&client, publish).await,
+            Packet::Publish(publish) => self.process_publish(&client, publish, traced).await,
             Packet::Subscribe(sub) => self.process_subscribe(&client, sub).await,
+            Packet::Unsubscribe(unsub) => self.process_unsubscribe(&client, unsub).await,
             _ => self.unimplemented(&client).await,
         }
     }
@@ -214,25 +585,120 @@ impl Dispatcher {
                     break;
                 }
             };
+            if packetinfo.traced {
+                if let Some(tracer) = &self.tracer {
+                    tracer.record(TraceStage::Dequeued);
+                }
+            }
             if let Err(e) = self
-                .process_packet(packetinfo.senderid.clone(), packetinfo.packet)
+                .process_packet(
+                    packetinfo.senderid.clone(),
+                    packetinfo.packet,
+                    packetinfo.traced,
+                )
                 .await
             {
-                error!(clientid = &*packetinfo.senderid, "{:?}", e);
+                if let Some(suppressed) = self.errors.admit(discriminant(&e), Instant::now()) {
+                    error!(clientid = &*packetinfo.senderid, suppressed, "{:?}", e);
+                }
             }
         }
     }
     #[instrument(name = "Dispatcher::run", skip(self))]
-    async fn run(self) {
+    async fn run(self, worker: usize) {
         let shutdown = self.shutdown.clone();
         tokio::select! {
-            _ = shutdown.notified() => (),
+            _ = shutdown.wait() => (),
             _ = self.process_forever() => (),
         }
     }
-    pub async fn spawn(self) -> JoinHandle<()> {
+    /// Forwards every packet to the queue of its worker, the packets of one worker are
+    /// processed in order
+    #[instrument(name = "Dispatcher::route", skip_all)]
+    async fn route(mut self, workers: Vec<Sender<PacketInfo>>) {
+        let shutdown = self.shutdown.clone();
+        let sharding = self.cfg.dispatcher_sharding;
+        let forward = async {
+            while let Some(packetinfo) = self.incoming.recv().await {
+                let worker = &workers[shard(sharding, &packetinfo, workers.len())];
+                if worker.send(packetinfo).await.is_err() {
+                    break;
+                }
+            }
+        };
+        tokio::select! {
+            _ = shutdown.wait() => (),
+            _ = forward => (),
+        }
+    }
+    pub async fn spawn(mut self) -> JoinHandle<()> {
+        let (lanes, mut handles) = Lanes::spawn(
+            &self.cfg.fanout_lanes,
+            self.fanout.clone(),
+            self.cfg.dispatcher_queue_size,
+        );
+        self.lanes = Arc::new(lanes);
+        let mut workers = Vec::new();
+        if self.cfg.dispatcher_workers > 1 {
+            let queue_len = (self.cfg.dispatcher_queue_size / size_of::<PacketInfo>()).max(1);
+            for worker in 0..self.cfg.dispatcher_workers {
+                let (tx, rx) = channel(queue_len);
+                // the workers must stop before the lanes they feed are joined
+                handles.insert(worker, tokio::spawn(self.worker(rx).run(worker)));
+                workers.push(tx);
+            }
+        }
         tokio::spawn(async move {
-            self.run().await;
+            // dropping the dispatcher and its workers closes the lanes
+            if workers.is_empty() {
+                self.run(0).await;
+            } else {
+                self.route(workers).await;
+            }
+            for handle in handles {
+                if let Err(e) = handle.await {
+                    error!("Failed joining a fan-out lane, {:?}", e);
+                }
+            }
         })
     }
 }
+
+/// Sums up the topic filters refused by `suback` in its ReasonString, with a UserProperty
+/// pairing each filter with why it was refused, when the client asked for it (3.1.2.11.7)
+fn explain_refusals(
+    subscriber: &Client,
+    suback: &mut SubAck,
+    refused: Vec<(Arc<str>, ServerError)>,
+) {
+    if refused.is_empty() || !subscriber.problem_info() {
+        return;
+    }
+    let summary = format!(
+        "{} of {} topic filters refused",
+        refused.len(),
+        suback.reason_codes().len()
+    );
+    if let Some(reason) = subscriber.explain(Some(summary)) {
+        suback.add_prop(Property::ReasonString, reason).unwrap();
+    }
+    for (filter, err) in refused {
+        let reason = Arc::from(err.reason_string().unwrap_or_default());
+        if let Ok(pair) = MqttPropValue::new_string_pair(filter, reason) {
+            suback.add_prop(Property::UserProperty, pair).unwrap();
+        }
+    }
+}
+
+/// The worker out of `workers` processing `packetinfo`
+fn shard(sharding: DispatcherSharding, packetinfo: &PacketInfo, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    match (sharding, &packetinfo.packet) {
+        // acknowledged publishes stay with their sender for their PUBACKs to keep its order
+        (DispatcherSharding::Topic, Packet::Publish(p)) if p.qos() == QoS::QoS0 => {
+            p.topic_name().hash(&mut hasher)
+        }
+        _ => packetinfo.senderid.hash(&mut hasher),
+    }
+    (hasher.finish() % workers as u64) as usize
+}
@@ -1,18 +1,106 @@
-use apiformes_packet::prelude::DataParseError;
+use crate::acl::Action;
+use apiformes_packet::prelude::{DataParseError, Violation};
 use std::io;
+use std::sync::Arc;
 #[derive(Debug)]
 pub enum ServerError {
     MaxPacketSizeExceeded,
     Io(io::Error),
     Packet(DataParseError),
+    /// The packet breaks an invariant spanning several of its fields
+    ProtocolViolation(Violation),
 
     #[cfg(feature = "noise")]
     Noise(snow::Error),
 
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+
     FirstPacketNotConnect,
+    ConnectionClosed,
+    ReservedClientId(Arc<str>),
+    ClientIdInUse(Arc<str>),
+    ZeroKeepAliveRejected,
+    BadAuthenticationMethod(Arc<str>),
+    AuthenticationFailed,
+    /// The listener of the client only accepts authenticated clients
+    AuthenticationRequired,
+    /// The authorizer refused the action
+    NotAuthorized(Action),
+    ServerBusy,
+    /// The IP address of the client connects too often
+    ConnectionRateExceeded,
+    /// The broker is a standby which was not promoted yet
+    Standby,
+    KeepAliveTimeout,
+    /// A write to a client with a backlog did not complete in time
+    Unwritable,
+    MaximumConnectTime,
+    InvalidConfig(String),
+    /// The given setting of the configuration is refused for the given reason
+    InvalidSetting {
+        field: &'static str,
+        reason: String,
+    },
+    PermeabilityViolation,
+    QoSNotSupported(u8),
+    /// The client published above its quota, disconnected with the given reason code
+    PublishQuotaExceeded(u8),
+    RetainNotSupported,
+    /// A payload validator refused the publish for the given reason
+    PayloadFormatInvalid(String),
+    #[cfg(feature = "edge-filter")]
+    BadPayloadFilter(String),
+    #[cfg(feature = "edge-filter")]
+    PayloadFilterQuotaExceeded,
+    /// The server sent a DISCONNECT with the given reason code
+    DisconnectedByServer(u8),
+    /// The client sent a DISCONNECT with the given reason code
+    DisconnectedByClient(u8),
     Misc(String),
 }
 
+impl ServerError {
+    /// Why the request of the client failed, for the ReasonString of the response. Errors
+    /// of the server itself, or the transport, are not explained to the client.
+    pub fn reason_string(&self) -> Option<String> {
+        let reason = match self {
+            ServerError::MaxPacketSizeExceeded => "Packet exceeds the maximum packet size".into(),
+            ServerError::Packet(e) => format!("Malformed packet, {:?}", e),
+            ServerError::ProtocolViolation(violation) => violation.to_string(),
+            ServerError::FirstPacketNotConnect => "The first packet must be a CONNECT".into(),
+            ServerError::ReservedClientId(id) => format!("Client id {} is reserved", id),
+            ServerError::ClientIdInUse(id) => format!("Client id {} is in use", id),
+            ServerError::ZeroKeepAliveRejected => "A keep alive of 0 is refused".into(),
+            ServerError::BadAuthenticationMethod(method) => {
+                format!("Unsupported authentication method {}", method)
+            }
+            ServerError::AuthenticationFailed => "Authentication failed".into(),
+            ServerError::AuthenticationRequired => "Authentication required".into(),
+            ServerError::NotAuthorized(action) => format!("Not authorized to {:?}", action),
+            ServerError::ServerBusy => "Too many connections open".into(),
+            ServerError::ConnectionRateExceeded => "Connecting too often".into(),
+            ServerError::Standby => "The server is a standby".into(),
+            ServerError::KeepAliveTimeout => "Keep alive timeout".into(),
+            ServerError::MaximumConnectTime => "Maximum connect time reached".into(),
+            ServerError::PermeabilityViolation => {
+                "Deliveries suppressed by strict channel permeability".into()
+            }
+            ServerError::QoSNotSupported(qos) => format!("QoS {} is not supported", qos),
+            ServerError::PublishQuotaExceeded(_) => "Publish quota exceeded".into(),
+            ServerError::RetainNotSupported => "Retain is not supported".into(),
+            ServerError::PayloadFormatInvalid(reason) => format!("Invalid payload, {}", reason),
+            #[cfg(feature = "edge-filter")]
+            ServerError::BadPayloadFilter(reason) => format!("Invalid payload filter, {}", reason),
+            #[cfg(feature = "edge-filter")]
+            ServerError::PayloadFilterQuotaExceeded => "Payload filter quota exceeded".into(),
+            ServerError::Misc(reason) => reason.clone(),
+            _ => return None,
+        };
+        Some(reason)
+    }
+}
+
 impl From<io::Error> for ServerError {
     fn from(err: io::Error) -> ServerError {
         ServerError::Io(err)
@@ -31,3 +119,10 @@ impl From<snow::Error> for ServerError {
         ServerError::Noise(err)
     }
 }
+
+#[cfg(feature = "websocket")]
+impl From<tokio_tungstenite::tungstenite::Error> for ServerError {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> ServerError {
+        ServerError::WebSocket(Box::new(err))
+    }
+}
@@ -0,0 +1,294 @@
+use crate::{config::FilterQuota, error::ServerError};
+
+/// Name of the SUBSCRIBE user property carrying a payload filter. Its value is either
+/// `prefix:<text>`, matching payloads starting with `<text>`, or `json:<pointer>=<value>`,
+/// matching JSON payloads where the value at the JSON pointer (RFC 6901) is textually
+/// equal to `<value>`, e.g. `json:/sensor/status="ok"`. A subscription only receives the
+/// messages matching all the filters of its SUBSCRIBE.
+pub const FILTER_PROPERTY: &str = "apiformes-filter";
+
+/// Nesting limit of the JSON documents filters look into, deeper payloads never match
+const MAX_NESTING: usize = 64;
+
+enum Condition {
+    Prefix(Box<[u8]>),
+    JsonEquals {
+        pointer: Vec<String>,
+        value: Box<[u8]>,
+    },
+}
+
+impl Condition {
+    fn parse(spec: &str, quota: &FilterQuota) -> Result<Self, ServerError> {
+        if spec.len() > quota.max_condition_len {
+            return Err(ServerError::PayloadFilterQuotaExceeded);
+        }
+        if let Some(prefix) = spec.strip_prefix("prefix:") {
+            return Ok(Condition::Prefix(prefix.as_bytes().into()));
+        }
+        let bad = || ServerError::BadPayloadFilter(spec.to_owned());
+        let (pointer, value) = spec
+            .strip_prefix("json:")
+            .and_then(|s| s.split_once('='))
+            .ok_or_else(bad)?;
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            return Err(bad());
+        }
+        // the empty pointer is the whole document
+        let pointer: Vec<String> = pointer
+            .split('/')
+            .skip(1)
+            .map(|token| token.replace("~1", "/").replace("~0", "~"))
+            .collect();
+        if pointer.len() > quota.max_pointer_depth {
+            return Err(ServerError::PayloadFilterQuotaExceeded);
+        }
+        let value = value.trim();
+        let mut scanner = Scanner::new(value.as_bytes());
+        if scanner.value(0).is_none() || scanner.pos != value.len() {
+            return Err(bad());
+        }
+        Ok(Condition::JsonEquals {
+            pointer,
+            value: value.as_bytes().into(),
+        })
+    }
+    fn matches(&self, payload: &[u8]) -> bool {
+        match self {
+            Condition::Prefix(prefix) => payload.starts_with(prefix),
+            Condition::JsonEquals { pointer, value } => {
+                Scanner::new(payload).lookup(pointer) == Some(&value[..])
+            }
+        }
+    }
+}
+
+/// Conditions a payload must meet to be delivered over a subscription
+pub struct PayloadFilter {
+    conditions: Vec<Condition>,
+}
+
+impl PayloadFilter {
+    /// Builds the filter described by the values of the `FILTER_PROPERTY` user properties,
+    /// `None` if there are none
+    pub fn parse<'a>(
+        specs: impl Iterator<Item = &'a str>,
+        quota: &FilterQuota,
+    ) -> Result<Option<Self>, ServerError> {
+        let mut conditions = Vec::new();
+        for spec in specs {
+            if conditions.len() == quota.max_conditions {
+                return Err(ServerError::PayloadFilterQuotaExceeded);
+            }
+            conditions.push(Condition::parse(spec, quota)?);
+        }
+        if conditions.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(PayloadFilter { conditions }))
+    }
+    pub fn matches(&self, payload: &[u8]) -> bool {
+        self.conditions.iter().all(|c| c.matches(payload))
+    }
+}
+
+/// Just enough of a JSON parser to find a value by its pointer without allocating
+struct Scanner<'a> {
+    json: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(json: &'a [u8]) -> Self {
+        Scanner { json, pos: 0 }
+    }
+    fn peek(&self) -> Option<u8> {
+        self.json.get(self.pos).copied()
+    }
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+    fn eat(&mut self, c: u8) -> Option<()> {
+        self.skip_ws();
+        if self.peek()? != c {
+            return None;
+        }
+        self.pos += 1;
+        Some(())
+    }
+    /// The raw content of a string, escape sequences are left untouched
+    fn string(&mut self) -> Option<&'a [u8]> {
+        self.eat(b'"')?;
+        let start = self.pos;
+        loop {
+            match self.peek()? {
+                b'"' => break,
+                b'\\' => self.pos += 2,
+                _ => self.pos += 1,
+            }
+        }
+        let s = &self.json[start..self.pos];
+        self.pos += 1;
+        Some(s)
+    }
+    /// Skips the next value and returns it with the surrounding whitespace trimmed
+    fn value(&mut self, depth: usize) -> Option<&'a [u8]> {
+        if depth > MAX_NESTING {
+            return None;
+        }
+        self.skip_ws();
+        let start = self.pos;
+        match self.peek()? {
+            b'"' => drop(self.string()?),
+            b'{' => {
+                self.pos += 1;
+                if self.eat(b'}').is_none() {
+                    loop {
+                        self.string()?;
+                        self.eat(b':')?;
+                        self.value(depth + 1)?;
+                        if self.eat(b',').is_none() {
+                            self.eat(b'}')?;
+                            break;
+                        }
+                    }
+                }
+            }
+            b'[' => {
+                self.pos += 1;
+                if self.eat(b']').is_none() {
+                    loop {
+                        self.value(depth + 1)?;
+                        if self.eat(b',').is_none() {
+                            self.eat(b']')?;
+                            break;
+                        }
+                    }
+                }
+            }
+            // numbers, true, false and null
+            _ => {
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || b"+-.".contains(&c))
+                {
+                    self.pos += 1;
+                }
+                if self.pos == start {
+                    return None;
+                }
+            }
+        }
+        let end = self.pos;
+        self.skip_ws();
+        Some(&self.json[start..end])
+    }
+    /// The raw value `pointer` refers to
+    fn lookup(&mut self, pointer: &[String]) -> Option<&'a [u8]> {
+        let (token, rest) = match pointer.split_first() {
+            Some(split) => split,
+            None => {
+                let value = self.value(0)?;
+                return (self.pos == self.json.len()).then_some(value);
+            }
+        };
+        if pointer.len() > MAX_NESTING {
+            return None;
+        }
+        self.skip_ws();
+        match self.peek()? {
+            b'{' => {
+                self.pos += 1;
+                loop {
+                    let key = self.string()?;
+                    self.eat(b':')?;
+                    if key == token.as_bytes() {
+                        return self.lookup_nested(rest);
+                    }
+                    self.value(0)?;
+                    self.eat(b',')?;
+                }
+            }
+            b'[' => {
+                let index: usize = token.parse().ok()?;
+                self.pos += 1;
+                for _ in 0..index {
+                    self.value(0)?;
+                    self.eat(b',')?;
+                }
+                self.lookup_nested(rest)
+            }
+            _ => None,
+        }
+    }
+    /// Looks `pointer` up in the value starting at the current position, the rest of the
+    /// document is not checked
+    fn lookup_nested(&mut self, pointer: &[String]) -> Option<&'a [u8]> {
+        if pointer.is_empty() {
+            return self.value(0);
+        }
+        self.lookup(pointer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn filter(specs: &[&str]) -> Result<Option<PayloadFilter>, ServerError> {
+        PayloadFilter::parse(specs.iter().copied(), &FilterQuota::default())
+    }
+    #[test]
+    fn test_payload_filter() {
+        assert!(filter(&[]).unwrap().is_none());
+        let prefix = filter(&["prefix:ALERT"]).unwrap().unwrap();
+        assert!(prefix.matches(b"ALERT: disk full"));
+        assert!(!prefix.matches(b"INFO: all good"));
+
+        let json = filter(&[r#"json:/sensor/status="ok""#, "json:/readings/1=21.5"])
+            .unwrap()
+            .unwrap();
+        let doc =
+            br#" { "id": [1, {"x": null}], "sensor": {"status" : "ok"}, "readings": [20, 21.5] } "#;
+        assert!(json.matches(doc));
+        assert!(!json.matches(br#"{"sensor": {"status": "down"}, "readings": [20, 21.5]}"#));
+        assert!(!json.matches(br#"{"sensor": {"status": "ok"}}"#));
+        assert!(!json.matches(b"not json"));
+
+        let escaped = filter(&["json:/a~1b/c~0d=true"]).unwrap().unwrap();
+        assert!(escaped.matches(br#"{"a/b": {"c~d": true}}"#));
+        let whole = filter(&["json:=[1,2]"]).unwrap().unwrap();
+        assert!(whole.matches(b"[1,2]"));
+        assert!(!whole.matches(b"[1,2] trailing"));
+    }
+    #[test]
+    fn test_payload_filter_quota() {
+        assert!(matches!(
+            filter(&["json:status=1"]),
+            Err(ServerError::BadPayloadFilter(_))
+        ));
+        assert!(matches!(
+            filter(&["json:/status={"]),
+            Err(ServerError::BadPayloadFilter(_))
+        ));
+        assert!(matches!(
+            filter(&["regex:.*"]),
+            Err(ServerError::BadPayloadFilter(_))
+        ));
+        let quota = FilterQuota::default();
+        let too_many = vec!["prefix:a"; quota.max_conditions + 1];
+        assert!(matches!(
+            filter(&too_many),
+            Err(ServerError::PayloadFilterQuotaExceeded)
+        ));
+        let too_deep = format!("json:{}=1", "/a".repeat(quota.max_pointer_depth + 1));
+        assert!(matches!(
+            filter(&[&too_deep]),
+            Err(ServerError::PayloadFilterQuotaExceeded)
+        ));
+        let too_long = format!("prefix:{}", "a".repeat(quota.max_condition_len));
+        assert!(matches!(
+            filter(&[&too_long]),
+            Err(ServerError::PayloadFilterQuotaExceeded)
+        ));
+    }
+}
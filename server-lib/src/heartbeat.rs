@@ -0,0 +1,168 @@
+use crate::{
+    clients::{is_internal_clientid, Client, InternalClient},
+    config::Heartbeat,
+    metrics::Metrics,
+    packetinfo::PacketInfo,
+    shutdown::Shutdown,
+    sys::{SysTopics, SYS_HEARTBEAT},
+    topics::TopicsTable,
+};
+use apiformes_packet::prelude::*;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::Arc;
+use tokio::sync::{mpsc::Sender, RwLock};
+use tokio::time::{interval, Duration, Instant};
+use tracing::{info, instrument, trace};
+
+/// Load indicators of the broker at one point in time
+struct Load {
+    clients: usize,
+    dispatcher_queue: usize,
+    memory_estimate: u64,
+    message_rate: f64,
+}
+
+impl Load {
+    /// The heartbeat payload, only the indicators enabled in `cfg` are included
+    fn to_json(&self, cfg: &Heartbeat) -> String {
+        let mut fields = Vec::new();
+        if cfg.clients {
+            fields.push(format!("\"clients\":{}", self.clients));
+        }
+        if cfg.queue_depths {
+            fields.push(format!(
+                "\"queues\":{{\"dispatcher\":{}}}",
+                self.dispatcher_queue
+            ));
+        }
+        if cfg.memory_estimate {
+            fields.push(format!("\"memory_estimate\":{}", self.memory_estimate));
+        }
+        if cfg.message_rate {
+            fields.push(format!("\"msg_per_sec\":{:.1}", self.message_rate));
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+/// Publishes the load of the broker under `SYS_HEARTBEAT` every `Heartbeat::interval`
+/// seconds. The last heartbeat is also kept as a `$SYS` topic for new subscribers.
+pub(crate) struct HeartbeatPublisher {
+    cfg: Heartbeat,
+    client: InternalClient,
+    clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+    topics: Arc<TopicsTable>,
+    metrics: Arc<Metrics>,
+    sys: Arc<SysTopics>,
+    incoming: Sender<PacketInfo>,
+    // capacity of the dispatcher queue
+    queue_len: usize,
+}
+
+impl HeartbeatPublisher {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        cfg: Heartbeat,
+        client: InternalClient,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+        topics: Arc<TopicsTable>,
+        metrics: Arc<Metrics>,
+        sys: Arc<SysTopics>,
+        incoming: Sender<PacketInfo>,
+        queue_len: usize,
+    ) -> Self {
+        HeartbeatPublisher {
+            cfg,
+            client,
+            clients,
+            topics,
+            metrics,
+            sys,
+            incoming,
+            queue_len,
+        }
+    }
+    async fn load(&self, elapsed: Duration, publishes: u64) -> Load {
+        let clients = self
+            .clients
+            .read()
+            .await
+            .keys()
+            .filter(|id| !is_internal_clientid(id))
+            .count();
+        let dispatcher_queue = self.queue_len - self.incoming.capacity();
+        let memory_estimate = self.topics.memory_estimate()
+            + (clients * size_of::<Client>()) as u64
+            + (dispatcher_queue * size_of::<PacketInfo>()) as u64;
+        Load {
+            clients,
+            dispatcher_queue,
+            memory_estimate,
+            message_rate: publishes as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        }
+    }
+    async fn beat(&self, payload: Bytes) {
+        self.sys
+            .set(Arc::from(SYS_HEARTBEAT), payload.clone())
+            .await;
+        // the heartbeat is skipped rather than adding to a full dispatcher queue
+        let publish = match Publish::new(Arc::from(SYS_HEARTBEAT), payload) {
+            Ok(publish) => publish,
+            Err(_) => return,
+        };
+        let packet = PacketInfo {
+            senderid: self.client.clientid().clone(),
+            packet: publish.build(),
+            traced: false,
+        };
+        if self.incoming.try_send(packet).is_err() {
+            trace!("Dispatcher queue is full, skipping heartbeat");
+        }
+    }
+    #[instrument(name = "HeartbeatPublisher::run", skip_all)]
+    pub(crate) async fn run(self, shutdown: Shutdown) {
+        let mut ticker = interval(Duration::from_secs(self.cfg.interval as u64));
+        // the first tick completes immediately
+        ticker.tick().await;
+        let mut last = (Instant::now(), self.metrics.publishes_received());
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = ticker.tick() => (),
+            }
+            let now = (Instant::now(), self.metrics.publishes_received());
+            let load = self.load(now.0 - last.0, now.1 - last.1).await;
+            last = now;
+            self.beat(Bytes::from(load.to_json(&self.cfg))).await;
+        }
+        self.client.unregister().await;
+        info!("shutting down");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_heartbeat_payload() {
+        let load = Load {
+            clients: 12,
+            dispatcher_queue: 3,
+            memory_estimate: 53248,
+            message_rate: 4.54,
+        };
+        let mut cfg = Heartbeat::default();
+        assert_eq!(
+            load.to_json(&cfg),
+            r#"{"clients":12,"queues":{"dispatcher":3},"memory_estimate":53248,"msg_per_sec":4.5}"#
+        );
+        cfg.queue_depths = false;
+        cfg.memory_estimate = false;
+        assert_eq!(load.to_json(&cfg), r#"{"clients":12,"msg_per_sec":4.5}"#);
+        cfg.clients = false;
+        cfg.message_rate = false;
+        assert_eq!(load.to_json(&cfg), "{}");
+    }
+}
@@ -0,0 +1,55 @@
+use crate::clients::{Client, DisconnectReason};
+use apiformes_packet::prelude::{Packet, Publish, QoS};
+use std::sync::Arc;
+
+/// Hooks called at the main steps of the life of the clients, for audit logging, payload
+/// transformation or custom routing without changing the broker. Every hook does nothing
+/// by default, internal clients are never intercepted. Hooks run in the tasks serving the
+/// clients, they must not block.
+pub trait Interceptor: Send + Sync {
+    /// The client was sent a successful CONNACK
+    fn on_connect(&self, _client: &Client) {}
+    /// The publish fanned out in place of the one of `sender`, its topic, payload and
+    /// properties may be changed, the result is authorized and validated again. `None` drops
+    /// it, a QoS 1 publish is still acknowledged.
+    fn on_publish(&self, _sender: &Client, publish: Publish) -> Option<Publish> {
+        Some(publish)
+    }
+    /// The subscription to `filter` was granted `qos`
+    fn on_subscribe(&self, _subscriber: &Client, _filter: &str, _qos: QoS) {}
+    /// Whether `publish` is delivered to `target`, also asked by
+    /// `MqttServer::dry_run_publish`
+    fn on_deliver(&self, _target: &Client, _publish: &Publish) -> bool {
+        true
+    }
+    /// The connection of the client ended
+    fn on_disconnect(&self, _client: &Client, _reason: &DisconnectReason) {}
+}
+
+/// Runs `publish` through every interceptor in turn, `None` once one of them dropped it
+pub(crate) fn publish(
+    interceptors: &[Arc<dyn Interceptor>],
+    sender: &Client,
+    publish: Publish,
+) -> Option<Publish> {
+    if sender.internal() {
+        return Some(publish);
+    }
+    interceptors
+        .iter()
+        .try_fold(publish, |publish, i| i.on_publish(sender, publish))
+}
+
+/// Whether every interceptor lets `packet` through to `target`
+pub(crate) fn deliver(
+    interceptors: &[Arc<dyn Interceptor>],
+    target: &Client,
+    packet: &Packet,
+) -> bool {
+    match packet {
+        Packet::Publish(p) if !target.internal() => {
+            interceptors.iter().all(|i| i.on_deliver(target, p))
+        }
+        _ => true,
+    }
+}
@@ -0,0 +1,753 @@
+use crate::{
+    clients::SharedFrame,
+    intercept,
+    metrics::Metrics,
+    topics::{SubscriptionFlags, SubscriptionInfo, TopicsTable},
+    trace::{PublishTracer, TraceStage},
+    unmatched::UnmatchedPublishes,
+    Client, MqttServerConfig, ServerError, SharedDelivery,
+};
+#[cfg(feature = "noise")]
+use crate::{Permeability, PermeabilityViolation};
+use apiformes_packet::prelude::*;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::Arc;
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender},
+    RwLock,
+};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+#[cfg(feature = "noise")]
+use tracing::warn;
+use tracing::{error, info, instrument, trace};
+
+/// A validated publish waiting to be delivered to the subscribers of its topic
+pub struct FanoutJob {
+    pub senderid: Arc<str>,
+    pub topic: Arc<str>,
+    /// the publish at the QoS it was published with, without packet identifier
+    pub packet: Arc<Packet>,
+    /// packet identifier of a QoS 1 publish, acknowledged once it is fanned out
+    pub ack: Option<u16>,
+    /// the publish followed by the `PublishTracer`
+    pub traced: bool,
+    /// the publisher is encrypted, unencrypted clients only get the publish when their
+    /// listener is `Permeability::Permissive`
+    #[cfg(feature = "noise")]
+    pub encrypted: bool,
+}
+
+/// What the fan-out does with a publish for one of the subscriptions its topic matches
+#[derive(Clone, Copy, PartialEq)]
+pub enum Delivery {
+    /// Sent to the subscriber at this QoS
+    Sent(QoS),
+    /// The subscriber is the publisher and subscribed with NoLocal
+    NoLocal,
+    /// The payload does not match the payload filter of the subscription
+    #[cfg(feature = "edge-filter")]
+    Filtered,
+    /// The subscriber is not connected, only its session is left
+    Offline,
+    /// Withheld from an unencrypted subscriber, see `Permeability::Strict`
+    #[cfg(feature = "noise")]
+    Suppressed,
+    /// Withheld by an `Interceptor`
+    Intercepted,
+}
+
+/// Delivers publishes to their subscribers, shared by the dispatcher and the lanes
+#[derive(Clone)]
+pub struct Fanout {
+    topics: Arc<TopicsTable>,
+    clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+    #[cfg_attr(not(any(feature = "noise", feature = "edge-filter")), allow(dead_code))]
+    metrics: Arc<Metrics>,
+    cfg: Arc<MqttServerConfig>,
+    // publishes reaching no connected subscriber are discarded when None
+    unmatched: Option<Arc<UnmatchedPublishes>>,
+    tracer: Option<Arc<PublishTracer>>,
+}
+
+impl FanoutJob {
+    fn qos(&self) -> QoS {
+        match &*self.packet {
+            Packet::Publish(p) => p.qos(),
+            _ => QoS::QoS0,
+        }
+    }
+    /// The publish as forwarded to a subscription granted `granted` (3.8.4), its RETAIN
+    /// flag is only kept for the subscriptions with Retain As Published (3.3.1.3)
+    fn forwarded(&self, granted: QoS, retain_as_published: bool) -> Arc<Packet> {
+        match &*self.packet {
+            Packet::Publish(p) => {
+                let downgrade = granted < p.qos();
+                let clear_retain = !retain_as_published && p.flags().contains(PublishFlags::RETAIN);
+                if !downgrade && !clear_retain {
+                    return self.packet.clone();
+                }
+                let mut publish = p.clone();
+                if downgrade {
+                    publish.set_qos(granted);
+                }
+                if clear_retain {
+                    publish.clear_retain();
+                }
+                Arc::new(publish.build())
+            }
+            _ => self.packet.clone(),
+        }
+    }
+}
+
+impl Fanout {
+    pub fn new(
+        topics: Arc<TopicsTable>,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+        metrics: Arc<Metrics>,
+        cfg: Arc<MqttServerConfig>,
+    ) -> Self {
+        Fanout {
+            topics,
+            clients,
+            metrics,
+            cfg,
+            unmatched: None,
+            tracer: None,
+        }
+    }
+    /// Records the stages of the publishes traced by `tracer`
+    pub fn trace_publishes(mut self, tracer: Arc<PublishTracer>) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+    fn tracer(&self, job: &FanoutJob) -> Option<&PublishTracer> {
+        self.tracer.as_deref().filter(|_| job.traced)
+    }
+    /// Hands the publishes reaching no connected subscriber to `unmatched`
+    pub fn keep_unmatched(mut self, unmatched: Arc<UnmatchedPublishes>) -> Self {
+        self.unmatched = Some(unmatched);
+        self
+    }
+    /// The delivery of `job` over the subscription `target` holds with `info`
+    fn delivery(
+        &self,
+        job: &FanoutJob,
+        clients: &HashMap<Arc<str>, Client>,
+        target: &str,
+        info: &SubscriptionInfo,
+    ) -> Delivery {
+        if target == &*job.senderid && info.flags.contains(SubscriptionFlags::NO_LOCAL) {
+            return Delivery::NoLocal;
+        }
+        #[cfg(feature = "edge-filter")]
+        if let (Some(filter), Packet::Publish(p)) = (&info.filter, &*job.packet) {
+            if !filter.matches(&p.payload()) {
+                return Delivery::Filtered;
+            }
+        }
+        match clients.get(target) {
+            None => Delivery::Offline,
+            #[cfg(feature = "noise")]
+            Some(c)
+                if job.encrypted
+                    && c.permeability() == Permeability::Strict
+                    && !c.encrypted()
+                    && !c.internal() =>
+            {
+                Delivery::Suppressed
+            }
+            Some(c) if !intercept::deliver(&self.cfg.interceptors, c, &job.packet) => {
+                Delivery::Intercepted
+            }
+            Some(_) => Delivery::Sent(info.qos.min(job.qos())),
+        }
+    }
+    /// The member of the shared subscription `key` receiving the next publish, None when
+    /// no member is connected
+    async fn shared_member(
+        &self,
+        clients: &HashMap<Arc<str>, Client>,
+        key: &str,
+        advance: bool,
+    ) -> Option<(Arc<str>, SubscriptionInfo)> {
+        let strategy = self.cfg.shared_delivery;
+        let load = |clientid: &str| {
+            let c = clients.get(clientid)?;
+            Some(match strategy {
+                SharedDelivery::RoundRobin => 0,
+                SharedDelivery::LeastLoaded => c.unacknowledged(),
+            })
+        };
+        self.topics.shared_member(key, load, advance).await
+    }
+    /// What `run` would do with `job` for every subscription its topic matches, ordered by
+    /// client id, nothing is sent. Shared subscriptions show the member next in line, or
+    /// their group when no member is connected.
+    pub async fn dry_run(&self, job: &FanoutJob) -> Vec<(Arc<str>, Delivery)> {
+        let clients = self.clients.read().await;
+        let mut deliveries = Vec::new();
+        for (target, info) in self.topics.matching_subscriptions(&job.topic).await {
+            if !TopicsTable::is_shared(&target) {
+                deliveries.push((target.clone(), self.delivery(job, &clients, &target, &info)));
+                continue;
+            }
+            match self.shared_member(&clients, &target, false).await {
+                Some((member, info)) => {
+                    let delivery = self.delivery(job, &clients, &member, &info);
+                    deliveries.push((member, delivery));
+                }
+                None => deliveries.push((target, Delivery::Offline)),
+            }
+        }
+        deliveries
+    }
+    pub async fn run(&self, job: &FanoutJob) -> Result<(), ServerError> {
+        let client = &*job.senderid;
+        let clients = self.clients.read().await;
+        // the publish as forwarded, by granted QoS and Retain As Published, along with the
+        // encoding shared by the workers of its recipients
+        let mut forwarded: Vec<(QoS, bool, Arc<Packet>, Arc<SharedFrame>)> = Vec::new();
+        let mut delivered = 0;
+        #[cfg(feature = "noise")]
+        let mut suppressed = 0;
+        #[cfg(feature = "edge-filter")]
+        let mut filtered = 0;
+
+        let subscriptions = self.topics.get_all_subscribed(&job.topic).await;
+        let tracer = self.tracer(job);
+        if let Some(tracer) = tracer {
+            tracer.record(TraceStage::Matched);
+        }
+        // recipients the traced publish is written to by a client worker
+        let mut traced_recipients = 0;
+        for (target, info) in subscriptions {
+            // shared subscriptions deliver to one of their members
+            let (target, info) = if TopicsTable::is_shared(&target) {
+                match self.shared_member(&clients, &target, true).await {
+                    Some(member) => member,
+                    None => continue,
+                }
+            } else {
+                (target, info)
+            };
+            let granted = match self.delivery(job, &clients, &target, &info) {
+                Delivery::Sent(granted) => granted,
+                #[cfg(feature = "edge-filter")]
+                Delivery::Filtered => {
+                    filtered += 1;
+                    continue;
+                }
+                #[cfg(feature = "noise")]
+                Delivery::Suppressed => {
+                    suppressed += 1;
+                    if self.metrics.inc_suppressed_delivery(&target, &job.topic) {
+                        warn!(
+                            clientid = target.as_ref(),
+                            topic = job.topic.as_ref(),
+                            "Unencrypted subscriber misses deliveries because of strict \
+                             permeability, further ones are only counted"
+                        );
+                    }
+                    continue;
+                }
+                Delivery::NoLocal | Delivery::Offline | Delivery::Intercepted => continue,
+            };
+            let retain_as_published = info.flags.contains(SubscriptionFlags::RETAIN_AS_PUBLISHED);
+            let (packet, frame) = match forwarded
+                .iter()
+                .find(|(qos, rap, _, _)| *qos == granted && *rap == retain_as_published)
+            {
+                Some((_, _, packet, frame)) => (packet.clone(), frame.clone()),
+                None => {
+                    let packet = job.forwarded(granted, retain_as_published);
+                    let frame = Arc::new(SharedFrame::default());
+                    forwarded.push((granted, retain_as_published, packet.clone(), frame.clone()));
+                    (packet, frame)
+                }
+            };
+            // `delivery` only grants a QoS to connected clients
+            if let Some(c) = clients.get(&target) {
+                if let Some(tracer) = tracer {
+                    // before sending, the worker may write the packet right away
+                    tracer.sent(&target, &packet);
+                    if !c.internal() {
+                        traced_recipients += 1;
+                    }
+                }
+                if c.send_shared(packet, frame).is_err() {
+                    trace!(clientid = target.as_ref(), "client shutdown: tx closed");
+                };
+            }
+            delivered += 1;
+        }
+        #[cfg(feature = "edge-filter")]
+        if filtered > 0 {
+            self.metrics.add_payload_filtered(filtered);
+        }
+        #[cfg(feature = "noise")]
+        if suppressed > 0 {
+            self.metrics.add_permeability_suppressed(suppressed);
+            trace!(
+                clientid = client,
+                "{} deliveries suppressed by strict permeability",
+                suppressed
+            );
+            if self.cfg.permeability_violation == PermeabilityViolation::Reject {
+                let err = ServerError::PermeabilityViolation;
+                if let Some(c) = clients.get(client) {
+                    // QoS 0 publishes cannot be acknowledged, their publisher is disconnected
+                    let response = match job.ack {
+                        Some(id) => {
+                            let mut puback = PubAck::new(id);
+                            puback.set_reason_code(PubAckReasonCode::ImplementationSpecificError);
+                            puback.build()
+                        }
+                        None => {
+                            c.disconnect(DisconnectReasonCode::ImplementationSpecificError, &err)
+                        }
+                    };
+                    if c.send(response).is_err() {
+                        trace!(clientid = client, "client shutdown: tx closed");
+                    }
+                }
+                return Err(err);
+            }
+        }
+        if let Some(tracer) = tracer {
+            tracer.fanned_out(traced_recipients);
+        }
+        if delivered == 0 {
+            match (&self.unmatched, &*job.packet) {
+                // already stored as the retained message of its topic by the dispatcher
+                (_, Packet::Publish(p)) if p.flags().contains(PublishFlags::RETAIN) => (),
+                (Some(unmatched), Packet::Publish(p)) => unmatched.keep(p, Instant::now()),
+                _ => (),
+            }
+        }
+        if let Some(id) = job.ack {
+            let mut puback = PubAck::new(id);
+            if delivered == 0 {
+                puback.set_reason_code(PubAckReasonCode::NoMatchingSubscribers);
+            }
+            if let Some(c) = clients.get(client) {
+                if c.send(puback.build()).is_err() {
+                    trace!(clientid = client, "client shutdown: tx closed");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Dedicated tasks fanning out the publishes of the configured topic prefixes, so heavy
+/// topics cannot delay the delivery of the others
+#[derive(Default)]
+pub struct Lanes {
+    lanes: Vec<(Arc<str>, Sender<FanoutJob>)>,
+}
+
+impl Lanes {
+    /// Starts one task per prefix, each task stops once the returned `Lanes` is dropped
+    pub fn spawn(
+        prefixes: &[String],
+        fanout: Fanout,
+        queue_size: usize,
+    ) -> (Self, Vec<JoinHandle<()>>) {
+        let queue_len = (queue_size / size_of::<FanoutJob>()).max(1);
+        let mut lanes = Vec::new();
+        let mut handles = Vec::new();
+        for prefix in prefixes {
+            let (tx, rx) = channel(queue_len);
+            let prefix: Arc<str> = Arc::from(&**prefix);
+            info!(prefix = &*prefix, "Starting fan-out lane");
+            handles.push(tokio::spawn(run_lane(prefix.clone(), fanout.clone(), rx)));
+            lanes.push((prefix, tx));
+        }
+        (Lanes { lanes }, handles)
+    }
+    /// The lane of the longest prefix of `topic`, `None` if the topic has no lane
+    pub fn route(&self, topic: &str) -> Option<&Sender<FanoutJob>> {
+        self.lanes
+            .iter()
+            .filter(|(prefix, _)| topic.starts_with(&**prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, tx)| tx)
+    }
+}
+
+#[instrument(name = "Lane::run", skip(fanout, rx))]
+async fn run_lane(prefix: Arc<str>, fanout: Fanout, mut rx: Receiver<FanoutJob>) {
+    while let Some(job) = rx.recv().await {
+        if let Err(e) = fanout.run(&job).await {
+            error!(clientid = &*job.senderid, "{:?}", e);
+        }
+    }
+    info!("shutting down");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clients::{outgoing_queue, Outgoing, OutgoingReceiver};
+    use crate::config::OverflowPolicy;
+    use crate::shutdown::Shutdown;
+    use crate::sys::SysTopics;
+
+    async fn subscriber(
+        fanout: &Fanout,
+        clientid: &str,
+        topic: &str,
+        qos: QoS,
+    ) -> OutgoingReceiver {
+        let (tx, rx) = outgoing_queue(None, OverflowPolicy::Disconnect);
+        let clientid: Arc<str> = Arc::from(clientid);
+        let client = Client::new_internal(clientid.clone(), Shutdown::new(), tx, true);
+        fanout
+            .clients
+            .write()
+            .await
+            .insert(clientid.clone(), client);
+        fanout
+            .topics
+            .subscribe(clientid, Arc::from(topic), qos, SubscriptionFlags::empty())
+            .await;
+        rx
+    }
+    fn job(topic: &str) -> FanoutJob {
+        FanoutJob {
+            senderid: Arc::from("publisher"),
+            topic: Arc::from(topic),
+            packet: Arc::new(
+                Publish::new(Arc::from(topic), Default::default())
+                    .unwrap()
+                    .build(),
+            ),
+            ack: None,
+            traced: false,
+            #[cfg(feature = "noise")]
+            encrypted: false,
+        }
+    }
+
+    fn fanout() -> Fanout {
+        let metrics = Arc::new(Metrics::new());
+        let sys = Arc::new(SysTopics::new("node"));
+        Fanout::new(
+            Arc::new(TopicsTable::new(metrics.clone(), sys, None)),
+            Arc::new(RwLock::new(HashMap::new())),
+            metrics,
+            Arc::new(MqttServerConfig::default()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_qos1_fanout() {
+        let fanout = fanout();
+        let mut publisher = subscriber(&fanout, "publisher", "unrelated", QoS::QoS0).await;
+        let mut qos0 = subscriber(&fanout, "qos0", "a", QoS::QoS0).await;
+        let mut qos1 = subscriber(&fanout, "qos1", "a", QoS::QoS1).await;
+        let mut publish = Publish::new(Arc::from("a"), Default::default()).unwrap();
+        publish.set_qos(QoS::QoS1);
+        let mut job = job("a");
+        job.packet = Arc::new(publish.build());
+        job.ack = Some(7);
+        fanout.run(&job).await.unwrap();
+
+        let qos_of = |outgoing: Outgoing| match &*outgoing.packet {
+            Packet::Publish(p) => p.qos(),
+            _ => panic!("not a publish"),
+        };
+        assert_eq!(qos_of(qos0.recv().await.unwrap()), QoS::QoS0);
+        assert_eq!(qos_of(qos1.recv().await.unwrap()), QoS::QoS1);
+        match &*publisher.recv().await.unwrap().packet {
+            Packet::PubAck(ack) => {
+                assert_eq!(ack.identifier(), 7);
+                assert!(matches!(ack.reason_code(), PubAckReasonCode::Success));
+            }
+            _ => panic!("not a puback"),
+        }
+
+        job.topic = Arc::from("b");
+        fanout.run(&job).await.unwrap();
+        match &*publisher.recv().await.unwrap().packet {
+            Packet::PubAck(ack) => {
+                assert!(matches!(
+                    ack.reason_code(),
+                    PubAckReasonCode::NoMatchingSubscribers
+                ))
+            }
+            _ => panic!("not a puback"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retain_as_published() {
+        let fanout = fanout();
+        let mut plain = subscriber(&fanout, "plain", "a", QoS::QoS1).await;
+        let mut kept = subscriber(&fanout, "kept", "b", QoS::QoS1).await;
+        fanout
+            .topics
+            .subscribe(
+                Arc::from("kept"),
+                Arc::from("a"),
+                QoS::QoS0,
+                SubscriptionFlags::RETAIN_AS_PUBLISHED,
+            )
+            .await;
+        let retain_of = |outgoing: Outgoing| match &*outgoing.packet {
+            Packet::Publish(p) => (p.qos(), p.flags().contains(PublishFlags::RETAIN)),
+            _ => panic!("not a publish"),
+        };
+        let mut publish = Publish::new(Arc::from("a"), Default::default()).unwrap();
+        publish.set_qos(QoS::QoS1);
+        publish.set_retain();
+        let mut retained = job("a");
+        retained.packet = Arc::new(publish.build());
+        fanout.run(&retained).await.unwrap();
+        assert_eq!(retain_of(plain.recv().await.unwrap()), (QoS::QoS1, false));
+        assert_eq!(retain_of(kept.recv().await.unwrap()), (QoS::QoS0, true));
+
+        // a publish without RETAIN is never forwarded with it
+        fanout.run(&job("a")).await.unwrap();
+        assert_eq!(retain_of(plain.recv().await.unwrap()), (QoS::QoS0, false));
+        assert_eq!(retain_of(kept.recv().await.unwrap()), (QoS::QoS0, false));
+    }
+
+    #[tokio::test]
+    async fn test_shared_frame() {
+        let fanout = fanout();
+        let mut first = subscriber(&fanout, "first", "a", QoS::QoS0).await;
+        let mut second = subscriber(&fanout, "second", "a", QoS::QoS0).await;
+        fanout.run(&job("a")).await.unwrap();
+        let first = first.recv().await.unwrap();
+        let second = second.recv().await.unwrap();
+        assert!(Arc::ptr_eq(&first.packet, &second.packet));
+        let (first, second) = (first.frame.unwrap(), second.frame.unwrap());
+        assert!(Arc::ptr_eq(&first, &second));
+        // encoded by the first worker writing it, the others get the same bytes
+        let written = first.encoded(&job("a").packet, ProtocolVersion::V5);
+        let shared = second.encoded(&job("a").packet, ProtocolVersion::V5);
+        assert_eq!(written.as_ptr(), shared.as_ptr());
+        let mut expected = bytes::BytesMut::new();
+        job("a").packet.to_bytes(&mut expected);
+        assert_eq!(shared, expected);
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let fanout = fanout();
+        let mut online = subscriber(&fanout, "online", "a", QoS::QoS1).await;
+        let _publisher = subscriber(&fanout, "publisher", "b", QoS::QoS0).await;
+        fanout
+            .topics
+            .subscribe(
+                Arc::from("publisher"),
+                Arc::from("a"),
+                QoS::QoS0,
+                SubscriptionFlags::NO_LOCAL,
+            )
+            .await;
+        // a parked session keeps its subscriptions
+        fanout
+            .topics
+            .subscribe(
+                Arc::from("offline"),
+                Arc::from("a"),
+                QoS::QoS0,
+                SubscriptionFlags::empty(),
+            )
+            .await;
+
+        let deliveries = fanout.dry_run(&job("a")).await;
+        let outcomes: Vec<_> = deliveries.iter().map(|(id, d)| (&**id, *d)).collect();
+        assert!(
+            outcomes
+                == vec![
+                    ("offline", Delivery::Offline),
+                    ("online", Delivery::Sent(QoS::QoS0)),
+                    ("publisher", Delivery::NoLocal),
+                ]
+        );
+        assert!(online.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shared_round_robin() {
+        let fanout = fanout();
+        let mut members = Vec::new();
+        for id in ["m1", "m2", "m3"] {
+            members.push(subscriber(&fanout, id, "$share/group/a/+", QoS::QoS0).await);
+        }
+        let mut plain = subscriber(&fanout, "plain", "a/+", QoS::QoS0).await;
+        // the first member is next in line and a dry run does not change that
+        for _ in 0..2 {
+            let deliveries = fanout.dry_run(&job("a/b")).await;
+            let outcomes: Vec<_> = deliveries.iter().map(|(id, d)| (&**id, *d)).collect();
+            assert!(outcomes.contains(&("m1", Delivery::Sent(QoS::QoS0))));
+            assert!(outcomes.contains(&("plain", Delivery::Sent(QoS::QoS0))));
+            assert_eq!(outcomes.len(), 2);
+        }
+        for _ in 0..6 {
+            fanout.run(&job("a/b")).await.unwrap();
+        }
+        for member in &mut members {
+            assert!(member.try_recv().is_some());
+            assert!(member.try_recv().is_some());
+            assert!(member.try_recv().is_none());
+        }
+        for _ in 0..6 {
+            assert!(plain.try_recv().is_some());
+        }
+
+        // disconnected members are skipped
+        fanout.clients.write().await.remove("m2");
+        for _ in 0..4 {
+            fanout.run(&job("a/b")).await.unwrap();
+        }
+        assert!(members[1].try_recv().is_none());
+        let received = members
+            .iter_mut()
+            .map(|m| std::iter::from_fn(|| m.try_recv()).count())
+            .sum::<usize>();
+        assert_eq!(received, 4);
+    }
+
+    #[cfg(feature = "noise")]
+    #[tokio::test]
+    async fn test_suppressed_deliveries() {
+        let fanout = fanout();
+        let mut plain = subscriber(&fanout, "plain", "secret/#", QoS::QoS0).await;
+        let _publisher = subscriber(&fanout, "publisher", "unrelated", QoS::QoS0).await;
+        for topic in ["secret/a", "secret/a", "secret/b"] {
+            let mut job = job(topic);
+            job.encrypted = true;
+            fanout.run(&job).await.unwrap();
+        }
+        assert!(plain.try_recv().is_none());
+        assert_eq!(fanout.metrics.permeability_suppressed(), 3);
+        let suppressed = fanout.metrics.suppressed_deliveries();
+        assert_eq!(suppressed.by_client.get("plain"), Some(&3));
+        assert_eq!(suppressed.by_topic.get("secret/a"), Some(&2));
+        assert_eq!(suppressed.by_topic.get("secret/b"), Some(&1));
+        assert_eq!(suppressed.by_client.len(), 1);
+    }
+
+    #[cfg(feature = "noise")]
+    #[tokio::test]
+    async fn test_permeability_violation_rejected() {
+        let mut fanout = fanout();
+        fanout.cfg = Arc::new(MqttServerConfig {
+            permeability_violation: PermeabilityViolation::Reject,
+            ..Default::default()
+        });
+        let mut publisher = subscriber(&fanout, "publisher", "unrelated", QoS::QoS0).await;
+        let mut plain = subscriber(&fanout, "plain", "secret/#", QoS::QoS1).await;
+        let mut secure = subscriber(&fanout, "secure", "secret/#", QoS::QoS1).await;
+        if let Some(c) = fanout.clients.write().await.get_mut("secure") {
+            c.set_encrypted();
+        }
+        let mut publish = Publish::new(Arc::from("secret/a"), Default::default()).unwrap();
+        publish.set_qos(QoS::QoS1);
+        let mut qos1 = job("secret/a");
+        qos1.packet = Arc::new(publish.build());
+        qos1.ack = Some(7);
+        qos1.encrypted = true;
+        assert!(matches!(
+            fanout.run(&qos1).await,
+            Err(ServerError::PermeabilityViolation)
+        ));
+        // the allowed subscribers are served before the publisher hears about the others
+        assert!(matches!(
+            &*secure.recv().await.unwrap().packet,
+            Packet::Publish(_)
+        ));
+        assert!(plain.try_recv().is_none());
+        match &*publisher.recv().await.unwrap().packet {
+            Packet::PubAck(ack) => {
+                assert_eq!(ack.identifier(), 7);
+                assert_eq!(
+                    ack.reason_code(),
+                    PubAckReasonCode::ImplementationSpecificError
+                );
+            }
+            _ => panic!("not a puback"),
+        }
+        // a single acknowledgement
+        assert!(publisher.try_recv().is_none());
+
+        // QoS 0 publishers are disconnected instead
+        let mut qos0 = job("secret/a");
+        qos0.encrypted = true;
+        assert!(fanout.run(&qos0).await.is_err());
+        assert!(secure.recv().await.is_some());
+        match &*publisher.recv().await.unwrap().packet {
+            Packet::Disconnect(d) => assert_eq!(
+                d.reason_code(),
+                DisconnectReasonCode::ImplementationSpecificError
+            ),
+            _ => panic!("not a disconnect"),
+        }
+        assert_eq!(fanout.metrics.permeability_suppressed(), 2);
+    }
+
+    #[test]
+    fn test_lane_routing() {
+        let lanes = Lanes {
+            lanes: vec![
+                (Arc::from("a/"), channel(1).0),
+                (Arc::from("a/b/"), channel(1).0),
+            ],
+        };
+        let routed_to = |topic, lane: usize| match lanes.route(topic) {
+            Some(tx) => std::ptr::eq(tx, &lanes.lanes[lane].1),
+            None => false,
+        };
+        assert!(routed_to("a/b/c", 1));
+        assert!(routed_to("a/c", 0));
+        assert!(lanes.route("b/a").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_isolated_lane_latency() {
+        const BULK_MESSAGES: usize = 20_000;
+        let fanout = fanout();
+        let mut bulk = Vec::new();
+        for i in 0..16 {
+            bulk.push(subscriber(&fanout, &format!("bulk{}", i), "bulk/data", QoS::QoS0).await);
+        }
+        let mut fast = subscriber(&fanout, "fast", "fast/ping", QoS::QoS0).await;
+        let prefixes = ["bulk/".to_owned(), "fast/".to_owned()];
+        let queue_size = BULK_MESSAGES * size_of::<FanoutJob>();
+        let (lanes, handles) = Lanes::spawn(&prefixes, fanout, queue_size);
+
+        // try_send never yields, the whole backlog is queued before the lanes get to run
+        for _ in 0..BULK_MESSAGES {
+            let lane = lanes.route("bulk/data").unwrap();
+            assert!(lane.try_send(job("bulk/data")).is_ok());
+        }
+        let lane = lanes.route("fast/ping").unwrap();
+        assert!(lane.try_send(job("fast/ping")).is_ok());
+        fast.recv().await.unwrap();
+
+        // the fast lane was served before the bulk lane got through its backlog, although
+        // the ping was queued last
+        let mut delivered = 0;
+        while bulk[0].try_recv().is_some() {
+            delivered += 1;
+        }
+        assert!(delivered < BULK_MESSAGES, "{} bulk messages", delivered);
+
+        drop(lanes);
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        // the bulk lane still delivered everything
+        while bulk[0].try_recv().is_some() {
+            delivered += 1;
+        }
+        assert_eq!(delivered, BULK_MESSAGES);
+    }
+}
@@ -1,67 +1,374 @@
+mod acl;
+mod admin;
+mod builder;
 mod cfg;
-pub mod clients;
+mod clients;
+mod cluster;
 mod config;
+mod cowtree;
 mod dispatcher;
-pub mod error;
+mod error;
+#[cfg(feature = "edge-filter")]
+mod filter;
+mod heartbeat;
+mod intercept;
+mod lanes;
+mod link;
+mod metrics;
+mod msgid;
 mod packetinfo;
+pub mod prelude;
+mod quota;
+mod replication;
+#[cfg(feature = "admin")]
+mod rest;
+mod shutdown;
+mod storage;
+mod subscription;
+mod sys;
+mod throttle;
 mod topics;
+mod trace;
+mod unmatched;
+mod validate;
 
-use clients::{Client, ClientManager};
-pub use config::MqttServerConfig;
+use acl::AclFile;
+use admin::{ApiError, ApiErrorCode};
+use apiformes_packet::prelude::*;
 #[cfg(feature = "noise")]
-pub use config::Permeability;
+use arc_swap::ArcSwap;
+use builder::MqttServerBuilder;
+use bytes::Bytes;
+use cfg::MAX_QOS;
+#[cfg(feature = "noise")]
+use clients::NoiseKey;
+use clients::{
+    is_internal_clientid, Client, ClientManager, ClientSession, DisconnectHistory,
+    DisconnectRecord, InternalClient, SessionExport, SessionStore,
+};
+use config::{DispatcherSharding, MqttServerConfig, Replication, SessionPolicy, SharedDelivery};
+#[cfg(feature = "noise")]
+use config::{Permeability, PermeabilityViolation};
 use dispatcher::Dispatcher;
 use error::ServerError;
+use heartbeat::HeartbeatPublisher;
+use lanes::Delivery;
+use lanes::{Fanout, FanoutJob};
+use metrics::Metrics;
+use msgid::MessageIds;
 use packetinfo::PacketInfo;
+use shutdown::Shutdown;
 use std::mem::size_of;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use storage::{DurableState, FileStorage};
+use subscription::{internal_publish, internal_subscribe, LocalClient, Subscription};
+use sys::SysTopics;
 use tokio::{
-    sync::{mpsc::channel, Notify, RwLock},
+    net::TcpListener,
+    sync::{
+        broadcast,
+        mpsc::{channel, Sender},
+        RwLock,
+    },
     task::JoinHandle,
+    time::{self, Duration, Instant},
 };
-use topics::TopicsTable;
-use tracing::{error, info, instrument};
+use topics::{SubscriptionInfo, TopicsTable};
+use trace::{PublishTrace, PublishTracer};
+use tracing::{error, info, instrument, warn};
+use unmatched::{RetainedTopic, UnmatchedPublishes};
+use uuid::Uuid;
 pub struct MqttServer {
     clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
-    shutdown: Arc<Notify>,
+    shutdown: Shutdown,
     workers: Vec<JoinHandle<()>>,
+    // the address each listener is bound to, by tag
+    local_addrs: Vec<(Arc<str>, SocketAddr)>,
     cfg: Arc<MqttServerConfig>,
     topics: Arc<TopicsTable>,
+    sys: Arc<SysTopics>,
+    metrics: Arc<Metrics>,
+    history: Arc<Mutex<DisconnectHistory>>,
+    sessions: Arc<SessionStore>,
+    // queue of the dispatcher and the internal client messages published by the
+    // embedding application are sent from
+    incoming: Sender<PacketInfo>,
+    publisher_id: Arc<str>,
+    next_subscription: AtomicU64,
+    message_ids: Arc<MessageIds>,
+    tracer: Arc<PublishTracer>,
+    // the retained messages and the kept unmatched publishes
+    unmatched: Arc<UnmatchedPublishes>,
+    // set while the broker is a standby refusing clients
+    standby: Arc<AtomicBool>,
+    // the task following the primary, until the standby is promoted
+    follower: Mutex<Option<JoinHandle<()>>>,
+    following: Shutdown,
+    // only set when the broker is part of a cluster
+    cluster: Option<Arc<cluster::Router>>,
+    // static key the next Noise handshakes are made with
+    #[cfg(feature = "noise")]
+    noise_key: NoiseKey,
 }
 
+/// Name of the internal client used by `MqttServer::publish`
+const EMBEDDER_CLIENT: &str = "server";
+
+/// How often `MqttServer::shutdown_gracefully` checks whether every client is gone
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
 impl MqttServer {
+    /// A builder starting from the default configuration
+    pub fn builder() -> MqttServerBuilder {
+        MqttServerBuilder::default()
+    }
     #[instrument(name = "MqttServer::new", skip(cfg))]
-    pub async fn new(cfg: MqttServerConfig) -> Result<Self, ServerError> {
+    pub async fn new(mut cfg: MqttServerConfig) -> Result<Self, ServerError> {
+        cfg.validate()?;
+        if let Some(path) = &cfg.acl_path {
+            cfg.authorizer = Some(Arc::new(AclFile::load(path)?));
+        }
+        if let Some(path) = &cfg.storage_path {
+            cfg.storage = Some(Arc::new(FileStorage::open(path.clone())?));
+        }
+        let node_id = cfg
+            .node_id
+            .get_or_insert_with(|| Uuid::new_v4().to_hyphenated().to_string())
+            .clone();
+        info!(
+            node_id = &*node_id,
+            "Starting broker {}",
+            sys::version_string()
+        );
         let queue_len = cfg.dispatcher_queue_size / size_of::<PacketInfo>();
         let (incoming_tx, incoming_rx) = channel(queue_len);
-        let shutdown = Arc::new(Notify::new());
+        let shutdown = Shutdown::new();
         let cfg = Arc::new(cfg);
         let clients = Arc::new(RwLock::new(HashMap::new()));
-        let mut workers =
-            ClientManager::start(cfg.clone(), clients.clone(), shutdown.clone(), incoming_tx)
-                .await?;
-        let topics = Arc::new(TopicsTable::new());
-        let dispatcher = Dispatcher::new(
+        let metrics = Arc::new(Metrics::new());
+        let sys = Arc::new(SysTopics::new(&node_id));
+        if cfg.publish_config {
+            sys.set(Arc::from(sys::SYS_CONFIG), Bytes::from(cfg.snapshot()?))
+                .await;
+        }
+        let topics = Arc::new(
+            TopicsTable::new(metrics.clone(), sys.clone(), cfg.topic_tree_alarm.clone())
+                .cache_subscribers(cfg.subscriber_cache_size)
+                .subscription_tree(cfg.subscription_tree),
+        );
+        let history = Arc::new(Mutex::new(DisconnectHistory::new(
+            cfg.disconnect_history_size,
+        )));
+        let sessions = Arc::new(SessionStore::new());
+        let tracer = Arc::new(PublishTracer::new());
+        let standby = Arc::new(AtomicBool::new(matches!(
+            cfg.replication,
+            Some(Replication::Standby { .. })
+        )));
+        #[cfg(feature = "noise")]
+        let noise_key = Arc::new(ArcSwap::from_pointee(cfg.private_key));
+        let (mut workers, mut local_addrs) = ClientManager::start(
+            cfg.clone(),
+            clients.clone(),
+            topics.clone(),
+            metrics.clone(),
+            history.clone(),
+            shutdown.clone(),
+            incoming_tx.clone(),
+            sessions.clone(),
+            tracer.clone(),
+            standby.clone(),
+            #[cfg(feature = "noise")]
+            noise_key.clone(),
+        )
+        .await?;
+        let message_ids = Arc::new(MessageIds::open(cfg.message_id_path.clone())?);
+        let (replicas, _) = broadcast::channel(replication::RETAINED_BACKLOG);
+        let mut unmatched = UnmatchedPublishes::new(
+            cfg.unmatched_publishes.clone(),
+            cfg.retained_limits.clone(),
+            metrics.clone(),
+        );
+        if let Some(Replication::Primary { .. }) = cfg.replication {
+            unmatched = unmatched.replicate(replicas.clone());
+        }
+        let unmatched = Arc::new(unmatched);
+        let mut dispatcher = Dispatcher::new(
             topics.clone(),
+            sys.clone(),
+            metrics.clone(),
             cfg.clone(),
             shutdown.clone(),
             clients.clone(),
             incoming_rx,
-        );
+            unmatched.clone(),
+        )
+        .trace_publishes(tracer.clone());
+        if cfg.stamp_message_ids {
+            dispatcher = dispatcher.stamp_message_ids(message_ids.clone());
+        }
+        let mut cluster = None;
+        if let Some(peers) = cfg.cluster.clone() {
+            let client =
+                InternalClient::register_untrusted("cluster", clients.clone(), shutdown.clone())
+                    .await?;
+            let router = Arc::new(cluster::Router::new(
+                peers,
+                topics.clone(),
+                metrics.clone(),
+                client.clientid().clone(),
+                incoming_tx.clone(),
+            ));
+            let (links, local) = cluster::start(router.clone(), client, shutdown.clone()).await?;
+            workers.extend(links);
+            local_addrs.push((Arc::from("cluster"), local));
+            dispatcher = dispatcher.forward_to_peers(router.clone());
+            cluster = Some(router);
+        }
         workers.push(dispatcher.spawn().await);
+        if let Some(heartbeat) = cfg.heartbeat.clone() {
+            let client =
+                InternalClient::register("heartbeat", clients.clone(), shutdown.clone()).await?;
+            let heartbeat = HeartbeatPublisher::new(
+                heartbeat,
+                client,
+                clients.clone(),
+                topics.clone(),
+                metrics.clone(),
+                sys.clone(),
+                incoming_tx.clone(),
+                queue_len,
+            );
+            workers.push(tokio::spawn(heartbeat.run(shutdown.clone())));
+        }
+        if let Some(period) = cfg.topic_tree_sweep {
+            let period = Duration::from_secs(period as u64);
+            workers.push(tokio::spawn(topics.clone().sweep(period, shutdown.clone())));
+        }
+        let durable = Arc::new(DurableState::new(
+            Arc::from(&*node_id),
+            clients.clone(),
+            sessions.clone(),
+            topics.clone(),
+            unmatched.clone(),
+            message_ids.clone(),
+        ));
+        if let Some(storage) = cfg.storage.clone() {
+            let (sessions, retained) = durable.restore(&*storage).await?;
+            info!(
+                "Restored {} sessions and {} retained messages",
+                sessions, retained
+            );
+            let period = Duration::from_secs(cfg.persist_interval as u64);
+            workers.push(tokio::spawn(storage::persist(
+                durable.clone(),
+                storage,
+                period,
+                shutdown.clone(),
+            )));
+        }
+        let following = Shutdown::new();
+        let mut follower = None;
+        match &cfg.replication {
+            Some(Replication::Primary {
+                listen,
+                key,
+                max_lag_ms,
+            }) => {
+                let listener = TcpListener::bind(listen).await?;
+                let local = listener.local_addr()?;
+                info!(
+                    SocketAddr = &*local.to_string(),
+                    "Starting listener for standbys"
+                );
+                local_addrs.push((Arc::from("replication"), local));
+                workers.push(tokio::spawn(replication::serve(
+                    listener,
+                    key.clone(),
+                    durable,
+                    replicas,
+                    Duration::from_millis(*max_lag_ms as u64),
+                    shutdown.clone(),
+                )));
+            }
+            Some(Replication::Standby { primary, key }) => {
+                follower = Some(tokio::spawn(replication::follow(
+                    primary.clone(),
+                    key.clone(),
+                    durable,
+                    following.clone(),
+                )));
+            }
+            None => (),
+        }
+        let mut publisher =
+            InternalClient::register(EMBEDDER_CLIENT, clients.clone(), shutdown.clone()).await?;
+        let publisher_id = publisher.clientid().clone();
+        #[cfg(feature = "admin")]
+        if let Some(saddr) = cfg.admin_socketaddr {
+            let listener = TcpListener::bind(saddr).await?;
+            let local = listener.local_addr()?;
+            info!(SocketAddr = &*local.to_string(), "Starting the admin API");
+            local_addrs.push((Arc::from("admin"), local));
+            let api = Arc::new(rest::AdminApi {
+                clients: clients.clone(),
+                topics: topics.clone(),
+                incoming: incoming_tx.clone(),
+                publisher_id: publisher_id.clone(),
+                max_body: cfg.max_packet_size as usize,
+            });
+            workers.push(tokio::spawn(rest::serve(listener, api, shutdown.clone())));
+        }
+        tokio::spawn(async move {
+            // nothing is ever addressed to this client except errors about its publishes
+            while let Some(packet) = publisher.recv().await {
+                if let Packet::Disconnect(d) = &*packet {
+                    warn!(
+                        clientid = &**publisher.clientid(),
+                        reason = d.reason_code().as_str(),
+                        "Publish rejected by the dispatcher"
+                    );
+                }
+            }
+        });
         Ok(MqttServer {
             clients,
             shutdown,
             workers,
+            local_addrs,
             cfg,
             topics,
+            sys,
+            metrics,
+            history,
+            sessions,
+            incoming: incoming_tx,
+            publisher_id,
+            next_subscription: AtomicU64::new(0),
+            message_ids,
+            tracer,
+            unmatched,
+            standby,
+            follower: Mutex::new(follower),
+            following,
+            cluster,
+            #[cfg(feature = "noise")]
+            noise_key,
         })
     }
 
     #[instrument(name = "MqttServer::shutdown", skip(self))]
     pub async fn shutdown(self) {
         // TODO keep track of https://github.com/tokio-rs/tokio/issues/3903
-        self.shutdown.notify_one();
+        self.shutdown.trigger();
+        self.following.trigger();
         for worker in self.workers {
             if let Err(e) = worker.await {
                 error!("Failed killing one of the workers, {:?}", e);
@@ -69,13 +376,355 @@ impl MqttServer {
         }
         info!("Shutting down");
     }
+    /// Stops accepting connections and lets every connected client receive and acknowledge
+    /// what is left for it before disconnecting it with ServerShuttingDown, then shuts down
+    /// once they are gone or `MqttServerConfig::drain_timeout` passed
+    #[instrument(name = "MqttServer::shutdown_gracefully", skip(self))]
+    pub async fn shutdown_gracefully(self) {
+        let deadline = Instant::now() + Duration::from_secs(self.cfg.drain_timeout as u64);
+        info!("Draining connections");
+        self.shutdown.drain(deadline);
+        while Instant::now() < deadline && !self.clients().await.is_empty() {
+            time::sleep(DRAIN_POLL).await;
+        }
+        self.shutdown().await;
+    }
+    /// Publishes a message to the connected clients as if it was sent by the broker's
+    /// internal client `$internal/server`, without going through a network connection
+    pub async fn publish(
+        &self,
+        topic: &str,
+        payload: Bytes,
+        qos: QoS,
+        retain: bool,
+        props: impl IntoIterator<Item = (Property, MqttPropValue)>,
+    ) -> Result<(), ServerError> {
+        let packet = internal_publish(topic, payload, qos, retain, props)?;
+        self.incoming
+            .send(PacketInfo {
+                senderid: self.publisher_id.clone(),
+                packet,
+                traced: false,
+            })
+            .await
+            .map_err(|_| ServerError::Misc("Dispatcher is not running".to_owned()))
+    }
+    /// Subscribes to `filter` through a new internal client `$internal/subscription/<n>`
+    /// and returns the stream of matching messages
+    pub async fn subscribe(&self, filter: &str, qos: QoS) -> Result<Subscription, ServerError> {
+        if qos.as_u8() > MAX_QOS {
+            return Err(ServerError::QoSNotSupported(qos.as_u8()));
+        }
+        MqttTopic::new(Arc::from(filter))?;
+        let n = self.next_subscription.fetch_add(1, Ordering::Relaxed);
+        let client = self
+            .register_internal_client(&format!("subscription/{}", n))
+            .await?;
+        let subscription = Subscription::new(client, self.topics.clone());
+        let clientid = subscription.clientid();
+        internal_subscribe(
+            &self.topics,
+            &self.sys,
+            &self.clients,
+            clientid,
+            filter,
+            qos,
+        )
+        .await?;
+        Ok(subscription)
+    }
+    /// A new client `$internal/local/<n>` publishing and subscribing without any network
+    /// connection, for the applications embedding the broker
+    pub async fn local_client(&self) -> Result<LocalClient, ServerError> {
+        let n = self.next_subscription.fetch_add(1, Ordering::Relaxed);
+        let client = self
+            .register_internal_client(&format!("local/{}", n))
+            .await?;
+        Ok(LocalClient::new(
+            client,
+            self.topics.clone(),
+            self.sys.clone(),
+            self.clients.clone(),
+            self.incoming.clone(),
+        ))
+    }
     pub fn get_topics(&self) -> &TopicsTable {
         &self.topics
     }
+    /// The subscriptions a message published on `topic` is matched against, ordered by
+    /// client id. Sessions of disconnected clients are included.
+    pub async fn matching_subscriptions(&self, topic: &str) -> Vec<(Arc<str>, SubscriptionInfo)> {
+        self.topics.matching_subscriptions(topic).await
+    }
+    /// Tells who would receive a message `senderid` publishes on `topic`, and why the other
+    /// matching subscriptions would not, without publishing anything. Meant for debugging
+    /// subscribers that do not get the messages they expect.
+    pub async fn dry_run_publish(
+        &self,
+        senderid: &str,
+        topic: &str,
+        payload: Bytes,
+        qos: QoS,
+    ) -> Result<Vec<(Arc<str>, Delivery)>, ServerError> {
+        let mut publish = Publish::new(Arc::from(topic), payload)?;
+        publish.set_qos(qos);
+        #[cfg(feature = "noise")]
+        let encrypted = matches!(self.clients.read().await.get(senderid), Some(c) if c.encrypted());
+        let job = FanoutJob {
+            senderid: Arc::from(senderid),
+            topic: Arc::from(topic),
+            packet: Arc::new(publish.build()),
+            ack: None,
+            traced: false,
+            #[cfg(feature = "noise")]
+            encrypted,
+        };
+        let fanout = Fanout::new(
+            self.topics.clone(),
+            self.clients.clone(),
+            self.metrics.clone(),
+            self.cfg.clone(),
+        );
+        Ok(fanout.dry_run(&job).await)
+    }
+    /// Returns the ids of all connected clients, internal pseudo-clients are not included
     pub async fn clients(&self) -> Vec<Arc<str>> {
+        self.clients
+            .read()
+            .await
+            .keys()
+            .filter(|id| !is_internal_clientid(id))
+            .cloned()
+            .collect()
+    }
+    /// The connected clients with their session, ordered by client id. Internal
+    /// pseudo-clients are not included.
+    pub async fn client_sessions(&self) -> Vec<ClientSession> {
+        clients::client_sessions(&self.clients, &self.topics).await
+    }
+    /// Disconnects `clientid` with the AdministrativeAction reason code. Its will is
+    /// published and its session kept as for any other disconnect initiated by the broker.
+    pub async fn disconnect_client(&self, clientid: &str) -> Result<(), ApiError> {
+        clients::disconnect_client(&self.clients, clientid).await?;
+        info!(clientid, "Disconnected by the embedding application");
+        Ok(())
+    }
+    /// Same as `clients` but also includes the broker's internal pseudo-clients
+    pub async fn all_clients(&self) -> Vec<Arc<str>> {
         self.clients.read().await.keys().cloned().collect()
     }
+    /// Registers a pseudo-client named `$internal/<name>`
+    pub async fn register_internal_client(
+        &self,
+        name: &str,
+    ) -> Result<InternalClient, ServerError> {
+        InternalClient::register(name, self.clients.clone(), self.shutdown.clone()).await
+    }
+    /// The address the listener tagged `tag` is bound to, e.g. to learn the port picked for
+    /// a listener configured with port 0. The listeners of `mqtt_socketaddr`,
+    /// `noise_socketaddr` and `multiplex_socketaddr` are tagged `mqtt`, `noise` and
+    /// `multiplex`, those of the admin API, the cluster and the replication `admin`,
+    /// `cluster` and `replication`.
+    pub fn local_addr(&self, tag: &str) -> Option<SocketAddr> {
+        self.local_addrs
+            .iter()
+            .find(|(t, _)| &**t == tag)
+            .map(|(_, addr)| *addr)
+    }
     pub fn config(&self) -> &MqttServerConfig {
         &self.cfg
     }
+    /// The configuration the broker is running with, as JSON with the secrets redacted
+    pub fn config_snapshot(&self) -> Result<String, ApiError> {
+        Ok(self.cfg.snapshot()?)
+    }
+    pub fn node_id(&self) -> &str {
+        // node_id is always populated by `MqttServer::new`
+        self.cfg.node_id.as_deref().unwrap_or_default()
+    }
+    /// Payload currently published under the `$SYS` topic `topic`, e.g.
+    /// `$SYS/broker/version`
+    pub async fn sys_topic(&self, topic: &str) -> Option<Bytes> {
+        self.sys.get(topic).await
+    }
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+    /// Drops the subscribers cached by topic, returns the number of topics they were cached
+    /// for. The cache is kept coherent on its own, this is meant for tooling.
+    pub fn flush_cache(&self) -> usize {
+        let flushed = self.topics.flush_cache();
+        info!("Flushed the subscribers of {} topics", flushed);
+        flushed
+    }
+    /// The id the next forwarded message is stamped with
+    pub fn message_id(&self) -> u64 {
+        self.message_ids.current()
+    }
+    /// Restarts the message ids at `value`, peers deduplicating on them must be told to
+    /// forget the ids they have already seen
+    pub fn reset_message_ids(&self, value: u64) -> Result<(), ApiError> {
+        self.message_ids.reset(value).map_err(|e| {
+            ApiError::new(ApiErrorCode::Unavailable, e.to_string()).with_field("message_id_path")
+        })
+    }
+    /// The sessions outliving their connection, those of the connected clients included,
+    /// with their subscriptions, pending publishes and wills. Payload filters are not
+    /// exported. Meant for moving clients to a new broker: import the sessions there with
+    /// `import_sessions`, then send the clients over with `redirect_clients`.
+    pub async fn export_sessions(&self) -> SessionExport {
+        SessionExport {
+            node_id: self.node_id().to_owned(),
+            message_id: self.message_id(),
+            sessions: clients::export_sessions(&self.clients, &self.sessions, &self.topics).await,
+        }
+    }
+    /// Parks the sessions of `export` until their clients connect, the clients already
+    /// connected or holding a session here keep theirs. Message ids are raised to those of
+    /// the exporting broker. Returns the number of sessions imported.
+    pub async fn import_sessions(&self, export: &SessionExport) -> Result<usize, ApiError> {
+        if self.cfg.session_policy != SessionPolicy::RetainUntilExpiry {
+            return Err(ApiError::new(
+                ApiErrorCode::InvalidConfig,
+                "sessions are not retained by this broker".to_owned(),
+            )
+            .with_field("session_policy"));
+        }
+        let imported = clients::import_sessions(
+            &export.sessions,
+            &self.clients,
+            &self.sessions,
+            &self.topics,
+        )
+        .await?;
+        if export.message_id > self.message_id() {
+            self.reset_message_ids(export.message_id)?;
+        }
+        info!(node_id = &*export.node_id, "Imported {} sessions", imported);
+        Ok(imported)
+    }
+    /// Turns a standby into a broker serving clients, with the retained messages and the
+    /// sessions last received from its primary. The primary must be gone for good, it
+    /// would otherwise keep serving its own clients.
+    pub async fn promote(&self) -> Result<(), ApiError> {
+        let follower = self.follower.lock().unwrap().take();
+        let follower = follower.ok_or_else(|| {
+            ApiError::new(
+                ApiErrorCode::InvalidArgument,
+                "this broker is not a standby".to_owned(),
+            )
+            .with_field("replication")
+        })?;
+        // the packet being applied is applied whole
+        self.following.trigger();
+        if let Err(e) = follower.await {
+            error!("Failed stopping the replication, {:?}", e);
+        }
+        self.standby.store(false, Ordering::Release);
+        info!("Promoted, accepting clients");
+        Ok(())
+    }
+    /// Replaces the static private key of the Noise listener. The handshakes started from now
+    /// on use the new key, the clients already connected keep their session.
+    #[cfg(feature = "noise")]
+    pub fn rotate_noise_key(&self, private_key: [u8; 32]) -> Result<(), ApiError> {
+        snow::Builder::new(self.cfg.noise_pattern.parse().unwrap())
+            .local_private_key(&private_key[..])
+            .build_responder()
+            .map_err(|e| {
+                ApiError::new(ApiErrorCode::InvalidArgument, format!("{:?}", e))
+                    .with_field("private_key")
+            })?;
+        self.noise_key.store(Arc::new(private_key));
+        info!("Rotated the static key of the noise listener");
+        Ok(())
+    }
+    /// Disconnects every client with the ServerMoved reason code, telling them to connect to
+    /// `server_reference` instead (4.11). Their wills are not published. Returns the number
+    /// of clients redirected.
+    pub async fn redirect_clients(&self, server_reference: &str) -> Result<usize, ApiError> {
+        let reference = MqttPropValue::new_string(Arc::from(server_reference)).map_err(|e| {
+            ApiError::new(ApiErrorCode::InvalidArgument, format!("{:?}", e))
+                .with_field("server_reference")
+        })?;
+        let mut disconnect = Disconnect::new(DisconnectReasonCode::ServerMoved);
+        disconnect
+            .add_prop(Property::ServerReference, reference)
+            .unwrap();
+        let disconnect = Arc::new(disconnect.build());
+        let clients = self.clients.read().await;
+        let redirected = clients
+            .values()
+            .filter(|c| !c.internal())
+            .filter(|c| c.send(disconnect.clone()).is_ok())
+            .count();
+        info!(
+            server_reference,
+            "Redirected {} clients to another server", redirected
+        );
+        Ok(redirected)
+    }
+    /// Follows the next publish a client sends on `topic` through the broker and returns
+    /// when each stage was reached. The trace is incomplete if some recipients were not
+    /// written to within `timeout`, an error if no publish was received at all. Meant for
+    /// debugging, a single publish is traced at a time.
+    pub async fn trace_next_publish(
+        &self,
+        topic: &str,
+        timeout: Duration,
+    ) -> Result<PublishTrace, ApiError> {
+        let invalid = || {
+            ApiError::new(
+                ApiErrorCode::InvalidArgument,
+                format!("{} is not a topic name", topic),
+            )
+            .with_field("topic")
+        };
+        match MqttTopic::new(Arc::from(topic)) {
+            Ok(name) if !topic.is_empty() && !name.is_wildcard() => (),
+            _ => return Err(invalid()),
+        }
+        let mut done = self.tracer.arm(topic)?;
+        if let Ok(Ok(trace)) = time::timeout(timeout, &mut done).await {
+            return Ok(trace);
+        }
+        match self.tracer.disarm() {
+            Some(partial) => Ok(partial),
+            // completed while giving up
+            None => done.try_recv().map_err(|_| {
+                ApiError::new(
+                    ApiErrorCode::Unavailable,
+                    format!("no publish on {} within {:?}", topic, timeout),
+                )
+            }),
+        }
+    }
+    /// The peers of this node and what it knows of them, empty outside of a cluster
+    pub fn cluster_peers(&self) -> Vec<cluster::ClusterPeer> {
+        self.cluster.as_ref().map_or_else(Vec::new, |c| c.peers())
+    }
+    /// How the retained topics were used, those published with RETAIN and those kept under
+    /// `UnmatchedPolicy::Retain`, the next one to be evicted first
+    pub fn retained_topics(&self) -> Vec<RetainedTopic> {
+        self.unmatched.retained_topics(Instant::now())
+    }
+    /// The deliveries withheld from unencrypted subscribers by `Permeability::Strict`, as
+    /// `{"by_client":{"sensor":3},"by_topic":{"secret/a":3}}`
+    #[cfg(feature = "noise")]
+    pub fn suppressed_deliveries(&self) -> String {
+        serde_json::to_string(&self.metrics.suppressed_deliveries()).unwrap()
+    }
+    /// Why and when `clientid` was last disconnected, if it is still remembered
+    pub fn last_disconnect(&self, clientid: &str) -> Option<DisconnectRecord> {
+        self.history.lock().unwrap().get(clientid).cloned()
+    }
+    /// The remembered disconnects, most recent first
+    pub fn recent_disconnects(&self) -> Vec<(Arc<str>, DisconnectRecord)> {
+        self.history
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, record)| (id.clone(), record.clone()))
+            .collect()
+    }
 }
@@ -0,0 +1,171 @@
+//! Framing of the packets exchanged between brokers over the cluster and replication links
+use crate::error::ServerError;
+use apiformes_packet::prelude::*;
+use bytes::BytesMut;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{timeout, Duration};
+
+/// Largest frame sent or accepted over a link. It bounds what a broken or hostile peer
+/// makes the broker buffer, the session export of a primary beyond it is not replicated.
+pub(crate) const MAX_LINK_FRAME: usize = 64 * 1024 * 1024;
+
+/// AuthenticationMethod of the AUTH packet opening a link, its AuthenticationData is the
+/// key shared by the brokers
+const KEY_METHOD: &str = "link-key";
+
+/// Time the broker opening a link has to present the key before it is closed
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Presents `key` to the broker accepting the link, before anything else is sent
+pub(crate) async fn authenticate<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    key: &str,
+) -> Result<(), ServerError> {
+    let mut auth = Auth::new(AuthReasonCode::ContinueAuthentication);
+    auth.add_prop(
+        Property::AuthenticationMethod,
+        MqttPropValue::new_string(Arc::from(KEY_METHOD))?,
+    )?;
+    auth.add_prop(
+        Property::AuthenticationData,
+        MqttPropValue::new_data(key.as_bytes())?,
+    )?;
+    send(writer, auth.build()).await
+}
+
+pub(crate) async fn send<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    packet: Packet,
+) -> Result<(), ServerError> {
+    let len = packet.frame_len();
+    if len > MAX_LINK_FRAME {
+        return Err(ServerError::MaxPacketSizeExceeded);
+    }
+    let mut bytes = BytesMut::with_capacity(len);
+    packet.to_bytes(&mut bytes);
+    writer.write_all_buf(&mut bytes).await?;
+    Ok(())
+}
+
+/// Decodes the packets a peer sends, a frame over `MAX_LINK_FRAME` breaks the link as
+/// soon as its fixed header is read
+pub(crate) struct LinkReader<R> {
+    reader: R,
+    buf: BytesMut,
+    decoder: PacketDecoder,
+}
+
+impl<R: AsyncRead + Unpin> LinkReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        LinkReader {
+            reader,
+            buf: BytesMut::new(),
+            decoder: PacketDecoder::new().max_packet_size(MAX_LINK_FRAME),
+        }
+    }
+    /// Waits for the broker opening the link to present `key` with `authenticate`, fails
+    /// when it presents another one or nothing within `HANDSHAKE_TIMEOUT`
+    pub(crate) async fn authenticated(&mut self, key: &str) -> Result<(), ServerError> {
+        let first = timeout(HANDSHAKE_TIMEOUT, async {
+            loop {
+                if let Decoded::Packet(packet) = self.decoder.decode_from(&mut self.buf)? {
+                    return Ok(packet);
+                }
+                if self.reader.read_buf(&mut self.buf).await? == 0 {
+                    return Err(ServerError::ConnectionClosed);
+                }
+            }
+        })
+        .await
+        .map_err(|_| ServerError::AuthenticationFailed)??;
+        let presented = match &first {
+            Packet::Auth(auth) => match auth.get_prop(Property::AuthenticationData) {
+                Some([data]) => data.into_data().map_or(&[][..], |data| &data[..]),
+                _ => &[],
+            },
+            _ => &[],
+        };
+        // compared in full whatever the first difference, not to hint at the key
+        let differences = presented
+            .iter()
+            .zip(key.as_bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        if presented.len() != key.len() || differences != 0 {
+            return Err(ServerError::AuthenticationFailed);
+        }
+        Ok(())
+    }
+    /// The packets received with the next read, or those already buffered, fails once the
+    /// link is closed. Cancelling it loses nothing, the packets are decoded once the read
+    /// completed.
+    pub(crate) async fn recv(&mut self) -> Result<Vec<Packet>, ServerError> {
+        let mut packets = self.decode()?;
+        if packets.is_empty() {
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return Err(ServerError::ConnectionClosed);
+            }
+            packets = self.decode()?;
+        }
+        Ok(packets)
+    }
+    fn decode(&mut self) -> Result<Vec<Packet>, ServerError> {
+        let mut packets = Vec::new();
+        while let Decoded::Packet(packet) = self.decoder.decode_from(&mut self.buf)? {
+            packets.push(packet);
+        }
+        Ok(packets)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_link_frames() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (mut reader, mut writer) = (LinkReader::new(server), client);
+        let publish = Publish::new("a/b".into(), "hello".into()).unwrap();
+        // a frame split over several reads
+        let mut bytes = BytesMut::new();
+        publish.clone().build().to_bytes(&mut bytes);
+        writer.write_all(&bytes[..3]).await.unwrap();
+        assert!(reader.recv().await.unwrap().is_empty());
+        writer.write_all(&bytes[3..]).await.unwrap();
+        send(&mut writer, Ping::new().build_req()).await.unwrap();
+        let mut packets = Vec::new();
+        while packets.len() < 2 {
+            packets.extend(reader.recv().await.unwrap());
+        }
+        assert_eq!(packets, [publish.build(), Ping::new().build_req()]);
+        // a PUBLISH announcing 256MB is refused before its body is read
+        writer
+            .write_all(&[0x30, 0xff, 0xff, 0xff, 0x7f])
+            .await
+            .unwrap();
+        assert!(reader.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_link_key() {
+        for (presented, accepted) in [("secret", true), ("secreT", false), ("secrets", false)] {
+            let (client, server) = tokio::io::duplex(1024);
+            let (mut reader, mut writer) = (LinkReader::new(server), client);
+            // sent along with the key, read once it is checked
+            let mut bytes = BytesMut::new();
+            Ping::new().build_req().to_bytes(&mut bytes);
+            authenticate(&mut writer, presented).await.unwrap();
+            writer.write_all(&bytes).await.unwrap();
+            assert_eq!(reader.authenticated("secret").await.is_ok(), accepted);
+            if accepted {
+                assert_eq!(reader.recv().await.unwrap(), [Ping::new().build_req()]);
+            }
+        }
+        // a link opening with anything but the key
+        let (client, server) = tokio::io::duplex(1024);
+        let (mut reader, mut writer) = (LinkReader::new(server), client);
+        send(&mut writer, Ping::new().build_req()).await.unwrap();
+        assert!(reader.authenticated("secret").await.is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Number of clients and of topics `SuppressedDeliveries` counts separately, the
+/// suppressions beyond it only show in `Metrics::permeability_suppressed`
+const MAX_SUPPRESSION_KEYS: usize = 10_000;
+
+/// Deliveries skipped because of `Permeability::Strict`, by unencrypted subscriber and by
+/// topic
+#[derive(Serialize, Default, Clone)]
+pub struct SuppressedDeliveries {
+    pub by_client: BTreeMap<String, u64>,
+    pub by_topic: BTreeMap<String, u64>,
+}
+
+impl SuppressedDeliveries {
+    /// Counts one suppression under `key`, true the first time `key` is counted
+    fn count(counters: &mut BTreeMap<String, u64>, key: &str) -> bool {
+        if let Some(n) = counters.get_mut(key) {
+            *n += 1;
+            return false;
+        }
+        if counters.len() >= MAX_SUPPRESSION_KEYS {
+            return false;
+        }
+        counters.insert(key.to_owned(), 1);
+        true
+    }
+}
+
+/// Counters and gauges describing the broker's health, they can be read at any time from
+/// any thread. Counters are only ever incremented.
+#[derive(Default)]
+pub struct Metrics {
+    worker_panics: AtomicU64,
+    permeability_suppressed: AtomicU64,
+    payload_filtered: AtomicU64,
+    topic_tree_depth: AtomicU64,
+    topic_tree_nodes: AtomicU64,
+    subscriptions: AtomicU64,
+    topic_tree_alarms: AtomicU64,
+    connects_paced: AtomicU64,
+    connects_refused_busy: AtomicU64,
+    connects_refused_rate: AtomicU64,
+    publishes_received: AtomicU64,
+    payloads_rejected: AtomicU64,
+    publishes_over_quota: AtomicU64,
+    acl_denied: AtomicU64,
+    retained_evictions: AtomicU64,
+    retained_bytes: AtomicU64,
+    subscriber_cache_hits: AtomicU64,
+    subscriber_cache_misses: AtomicU64,
+    clients_purged: AtomicU64,
+    sessions_evicted: AtomicU64,
+    cluster_forwarded: AtomicU64,
+    cluster_dropped: AtomicU64,
+    suppressed_deliveries: Mutex<SuppressedDeliveries>,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+    /// Number of client workers that panicked and had to be cleaned up
+    pub fn worker_panics(&self) -> u64 {
+        self.worker_panics.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_worker_panics(&self) {
+        self.worker_panics.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of deliveries skipped because of `Permeability::Strict`
+    pub fn permeability_suppressed(&self) -> u64 {
+        self.permeability_suppressed.load(Ordering::Relaxed)
+    }
+    #[cfg_attr(not(feature = "noise"), allow(dead_code))]
+    pub(crate) fn add_permeability_suppressed(&self, n: u64) {
+        self.permeability_suppressed.fetch_add(n, Ordering::Relaxed);
+    }
+    /// The deliveries counted by `permeability_suppressed` by subscriber and by topic
+    pub fn suppressed_deliveries(&self) -> SuppressedDeliveries {
+        self.suppressed_deliveries.lock().unwrap().clone()
+    }
+    /// Counts a delivery to `clientid` on `topic` suppressed by `Permeability::Strict`,
+    /// true the first time one is suppressed for `clientid`
+    #[cfg_attr(not(feature = "noise"), allow(dead_code))]
+    pub(crate) fn inc_suppressed_delivery(&self, clientid: &str, topic: &str) -> bool {
+        let mut suppressed = self.suppressed_deliveries.lock().unwrap();
+        SuppressedDeliveries::count(&mut suppressed.by_topic, topic);
+        SuppressedDeliveries::count(&mut suppressed.by_client, clientid)
+    }
+    /// Number of deliveries skipped because the payload did not match the subscription filter
+    pub fn payload_filtered(&self) -> u64 {
+        self.payload_filtered.load(Ordering::Relaxed)
+    }
+    #[cfg_attr(not(feature = "edge-filter"), allow(dead_code))]
+    pub(crate) fn add_payload_filtered(&self, n: u64) {
+        self.payload_filtered.fetch_add(n, Ordering::Relaxed);
+    }
+    /// Number of publishes refused by a payload validator
+    pub fn payloads_rejected(&self) -> u64 {
+        self.payloads_rejected.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_payloads_rejected(&self) {
+        self.payloads_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of clients disconnected for publishing above `MqttServerConfig::publish_quotas`
+    pub fn publishes_over_quota(&self) -> u64 {
+        self.publishes_over_quota.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_publishes_over_quota(&self) {
+        self.publishes_over_quota.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of publishes and subscriptions refused by the authorizer
+    pub fn acl_denied(&self) -> u64 {
+        self.acl_denied.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_acl_denied(&self) {
+        self.acl_denied.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of levels of the deepest topic filter ever subscribed to
+    pub fn topic_tree_depth(&self) -> u64 {
+        self.topic_tree_depth.load(Ordering::Relaxed)
+    }
+    /// Number of nodes in the subscription tree, empty ones are only removed by the sweeps of
+    /// `MqttServerConfig::topic_tree_sweep`
+    pub fn topic_tree_nodes(&self) -> u64 {
+        self.topic_tree_nodes.load(Ordering::Relaxed)
+    }
+    /// Number of active subscriptions, across all clients
+    pub fn subscriptions(&self) -> u64 {
+        self.subscriptions.load(Ordering::Relaxed)
+    }
+    /// Number of times the subscription tree went over the bounds of `TopicTreeAlarm`
+    pub fn topic_tree_alarms(&self) -> u64 {
+        self.topic_tree_alarms.load(Ordering::Relaxed)
+    }
+    pub(crate) fn update_topic_tree_depth(&self, depth: u64) {
+        self.topic_tree_depth.fetch_max(depth, Ordering::Relaxed);
+    }
+    pub(crate) fn add_topic_tree_nodes(&self, n: u64) {
+        self.topic_tree_nodes.fetch_add(n, Ordering::Relaxed);
+    }
+    pub(crate) fn sub_topic_tree_nodes(&self, n: u64) {
+        self.topic_tree_nodes.fetch_sub(n, Ordering::Relaxed);
+    }
+    pub(crate) fn add_subscriptions(&self, n: u64) {
+        self.subscriptions.fetch_add(n, Ordering::Relaxed);
+    }
+    pub(crate) fn sub_subscriptions(&self, n: u64) {
+        self.subscriptions.fetch_sub(n, Ordering::Relaxed);
+    }
+    pub(crate) fn inc_topic_tree_alarms(&self) {
+        self.topic_tree_alarms.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of CONNACKs delayed by `MqttServerConfig::connect_rate`
+    pub fn connects_paced(&self) -> u64 {
+        self.connects_paced.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_connects_paced(&self) {
+        self.connects_paced.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of connections refused with ServerBusy by `MqttServerConfig::connect_rate` or
+    /// `MqttServerConfig::max_connections`
+    pub fn connects_refused_busy(&self) -> u64 {
+        self.connects_refused_busy.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_connects_refused_busy(&self) {
+        self.connects_refused_busy.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of connections refused with ConnectionRateExceeded by
+    /// `MqttServerConfig::connect_rate_per_ip`
+    pub fn connects_refused_rate(&self) -> u64 {
+        self.connects_refused_rate.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_connects_refused_rate(&self) {
+        self.connects_refused_rate.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of PUBLISH packets accepted by the dispatcher, including the ones published
+    /// by internal clients
+    pub fn publishes_received(&self) -> u64 {
+        self.publishes_received.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_publishes_received(&self) {
+        self.publishes_received.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of retained messages evicted to stay within `RetainedLimits`
+    pub fn retained_evictions(&self) -> u64 {
+        self.retained_evictions.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_retained_evictions(&self) {
+        self.retained_evictions.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Size of the retained topic names and payloads
+    pub fn retained_bytes(&self) -> u64 {
+        self.retained_bytes.load(Ordering::Relaxed)
+    }
+    pub(crate) fn set_retained_bytes(&self, bytes: u64) {
+        self.retained_bytes.store(bytes, Ordering::Relaxed);
+    }
+    /// Number of publishes whose subscribers were found in the cache of
+    /// `MqttServerConfig::subscriber_cache_size`
+    pub fn subscriber_cache_hits(&self) -> u64 {
+        self.subscriber_cache_hits.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_subscriber_cache_hits(&self) {
+        self.subscriber_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of publishes whose subscribers had to be collected from the subscription tree
+    /// while the cache is enabled
+    pub fn subscriber_cache_misses(&self) -> u64 {
+        self.subscriber_cache_misses.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_subscriber_cache_misses(&self) {
+        self.subscriber_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of clients removed because a packet could not be queued for them, before
+    /// their worker retired
+    pub fn clients_purged(&self) -> u64 {
+        self.clients_purged.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_clients_purged(&self) {
+        self.clients_purged.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of retained sessions ended early to stay within `max_sessions`
+    pub fn sessions_evicted(&self) -> u64 {
+        self.sessions_evicted.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_sessions_evicted(&self) {
+        self.sessions_evicted.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of publishes forwarded to a peer node hosting matching subscriptions
+    pub fn cluster_forwarded(&self) -> u64 {
+        self.cluster_forwarded.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_cluster_forwarded(&self) {
+        self.cluster_forwarded.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Number of publishes not forwarded to a peer node whose link was full or down
+    pub fn cluster_dropped(&self) -> u64 {
+        self.cluster_dropped.load(Ordering::Relaxed)
+    }
+    pub(crate) fn inc_cluster_dropped(&self) {
+        self.cluster_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
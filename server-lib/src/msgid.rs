@@ -0,0 +1,109 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+/// Name of the user property message ids are stamped under
+pub const MESSAGE_ID_PROPERTY: &str = "apiformes-msgid";
+
+/// Ids are reserved in blocks of this size, so the high-water mark is only written to disk
+/// once every `RESERVE` messages
+const RESERVE: u64 = 4096;
+
+/// Hands out strictly increasing message ids. The high-water mark is persisted so ids keep
+/// growing across restarts, letting peers deduplicate messages replayed after reconnecting.
+/// Ids reserved but not handed out before a restart are skipped.
+pub struct MessageIds {
+    next: AtomicU64,
+    // ids below this value have been persisted and can be handed out freely
+    reserved: AtomicU64,
+    path: Option<PathBuf>,
+    persist_lock: Mutex<()>,
+}
+
+impl MessageIds {
+    /// Loads the high-water mark from `path`, ids are only kept in memory when it is `None`
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        let start = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(s) => s
+                    .trim()
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e),
+            },
+            None => 0,
+        };
+        Ok(MessageIds {
+            next: AtomicU64::new(start),
+            reserved: AtomicU64::new(start),
+            path,
+            persist_lock: Mutex::new(()),
+        })
+    }
+    fn persist(&self, high_water_mark: u64) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, high_water_mark.to_string())?;
+            fs::rename(&tmp, path)?;
+        }
+        self.reserved.store(high_water_mark, Ordering::Release);
+        Ok(())
+    }
+    /// Returns a new id, greater than every id returned before
+    pub fn next(&self) -> io::Result<u64> {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        if id >= self.reserved.load(Ordering::Acquire) {
+            let _guard = self.persist_lock.lock().unwrap();
+            if id >= self.reserved.load(Ordering::Acquire) {
+                self.persist(id + RESERVE)?;
+            }
+        }
+        Ok(id)
+    }
+    /// The id the next call to `next` returns
+    pub fn current(&self) -> u64 {
+        self.next.load(Ordering::Relaxed)
+    }
+    /// Restarts the sequence at `value`, peers must be told to forget the ids they have seen
+    pub fn reset(&self, value: u64) -> io::Result<()> {
+        let _guard = self.persist_lock.lock().unwrap();
+        self.persist(value)?;
+        self.next.store(value, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_message_ids_persistence() {
+        let path = std::env::temp_dir().join(format!("apiformes-msgid-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ids = MessageIds::open(Some(path.clone())).unwrap();
+        assert_eq!(ids.next().unwrap(), 0);
+        assert_eq!(ids.next().unwrap(), 1);
+        assert_eq!(ids.current(), 2);
+        drop(ids);
+
+        // a restart continues after the reserved block
+        let ids = MessageIds::open(Some(path.clone())).unwrap();
+        assert_eq!(ids.next().unwrap(), RESERVE);
+        ids.reset(10).unwrap();
+        assert_eq!(ids.next().unwrap(), 10);
+        drop(ids);
+        let ids = MessageIds::open(Some(path.clone())).unwrap();
+        assert_eq!(ids.current(), 10 + RESERVE);
+        fs::remove_file(&path).unwrap();
+
+        let ids = MessageIds::open(None).unwrap();
+        for i in 0..(RESERVE * 2 + 1) {
+            assert_eq!(ids.next().unwrap(), i);
+        }
+    }
+}
@@ -4,4 +4,6 @@ use std::sync::Arc;
 pub struct PacketInfo {
     pub senderid: Arc<str>,
     pub packet: Packet,
+    /// The publish followed by the `PublishTracer`
+    pub traced: bool,
 }
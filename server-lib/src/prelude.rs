@@ -0,0 +1,42 @@
+//! The public API of the broker, embedders should only need `use apiformes_server_lib::prelude::*`.
+//! The modules of the crate are private, items are added here as they become part of the
+//! supported surface.
+pub use crate::acl::{AclFile, Action, Authorizer, Identity};
+pub use crate::admin::{ApiError, ApiErrorCode};
+pub use crate::builder::MqttServerBuilder;
+pub use crate::clients::{
+    AuthExchange, AuthProvider, AuthStep, Client, ClientIdGenerator, ClientSession, ConnectionInfo,
+    DisconnectReason, DisconnectRecord, ExportedSession, ExportedSubscription, InternalClient,
+    PublicKeyClientIds, SequentialClientIds, SessionExport, UuidClientIds,
+};
+pub use crate::cluster::ClusterPeer;
+#[cfg(feature = "edge-filter")]
+pub use crate::config::FilterQuota;
+pub use crate::config::{
+    Cluster, ConnectRate, DispatcherSharding, Heartbeat, IpConnectRate, ListenerConfig,
+    MqttServerConfig, OverflowPolicy, PublishQuota, PublishQuotas, ReadTuning, Replication,
+    RetainedEviction, RetainedLimits, SessionPolicy, SharedDelivery, SubscriptionTree,
+    TopicTreeAlarm, UnmatchedPolicy, UnmatchedTopics, ZeroKeepAlive,
+};
+#[cfg(feature = "noise")]
+pub use crate::config::{Permeability, PermeabilityViolation};
+pub use crate::error::ServerError;
+#[cfg(feature = "edge-filter")]
+pub use crate::filter::FILTER_PROPERTY;
+pub use crate::intercept::Interceptor;
+pub use crate::lanes::Delivery;
+pub use crate::metrics::{Metrics, SuppressedDeliveries};
+#[cfg(feature = "sled-storage")]
+pub use crate::storage::SledStorage;
+pub use crate::storage::{FileStorage, MemoryStorage, Storage};
+pub use crate::subscription::{LocalClient, Message, Subscription};
+pub use crate::topics::{Matched, SubscriptionFlags, SubscriptionInfo, TopicsTable};
+pub use crate::trace::{PublishTrace, TraceEvent, TraceStage};
+pub use crate::unmatched::RetainedTopic;
+pub use crate::validate::{PayloadValidator, TopicValidator, Utf8Payloads};
+pub use crate::MqttServer;
+/// The client of `apiformes-client-lib`, to reach this broker or another one over the
+/// network, e.g. `client::Client::connect`
+#[cfg(feature = "client")]
+pub use apiformes_client_lib::prelude as client;
+pub use apiformes_packet::prelude::*;
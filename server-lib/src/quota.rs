@@ -0,0 +1,92 @@
+use crate::config::PublishQuota;
+use apiformes_packet::prelude::DisconnectReasonCode;
+use tokio::time::Instant;
+
+/// Token buckets holding a client to its `PublishQuota`, shared by the dispatcher workers
+/// processing its publishes
+pub(crate) struct QuotaTracker {
+    quota: PublishQuota,
+    // missing rates are never charged
+    messages: f64,
+    bytes: f64,
+    last: Instant,
+}
+
+impl QuotaTracker {
+    pub(crate) fn new(quota: PublishQuota, now: Instant) -> Self {
+        let burst = quota.burst_seconds as f64;
+        QuotaTracker {
+            messages: quota.messages_per_second.unwrap_or_default() as f64 * burst,
+            bytes: quota.bytes_per_second.unwrap_or_default() as f64 * burst,
+            quota,
+            last: now,
+        }
+    }
+    /// Charges a publish of `payload_len` bytes, the reason code to disconnect the client
+    /// with when it is over its quota
+    pub(crate) fn charge(
+        &mut self,
+        payload_len: usize,
+        now: Instant,
+    ) -> Result<(), DisconnectReasonCode> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        let burst = self.quota.burst_seconds as f64;
+        if let Some(rate) = self.quota.messages_per_second {
+            let rate = rate as f64;
+            self.messages = (self.messages + elapsed * rate).min(rate * burst) - 1.0;
+            if self.messages < 0.0 {
+                return Err(DisconnectReasonCode::MessageRateTooHigh);
+            }
+        }
+        if let Some(rate) = self.quota.bytes_per_second {
+            let rate = rate as f64;
+            self.bytes = (self.bytes + elapsed * rate).min(rate * burst) - payload_len as f64;
+            if self.bytes < 0.0 {
+                return Err(DisconnectReasonCode::QuotaExceeded);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::Duration;
+    #[test]
+    fn test_quota_tracker() {
+        let now = Instant::now();
+        let mut messages = QuotaTracker::new(
+            PublishQuota {
+                messages_per_second: Some(2),
+                bytes_per_second: None,
+                burst_seconds: 2,
+            },
+            now,
+        );
+        for _ in 0..4 {
+            assert!(messages.charge(1 << 20, now).is_ok());
+        }
+        assert!(matches!(
+            messages.charge(0, now),
+            Err(DisconnectReasonCode::MessageRateTooHigh)
+        ));
+        let later = now + Duration::from_secs(1);
+        assert!(messages.charge(0, later).is_ok());
+
+        let mut bytes = QuotaTracker::new(
+            PublishQuota {
+                messages_per_second: None,
+                bytes_per_second: Some(100),
+                burst_seconds: 1,
+            },
+            now,
+        );
+        assert!(bytes.charge(60, now).is_ok());
+        assert!(matches!(
+            bytes.charge(60, now),
+            Err(DisconnectReasonCode::QuotaExceeded)
+        ));
+    }
+}
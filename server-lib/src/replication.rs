@@ -0,0 +1,197 @@
+use crate::{
+    clients::SessionExport,
+    error::ServerError,
+    link::{authenticate, send, LinkReader},
+    shutdown::Shutdown,
+    storage::DurableState,
+};
+use apiformes_packet::prelude::*;
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+    time::{self, Duration},
+};
+use tracing::{info, warn};
+
+/// Topic of the PUBLISH packets carrying the `SessionExport` of the primary, every other
+/// PUBLISH sent over a replication link is a retained message
+const SESSIONS_TOPIC: &str = "$replication/sessions";
+
+/// Delay before a standby connects again to a primary it lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Number of retained messages a standby may fall behind, it is sent every retained
+/// message again beyond it
+pub(crate) const RETAINED_BACKLOG: usize = 1024;
+
+/// Applies a PUBLISH sent by the primary, an invalid one is logged and skipped so the
+/// standby keeps the last valid state
+async fn apply(state: &DurableState, publish: Publish) {
+    if &**publish.topic_name() != SESSIONS_TOPIC {
+        state.retain(&publish);
+        return;
+    }
+    let export = match std::str::from_utf8(&publish.payload()) {
+        Ok(json) => SessionExport::from_json(json),
+        Err(e) => {
+            warn!("Replicated sessions are not valid UTF-8, {}", e);
+            return;
+        }
+    };
+    let replaced = match export {
+        Ok(export) => state.replace_sessions(&export).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = replaced {
+        warn!("Replicated sessions refused, {}", e);
+    }
+}
+
+async fn send_retained(stream: &mut TcpStream, state: &DurableState) -> Result<(), ServerError> {
+    for publish in state.retained() {
+        send(stream, publish.build()).await?;
+    }
+    Ok(())
+}
+
+/// Streams the state to every standby connecting to `listener` with `key` until the
+/// shutdown
+pub(crate) async fn serve(
+    listener: TcpListener,
+    key: String,
+    state: Arc<DurableState>,
+    retained: broadcast::Sender<Publish>,
+    max_lag: Duration,
+    shutdown: Shutdown,
+) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            _ = shutdown.wait() => return,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed accepting a standby, {:?}", e);
+                    continue;
+                }
+            },
+        };
+        let link = stream_to(
+            stream,
+            addr,
+            key.clone(),
+            state.clone(),
+            retained.subscribe(),
+            max_lag,
+            shutdown.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = link.await {
+                warn!(standby = &*addr.to_string(), "Replication stopped, {:?}", e);
+            }
+        });
+    }
+}
+
+/// Sends every retained message to the standby at `addr` once it presented `key`, then
+/// the new ones as they are stored and the sessions every `max_lag` when they changed. A
+/// PINGREQ is sent when they did not, so the standby knows its primary is still there.
+async fn stream_to(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    key: String,
+    state: Arc<DurableState>,
+    mut retained: broadcast::Receiver<Publish>,
+    max_lag: Duration,
+    shutdown: Shutdown,
+) -> Result<(), ServerError> {
+    // a standby sends nothing but its key
+    LinkReader::new(&mut stream).authenticated(&key).await?;
+    info!(standby = &*addr.to_string(), "Replicating to a standby");
+    send_retained(&mut stream, &state).await?;
+    let mut sent = String::new();
+    let mut tick = time::interval(max_lag);
+    loop {
+        tokio::select! {
+            _ = shutdown.wait() => return Ok(()),
+            publish = retained.recv() => match publish {
+                Ok(publish) => send(&mut stream, publish.build()).await?,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(
+                        standby = &*addr.to_string(),
+                        "Standby missed {} retained messages, sending them all again", missed
+                    );
+                    send_retained(&mut stream, &state).await?;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = tick.tick() => {
+                let export = state.export_sessions().await.to_json();
+                if export == sent {
+                    send(&mut stream, Ping::new().build_req()).await?;
+                    continue;
+                }
+                let publish = Publish::new(Arc::from(SESSIONS_TOPIC), Bytes::from(export.clone()))?;
+                send(&mut stream, publish.build()).await?;
+                sent = export;
+            }
+        }
+    }
+}
+
+/// Keeps the state up to date with the primary at `primary` until `stop` is triggered,
+/// connecting again whenever the link breaks
+pub(crate) async fn follow(primary: String, key: String, state: Arc<DurableState>, stop: Shutdown) {
+    loop {
+        let connected = tokio::select! {
+            _ = stop.wait() => return,
+            connected = connect(&primary, &key) => connected,
+        };
+        match connected {
+            Ok(stream) => {
+                info!(primary = &*primary, "Following the primary");
+                match receive(stream, &state, &stop).await {
+                    Ok(()) => return,
+                    Err(e) => warn!(primary = &*primary, "Lost the primary, {:?}", e),
+                }
+            }
+            Err(e) => warn!(
+                primary = &*primary,
+                "Failed connecting to the primary, {:?}", e
+            ),
+        }
+        tokio::select! {
+            _ = stop.wait() => return,
+            _ = time::sleep(RECONNECT_DELAY) => (),
+        }
+    }
+}
+
+async fn connect(primary: &str, key: &str) -> Result<TcpStream, ServerError> {
+    let mut stream = TcpStream::connect(primary).await?;
+    authenticate(&mut stream, key).await?;
+    Ok(stream)
+}
+
+/// Applies the packets of the primary until `stop` is triggered, a packet already received
+/// is always applied whole
+async fn receive(
+    stream: TcpStream,
+    state: &DurableState,
+    stop: &Shutdown,
+) -> Result<(), ServerError> {
+    let mut reader = LinkReader::new(stream);
+    loop {
+        let packets = tokio::select! {
+            _ = stop.wait() => return Ok(()),
+            packets = reader.recv() => packets?,
+        };
+        for packet in packets {
+            if let Packet::Publish(publish) = packet {
+                apply(state, publish).await;
+            }
+        }
+    }
+}
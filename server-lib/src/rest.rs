@@ -0,0 +1,349 @@
+use crate::{
+    admin::{ApiError, ApiErrorCode},
+    clients::{client_sessions, disconnect_client, Client},
+    error::ServerError,
+    packetinfo::PacketInfo,
+    shutdown::Shutdown,
+    subscription::internal_publish,
+    topics::TopicsTable,
+};
+use apiformes_packet::prelude::*;
+use bytes::{Bytes, BytesMut};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc::Sender, RwLock};
+use tokio::time::{timeout, Duration};
+use tracing::{info, warn};
+
+/// Largest request line and headers accepted
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 32;
+/// A connection sending no complete request within this delay is closed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The HTTP management API served on `MqttServerConfig::admin_socketaddr`:
+///
+/// - `GET /clients` lists the connected clients with their session, see `ClientSession`
+/// - `DELETE /clients/<clientid>` disconnects a client with AdministrativeAction
+/// - `GET /subscriptions?topic=<topic>` lists the subscriptions matching a topic name
+/// - `POST /publish?topic=<topic>&qos=<qos>` publishes the request body on behalf of the
+///   broker, as `MqttServer::publish` does
+///
+/// Path segments and query values are percent-decoded, so `#` and `+` must be sent as
+/// `%23` and `%2B`. Errors are answered with an `ApiError` as JSON.
+pub(crate) struct AdminApi {
+    pub(crate) clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+    pub(crate) topics: Arc<TopicsTable>,
+    pub(crate) incoming: Sender<PacketInfo>,
+    /// the internal client injected publishes are sent as
+    pub(crate) publisher_id: Arc<str>,
+    /// largest request body accepted
+    pub(crate) max_body: usize,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    body: Bytes,
+}
+
+struct Response {
+    status: u16,
+    body: Option<String>,
+}
+
+impl Response {
+    fn json(value: &impl Serialize) -> Self {
+        Response {
+            status: 200,
+            body: Some(serde_json::to_string(value).unwrap()),
+        }
+    }
+    fn no_content() -> Self {
+        Response {
+            status: 204,
+            body: None,
+        }
+    }
+}
+
+impl From<ApiError> for Response {
+    fn from(err: ApiError) -> Self {
+        let status = match err.code {
+            ApiErrorCode::InvalidArgument => 400,
+            ApiErrorCode::NotFound => 404,
+            ApiErrorCode::InvalidConfig => 409,
+            ApiErrorCode::Unavailable => 503,
+            ApiErrorCode::Internal => 500,
+        };
+        Response {
+            status,
+            body: Some(err.to_json()),
+        }
+    }
+}
+
+/// A subscription matching the topic of `GET /subscriptions`
+#[derive(Serialize)]
+struct TopicSubscription {
+    clientid: String,
+    filter: String,
+    qos: u8,
+}
+
+fn invalid(field: &str, message: String) -> ApiError {
+    ApiError::new(ApiErrorCode::InvalidArgument, message).with_field(field)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Decodes the `%XX` escapes of `s`, `None` when they are malformed or not UTF-8
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn parse_query(query: &str) -> Option<HashMap<String, String>> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((percent_decode(k)?, percent_decode(v)?))
+        })
+        .collect()
+}
+
+impl AdminApi {
+    async fn route(&self, req: Request) -> Response {
+        let segments: Vec<&str> = req.path.trim_start_matches('/').split('/').collect();
+        let result = match (req.method.as_str(), &segments[..]) {
+            ("GET", ["clients"]) => Ok(Response::json(
+                &client_sessions(&self.clients, &self.topics).await,
+            )),
+            ("DELETE", ["clients", clientid]) => match percent_decode(clientid) {
+                Some(clientid) => disconnect_client(&self.clients, &clientid).await.map(|()| {
+                    info!(clientid = &*clientid, "Disconnected through the admin API");
+                    Response::no_content()
+                }),
+                None => Err(invalid("clientid", "malformed client id".to_owned())),
+            },
+            ("GET", ["subscriptions"]) => self.subscriptions(&req.query).await,
+            ("POST", ["publish"]) => self.publish(&req.query, req.body).await,
+            (_, ["clients"] | ["clients", _] | ["subscriptions"] | ["publish"]) => {
+                return Response {
+                    status: 405,
+                    body: None,
+                }
+            }
+            _ => Err(ApiError::new(
+                ApiErrorCode::NotFound,
+                format!("no such route {}", req.path),
+            )),
+        };
+        result.unwrap_or_else(Response::from)
+    }
+    async fn subscriptions(&self, query: &HashMap<String, String>) -> Result<Response, ApiError> {
+        let topic = query
+            .get("topic")
+            .ok_or_else(|| invalid("topic", "missing".to_owned()))?;
+        match MqttTopic::new(Arc::from(&**topic)) {
+            Ok(name) if !topic.is_empty() && !name.is_wildcard() => (),
+            _ => return Err(invalid("topic", format!("{} is not a topic name", topic))),
+        }
+        let matched: Vec<_> = self
+            .topics
+            .matched(topic)
+            .await
+            .map(|(filter, clientid, info)| TopicSubscription {
+                clientid: clientid.to_string(),
+                filter: filter.to_string(),
+                qos: info.qos.as_u8(),
+            })
+            .collect();
+        Ok(Response::json(&matched))
+    }
+    async fn publish(
+        &self,
+        query: &HashMap<String, String>,
+        payload: Bytes,
+    ) -> Result<Response, ApiError> {
+        let topic = query
+            .get("topic")
+            .ok_or_else(|| invalid("topic", "missing".to_owned()))?;
+        let qos = match query.get("qos").map(|qos| qos.parse().map(QoS::from_u8)) {
+            None => QoS::QoS0,
+            Some(Ok(Ok(qos))) => qos,
+            _ => return Err(invalid("qos", "not a QoS".to_owned())),
+        };
+        let packet = internal_publish(topic, payload, qos, false, []).map_err(|e| match e {
+            ServerError::QoSNotSupported(_) => invalid("qos", "not supported".to_owned()),
+            e => invalid("topic", format!("{:?}", e)),
+        })?;
+        self.incoming
+            .send(PacketInfo {
+                senderid: self.publisher_id.clone(),
+                packet,
+                traced: false,
+            })
+            .await
+            .map_err(|_| {
+                ApiError::new(
+                    ApiErrorCode::Unavailable,
+                    "dispatcher is not running".to_owned(),
+                )
+            })?;
+        Ok(Response::no_content())
+    }
+}
+
+/// Reads a request, an error response when it cannot be served
+async fn read_request(
+    stream: &mut TcpStream,
+    max_body: usize,
+) -> Result<Result<Request, Response>, ServerError> {
+    let mut buf = BytesMut::with_capacity(1024);
+    let (method, target, head_len, body_len) = loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(ServerError::ConnectionClosed);
+        }
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf) {
+            Ok(httparse::Status::Complete(head_len)) => {
+                let body_len = req
+                    .headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+                    .map(|h| std::str::from_utf8(h.value).ok()?.trim().parse().ok());
+                let body_len = match body_len {
+                    None => 0,
+                    Some(Some(len)) => len,
+                    Some(None) => return Ok(Err(invalid_request("Content-Length"))),
+                };
+                let method = req.method.unwrap_or_default().to_owned();
+                let target = req.path.unwrap_or_default().to_owned();
+                break (method, target, head_len, body_len);
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_HEAD_SIZE => (),
+            _ => return Ok(Err(invalid_request("request"))),
+        }
+    };
+    if body_len > max_body {
+        return Ok(Err(Response {
+            status: 413,
+            body: None,
+        }));
+    }
+    while buf.len() < head_len + body_len {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(ServerError::ConnectionClosed);
+        }
+    }
+    let body = buf.split_off(head_len).split_to(body_len).freeze();
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = match parse_query(query) {
+        Some(query) => query,
+        None => return Ok(Err(invalid_request("query"))),
+    };
+    Ok(Ok(Request {
+        method,
+        path: path.to_owned(),
+        query,
+        body,
+    }))
+}
+
+fn invalid_request(field: &str) -> Response {
+    Response::from(invalid(field, "malformed HTTP request".to_owned()))
+}
+
+/// Answers a single request, the connection is closed afterwards
+async fn serve_connection(mut stream: TcpStream, api: Arc<AdminApi>) -> Result<(), ServerError> {
+    let response = match timeout(REQUEST_TIMEOUT, read_request(&mut stream, api.max_body)).await {
+        Ok(read) => match read? {
+            Ok(req) => api.route(req).await,
+            Err(response) => response,
+        },
+        Err(_) => return Ok(()),
+    };
+    let body = response.body.unwrap_or_default();
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nConnection: close\r\n",
+        response.status,
+        reason(response.status)
+    );
+    if !body.is_empty() {
+        head.push_str("Content-Type: application/json\r\n");
+    }
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Serves the management API on `listener` until the shutdown
+pub(crate) async fn serve(listener: TcpListener, api: Arc<AdminApi>, shutdown: Shutdown) {
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.wait() => return,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed accepting an admin connection, {:?}", e);
+                    continue;
+                }
+            },
+        };
+        let api = api.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, api).await {
+                warn!("Admin request failed, {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(percent_decode("a%2Fb%23").as_deref(), Some("a/b#"));
+        assert_eq!(percent_decode("%e2%82%ac").as_deref(), Some("€"));
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        let query = parse_query("topic=rooms%2F1&qos=1&flag").unwrap();
+        assert_eq!(query["topic"], "rooms/1");
+        assert_eq!(query["qos"], "1");
+        assert_eq!(query["flag"], "");
+        assert!(parse_query("").unwrap().is_empty());
+        assert!(parse_query("topic=%ff").is_none());
+    }
+}
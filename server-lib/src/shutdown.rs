@@ -0,0 +1,133 @@
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch::{channel, Receiver, Sender};
+use tokio::time::Instant;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Running,
+    /// No new connection is accepted and the connected clients are disconnected once
+    /// they received what was left for them, until the deadline
+    Draining(Instant),
+    Stopped,
+}
+
+/// Server wide shutdown signal. Every clone observes it, whether it was waiting when the
+/// shutdown was triggered or starts waiting afterwards, so listeners, the manager, the
+/// dispatcher and every client worker all stop.
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<Sender<Phase>>,
+    rx: Receiver<Phase>,
+    // a draining started concurrently with the shutdown must not undo it
+    transition: Arc<Mutex<()>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, rx) = channel(Phase::Running);
+        Shutdown {
+            tx: Arc::new(tx),
+            rx,
+            transition: Arc::default(),
+        }
+    }
+    pub fn trigger(&self) {
+        let _transition = self.transition.lock().unwrap();
+        self.tx.send_replace(Phase::Stopped);
+    }
+    /// Starts draining the connections, they must be gone by `deadline`
+    pub fn drain(&self, deadline: Instant) {
+        let _transition = self.transition.lock().unwrap();
+        if *self.rx.borrow() == Phase::Running {
+            self.tx.send_replace(Phase::Draining(deadline));
+        }
+    }
+    /// Resolves once the phase matches `until`
+    async fn changed_until<T>(&self, until: impl Fn(Phase) -> Option<T>) -> Option<T> {
+        let mut rx = self.rx.clone();
+        loop {
+            if let Some(v) = until(*rx.borrow_and_update()) {
+                return Some(v);
+            }
+            // the sender lives as long as any clone, so the channel cannot close under us
+            if rx.changed().await.is_err() {
+                return None;
+            }
+        }
+    }
+    /// Resolves once the shutdown is triggered
+    pub async fn wait(&self) {
+        self.changed_until(|phase| (phase == Phase::Stopped).then_some(()))
+            .await;
+    }
+    /// Resolves once the connections are drained or the shutdown is triggered, with the
+    /// deadline of the draining if any
+    pub async fn draining(&self) -> Option<Instant> {
+        self.changed_until(|phase| match phase {
+            Phase::Running => None,
+            Phase::Draining(deadline) => Some(Some(deadline)),
+            Phase::Stopped => Some(None),
+        })
+        .await
+        .flatten()
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::{timeout, Duration};
+    #[tokio::test]
+    async fn test_shutdown_reaches_every_waiter() {
+        let shutdown = Shutdown::new();
+        let waiters: Vec<_> = (0..100)
+            .map(|_| {
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move { shutdown.wait().await })
+            })
+            .collect();
+        tokio::task::yield_now().await;
+        shutdown.trigger();
+        for waiter in waiters {
+            timeout(Duration::from_secs(1), waiter)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        // late waiters return immediately
+        timeout(Duration::from_secs(1), shutdown.wait())
+            .await
+            .unwrap();
+    }
+    #[tokio::test]
+    async fn test_drain_then_shutdown() {
+        let shutdown = Shutdown::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let draining = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { shutdown.draining().await })
+        };
+        tokio::task::yield_now().await;
+        shutdown.drain(deadline);
+        assert_eq!(draining.await.unwrap(), Some(deadline));
+        // draining is not stopping
+        assert!(timeout(Duration::from_millis(10), shutdown.wait())
+            .await
+            .is_err());
+        // draining again keeps the first deadline
+        shutdown.drain(deadline + Duration::from_secs(5));
+        assert_eq!(shutdown.draining().await, Some(deadline));
+        shutdown.trigger();
+        shutdown.wait().await;
+        // stopping without draining leaves no deadline
+        assert_eq!(shutdown.draining().await, None);
+        shutdown.drain(deadline);
+        assert_eq!(shutdown.draining().await, None);
+    }
+}
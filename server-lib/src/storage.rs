@@ -0,0 +1,391 @@
+use crate::{
+    admin::ApiError,
+    clients::{self, Client, ExportedSession, SessionExport, SessionStore},
+    msgid::MessageIds,
+    shutdown::Shutdown,
+    topics::TopicsTable,
+    unmatched::UnmatchedPublishes,
+};
+use apiformes_packet::prelude::*;
+use futures::future::{BoxFuture, FutureExt};
+use std::collections::HashMap;
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::{
+    fs,
+    sync::RwLock,
+    time::{self, Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Where the sessions, their in-flight publishes included, and the retained messages are
+/// saved so the broker recovers them after a restart, see `MqttServerConfig::storage`.
+/// Every save replaces what was saved before.
+pub trait Storage: Send + Sync {
+    fn load_sessions(&self) -> BoxFuture<'_, io::Result<Vec<ExportedSession>>>;
+    fn save_sessions(&self, sessions: Vec<ExportedSession>) -> BoxFuture<'_, io::Result<()>>;
+    /// The retained messages as encoded PUBLISH packets, in eviction order
+    fn load_retained(&self) -> BoxFuture<'_, io::Result<Vec<Vec<u8>>>>;
+    fn save_retained(&self, retained: Vec<Vec<u8>>) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// Keeps the state in memory, it survives a restart of an `MqttServer` within the same
+/// process only
+#[derive(Default)]
+pub struct MemoryStorage {
+    sessions: Mutex<Vec<ExportedSession>>,
+    retained: Mutex<Vec<Vec<u8>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn load_sessions(&self) -> BoxFuture<'_, io::Result<Vec<ExportedSession>>> {
+        let sessions = self.sessions.lock().unwrap().clone();
+        async move { Ok(sessions) }.boxed()
+    }
+    fn save_sessions(&self, sessions: Vec<ExportedSession>) -> BoxFuture<'_, io::Result<()>> {
+        *self.sessions.lock().unwrap() = sessions;
+        async { Ok(()) }.boxed()
+    }
+    fn load_retained(&self) -> BoxFuture<'_, io::Result<Vec<Vec<u8>>>> {
+        let retained = self.retained.lock().unwrap().clone();
+        async move { Ok(retained) }.boxed()
+    }
+    fn save_retained(&self, retained: Vec<Vec<u8>>) -> BoxFuture<'_, io::Result<()>> {
+        *self.retained.lock().unwrap() = retained;
+        async { Ok(()) }.boxed()
+    }
+}
+
+/// Saves the state as JSON files in a directory, each one written to a temporary file
+/// first so a crash while saving leaves the previous one intact
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    /// Stores the state in `dir`, created if missing
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(FileStorage { dir })
+    }
+    async fn load<T: serde::de::DeserializeOwned + Default>(&self, name: &str) -> io::Result<T> {
+        match fs::read(self.dir.join(name)).await {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+            Err(e) => Err(e),
+        }
+    }
+    async fn save<T: serde::Serialize>(&self, name: &str, value: T) -> io::Result<()> {
+        let path = self.dir.join(name);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&value)?).await?;
+        fs::rename(&tmp, &path).await
+    }
+}
+
+impl Storage for FileStorage {
+    fn load_sessions(&self) -> BoxFuture<'_, io::Result<Vec<ExportedSession>>> {
+        self.load("sessions.json").boxed()
+    }
+    fn save_sessions(&self, sessions: Vec<ExportedSession>) -> BoxFuture<'_, io::Result<()>> {
+        self.save("sessions.json", sessions).boxed()
+    }
+    fn load_retained(&self) -> BoxFuture<'_, io::Result<Vec<Vec<u8>>>> {
+        self.load("retained.json").boxed()
+    }
+    fn save_retained(&self, retained: Vec<Vec<u8>>) -> BoxFuture<'_, io::Result<()>> {
+        self.save("retained.json", retained).boxed()
+    }
+}
+
+/// Saves the state in a sled database, one entry per session and per retained message
+#[cfg(feature = "sled-storage")]
+pub struct SledStorage {
+    sessions: sled::Tree,
+    retained: sled::Tree,
+}
+
+#[cfg(feature = "sled-storage")]
+impl SledStorage {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let db = sled::open(path).map_err(sled_error)?;
+        Ok(SledStorage {
+            sessions: db.open_tree("sessions").map_err(sled_error)?,
+            retained: db.open_tree("retained").map_err(sled_error)?,
+        })
+    }
+    /// Makes `entries` the content of `tree` in a single batch, which only writes the keys
+    /// that are gone, new or whose value changed since the previous save
+    async fn replace(tree: &sled::Tree, entries: Vec<(Vec<u8>, Vec<u8>)>) -> io::Result<()> {
+        let stored = tree.clone();
+        blocking(move || {
+            let mut entries: HashMap<Vec<u8>, Vec<u8>> = entries.into_iter().collect();
+            let mut batch = sled::Batch::default();
+            for entry in stored.iter() {
+                let (key, value) = entry.map_err(sled_error)?;
+                match entries.remove(&key[..]) {
+                    Some(entry) if entry[..] == value[..] => (),
+                    Some(entry) => batch.insert(key, entry),
+                    None => batch.remove(key),
+                }
+            }
+            for (key, value) in entries {
+                batch.insert(key, value);
+            }
+            stored.apply_batch(batch).map_err(sled_error)
+        })
+        .await?;
+        tree.flush_async().await.map_err(sled_error)?;
+        Ok(())
+    }
+}
+
+/// Runs `io` on the blocking threads, sled reads and writes the disk synchronously
+#[cfg(feature = "sled-storage")]
+async fn blocking<T: Send + 'static>(
+    io: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(io)
+        .await
+        .map_err(io::Error::other)?
+}
+
+#[cfg(feature = "sled-storage")]
+fn sled_error(e: sled::Error) -> io::Error {
+    match e {
+        sled::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(feature = "sled-storage")]
+impl Storage for SledStorage {
+    fn load_sessions(&self) -> BoxFuture<'_, io::Result<Vec<ExportedSession>>> {
+        let sessions = self.sessions.clone();
+        blocking(move || {
+            sessions
+                .iter()
+                .values()
+                .map(|value| {
+                    serde_json::from_slice(&value.map_err(sled_error)?)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                })
+                .collect()
+        })
+        .boxed()
+    }
+    fn save_sessions(&self, sessions: Vec<ExportedSession>) -> BoxFuture<'_, io::Result<()>> {
+        let entries = sessions
+            .into_iter()
+            .map(|session| {
+                let value = serde_json::to_vec(&session).unwrap();
+                (session.clientid.into_bytes(), value)
+            })
+            .collect();
+        SledStorage::replace(&self.sessions, entries).boxed()
+    }
+    fn load_retained(&self) -> BoxFuture<'_, io::Result<Vec<Vec<u8>>>> {
+        // keys are big endian ranks, iterating them follows the eviction order
+        let retained = self.retained.clone();
+        blocking(move || {
+            retained
+                .iter()
+                .values()
+                .map(|value| Ok(value.map_err(sled_error)?.to_vec()))
+                .collect()
+        })
+        .boxed()
+    }
+    fn save_retained(&self, retained: Vec<Vec<u8>>) -> BoxFuture<'_, io::Result<()>> {
+        let entries = retained
+            .into_iter()
+            .enumerate()
+            .map(|(rank, publish)| ((rank as u64).to_be_bytes().to_vec(), publish))
+            .collect();
+        SledStorage::replace(&self.retained, entries).boxed()
+    }
+}
+
+/// The state outliving the connections: the sessions and the retained messages. It is what
+/// a `Storage` saves and a primary replicates to its standbys.
+pub(crate) struct DurableState {
+    node_id: Arc<str>,
+    clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+    sessions: Arc<SessionStore>,
+    topics: Arc<TopicsTable>,
+    unmatched: Arc<UnmatchedPublishes>,
+    message_ids: Arc<MessageIds>,
+}
+
+impl DurableState {
+    pub(crate) fn new(
+        node_id: Arc<str>,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+        sessions: Arc<SessionStore>,
+        topics: Arc<TopicsTable>,
+        unmatched: Arc<UnmatchedPublishes>,
+        message_ids: Arc<MessageIds>,
+    ) -> Self {
+        DurableState {
+            node_id,
+            clients,
+            sessions,
+            topics,
+            unmatched,
+            message_ids,
+        }
+    }
+    /// The retained messages in eviction order
+    pub(crate) fn retained(&self) -> Vec<Publish> {
+        self.unmatched.retained_publishes()
+    }
+    /// Stores `publish` as a retained message whatever the policy of its topic, one with an
+    /// empty payload clears the retained message of its topic
+    pub(crate) fn retain(&self, publish: &Publish) {
+        self.unmatched.publish_retained(publish, Instant::now());
+    }
+    pub(crate) async fn export_sessions(&self) -> SessionExport {
+        SessionExport {
+            node_id: self.node_id.to_string(),
+            message_id: self.message_ids.current(),
+            sessions: clients::export_sessions(&self.clients, &self.sessions, &self.topics).await,
+        }
+    }
+    /// Makes the sessions of `export` the only parked ones, raising the message ids to
+    /// those of the exporting broker
+    pub(crate) async fn replace_sessions(&self, export: &SessionExport) -> Result<usize, ApiError> {
+        if export.message_id > self.message_ids.current() {
+            if let Err(e) = self.message_ids.reset(export.message_id) {
+                warn!("Failed raising the message ids, {}", e);
+            }
+        }
+        clients::replace_sessions(
+            &export.sessions,
+            &self.clients,
+            &self.sessions,
+            &self.topics,
+        )
+        .await
+    }
+    /// Loads what `storage` saved, returns the number of sessions and retained messages
+    /// restored
+    pub(crate) async fn restore(&self, storage: &dyn Storage) -> io::Result<(usize, usize)> {
+        let sessions = storage.load_sessions().await?;
+        let sessions =
+            clients::import_sessions(&sessions, &self.clients, &self.sessions, &self.topics)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let mut retained = 0;
+        for encoded in storage.load_retained().await? {
+            match Packet::from_bytes(&mut Cursor::new(&encoded[..])) {
+                Ok(Packet::Publish(publish)) => {
+                    self.retain(&publish);
+                    retained += 1;
+                }
+                _ => warn!("Stored retained message is not a valid PUBLISH, skipped"),
+            }
+        }
+        Ok((sessions, retained))
+    }
+    async fn save(&self, storage: &dyn Storage) -> io::Result<()> {
+        let retained = self
+            .retained()
+            .iter()
+            .map(clients::encode_publish)
+            .collect();
+        storage.save_retained(retained).await?;
+        storage
+            .save_sessions(self.export_sessions().await.sessions)
+            .await
+    }
+}
+
+/// Saves the state to `storage` every `period` and a last time on shutdown
+pub(crate) async fn persist(
+    state: Arc<DurableState>,
+    storage: Arc<dyn Storage>,
+    period: Duration,
+    shutdown: Shutdown,
+) {
+    let mut tick = time::interval_at(Instant::now() + period, period);
+    loop {
+        let stopping = tokio::select! {
+            _ = shutdown.wait() => true,
+            _ = tick.tick() => false,
+        };
+        if let Err(e) = state.save(&*storage).await {
+            warn!("Failed saving the sessions and retained messages, {}", e);
+        }
+        if stopping {
+            info!("Saved the sessions and retained messages");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn session(clientid: &str) -> ExportedSession {
+        ExportedSession {
+            clientid: clientid.to_owned(),
+            expiry: 60,
+            subscriptions: Vec::new(),
+            unacked: vec![vec![0x30, 0x03, 0x00, 0x01, b'a']],
+            queued: Vec::new(),
+            will: None,
+            will_delay: 0,
+        }
+    }
+    async fn roundtrip(storage: &dyn Storage) {
+        assert!(storage.load_sessions().await.unwrap().is_empty());
+        assert!(storage.load_retained().await.unwrap().is_empty());
+        storage
+            .save_sessions(vec![session("a"), session("b")])
+            .await
+            .unwrap();
+        storage
+            .save_retained(vec![vec![1], vec![2], vec![3]])
+            .await
+            .unwrap();
+        // a save replaces the previous one
+        let mut updated = session("b");
+        updated.expiry = 30;
+        storage.save_sessions(vec![updated.clone()]).await.unwrap();
+        assert_eq!(storage.load_sessions().await.unwrap(), vec![updated]);
+        assert_eq!(
+            storage.load_retained().await.unwrap(),
+            vec![vec![1], vec![2], vec![3]]
+        );
+        storage.save_retained(vec![vec![2], vec![3]]).await.unwrap();
+        assert_eq!(
+            storage.load_retained().await.unwrap(),
+            vec![vec![2], vec![3]]
+        );
+    }
+    fn storage_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("apiformes-storage-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+    #[tokio::test]
+    async fn test_storage_roundtrip() {
+        roundtrip(&MemoryStorage::default()).await;
+        let dir = storage_dir("files");
+        roundtrip(&FileStorage::open(dir.clone()).unwrap()).await;
+        // what was saved is found again once reopened
+        let reopened = FileStorage::open(dir.clone()).unwrap();
+        assert_eq!(reopened.load_sessions().await.unwrap().len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+        #[cfg(feature = "sled-storage")]
+        {
+            let dir = storage_dir("sled");
+            roundtrip(&SledStorage::open(dir.clone()).unwrap()).await;
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use crate::cfg::MAX_QOS;
+use crate::clients::{Client, InternalClient};
+use crate::packetinfo::PacketInfo;
+use crate::sys::SysTopics;
+use crate::topics::{SubscriptionFlags, TopicsTable};
+use crate::ServerError;
+use apiformes_packet::prelude::*;
+use bytes::Bytes;
+use futures::Stream;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::{mpsc::Sender, RwLock};
+
+/// An application message delivered to a `Subscription`
+pub struct Message {
+    pub topic: Arc<str>,
+    pub payload: Bytes,
+    pub qos: QoS,
+    pub retain: bool,
+    pub properties: Vec<(Property, MqttPropValue)>,
+}
+
+impl From<&Publish> for Message {
+    fn from(publish: &Publish) -> Self {
+        Message {
+            topic: publish.topic_name().clone(),
+            payload: publish.payload(),
+            qos: publish.qos(),
+            retain: publish.flags().contains(PublishFlags::RETAIN),
+            properties: publish.props_iter().map(|(k, v)| (*k, v.clone())).collect(),
+        }
+    }
+}
+
+/// Stream of the messages matching a topic filter, returned by `MqttServer::subscribe`.
+/// Dropping it removes the subscription from the broker.
+pub struct Subscription {
+    client: Option<InternalClient>,
+    topics: Arc<TopicsTable>,
+}
+
+impl Subscription {
+    pub(crate) fn new(client: InternalClient, topics: Arc<TopicsTable>) -> Self {
+        Subscription {
+            client: Some(client),
+            topics,
+        }
+    }
+    /// Id of the internal client backing this subscription
+    pub fn clientid(&self) -> &Arc<str> {
+        // only taken out while dropping
+        self.client.as_ref().unwrap().clientid()
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Message;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        let client = self.client.as_mut().unwrap();
+        loop {
+            match client.poll_recv(cx) {
+                Poll::Ready(Some(packet)) => {
+                    if let Packet::Publish(publish) = &*packet {
+                        return Poll::Ready(Some(Message::from(publish)));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            release(client, self.topics.clone());
+        }
+    }
+}
+
+/// Removes the subscriptions of an internal client going away, then the client itself
+fn release(client: InternalClient, topics: Arc<TopicsTable>) {
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        handle.spawn(async move {
+            topics.unsubscribe_all(client.clientid().clone()).await;
+            client.unregister().await;
+        });
+    }
+}
+
+/// The publish of an internal client, refused when the broker cannot honour it
+pub(crate) fn internal_publish(
+    topic: &str,
+    payload: Bytes,
+    qos: QoS,
+    retain: bool,
+    props: impl IntoIterator<Item = (Property, MqttPropValue)>,
+) -> Result<Packet, ServerError> {
+    if qos.as_u8() > MAX_QOS {
+        return Err(ServerError::QoSNotSupported(qos.as_u8()));
+    }
+    if retain {
+        return Err(ServerError::RetainNotSupported);
+    }
+    let mut publish = Publish::new(Arc::from(topic), payload)?;
+    publish.set_qos(qos);
+    for (k, v) in props {
+        publish.add_prop(k, v)?;
+    }
+    Ok(publish.build())
+}
+
+/// Subscribes the internal client `clientid` to `filter`, the retained messages matching it
+/// are sent right away
+pub(crate) async fn internal_subscribe(
+    topics: &TopicsTable,
+    sys: &SysTopics,
+    clients: &RwLock<HashMap<Arc<str>, Client>>,
+    clientid: &Arc<str>,
+    filter: &str,
+    qos: QoS,
+) -> Result<(), ServerError> {
+    if qos.as_u8() > MAX_QOS {
+        return Err(ServerError::QoSNotSupported(qos.as_u8()));
+    }
+    let filter = MqttTopic::new(Arc::from(filter))?.unwrap();
+    topics
+        .subscribe(
+            clientid.clone(),
+            filter.clone(),
+            qos,
+            SubscriptionFlags::empty(),
+        )
+        .await;
+    let retained = sys.matching(&filter).await;
+    if let Some(c) = clients.read().await.get(clientid) {
+        for publish in retained {
+            c.send(publish)?;
+        }
+    }
+    Ok(())
+}
+
+/// A client living inside the broker, returned by `MqttServer::local_client`. It publishes
+/// and subscribes like a network client without any connection, and is a stream of the
+/// messages matching its subscriptions. Dropping it removes its subscriptions.
+pub struct LocalClient {
+    client: Option<InternalClient>,
+    topics: Arc<TopicsTable>,
+    sys: Arc<SysTopics>,
+    clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+    incoming: Sender<PacketInfo>,
+}
+
+impl LocalClient {
+    pub(crate) fn new(
+        client: InternalClient,
+        topics: Arc<TopicsTable>,
+        sys: Arc<SysTopics>,
+        clients: Arc<RwLock<HashMap<Arc<str>, Client>>>,
+        incoming: Sender<PacketInfo>,
+    ) -> Self {
+        LocalClient {
+            client: Some(client),
+            topics,
+            sys,
+            clients,
+            incoming,
+        }
+    }
+    /// Id of the internal client, `$internal/local/<n>`
+    pub fn clientid(&self) -> &Arc<str> {
+        // only taken out while dropping
+        self.client.as_ref().unwrap().clientid()
+    }
+    /// Publishes a message as this client, it is delivered to its own matching
+    /// subscriptions as well
+    pub async fn publish(
+        &self,
+        topic: &str,
+        payload: Bytes,
+        qos: QoS,
+        retain: bool,
+        props: impl IntoIterator<Item = (Property, MqttPropValue)>,
+    ) -> Result<(), ServerError> {
+        let packet = internal_publish(topic, payload, qos, retain, props)?;
+        self.incoming
+            .send(PacketInfo {
+                senderid: self.clientid().clone(),
+                packet,
+                traced: false,
+            })
+            .await
+            .map_err(|_| ServerError::Misc("Dispatcher is not running".to_owned()))
+    }
+    /// Adds `filter` to the subscriptions of this client
+    pub async fn subscribe(&self, filter: &str, qos: QoS) -> Result<(), ServerError> {
+        let clientid = self.clientid();
+        internal_subscribe(
+            &self.topics,
+            &self.sys,
+            &self.clients,
+            clientid,
+            filter,
+            qos,
+        )
+        .await
+    }
+    /// Removes `filter` from the subscriptions of this client, false if it had none
+    pub async fn unsubscribe(&self, filter: &str) -> bool {
+        self.topics
+            .unsubscribe(self.clientid().clone(), filter)
+            .await
+    }
+}
+
+impl Stream for LocalClient {
+    type Item = Message;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        let client = self.client.as_mut().unwrap();
+        loop {
+            match client.poll_recv(cx) {
+                Poll::Ready(Some(packet)) => {
+                    if let Packet::Publish(publish) = &*packet {
+                        return Poll::Ready(Some(Message::from(publish)));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Drop for LocalClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            release(client, self.topics.clone());
+        }
+    }
+}
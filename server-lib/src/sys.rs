@@ -0,0 +1,90 @@
+use apiformes_packet::prelude::*;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+pub const SYS_VERSION: &str = "$SYS/broker/version";
+pub const SYS_NODE_ID: &str = "$SYS/broker/node_id";
+pub const SYS_TOPIC_TREE_ALARM: &str = "$SYS/broker/alarms/topic_tree";
+pub const SYS_HEARTBEAT: &str = "$SYS/broker/heartbeat";
+pub const SYS_CONFIG: &str = "$SYS/broker/config";
+
+/// Returns a human readable description of the running broker build,
+/// e.g. `0.1.0 (commit 1a2b3c4, release build)`
+pub fn version_string() -> String {
+    let profile = if cfg!(debug_assertions) {
+        "debug"
+    } else {
+        "release"
+    };
+    format!(
+        "{} (commit {}, {} build)",
+        env!("CARGO_PKG_VERSION"),
+        env!("APIFORMES_COMMIT"),
+        profile
+    )
+}
+
+/// Broker information published under the `$SYS/` hierarchy. Those topics behave like
+/// retained messages, they are delivered to clients as soon as they subscribe to a
+/// matching filter.
+pub struct SysTopics {
+    topics: RwLock<HashMap<Arc<str>, Bytes>>,
+}
+
+impl SysTopics {
+    pub fn new(node_id: &str) -> Self {
+        let mut topics = HashMap::new();
+        topics.insert(
+            Arc::from(SYS_VERSION),
+            Bytes::from(version_string().into_bytes()),
+        );
+        topics.insert(
+            Arc::from(SYS_NODE_ID),
+            Bytes::copy_from_slice(node_id.as_bytes()),
+        );
+        SysTopics {
+            topics: RwLock::new(topics),
+        }
+    }
+    pub async fn set(&self, topic: Arc<str>, payload: Bytes) {
+        self.topics.write().await.insert(topic, payload);
+    }
+    pub async fn remove(&self, topic: &str) {
+        self.topics.write().await.remove(topic);
+    }
+    pub async fn get(&self, topic: &str) -> Option<Bytes> {
+        self.topics.read().await.get(topic).cloned()
+    }
+    /// Builds a retained publish packet for every `$SYS` topic matching `filter`
+    pub async fn matching(&self, filter: &str) -> Vec<Packet> {
+        self.topics
+            .read()
+            .await
+            .iter()
+            .filter(|(topic, _)| filter_matches(filter, topic))
+            .filter_map(|(topic, payload)| {
+                let mut publish = Publish::new(topic.clone(), payload.clone()).ok()?;
+                publish.set_retain();
+                Some(publish.build())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_filter_matches() {
+        assert!(filter_matches("$SYS/#", SYS_VERSION));
+        assert!(filter_matches("$SYS/broker/+", SYS_VERSION));
+        assert!(filter_matches("$SYS/broker/version", SYS_VERSION));
+        assert!(!filter_matches("#", SYS_VERSION));
+        assert!(!filter_matches("+/broker/version", SYS_VERSION));
+        assert!(!filter_matches("$SYS/broker", SYS_VERSION));
+        assert!(filter_matches("a/#", "a"));
+        assert!(!filter_matches("a/+", "a"));
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+struct Seen {
+    logged_at: Instant,
+    suppressed: u64,
+}
+
+/// Keeps repeated log lines from flooding the logs, e.g. when a misbehaving client triggers
+/// the same error for every packet it sends. Lines sharing a key are logged at most once
+/// per `window`, the occurrences in between are only counted.
+pub(crate) struct LogThrottle<K = &'static str> {
+    window: Duration,
+    seen: Mutex<HashMap<K, Seen>>,
+}
+
+impl<K: Hash + Eq> LogThrottle<K> {
+    /// A zero `window` logs every occurrence
+    pub(crate) fn new(window: Duration) -> Self {
+        LogThrottle {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Checks if the occurrence of `key` at `now` is to be logged, returns the number of
+    /// occurrences suppressed since the last logged one if so
+    pub(crate) fn admit(&self, key: K, now: Instant) -> Option<u64> {
+        let mut seen = self.seen.lock().unwrap();
+        match seen.get_mut(&key) {
+            Some(s) if now < s.logged_at + self.window => {
+                s.suppressed += 1;
+                None
+            }
+            Some(s) => {
+                let suppressed = s.suppressed;
+                s.logged_at = now;
+                s.suppressed = 0;
+                Some(suppressed)
+            }
+            None => {
+                seen.insert(
+                    key,
+                    Seen {
+                        logged_at: now,
+                        suppressed: 0,
+                    },
+                );
+                Some(0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_log_throttle() {
+        let throttle = LogThrottle::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert_eq!(throttle.admit("a", now), Some(0));
+        assert_eq!(throttle.admit("b", now), Some(0));
+        for i in 1..5 {
+            assert_eq!(throttle.admit("a", now + Duration::from_secs(i)), None);
+        }
+        let later = now + Duration::from_secs(10);
+        assert_eq!(throttle.admit("a", later), Some(4));
+        assert_eq!(throttle.admit("a", later), None);
+        assert_eq!(throttle.admit("b", later), Some(0));
+
+        let unthrottled = LogThrottle::new(Duration::ZERO);
+        assert_eq!(unthrottled.admit("a", now), Some(0));
+        assert_eq!(unthrottled.admit("a", now), Some(0));
+    }
+}
@@ -1,13 +1,67 @@
+#[cfg(feature = "edge-filter")]
+use crate::filter::PayloadFilter;
+use crate::{
+    clients::INTERNAL_CLIENTID_PREFIX,
+    config::{SubscriptionTree, TopicTreeAlarm},
+    cowtree::CowTree,
+    metrics::Metrics,
+    shutdown::Shutdown,
+    sys::{SysTopics, SYS_TOPIC_TREE_ALARM},
+};
 use apiformes_packet::prelude::*;
 use async_recursion::async_recursion;
 use bitflags::bitflags;
+use bytes::Bytes;
 use futures::future::BoxFuture;
-use std::collections::{hash_map::Entry, HashMap, HashSet};
-use std::sync::Arc;
+use std::collections::{hash_map::Entry, BTreeSet, HashMap, HashSet};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
 use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
-use tracing::trace;
+use tokio::time::{interval, Duration};
+use tracing::{debug, info, trace, warn};
 type ClientId = Arc<str>;
 type SubTopic = Arc<str>;
+type Subscribers = HashMap<ClientId, SubscriptionInfo>;
+
+/// Topic filters of shared subscriptions start with this prefix (4.8.2)
+pub const SHARED_PREFIX: &str = "$share/";
+
+/// Splits the shared subscription `$share/{ShareName}/{filter}` into its share name and
+/// topic filter, None if `topic` is not a shared subscription. Either part may be empty.
+pub fn split_shared(topic: &str) -> Option<(&str, &str)> {
+    topic
+        .strip_prefix(SHARED_PREFIX)
+        .map(|rest| rest.split_once('/').unwrap_or((rest, "")))
+}
+
+/// The key standing for the whole group of a shared subscription in the subscription tree,
+/// it cannot clash with the id of a client
+fn shared_key(topic: &str) -> ClientId {
+    Arc::from(format!("{}{}", INTERNAL_CLIENTID_PREFIX, topic))
+}
+
+/// A subscription matching a topic: the topic filter it matched through, the subscriber
+/// and its subscription
+pub type Matched = (SubTopic, ClientId, SubscriptionInfo);
+
+/// Adds the subscriptions of the tree level reached through `levels` to `found`, `levels`
+/// as split by `topic_to_subtopics`
+pub(crate) fn push_matched(found: &mut Vec<Matched>, levels: &[&str], subs: &Subscribers) {
+    if subs.is_empty() {
+        return;
+    }
+    let mut filter = levels.join("/");
+    if levels.first() == Some(&"/") {
+        // the first level of `/x` is empty
+        filter.remove(0);
+    }
+    let filter: SubTopic = Arc::from(filter);
+    for (clientid, info) in subs {
+        found.push((filter.clone(), clientid.clone(), info.clone()));
+    }
+}
 
 bitflags! {
     pub struct SubscriptionFlags: u8 {
@@ -20,14 +74,30 @@ bitflags! {
 pub struct SubscriptionInfo {
     pub qos: QoS,
     pub flags: SubscriptionFlags,
+    /// only payloads matching the filter are delivered
+    #[cfg(feature = "edge-filter")]
+    pub filter: Option<Arc<PayloadFilter>>,
 }
 
 impl SubscriptionInfo {
     fn new(qos: QoS, flags: SubscriptionFlags) -> Self {
-        SubscriptionInfo { qos, flags }
+        SubscriptionInfo {
+            qos,
+            flags,
+            #[cfg(feature = "edge-filter")]
+            filter: None,
+        }
     }
 }
 
+/// The members of one shared subscription, each publish matching it goes to one of them
+#[derive(Default)]
+struct SharedGroup {
+    members: Vec<(ClientId, SubscriptionInfo)>,
+    // rotates the member considered first
+    next: AtomicUsize,
+}
+
 /// This Block structure is designed to have minimal concurrency overhead,
 /// Justification for subscribers Lock:
 /// Lets assume that client is publishing to `/hello/world`, that client would
@@ -66,17 +136,15 @@ impl BlockInner {
             sub_blocks: HashMap::new(),
         }
     }
-    async fn insert_into_hash(&self, clientid: Arc<str>, qos: QoS, flags: SubscriptionFlags) {
+    async fn insert_into_hash(&self, clientid: Arc<str>, info: SubscriptionInfo) {
         trace!("Inserting {} into hash", clientid);
-        let info = SubscriptionInfo::new(qos, flags);
         self.hash_wildcard.write().await.insert(clientid, info);
     }
     async fn remove_from_hash(&self, clientid: Arc<str>) {
         self.hash_wildcard.write().await.remove(&clientid);
     }
-    async fn insert_into_subs(&self, clientid: Arc<str>, qos: QoS, flags: SubscriptionFlags) {
+    async fn insert_into_subs(&self, clientid: Arc<str>, info: SubscriptionInfo) {
         trace!("Inserting {} into subs", clientid);
-        let info = SubscriptionInfo::new(qos, flags);
         self.subscribers.write().await.insert(clientid, info);
     }
     async fn remove_from_subs(&self, clientid: Arc<str>) {
@@ -97,6 +165,11 @@ impl BlockInner {
         }
     }
 
+    async fn is_empty(&self) -> bool {
+        self.sub_blocks.is_empty()
+            && self.hash_wildcard.read().await.is_empty()
+            && self.subscribers.read().await.is_empty()
+    }
     async fn collect_subscribers(&self, subs: &mut HashMap<ClientId, SubscriptionInfo>) {
         trace!(
             "Collecting subsscribers total = {}",
@@ -138,27 +211,41 @@ impl Block {
         self.inner.write().await
     }
 
+    /// Returns the number of blocks created on the way
     #[async_recursion]
     async fn visit<'a, S>(
         &self,
         mut sections: S,
         create: bool,
         run: impl FnOnce(&Block, bool) -> BoxFuture<()> + Send + 'async_recursion,
-    ) where
+    ) -> u64
+    where
         S: Iterator<Item = &'a str> + Send,
     {
         let x = sections.next();
         trace!("Visiting {:?}", x);
         match x {
-            Some("#") => run(self, true).await,
-            None => run(self, false).await,
+            Some("#") => {
+                run(self, true).await;
+                0
+            }
+            None => {
+                run(self, false).await;
+                0
+            }
             Some(section) => {
-                if create {
-                    self.create_if_not_existing(section).await;
-                }
-                let raii = self.read().await;
-                if let Some(sub_block) = raii.sub_blocks.get(section) {
-                    sub_block.visit(sections, create, run).await;
+                let mut created = 0;
+                loop {
+                    created += (create && self.create_if_not_existing(section).await) as u64;
+                    let raii = self.read().await;
+                    match raii.sub_blocks.get(section) {
+                        Some(sub_block) => {
+                            return sub_block.visit(sections, create, run).await + created
+                        }
+                        // pruned before we got to it
+                        None if create => continue,
+                        None => return created,
+                    }
                 }
             }
         }
@@ -189,20 +276,126 @@ impl Block {
         }
     }
 
+    /// Same as `collect_subs` keeping every matching subscription along with its filter,
+    /// `levels` leads to this block
+    #[async_recursion]
+    async fn collect_matched<'a, S>(
+        &self,
+        found: &mut Vec<Matched>,
+        levels: &mut Vec<&'a str>,
+        mut sections: S,
+    ) where
+        S: Iterator<Item = &'a str> + Send + Sync + Clone,
+    {
+        let raii = self.read().await;
+        levels.push("#");
+        push_matched(found, levels, &*raii.hash_wildcard.read().await);
+        levels.pop();
+        match sections.next() {
+            Some(section) => {
+                for level in ["+", section] {
+                    if let Some(sub_block) = raii.sub_blocks.get(level) {
+                        levels.push(level);
+                        sub_block
+                            .collect_matched(found, levels, sections.clone())
+                            .await;
+                        levels.pop();
+                    }
+                }
+            }
+            None => push_matched(found, levels, &*raii.subscribers.read().await),
+        }
+    }
+
+    /// Removes the blocks below this one holding no subscription, returns how many
+    #[async_recursion]
+    async fn prune(&self) -> u64 {
+        let mut pruned = 0;
+        for sub_block in self.read().await.sub_blocks.values() {
+            pruned += sub_block.prune().await;
+        }
+        // nobody is below this block while we hold the write lock, blocks filled since
+        // they were pruned are not empty anymore
+        let mut raii = self.write().await;
+        let mut empty = Vec::new();
+        for (subtopic, sub_block) in raii.sub_blocks.iter() {
+            if sub_block.read().await.is_empty().await {
+                empty.push(subtopic.clone());
+            }
+        }
+        for subtopic in &empty {
+            raii.sub_blocks.remove(subtopic);
+        }
+        pruned + empty.len() as u64
+    }
+
     async fn contains_subtopic(&self, subtopic: &str) -> bool {
         self.read().await.sub_blocks.contains_key(subtopic)
     }
-    async fn create_if_not_existing(&self, subtopic: &str) {
-        if !self.contains_subtopic(subtopic).await {
-            let block = Block::new();
-            self.write()
-                .await
-                .sub_blocks
-                .insert(Arc::from(subtopic), block);
+    /// Returns true if the block did not exist
+    async fn create_if_not_existing(&self, subtopic: &str) -> bool {
+        if self.contains_subtopic(subtopic).await {
+            return false;
+        }
+        // another subscriber may have created it since we released the read lock
+        match self.write().await.sub_blocks.entry(Arc::from(subtopic)) {
+            Entry::Vacant(e) => {
+                e.insert(Block::new());
+                true
+            }
+            Entry::Occupied(_) => false,
         }
     }
 }
 
+/// The subscribers of the topics published to recently. Entries are tagged with the
+/// generation of the tree they were collected from, so any change to the tree makes them
+/// all stale.
+struct SubscriberCache {
+    capacity: usize,
+    // bumped after every change to the tree
+    generation: AtomicU64,
+    entries: Mutex<HashMap<Box<str>, (u64, Subscribers)>>,
+}
+
+impl SubscriberCache {
+    fn new(capacity: usize) -> Self {
+        SubscriberCache {
+            capacity,
+            generation: AtomicU64::new(0),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+    fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+    fn get(&self, topic: &str, generation: u64) -> Option<Subscribers> {
+        match self.entries.lock().unwrap().get(topic) {
+            Some((collected, subs)) if *collected == generation => Some(subs.clone()),
+            _ => None,
+        }
+    }
+    /// Keeps the subscribers of `topic` as collected at `generation`, the cache starts over
+    /// once full
+    fn insert(&self, topic: &str, generation: u64, subs: Subscribers) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(topic) {
+            entries.clear();
+        }
+        entries.insert(Box::from(topic), (generation, subs));
+    }
+    /// Drops every entry, returns how many
+    fn flush(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let flushed = entries.len();
+        entries.clear();
+        flushed
+    }
+}
+
 /// There is important invariant to maintain here, the one and only use of
 /// `reverse_index` is when client disconnects and we want to remove all his
 /// subscriptions, we need to guarantee that for all entries in `reverse_index`
@@ -211,6 +404,16 @@ impl Block {
 pub struct TopicsTable {
     root_block: Block,
     reverse_index: RwLock<HashMap<ClientId, HashSet<SubTopic>>>,
+    // shared subscriptions by `$share/{ShareName}/{filter}`, only their group is in the tree
+    shared: RwLock<HashMap<SubTopic, SharedGroup>>,
+    metrics: Arc<Metrics>,
+    sys: Arc<SysTopics>,
+    alarm: Option<TopicTreeAlarm>,
+    alarm_raised: AtomicBool,
+    // only set when the subscriber cache is enabled
+    cache: Option<SubscriberCache>,
+    // replaces `root_block` when set
+    cow: Option<CowTree>,
     //TODO we can have slab allocator for Block here
     // and another slab allocator for subscription info
     // but first to make sure that this is not just
@@ -220,17 +423,94 @@ pub struct TopicsTable {
     // more complex benchmarks
 }
 
-impl Default for TopicsTable {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
 impl TopicsTable {
-    pub fn new() -> Self {
+    pub fn new(metrics: Arc<Metrics>, sys: Arc<SysTopics>, alarm: Option<TopicTreeAlarm>) -> Self {
         TopicsTable {
             root_block: Block::new(),
             reverse_index: RwLock::new(HashMap::new()),
+            shared: RwLock::new(HashMap::new()),
+            metrics,
+            sys,
+            alarm,
+            alarm_raised: AtomicBool::new(false),
+            cache: None,
+            cow: None,
+        }
+    }
+    /// Stores the subscriptions as `tree` describes, before any subscription is added
+    pub fn subscription_tree(mut self, tree: SubscriptionTree) -> Self {
+        self.cow = match tree {
+            SubscriptionTree::Locked => None,
+            SubscriptionTree::CopyOnWrite => Some(CowTree::new()),
+        };
+        self
+    }
+    /// Caches the subscribers of up to `capacity` topics, 0 caches nothing
+    pub fn cache_subscribers(mut self, capacity: usize) -> Self {
+        self.cache = (capacity > 0).then(|| SubscriberCache::new(capacity));
+        self
+    }
+    /// Drops the cached subscribers, returns the number of topics they were cached for
+    pub(crate) fn flush_cache(&self) -> usize {
+        self.cache.as_ref().map_or(0, SubscriberCache::flush)
+    }
+    fn invalidate_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate();
+        }
+    }
+    /// Rough number of bytes used by the subscription tree and the reverse index, the
+    /// allocations of topic levels and client ids are not accounted for
+    pub(crate) fn memory_estimate(&self) -> u64 {
+        let node = std::mem::size_of::<Block>() + std::mem::size_of::<BlockInner>();
+        let subscription =
+            std::mem::size_of::<SubscriptionInfo>() + 2 * std::mem::size_of::<ClientId>();
+        self.metrics.topic_tree_nodes() * node as u64
+            + self.metrics.subscriptions() * subscription as u64
+    }
+    /// Describes the first bound of the alarm the tree is over, if any
+    fn exceeded_bound(&self) -> Option<String> {
+        let alarm = self.alarm.as_ref()?;
+        let depth = self.metrics.topic_tree_depth();
+        let nodes = self.metrics.topic_tree_nodes();
+        let subscriptions = self.metrics.subscriptions();
+        if depth > alarm.max_depth {
+            Some(format!("depth {} > {}", depth, alarm.max_depth))
+        } else if nodes > alarm.max_nodes {
+            Some(format!("{} nodes > {}", nodes, alarm.max_nodes))
+        } else if subscriptions > alarm.max_subscriptions {
+            Some(format!(
+                "{} subscriptions > {}",
+                subscriptions, alarm.max_subscriptions
+            ))
+        } else {
+            None
+        }
+    }
+    async fn check_alarm(&self) {
+        let sys_event = match &self.alarm {
+            Some(alarm) => alarm.sys_event,
+            None => return,
+        };
+        match self.exceeded_bound() {
+            Some(bound) => {
+                if !self.alarm_raised.swap(true, Ordering::Relaxed) {
+                    warn!("Subscription tree over its bounds, {}", bound);
+                    self.metrics.inc_topic_tree_alarms();
+                    if sys_event {
+                        let payload = Bytes::from(bound.into_bytes());
+                        self.sys.set(Arc::from(SYS_TOPIC_TREE_ALARM), payload).await;
+                    }
+                }
+            }
+            None => {
+                if self.alarm_raised.swap(false, Ordering::Relaxed) {
+                    info!("Subscription tree back within its bounds");
+                    if sys_event {
+                        self.sys.remove(SYS_TOPIC_TREE_ALARM).await;
+                    }
+                }
+            }
         }
     }
     fn topic_to_subtopics<'a>(&self, topic: &'a str) -> impl Iterator<Item = &'a str> + Clone {
@@ -248,78 +528,201 @@ impl TopicsTable {
     /// Note: this visit functions assumes that topic is valid MQTT protocol topic as per the specs
     /// TODO: keep watching for https://github.com/rust-lang/rust/issues/62290
     /// This way you may have async futures, thus avoid returning a boxed value everytime.
+    /// Returns the number of blocks created
     async fn visit(
         &self,
         topic: &str,
         create: bool,
         run: impl FnOnce(&Block, bool) -> BoxFuture<()> + Send,
-    ) {
+    ) -> u64 {
         let sections = self.topic_to_subtopics(topic);
-        self.root_block.visit(sections, create, run).await;
+        self.root_block.visit(sections, create, run).await
     }
 
     // the reason we made clientid Arc but topic reference is that topic will be sliced anyways so
     // no need to do expensive AtomicUsize increment
-    async fn topics_add(
+    async fn topics_add(&self, clientid: Arc<str>, topic: &str, info: SubscriptionInfo) -> u64 {
+        if let Some(cow) = &self.cow {
+            let created = cow.insert(self.topic_to_subtopics(topic), clientid, info);
+            self.invalidate_cache();
+            return created;
+        }
+        let created = self
+            .visit(topic, true, |block: &Block, is_hash: bool| {
+                Box::pin(async move {
+                    if is_hash {
+                        block.read().await.insert_into_hash(clientid, info).await;
+                    } else {
+                        block.read().await.insert_into_subs(clientid, info).await;
+                    }
+                })
+            })
+            .await;
+        self.invalidate_cache();
+        created
+    }
+    /// Adds `clientid` to the shared subscription `topic`, its group is inserted in the tree
+    /// by the first member. Returns the number of blocks created
+    async fn shared_add(
         &self,
         clientid: Arc<str>,
-        topic: &str,
-        qos: QoS,
-        flags: SubscriptionFlags,
-    ) {
-        self.visit(topic, true, |block: &Block, is_hash: bool| {
-            Box::pin(async move {
-                if is_hash {
-                    block
-                        .read()
-                        .await
-                        .insert_into_hash(clientid, qos, flags)
-                        .await;
-                } else {
-                    block
-                        .read()
-                        .await
-                        .insert_into_subs(clientid, qos, flags)
-                        .await;
-                }
-            })
-        })
-        .await
+        topic: &Arc<str>,
+        filter: &str,
+        info: SubscriptionInfo,
+    ) -> u64 {
+        let mut shared = self.shared.write().await;
+        let mut created = 0;
+        let group = match shared.entry(topic.clone()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                created = self
+                    .topics_add(shared_key(topic), filter, info.clone())
+                    .await;
+                e.insert(SharedGroup::default())
+            }
+        };
+        match group.members.iter_mut().find(|(id, _)| *id == clientid) {
+            Some(member) => member.1 = info,
+            None => group.members.push((clientid, info)),
+        }
+        created
     }
-    async fn reverse_index_add(&self, clientid: Arc<str>, topic: Arc<str>) {
-        match self.reverse_index.write().await.entry(clientid) {
-            Entry::Occupied(mut e) => {
-                e.get_mut().insert(topic);
+    async fn shared_remove(&self, clientid: &str, topic: &str, filter: &str) {
+        let mut shared = self.shared.write().await;
+        let empty = match shared.get_mut(topic) {
+            Some(group) => {
+                group.members.retain(|(id, _)| &**id != clientid);
+                group.members.is_empty()
             }
+            None => false,
+        };
+        if empty {
+            shared.remove(topic);
+            self.topic_remove(shared_key(topic), filter).await;
+        }
+    }
+    /// The member of the shared subscription standing behind `key` to deliver to, `load`
+    /// tells how busy a member is and None if it cannot receive anything. The least busy
+    /// member wins, ties go to the next one in turn. Returns None if `key` is not a shared
+    /// subscription or no member can receive the publish. The turn only moves on when
+    /// `advance` is set.
+    pub(crate) async fn shared_member(
+        &self,
+        key: &str,
+        load: impl Fn(&str) -> Option<usize>,
+        advance: bool,
+    ) -> Option<(ClientId, SubscriptionInfo)> {
+        let topic = key.strip_prefix(INTERNAL_CLIENTID_PREFIX)?;
+        let shared = self.shared.read().await;
+        let group = shared.get(topic)?;
+        let start = if advance {
+            group.next.fetch_add(1, Ordering::Relaxed)
+        } else {
+            group.next.load(Ordering::Relaxed)
+        };
+        let n = group.members.len();
+        (0..n)
+            .map(|i| &group.members[(start + i) % n])
+            .filter_map(|member| load(&member.0).map(|l| (l, member)))
+            .min_by_key(|(l, _)| *l)
+            .map(|(_, (id, info))| (id.clone(), info.clone()))
+    }
+    /// Checks if `clientid` stands for the group of a shared subscription in the tree
+    pub(crate) fn is_shared(clientid: &str) -> bool {
+        clientid
+            .strip_prefix(INTERNAL_CLIENTID_PREFIX)
+            .is_some_and(|topic| topic.starts_with(SHARED_PREFIX))
+    }
+    // returns true if the client was not already subscribed to `topic`
+    async fn reverse_index_add(&self, clientid: Arc<str>, topic: Arc<str>) -> bool {
+        match self.reverse_index.write().await.entry(clientid) {
+            Entry::Occupied(mut e) => e.get_mut().insert(topic),
             Entry::Vacant(e) => {
                 let s = e.insert(HashSet::new());
-                s.insert(topic);
+                s.insert(topic)
             }
         }
     }
+    /// Subscribes `clientid` to `topic`, returns true if this is a new subscription
+    /// and false if an existing subscription got replaced
     pub async fn subscribe(
         &self,
         clientid: Arc<str>,
         topic: Arc<str>,
         qos: QoS,
         flags: SubscriptionFlags,
-    ) {
-        self.topics_add(clientid.clone(), &topic, qos, flags).await;
-        self.reverse_index_add(clientid, topic).await;
+    ) -> bool {
+        let info = SubscriptionInfo::new(qos, flags);
+        self.add_subscription(clientid, topic, info).await
     }
-    async fn reverse_index_remove(&self, clientid: &str, topic: &str) {
+    /// Same as `subscribe`, only delivering the messages whose payload matches `filter`
+    #[cfg(feature = "edge-filter")]
+    pub async fn subscribe_filtered(
+        &self,
+        clientid: Arc<str>,
+        topic: Arc<str>,
+        qos: QoS,
+        flags: SubscriptionFlags,
+        filter: Option<Arc<PayloadFilter>>,
+    ) -> bool {
+        let info = SubscriptionInfo {
+            filter,
+            ..SubscriptionInfo::new(qos, flags)
+        };
+        self.add_subscription(clientid, topic, info).await
+    }
+    async fn add_subscription(
+        &self,
+        clientid: Arc<str>,
+        topic: Arc<str>,
+        info: SubscriptionInfo,
+    ) -> bool {
+        let filter = split_shared(&topic).map_or(&*topic, |(_, filter)| filter);
+        let created = if filter.len() == topic.len() {
+            self.topics_add(clientid.clone(), &topic, info).await
+        } else {
+            self.shared_add(clientid.clone(), &topic, filter, info)
+                .await
+        };
+        let depth = self
+            .topic_to_subtopics(filter)
+            .filter(|s| *s != "#")
+            .count();
+        self.metrics.update_topic_tree_depth(depth as u64);
+        self.metrics.add_topic_tree_nodes(created);
+        let is_new = self.reverse_index_add(clientid, topic).await;
+        if is_new {
+            self.metrics.add_subscriptions(1);
+        }
+        self.check_alarm().await;
+        is_new
+    }
+    // returns true if the client was subscribed to `topic`
+    async fn reverse_index_remove(&self, clientid: &str, topic: &str) -> bool {
         let mut raii = self.reverse_index.write().await;
+        let mut removed = false;
         let mut cleanup = false;
         if let Some(set) = raii.get_mut(clientid) {
-            set.remove(topic);
+            removed = set.remove(topic);
+            if removed {
+                self.metrics.sub_subscriptions(1);
+            }
             cleanup = set.is_empty();
         }
         if cleanup {
             raii.remove(clientid);
         }
+        removed
     }
     //TODO replace Arc<str> with &str
     async fn topic_remove(&self, clientid: Arc<str>, topic: &str) {
+        if let Some(cow) = &self.cow {
+            // empty levels are dropped right away instead of by `prune`
+            let dropped = cow.remove(self.topic_to_subtopics(topic), &clientid);
+            self.metrics.sub_topic_tree_nodes(dropped);
+            self.invalidate_cache();
+            return;
+        }
         self.visit(topic, false, |block: &Block, is_hash: bool| {
             Box::pin(async move {
                 if is_hash {
@@ -329,30 +732,566 @@ impl TopicsTable {
                 }
             })
         })
-        .await
+        .await;
+        self.invalidate_cache();
+    }
+    /// Removes the subscription of `clientid` to `topic` from the tree, or from its group
+    /// for shared subscriptions
+    async fn subscription_remove(&self, clientid: Arc<str>, topic: &str) {
+        match split_shared(topic) {
+            Some((_, filter)) => self.shared_remove(&clientid, topic, filter).await,
+            None => self.topic_remove(clientid, topic).await,
+        }
     }
-    pub async fn unsubscribe(&self, clientid: Arc<str>, topic: &str) {
-        self.reverse_index_remove(&clientid, topic).await;
-        self.topic_remove(clientid, topic).await;
+    /// Removes the subscription of `clientid` to `topic`, returns false if it had none
+    pub async fn unsubscribe(&self, clientid: Arc<str>, topic: &str) -> bool {
+        if !self.reverse_index_remove(&clientid, topic).await {
+            return false;
+        }
+        self.subscription_remove(clientid, topic).await;
+        self.check_alarm().await;
+        true
     }
     pub async fn reverse_index_remove_all(&self, clientid: &str) -> Option<HashSet<SubTopic>> {
-        self.reverse_index.write().await.remove(clientid)
+        let topics = self.reverse_index.write().await.remove(clientid);
+        if let Some(topics) = &topics {
+            self.metrics.sub_subscriptions(topics.len() as u64);
+        }
+        topics
+    }
+    /// Removes the nodes of the subscription tree left without subscriptions, returns how
+    /// many
+    pub(crate) async fn prune(&self) -> u64 {
+        let pruned = self.root_block.prune().await;
+        self.metrics.sub_topic_tree_nodes(pruned);
+        self.check_alarm().await;
+        pruned
+    }
+    /// Prunes the subscription tree every `period` until the shutdown
+    pub(crate) async fn sweep(self: Arc<Self>, period: Duration, shutdown: Shutdown) {
+        let mut ticker = interval(period);
+        // the first tick completes immediately
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = ticker.tick() => (),
+            }
+            let pruned = self.prune().await;
+            if pruned > 0 {
+                debug!("Pruned {} empty nodes from the subscription tree", pruned);
+            }
+        }
     }
     pub async fn unsubscribe_all(&self, clientid: Arc<str>) {
         if let Some(topics) = self.reverse_index_remove_all(&clientid).await {
             for topic in topics {
-                self.topic_remove(clientid.clone(), &topic).await
+                self.subscription_remove(clientid.clone(), &topic).await
+            }
+            self.check_alarm().await;
+        }
+    }
+    /// Every topic filter subscribed to by some client, shared subscriptions by the filter
+    /// of their group
+    pub(crate) async fn filters(&self) -> BTreeSet<SubTopic> {
+        let mut filters = BTreeSet::new();
+        for topics in self.reverse_index.read().await.values() {
+            for topic in topics {
+                match split_shared(topic) {
+                    Some((_, filter)) => filters.insert(Arc::from(filter)),
+                    None => filters.insert(topic.clone()),
+                };
             }
         }
+        filters
     }
+    /// The subscriptions of `clientid` ordered by topic filter, shared ones included
+    pub(crate) async fn subscriptions_of(
+        &self,
+        clientid: &Arc<str>,
+    ) -> Vec<(SubTopic, SubscriptionInfo)> {
+        let mut topics: Vec<_> = match self.reverse_index.read().await.get(clientid) {
+            Some(topics) => topics.iter().cloned().collect(),
+            None => return Vec::new(),
+        };
+        topics.sort();
+        let mut subscriptions = Vec::new();
+        for topic in topics {
+            let info = match split_shared(&topic) {
+                Some(_) => self.shared.read().await.get(&topic).and_then(|group| {
+                    let member = group.members.iter().find(|(id, _)| id == clientid);
+                    member.map(|(_, info)| info.clone())
+                }),
+                None => self.subscription_info(clientid.clone(), &topic).await,
+            };
+            if let Some(info) = info {
+                subscriptions.push((topic, info));
+            }
+        }
+        subscriptions
+    }
+    async fn subscription_info(&self, clientid: Arc<str>, topic: &str) -> Option<SubscriptionInfo> {
+        if let Some(cow) = &self.cow {
+            return cow.get(self.topic_to_subtopics(topic), &clientid);
+        }
+        let found = Arc::new(Mutex::new(None));
+        let slot = found.clone();
+        self.visit(topic, false, move |block: &Block, is_hash: bool| {
+            Box::pin(async move {
+                let inner = block.read().await;
+                let subs = if is_hash {
+                    &inner.hash_wildcard
+                } else {
+                    &inner.subscribers
+                };
+                let info = subs.read().await.get(&clientid).cloned();
+                *slot.lock().unwrap() = info;
+            })
+        })
+        .await;
+        let info = found.lock().unwrap().take();
+        info
+    }
+    /// Every subscription matching `topic` by client id, shared subscriptions are listed
+    /// once under a key standing for their whole group, see `shared_member`
     pub async fn get_all_subscribed(&self, topic: &str) -> HashMap<ClientId, SubscriptionInfo> {
-        let mut subs = HashMap::new();
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.collect_subscribed(topic).await,
+        };
+        // read first, a change while collecting leaves the entry stale
+        let generation = cache.generation();
+        if let Some(subs) = cache.get(topic, generation) {
+            self.metrics.inc_subscriber_cache_hits();
+            return subs;
+        }
+        self.metrics.inc_subscriber_cache_misses();
+        let subs = self.collect_subscribed(topic).await;
+        cache.insert(topic, generation, subs.clone());
+        subs
+    }
+    async fn collect_subscribed(&self, topic: &str) -> HashMap<ClientId, SubscriptionInfo> {
         let sections = self.topic_to_subtopics(topic);
+        if let Some(cow) = &self.cow {
+            return cow.collect(sections);
+        }
+        let mut subs = HashMap::new();
         trace!(
             "collected_sections {:?}",
             sections.clone().collect::<Vec<_>>()
         );
-        self.root_block.collect_subs(&mut subs, sections).await;
+        match sections.clone().next() {
+            // 4.7.2: filters starting with a wildcard do not match topics starting with `$`
+            Some(first) if first.starts_with('$') => {
+                if let Some(sub_block) = self.root_block.read().await.sub_blocks.get(first) {
+                    sub_block.collect_subs(&mut subs, sections.skip(1)).await;
+                }
+            }
+            _ => self.root_block.collect_subs(&mut subs, sections).await,
+        }
         subs
     }
+    /// Same as `get_all_subscribed` ordered by client id, meant for tooling looking into
+    /// why a subscriber does or does not receive the messages of a topic
+    pub async fn matching_subscriptions(&self, topic: &str) -> Vec<(ClientId, SubscriptionInfo)> {
+        let mut subs: Vec<_> = self.get_all_subscribed(topic).await.into_iter().collect();
+        subs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        subs
+    }
+    /// Tells if the subscriptions to `filter` match `topic` the way the table matches them,
+    /// shared subscriptions by the filter of their group
+    pub fn matches(filter: &str, topic: &str) -> bool {
+        let filter = split_shared(filter).map_or(filter, |(_, filter)| filter);
+        filter_matches(filter, topic)
+    }
+    /// Every subscription matching `topic` along with the filter it matched through,
+    /// ordered by filter then client id. Members of shared subscriptions are listed one by
+    /// one under the `$share/{ShareName}/{filter}` they subscribed to.
+    pub async fn matched(&self, topic: &str) -> impl Iterator<Item = Matched> {
+        let sections = self.topic_to_subtopics(topic);
+        let mut found = Vec::new();
+        match &self.cow {
+            Some(cow) => cow.collect_matched(&mut found, sections),
+            None => match sections.clone().next() {
+                // 4.7.2, see `collect_subscribed`
+                Some(first) if first.starts_with('$') => {
+                    if let Some(sub_block) = self.root_block.read().await.sub_blocks.get(first) {
+                        sub_block
+                            .collect_matched(&mut found, &mut vec![first], sections.skip(1))
+                            .await
+                    }
+                }
+                _ => {
+                    let mut levels = Vec::new();
+                    self.root_block
+                        .collect_matched(&mut found, &mut levels, sections)
+                        .await
+                }
+            },
+        }
+        let mut matched = Vec::with_capacity(found.len());
+        let shared = self.shared.read().await;
+        for (filter, clientid, info) in found {
+            let group = clientid
+                .strip_prefix(INTERNAL_CLIENTID_PREFIX)
+                .and_then(|topic| shared.get_key_value(topic));
+            match group {
+                Some((topic, group)) => {
+                    for (member, info) in &group.members {
+                        matched.push((topic.clone(), member.clone(), info.clone()));
+                    }
+                }
+                None => matched.push((filter, clientid, info)),
+            }
+        }
+        matched.sort_by(|(f1, c1, _), (f2, c2, _)| (f1, c1).cmp(&(f2, c2)));
+        matched.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[tokio::test]
+    async fn test_topic_tree_metrics_and_alarm() {
+        let metrics = Arc::new(Metrics::new());
+        let sys = Arc::new(SysTopics::new("node"));
+        let alarm = TopicTreeAlarm {
+            max_depth: 8,
+            max_nodes: 16,
+            max_subscriptions: 2,
+            sys_event: true,
+        };
+        let topics = TopicsTable::new(metrics.clone(), sys.clone(), Some(alarm));
+        let (a, b): (Arc<str>, Arc<str>) = (Arc::from("a"), Arc::from("b"));
+        let flags = SubscriptionFlags::empty();
+        assert!(
+            topics
+                .subscribe(a.clone(), Arc::from("x/y/z"), QoS::QoS0, flags)
+                .await
+        );
+        assert!(
+            !topics
+                .subscribe(a.clone(), Arc::from("x/y/z"), QoS::QoS0, flags)
+                .await
+        );
+        topics
+            .subscribe(a.clone(), Arc::from("x/#"), QoS::QoS0, flags)
+            .await;
+        assert_eq!(metrics.topic_tree_depth(), 3);
+        assert_eq!(metrics.topic_tree_nodes(), 3);
+        assert_eq!(metrics.subscriptions(), 2);
+        assert_eq!(metrics.topic_tree_alarms(), 0);
+
+        topics
+            .subscribe(b.clone(), Arc::from("x/y"), QoS::QoS0, flags)
+            .await;
+        assert_eq!(metrics.subscriptions(), 3);
+        assert_eq!(metrics.topic_tree_alarms(), 1);
+        assert!(sys.get(SYS_TOPIC_TREE_ALARM).await.is_some());
+
+        topics.unsubscribe_all(a).await;
+        assert_eq!(metrics.subscriptions(), 1);
+        assert!(sys.get(SYS_TOPIC_TREE_ALARM).await.is_none());
+        topics.unsubscribe(b, "x/y").await;
+        assert_eq!(metrics.subscriptions(), 0);
+        assert_eq!(metrics.topic_tree_nodes(), 3);
+    }
+    #[tokio::test]
+    async fn test_subscriber_cache() {
+        let metrics = Arc::new(Metrics::new());
+        let sys = Arc::new(SysTopics::new("node"));
+        let cached = TopicsTable::new(metrics.clone(), sys.clone(), None).cache_subscribers(4);
+        let reference = TopicsTable::new(Arc::new(Metrics::new()), sys, None);
+        let filters = ["x/y", "x/+", "x/#", "#", "+/y", "$share/g/x/y", "z"];
+        let topics = ["x/y", "x/z", "z", "x"];
+        let clients: Vec<Arc<str>> = vec![Arc::from("a"), Arc::from("b"), Arc::from("c")];
+        let sorted = |subs: HashMap<ClientId, SubscriptionInfo>| {
+            let mut subs: Vec<_> = subs.into_iter().map(|(id, i)| (id, i.qos)).collect();
+            subs.sort_by(|a, b| a.0.cmp(&b.0));
+            subs
+        };
+        // xorshift, any interleaving of subscriptions and publishes must see the same
+        // subscribers as the uncached table
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize % n
+        };
+        for _ in 0..2000 {
+            let clientid = clients[next(clients.len())].clone();
+            let filter: Arc<str> = Arc::from(filters[next(filters.len())]);
+            let topic = topics[next(topics.len())];
+            match next(4) {
+                0 => {
+                    let qos = QoS::from_u8(next(2) as u8).unwrap();
+                    let flags = SubscriptionFlags::empty();
+                    for table in [&cached, &reference] {
+                        table
+                            .subscribe(clientid.clone(), filter.clone(), qos, flags)
+                            .await;
+                    }
+                }
+                1 => {
+                    for table in [&cached, &reference] {
+                        table.unsubscribe(clientid.clone(), &filter).await;
+                    }
+                }
+                _ => {
+                    let subs = sorted(cached.get_all_subscribed(topic).await);
+                    let expected = sorted(reference.get_all_subscribed(topic).await);
+                    assert!(subs == expected, "stale subscribers of {}", topic);
+                }
+            }
+        }
+        assert!(metrics.subscriber_cache_hits() > 0);
+        assert!(metrics.subscriber_cache_misses() > 0);
+
+        cached.get_all_subscribed("x/y").await;
+        let hits = metrics.subscriber_cache_hits();
+        cached.get_all_subscribed("x/y").await;
+        assert_eq!(metrics.subscriber_cache_hits(), hits + 1);
+        assert!(cached.flush_cache() > 0);
+        assert_eq!(cached.flush_cache(), 0);
+        cached.get_all_subscribed("x/y").await;
+        assert_eq!(metrics.subscriber_cache_hits(), hits + 1);
+    }
+    #[tokio::test]
+    async fn test_copy_on_write_tree() {
+        let sys = Arc::new(SysTopics::new("node"));
+        let cow_metrics = Arc::new(Metrics::new());
+        let cow = TopicsTable::new(cow_metrics.clone(), sys.clone(), None)
+            .subscription_tree(SubscriptionTree::CopyOnWrite);
+        let locked_metrics = Arc::new(Metrics::new());
+        let locked = TopicsTable::new(locked_metrics.clone(), sys, None);
+        let filters = [
+            "x/y",
+            "x/+",
+            "x/#",
+            "#",
+            "+/y",
+            "$share/g/x/y",
+            "z/y/w",
+            "/a",
+        ];
+        let topics = ["x/y", "x/z", "z/y/w", "x", "/a"];
+        let clients: Vec<Arc<str>> = vec![Arc::from("a"), Arc::from("b"), Arc::from("c")];
+        let sorted = |subs: HashMap<ClientId, SubscriptionInfo>| {
+            let mut subs: Vec<_> = subs.into_iter().map(|(id, i)| (id, i.qos)).collect();
+            subs.sort_by(|a, b| a.0.cmp(&b.0));
+            subs
+        };
+        // xorshift, both trees must match the same subscribers and hold as many nodes once
+        // the locked one is pruned
+        let mut seed = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut next = |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize % n
+        };
+        for _ in 0..2000 {
+            let clientid = clients[next(clients.len())].clone();
+            let filter: Arc<str> = Arc::from(filters[next(filters.len())]);
+            let topic = topics[next(topics.len())];
+            match next(4) {
+                0 => {
+                    let qos = QoS::from_u8(next(2) as u8).unwrap();
+                    let flags = SubscriptionFlags::empty();
+                    for table in [&cow, &locked] {
+                        table
+                            .subscribe(clientid.clone(), filter.clone(), qos, flags)
+                            .await;
+                    }
+                }
+                1 => {
+                    for table in [&cow, &locked] {
+                        table.unsubscribe(clientid.clone(), &filter).await;
+                    }
+                }
+                2 => {
+                    let subs = sorted(cow.get_all_subscribed(topic).await);
+                    let expected = sorted(locked.get_all_subscribed(topic).await);
+                    assert!(subs == expected, "subscribers of {}", topic);
+                }
+                _ => {
+                    let qos_of = |subs: Vec<(SubTopic, SubscriptionInfo)>| {
+                        subs.into_iter()
+                            .map(|(t, i)| (t, i.qos))
+                            .collect::<Vec<_>>()
+                    };
+                    let subs = qos_of(cow.subscriptions_of(&clientid).await);
+                    let expected = qos_of(locked.subscriptions_of(&clientid).await);
+                    assert!(subs == expected, "subscriptions of {}", clientid);
+                }
+            }
+        }
+        assert_eq!(cow.prune().await, 0);
+        locked.prune().await;
+        assert_eq!(
+            cow_metrics.topic_tree_nodes(),
+            locked_metrics.topic_tree_nodes()
+        );
+        for clientid in &clients {
+            cow.unsubscribe_all(clientid.clone()).await;
+        }
+        assert_eq!(cow_metrics.topic_tree_nodes(), 0);
+        assert!(cow.get_all_subscribed("x/y").await.is_empty());
+    }
+    #[tokio::test]
+    async fn test_prune() {
+        let metrics = Arc::new(Metrics::new());
+        let topics = TopicsTable::new(metrics.clone(), Arc::new(SysTopics::new("node")), None);
+        let (a, b): (Arc<str>, Arc<str>) = (Arc::from("a"), Arc::from("b"));
+        let flags = SubscriptionFlags::empty();
+        topics
+            .subscribe(a.clone(), Arc::from("x/y/z"), QoS::QoS0, flags)
+            .await;
+        topics
+            .subscribe(b.clone(), Arc::from("x/#"), QoS::QoS0, flags)
+            .await;
+        topics
+            .subscribe(b.clone(), Arc::from("v/w"), QoS::QoS0, flags)
+            .await;
+        assert_eq!(metrics.topic_tree_nodes(), 5);
+        assert_eq!(topics.prune().await, 0);
+
+        topics.unsubscribe_all(a.clone()).await;
+        topics.unsubscribe(b.clone(), "v/w").await;
+        // x still holds the `#` subscription
+        assert_eq!(topics.prune().await, 4);
+        assert_eq!(metrics.topic_tree_nodes(), 1);
+        assert_eq!(topics.get_all_subscribed("x/y/z").await.len(), 1);
+
+        // pruned nodes are created again by the next subscription
+        topics
+            .subscribe(a.clone(), Arc::from("x/y/z"), QoS::QoS0, flags)
+            .await;
+        assert_eq!(metrics.topic_tree_nodes(), 3);
+        assert_eq!(topics.get_all_subscribed("x/y/z").await.len(), 2);
+        topics.unsubscribe_all(a).await;
+        topics.unsubscribe_all(b).await;
+        assert_eq!(topics.prune().await, 3);
+        assert_eq!(metrics.topic_tree_nodes(), 0);
+    }
+    #[tokio::test]
+    async fn test_matched() {
+        assert!(TopicsTable::matches("x/+/z", "x/y/z"));
+        assert!(TopicsTable::matches("$share/g/x/#", "x/y"));
+        assert!(!TopicsTable::matches("#", "$SYS/x"));
+        assert!(!TopicsTable::matches("+/x", "$SYS/x"));
+        assert!(TopicsTable::matches("$SYS/#", "$SYS/x"));
+        assert!(TopicsTable::matches("/+", "/x"));
+        assert!(!TopicsTable::matches("x/+", "x/y/z"));
+
+        let sys = Arc::new(SysTopics::new("node"));
+        let locked = TopicsTable::new(Arc::new(Metrics::new()), sys.clone(), None);
+        let cow = TopicsTable::new(Arc::new(Metrics::new()), sys, None)
+            .subscription_tree(SubscriptionTree::CopyOnWrite);
+        let subscriptions = [
+            ("a", "x/y", QoS::QoS0),
+            ("a", "x/+", QoS::QoS1),
+            ("b", "#", QoS::QoS0),
+            ("b", "x/y/z", QoS::QoS0),
+            ("c", "$share/g/+/y", QoS::QoS1),
+            ("d", "$share/g/+/y", QoS::QoS0),
+            ("d", "/x", QoS::QoS0),
+            ("e", "$SYS/#", QoS::QoS0),
+        ];
+        for table in [&locked, &cow] {
+            for (clientid, filter, qos) in subscriptions {
+                table
+                    .subscribe(
+                        Arc::from(clientid),
+                        Arc::from(filter),
+                        qos,
+                        SubscriptionFlags::empty(),
+                    )
+                    .await;
+            }
+            let matched: Vec<_> = table
+                .matched("x/y")
+                .await
+                .map(|(filter, clientid, info)| {
+                    (filter.to_string(), clientid.to_string(), info.qos)
+                })
+                .collect();
+            let expected = [
+                ("#", "b", QoS::QoS0),
+                ("$share/g/+/y", "c", QoS::QoS1),
+                ("$share/g/+/y", "d", QoS::QoS0),
+                ("x/+", "a", QoS::QoS1),
+                ("x/y", "a", QoS::QoS0),
+            ];
+            let expected: Vec<_> = expected
+                .iter()
+                .map(|(f, c, qos)| (f.to_string(), c.to_string(), *qos))
+                .collect();
+            assert!(matched == expected);
+            for (filter, _, _) in &matched {
+                assert!(TopicsTable::matches(filter, "x/y"));
+            }
+            let filters: Vec<_> = table.matched("/x").await.map(|(f, _, _)| f).collect();
+            assert_eq!(filters, [Arc::from("#"), Arc::from("/x")]);
+            // 4.7.2: `#` and `+/y` do not match a topic starting with `$`
+            let filters: Vec<_> = table.matched("$SYS/y").await.map(|(f, _, _)| f).collect();
+            assert_eq!(filters, [Arc::from("$SYS/#")]);
+            let subs = table.get_all_subscribed("$SYS/y").await;
+            assert_eq!(subs.keys().collect::<Vec<_>>(), [&Arc::from("e")]);
+        }
+    }
+    #[tokio::test]
+    async fn test_shared_subscriptions() {
+        assert_eq!(split_shared("$share/g/x/+"), Some(("g", "x/+")));
+        assert_eq!(split_shared("$share/g"), Some(("g", "")));
+        assert_eq!(split_shared("x/$share/g"), None);
+
+        let metrics = Arc::new(Metrics::new());
+        let topics = TopicsTable::new(metrics.clone(), Arc::new(SysTopics::new("node")), None);
+        let (a, b): (Arc<str>, Arc<str>) = (Arc::from("a"), Arc::from("b"));
+        let shared: Arc<str> = Arc::from("$share/g/x/+");
+        for clientid in [&a, &b] {
+            topics
+                .subscribe(
+                    clientid.clone(),
+                    shared.clone(),
+                    QoS::QoS0,
+                    SubscriptionFlags::empty(),
+                )
+                .await;
+        }
+        assert_eq!(metrics.subscriptions(), 2);
+        assert_eq!(metrics.topic_tree_depth(), 2);
+        let subs = topics.get_all_subscribed("x/y").await;
+        assert_eq!(subs.len(), 1);
+        let key = subs.keys().next().unwrap().clone();
+        assert!(TopicsTable::is_shared(&key));
+        assert!(!TopicsTable::is_shared("$share/g/x/+"));
+
+        let pick = |load: fn(&str) -> Option<usize>, advance| {
+            let topics = &topics;
+            let key = key.clone();
+            async move {
+                let member = topics.shared_member(&key, load, advance).await;
+                member.map(|(id, _)| id.to_string())
+            }
+        };
+        let idle = |_: &str| Some(0);
+        assert_eq!(pick(idle, false).await.as_deref(), Some("a"));
+        assert_eq!(pick(idle, true).await.as_deref(), Some("a"));
+        assert_eq!(pick(idle, true).await.as_deref(), Some("b"));
+        assert_eq!(pick(idle, true).await.as_deref(), Some("a"));
+        let busy_a = |id: &str| Some(if id == "a" { 5 } else { 0 });
+        assert_eq!(pick(busy_a, true).await.as_deref(), Some("b"));
+        assert_eq!(pick(busy_a, true).await.as_deref(), Some("b"));
+        assert_eq!(pick(|_| None, true).await, None);
+
+        topics.unsubscribe(a, &shared).await;
+        assert_eq!(pick(idle, true).await.as_deref(), Some("b"));
+        topics.unsubscribe_all(b).await;
+        assert!(topics.get_all_subscribed("x/y").await.is_empty());
+        assert_eq!(metrics.subscriptions(), 0);
+    }
 }
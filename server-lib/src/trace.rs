@@ -0,0 +1,306 @@
+use crate::admin::{ApiError, ApiErrorCode};
+use apiformes_packet::prelude::Packet;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// Step of the broker pipeline a traced publish went through
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceStage {
+    /// The whole packet was read from the connection, the origin of the trace
+    Received,
+    Decoded,
+    /// Queued for the dispatcher
+    Enqueued,
+    /// Taken from the queue by the dispatcher
+    Dequeued,
+    /// The subscriptions matching the topic were looked up
+    Matched,
+    /// Handed to the worker of a recipient
+    Sent,
+    /// Written to the connection of a recipient
+    Flushed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TraceEvent {
+    pub stage: TraceStage,
+    /// The recipient, for the `Sent` and `Flushed` stages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clientid: Option<String>,
+    /// Nanoseconds since the publish was received
+    pub elapsed_ns: u64,
+}
+
+/// Timestamps of a publish through the broker, see `MqttServer::trace_next_publish`
+#[derive(Serialize, Clone, Debug)]
+pub struct PublishTrace {
+    pub topic: String,
+    pub publisher: String,
+    /// In the order they happened
+    pub events: Vec<TraceEvent>,
+    /// False when some recipients were not written to before the trace timed out
+    pub complete: bool,
+}
+
+impl PublishTrace {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+struct Tracing {
+    trace: PublishTrace,
+    received: Instant,
+    // the packets handed to the recipients, told apart from other publishes by address
+    packets: Vec<Arc<Packet>>,
+    // recipients not written to yet, unknown until the fan-out is over
+    pending: Option<usize>,
+    done: oneshot::Sender<PublishTrace>,
+}
+
+enum State {
+    Idle,
+    Armed {
+        topic: Arc<str>,
+        done: oneshot::Sender<PublishTrace>,
+    },
+    Tracing(Box<Tracing>),
+}
+
+/// Follows a single publish through the pipeline, the stages record nothing unless a trace
+/// is armed or in progress
+pub(crate) struct PublishTracer {
+    // spares the untraced publishes the lock
+    active: AtomicBool,
+    state: Mutex<State>,
+}
+
+impl Default for PublishTracer {
+    fn default() -> Self {
+        PublishTracer {
+            active: AtomicBool::new(false),
+            state: Mutex::new(State::Idle),
+        }
+    }
+}
+
+impl PublishTracer {
+    pub(crate) fn new() -> Self {
+        PublishTracer::default()
+    }
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+    /// Traces the next publish on `topic`, the trace is sent once every recipient was
+    /// written to
+    pub(crate) fn arm(&self, topic: &str) -> Result<oneshot::Receiver<PublishTrace>, ApiError> {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Idle) {
+            return Err(ApiError::new(
+                ApiErrorCode::Unavailable,
+                "another publish is being traced".to_owned(),
+            ));
+        }
+        let (done, rx) = oneshot::channel();
+        *state = State::Armed {
+            topic: Arc::from(topic),
+            done,
+        };
+        self.active.store(true, Ordering::Release);
+        Ok(rx)
+    }
+    /// Ends the trace, returns what was recorded so far if the publish was seen
+    pub(crate) fn disarm(&self) -> Option<PublishTrace> {
+        let mut state = self.state.lock().unwrap();
+        self.active.store(false, Ordering::Release);
+        match std::mem::replace(&mut *state, State::Idle) {
+            State::Tracing(tracing) => Some(tracing.trace),
+            _ => None,
+        }
+    }
+    /// Starts tracing the publish `publisher` sent on `topic` if a trace is armed for this
+    /// topic, returns whether the publish is traced
+    pub(crate) fn start(
+        &self,
+        publisher: &str,
+        topic: &str,
+        received: Instant,
+        decoded: Instant,
+    ) -> bool {
+        if !self.is_active() {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap();
+        let done = match std::mem::replace(&mut *state, State::Idle) {
+            State::Armed { topic: armed, done } if &*armed == topic => done,
+            other => {
+                *state = other;
+                return false;
+            }
+        };
+        let mut tracing = Tracing {
+            trace: PublishTrace {
+                topic: topic.to_owned(),
+                publisher: publisher.to_owned(),
+                events: Vec::new(),
+                complete: false,
+            },
+            received,
+            packets: Vec::new(),
+            pending: None,
+            done,
+        };
+        tracing.record(TraceStage::Received, None, received);
+        tracing.record(TraceStage::Decoded, None, decoded);
+        *state = State::Tracing(Box::new(tracing));
+        true
+    }
+    fn with_tracing(&self, f: impl FnOnce(&mut Tracing)) {
+        if !self.is_active() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let finished = match &mut *state {
+            State::Tracing(tracing) => {
+                f(tracing);
+                tracing.pending == Some(0)
+            }
+            _ => false,
+        };
+        if finished {
+            self.active.store(false, Ordering::Release);
+            if let State::Tracing(mut tracing) = std::mem::replace(&mut *state, State::Idle) {
+                tracing.trace.complete = true;
+                // the caller gave up waiting
+                let _ = tracing.done.send(tracing.trace);
+            }
+        }
+    }
+    /// Records `stage` of the traced publish
+    pub(crate) fn record(&self, stage: TraceStage) {
+        let now = Instant::now();
+        self.with_tracing(|tracing| tracing.record(stage, None, now));
+    }
+    /// The traced publish was handed to the worker of `clientid` as `packet`
+    pub(crate) fn sent(&self, clientid: &str, packet: &Arc<Packet>) {
+        let now = Instant::now();
+        self.with_tracing(|tracing| {
+            tracing.record(TraceStage::Sent, Some(clientid), now);
+            if !tracing.packets.iter().any(|p| Arc::ptr_eq(p, packet)) {
+                tracing.packets.push(packet.clone());
+            }
+        });
+    }
+    /// The fan-out of the traced publish is over, it was sent to `recipients` clients
+    pub(crate) fn fanned_out(&self, recipients: usize) {
+        self.with_tracing(|tracing| {
+            let flushed = tracing
+                .trace
+                .events
+                .iter()
+                .filter(|e| e.stage == TraceStage::Flushed)
+                .count();
+            tracing.pending = Some(recipients.saturating_sub(flushed));
+        });
+    }
+    /// `packet` was written to the connection of `clientid`, only recorded if it is the
+    /// traced publish
+    pub(crate) fn flushed(&self, clientid: &str, packet: &Arc<Packet>) {
+        let now = Instant::now();
+        self.with_tracing(|tracing| {
+            if !tracing.packets.iter().any(|p| Arc::ptr_eq(p, packet)) {
+                return;
+            }
+            tracing.record(TraceStage::Flushed, Some(clientid), now);
+            if let Some(pending) = &mut tracing.pending {
+                *pending = pending.saturating_sub(1);
+            }
+        });
+    }
+}
+
+impl Tracing {
+    fn record(&mut self, stage: TraceStage, clientid: Option<&str>, at: Instant) {
+        let elapsed = at.saturating_duration_since(self.received);
+        self.trace.events.push(TraceEvent {
+            stage,
+            clientid: clientid.map(str::to_owned),
+            elapsed_ns: elapsed.as_nanos() as u64,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use apiformes_packet::prelude::*;
+
+    fn packet() -> Arc<Packet> {
+        Arc::new(
+            Publish::new(Arc::from("a"), Default::default())
+                .unwrap()
+                .build(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_publish_tracer() {
+        let tracer = PublishTracer::new();
+        let now = Instant::now();
+        // nothing is armed
+        assert!(!tracer.start("pub", "a", now, now));
+        let rx = tracer.arm("a").unwrap();
+        assert!(tracer.arm("b").is_err());
+        assert!(!tracer.start("pub", "b", now, now));
+        assert!(tracer.start("pub", "a", now, now));
+        // only the first publish is traced
+        assert!(!tracer.start("pub", "a", now, now));
+
+        let (traced, other) = (packet(), packet());
+        tracer.record(TraceStage::Enqueued);
+        tracer.record(TraceStage::Dequeued);
+        tracer.record(TraceStage::Matched);
+        tracer.sent("x", &traced);
+        tracer.sent("y", &traced);
+        tracer.flushed("x", &traced);
+        tracer.flushed("x", &other);
+        tracer.fanned_out(2);
+        tracer.flushed("y", &traced);
+
+        let trace = rx.await.unwrap();
+        assert!(trace.complete);
+        assert_eq!(trace.publisher, "pub");
+        let stages: Vec<_> = trace.events.iter().map(|e| e.stage).collect();
+        assert_eq!(
+            stages,
+            [
+                TraceStage::Received,
+                TraceStage::Decoded,
+                TraceStage::Enqueued,
+                TraceStage::Dequeued,
+                TraceStage::Matched,
+                TraceStage::Sent,
+                TraceStage::Sent,
+                TraceStage::Flushed,
+                TraceStage::Flushed,
+            ]
+        );
+        assert_eq!(trace.events[8].clientid.as_deref(), Some("y"));
+        assert!(trace
+            .events
+            .windows(2)
+            .all(|w| w[0].elapsed_ns <= w[1].elapsed_ns));
+
+        // a trace can be armed again, and given up on
+        let _rx = tracer.arm("a").unwrap();
+        assert!(tracer.start("pub", "a", now, now));
+        let partial = tracer.disarm().unwrap();
+        assert!(!partial.complete);
+        assert_eq!(partial.events.len(), 2);
+        assert!(tracer.disarm().is_none());
+    }
+}
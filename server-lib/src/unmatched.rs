@@ -0,0 +1,436 @@
+use crate::{
+    config::{RetainedEviction, RetainedLimits, UnmatchedPolicy, UnmatchedTopics},
+    metrics::Metrics,
+};
+use apiformes_packet::prelude::*;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::time::{Duration, Instant};
+use tracing::info;
+
+/// Publishes waiting for their grace window to end, once reached the oldest ones are
+/// dropped first
+const MAX_QUEUED: usize = 10_000;
+
+struct Queued {
+    publish: Publish,
+    deadline: Instant,
+}
+
+/// How a retained topic was used, see `MqttServer::retained_topics`
+#[derive(Clone, Debug)]
+pub struct RetainedTopic {
+    pub topic: Arc<str>,
+    /// Size of the topic name and payload, counted against `RetainedLimits::max_bytes`
+    pub bytes: usize,
+    /// Number of publishes stored for the topic, each one replacing the previous one
+    pub updates: u64,
+    /// Number of times the retained message was sent to a new subscription
+    pub deliveries: u64,
+    /// Time since the message was stored or last delivered
+    pub idle: Duration,
+}
+
+struct RetainedEntry {
+    publish: Publish,
+    stats: RetainedTopic,
+    used: Instant,
+    // position in `Retained::order`
+    rank: u64,
+}
+
+#[derive(Default)]
+struct Retained {
+    entries: HashMap<Arc<str>, RetainedEntry>,
+    // the next entry to evict first
+    order: BTreeMap<u64, Arc<str>>,
+    next_rank: u64,
+    bytes: usize,
+}
+
+impl Retained {
+    fn rank(&mut self, topic: &Arc<str>) -> u64 {
+        let rank = self.next_rank;
+        self.next_rank += 1;
+        self.order.insert(rank, topic.clone());
+        rank
+    }
+    fn remove(&mut self, topic: &str) -> Option<RetainedEntry> {
+        let entry = self.entries.remove(topic)?;
+        self.order.remove(&entry.rank);
+        self.bytes -= entry.stats.bytes;
+        Some(entry)
+    }
+    fn over(&self, limits: &RetainedLimits) -> bool {
+        matches!(limits.max_entries, Some(max) if self.entries.len() > max)
+            || matches!(limits.max_bytes, Some(max) if self.bytes > max)
+    }
+    /// Moves `topic` at the back of the eviction order
+    fn touch(&mut self, topic: &Arc<str>) {
+        if let Some(rank) = self.entries.get(topic).map(|e| e.rank) {
+            self.order.remove(&rank);
+            let rank = self.rank(topic);
+            self.entries.get_mut(topic).unwrap().rank = rank;
+        }
+    }
+}
+
+/// The retained messages and the publishes that reached no connected subscriber kept for
+/// the subscriptions to come, see `UnmatchedPolicy`
+pub(crate) struct UnmatchedPublishes {
+    policies: Vec<UnmatchedTopics>,
+    limits: RetainedLimits,
+    metrics: Arc<Metrics>,
+    // the last unmatched publish of every topic under `UnmatchedPolicy::Retain`
+    retained: Mutex<Retained>,
+    // in the order they were published
+    queued: Mutex<VecDeque<Queued>>,
+    // every stored retained message is sent to the standbys through it
+    replicas: Option<broadcast::Sender<Publish>>,
+}
+
+impl UnmatchedPublishes {
+    pub(crate) fn new(
+        policies: Vec<UnmatchedTopics>,
+        limits: RetainedLimits,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        UnmatchedPublishes {
+            policies,
+            limits,
+            metrics,
+            retained: Mutex::new(Retained::default()),
+            queued: Mutex::new(VecDeque::new()),
+            replicas: None,
+        }
+    }
+    /// Sends every retained message stored from now on to `replicas`
+    pub(crate) fn replicate(mut self, replicas: broadcast::Sender<Publish>) -> Self {
+        self.replicas = Some(replicas);
+        self
+    }
+    /// The policy of the longest prefix of `topic`, unmatched publishes are discarded
+    /// outside of every prefix
+    fn policy(&self, topic: &str) -> UnmatchedPolicy {
+        self.policies
+            .iter()
+            .filter(|p| topic.starts_with(&*p.prefix))
+            .max_by_key(|p| p.prefix.len())
+            .map_or(UnmatchedPolicy::Discard, |p| p.policy)
+    }
+    /// Keeps `publish`, which no connected subscriber received, if the policy of its topic
+    /// says so
+    pub(crate) fn keep(&self, publish: &Publish, now: Instant) {
+        match self.policy(publish.topic_name()) {
+            UnmatchedPolicy::Discard => (),
+            UnmatchedPolicy::Retain => self.retain(publish, now),
+            UnmatchedPolicy::Queue { grace } => {
+                let mut queued = self.queued.lock().unwrap();
+                if queued.len() >= MAX_QUEUED {
+                    queued.pop_front();
+                }
+                queued.push_back(Queued {
+                    publish: publish.clone(),
+                    deadline: now + Duration::from_secs(grace as u64),
+                });
+            }
+        }
+    }
+    /// Stores a publish sent with RETAIN as the retained message of its topic whatever the
+    /// policy of the topic, one with an empty payload clears it instead (3.3.1.3). The
+    /// standbys are sent the clearing publish as well.
+    pub(crate) fn publish_retained(&self, publish: &Publish, now: Instant) {
+        if !publish.payload().is_empty() {
+            return self.retain(publish, now);
+        }
+        let mut retained = self.retained.lock().unwrap();
+        if retained.remove(publish.topic_name()).is_some() {
+            self.metrics.set_retained_bytes(retained.bytes as u64);
+        }
+        if let Some(replicas) = &self.replicas {
+            // no standby may be connected
+            let _ = replicas.send(publish.clone());
+        }
+    }
+    /// Stores `publish` as the retained message of its topic, evicting others to stay
+    /// within the `RetainedLimits`
+    pub(crate) fn retain(&self, publish: &Publish, now: Instant) {
+        let topic = publish.topic_name();
+        let bytes = topic.len() + publish.payload().len();
+        let mut retained = self.retained.lock().unwrap();
+        let previous = retained.remove(topic);
+        let (updates, deliveries) = previous
+            .as_ref()
+            .map_or((0, 0), |e| (e.stats.updates, e.stats.deliveries));
+        // it would evict every other topic and still not fit
+        if matches!(self.limits.max_bytes, Some(max) if bytes > max) {
+            self.evicted(topic, bytes, "larger than max_bytes");
+            self.metrics.set_retained_bytes(retained.bytes as u64);
+            return;
+        }
+        let rank = retained.rank(topic);
+        retained.entries.insert(
+            topic.clone(),
+            RetainedEntry {
+                publish: publish.clone(),
+                stats: RetainedTopic {
+                    topic: topic.clone(),
+                    bytes,
+                    updates: updates + 1,
+                    deliveries,
+                    idle: Duration::ZERO,
+                },
+                used: now,
+                rank,
+            },
+        );
+        retained.bytes += bytes;
+        if let Some(replicas) = &self.replicas {
+            // no standby may be connected
+            let _ = replicas.send(publish.clone());
+        }
+        while retained.over(&self.limits) {
+            let first = retained.order.values().next().cloned();
+            match first.and_then(|topic| retained.remove(&topic)) {
+                Some(entry) => self.evicted(&entry.stats.topic, entry.stats.bytes, "limit reached"),
+                None => break,
+            }
+        }
+        self.metrics.set_retained_bytes(retained.bytes as u64);
+    }
+    fn evicted(&self, topic: &str, bytes: usize, reason: &str) {
+        self.metrics.inc_retained_evictions();
+        info!(topic, bytes, reason, "Evicted retained message");
+    }
+    /// The usage of every retained topic, in eviction order
+    pub(crate) fn retained_topics(&self, now: Instant) -> Vec<RetainedTopic> {
+        let retained = self.retained.lock().unwrap();
+        retained
+            .order
+            .values()
+            .map(|topic| {
+                let entry = &retained.entries[topic];
+                let mut stats = entry.stats.clone();
+                stats.idle = now.saturating_duration_since(entry.used);
+                stats
+            })
+            .collect()
+    }
+    /// The retained messages in eviction order, as sent to a standby when it connects
+    pub(crate) fn retained_publishes(&self) -> Vec<Publish> {
+        let retained = self.retained.lock().unwrap();
+        retained
+            .order
+            .values()
+            .map(|topic| retained.entries[topic].publish.clone())
+            .collect()
+    }
+    /// The kept publishes matching `filter`, delivered at most at `qos`. Retained ones are
+    /// flagged as such, queued ones are sent as they were published, their RETAIN flag
+    /// only kept with `retain_as_published` (3.3.1.3).
+    pub(crate) fn matching(
+        &self,
+        filter: &str,
+        qos: QoS,
+        retain_as_published: bool,
+        now: Instant,
+    ) -> Vec<Packet> {
+        let downgrade = |mut publish: Publish| {
+            if publish.qos() > qos {
+                publish.set_qos(qos);
+            }
+            publish
+        };
+        let mut packets = Vec::new();
+        let mut retained = self.retained.lock().unwrap();
+        let matched: Vec<_> = retained
+            .entries
+            .keys()
+            .filter(|topic| filter_matches(filter, topic))
+            .cloned()
+            .collect();
+        for topic in matched {
+            if self.limits.eviction == RetainedEviction::LeastRecentlyUsed {
+                retained.touch(&topic);
+            }
+            let entry = retained.entries.get_mut(&topic).unwrap();
+            entry.stats.deliveries += 1;
+            entry.used = now;
+            let mut publish = downgrade(entry.publish.clone());
+            publish.set_retain();
+            packets.push(publish.build());
+        }
+        drop(retained);
+        let mut queued = self.queued.lock().unwrap();
+        queued.retain(|q| q.deadline > now);
+        packets.extend(
+            queued
+                .iter()
+                .filter(|q| filter_matches(filter, q.publish.topic_name()))
+                .map(|q| {
+                    let mut publish = downgrade(q.publish.clone());
+                    if !retain_as_published {
+                        publish.clear_retain();
+                    }
+                    publish.build()
+                }),
+        );
+        packets
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+    fn publish(topic: &str, qos: QoS) -> Publish {
+        let mut publish = Publish::new(Arc::from(topic), Default::default()).unwrap();
+        publish.set_qos(qos);
+        publish
+    }
+    fn topics(packets: &[Packet]) -> Vec<(&str, bool)> {
+        packets
+            .iter()
+            .map(|p| match p {
+                Packet::Publish(p) => (&**p.topic_name(), p.flags().contains(PublishFlags::RETAIN)),
+                _ => panic!("not a publish"),
+            })
+            .collect()
+    }
+    fn retaining(limits: RetainedLimits) -> (UnmatchedPublishes, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::new());
+        let policies = vec![UnmatchedTopics {
+            prefix: String::new(),
+            policy: UnmatchedPolicy::Retain,
+        }];
+        (
+            UnmatchedPublishes::new(policies, limits, metrics.clone()),
+            metrics,
+        )
+    }
+    #[test]
+    fn test_unmatched_policies() {
+        let unmatched = UnmatchedPublishes::new(
+            vec![
+                UnmatchedTopics {
+                    prefix: "fleet/".to_owned(),
+                    policy: UnmatchedPolicy::Queue { grace: 10 },
+                },
+                UnmatchedTopics {
+                    prefix: "fleet/config/".to_owned(),
+                    policy: UnmatchedPolicy::Retain,
+                },
+            ],
+            Default::default(),
+            Arc::new(Metrics::new()),
+        );
+        let now = Instant::now();
+        for topic in ["fleet/a", "fleet/b", "fleet/config/a", "other/a"] {
+            unmatched.keep(&publish(topic, QoS::QoS1), now);
+        }
+        // only the last publish of a retained topic is kept
+        unmatched.keep(&publish("fleet/config/a", QoS::QoS0), now);
+
+        let packets = unmatched.matching("#", QoS::QoS1, false, now);
+        let mut kept = topics(&packets);
+        kept.sort();
+        assert_eq!(
+            kept,
+            vec![
+                ("fleet/a", false),
+                ("fleet/b", false),
+                ("fleet/config/a", true)
+            ]
+        );
+        let packets = unmatched.matching("fleet/+", QoS::QoS0, false, now);
+        assert_eq!(
+            topics(&packets),
+            vec![("fleet/a", false), ("fleet/b", false)]
+        );
+        assert!(packets.iter().all(|p| match p {
+            Packet::Publish(p) => p.qos() == QoS::QoS0,
+            _ => false,
+        }));
+
+        // queued publishes expire after their grace window, retained ones stay
+        let later = now + Duration::from_secs(10);
+        let packets = unmatched.matching("#", QoS::QoS1, false, later);
+        assert_eq!(topics(&packets), vec![("fleet/config/a", true)]);
+    }
+    #[test]
+    fn test_retained_eviction() {
+        let now = Instant::now();
+        let kept = |unmatched: &UnmatchedPublishes| -> Vec<String> {
+            unmatched
+                .retained_topics(now)
+                .iter()
+                .map(|t| t.topic.to_string())
+                .collect()
+        };
+        let limits = RetainedLimits {
+            max_entries: Some(2),
+            max_bytes: None,
+            eviction: RetainedEviction::LeastRecentlyUsed,
+        };
+        let (unmatched, metrics) = retaining(limits.clone());
+        unmatched.keep(&publish("a", QoS::QoS0), now);
+        unmatched.keep(&publish("b", QoS::QoS0), now);
+        // delivering a spares it from the next eviction
+        assert_eq!(unmatched.matching("a", QoS::QoS0, false, now).len(), 1);
+        unmatched.keep(&publish("c", QoS::QoS0), now);
+        assert_eq!(kept(&unmatched), ["a", "c"]);
+        assert_eq!(metrics.retained_evictions(), 1);
+        let stats = unmatched.retained_topics(now);
+        assert_eq!((stats[0].updates, stats[0].deliveries), (1, 1));
+
+        let (unmatched, _) = retaining(RetainedLimits {
+            eviction: RetainedEviction::OldestFirst,
+            ..limits
+        });
+        unmatched.keep(&publish("a", QoS::QoS0), now);
+        unmatched.keep(&publish("b", QoS::QoS0), now);
+        unmatched.matching("a", QoS::QoS0, false, now);
+        unmatched.keep(&publish("c", QoS::QoS0), now);
+        assert_eq!(kept(&unmatched), ["b", "c"]);
+        // an update makes the topic the newest
+        unmatched.keep(&publish("b", QoS::QoS0), now);
+        unmatched.keep(&publish("d", QoS::QoS0), now);
+        assert_eq!(kept(&unmatched), ["b", "d"]);
+        assert_eq!(unmatched.retained_topics(now)[0].updates, 2);
+
+        let (unmatched, metrics) = retaining(RetainedLimits {
+            max_entries: None,
+            max_bytes: Some(3),
+            eviction: RetainedEviction::OldestFirst,
+        });
+        unmatched.keep(&publish("a", QoS::QoS0), now);
+        unmatched.keep(&publish("bb", QoS::QoS0), now);
+        assert_eq!(metrics.retained_bytes(), 3);
+        unmatched.keep(&publish("cc", QoS::QoS0), now);
+        assert_eq!(kept(&unmatched), ["cc"]);
+        // too large to ever fit, the previous message of the topic is dropped as well
+        let large = Publish::new(Arc::from("cc"), Bytes::from_static(b"xx")).unwrap();
+        unmatched.keep(&large, now);
+        assert!(kept(&unmatched).is_empty());
+        assert_eq!(metrics.retained_evictions(), 3);
+        assert_eq!(metrics.retained_bytes(), 0);
+    }
+    #[test]
+    fn test_publish_retained() {
+        let metrics = Arc::new(Metrics::new());
+        // published with RETAIN, stored whatever the unmatched policy
+        let unmatched = UnmatchedPublishes::new(Vec::new(), Default::default(), metrics.clone());
+        let now = Instant::now();
+        let on = Publish::new(Arc::from("a"), Bytes::from_static(b"on")).unwrap();
+        unmatched.publish_retained(&on, now);
+        assert_eq!(
+            topics(&unmatched.matching("#", QoS::QoS0, false, now)),
+            [("a", true)]
+        );
+        assert_eq!(metrics.retained_bytes(), 3);
+        unmatched.publish_retained(&publish("a", QoS::QoS0), now);
+        assert!(unmatched.matching("#", QoS::QoS0, false, now).is_empty());
+        assert_eq!(metrics.retained_bytes(), 0);
+    }
+}
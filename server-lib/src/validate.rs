@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+/// Checks the payloads published on some topics before they are fanned out, a JSON Schema
+/// or a protobuf descriptor check for instance. Invalid publishes are refused with the
+/// PayloadFormatInvalid reason code so downstream consumers never see them.
+pub trait PayloadValidator: Send + Sync {
+    /// Why `payload`, published on `topic`, is invalid
+    fn validate(&self, topic: &str, payload: &[u8]) -> Result<(), String>;
+}
+
+/// A validator checking the publishes on the topics starting with `prefix`
+#[derive(Clone)]
+pub struct TopicValidator {
+    pub prefix: String,
+    pub validator: Arc<dyn PayloadValidator>,
+}
+
+/// Accepts UTF-8 payloads only
+pub struct Utf8Payloads;
+
+impl PayloadValidator for Utf8Payloads {
+    fn validate(&self, _: &str, payload: &[u8]) -> Result<(), String> {
+        std::str::from_utf8(payload)
+            .map(drop)
+            .map_err(|e| format!("payload is not UTF-8, {}", e))
+    }
+}
+
+/// Runs every validator whose prefix `topic` starts with, the first failure wins
+pub(crate) fn validate(
+    validators: &[TopicValidator],
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), String> {
+    validators
+        .iter()
+        .filter(|v| topic.starts_with(&v.prefix))
+        .try_for_each(|v| v.validator.validate(topic, payload))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    struct NotEmpty;
+    impl PayloadValidator for NotEmpty {
+        fn validate(&self, _: &str, payload: &[u8]) -> Result<(), String> {
+            match payload.is_empty() {
+                true => Err("empty payload".to_owned()),
+                false => Ok(()),
+            }
+        }
+    }
+    #[test]
+    fn test_validate_by_prefix() {
+        let validators = vec![
+            TopicValidator {
+                prefix: "sensors/".to_owned(),
+                validator: Arc::new(Utf8Payloads),
+            },
+            TopicValidator {
+                prefix: "sensors/temp/".to_owned(),
+                validator: Arc::new(NotEmpty),
+            },
+        ];
+        assert!(validate(&validators, "sensors/temp/1", b"21.5").is_ok());
+        assert!(validate(&validators, "sensors/hum/1", b"").is_ok());
+        assert_eq!(
+            validate(&validators, "sensors/temp/1", b""),
+            Err("empty payload".to_owned())
+        );
+        assert!(validate(&validators, "sensors/hum/1", b"\xff").is_err());
+        // topics outside of every prefix are not checked
+        assert!(validate(&validators, "raw/1", b"\xff").is_ok());
+    }
+}
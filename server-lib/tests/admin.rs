@@ -0,0 +1,81 @@
+#![cfg(feature = "admin")]
+mod common;
+
+use apiformes_server_lib::prelude::*;
+use common::{any_port, start, wait_until, TestClient, WITHIN};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// Sends a raw HTTP request, returns the status and the body of the response
+async fn http(addr: SocketAddr, request: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    timeout(WITHIN, stream.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head[9..12].parse().unwrap(), body.to_owned())
+}
+#[tokio::test]
+async fn test_admin_api() {
+    let (server, addr) = start(MqttServerConfig {
+        admin_socketaddr: Some(any_port()),
+        ..Default::default()
+    })
+    .await;
+    let admin = server.local_addr("admin").unwrap();
+    let mut client = TestClient::connect(addr, "sensor").await;
+    client.subscribe("rooms/+", QoS::QoS1).await;
+
+    let (status, body) = http(admin, "GET /clients HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, 200);
+    let sessions: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(sessions[0]["clientid"], "sensor");
+    assert_eq!(sessions[0]["listener"], "mqtt");
+    assert_eq!(sessions[0]["subscriptions"][0]["filter"], "rooms/+");
+    assert_eq!(server.client_sessions().await.len(), 1);
+
+    let (status, body) = http(admin, "GET /subscriptions?topic=rooms%2F1 HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        body,
+        r#"[{"clientid":"sensor","filter":"rooms/+","qos":1}]"#
+    );
+    let (status, body) = http(admin, "GET /subscriptions?topic=%23 HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, 400);
+    assert!(body.contains(r#""field":"topic""#));
+
+    let (status, _) = http(
+        admin,
+        "POST /publish?topic=rooms/1&qos=1 HTTP/1.1\r\nContent-Length: 2\r\n\r\non",
+    )
+    .await;
+    assert_eq!(status, 204);
+    let injected = client.publish().await;
+    assert_eq!(&injected.payload()[..], b"on");
+    assert_eq!(injected.qos(), QoS::QoS1);
+    let (status, _) = http(admin, "POST /publish?topic=rooms/1&qos=2 HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, 400);
+
+    let (status, _) = http(admin, "DELETE /clients/sensor HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, 204);
+    assert_eq!(
+        client.disconnect().await.reason_code(),
+        DisconnectReasonCode::AdministrativeAction
+    );
+    wait_until("the client retired", || async {
+        server.clients().await.is_empty()
+    })
+    .await;
+    let (status, _) = http(admin, "DELETE /clients/sensor HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, 404);
+    let (status, _) = http(admin, "PUT /clients HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, 405);
+    let (status, _) = http(admin, "GET /nowhere HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, 404);
+    server.shutdown().await;
+}
@@ -0,0 +1,214 @@
+//! A broker listening on a free port and a raw MQTT client to drive it, shared by the
+//! integration tests
+#![allow(dead_code)]
+use apiformes_server_lib::prelude::*;
+use bytes::{Buf, Bytes, BytesMut};
+use std::future::Future;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Duration};
+
+/// How long a client waits for a packet, and `wait_until` for its condition
+pub const WITHIN: Duration = Duration::from_secs(5);
+
+/// A loopback address the listener picks a free port on, see `MqttServer::local_addr`
+pub fn any_port() -> SocketAddr {
+    ([127, 0, 0, 1], 0).into()
+}
+
+/// Starts a broker configured with `cfg`, its MQTT listener on a free port. Returns the
+/// broker and the address of the listener.
+pub async fn start(cfg: MqttServerConfig) -> (MqttServer, SocketAddr) {
+    let server = MqttServer::new(MqttServerConfig {
+        mqtt_socketaddr: Some(any_port()),
+        ..cfg
+    })
+    .await
+    .unwrap();
+    let addr = server.local_addr("mqtt").unwrap();
+    (server, addr)
+}
+
+/// Polls `condition` until it holds, panics after `WITHIN`
+pub async fn wait_until<F, Fut>(what: &str, mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let polled = timeout(WITHIN, async {
+        while !condition().await {
+            sleep(Duration::from_millis(10)).await;
+        }
+    });
+    if polled.await.is_err() {
+        panic!("{} not reached within {:?}", what, WITHIN);
+    }
+}
+
+/// Relays the connections accepted on `listener` to `to`, for the addresses a broker must be
+/// configured with before the one they point at is started
+pub fn relay(listener: TcpListener, to: SocketAddr) {
+    tokio::spawn(async move {
+        while let Ok((mut inbound, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                if let Ok(mut outbound) = TcpStream::connect(to).await {
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                }
+            });
+        }
+    });
+}
+
+/// A CONNECT keeping the session for an hour after the connection closes
+pub fn persistent(clientid: &str) -> Connect {
+    let mut connect = Connect::new(Arc::from(clientid)).unwrap();
+    connect
+        .add_prop(
+            Property::SessionExpiryInterval,
+            MqttPropValue::new_u32(3600),
+        )
+        .unwrap();
+    connect
+}
+
+/// A SUBSCRIBE to each of `filters`
+pub fn subscribe(id: u16, filters: &[(&str, QoS)]) -> Packet {
+    let mut subscribe = Subscribe::new(id);
+    for (filter, qos) in filters {
+        subscribe
+            .add_topic(Arc::from(*filter), (*qos).into())
+            .unwrap();
+    }
+    subscribe.build()
+}
+
+/// A PUBLISH, `id` is ignored at QoS 0
+pub fn publish(topic: &str, payload: &[u8], qos: QoS, id: u16) -> Packet {
+    let mut publish = Publish::new(Arc::from(topic), Bytes::copy_from_slice(payload)).unwrap();
+    publish.set_qos(qos);
+    if qos != QoS::QoS0 {
+        publish.set_packet_identifier(id).unwrap();
+    }
+    publish.build()
+}
+
+/// A connection speaking MQTT 5 unless told otherwise, every wait panics after `WITHIN`
+pub struct TestClient {
+    stream: TcpStream,
+    buf: BytesMut,
+    version: ProtocolVersion,
+}
+
+impl TestClient {
+    /// Opens a connection without sending anything
+    pub async fn open(addr: SocketAddr) -> Self {
+        TestClient {
+            stream: TcpStream::connect(addr).await.unwrap(),
+            buf: BytesMut::new(),
+            version: ProtocolVersion::V5,
+        }
+    }
+    /// Opens a connection speaking `version`
+    pub async fn open_versioned(addr: SocketAddr, version: ProtocolVersion) -> Self {
+        TestClient {
+            version,
+            ..TestClient::open(addr).await
+        }
+    }
+    /// Connects `clientid` and waits for the broker to accept it
+    pub async fn connect(addr: SocketAddr, clientid: &str) -> Self {
+        let (client, connack) =
+            TestClient::connect_with(addr, Connect::new(Arc::from(clientid)).unwrap()).await;
+        assert_eq!(connack.reason_code(), ConnAckReasonCode::Success);
+        client
+    }
+    /// Sends `connect`, returns the connection and the CONNACK
+    pub async fn connect_with(addr: SocketAddr, connect: Connect) -> (Self, ConnAck) {
+        let mut client = TestClient::open(addr).await;
+        client.send([connect.build()]).await;
+        let connack = client.connack().await;
+        (client, connack)
+    }
+    /// Sends `packets` in a single write
+    pub async fn send(&mut self, packets: impl IntoIterator<Item = Packet>) {
+        let mut buf = BytesMut::new();
+        for packet in packets {
+            packet.to_bytes_versioned(&mut buf, self.version);
+        }
+        self.send_raw(&buf).await;
+    }
+    pub async fn send_raw(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).await.unwrap();
+    }
+    /// The next packet, `None` once the broker closed the connection
+    pub async fn try_recv(&mut self) -> Option<Packet> {
+        let read = timeout(WITHIN, async {
+            loop {
+                let mut cursor = Cursor::new(&self.buf[..]);
+                if let Ok(packet) = Packet::from_bytes_versioned(&mut cursor, self.version) {
+                    self.buf.advance(packet.frame_len_versioned(self.version));
+                    return Some(packet);
+                }
+                match self.stream.read_buf(&mut self.buf).await {
+                    Ok(0) | Err(_) => return None,
+                    Ok(_) => (),
+                }
+            }
+        });
+        match read.await {
+            Ok(packet) => packet,
+            Err(_) => panic!("nothing received within {:?}", WITHIN),
+        }
+    }
+    pub async fn recv(&mut self) -> Packet {
+        self.try_recv().await.expect("connection closed")
+    }
+    /// Checks that nothing is received for `during`
+    pub async fn recv_nothing(&mut self, during: Duration) {
+        if let Ok(packet) = timeout(during, self.try_recv()).await {
+            panic!("expected nothing, got {:?}", packet);
+        }
+    }
+    /// Waits for the broker to close the connection, skipping what it sends before
+    pub async fn closed(&mut self) {
+        while self.try_recv().await.is_some() {}
+    }
+    pub async fn connack(&mut self) -> ConnAck {
+        match self.recv().await {
+            Packet::ConnAck(connack) => connack,
+            other => panic!("expected a CONNACK, got {:?}", other),
+        }
+    }
+    pub async fn suback(&mut self) -> SubAck {
+        match self.recv().await {
+            Packet::SubAck(suback) => suback,
+            other => panic!("expected a SUBACK, got {:?}", other),
+        }
+    }
+    pub async fn puback(&mut self) -> PubAck {
+        match self.recv().await {
+            Packet::PubAck(puback) => puback,
+            other => panic!("expected a PUBACK, got {:?}", other),
+        }
+    }
+    pub async fn publish(&mut self) -> Publish {
+        match self.recv().await {
+            Packet::Publish(publish) => publish,
+            other => panic!("expected a PUBLISH, got {:?}", other),
+        }
+    }
+    pub async fn disconnect(&mut self) -> Disconnect {
+        match self.recv().await {
+            Packet::Disconnect(disconnect) => disconnect,
+            other => panic!("expected a DISCONNECT, got {:?}", other),
+        }
+    }
+    /// Subscribes to `filter` and waits for the SUBACK
+    pub async fn subscribe(&mut self, filter: &str, qos: QoS) -> SubAck {
+        self.send([subscribe(1, &[(filter, qos)])]).await;
+        self.suback().await
+    }
+}
@@ -0,0 +1,676 @@
+mod common;
+
+use apiformes_server_lib::prelude::*;
+use bytes::Bytes;
+use common::{any_port, publish, start, subscribe, wait_until, TestClient};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+#[tokio::test]
+async fn test_shutdown_with_live_clients() {
+    const CLIENTS: usize = 300;
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let mut connections = Vec::new();
+    for i in 0..CLIENTS {
+        let mut client = TestClient::open(addr).await;
+        let clientid = Arc::from(format!("client{}", i));
+        client.send([Connect::new(clientid).unwrap().build()]).await;
+        connections.push(client);
+    }
+    wait_until("every client connected", || async {
+        server.clients().await.len() == CLIENTS
+    })
+    .await;
+    // every listener, the manager, the dispatcher and all the client workers must stop
+    timeout(Duration::from_secs(5), server.shutdown())
+        .await
+        .unwrap();
+}
+/// Sends CONNECT, SUBSCRIBE and PUBLISH in a single write, without waiting for the
+/// CONNACK, and expects them to be answered in order
+async fn pipelining_client(addr: SocketAddr, clientid: &str) {
+    let mut client = TestClient::open(addr).await;
+    client
+        .send([
+            Connect::new(Arc::from(clientid)).unwrap().build(),
+            subscribe(1, &[("pipelined", QoS::QoS0)]),
+            publish("pipelined", b"hello", QoS::QoS0, 0),
+        ])
+        .await;
+    client.connack().await;
+    client.suback().await;
+    assert_eq!(&client.publish().await.payload()[..], b"hello");
+}
+#[tokio::test]
+async fn test_pipelined_packets_before_connack() {
+    let (server, addr) = start(MqttServerConfig {
+        // the first handshake uses the burst, the second waits for its CONNACK
+        connect_rate: Some(ConnectRate {
+            per_second: 4,
+            burst: 1,
+            max_wait_ms: 5000,
+        }),
+        ..Default::default()
+    })
+    .await;
+    pipelining_client(addr, "immediate").await;
+    pipelining_client(addr, "paced").await;
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_read_tuning() {
+    for (buffer_size, coalesce_frames) in [(0, true), (16, false), (4096, true)] {
+        let (server, addr) = start(MqttServerConfig {
+            mqtt_read: ReadTuning {
+                buffer_size,
+                coalesce_frames,
+            },
+            max_packet_size: 1024,
+            ..Default::default()
+        })
+        .await;
+        // the packets following the CONNECT are read along with it
+        pipelining_client(addr, "pipelining").await;
+        // reading ahead does not let a packet above max_packet_size through
+        let mut client = TestClient::connect(addr, "oversized").await;
+        client
+            .send([publish("big", &[0; 2048], QoS::QoS0, 0)])
+            .await;
+        assert_eq!(
+            client.disconnect().await.reason_code(),
+            DisconnectReasonCode::PacketTooLarge
+        );
+        server.shutdown().await;
+    }
+}
+/// Challenges the client with a nonce it must answer with `<nonce>:<secret>`
+struct SharedSecret;
+struct SharedSecretExchange {
+    challenged: bool,
+}
+impl AuthProvider for SharedSecret {
+    fn method(&self) -> &str {
+        "shared-secret"
+    }
+    fn start(&self, _: &str, _: Option<&str>) -> Box<dyn AuthExchange> {
+        Box::new(SharedSecretExchange { challenged: false })
+    }
+}
+impl AuthExchange for SharedSecretExchange {
+    fn step(&mut self, data: Option<&[u8]>) -> AuthStep {
+        if !self.challenged {
+            self.challenged = true;
+            return AuthStep::Continue(Bytes::from_static(b"nonce"));
+        }
+        match data {
+            Some(b"nonce:secret") => AuthStep::Success(Some(Bytes::from_static(b"ok"))),
+            _ => AuthStep::Failure,
+        }
+    }
+}
+/// Authenticates with `method` answering the challenge with `response`, returns the
+/// CONNACK
+async fn authenticate(addr: SocketAddr, method: &str, response: &'static [u8]) -> ConnAck {
+    let mut connect = Connect::new(Arc::from("auth")).unwrap();
+    connect
+        .add_prop(
+            Property::AuthenticationMethod,
+            MqttPropValue::new_string(Arc::from(method)).unwrap(),
+        )
+        .unwrap();
+    let mut client = TestClient::open(addr).await;
+    client.send([connect.build()]).await;
+    match client.recv().await {
+        Packet::Auth(auth) => {
+            let challenge = auth.get_prop(Property::AuthenticationData).unwrap();
+            assert_eq!(&challenge[0].into_data().unwrap()[..], b"nonce");
+        }
+        Packet::ConnAck(connack) => return connack,
+        other => panic!("expected an AUTH or a CONNACK, got {:?}", other),
+    }
+    let mut auth = Auth::new(AuthReasonCode::ContinueAuthentication);
+    auth.add_prop(
+        Property::AuthenticationMethod,
+        MqttPropValue::new_string(Arc::from(method)).unwrap(),
+    )
+    .unwrap();
+    auth.add_prop(
+        Property::AuthenticationData,
+        MqttPropValue::new_data(response).unwrap(),
+    )
+    .unwrap();
+    client.send([auth.build()]).await;
+    client.connack().await
+}
+#[tokio::test]
+async fn test_enhanced_authentication() {
+    let (server, addr) = start(MqttServerConfig {
+        auth_providers: vec![Arc::new(SharedSecret)],
+        ..Default::default()
+    })
+    .await;
+    let connack = authenticate(addr, "shared-secret", b"nonce:secret").await;
+    assert_eq!(connack.reason_code(), ConnAckReasonCode::Success);
+    let data = connack.get_prop(Property::AuthenticationData).unwrap();
+    assert_eq!(&data[0].into_data().unwrap()[..], b"ok");
+
+    let connack = authenticate(addr, "shared-secret", b"nonce:guess").await;
+    assert_eq!(connack.reason_code(), ConnAckReasonCode::NotAuthorized);
+    let connack = authenticate(addr, "kerberos", b"").await;
+    assert_eq!(
+        connack.reason_code(),
+        ConnAckReasonCode::BadAuthenicationMethod
+    );
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_problem_information() {
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let connect = Connect::new(Arc::from("$internal/me")).unwrap();
+    let (_, connack) = TestClient::connect_with(addr, connect).await;
+    let reason = connack.get_prop(Property::ReasonString).unwrap()[0].clone();
+    assert_eq!(
+        reason.into_str(),
+        Some("Client id $internal/me is reserved")
+    );
+    for (clientid, problem_info) in [("curious", true), ("quiet", false)] {
+        let mut connect = Connect::new(Arc::from(clientid)).unwrap();
+        connect
+            .add_prop(
+                Property::RequestProblemInformation,
+                MqttPropValue::new_bool(problem_info),
+            )
+            .unwrap();
+        let mut client = TestClient::open(addr).await;
+        client
+            .send([
+                connect.build(),
+                subscribe(1, &[("rooms/+", QoS::QoS1), ("$share//rooms", QoS::QoS1)]),
+            ])
+            .await;
+        client.connack().await;
+        let suback = client.suback().await;
+        assert_eq!(
+            suback.reason_codes()[1],
+            SubAckReasonCode::TopicFilterInvalid
+        );
+        if !problem_info {
+            // 3.1.2.11.7: only PUBLISH, CONNACK and DISCONNECT may explain anything
+            assert_eq!(suback.props_iter().count(), 0);
+            continue;
+        }
+        let reason = suback.get_prop(Property::ReasonString).unwrap()[0].clone();
+        assert_eq!(reason.into_str(), Some("1 of 2 topic filters refused"));
+        let refusals = suback.get_prop(Property::UserProperty).unwrap();
+        assert_eq!(refusals.len(), 1);
+        assert_eq!(&**refusals[0].into_str_pair().unwrap().0, "$share//rooms");
+    }
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_response_information() {
+    let (server, addr) = start(MqttServerConfig {
+        response_information: Some("replies/%c".to_owned()),
+        ..Default::default()
+    })
+    .await;
+    for (clientid, requested) in [("asking", true), ("silent", false)] {
+        let mut connect = Connect::new(Arc::from(clientid)).unwrap();
+        if requested {
+            connect
+                .add_prop(
+                    Property::RequestResponseInformation,
+                    MqttPropValue::new_bool(true),
+                )
+                .unwrap();
+        }
+        let (_, connack) = TestClient::connect_with(addr, connect).await;
+        let info = connack
+            .get_prop(Property::ResponseInformation)
+            .map(|v| v[0].into_str().unwrap().to_owned());
+        assert_eq!(info, requested.then(|| format!("replies/{}", clientid)));
+    }
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_listeners() {
+    let listener = |tag: &str| ListenerConfig {
+        tag: tag.to_owned(),
+        socketaddr: any_port(),
+        max_connections: None,
+        require_auth: false,
+        max_qos: None,
+        max_packet_size: None,
+        #[cfg(feature = "noise")]
+        channel_permeability: None,
+        read: None,
+        publish_quota: None,
+    };
+    let sensors = ListenerConfig {
+        max_connections: Some(1),
+        max_qos: Some(0),
+        max_packet_size: Some(1024),
+        ..listener("sensors")
+    };
+    let admins = ListenerConfig {
+        require_auth: true,
+        ..listener("admins")
+    };
+    let (server, addr) = start(MqttServerConfig {
+        listeners: vec![sensors, admins],
+        ..Default::default()
+    })
+    .await;
+    let sensors = server.local_addr("sensors").unwrap();
+    let admins = server.local_addr("admins").unwrap();
+    let mut clients = Vec::new();
+    for (addr, clientid) in [(addr, "plain"), (sensors, "sensor")] {
+        let connect = Connect::new(Arc::from(clientid)).unwrap();
+        let (client, connack) = TestClient::connect_with(addr, connect).await;
+        let prop = |key| connack.get_prop(key).map(|v| v[0].clone());
+        let packet_size = prop(Property::MaximumPacketSize).and_then(|v| v.into_u32());
+        if clientid == "sensor" {
+            assert_eq!(
+                prop(Property::MaximumQoS).and_then(|v| v.into_u8()),
+                Some(0)
+            );
+            assert_eq!(packet_size, Some(1024));
+        } else {
+            assert_eq!(packet_size, Some(64 * 1024));
+        }
+        clients.push(client);
+    }
+    let listeners: Vec<_> = server
+        .client_sessions()
+        .await
+        .into_iter()
+        .map(|session| (session.clientid, session.listener))
+        .collect();
+    assert_eq!(
+        listeners,
+        [
+            ("plain".to_owned(), "mqtt".to_owned()),
+            ("sensor".to_owned(), "sensors".to_owned())
+        ]
+    );
+    for (addr, clientid, refused) in [
+        (sensors, "second", ConnAckReasonCode::ServerBusy),
+        (admins, "anonymous", ConnAckReasonCode::NotAuthorized),
+    ] {
+        let connect = Connect::new(Arc::from(clientid)).unwrap();
+        let (_, connack) = TestClient::connect_with(addr, connect).await;
+        assert_eq!(connack.reason_code(), refused);
+    }
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_ping_keeps_client_alive() {
+    let (server, addr) = start(MqttServerConfig {
+        keep_alive: 1,
+        ..Default::default()
+    })
+    .await;
+    let mut client = TestClient::connect(addr, "pinger").await;
+    // well past the 1.5 seconds allowed without traffic, the pings are what is tested so
+    // they are paced rather than sent on some event
+    for _ in 0..6 {
+        sleep(Duration::from_millis(500)).await;
+        client.send([Ping::new().build_req()]).await;
+        assert!(matches!(client.recv().await, Packet::PingRes(_)));
+    }
+    assert_eq!(server.clients().await.len(), 1);
+    // a silent client is disconnected
+    assert_eq!(
+        client.disconnect().await.reason_code(),
+        DisconnectReasonCode::KeepAliveTimeout
+    );
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_keep_alive_expiry() {
+    let (server, addr) = start(MqttServerConfig {
+        keep_alive: 1,
+        ..Default::default()
+    })
+    .await;
+    let mut client = TestClient::connect(addr, "sleeper").await;
+    // 3.1.2.10: one and a half times the keep alive is allowed between two packets
+    sleep(Duration::from_millis(1200)).await;
+    client.send([Ping::new().build_req()]).await;
+    assert!(matches!(client.recv().await, Packet::PingRes(_)));
+    let pinged = Instant::now();
+    assert_eq!(
+        client.disconnect().await.reason_code(),
+        DisconnectReasonCode::KeepAliveTimeout
+    );
+    assert!(pinged.elapsed() >= Duration::from_millis(1500));
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_handshake_timeout() {
+    let (server, addr) = start(MqttServerConfig {
+        handshake_timeout: 1,
+        ..Default::default()
+    })
+    .await;
+    // closed long before the keep alive of 50 seconds, nothing is sent to a silent peer
+    let opened = Instant::now();
+    let mut silent = TestClient::open(addr).await;
+    assert!(silent.try_recv().await.is_none());
+    assert!(opened.elapsed() < Duration::from_secs(3));
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_zero_keep_alive() {
+    for policy in [
+        ZeroKeepAlive::Allow,
+        ZeroKeepAlive::Override,
+        ZeroKeepAlive::Reject,
+    ] {
+        let (server, addr) = start(MqttServerConfig {
+            keep_alive: 1,
+            zero_keep_alive: policy,
+            ..Default::default()
+        })
+        .await;
+        let mut connect = Connect::new(Arc::from("idle")).unwrap();
+        connect.set_keep_alive(0);
+        let (mut client, connack) = TestClient::connect_with(addr, connect).await;
+        let server_keep_alive = connack
+            .get_prop(Property::ServerKeepAlive)
+            .map(|v| v[0].into_u16());
+        match policy {
+            ZeroKeepAlive::Allow => {
+                assert_eq!(connack.reason_code(), ConnAckReasonCode::Success);
+                assert_eq!(server_keep_alive, None);
+                // never timed out
+                client.recv_nothing(Duration::from_secs(2)).await;
+                assert_eq!(server.clients().await.len(), 1);
+            }
+            ZeroKeepAlive::Override => {
+                assert_eq!(connack.reason_code(), ConnAckReasonCode::Success);
+                assert_eq!(server_keep_alive, Some(Some(1)));
+                assert_eq!(
+                    client.disconnect().await.reason_code(),
+                    DisconnectReasonCode::KeepAliveTimeout
+                );
+            }
+            ZeroKeepAlive::Reject => {
+                assert_eq!(
+                    connack.reason_code(),
+                    ConnAckReasonCode::ImplementationSpecificError
+                );
+                assert!(client.try_recv().await.is_none());
+            }
+        }
+        server.shutdown().await;
+    }
+}
+#[tokio::test]
+async fn test_max_connect_time() {
+    let (server, addr) = start(MqttServerConfig {
+        max_connect_time: Some(1),
+        ..Default::default()
+    })
+    .await;
+    let connected = Instant::now();
+    let mut client = TestClient::connect(addr, "bounded").await;
+    // an active client is disconnected all the same
+    let disconnect = loop {
+        client.send([Ping::new().build_req()]).await;
+        match client.recv().await {
+            Packet::PingRes(_) => sleep(Duration::from_millis(200)).await,
+            Packet::Disconnect(d) => break d,
+            packet => panic!("unexpected {:?}", packet),
+        }
+    };
+    assert_eq!(
+        disconnect.reason_code(),
+        DisconnectReasonCode::MaximumConnectTime
+    );
+    assert!(connected.elapsed() >= Duration::from_secs(1));
+    wait_until("the client retired", || async {
+        server.clients().await.is_empty()
+    })
+    .await;
+    assert!(matches!(
+        server.last_disconnect("bounded").unwrap().reason,
+        DisconnectReason::MaximumConnectTime
+    ));
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_announced_packet_too_large() {
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let connect = Connect::new(Arc::from("bogus-length")).unwrap();
+    let (mut client, connack) = TestClient::connect_with(addr, connect).await;
+    match connack.get_prop(Property::MaximumPacketSize) {
+        Some([v]) => assert_eq!(v.into_u32(), Some(64 * 1024)),
+        _ => panic!("expected the maximum packet size"),
+    }
+    // a PUBLISH fixed header announcing 256MB, the body is never sent
+    client.send_raw(&[0x30, 0xff, 0xff, 0xff, 0x7f]).await;
+    assert_eq!(
+        client.disconnect().await.reason_code(),
+        DisconnectReasonCode::PacketTooLarge
+    );
+    assert!(client.try_recv().await.is_none());
+    wait_until("the client retired", || async {
+        server.clients().await.is_empty()
+    })
+    .await;
+    assert!(matches!(
+        server.last_disconnect("bogus-length").unwrap().reason,
+        DisconnectReason::PacketTooLarge
+    ));
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_protocol_violation() {
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let mut client = TestClient::connect(addr, "a").await;
+    // an empty topic name without a topic alias
+    client
+        .send([Publish::new(Arc::from(""), Bytes::new()).unwrap().build()])
+        .await;
+    assert_eq!(
+        client.disconnect().await.reason_code(),
+        DisconnectReasonCode::ProtocolError
+    );
+    assert!(client.try_recv().await.is_none());
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_sys_topics() {
+    let (server, _) = start(MqttServerConfig {
+        node_id: Some("node-a".to_owned()),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        server.sys_topic("$SYS/broker/node_id").await.unwrap(),
+        "node-a"
+    );
+    let version = server.sys_topic("$SYS/broker/version").await.unwrap();
+    assert!(version.starts_with(env!("CARGO_PKG_VERSION").as_bytes()));
+    assert!(server.sys_topic("$SYS/broker/none").await.is_none());
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_strict_parsing() {
+    // a QoS 1 PUBLISH with a remaining length of 7 encoded as 0x87 0x00
+    let padded = [0x32, 0x87, 0x00, 0x00, 0x01, b'a', 0x00, 0x01, 0x00, b'x'];
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let mut client = TestClient::connect(addr, "lenient").await;
+    client.send_raw(&padded).await;
+    assert_eq!(client.puback().await.identifier(), 1);
+    server.shutdown().await;
+
+    let (server, addr) = start(MqttServerConfig {
+        strict_parsing: true,
+        ..Default::default()
+    })
+    .await;
+    let mut client = TestClient::connect(addr, "strict").await;
+    client.send_raw(&padded).await;
+    client.closed().await;
+    wait_until("the client retired", || async {
+        server.clients().await.is_empty()
+    })
+    .await;
+    assert!(matches!(
+        server.last_disconnect("strict").unwrap().reason,
+        DisconnectReason::MalformedPacket(_)
+    ));
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_mqtt311_client() {
+    const V311: ProtocolVersion = ProtocolVersion::V311;
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let mut v311 = TestClient::open_versioned(addr, V311).await;
+    let mut connect = Connect::new(Arc::from("v311")).unwrap();
+    connect.set_clean_start();
+    connect.set_keep_alive(30);
+    v311.send([connect.build(), subscribe(1, &[("compat", QoS::QoS1)])])
+        .await;
+    assert_eq!(
+        v311.connack().await.reason_code(),
+        ConnAckReasonCode::Success
+    );
+    assert!(matches!(
+        v311.suback().await.reason_codes(),
+        [SubAckReasonCode::GrantedQoS1]
+    ));
+
+    // an MQTT 5 publish reaches the 3.1.1 subscriber without its properties
+    let mut v5 = TestClient::connect(addr, "v5").await;
+    let mut publish = Publish::new(Arc::from("compat"), Bytes::from_static(b"hello")).unwrap();
+    publish.set_qos(QoS::QoS1);
+    publish.set_packet_identifier(1).unwrap();
+    publish
+        .add_prop(
+            Property::ContentType,
+            MqttPropValue::new_string(Arc::from("text/plain")).unwrap(),
+        )
+        .unwrap();
+    v5.send([publish.build()]).await;
+    v5.puback().await;
+    let received = v311.publish().await;
+    assert_eq!(&received.payload()[..], b"hello");
+    assert_eq!(received.qos(), QoS::QoS1);
+    let id = received.packet_identifier().unwrap();
+    v311.send([PubAck::new(id).build(), Ping::new().build_req()])
+        .await;
+    assert!(matches!(v311.recv().await, Packet::PingRes(_)));
+
+    // 3.1.3-8: no session to resume without a client id
+    let mut anonymous = TestClient::open_versioned(addr, V311).await;
+    anonymous
+        .send([Connect::new(Arc::from("")).unwrap().build()])
+        .await;
+    assert_eq!(
+        anonymous.connack().await.reason_code(),
+        ConnAckReasonCode::ClientIdentifierNotValid
+    );
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_empty_clientid() {
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    for clean_start in [false, true] {
+        let mut connect = Connect::new(Arc::from("")).unwrap();
+        if clean_start {
+            connect.set_clean_start();
+        }
+        let (_, connack) = TestClient::connect_with(addr, connect).await;
+        if clean_start {
+            assert_eq!(connack.reason_code(), ConnAckReasonCode::Success);
+            assert!(connack
+                .get_prop(Property::AssignedClientIdentifier)
+                .is_some());
+        } else {
+            assert_eq!(
+                connack.reason_code(),
+                ConnAckReasonCode::ClientIdentifierNotValid
+            );
+        }
+    }
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_shutdown_gracefully() {
+    let (server, addr) = start(MqttServerConfig {
+        drain_timeout: 30,
+        ..Default::default()
+    })
+    .await;
+    let mut subscriber = TestClient::connect(addr, "subscriber").await;
+    subscriber.subscribe("drained", QoS::QoS1).await;
+    server
+        .publish("drained", Bytes::from_static(b"last"), QoS::QoS1, false, [])
+        .await
+        .unwrap();
+    let id = subscriber.publish().await.packet_identifier().unwrap();
+    let shutdown = tokio::spawn(server.shutdown_gracefully());
+    // no new connection while draining
+    wait_until("the listener closed", || async {
+        TcpStream::connect(addr).await.is_err()
+    })
+    .await;
+    // the unacknowledged publish holds the connection open
+    subscriber.recv_nothing(Duration::from_millis(100)).await;
+    subscriber.send([PubAck::new(id).build()]).await;
+    assert_eq!(
+        subscriber.disconnect().await.reason_code(),
+        DisconnectReasonCode::ServerShuttingDown
+    );
+    // well before the drain timeout
+    timeout(Duration::from_secs(5), shutdown)
+        .await
+        .unwrap()
+        .unwrap();
+}
+#[tokio::test]
+async fn test_unwritable_subscriber() {
+    let reason = flood_stalled_subscriber(MqttServerConfig {
+        backlog_write_timeout: Some(1),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(reason, DisconnectReason::Unwritable);
+}
+#[tokio::test]
+async fn test_outgoing_queue_overflow() {
+    let reason = flood_stalled_subscriber(MqttServerConfig {
+        backlog_write_timeout: None,
+        max_outgoing_packets: Some(16),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(reason, DisconnectReason::QueueOverflow);
+}
+/// Why a subscriber that stopped reading was disconnected while publishes flood it
+async fn flood_stalled_subscriber(cfg: MqttServerConfig) -> DisconnectReason {
+    let (server, addr) = start(cfg).await;
+    // subscribes and never reads again
+    let mut subscriber = TestClient::connect(addr, "stalled").await;
+    subscriber.subscribe("flood", QoS::QoS0).await;
+
+    let mut publisher = TestClient::connect(addr, "flooding").await;
+    // far more than the socket buffers of the subscriber hold
+    for _ in 0..400 {
+        publisher
+            .send([publish("flood", &[0; 60 * 1024], QoS::QoS0, 0)])
+            .await;
+    }
+    wait_until("the subscriber disconnected", || async {
+        server.last_disconnect("stalled").is_some()
+    })
+    .await;
+    let reason = server.last_disconnect("stalled").unwrap().reason;
+    server.shutdown().await;
+    reason
+}
@@ -0,0 +1,691 @@
+mod common;
+
+use apiformes_server_lib::prelude::*;
+use bytes::Bytes;
+use common::{publish, start, subscribe, wait_until, TestClient};
+use futures::StreamExt;
+use std::sync::{Arc, Mutex};
+use tokio::time::{timeout, Duration};
+
+/// The reason code of each PUBACK, by packet identifier
+async fn pubacks(client: &mut TestClient, count: usize) -> Vec<(u16, PubAckReasonCode)> {
+    let mut reason_codes = Vec::new();
+    for _ in 0..count {
+        let ack = client.puback().await;
+        reason_codes.push((ack.identifier(), ack.reason_code()));
+    }
+    reason_codes.sort_by_key(|(id, _)| *id);
+    reason_codes
+}
+#[tokio::test]
+async fn test_qos1_delivery() {
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let mut subscriber = TestClient::connect(addr, "a").await;
+    subscriber.subscribe("x/#", QoS::QoS1).await;
+    let mut publisher = TestClient::connect(addr, "b").await;
+    publisher
+        .send([publish("x/y", b"hello", QoS::QoS1, 1)])
+        .await;
+    let received = timeout(Duration::from_millis(100), subscriber.publish())
+        .await
+        .unwrap();
+    assert_eq!(&**received.topic_name(), "x/y");
+    assert_eq!(&received.payload()[..], b"hello");
+    subscriber
+        .send([PubAck::new(received.packet_identifier().unwrap()).build()])
+        .await;
+    assert_eq!(
+        pubacks(&mut publisher, 1).await,
+        [(1, PubAckReasonCode::Success)]
+    );
+    publisher.send([publish("z", b"lost", QoS::QoS1, 2)]).await;
+    assert_eq!(
+        pubacks(&mut publisher, 1).await,
+        [(2, PubAckReasonCode::NoMatchingSubscribers)]
+    );
+    subscriber.recv_nothing(Duration::from_millis(100)).await;
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_no_local() {
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let mut a = TestClient::connect(addr, "a").await;
+    let mut subscribe = Subscribe::new(1);
+    subscribe
+        .add_topic(
+            Arc::from("chat"),
+            SubscriptionOptions::from(QoS::QoS0) | SubscriptionOptions::NO_LOCAL,
+        )
+        .unwrap();
+    a.send([subscribe.build()]).await;
+    assert!(matches!(
+        a.suback().await.reason_codes(),
+        [SubAckReasonCode::GrantedQoS0]
+    ));
+    let mut b = TestClient::connect(addr, "b").await;
+    b.subscribe("chat", QoS::QoS0).await;
+    a.send([publish("chat", b"hi", QoS::QoS0, 0)]).await;
+    assert_eq!(&b.publish().await.payload()[..], b"hi");
+    a.recv_nothing(Duration::from_millis(100)).await;
+    b.send([Disconnect::new(DisconnectReasonCode::NormalDisconnection).build()])
+        .await;
+    a.send([publish("chat", b"anyone?", QoS::QoS0, 0)]).await;
+    a.recv_nothing(Duration::from_millis(100)).await;
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_qos_above_maximum() {
+    let (server, addr) = start(MqttServerConfig {
+        max_qos: 0,
+        ..Default::default()
+    })
+    .await;
+    let mut client = TestClient::connect(addr, "a").await;
+    client.send([publish("x", b"", QoS::QoS1, 1)]).await;
+    assert_eq!(
+        client.disconnect().await.reason_code(),
+        DisconnectReasonCode::QoSNotSupported
+    );
+    assert!(client.try_recv().await.is_none());
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_payload_validation() {
+    let (server, addr) = start(MqttServerConfig {
+        payload_validators: vec![TopicValidator {
+            prefix: "text/".to_owned(),
+            validator: Arc::new(Utf8Payloads),
+        }],
+        ..Default::default()
+    })
+    .await;
+    let mut client = TestClient::connect(addr, "publisher").await;
+    client
+        .send([
+            publish("text/greeting", b"hello", QoS::QoS1, 1),
+            publish("text/greeting", b"\xff\xfe", QoS::QoS1, 2),
+        ])
+        .await;
+    assert_eq!(
+        pubacks(&mut client, 2).await,
+        [
+            (1, PubAckReasonCode::NoMatchingSubscribers),
+            (2, PubAckReasonCode::PayloadFormatInvalid)
+        ]
+    );
+    assert_eq!(server.metrics().payloads_rejected(), 1);
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_publish_quota() {
+    let mut cfg = MqttServerConfig::default();
+    cfg.publish_quotas.mqtt = Some(PublishQuota {
+        messages_per_second: Some(1),
+        bytes_per_second: None,
+        burst_seconds: 3,
+    });
+    let (server, addr) = start(cfg).await;
+    let mut client = TestClient::connect(addr, "flooder").await;
+    client
+        .send((0..4).map(|_| publish("flood", b"x", QoS::QoS0, 0)))
+        .await;
+    assert_eq!(
+        client.disconnect().await.reason_code(),
+        DisconnectReasonCode::MessageRateTooHigh
+    );
+    assert_eq!(server.metrics().publishes_over_quota(), 1);
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_unsubscribe() {
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let mut client = TestClient::connect(addr, "leaving").await;
+    client.subscribe("rooms/+", QoS::QoS1).await;
+    for id in 2..4 {
+        let mut unsub = Unsubscribe::new(id);
+        for topic in ["rooms/+", "$share//rooms"] {
+            unsub.add_topic(Arc::from(topic)).unwrap();
+        }
+        client.send([unsub.build()]).await;
+        let unsuback = match client.recv().await {
+            Packet::UnsubAck(unsuback) => unsuback,
+            other => panic!("expected an UNSUBACK, got {:?}", other),
+        };
+        assert_eq!(unsuback.identifier(), id);
+        // the second time there is no subscription left
+        let expected = match id {
+            2 => UnsubAckReasonCode::Success,
+            _ => UnsubAckReasonCode::NoSubscriptionExisted,
+        };
+        let codes: Vec<_> = unsuback.reason_codes().iter().map(|c| *c as u8).collect();
+        assert_eq!(
+            codes,
+            [expected as u8, UnsubAckReasonCode::TopicFilterInvalid as u8]
+        );
+    }
+    assert_eq!(server.metrics().subscriptions(), 0);
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_acl() {
+    let acl: AclFile = "allow client * subscribe public/#\nallow client * publish devices/%c/#"
+        .parse()
+        .unwrap();
+    let (server, addr) = start(MqttServerConfig {
+        authorizer: Some(Arc::new(acl)),
+        ..Default::default()
+    })
+    .await;
+    let mut client = TestClient::connect(addr, "dev1").await;
+    client
+        .send([
+            subscribe(1, &[("public/#", QoS::QoS0), ("private/#", QoS::QoS0)]),
+            publish("devices/dev1/temp", b"20", QoS::QoS1, 2),
+            publish("devices/dev2/temp", b"20", QoS::QoS1, 3),
+        ])
+        .await;
+    assert!(matches!(
+        client.suback().await.reason_codes(),
+        [
+            SubAckReasonCode::GrantedQoS0,
+            SubAckReasonCode::NotAuthorized
+        ]
+    ));
+    assert_eq!(
+        pubacks(&mut client, 2).await,
+        [
+            (2, PubAckReasonCode::NoMatchingSubscribers),
+            (3, PubAckReasonCode::NotAuthorized)
+        ]
+    );
+    assert_eq!(server.metrics().acl_denied(), 2);
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_interceptors() {
+    #[derive(Default)]
+    struct Audit(Mutex<Vec<String>>);
+    impl Interceptor for Audit {
+        fn on_connect(&self, client: &Client) {
+            let event = format!("connect {}", client.clientid());
+            self.0.lock().unwrap().push(event);
+        }
+        fn on_publish(&self, _: &Client, mut publish: Publish) -> Option<Publish> {
+            if publish.topic_name().starts_with("drop/") {
+                return None;
+            }
+            let shouted = publish.payload().to_ascii_uppercase();
+            publish.set_payload_bytes(Bytes::from(shouted));
+            Some(publish)
+        }
+        fn on_subscribe(&self, subscriber: &Client, filter: &str, _: QoS) {
+            let event = format!("subscribe {} {}", subscriber.clientid(), filter);
+            self.0.lock().unwrap().push(event);
+        }
+        fn on_deliver(&self, target: &Client, _: &Publish) -> bool {
+            &**target.clientid() != "muted"
+        }
+    }
+    let audit = Arc::new(Audit::default());
+    let (server, addr) = start(MqttServerConfig {
+        interceptors: vec![audit.clone()],
+        ..Default::default()
+    })
+    .await;
+    let mut subscribers = Vec::new();
+    for clientid in ["reader", "muted"] {
+        let mut subscriber = TestClient::connect(addr, clientid).await;
+        subscriber.subscribe("news/#", QoS::QoS0).await;
+        subscribers.push(subscriber);
+    }
+    let mut speaker = TestClient::connect(addr, "speaker").await;
+    speaker
+        .send([
+            publish("drop/a", b"hi", QoS::QoS1, 1),
+            publish("news/a", b"hi", QoS::QoS1, 2),
+        ])
+        .await;
+    for id in [1, 2] {
+        assert_eq!(speaker.puback().await.identifier(), id);
+    }
+    let received = subscribers[0].publish().await;
+    assert_eq!(&**received.topic_name(), "news/a");
+    assert_eq!(&received.payload()[..], b"HI");
+    let deliveries = server
+        .dry_run_publish("speaker", "news/a", Bytes::new(), QoS::QoS0)
+        .await
+        .unwrap();
+    assert!(deliveries
+        .iter()
+        .any(|(id, d)| &**id == "muted" && *d == Delivery::Intercepted));
+    let events = audit.0.lock().unwrap().clone();
+    for event in [
+        "connect reader",
+        "subscribe muted news/#",
+        "connect speaker",
+    ] {
+        assert!(events.iter().any(|e| e == event), "missing {}", event);
+    }
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_intercepted_publish_checked() {
+    // moves the publishes of `leak/` under `secret/` and the ones of `raw/` under `text/`
+    struct Rename;
+    impl Interceptor for Rename {
+        fn on_publish(&self, _: &Client, publish: Publish) -> Option<Publish> {
+            let topic = publish.topic_name();
+            let renamed = match topic.split_once('/') {
+                Some(("leak", rest)) => format!("secret/{}", rest),
+                Some(("raw", rest)) => format!("text/{}", rest),
+                _ => return Some(publish),
+            };
+            let mut renamed = Publish::new(Arc::from(renamed), publish.payload()).unwrap();
+            renamed.set_qos(publish.qos());
+            Some(renamed)
+        }
+    }
+    let acl: AclFile = "allow client * publish leak/#\nallow client * publish raw/#\n\
+                        allow client * publish text/#"
+        .parse()
+        .unwrap();
+    let (server, addr) = start(MqttServerConfig {
+        authorizer: Some(Arc::new(acl)),
+        interceptors: vec![Arc::new(Rename)],
+        payload_validators: vec![TopicValidator {
+            prefix: "text/".to_owned(),
+            validator: Arc::new(Utf8Payloads),
+        }],
+        ..Default::default()
+    })
+    .await;
+    let mut client = TestClient::connect(addr, "renamed").await;
+    client
+        .send([
+            publish("leak/a", b"x", QoS::QoS1, 1),
+            publish("raw/a", b"\xff\xfe", QoS::QoS1, 2),
+        ])
+        .await;
+    assert_eq!(
+        pubacks(&mut client, 2).await,
+        [
+            (1, PubAckReasonCode::NotAuthorized),
+            (2, PubAckReasonCode::PayloadFormatInvalid)
+        ]
+    );
+    assert_eq!(server.metrics().acl_denied(), 1);
+    assert_eq!(server.metrics().payloads_rejected(), 1);
+    server.shutdown().await;
+}
+// the interceptor blocks a dispatcher thread while the publish is held
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_duplicate_publish() {
+    /// Holds every publish until the test lets it through, its PUBACK waits meanwhile
+    struct Hold(Mutex<std::sync::mpsc::Receiver<()>>);
+    impl Interceptor for Hold {
+        fn on_publish(&self, _: &Client, publish: Publish) -> Option<Publish> {
+            let released = self.0.lock().unwrap().recv_timeout(Duration::from_secs(5));
+            released.expect("publish held too long");
+            Some(publish)
+        }
+    }
+    let (release, held) = std::sync::mpsc::channel();
+    let (server, addr) = start(MqttServerConfig {
+        interceptors: vec![Arc::new(Hold(Mutex::new(held)))],
+        ..Default::default()
+    })
+    .await;
+    let mut subscriber = TestClient::connect(addr, "subscriber").await;
+    subscriber.subscribe("dup/#", QoS::QoS0).await;
+    let mut publisher = TestClient::connect(addr, "publisher").await;
+    let mut duplicate = Publish::new(Arc::from("dup/a"), Bytes::from_static(b"x")).unwrap();
+    duplicate.set_qos(QoS::QoS1);
+    duplicate.set_packet_identifier(7).unwrap();
+    duplicate.set_dup();
+    // 4.3.2: the retransmission of a publish not acknowledged yet is discarded, a new
+    // publish with its identifier is refused
+    publisher
+        .send([
+            publish("dup/a", b"x", QoS::QoS1, 7),
+            duplicate.clone().build(),
+            publish("dup/a", b"y", QoS::QoS1, 7),
+        ])
+        .await;
+    let refused = publisher.puback().await;
+    assert_eq!(refused.identifier(), 7);
+    assert_eq!(
+        refused.reason_code(),
+        PubAckReasonCode::PacketIdentifierInUse
+    );
+    release.send(()).unwrap();
+    let acked = publisher.puback().await;
+    assert_eq!(acked.identifier(), 7);
+    assert_eq!(acked.reason_code(), PubAckReasonCode::Success);
+    assert_eq!(&subscriber.publish().await.payload()[..], b"x");
+    subscriber.recv_nothing(Duration::from_millis(200)).await;
+    publisher.recv_nothing(Duration::from_millis(100)).await;
+    // once acknowledged, a retransmission is a new delivery
+    release.send(()).unwrap();
+    publisher.send([duplicate.build()]).await;
+    let acked = publisher.puback().await;
+    assert_eq!(acked.identifier(), 7);
+    assert_eq!(acked.reason_code(), PubAckReasonCode::Success);
+    assert_eq!(&subscriber.publish().await.payload()[..], b"x");
+    subscriber.recv_nothing(Duration::from_millis(200)).await;
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_local_client() {
+    let server = MqttServer::builder()
+        .mqtt(common::any_port())
+        .build()
+        .await
+        .unwrap();
+    let addr = server.local_addr("mqtt").unwrap();
+    let mut shadow = server.local_client().await.unwrap();
+    assert!(shadow.clientid().starts_with("$internal/"));
+    shadow.subscribe("reported/#", QoS::QoS0).await.unwrap();
+    let mut device = TestClient::connect(addr, "dev1").await;
+    device
+        .send([
+            subscribe(1, &[("desired/dev1", QoS::QoS0)]),
+            publish("reported/dev1", b"on", QoS::QoS0, 0),
+        ])
+        .await;
+    device.suback().await;
+    let reported = timeout(common::WITHIN, shadow.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&*reported.topic, "reported/dev1");
+    assert_eq!(&reported.payload[..], b"on");
+    shadow
+        .publish(
+            "desired/dev1",
+            Bytes::from_static(b"off"),
+            QoS::QoS0,
+            false,
+            [],
+        )
+        .await
+        .unwrap();
+    assert_eq!(&device.publish().await.payload()[..], b"off");
+    assert!(shadow.unsubscribe("reported/#").await);
+    assert!(!shadow.unsubscribe("reported/#").await);
+    let clientid = shadow.clientid().clone();
+    drop(shadow);
+    wait_until("the local client retired", || async {
+        !server.all_clients().await.contains(&clientid)
+    })
+    .await;
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_topic_alias() {
+    let (server, addr) = start(MqttServerConfig::default()).await;
+
+    // server -> client: a subscriber accepting aliases never receives one
+    let mut connect = Connect::new(Arc::from("aliased")).unwrap();
+    connect
+        .add_prop(Property::TopicAliasMaximum, MqttPropValue::new_u16(10))
+        .unwrap();
+    let (mut subscriber, connack) = TestClient::connect_with(addr, connect).await;
+    match connack.get_prop(Property::TopicAliasMaximum) {
+        Some([v]) => assert_eq!(v.into_u16(), Some(0)),
+        _ => panic!("expected the topic alias maximum"),
+    }
+    subscriber.subscribe("alias/#", QoS::QoS0).await;
+    let mut publisher = TestClient::connect(addr, "publisher").await;
+    publisher
+        .send([publish("alias/a", b"hello", QoS::QoS0, 0)])
+        .await;
+    let received = subscriber.publish().await;
+    assert_eq!(&**received.topic_name(), "alias/a");
+    assert!(received.get_prop(Property::TopicAlias).is_none());
+    // nor the alias of an internal publish
+    server
+        .publish(
+            "alias/b",
+            Bytes::from_static(b"aliased"),
+            QoS::QoS0,
+            false,
+            [(Property::TopicAlias, MqttPropValue::new_u16(1))],
+        )
+        .await
+        .unwrap();
+    let received = subscriber.publish().await;
+    assert_eq!(&**received.topic_name(), "alias/b");
+    assert!(received.get_prop(Property::TopicAlias).is_none());
+
+    // client -> server: an alias above the advertised maximum of 0, then an alias used
+    // without being defined
+    for (clientid, topic) in [("aliasing", "alias/b"), ("undefined", "")] {
+        let mut publisher = TestClient::connect(addr, clientid).await;
+        let mut publish = Publish::new(Arc::from(topic), Bytes::new()).unwrap();
+        publish
+            .add_prop(Property::TopicAlias, MqttPropValue::new_u16(1))
+            .unwrap();
+        publisher.send([publish.build()]).await;
+        assert_eq!(
+            publisher.disconnect().await.reason_code(),
+            DisconnectReasonCode::TopicAliasInvalid
+        );
+    }
+    // neither publish was delivered
+    subscriber.recv_nothing(Duration::from_millis(200)).await;
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_dispatcher_workers() {
+    const PUBLISHERS: usize = 8;
+    const MESSAGES: u8 = 50;
+    for sharding in [DispatcherSharding::ClientId, DispatcherSharding::Topic] {
+        let (server, addr) = start(MqttServerConfig {
+            dispatcher_workers: 4,
+            dispatcher_sharding: sharding,
+            ..Default::default()
+        })
+        .await;
+        let mut subscriber = TestClient::connect(addr, "subscriber").await;
+        subscriber.subscribe("sharded/+", QoS::QoS0).await;
+        let mut publishers = Vec::new();
+        for i in 0..PUBLISHERS {
+            publishers.push(TestClient::connect(addr, &format!("publisher{}", i)).await);
+        }
+        for n in 0..MESSAGES {
+            for (i, publisher) in publishers.iter_mut().enumerate() {
+                let topic = format!("sharded/{}", i);
+                publisher.send([publish(&topic, &[n], QoS::QoS0, 0)]).await;
+            }
+        }
+        // every publish arrives, in order for each publisher
+        let mut next = [0; PUBLISHERS];
+        for _ in 0..PUBLISHERS * MESSAGES as usize {
+            let received = subscriber.publish().await;
+            let i: usize = received.topic_name()["sharded/".len()..].parse().unwrap();
+            assert_eq!(received.payload()[..], [next[i]]);
+            next[i] += 1;
+        }
+        assert_eq!(next, [MESSAGES; PUBLISHERS]);
+        // 4.6: the PUBACKs follow the order of the publishes whatever their topic
+        let publisher = &mut publishers[0];
+        publisher
+            .send((1..=MESSAGES as u16).map(|id| {
+                let topic = format!("sharded/{}", id);
+                publish(&topic, b"x", QoS::QoS1, id)
+            }))
+            .await;
+        for id in 1..=MESSAGES as u16 {
+            assert_eq!(publisher.puback().await.identifier(), id);
+        }
+        server.shutdown().await;
+    }
+}
+#[tokio::test]
+async fn test_trace_next_publish() {
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let server = Arc::new(server);
+    assert!(server
+        .trace_next_publish("traced/#", Duration::from_secs(1))
+        .await
+        .is_err());
+    let mut subscriber = TestClient::connect(addr, "subscriber").await;
+    subscriber.subscribe("traced", QoS::QoS0).await;
+    let mut publisher = TestClient::connect(addr, "publisher").await;
+
+    let tracing = server.clone();
+    let mut trace = tokio::spawn(async move {
+        tracing
+            .trace_next_publish("traced", Duration::from_secs(5))
+            .await
+    });
+    // the publishes sent before the trace is armed are not traced, publish until one is
+    let trace = loop {
+        publisher
+            .send([publish("traced", b"hello", QoS::QoS0, 0)])
+            .await;
+        subscriber.publish().await;
+        if let Ok(trace) = timeout(Duration::from_millis(50), &mut trace).await {
+            break trace.unwrap().unwrap();
+        }
+    };
+    assert!(trace.complete);
+    assert_eq!(trace.publisher, "publisher");
+    let stages: Vec<_> = trace.events.iter().map(|e| e.stage).collect();
+    assert_eq!(
+        stages,
+        [
+            TraceStage::Received,
+            TraceStage::Decoded,
+            TraceStage::Enqueued,
+            TraceStage::Dequeued,
+            TraceStage::Matched,
+            TraceStage::Sent,
+            TraceStage::Flushed,
+        ]
+    );
+    assert_eq!(trace.events[6].clientid.as_deref(), Some("subscriber"));
+
+    // nothing published, nothing traced
+    let err = server
+        .trace_next_publish("traced", Duration::from_millis(100))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, ApiErrorCode::Unavailable);
+    Arc::try_unwrap(server).ok().unwrap().shutdown().await;
+}
+#[tokio::test]
+async fn test_will_message() {
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let mut subscriber = TestClient::connect(addr, "subscriber").await;
+    subscriber.subscribe("will", QoS::QoS0).await;
+    let with_will = |clientid: &str, payload: &'static [u8]| {
+        let mut connect = Connect::new(Arc::from(clientid)).unwrap();
+        connect.set_will(Will::new(Arc::from("will"), Bytes::from_static(payload)).unwrap());
+        connect
+    };
+
+    // a normal disconnection discards the will
+    let (mut polite, _) = TestClient::connect_with(addr, with_will("polite", b"polite")).await;
+    polite
+        .send([Disconnect::new(DisconnectReasonCode::NormalDisconnection).build()])
+        .await;
+    polite.closed().await;
+    wait_until("the polite client retired", || async {
+        server.last_disconnect("polite").is_some()
+    })
+    .await;
+
+    // closing the connection without a DISCONNECT publishes it
+    drop(TestClient::connect_with(addr, with_will("abrupt", b"abrupt")).await);
+    assert_eq!(&subscriber.publish().await.payload()[..], b"abrupt");
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_unmatched_publish_queued() {
+    let (server, addr) = start(MqttServerConfig {
+        unmatched_publishes: vec![UnmatchedTopics {
+            prefix: "fleet/".to_owned(),
+            policy: UnmatchedPolicy::Queue { grace: 60 },
+        }],
+        ..Default::default()
+    })
+    .await;
+    let mut publisher = TestClient::connect(addr, "publisher").await;
+    publisher
+        .send([publish("fleet/a", b"late", QoS::QoS1, 1)])
+        .await;
+    publisher.puback().await;
+
+    let mut subscriber = TestClient::connect(addr, "subscriber").await;
+    subscriber.subscribe("fleet/#", QoS::QoS0).await;
+    let queued = subscriber.publish().await;
+    assert_eq!(&queued.payload()[..], b"late");
+    assert_eq!(queued.qos(), QoS::QoS0);
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_retained_messages() {
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let mut publisher = TestClient::connect(addr, "publisher").await;
+    let mut retained = Publish::new(Arc::from("rooms/a"), Bytes::from_static(b"on")).unwrap();
+    retained.set_retain();
+    publisher.send([retained.build()]).await;
+    wait_until("the message retained", || async {
+        !server.retained_topics().is_empty()
+    })
+    .await;
+
+    // a new subscription is sent the retained message with RETAIN set
+    let mut subscriber = TestClient::connect(addr, "subscriber").await;
+    subscriber.subscribe("rooms/+", QoS::QoS0).await;
+    let received = subscriber.publish().await;
+    assert_eq!(&received.payload()[..], b"on");
+    assert!(received.flags().contains(PublishFlags::RETAIN));
+
+    // an empty retained publish clears it, and reaches the current subscribers
+    let mut clear = Publish::new(Arc::from("rooms/a"), Bytes::new()).unwrap();
+    clear.set_retain();
+    publisher.send([clear.build()]).await;
+    assert!(subscriber.publish().await.payload().is_empty());
+    assert!(server.retained_topics().is_empty());
+
+    // the will of a client may be retained as well
+    let mut connect = Connect::new(Arc::from("abrupt")).unwrap();
+    connect.set_will(Will::new(Arc::from("rooms/b"), Bytes::from_static(b"gone")).unwrap());
+    connect.set_will_retain().unwrap();
+    drop(TestClient::connect_with(addr, connect).await);
+    assert_eq!(&subscriber.publish().await.payload()[..], b"gone");
+    let mut late = TestClient::connect(addr, "late").await;
+    late.subscribe("rooms/#", QoS::QoS0).await;
+    let received = late.publish().await;
+    assert_eq!(&**received.topic_name(), "rooms/b");
+    assert!(received.flags().contains(PublishFlags::RETAIN));
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_retain_as_published() {
+    let (server, addr) = start(MqttServerConfig::default()).await;
+    let mut subscribers = Vec::new();
+    for (clientid, options) in [
+        ("published", SubscriptionOptions::RETAIN_AS_PUBLISHED),
+        ("cleared", SubscriptionOptions::NO_FLAGS),
+    ] {
+        let mut subscriber = TestClient::connect(addr, clientid).await;
+        let mut subscribe = Subscribe::new(1);
+        subscribe.add_topic(Arc::from("rooms/+"), options).unwrap();
+        subscriber.send([subscribe.build()]).await;
+        subscriber.suback().await;
+        subscribers.push(subscriber);
+    }
+    let mut publisher = TestClient::connect(addr, "publisher").await;
+    let mut retained = Publish::new(Arc::from("rooms/a"), Bytes::from_static(b"on")).unwrap();
+    retained.set_retain();
+    publisher.send([retained.build()]).await;
+    // 3.3.1.3: a live publish keeps RETAIN only for Retain As Published subscriptions
+    let flags = subscribers[0].publish().await.flags();
+    assert!(flags.contains(PublishFlags::RETAIN));
+    let flags = subscribers[1].publish().await.flags();
+    assert!(!flags.contains(PublishFlags::RETAIN));
+    server.shutdown().await;
+}
@@ -0,0 +1,346 @@
+mod common;
+
+use apiformes_server_lib::prelude::*;
+use bytes::Bytes;
+use common::{any_port, persistent, publish, relay, start, wait_until, TestClient};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// Connects `clientid` with a session outliving the connection, returns whether a session
+/// was present
+async fn connect_persistent(addr: SocketAddr, clientid: &str) -> (TestClient, bool) {
+    let (client, connack) = TestClient::connect_with(addr, persistent(clientid)).await;
+    assert_eq!(connack.reason_code(), ConnAckReasonCode::Success);
+    (
+        client,
+        connack.flags().contains(ConnAckFlags::SESSION_PRESENT),
+    )
+}
+/// Waits until the session of `clientid` outlives its connection
+async fn wait_parked(server: &MqttServer, clientid: &str) {
+    wait_until("the session parked", || async {
+        !server.clients().await.iter().any(|id| &**id == clientid)
+            && server
+                .export_sessions()
+                .await
+                .sessions
+                .iter()
+                .any(|session| session.clientid == clientid)
+    })
+    .await;
+}
+#[tokio::test]
+async fn test_session_migration() {
+    let cfg = || MqttServerConfig {
+        session_policy: SessionPolicy::RetainUntilExpiry,
+        ..Default::default()
+    };
+    let (old, old_addr) = start(cfg()).await;
+    let (new, new_addr) = start(cfg()).await;
+    let (mut client, present) = connect_persistent(old_addr, "mover").await;
+    assert!(!present);
+    client.subscribe("moving/+", QoS::QoS1).await;
+
+    let export = old.export_sessions().await;
+    assert_eq!(export.sessions.len(), 1);
+    assert_eq!(export.sessions[0].subscriptions[0].filter, "moving/+");
+    let export = SessionExport::from_json(&export.to_json()).unwrap();
+    assert_eq!(new.import_sessions(&export).await.unwrap(), 1);
+    // importing again leaves the session alone
+    assert_eq!(new.import_sessions(&export).await.unwrap(), 0);
+
+    assert_eq!(old.redirect_clients("new.example.com").await.unwrap(), 1);
+    let redirect = client.disconnect().await;
+    assert_eq!(redirect.reason_code(), DisconnectReasonCode::ServerMoved);
+    let reference = redirect.get_prop(Property::ServerReference).unwrap();
+    assert_eq!(reference[0].into_str(), Some("new.example.com"));
+
+    let (mut client, present) = connect_persistent(new_addr, "mover").await;
+    assert!(present);
+    new.publish(
+        "moving/in",
+        Bytes::from_static(b"welcome"),
+        QoS::QoS0,
+        false,
+        [],
+    )
+    .await
+    .unwrap();
+    assert_eq!(&client.publish().await.payload()[..], b"welcome");
+    old.shutdown().await;
+    new.shutdown().await;
+}
+#[tokio::test]
+async fn test_standby_promotion() {
+    let cfg = |replication| MqttServerConfig {
+        session_policy: SessionPolicy::RetainUntilExpiry,
+        unmatched_publishes: vec![UnmatchedTopics {
+            prefix: "config/".to_owned(),
+            policy: UnmatchedPolicy::Retain,
+        }],
+        replication: Some(replication),
+        ..Default::default()
+    };
+    let (primary, primary_addr) = start(cfg(Replication::Primary {
+        listen: any_port(),
+        key: "secret".to_owned(),
+        max_lag_ms: 10,
+    }))
+    .await;
+    let replication = primary.local_addr("replication").unwrap();
+    let (standby, standby_addr) = start(cfg(Replication::Standby {
+        primary: replication.to_string(),
+        key: "secret".to_owned(),
+    }))
+    .await;
+    // anyone else connecting is sent nothing
+    let mut stranger = TestClient::open(replication).await;
+    stranger.send([Packet::ping_req()]).await;
+    assert!(stranger.try_recv().await.is_none());
+    assert!(primary.promote().await.is_err());
+
+    let (mut client, _) = connect_persistent(primary_addr, "follower").await;
+    client.subscribe("updates/+", QoS::QoS1).await;
+    primary
+        .publish("config/a", Bytes::from_static(b"a"), QoS::QoS0, false, [])
+        .await
+        .unwrap();
+    wait_until("the standby caught up", || async {
+        !standby.export_sessions().await.sessions.is_empty()
+            && !standby.retained_topics().is_empty()
+    })
+    .await;
+
+    // clients are refused until the promotion
+    let connect = Connect::new(Arc::from("early")).unwrap();
+    let (_, connack) = TestClient::connect_with(standby_addr, connect).await;
+    assert_eq!(connack.reason_code(), ConnAckReasonCode::ServerUnavailable);
+    primary.shutdown().await;
+    standby.promote().await.unwrap();
+    assert!(standby.promote().await.is_err());
+
+    let (mut client, present) = connect_persistent(standby_addr, "follower").await;
+    assert!(present);
+    standby
+        .publish("updates/a", Bytes::from_static(b"x"), QoS::QoS0, false, [])
+        .await
+        .unwrap();
+    assert_eq!(&client.publish().await.payload()[..], b"x");
+    client.subscribe("config/#", QoS::QoS0).await;
+    assert_eq!(&**client.publish().await.topic_name(), "config/a");
+    standby.shutdown().await;
+}
+#[tokio::test]
+async fn test_restart_with_storage() {
+    let storage = Arc::new(MemoryStorage::default());
+    let cfg = || MqttServerConfig {
+        session_policy: SessionPolicy::RetainUntilExpiry,
+        unmatched_publishes: vec![UnmatchedTopics {
+            prefix: "config/".to_owned(),
+            policy: UnmatchedPolicy::Retain,
+        }],
+        storage: Some(storage.clone()),
+        ..Default::default()
+    };
+    let (server, addr) = start(cfg()).await;
+    let (mut client, _) = connect_persistent(addr, "durable").await;
+    client.subscribe("updates/+", QoS::QoS1).await;
+    server
+        .publish("config/a", Bytes::from_static(b"a"), QoS::QoS0, false, [])
+        .await
+        .unwrap();
+    wait_until("the publish retained", || async {
+        !server.retained_topics().is_empty()
+    })
+    .await;
+    // the state is saved on shutdown
+    server.shutdown().await;
+    drop(client);
+
+    let (server, addr) = start(cfg()).await;
+    assert_eq!(server.retained_topics().len(), 1);
+    let (mut client, present) = connect_persistent(addr, "durable").await;
+    assert!(present);
+    server
+        .publish("updates/a", Bytes::from_static(b"x"), QoS::QoS0, false, [])
+        .await
+        .unwrap();
+    assert_eq!(&client.publish().await.payload()[..], b"x");
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_cluster_routing() {
+    let node = |peer: SocketAddr| MqttServerConfig {
+        cluster: Some(Cluster {
+            listen: any_port(),
+            peers: vec![peer.to_string()],
+            key: "secret".to_owned(),
+            gossip_interval_ms: 10,
+        }),
+        ..Default::default()
+    };
+    // the publishes of the peers are submitted to the authorizer
+    let acl: AclFile = "allow client * subscribe #\nallow client $internal/cluster publish news/a"
+        .parse()
+        .unwrap();
+    // `a` must be told the address of `b` before `b` is started, it dials it through a relay
+    let to_b = TcpListener::bind(any_port()).await.unwrap();
+    let (a, _) = start(node(to_b.local_addr().unwrap())).await;
+    let (b, b_addr) = start(MqttServerConfig {
+        authorizer: Some(Arc::new(acl)),
+        ..node(a.local_addr("cluster").unwrap())
+    })
+    .await;
+    relay(to_b, b.local_addr("cluster").unwrap());
+
+    let mut client = TestClient::connect(b_addr, "remote").await;
+    client.subscribe("news/+", QoS::QoS1).await;
+    // a subscriber on both nodes, publishes must not bounce between them
+    let _local = a.subscribe("news/#", QoS::QoS0).await.unwrap();
+    wait_until("the filters announced", || async {
+        a.cluster_peers()[0].filters > 0 && b.cluster_peers()[0].filters > 0
+    })
+    .await;
+    assert!(a.cluster_peers()[0].connected);
+
+    a.publish(
+        "other/a",
+        Bytes::from_static(b"skipped"),
+        QoS::QoS0,
+        false,
+        [],
+    )
+    .await
+    .unwrap();
+    a.publish(
+        "news/b",
+        Bytes::from_static(b"denied"),
+        QoS::QoS0,
+        false,
+        [],
+    )
+    .await
+    .unwrap();
+    a.publish(
+        "news/a",
+        Bytes::from_static(b"routed"),
+        QoS::QoS1,
+        false,
+        [],
+    )
+    .await
+    .unwrap();
+    let routed = client.publish().await;
+    assert_eq!(&routed.payload()[..], b"routed");
+    assert_eq!(routed.qos(), QoS::QoS1);
+    assert_eq!(a.metrics().cluster_forwarded(), 2);
+    assert_eq!(b.metrics().acl_denied(), 1);
+    // the publish is not sent back to the node it came from
+    assert_eq!(b.metrics().cluster_forwarded(), 0);
+    a.shutdown().await;
+    b.shutdown().await;
+}
+#[tokio::test]
+async fn test_cluster_key() {
+    let (server, _) = start(MqttServerConfig {
+        cluster: Some(Cluster {
+            listen: any_port(),
+            peers: Vec::new(),
+            key: "secret".to_owned(),
+            gossip_interval_ms: 10,
+        }),
+        ..Default::default()
+    })
+    .await;
+    let cluster = server.local_addr("cluster").unwrap();
+    // a would-be peer publishing without presenting the key
+    let mut stranger = TestClient::open(cluster).await;
+    stranger
+        .send([publish("news/a", b"forged", QoS::QoS0, 0)])
+        .await;
+    // closed without a single announcement of the filters
+    assert!(stranger.try_recv().await.is_none());
+    let mut stranger = TestClient::open(cluster).await;
+    let mut auth = Auth::new(AuthReasonCode::ContinueAuthentication);
+    auth.add_prop(
+        Property::AuthenticationMethod,
+        MqttPropValue::new_string(Arc::from("link-key")).unwrap(),
+    )
+    .unwrap();
+    auth.add_prop(
+        Property::AuthenticationData,
+        MqttPropValue::new_data(&b"guessed"[..]).unwrap(),
+    )
+    .unwrap();
+    stranger.send([auth.build()]).await;
+    assert!(stranger.try_recv().await.is_none());
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_disconnect_session_expiry() {
+    let (server, addr) = start(MqttServerConfig {
+        session_policy: SessionPolicy::RetainUntilExpiry,
+        ..Default::default()
+    })
+    .await;
+    let disconnect_with_expiry = |expiry| {
+        let mut disconnect = Disconnect::new(DisconnectReasonCode::NormalDisconnection);
+        disconnect
+            .add_prop(
+                Property::SessionExpiryInterval,
+                MqttPropValue::new_u32(expiry),
+            )
+            .unwrap();
+        disconnect.build()
+    };
+    // the session is kept without an override
+    let (mut client, _) = connect_persistent(addr, "kept").await;
+    client
+        .send([Disconnect::new(DisconnectReasonCode::NormalDisconnection).build()])
+        .await;
+    client.closed().await;
+    wait_parked(&server, "kept").await;
+    // the client ends its session when disconnecting
+    let (mut client, _) = connect_persistent(addr, "ended").await;
+    client.send([disconnect_with_expiry(0)]).await;
+    client.closed().await;
+    wait_until("the client retired", || async {
+        server.last_disconnect("ended").is_some() && server.clients().await.is_empty()
+    })
+    .await;
+    assert!(connect_persistent(addr, "kept").await.1);
+    assert!(!connect_persistent(addr, "ended").await.1);
+
+    // 3.14.2.2.2: a session ending with the connection cannot be extended
+    let mut client = TestClient::connect(addr, "transient").await;
+    client.send([disconnect_with_expiry(60)]).await;
+    assert_eq!(
+        client.disconnect().await.reason_code(),
+        DisconnectReasonCode::ProtocolError
+    );
+    server.shutdown().await;
+}
+#[tokio::test]
+async fn test_max_sessions() {
+    let (server, addr) = start(MqttServerConfig {
+        session_policy: SessionPolicy::RetainUntilExpiry,
+        max_sessions: Some(2),
+        ..Default::default()
+    })
+    .await;
+    for clientid in ["oldest", "newest"] {
+        drop(connect_persistent(addr, clientid).await.0);
+        wait_parked(&server, clientid).await;
+    }
+    // a third client does not fit with both retained sessions
+    let (_connected, present) = connect_persistent(addr, "connected").await;
+    assert!(!present);
+    wait_until("a session evicted", || async {
+        server.metrics().sessions_evicted() == 1
+    })
+    .await;
+    assert!(connect_persistent(addr, "newest").await.1);
+    assert!(!connect_persistent(addr, "oldest").await.1);
+    server.shutdown().await;
+}
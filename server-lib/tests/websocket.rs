@@ -0,0 +1,101 @@
+#![cfg(feature = "websocket")]
+mod common;
+
+use apiformes_server_lib::prelude::*;
+use bytes::BytesMut;
+use common::{any_port, publish, start, subscribe, TestClient, WITHIN};
+use futures::{SinkExt, StreamExt};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// An MQTT client over WebSocket, one packet per binary message
+struct WsTestClient(WebSocketStream<TcpStream>);
+
+impl WsTestClient {
+    async fn open(addr: SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut request = format!("ws://{}/mqtt", addr).into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", HeaderValue::from_static("mqtt"));
+        let (ws, response) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get("Sec-WebSocket-Protocol").unwrap(),
+            "mqtt"
+        );
+        WsTestClient(ws)
+    }
+    async fn send(&mut self, packet: Packet) {
+        let mut buf = BytesMut::new();
+        packet.to_bytes_versioned(&mut buf, ProtocolVersion::V5);
+        self.0.send(Message::Binary(buf.to_vec())).await.unwrap();
+    }
+    async fn recv(&mut self) -> Packet {
+        loop {
+            let message = timeout(WITHIN, self.0.next())
+                .await
+                .expect("nothing received")
+                .expect("connection closed")
+                .unwrap();
+            if let Message::Binary(bytes) = message {
+                let mut cursor = Cursor::new(&bytes[..]);
+                return Packet::from_bytes_versioned(&mut cursor, ProtocolVersion::V5).unwrap();
+            }
+        }
+    }
+}
+#[tokio::test]
+async fn test_mqtt_over_websocket() {
+    let (server, _) = start(MqttServerConfig {
+        multiplex_socketaddr: Some(any_port()),
+        ..Default::default()
+    })
+    .await;
+    let multiplex = server.local_addr("multiplex").unwrap();
+    let mut browser = WsTestClient::open(multiplex).await;
+    browser
+        .send(Connect::new(Arc::from("browser")).unwrap().build())
+        .await;
+    match browser.recv().await {
+        Packet::ConnAck(connack) => assert_eq!(connack.reason_code(), ConnAckReasonCode::Success),
+        packet => panic!("expected a CONNACK, got {:?}", packet),
+    }
+    browser.send(subscribe(1, &[("ws/#", QoS::QoS0)])).await;
+    assert!(matches!(browser.recv().await, Packet::SubAck(_)));
+    // raw MQTT on the same port
+    let mut device = TestClient::connect(multiplex, "device").await;
+    device.subscribe("tcp/#", QoS::QoS0).await;
+    device
+        .send([publish("ws/a", b"to browser", QoS::QoS0, 0)])
+        .await;
+    match browser.recv().await {
+        Packet::Publish(p) => assert_eq!(&p.payload()[..], b"to browser"),
+        packet => panic!("expected a PUBLISH, got {:?}", packet),
+    }
+    browser
+        .send(publish("tcp/a", b"to device", QoS::QoS1, 1))
+        .await;
+    match browser.recv().await {
+        Packet::PubAck(ack) => assert_eq!(ack.identifier(), 1),
+        packet => panic!("expected a PUBACK, got {:?}", packet),
+    }
+    assert_eq!(&device.publish().await.payload()[..], b"to device");
+    let mut listeners: Vec<_> = server
+        .client_sessions()
+        .await
+        .into_iter()
+        .map(|session| session.listener)
+        .collect();
+    listeners.dedup();
+    assert_eq!(listeners, ["multiplex"]);
+    server.shutdown().await;
+}
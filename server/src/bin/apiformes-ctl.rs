@@ -0,0 +1,155 @@
+//! Command line client of the HTTP management API served on `admin_socketaddr`:
+//!
+//! - `apiformes-ctl clients` lists the connected clients
+//! - `apiformes-ctl disconnect <clientid>` disconnects a client
+//! - `apiformes-ctl subscriptions <topic>` lists the subscriptions matching a topic name
+//! - `apiformes-ctl publish <topic> <payload> [--qos <qos>]` publishes on behalf of the broker
+//!
+//! The API is reached on `--admin <ip:port>`, `APIFORMES_ADMIN_ADDR` or `127.0.0.1:8080`.
+//! Responses are printed as the JSON the API answers, errors exit with the status of their
+//! `ApiErrorCode`.
+use apiformes_server_lib::prelude::*;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+const DEFAULT_ADMIN_ADDR: &str = "127.0.0.1:8080";
+/// How long the API has to answer
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+const USAGE: &str = "usage: apiformes-ctl [--admin <ip:port>] \
+    clients | disconnect <clientid> | subscriptions <topic> | publish <topic> <payload> [--qos <qos>]";
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(args).await {
+        Ok(Some(body)) => println!("{}", body),
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(e.code.exit_code());
+        }
+    }
+}
+
+fn invalid(message: &str) -> ApiError {
+    ApiError::new(ApiErrorCode::InvalidArgument, message.to_owned())
+}
+
+/// Takes the value of the option `name` out of `args`
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, ApiError> {
+    let i = match args.iter().position(|arg| arg == name) {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    if i + 1 == args.len() {
+        return Err(invalid(&format!("{} expects a value", name)));
+    }
+    args.remove(i);
+    Ok(Some(args.remove(i)))
+}
+
+/// Sends the request `args` stand for, returns the body of the response if any
+async fn run(mut args: Vec<String>) -> Result<Option<String>, ApiError> {
+    let admin = match take_option(&mut args, "--admin")? {
+        Some(admin) => admin,
+        None => {
+            std::env::var("APIFORMES_ADMIN_ADDR").unwrap_or_else(|_| DEFAULT_ADMIN_ADDR.to_owned())
+        }
+    };
+    let admin: SocketAddr = admin
+        .parse()
+        .map_err(|_| invalid(&format!("{} is not an address", admin)).with_field("admin"))?;
+    let qos = take_option(&mut args, "--qos")?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (method, target, body) = match (&args[..], qos) {
+        (["clients"], None) => ("GET", "/clients".to_owned(), ""),
+        (["disconnect", clientid], None) => (
+            "DELETE",
+            format!("/clients/{}", percent_encode(clientid)),
+            "",
+        ),
+        (["subscriptions", topic], None) => (
+            "GET",
+            format!("/subscriptions?topic={}", percent_encode(topic)),
+            "",
+        ),
+        (["publish", topic, payload], qos) => {
+            let qos = qos.as_deref().unwrap_or("0");
+            let target = format!(
+                "/publish?topic={}&qos={}",
+                percent_encode(topic),
+                percent_encode(qos)
+            );
+            ("POST", target, *payload)
+        }
+        _ => return Err(invalid(USAGE)),
+    };
+    request(admin, method, &target, body).await
+}
+
+/// Escapes every byte of `s` but the unreserved characters of RFC 3986
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+async fn request(
+    admin: SocketAddr,
+    method: &str,
+    target: &str,
+    body: &str,
+) -> Result<Option<String>, ApiError> {
+    let unavailable =
+        |e: std::io::Error| ApiError::new(ApiErrorCode::Unavailable, format!("{}, {}", admin, e));
+    let mut stream = TcpStream::connect(admin).await.map_err(unavailable)?;
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        target,
+        admin,
+        body.len(),
+        body
+    );
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(unavailable)?;
+    // the API closes the connection after its response
+    let mut response = String::new();
+    match timeout(RESPONSE_TIMEOUT, stream.read_to_string(&mut response)).await {
+        Ok(read) => read.map_err(unavailable)?,
+        Err(_) => {
+            return Err(ApiError::new(
+                ApiErrorCode::Unavailable,
+                format!("{} did not answer", admin),
+            ))
+        }
+    };
+    let malformed = || ApiError::new(ApiErrorCode::Internal, "malformed response".to_owned());
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(malformed)?;
+    let status: u16 = head
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(malformed)?;
+    match status {
+        200..=299 if body.is_empty() => Ok(None),
+        200..=299 => Ok(Some(body.to_owned())),
+        // the routes exist but not with this method, a bug of this tool
+        405 => Err(ApiError::new(
+            ApiErrorCode::Internal,
+            format!("{} {} is not allowed", method, target),
+        )),
+        _ => Err(serde_json::from_str(body).unwrap_or_else(|_| {
+            ApiError::new(ApiErrorCode::Internal, format!("status {}", status))
+        })),
+    }
+}
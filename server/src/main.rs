@@ -1,5 +1,5 @@
-use apiformes_server_lib::{MqttServer, MqttServerConfig, Permeability};
-use tokio::time::{sleep, Duration};
+use apiformes_server_lib::prelude::*;
+use std::str::FromStr;
 use tracing_subscriber::{filter::EnvFilter, FmtSubscriber};
 #[tokio::main]
 async fn main() {
@@ -10,27 +10,93 @@ async fn main() {
         .finish();
 
     tracing::subscriber::set_global_default(sub).expect("setting tracing default failed");
-    let cfg = MqttServerConfig {
-        mqtt_socketaddr: Some("0.0.0.0:1883".parse().unwrap()),
-        keep_alive: 50,
-        noise_socketaddr: Some("0.0.0.0:8883".parse().unwrap()),
-        channel_permeability: Permeability::Strict,
-        dispatcher_queue_size: 1024 * 1024,
-        max_packet_size: 64 * 1024,
-        private_key: [
-            205, 100, 157, 80, 236, 140, 109, 150, 91, 254, 27, 10, 200, 89, 193, 158, 49, 238, 24,
-            134, 137, 225, 220, 169, 32, 209, 239, 35, 2, 254, 0, 166,
-        ],
-        //public_key = [
-        //          180, 132, 40, 246, 52, 36, 9, 93, 224,
-        //          18, 51, 123, 188, 226, 131, 145, 196,
-        //          93, 24, 112, 227, 133, 8, 199, 229, 139,
-        //          2, 248, 5, 115, 136, 37
-        //  ]
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // --config <path> reads the whole configuration from a TOML or YAML file instead
+    let builder = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => match args.get(i + 1) {
+            Some(path) => MqttServerConfig::from_path(path)
+                .map(MqttServerBuilder::from)
+                .map_err(ApiError::from),
+            None => Err(ApiError::new(
+                ApiErrorCode::InvalidArgument,
+                "--config expects a path".to_owned(),
+            )),
+        },
+        None => default_builder(),
     };
-    let _server = MqttServer::new(cfg).await.unwrap();
-    //server.shutdown().await;
-    loop {
-        sleep(Duration::from_secs(600)).await;
+    let builder = builder.unwrap_or_else(|e| exit("Configuration loading failed", e));
+    // --check validates the deployment and exits without serving any traffic
+    if args.iter().any(|arg| arg == "--check") {
+        match builder.check().await {
+            Ok(()) => println!("Configuration OK"),
+            Err(e) => {
+                // the error goes to stdout as JSON for tooling, the exit status tells its kind
+                let e = ApiError::from(e);
+                println!("{}", e.to_json());
+                exit("Configuration check failed", e);
+            }
+        }
+        return;
     }
+    let server = match builder.build().await {
+        Ok(server) => server,
+        Err(e) => exit("Startup failed", e.into()),
+    };
+    tokio::signal::ctrl_c()
+        .await
+        .expect("cannot listen for Ctrl-C");
+    server.shutdown_gracefully().await;
+}
+
+/// Reports `e` and exits with the status telling its kind
+fn exit(context: &str, e: ApiError) -> ! {
+    eprintln!("{}, {}", context, e);
+    std::process::exit(e.code.exit_code());
+}
+
+/// The environment variable `name` parsed, `None` if it is not set
+fn env<T: FromStr>(name: &str) -> Result<Option<T>, ApiError> {
+    match std::env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|_| {
+            ApiError::new(ApiErrorCode::InvalidConfig, format!("invalid {}", value))
+                .with_field(name)
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Configuration used without --config, tuned through environment variables
+fn default_builder() -> Result<MqttServerBuilder, ApiError> {
+    let private_key = [
+        205, 100, 157, 80, 236, 140, 109, 150, 91, 254, 27, 10, 200, 89, 193, 158, 49, 238, 24,
+        134, 137, 225, 220, 169, 32, 209, 239, 35, 2, 254, 0, 166,
+    ];
+    //public_key = [
+    //          180, 132, 40, 246, 52, 36, 9, 93, 224,
+    //          18, 51, 123, 188, 226, 131, 145, 196,
+    //          93, 24, 112, 227, 133, 8, 199, 229, 139,
+    //          2, 248, 5, 115, 136, 37
+    //  ]
+    let mut builder = MqttServer::builder()
+        .mqtt(([0, 0, 0, 0], 1883).into())
+        .noise(([0, 0, 0, 0], 8883).into(), private_key);
+    if let Some(saddr) = env("APIFORMES_MULTIPLEX_ADDR")? {
+        builder = builder.multiplex(saddr);
+    }
+    if let Some(saddr) = env("APIFORMES_ADMIN_ADDR")? {
+        builder = builder.admin(saddr);
+    }
+    let node_id = env("APIFORMES_NODE_ID")?;
+    let buffer_size = env("APIFORMES_READ_BUFFER")?.unwrap_or(0);
+    let dispatcher_workers = env("APIFORMES_DISPATCHER_WORKERS")?.unwrap_or(1);
+    Ok(builder.configure(|cfg| {
+        cfg.node_id = node_id;
+        cfg.mqtt_read = ReadTuning {
+            buffer_size,
+            coalesce_frames: std::env::var("APIFORMES_COALESCE_FRAMES").is_ok(),
+        };
+        cfg.dispatcher_workers = dispatcher_workers;
+        cfg.storage_path = std::env::var_os("APIFORMES_STORAGE_PATH").map(Into::into);
+        cfg.channel_permeability = Permeability::Strict;
+    }))
 }